@@ -0,0 +1,92 @@
+use {
+    criterion::{criterion_group, criterion_main, Criterion},
+    deneb_core::{
+        catalog::{open_catalog, Catalog, CatalogType},
+        inode::{FileAttributes, INode},
+    },
+    rand::{thread_rng, Rng},
+    std::path::{Path, PathBuf},
+    tempdir::TempDir,
+};
+
+mod common;
+
+const NUM_ENTRIES: u64 = 10_000;
+
+fn make_inode(index: u64) -> INode {
+    let mut attributes = FileAttributes::default();
+    attributes.index = index;
+    INode::new(attributes, vec![])
+}
+
+fn populated_catalog(tmp: &TempDir) -> Box<dyn Catalog> {
+    let catalog = open_catalog(CatalogType::Lmdb, tmp.path().join("catalog"), true).unwrap();
+    catalog.add_inode(&make_inode(1)).unwrap();
+    for index in 2..=NUM_ENTRIES {
+        catalog.add_inode(&make_inode(index)).unwrap();
+        catalog.add_dir_entry(1, &entry_name(index), index).unwrap();
+    }
+    catalog
+}
+
+fn entry_name(index: u64) -> PathBuf {
+    PathBuf::from(format!("entry_{}", index))
+}
+
+fn insert(c: &mut Criterion) {
+    let tmp = TempDir::new("deneb_bench_catalog").unwrap();
+    let catalog = open_catalog(CatalogType::Lmdb, tmp.path().join("catalog"), true).unwrap();
+
+    common::report_io("catalog_insert", || {
+        catalog.add_inode(&make_inode(1)).unwrap();
+    });
+
+    let mut index = 1;
+    c.bench_function("catalog_insert", move |b| {
+        let _keep = &tmp;
+        b.iter(|| {
+            index += 1;
+            catalog.add_inode(&make_inode(index)).unwrap();
+        })
+    });
+}
+
+fn inode_lookup(c: &mut Criterion) {
+    let tmp = TempDir::new("deneb_bench_catalog").unwrap();
+    let catalog = populated_catalog(&tmp);
+
+    common::report_io("catalog_inode_lookup", || {
+        catalog.inode(NUM_ENTRIES / 2).unwrap();
+    });
+
+    c.bench_function("catalog_inode_lookup", move |b| {
+        let _keep = &tmp;
+        let mut rng = thread_rng();
+        b.iter(|| catalog.inode(rng.gen_range(1, NUM_ENTRIES + 1)).unwrap())
+    });
+}
+
+fn dir_entry_lookup(c: &mut Criterion) {
+    let tmp = TempDir::new("deneb_bench_catalog").unwrap();
+    let catalog = populated_catalog(&tmp);
+
+    common::report_io("catalog_dir_entry_lookup", || {
+        catalog.dir_entry_index(1, Path::new("entry_2")).unwrap();
+    });
+
+    c.bench_function("catalog_dir_entry_lookup", move |b| {
+        let _keep = &tmp;
+        let mut rng = thread_rng();
+        b.iter(|| {
+            let name = entry_name(rng.gen_range(2, NUM_ENTRIES + 1));
+            catalog.dir_entry_index(1, &name).unwrap()
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = insert, inode_lookup, dir_entry_lookup
+}
+criterion_main!(benches);
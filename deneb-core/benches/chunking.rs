@@ -0,0 +1,68 @@
+use {
+    criterion::{criterion_group, criterion_main, Benchmark, Criterion, Throughput},
+    deneb_core::{
+        cas::Algorithm,
+        crypt::EncryptionKey,
+        store::{open_store, StoreType},
+    },
+    rand::{thread_rng, RngCore},
+    tempdir::TempDir,
+};
+
+mod common;
+
+const DATA_SIZE: usize = 16 * 1024 * 1024; // 16MB
+
+fn put_file_chunked(
+    c: &mut Criterion,
+    name: &str,
+    store_type: StoreType,
+    encryption_key: Option<EncryptionKey>,
+    algorithm: Algorithm,
+) {
+    deneb_core::init().unwrap();
+    let tmp = TempDir::new("deneb_bench_chunking").unwrap();
+    let mut store = open_store(
+        store_type,
+        tmp.path(),
+        encryption_key,
+        common::CHUNK_SIZE,
+        common::CHUNK_CACHE_SIZE,
+        false,
+    )
+    .unwrap();
+    store.set_algorithm(algorithm);
+
+    let mut data = vec![0 as u8; DATA_SIZE];
+    thread_rng().fill_bytes(data.as_mut());
+
+    common::report_io(name, || {
+        store.put_file_chunked(&mut data.as_slice()).unwrap();
+    });
+
+    c.bench(
+        "chunking",
+        Benchmark::new(name, move |b| {
+            let _keep = &tmp;
+            b.iter(|| store.put_file_chunked(&mut data.as_slice()).unwrap())
+        })
+        .sample_size(10)
+        .throughput(Throughput::Bytes(DATA_SIZE as u32)),
+    );
+}
+
+fn chunking(c: &mut Criterion) {
+    put_file_chunked(c, "memory", StoreType::InMemory, None, Algorithm::Sha512);
+    put_file_chunked(c, "disk", StoreType::OnDisk, None, Algorithm::Sha512);
+    put_file_chunked(
+        c,
+        "disk_encrypted",
+        StoreType::OnDisk,
+        Some(EncryptionKey::new()),
+        Algorithm::Sha512,
+    );
+    put_file_chunked(c, "disk_blake3", StoreType::OnDisk, None, Algorithm::Blake3);
+}
+
+criterion_group!(benches, chunking);
+criterion_main!(benches);
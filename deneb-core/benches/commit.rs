@@ -0,0 +1,53 @@
+use {
+    common::{BenchEngine, ROOT_INDEX},
+    criterion::{criterion_group, criterion_main, Criterion},
+    deneb_core::store::StoreType,
+    std::{cell::Cell, ffi::OsStr},
+};
+
+mod common;
+
+const FILE_SIZE: usize = 4096;
+
+// Create a new directory containing `num_files` small files
+fn make_tree(engine: &BenchEngine, name: &str, num_files: usize) {
+    let entry = engine
+        .handle
+        .create_dir(&common::request_id(), ROOT_INDEX, OsStr::new(name), 0o755)
+        .unwrap();
+    for i in 0..num_files {
+        let index = engine
+            .create_file(entry.attributes.index, &format!("{}", i))
+            .unwrap();
+        engine.write_file(index, FILE_SIZE, FILE_SIZE).unwrap();
+    }
+}
+
+fn commit(c: &mut Criterion) {
+    for &num_files in &[10, 100, 1000] {
+        let engine = BenchEngine::start(StoreType::InMemory).unwrap();
+
+        common::report_io(&format!("commit_{}_files", num_files), || {
+            make_tree(&engine, "reported", num_files);
+            engine.handle.commit(None).unwrap();
+        });
+
+        let counter = Cell::new(0);
+        c.bench_function(&format!("commit_{}_files", num_files), move |b| {
+            b.iter_with_setup(
+                || {
+                    counter.set(counter.get() + 1);
+                    make_tree(&engine, &format!("tree_{}", counter.get()), num_files);
+                },
+                |_| engine.handle.commit(None).unwrap(),
+            )
+        });
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = commit
+}
+criterion_main!(benches);
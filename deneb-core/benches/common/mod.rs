@@ -0,0 +1,105 @@
+#![allow(dead_code)]
+
+use {
+    deneb_core::{
+        catalog::CatalogType,
+        engine::{start_engine, EngineOptions, Handle, RequestId, WorkspaceOptions},
+        errors::DenebResult,
+        inode::FileAttributeChanges,
+        store::StoreType,
+    },
+    std::{ffi::OsStr, fs::create_dir_all},
+    tempdir::TempDir,
+};
+
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024; // 4MB
+pub const CHUNK_CACHE_SIZE: u64 = 1024 * 1024 * 1024; // 1GB
+pub const ROOT_INDEX: u64 = 1;
+
+/// An engine running on top of a temporary work dir
+///
+/// The work dir is removed when the `BenchEngine` is dropped.
+pub struct BenchEngine {
+    pub handle: Handle,
+    _work_dir: TempDir,
+}
+
+impl BenchEngine {
+    pub fn start(store_type: StoreType) -> DenebResult<BenchEngine> {
+        deneb_core::init()?;
+        let work_dir = TempDir::new("deneb_bench")?;
+        // The repository starts out with the contents of an empty dir
+        let empty_dir = work_dir.path().join("empty");
+        create_dir_all(&empty_dir)?;
+        let handle = start_engine(
+            CatalogType::Lmdb,
+            store_type,
+            work_dir.path().to_owned(),
+            EngineOptions {
+                workspace: WorkspaceOptions {
+                    sync_dir: Some(empty_dir),
+                    chunk_size: CHUNK_SIZE,
+                    chunk_cache_size: CHUNK_CACHE_SIZE,
+                    ..WorkspaceOptions::default()
+                },
+                ..EngineOptions::default()
+            },
+        )?;
+        Ok(BenchEngine {
+            handle,
+            _work_dir: work_dir,
+        })
+    }
+
+    pub fn create_file(&self, parent: u64, name: &str) -> DenebResult<u64> {
+        let (entry, _) =
+            self.handle
+                .create_file(&request_id(), parent, OsStr::new(name), 0o644, 0)?;
+        Ok(entry.attributes.index)
+    }
+
+    pub fn write_file(&self, index: u64, size: usize, block_size: usize) -> DenebResult<()> {
+        let block = vec![0xa5 as u8; block_size];
+        let mut offset = 0;
+        while offset < size {
+            self.handle
+                .write_data(&request_id(), index, offset as i64, block.clone())?;
+            offset += block_size;
+        }
+        Ok(())
+    }
+
+    pub fn truncate(&self, index: u64, size: u64) -> DenebResult<()> {
+        let changes =
+            FileAttributeChanges::new(None, None, None, Some(size), None, None, None, None, None);
+        self.handle.set_attr(&request_id(), index, changes)?;
+        Ok(())
+    }
+}
+
+impl Drop for BenchEngine {
+    fn drop(&mut self) {
+        self.handle.stop_engine();
+    }
+}
+
+pub fn request_id() -> RequestId {
+    RequestId {
+        unique_id: 0,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    }
+}
+
+/// Run a single iteration of a scenario and print the I/O operations it performed
+#[cfg(feature = "instrumentation")]
+pub fn report_io<F: FnOnce()>(scenario: &str, run: F) {
+    use deneb_core::instrument::{io_stats, reset_io_stats};
+    reset_io_stats();
+    run();
+    println!("I/O operations for {}: {:#?}", scenario, io_stats());
+}
+
+#[cfg(not(feature = "instrumentation"))]
+pub fn report_io<F: FnOnce()>(_scenario: &str, _run: F) {}
@@ -0,0 +1,29 @@
+use {
+    criterion::{criterion_group, criterion_main, Benchmark, Criterion, Throughput},
+    deneb_core::cas::Algorithm,
+    rand::{thread_rng, RngCore},
+};
+
+mod common;
+
+fn hash(c: &mut Criterion, algorithm: Algorithm) {
+    deneb_core::init().unwrap();
+    let mut data = vec![0 as u8; common::CHUNK_SIZE];
+    thread_rng().fill_bytes(data.as_mut());
+
+    c.bench(
+        "digest",
+        Benchmark::new(algorithm.to_string(), move |b| {
+            b.iter(|| algorithm.hash(&data))
+        })
+        .throughput(Throughput::Bytes(common::CHUNK_SIZE as u32)),
+    );
+}
+
+fn digest(c: &mut Criterion) {
+    hash(c, Algorithm::Sha512);
+    hash(c, Algorithm::Blake3);
+}
+
+criterion_group!(benches, digest);
+criterion_main!(benches);
@@ -0,0 +1,155 @@
+use {
+    common::{request_id, BenchEngine, ROOT_INDEX},
+    criterion::{criterion_group, criterion_main, Benchmark, Criterion, Throughput},
+    deneb_core::store::StoreType,
+    rand::{thread_rng, Rng},
+};
+
+mod common;
+
+const FILE_SIZE: usize = 16 * 1024 * 1024; // 16MB
+const SEQUENTIAL_BLOCK_SIZE: usize = 128 * 1024; // 128KB
+const RANDOM_BLOCK_SIZE: usize = 4096;
+const RANDOM_OPS: usize = 256;
+
+fn sequential_write(c: &mut Criterion) {
+    let engine = BenchEngine::start(StoreType::InMemory).unwrap();
+    let index = engine.create_file(ROOT_INDEX, "sequential_write").unwrap();
+
+    common::report_io("sequential_write", || {
+        engine
+            .write_file(index, FILE_SIZE, SEQUENTIAL_BLOCK_SIZE)
+            .unwrap();
+    });
+
+    c.bench(
+        "workspace",
+        Benchmark::new("sequential_write", move |b| {
+            b.iter(|| {
+                engine.truncate(index, 0).unwrap();
+                engine
+                    .write_file(index, FILE_SIZE, SEQUENTIAL_BLOCK_SIZE)
+                    .unwrap();
+            })
+        })
+        .sample_size(10)
+        .throughput(Throughput::Bytes(FILE_SIZE as u32)),
+    );
+}
+
+fn random_write(c: &mut Criterion) {
+    let engine = BenchEngine::start(StoreType::InMemory).unwrap();
+    let index = engine.create_file(ROOT_INDEX, "random_write").unwrap();
+    let block = vec![0x5a as u8; RANDOM_BLOCK_SIZE];
+
+    let write_random_blocks = move |engine: &BenchEngine| {
+        let mut rng = thread_rng();
+        for _ in 0..RANDOM_OPS {
+            let offset = rng.gen_range(0, FILE_SIZE - RANDOM_BLOCK_SIZE);
+            engine
+                .handle
+                .write_data(&request_id(), index, offset as i64, block.clone())
+                .unwrap();
+        }
+    };
+
+    common::report_io("random_write", || {
+        engine.truncate(index, FILE_SIZE as u64).unwrap();
+        write_random_blocks(&engine);
+    });
+
+    c.bench(
+        "workspace",
+        Benchmark::new("random_write", move |b| {
+            b.iter(|| {
+                // Start each iteration from a sparse file, to bound the size of the upper layer
+                engine.truncate(index, 0).unwrap();
+                engine.truncate(index, FILE_SIZE as u64).unwrap();
+                write_random_blocks(&engine);
+            })
+        })
+        .sample_size(10)
+        .throughput(Throughput::Bytes((RANDOM_OPS * RANDOM_BLOCK_SIZE) as u32)),
+    );
+}
+
+// Create a committed file, so that reads are served from the lower layer
+fn committed_file(name: &str) -> (BenchEngine, u64) {
+    let engine = BenchEngine::start(StoreType::InMemory).unwrap();
+    let index = engine.create_file(ROOT_INDEX, name).unwrap();
+    engine
+        .write_file(index, FILE_SIZE, SEQUENTIAL_BLOCK_SIZE)
+        .unwrap();
+    engine.handle.commit(None).unwrap();
+    engine.handle.open_file(&request_id(), index, 0).unwrap();
+    (engine, index)
+}
+
+fn sequential_read(c: &mut Criterion) {
+    let (engine, index) = committed_file("sequential_read");
+
+    let read_file = move |engine: &BenchEngine| {
+        let mut offset = 0;
+        while offset < FILE_SIZE {
+            engine
+                .handle
+                .read_data(
+                    &request_id(),
+                    index,
+                    offset as i64,
+                    SEQUENTIAL_BLOCK_SIZE as u32,
+                )
+                .unwrap();
+            offset += SEQUENTIAL_BLOCK_SIZE;
+        }
+    };
+
+    common::report_io("sequential_read", || read_file(&engine));
+
+    c.bench(
+        "workspace",
+        Benchmark::new("sequential_read", move |b| b.iter(|| read_file(&engine)))
+            .sample_size(10)
+            .throughput(Throughput::Bytes(FILE_SIZE as u32)),
+    );
+}
+
+fn random_read(c: &mut Criterion) {
+    let (engine, index) = committed_file("random_read");
+
+    let read_random_blocks = move |engine: &BenchEngine| {
+        let mut rng = thread_rng();
+        for _ in 0..RANDOM_OPS {
+            let offset = rng.gen_range(0, FILE_SIZE - RANDOM_BLOCK_SIZE);
+            engine
+                .handle
+                .read_data(
+                    &request_id(),
+                    index,
+                    offset as i64,
+                    RANDOM_BLOCK_SIZE as u32,
+                )
+                .unwrap();
+        }
+    };
+
+    common::report_io("random_read", || read_random_blocks(&engine));
+
+    c.bench(
+        "workspace",
+        Benchmark::new("random_read", move |b| {
+            b.iter(|| read_random_blocks(&engine))
+        })
+        .sample_size(10)
+        .throughput(Throughput::Bytes((RANDOM_OPS * RANDOM_BLOCK_SIZE) as u32)),
+    );
+}
+
+criterion_group!(
+    benches,
+    sequential_write,
+    random_write,
+    sequential_read,
+    random_read
+);
+criterion_main!(benches);
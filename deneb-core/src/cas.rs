@@ -1,19 +1,177 @@
 use {
     crate::errors::{DenebError, DenebResult},
+    blake3::Hasher as Blake3Hasher,
     data_encoding::HEXLOWER,
     serde::{
         de::{Error, Visitor},
         Deserialize, Deserializer, Serialize, Serializer,
     },
-    sodiumoxide::crypto::hash::{hash as sodium_hash, sha512::Digest as SodiumDigest},
-    std::fmt::{Display, Formatter, Result as FmtResult},
+    sodiumoxide::crypto::hash::{
+        hash as sodium_hash,
+        sha512::{Digest as SodiumDigest, State},
+    },
+    std::{
+        fmt::{Display, Formatter, Result as FmtResult},
+        io::{Read, Result as IoResult},
+        str::FromStr,
+    },
 };
 
+/// Length of the BLAKE3 digests, in bytes
+const BLAKE3_LEN: usize = 32;
+
+/// Hash function with which the digests of the chunks of a repository are
+/// computed
+///
+/// The algorithm is chosen when the repository is created. The length of a
+/// digest tells which algorithm produced it, so chunks are verified with the
+/// right one whatever the algorithm of the repository.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    /// SHA-512, the algorithm of the repositories created before it could be
+    /// chosen
+    Sha512,
+    /// BLAKE3, which is several times faster than SHA-512
+    Blake3,
+}
+
+impl Default for Algorithm {
+    fn default() -> Algorithm {
+        Algorithm::Sha512
+    }
+}
+
+impl Algorithm {
+    pub fn hash(self, msg: &[u8]) -> Digest {
+        match self {
+            Algorithm::Sha512 => Digest(Inner::Sha512(sodium_hash(msg))),
+            Algorithm::Blake3 => Digest(Inner::Blake3(*blake3::hash(msg).as_bytes())),
+        }
+    }
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Algorithm::Sha512 => write!(f, "sha512"),
+            Algorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> DenebResult<Algorithm> {
+        match s {
+            "sha512" => Ok(Algorithm::Sha512),
+            "blake3" => Ok(Algorithm::Blake3),
+            _ => Err(DenebError::UnknownAlgorithm(s.to_owned()).into()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Digest(Inner);
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-pub struct Digest(SodiumDigest);
+enum Inner {
+    Sha512(SodiumDigest),
+    Blake3([u8; BLAKE3_LEN]),
+}
+
+impl Digest {
+    pub fn algorithm(&self) -> Algorithm {
+        match self.0 {
+            Inner::Sha512(_) => Algorithm::Sha512,
+            Inner::Blake3(_) => Algorithm::Blake3,
+        }
+    }
+
+    /// Whether `data` hashes to this digest, with the algorithm of the digest
+    pub(crate) fn matches(&self, data: &[u8]) -> bool {
+        self.algorithm().hash(data) == *self
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        match self.0 {
+            Inner::Sha512(SodiumDigest(ref digest)) => digest,
+            Inner::Blake3(ref digest) => digest,
+        }
+    }
+}
 
+/// Computes the SHA-512 digest of a message
 pub fn hash(msg: &[u8]) -> Digest {
-    Digest(sodium_hash(msg))
+    Algorithm::Sha512.hash(msg)
+}
+
+/// Computes the digest of a message which is received in several parts
+///
+/// The resulting digest is the same as the one returned by `Algorithm::hash`
+/// for the concatenation of all the parts.
+pub(crate) struct Hasher(HasherState);
+
+enum HasherState {
+    Sha512(State),
+    Blake3(Box<Blake3Hasher>),
+}
+
+impl Hasher {
+    pub(crate) fn new() -> Hasher {
+        Hasher::with_algorithm(Algorithm::Sha512)
+    }
+
+    pub(crate) fn with_algorithm(algorithm: Algorithm) -> Hasher {
+        Hasher(match algorithm {
+            Algorithm::Sha512 => HasherState::Sha512(State::new()),
+            Algorithm::Blake3 => HasherState::Blake3(Box::new(Blake3Hasher::new())),
+        })
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self.0 {
+            HasherState::Sha512(ref mut state) => state.update(data),
+            HasherState::Blake3(ref mut hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> Digest {
+        match self.0 {
+            HasherState::Sha512(state) => Digest(Inner::Sha512(state.finalize())),
+            HasherState::Blake3(hasher) => Digest(Inner::Blake3(*hasher.finalize().as_bytes())),
+        }
+    }
+}
+
+/// Computes the digest of the data which is read through it
+pub(crate) struct HashingReader<R> {
+    reader: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub(crate) fn new(reader: R) -> HashingReader<R> {
+        HashingReader {
+            reader,
+            hasher: Hasher::new(),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Digest {
+        self.hasher.finish()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let n = self.reader.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
 }
 
 /// Reads bytes from an input source and produces a series of chunks
@@ -62,8 +220,15 @@ pub(crate) fn read_chunked<R: ::std::io::Read>(
 
 impl Display for Digest {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        let &Digest(SodiumDigest(digest)) = self;
-        write!(f, "{}", HEXLOWER.encode(&digest))
+        write!(f, "{}", HEXLOWER.encode(self.as_bytes()))
+    }
+}
+
+impl FromStr for Digest {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> DenebResult<Digest> {
+        digest_from_slice(s.as_bytes())
     }
 }
 
@@ -88,7 +253,9 @@ impl<'de> Deserialize<'de> for Digest {
             type Value = Digest;
 
             fn expecting(&self, formatter: &mut Formatter) -> FmtResult {
-                formatter.write_str("A string representing a HEXLOWER encoding of a SHA512 digest")
+                formatter.write_str(
+                    "A string representing a HEXLOWER encoding of a SHA512 or BLAKE3 digest",
+                )
             }
 
             fn visit_str<E>(self, v: &str) -> ::std::result::Result<Self::Value, E>
@@ -103,10 +270,14 @@ impl<'de> Deserialize<'de> for Digest {
     }
 }
 
-fn digest_from_slice(s: &[u8]) -> DenebResult<Digest> {
+pub(crate) fn digest_from_slice(s: &[u8]) -> DenebResult<Digest> {
     let decoded = HEXLOWER.decode(s)?;
-    if let Some(sd) = SodiumDigest::from_slice(decoded.as_slice()) {
-        Ok(Digest(sd))
+    if decoded.len() == BLAKE3_LEN {
+        let mut digest = [0; BLAKE3_LEN];
+        digest.copy_from_slice(&decoded);
+        Ok(Digest(Inner::Blake3(digest)))
+    } else if let Some(sd) = SodiumDigest::from_slice(decoded.as_slice()) {
+        Ok(Digest(Inner::Sha512(sd)))
     } else {
         Err(DenebError::DigestFromSlice.into())
     }
@@ -131,6 +302,44 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn blake3_digests_are_told_apart() -> DenebResult<()> {
+        let digest = Algorithm::Blake3.hash(b"alabalaportocala");
+        assert_eq!(digest.algorithm(), Algorithm::Blake3);
+        assert_eq!(digest.to_string().len(), 64);
+        assert_eq!(digest_from_slice(digest.to_string().as_bytes())?, digest);
+        assert_ne!(digest, hash(b"alabalaportocala"));
+        assert!(digest.matches(b"alabalaportocala"));
+        assert!(!digest.matches(b"40 de turcaleti"));
+
+        let mut hasher = Hasher::with_algorithm(Algorithm::Blake3);
+        hasher.update(b"alabala");
+        hasher.update(b"portocala");
+        assert_eq!(hasher.finish(), digest);
+
+        assert_eq!("blake3".parse::<Algorithm>()?, Algorithm::Blake3);
+        assert_eq!(Algorithm::Sha512.to_string(), "sha512");
+        assert!("md5".parse::<Algorithm>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn incremental_hash_matches_hash() -> DenebResult<()> {
+        let mut contents = vec![0 as u8; 10000];
+        thread_rng().fill_bytes(contents.as_mut());
+        let mut hasher = Hasher::new();
+        for part in contents.chunks(333) {
+            hasher.update(part);
+        }
+        assert_eq!(hasher.finish(), hash(&contents));
+
+        let mut reader = HashingReader::new(contents.as_slice());
+        let mut buffer = vec![0 as u8; 333];
+        read_chunked(&mut reader, &mut buffer, |_| Ok(()))?;
+        assert_eq!(reader.finish(), hash(&contents));
+        Ok(())
+    }
+
     fn helper(file_size: usize, chunk_size: u64) -> DenebResult<bool> {
         let mut contents = vec![0 as u8; file_size];
         thread_rng().fill_bytes(contents.as_mut());
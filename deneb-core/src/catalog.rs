@@ -1,11 +1,22 @@
 use {
     crate::{errors::DenebResult, inode::INode},
-    std::path::{Path, PathBuf},
+    std::{
+        cmp::max,
+        path::{Path, PathBuf},
+    },
+    time::now_utc,
 };
 
 mod lmdb;
 mod mem;
 
+#[cfg(test)]
+pub(crate) use self::lmdb::write_decimal_keys;
+
+/// Index of the root directory of new catalogs, and of the catalogs written
+/// before the root was recorded
+pub const ROOT_INDEX: u64 = 1;
+
 #[derive(Clone, Copy)]
 pub enum CatalogType {
     InMemory,
@@ -17,30 +28,97 @@ pub fn open_catalog<P: AsRef<Path>>(
     path: P,
     create: bool,
 ) -> DenebResult<Box<dyn Catalog>> {
-    Ok(match catalog_type {
-        CatalogType::InMemory => Box::new(mem::MemCatalog::new()),
+    let catalog: Box<dyn Catalog> = match catalog_type {
+        CatalogType::InMemory => Box::new(mem::MemCatalog::open(path.as_ref(), create)?),
         CatalogType::Lmdb => Box::new(lmdb::LmdbCatalog::open(path.as_ref(), create)?),
-    })
+    };
+
+    #[cfg(feature = "instrumentation")]
+    let catalog: Box<dyn Catalog> =
+        Box::new(crate::instrument::InstrumentedCatalog::new(catalog));
+
+    Ok(catalog)
+}
+
+/// Open an existing catalog for reading only
+///
+/// An LMDB catalog can be read this way while an engine in another process
+/// writes to it; the readers see the transactions it committed.
+pub fn open_catalog_read_only<P: AsRef<Path>>(
+    catalog_type: CatalogType,
+    path: P,
+) -> DenebResult<Box<dyn Catalog>> {
+    let catalog: Box<dyn Catalog> = match catalog_type {
+        CatalogType::InMemory => Box::new(mem::MemCatalog::open_read_only(path.as_ref())?),
+        CatalogType::Lmdb => Box::new(lmdb::LmdbCatalog::open_read_only(path.as_ref())?),
+    };
+
+    #[cfg(feature = "instrumentation")]
+    let catalog: Box<dyn Catalog> =
+        Box::new(crate::instrument::InstrumentedCatalog::new(catalog));
+
+    Ok(catalog)
 }
 
 /// Describes the interface of metadata catalogs
 ///
-pub trait Catalog: Send {
+/// All the methods take `&self`, and a catalog may be shared between threads:
+/// each write is atomic, and the largest index only grows.
+pub trait Catalog: Send + Sync {
     fn show_stats(&self) {}
 
     fn max_index(&self) -> u64;
 
     fn inode(&self, index: u64) -> DenebResult<INode>;
 
+    /// Read several inodes at once, e.g. the children of a directory
+    ///
+    /// Each inode is read separately, in the order of `indices`, and a failure
+    /// to read one of them doesn't prevent reading the others. Catalogs should
+    /// read all of them in a single transaction.
+    fn inodes(&self, indices: &[u64]) -> Vec<DenebResult<INode>> {
+        indices.iter().map(|&index| self.inode(index)).collect()
+    }
+
     fn dir_entry_index(&self, parent: u64, name: &Path) -> DenebResult<Option<u64>>;
 
+    /// The entries of the directory `parent`, sorted by name
     fn dir_entries(&self, parent: u64) -> DenebResult<Vec<(PathBuf, u64)>>;
 
-    fn add_inode(&mut self, inode: &INode) -> DenebResult<()>;
+    fn add_inode(&self, inode: &INode) -> DenebResult<()>;
+
+    /// Link the inode `index` into the directory `parent` under `name`
+    ///
+    /// The link count of the inode isn't updated; it's kept by the caller.
+    fn add_dir_entry(&self, parent: u64, name: &Path, index: u64) -> DenebResult<()>;
 
-    fn add_dir_entry(&mut self, parent: u64, name: &Path, index: u64) -> DenebResult<()>;
+    /// Unlink the entry `name` from the directory `parent`, if it's there
+    fn remove_dir_entry(&self, parent: u64, name: &Path) -> DenebResult<()>;
 
-    fn remove_inode(&mut self, index: u64) -> DenebResult<()>;
+    fn remove_inode(&self, index: u64) -> DenebResult<()>;
+
+    /// Index of the root directory
+    fn root_index(&self) -> DenebResult<u64>;
+
+    fn set_root_index(&self, index: u64) -> DenebResult<()>;
+
+    /// Returns the generation table kept by `Generations`
+    fn generations(&self) -> DenebResult<Vec<(u64, u64)>>;
+
+    fn set_generations(&self, generations: &[(u64, u64)]) -> DenebResult<()>;
+
+    /// Returns the indices of the pinned entries, in increasing order
+    fn pins(&self) -> DenebResult<Vec<u64>>;
+
+    fn set_pins(&self, pins: &[u64]) -> DenebResult<()>;
+
+    /// Save the contents of the catalog which are only held in memory to its
+    /// file, from which it is committed
+    ///
+    /// This is a no-op for catalogs which write every change to their file.
+    fn persist(&self) -> DenebResult<()> {
+        Ok(())
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -59,6 +137,11 @@ impl IndexGenerator {
         IndexGenerator { current_index: i0 }
     }
 
+    /// The last index handed out
+    pub fn current(&self) -> u64 {
+        self.current_index
+    }
+
     pub fn next(&mut self) -> u64 {
         if self.current_index == std::u64::MAX {
             panic!("Index generator: max index reached.")
@@ -67,3 +150,196 @@ impl IndexGenerator {
         self.current_index
     }
 }
+
+/// Generation numbers of the inode indices
+///
+/// The indices handed out after the last commit, e.g. before a crash, are
+/// handed out again after a restart. Each time the catalog is opened, a new
+/// generation starts for the indices above the largest committed one, so that
+/// an index and its generation never identify two different inodes. The
+/// generations are numbered after the time they started, such that they grow
+/// even when the table of the previous session wasn't committed.
+///
+/// The table holds the first index of each generation, in increasing order.
+/// Indices below the first one have generation 0.
+pub(crate) struct Generations {
+    table: Vec<(u64, u64)>,
+}
+
+impl Generations {
+    /// Start a new generation and save it in the catalog
+    pub(crate) fn start(catalog: &dyn Catalog) -> DenebResult<Generations> {
+        let first_index = catalog.max_index() + 1;
+        let mut table = catalog.generations()?;
+        let last = table.last().map_or(0, |&(_, generation)| generation);
+        // No committed inode belongs to the generations starting above the
+        // largest committed index
+        while table
+            .last()
+            .map_or(false, |&(index, _)| index >= first_index)
+        {
+            table.pop();
+        }
+        let started = now_utc().to_timespec().sec as u64;
+        table.push((first_index, max(started, last + 1)));
+        catalog.set_generations(&table)?;
+        Ok(Generations { table })
+    }
+
+    pub(crate) fn of(&self, index: u64) -> u64 {
+        self.table
+            .iter()
+            .rev()
+            .find(|&&(first_index, _)| first_index <= index)
+            .map_or(0, |&(_, generation)| generation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::inode::FileAttributes,
+        std::{sync::Arc, thread},
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn reused_indices_get_new_generations() -> DenebResult<()> {
+        let catalog = open_catalog(CatalogType::InMemory, "/", true)?;
+        let mut attributes = FileAttributes::default();
+        attributes.index = 2;
+        catalog.add_inode(&INode::new(attributes, vec![]))?;
+
+        let first = Generations::start(&*catalog)?;
+        assert_eq!(first.of(2), 0);
+        assert!(first.of(3) > 0);
+
+        // Nothing was committed in the first session, so index 3 is reused
+        let second = Generations::start(&*catalog)?;
+        assert_eq!(catalog.generations()?.len(), 1);
+        assert!(second.of(3) > first.of(3));
+
+        attributes.index = 3;
+        catalog.add_inode(&INode::new(attributes, vec![]))?;
+        let third = Generations::start(&*catalog)?;
+        assert_eq!(third.of(3), second.of(3));
+        assert!(third.of(4) > second.of(4));
+        assert_eq!(third.of(2), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn inodes_are_read_together() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_catalog_inodes")?;
+        for catalog_type in &[CatalogType::InMemory, CatalogType::Lmdb] {
+            let catalog = open_catalog(*catalog_type, tmp.path().join("catalog"), true)?;
+            for index in &[2, 4] {
+                let mut attributes = FileAttributes::default();
+                attributes.index = *index;
+                catalog.add_inode(&INode::new(attributes, vec![]))?;
+            }
+
+            // The missing inode doesn't prevent reading the others
+            let inodes = catalog.inodes(&[4, 3, 2]);
+            assert_eq!(inodes.len(), 3);
+            assert_eq!(inodes[0].as_ref().unwrap().attributes.index, 4);
+            assert!(inodes[1].is_err());
+            assert_eq!(inodes[2].as_ref().unwrap().attributes.index, 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn catalogs_are_written_from_several_threads() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_catalog_threads")?;
+        for catalog_type in &[CatalogType::InMemory, CatalogType::Lmdb] {
+            let catalog: Arc<dyn Catalog> = Arc::from(open_catalog(
+                *catalog_type,
+                tmp.path().join("catalog"),
+                true,
+            )?);
+            let writers = (0..4u64)
+                .map(|t| {
+                    let catalog = Arc::clone(&catalog);
+                    thread::spawn(move || -> DenebResult<()> {
+                        for i in 0..50 {
+                            let mut attributes = FileAttributes::default();
+                            attributes.index = 2 + t * 50 + i;
+                            catalog.add_inode(&INode::new(attributes, vec![]))?;
+                            let name = PathBuf::from(format!("{}", attributes.index));
+                            catalog.add_dir_entry(ROOT_INDEX, &name, attributes.index)?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            for writer in writers {
+                writer.join().unwrap()?;
+            }
+
+            assert_eq!(catalog.max_index(), 201);
+            assert_eq!(catalog.dir_entries(ROOT_INDEX)?.len(), 200);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn persisted_catalogs_are_reopened() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_catalog_persist")?;
+        for (catalog_type, name) in &[(CatalogType::InMemory, "mem"), (CatalogType::Lmdb, "lmdb")] {
+            let path = tmp.path().join(name);
+            {
+                let catalog = open_catalog(*catalog_type, &path, true)?;
+                let mut attributes = FileAttributes::default();
+                attributes.index = 2;
+                catalog.add_inode(&INode::new(attributes, vec![]))?;
+                catalog.add_dir_entry(ROOT_INDEX, Path::new("notes.txt"), 2)?;
+                catalog.add_dir_entry(ROOT_INDEX, Path::new("notes.txt"), 3)?;
+                catalog.set_root_index(ROOT_INDEX)?;
+                catalog.set_pins(&[2])?;
+                catalog.persist()?;
+            }
+
+            let catalog = open_catalog(*catalog_type, &path, false)?;
+            assert_eq!(catalog.max_index(), 2);
+            assert_eq!(catalog.inode(2)?.attributes.index, 2);
+            assert_eq!(
+                catalog.dir_entries(ROOT_INDEX)?,
+                vec![(PathBuf::from("notes.txt"), 3)]
+            );
+            assert_eq!(catalog.pins()?, vec![2]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_catalogs_follow_the_writer() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_catalog_read_only")?;
+        let path = tmp.path().join("catalog");
+        assert!(open_catalog_read_only(CatalogType::Lmdb, &path).is_err());
+
+        let writer = open_catalog(CatalogType::Lmdb, &path, true)?;
+        let reader = open_catalog_read_only(CatalogType::Lmdb, &path)?;
+        let mut attributes = FileAttributes::default();
+        attributes.index = 2;
+        writer.add_inode(&INode::new(attributes, vec![]))?;
+        writer.add_dir_entry(ROOT_INDEX, Path::new("notes.txt"), 2)?;
+        assert_eq!(reader.inode(2)?.attributes.index, 2);
+        assert_eq!(
+            reader.dir_entries(ROOT_INDEX)?,
+            vec![(PathBuf::from("notes.txt"), 2)]
+        );
+        assert!(reader
+            .add_dir_entry(ROOT_INDEX, Path::new("other"), 2)
+            .is_err());
+
+        let mem_path = tmp.path().join("mem");
+        let catalog = open_catalog(CatalogType::InMemory, &mem_path, true)?;
+        catalog.persist()?;
+        let reader = open_catalog_read_only(CatalogType::InMemory, &mem_path)?;
+        assert!(reader.persist().is_err());
+        Ok(())
+    }
+}
@@ -1,30 +1,51 @@
 use {
     super::*,
-    crate::errors::CatalogError,
+    crate::{
+        errors::CatalogError,
+        inode::{LegacyDigestINode, LegacyINode},
+    },
     ::lmdb::{
-        Database, DatabaseFlags, Environment, EnvironmentFlags, Error as LmdbError, Transaction,
-        WriteFlags,
+        Cursor, Database, DatabaseFlags, Environment, EnvironmentFlags, Error as LmdbError,
+        RwTransaction, Transaction, WriteFlags,
     },
     bincode::{deserialize, serialize},
     failure::ResultExt,
-    lmdb_sys::{mdb_env_info, mdb_env_stat, MDB_envinfo, MDB_stat},
+    lmdb_sys::{
+        mdb_env_info, mdb_env_stat, MDB_envinfo, MDB_stat, MDB_FIRST, MDB_GET_BOTH,
+        MDB_GET_BOTH_RANGE, MDB_NEXT_DUP, MDB_SET_KEY,
+    },
     log::info,
-    std::{collections::BTreeMap, str::from_utf8},
+    std::{
+        collections::BTreeMap,
+        ffi::OsStr,
+        os::unix::ffi::OsStrExt,
+        str::from_utf8,
+        sync::atomic::{AtomicU64, Ordering},
+    },
 };
 
 const MAX_CATALOG_SIZE: usize = 100 * 1024 * 1024; // 100MB
 const MAX_CATALOG_READERS: u32 = 100;
-const MAX_CATALOG_DBS: u32 = 3;
+const MAX_CATALOG_DBS: u32 = 4;
 
-const CATALOG_VERSION: u32 = 1;
+const CATALOG_VERSION: u32 = 3;
+/// Catalogs before this version kept the entries of each directory in a
+/// single value
+const SORTED_DIR_ENTRIES_VERSION: u32 = 2;
+/// Catalogs before this version were keyed by the decimal inode indices
+const BINARY_KEYS_VERSION: u32 = 3;
 
 // Note: Could be enhanced with an in-memory LRU cache
 /// A filesystem metadata catalog backed by an LMDB database
+///
+/// The entries of a directory are the duplicates of its key in a sorted
+/// duplicates database, so that adding one to a large directory doesn't
+/// rewrite all the others.
 pub(super) struct LmdbCatalog {
     env: Environment,
     inodes: Database,
     dir_entries: Database,
-    max_index: u64,
+    max_index: AtomicU64,
     meta: Database,
     version: u32,
 }
@@ -57,6 +78,31 @@ impl LmdbCatalog {
         if ver > CATALOG_VERSION {
             return Err(CatalogError::Version(ver).into());
         }
+        if ver < CATALOG_VERSION {
+            // All the changes are made in one transaction, so that a catalog
+            // is never left half migrated
+            let legacy_dir_entries = match env.open_db(Some("dir_entries")) {
+                Ok(db) => Some(db),
+                Err(LmdbError::NotFound) => None,
+                Err(e) => return Err(e.into()),
+            };
+            let mut writer = env.begin_rw_txn()?;
+            if ver < BINARY_KEYS_VERSION {
+                migrate_keys(&mut writer, inodes)?;
+                migrate_keys(&mut writer, dir_entries)?;
+            }
+            if let (true, Some(legacy)) = (ver < SORTED_DIR_ENTRIES_VERSION, legacy_dir_entries) {
+                migrate_dir_entries(&mut writer, legacy, dir_entries)?;
+            }
+            writer.put(
+                meta,
+                &"catalog_version",
+                &format!("{}", CATALOG_VERSION),
+                WriteFlags::empty(),
+            )?;
+            writer.commit()?;
+            info!("Migrated LMDB catalog {:?} from version {}.", path, ver);
+        }
 
         // Retrieve the largest inode index in the catalog
         let max_index = {
@@ -71,9 +117,42 @@ impl LmdbCatalog {
             env,
             inodes,
             dir_entries,
-            max_index,
+            max_index: AtomicU64::new(max_index),
             meta,
-            version: ver,
+            version: CATALOG_VERSION,
+        })
+    }
+
+    /// Open an existing catalog without writing to it, e.g. the catalog of
+    /// an engine running in another process
+    ///
+    /// Catalogs of older versions are refused, since migrating them means
+    /// writing to them.
+    pub(super) fn open_read_only(path: &Path) -> DenebResult<LmdbCatalog> {
+        let env = open_environment(path, EnvironmentFlags::READ_ONLY)?;
+        let inodes = env.open_db(Some("inodes"))?;
+        let dir_entries = env.open_db(Some("dir_entry_list"))?;
+        let meta = env.open_db(Some("meta"))?;
+
+        let (ver, max_index) = {
+            let reader = env.begin_ro_txn()?;
+            let ver = from_utf8(reader.get(meta, &"catalog_version")?)?.parse::<u32>()?;
+            let max_index = from_utf8(reader.get(meta, &"max_index")?)?.parse::<u64>()?;
+            (ver, max_index)
+        };
+        if ver != CATALOG_VERSION {
+            return Err(CatalogError::Version(ver).into());
+        }
+
+        info!("Opened LMDB catalog {:?} read-only.", path);
+
+        Ok(LmdbCatalog {
+            env,
+            inodes,
+            dir_entries,
+            max_index: AtomicU64::new(max_index),
+            meta,
+            version: CATALOG_VERSION,
         })
     }
 }
@@ -104,61 +183,79 @@ impl Catalog for LmdbCatalog {
     }
 
     fn max_index(&self) -> u64 {
-        self.max_index
+        self.max_index.load(Ordering::Acquire)
     }
 
     fn inode(&self, index: u64) -> DenebResult<INode> {
         let reader = self.env.begin_ro_txn()?;
-        let buffer = reader
-            .get(self.inodes, &format!("{}", index))
-            .context(CatalogError::INodeRead(index))?;
-        deserialize::<INode>(buffer)
-            .context(CatalogError::INodeDeserialization(index))
-            .map_err(std::convert::Into::into)
+        read_inode(&reader, self.inodes, index)
+    }
+
+    fn inodes(&self, indices: &[u64]) -> Vec<DenebResult<INode>> {
+        match self.env.begin_ro_txn() {
+            Ok(reader) => indices
+                .iter()
+                .map(|&index| read_inode(&reader, self.inodes, index))
+                .collect(),
+            Err(e) => indices
+                .iter()
+                .map(|&index| {
+                    Err(e)
+                        .context(CatalogError::INodeRead(index))
+                        .map_err(std::convert::Into::into)
+                })
+                .collect(),
+        }
     }
 
     fn dir_entry_index(&self, parent: u64, name: &Path) -> DenebResult<Option<u64>> {
         let reader = self.env.begin_ro_txn()?;
-        let buffer = reader
-            .get(self.dir_entries, &format!("{}", parent))
-            .context(CatalogError::DEntryRead(parent))?;
-        let entries = deserialize::<BTreeMap<PathBuf, u64>>(buffer)
-            .context(CatalogError::DEntryDeserialization(parent))?;
-        Ok(entries.get(name).cloned())
+        find_dir_entry(&reader, self.dir_entries, parent, name)
     }
 
     fn dir_entries(&self, parent: u64) -> DenebResult<Vec<(PathBuf, u64)>> {
         let reader = self.env.begin_ro_txn()?;
-        let buffer = reader
-            .get(self.dir_entries, &format!("{}", parent))
-            .context(CatalogError::DEntryRead(parent))?;
-        let entries = deserialize::<BTreeMap<PathBuf, u64>>(buffer)
-            .context(CatalogError::DEntryDeserialization(parent))?;
-        Ok(entries
-            .iter()
-            .map(|(name, index)| (name.to_owned(), *index))
-            .collect::<Vec<(PathBuf, u64)>>())
+        let cursor = reader.open_ro_cursor(self.dir_entries)?;
+        let key = index_key(parent);
+        let mut entries = Vec::new();
+        let mut next = cursor.get(Some(&key[..]), None, MDB_SET_KEY);
+        loop {
+            match next {
+                Ok((_, value)) => {
+                    let (name, index) = parse_dir_entry(value)
+                        .ok_or_else(|| CatalogError::DEntryDeserialization(parent))?;
+                    entries.push((PathBuf::from(OsStr::from_bytes(name)), index));
+                }
+                Err(LmdbError::NotFound) => break,
+                Err(e) => {
+                    return Err(e)
+                        .context(CatalogError::DEntryRead(parent))
+                        .map_err(std::convert::Into::into)
+                }
+            }
+            next = cursor.get(None, None, MDB_NEXT_DUP);
+        }
+        // Directories always have at least the "." and ".." entries
+        if entries.is_empty() {
+            return Err(CatalogError::DEntryRead(parent).into());
+        }
+        Ok(entries)
     }
 
-    fn add_inode(&mut self, inode: &INode) -> DenebResult<()> {
+    fn add_inode(&self, inode: &INode) -> DenebResult<()> {
         let index = inode.attributes.index;
         let buffer = serialize(&inode).context(CatalogError::INodeSerialization(index))?;
 
+        // The largest index is read in the write transaction, which LMDB
+        // serializes, so that concurrent writers don't lower it
+        let mut writer = self.env.begin_rw_txn()?;
         let max_index = {
-            let reader = self.env.begin_ro_txn()?;
-            let v = reader.get(self.meta, &"max_index")?;
+            let v = writer.get(self.meta, &"max_index")?;
             from_utf8(v)?.parse::<u64>()
         }?;
 
-        let mut writer = self.env.begin_rw_txn()?;
-
         writer
-            .put(
-                self.inodes,
-                &format!("{}", index),
-                &buffer,
-                WriteFlags::empty(),
-            )
+            .put(self.inodes, &index_key(index), &buffer, WriteFlags::empty())
             .context(CatalogError::INodeWrite(index))?;
 
         if index > max_index {
@@ -168,77 +265,126 @@ impl Catalog for LmdbCatalog {
                 &format!("{}", index),
                 WriteFlags::empty(),
             )?;
-            self.max_index = index;
         }
 
         writer.commit()?;
+        self.max_index.fetch_max(index, Ordering::AcqRel);
 
         Ok(())
     }
 
-    fn add_dir_entry(&mut self, parent: u64, name: &Path, index: u64) -> DenebResult<()> {
+    fn add_dir_entry(&self, parent: u64, name: &Path, index: u64) -> DenebResult<()> {
         let mut writer = self.env.begin_rw_txn()?;
-        {
-            // Retrieve and update dir entries for parent
-            let mut entries = BTreeMap::new();
-            if let Ok(buffer) = writer.get(self.dir_entries, &format!("{}", parent)) {
-                // Dir entries exist for parent
-                entries = deserialize::<BTreeMap<PathBuf, u64>>(buffer)
-                    .context(CatalogError::DEntryDeserialization(parent))?;
-                entries.insert(name.to_owned(), index);
-            } else {
-                // No dir entries exist for parent
-                entries.insert(name.to_owned(), index);
-            }
-
-            // Write updated dir entries to database
-            let buffer = serialize(&entries).context(CatalogError::DEntrySerialization(parent))?;
-            writer
-                .put(
-                    self.dir_entries,
-                    &format!("{}", parent),
-                    &buffer,
-                    WriteFlags::empty(),
-                )
+        let key = index_key(parent);
+        // An entry with the same name is replaced
+        if let Some(previous) = find_dir_entry(&writer, self.dir_entries, parent, name)? {
+            delete_dir_entry(&mut writer, self.dir_entries, parent, name, previous)
                 .context(CatalogError::DEntryWrite(parent))?;
+        }
+        writer
+            .put(
+                self.dir_entries,
+                &key,
+                &dir_entry_value(name, index),
+                WriteFlags::empty(),
+            )
+            .context(CatalogError::DEntryWrite(parent))?;
+        writer.commit()?;
+        Ok(())
+    }
 
-            // Retrieve inode of index
-            let buffer = {
-                let buffer = writer
-                    .get(self.inodes, &format!("{}", index))
-                    .context(CatalogError::INodeRead(index))?;
-                let mut inode = deserialize::<INode>(buffer)
-                    .context(CatalogError::INodeDeserialization(index))?;
+    fn remove_dir_entry(&self, parent: u64, name: &Path) -> DenebResult<()> {
+        let mut writer = self.env.begin_rw_txn()?;
+        let index = match find_dir_entry(&writer, self.dir_entries, parent, name)? {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        delete_dir_entry(&mut writer, self.dir_entries, parent, name, index)
+            .context(CatalogError::DEntryWrite(parent))?;
+        writer.commit()?;
+        Ok(())
+    }
 
-                // Update number of hardlink in inode
-                inode.attributes.nlink += 1;
+    fn remove_inode(&self, index: u64) -> DenebResult<()> {
+        let mut writer = self.env.begin_rw_txn()?;
 
-                // Write inode back to database
-                serialize(&inode).context(CatalogError::INodeSerialization(index))
-            }?;
-            writer
-                .put(
-                    self.inodes,
-                    &format!("{}", index),
-                    &buffer,
-                    WriteFlags::empty(),
-                )
-                .context(CatalogError::INodeWrite(index))?;
+        writer
+            .del(self.inodes, &index_key(index), None)
+            .context(CatalogError::INodeDelete(index))?;
+        // Only directories have entries
+        match writer.del(self.dir_entries, &index_key(index), None) {
+            Ok(()) | Err(LmdbError::NotFound) => {}
+            Err(e) => {
+                return Err(e)
+                    .context(CatalogError::INodeDelete(index))
+                    .map_err(std::convert::Into::into)
+            }
         }
+
         writer.commit()?;
         Ok(())
     }
 
-    fn remove_inode(&mut self, index: u64) -> DenebResult<()> {
+    fn root_index(&self) -> DenebResult<u64> {
+        let reader = self.env.begin_ro_txn()?;
+        let root_index = match reader.get(self.meta, &"root_index") {
+            Ok(v) => from_utf8(v)?.parse::<u64>()?,
+            // Catalogs written before the root was recorded
+            Err(LmdbError::NotFound) => ROOT_INDEX,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(root_index)
+    }
+
+    fn set_root_index(&self, index: u64) -> DenebResult<()> {
         let mut writer = self.env.begin_rw_txn()?;
+        writer.put(
+            self.meta,
+            &"root_index",
+            &format!("{}", index),
+            WriteFlags::empty(),
+        )?;
+        writer.commit()?;
+        Ok(())
+    }
 
-        writer
-            .del(self.inodes, &format!("{}", index), None)
-            .context(CatalogError::INodeDelete(index))?;
-        writer
-            .del(self.dir_entries, &format!("{}", index), None)
-            .context(CatalogError::INodeDelete(index))?;
+    fn generations(&self) -> DenebResult<Vec<(u64, u64)>> {
+        let reader = self.env.begin_ro_txn()?;
+        let generations = match reader.get(self.meta, &"generations") {
+            Ok(buffer) => deserialize(buffer)?,
+            // Catalogs written before generations were kept
+            Err(LmdbError::NotFound) => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        Ok(generations)
+    }
 
+    fn set_generations(&self, generations: &[(u64, u64)]) -> DenebResult<()> {
+        let mut writer = self.env.begin_rw_txn()?;
+        writer.put(
+            self.meta,
+            &"generations",
+            &serialize(generations)?,
+            WriteFlags::empty(),
+        )?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    fn pins(&self) -> DenebResult<Vec<u64>> {
+        let reader = self.env.begin_ro_txn()?;
+        let pins = match reader.get(self.meta, &"pins") {
+            Ok(buffer) => deserialize(buffer)?,
+            // Catalogs written before entries were pinned
+            Err(LmdbError::NotFound) => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        Ok(pins)
+    }
+
+    fn set_pins(&self, pins: &[u64]) -> DenebResult<()> {
+        let mut writer = self.env.begin_rw_txn()?;
+        writer.put(self.meta, &"pins", &serialize(pins)?, WriteFlags::empty())?;
         writer.commit()?;
         Ok(())
     }
@@ -247,27 +393,194 @@ impl Catalog for LmdbCatalog {
 fn init_db<P: AsRef<Path>>(
     path: P,
 ) -> Result<(Environment, Database, Database, Database), LmdbError> {
-    let env = open_environment(path.as_ref())?;
+    let env = open_environment(path.as_ref(), EnvironmentFlags::empty())?;
 
     // Create databases
-    let inodes = try_create_db(&env, "inodes")?;
-    let dir_entries = try_create_db(&env, "dir_entries")?;
-    let meta = try_create_db(&env, "meta")?;
+    let inodes = try_create_db(&env, "inodes", DatabaseFlags::empty())?;
+    let dir_entries = try_create_db(&env, "dir_entry_list", DatabaseFlags::DUP_SORT)?;
+    let meta = try_create_db(&env, "meta", DatabaseFlags::empty())?;
 
     Ok((env, inodes, dir_entries, meta))
 }
 
-fn open_environment(path: &Path) -> Result<Environment, LmdbError> {
+fn open_environment(path: &Path, flags: EnvironmentFlags) -> Result<Environment, LmdbError> {
     Environment::new()
-        .set_flags(EnvironmentFlags::NO_SUB_DIR)
+        .set_flags(EnvironmentFlags::NO_SUB_DIR | flags)
         .set_max_dbs(MAX_CATALOG_DBS)
         .set_max_readers(MAX_CATALOG_READERS)
         .set_map_size(MAX_CATALOG_SIZE)
         .open_with_permissions(path, 0o600)
 }
 
-fn try_create_db(env: &Environment, name: &str) -> Result<Database, LmdbError> {
-    env.create_db(Some(name), DatabaseFlags::empty())
+fn try_create_db(
+    env: &Environment,
+    name: &str,
+    flags: DatabaseFlags,
+) -> Result<Database, LmdbError> {
+    env.create_db(Some(name), flags)
+}
+
+/// Move the dir entries of a catalog written before they were sorted
+/// duplicates into the dir entry list
+fn migrate_dir_entries(
+    writer: &mut RwTransaction,
+    legacy: Database,
+    dir_entries: Database,
+) -> DenebResult<()> {
+    let mut dirs = Vec::new();
+    for (key, buffer) in read_items(&*writer, legacy)? {
+        let parent = from_utf8(&key)?.parse::<u64>()?;
+        let entries = deserialize::<BTreeMap<PathBuf, u64>>(&buffer)
+            .context(CatalogError::DEntryDeserialization(parent))?;
+        dirs.push((parent, entries));
+    }
+    for (parent, entries) in dirs {
+        for (name, index) in entries {
+            writer
+                .put(
+                    dir_entries,
+                    &index_key(parent),
+                    &dir_entry_value(&name, index),
+                    WriteFlags::empty(),
+                )
+                .context(CatalogError::DEntryWrite(parent))?;
+        }
+    }
+    writer.clear_db(legacy)?;
+    Ok(())
+}
+
+/// Returns the keys and values of all the items of `db`
+///
+/// `iter_start` panics on an empty database, so the cursor is positioned on
+/// the first item, if there is one, before iterating over the others.
+fn read_items(txn: &impl Transaction, db: Database) -> DenebResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut cursor = txn.open_ro_cursor(db)?;
+    let mut items = Vec::new();
+    match cursor.get(None, None, MDB_FIRST) {
+        Ok((key, value)) => items.push((key.unwrap_or_default().to_vec(), value.to_vec())),
+        Err(LmdbError::NotFound) => return Ok(items),
+        Err(e) => return Err(e.into()),
+    }
+    for (key, value) in cursor.iter() {
+        items.push((key.to_vec(), value.to_vec()));
+    }
+    Ok(items)
+}
+
+/// Replace the decimal keys of a database with binary ones
+fn migrate_keys(writer: &mut RwTransaction, db: Database) -> DenebResult<()> {
+    let items = read_items(&*writer, db)?;
+    writer.clear_db(db)?;
+    for (key, value) in items {
+        let index = from_utf8(&key)?.parse::<u64>()?;
+        writer.put(db, &index_key(index), &value, WriteFlags::empty())?;
+    }
+    Ok(())
+}
+
+/// Rewrite the catalog at `path` in the version 2 format, keyed by the
+/// decimal inode indices
+#[cfg(test)]
+pub(crate) fn write_decimal_keys(path: &Path) -> DenebResult<()> {
+    let (env, inodes, dir_entries, meta) = init_db(path)?;
+    let mut writer = env.begin_rw_txn()?;
+    for db in &[inodes, dir_entries] {
+        let items = read_items(&writer, *db)?;
+        writer.clear_db(*db)?;
+        for (key, value) in items {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&key);
+            let key = u64::from_be_bytes(bytes).to_string();
+            writer.put(*db, &key, &value, WriteFlags::empty())?;
+        }
+    }
+    writer.put(meta, &"catalog_version", &"2", WriteFlags::empty())?;
+    writer.commit()?;
+    Ok(())
+}
+
+// Keys are the big endian inode indices, which sort numerically and are built
+// without allocating
+fn index_key(index: u64) -> [u8; 8] {
+    index.to_be_bytes()
+}
+
+// A dir entry is stored as its name, which can't contain a null byte, followed
+// by a null byte and the big endian index of the entry, so that the entries
+// of a directory are sorted by name
+fn dir_entry_value(name: &Path, index: u64) -> Vec<u8> {
+    let name = name.as_os_str().as_bytes();
+    let mut value = Vec::with_capacity(name.len() + 9);
+    value.extend_from_slice(name);
+    value.push(0);
+    value.extend_from_slice(&index.to_be_bytes());
+    value
+}
+
+fn parse_dir_entry(value: &[u8]) -> Option<(&[u8], u64)> {
+    if value.len() < 9 || value[value.len() - 9] != 0 {
+        return None;
+    }
+    let (name, index) = value.split_at(value.len() - 9);
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&index[1..]);
+    Some((name, u64::from_be_bytes(bytes)))
+}
+
+/// Delete the entry `name` of `parent`, which has the index `index`
+///
+/// `RwTransaction::del` can't be given the value of a duplicate, so a cursor
+/// is positioned on it instead.
+fn delete_dir_entry(
+    writer: &mut RwTransaction,
+    dir_entries: Database,
+    parent: u64,
+    name: &Path,
+    index: u64,
+) -> Result<(), LmdbError> {
+    let mut cursor = writer.open_rw_cursor(dir_entries)?;
+    let key = index_key(parent);
+    let value = dir_entry_value(name, index);
+    cursor.get(Some(&key[..]), Some(&value), MDB_GET_BOTH)?;
+    cursor.del(WriteFlags::empty())
+}
+
+/// Returns the index of the entry `name` of `parent`, if there is one
+fn find_dir_entry(
+    txn: &impl Transaction,
+    dir_entries: Database,
+    parent: u64,
+    name: &Path,
+) -> DenebResult<Option<u64>> {
+    let cursor = txn.open_ro_cursor(dir_entries)?;
+    let key = index_key(parent);
+    let mut prefix = name.as_os_str().as_bytes().to_vec();
+    prefix.push(0);
+    // The first entry of the directory which isn't sorted before `name`
+    match cursor.get(Some(&key[..]), Some(&prefix), MDB_GET_BOTH_RANGE) {
+        Ok((_, value)) => Ok(parse_dir_entry(value)
+            .filter(|(entry, _)| entry == &&prefix[..prefix.len() - 1])
+            .map(|(_, index)| index)),
+        Err(LmdbError::NotFound) => Ok(None),
+        Err(e) => Err(e)
+            .context(CatalogError::DEntryRead(parent))
+            .map_err(std::convert::Into::into),
+    }
+}
+
+fn read_inode(reader: &impl Transaction, inodes: Database, index: u64) -> DenebResult<INode> {
+    let buffer = reader
+        .get(inodes, &index_key(index))
+        .context(CatalogError::INodeRead(index))?;
+    // Inodes written before the digest of the contents was recorded, or
+    // before the extended attributes were kept, are shorter, and fail to
+    // deserialize as the current version
+    deserialize::<INode>(buffer)
+        .or_else(|_| deserialize::<LegacyDigestINode>(buffer).map(INode::from))
+        .or_else(|_| deserialize::<LegacyINode>(buffer).map(INode::from))
+        .context(CatalogError::INodeDeserialization(index))
+        .map_err(std::convert::Into::into)
 }
 
 fn env_info(env: &Environment) -> MDB_envinfo {
@@ -291,18 +604,22 @@ fn env_stat(env: &Environment) -> MDB_stat {
 #[cfg(test)]
 mod tests {
     use nix::sys::stat::lstat;
+    use serde::Serialize;
     use tempdir::TempDir;
 
     use super::*;
 
-    use crate::inode::FileAttributes;
+    use crate::{
+        cas::{hash, Digest},
+        inode::{ChunkDescriptor, FileAttributes},
+    };
 
     #[test]
     fn lmdb_catalog_create_then_reopen() -> DenebResult<()> {
         let tmp = TempDir::new("/tmp/deneb_lmdb_test")?;
         let catalog_path = tmp.path().to_owned().join("test-lmdb-catalog");
         {
-            let mut catalog = open_catalog(CatalogType::Lmdb, &catalog_path, true)?;
+            let catalog = open_catalog(CatalogType::Lmdb, &catalog_path, true)?;
             catalog.show_stats();
 
             let stats1 = lstat(Path::new("/tmp/"))?;
@@ -313,11 +630,153 @@ mod tests {
             let inode2 = INode::new(attrs2, vec![]);
             assert!(catalog.add_inode(&inode1).is_ok());
             assert!(catalog.add_inode(&inode2).is_ok());
+            assert!(catalog.generations()?.is_empty());
+            catalog.set_generations(&[(4, 1000)])?;
+            assert_eq!(catalog.root_index()?, ROOT_INDEX);
+            catalog.set_root_index(2)?;
+            catalog.add_dir_entry(2, Path::new("usr"), 3)?;
+            catalog.add_dir_entry(2, Path::new("tmp"), 4)?;
+            catalog.remove_dir_entry(2, Path::new("tmp"))?;
         }
         {
             let catalog = open_catalog(CatalogType::Lmdb, &catalog_path, false)?;
             assert_eq!(catalog.max_index(), 3);
+            assert_eq!(catalog.generations()?, vec![(4, 1000)]);
+            assert_eq!(catalog.root_index()?, 2);
+            assert_eq!(catalog.dir_entries(2)?, vec![(PathBuf::from("usr"), 3)]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_inodes_are_read() -> DenebResult<()> {
+        #[derive(Serialize)]
+        struct Legacy {
+            attributes: FileAttributes,
+            chunks: Vec<ChunkDescriptor>,
+        }
+        #[derive(Serialize)]
+        struct LegacyDigest {
+            attributes: FileAttributes,
+            chunks: Vec<ChunkDescriptor>,
+            digest: Option<Digest>,
+        }
+
+        let tmp = TempDir::new("/tmp/deneb_lmdb_legacy")?;
+        let catalog = LmdbCatalog::open(&tmp.path().join("catalog"), true)?;
+        let mut attributes = FileAttributes::default();
+        attributes.index = 2;
+        attributes.size = 16;
+        let legacy = Legacy {
+            attributes,
+            chunks: vec![],
+        };
+        attributes.index = 3;
+        let digest = hash(b"alabalaportocala");
+        let legacy_digest = LegacyDigest {
+            attributes,
+            chunks: vec![],
+            digest: Some(digest),
+        };
+        let mut writer = catalog.env.begin_rw_txn()?;
+        writer.put(
+            catalog.inodes,
+            &index_key(2),
+            &serialize(&legacy)?,
+            WriteFlags::empty(),
+        )?;
+        writer.put(
+            catalog.inodes,
+            &index_key(3),
+            &serialize(&legacy_digest)?,
+            WriteFlags::empty(),
+        )?;
+        writer.commit()?;
+
+        let inode = catalog.inode(2)?;
+        assert_eq!(inode.attributes.size, 16);
+        assert!(inode.digest.is_none());
+        let inode = catalog.inode(3)?;
+        assert_eq!(inode.attributes.size, 16);
+        assert_eq!(inode.digest, Some(digest));
+        assert!(inode.xattrs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_dir_entries_are_migrated() -> DenebResult<()> {
+        let tmp = TempDir::new("/tmp/deneb_lmdb_dir_entries")?;
+        let catalog_path = tmp.path().join("catalog");
+        {
+            let catalog = LmdbCatalog::open(&catalog_path, true)?;
+            let legacy = catalog
+                .env
+                .create_db(Some("dir_entries"), DatabaseFlags::empty())?;
+            let mut entries = BTreeMap::<PathBuf, u64>::new();
+            entries.insert(PathBuf::from("."), 2);
+            entries.insert(PathBuf::from(".."), 2);
+            entries.insert(PathBuf::from("usr"), 3);
+            let mut writer = catalog.env.begin_rw_txn()?;
+            writer.put(legacy, &"2", &serialize(&entries)?, WriteFlags::empty())?;
+            writer.put(catalog.meta, &"catalog_version", &"1", WriteFlags::empty())?;
+            writer.commit()?;
+        }
+
+        let catalog = LmdbCatalog::open(&catalog_path, false)?;
+        assert_eq!(catalog.version, CATALOG_VERSION);
+        assert_eq!(catalog.dir_entry_index(2, Path::new("usr"))?, Some(3));
+        assert_eq!(catalog.dir_entry_index(2, Path::new("us"))?, None);
+        catalog.add_dir_entry(2, Path::new("usr"), 4)?;
+        catalog.add_dir_entry(2, Path::new("us"), 5)?;
+        assert_eq!(
+            catalog.dir_entries(2)?,
+            vec![
+                (PathBuf::from("."), 2),
+                (PathBuf::from(".."), 2),
+                (PathBuf::from("us"), 5),
+                (PathBuf::from("usr"), 4),
+            ]
+        );
+        catalog.remove_dir_entry(2, Path::new("usr"))?;
+        assert_eq!(catalog.dir_entry_index(2, Path::new("usr"))?, None);
+        assert!(catalog.dir_entries(3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decimal_keys_are_migrated() -> DenebResult<()> {
+        let tmp = TempDir::new("/tmp/deneb_lmdb_keys")?;
+        let catalog_path = tmp.path().join("catalog");
+        let mut attributes = FileAttributes::default();
+        attributes.index = 2;
+        attributes.size = 16;
+        {
+            let catalog = LmdbCatalog::open(&catalog_path, true)?;
+            let mut writer = catalog.env.begin_rw_txn()?;
+            let inode = INode::new(attributes, vec![]);
+            writer.put(
+                catalog.inodes,
+                &"2",
+                &serialize(&inode)?,
+                WriteFlags::empty(),
+            )?;
+            for (name, index) in &[(".", 2), ("..", 2), ("usr", 10)] {
+                writer.put(
+                    catalog.dir_entries,
+                    &"2",
+                    &dir_entry_value(Path::new(name), *index),
+                    WriteFlags::empty(),
+                )?;
+            }
+            writer.put(catalog.meta, &"catalog_version", &"2", WriteFlags::empty())?;
+            writer.commit()?;
         }
+
+        let catalog = LmdbCatalog::open(&catalog_path, false)?;
+        assert_eq!(catalog.version, CATALOG_VERSION);
+        assert_eq!(catalog.inode(2)?.attributes.size, 16);
+        assert_eq!(catalog.dir_entry_index(2, Path::new("usr"))?, Some(10));
+        assert_eq!(catalog.dir_entries(2)?.len(), 3);
         Ok(())
     }
 }
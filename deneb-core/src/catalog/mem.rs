@@ -1,31 +1,78 @@
 use {
     super::*,
-    crate::{errors::CatalogError, inode::INode},
+    crate::{errors::CatalogError, inode::INode, util::atomic_write},
+    bincode::{deserialize, serialize},
     log::info,
+    serde::{Deserialize, Serialize},
     std::{
-        collections::HashMap,
+        collections::BTreeMap,
+        fs::read,
         path::{Path, PathBuf},
+        sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
     },
 };
 
-#[derive(Default)]
+/// A catalog held in memory
+///
+/// It is only written to its file when it is persisted, before being
+/// committed, and read back from the file when it is opened.
 pub(super) struct MemCatalog {
-    inodes: HashMap<u64, INode>,
-    dir_entries: HashMap<u64, HashMap<PathBuf, u64>>,
+    path: PathBuf,
+    state: RwLock<State>,
+    read_only: bool,
+}
+
+// The maps are ordered, so that unchanged catalogs are written identically
+#[derive(Default, Deserialize, Serialize)]
+struct State {
+    inodes: BTreeMap<u64, INode>,
+    dir_entries: BTreeMap<u64, BTreeMap<PathBuf, u64>>,
     max_index: u64,
+    root_index: Option<u64>,
+    generations: Vec<(u64, u64)>,
+    pins: Vec<u64>,
 }
 
 impl MemCatalog {
-    pub(super) fn new() -> MemCatalog {
-        Self::default()
+    pub(super) fn open(path: &Path, create: bool) -> DenebResult<MemCatalog> {
+        let state = if create || !path.is_file() {
+            State::default()
+        } else {
+            deserialize(&read(path)?)?
+        };
+        Ok(MemCatalog {
+            path: path.to_owned(),
+            state: RwLock::new(state),
+            read_only: false,
+        })
+    }
+
+    /// Open an existing catalog which is never written back to its file
+    pub(super) fn open_read_only(path: &Path) -> DenebResult<MemCatalog> {
+        Ok(MemCatalog {
+            path: path.to_owned(),
+            state: RwLock::new(deserialize(&read(path)?)?),
+            read_only: true,
+        })
+    }
+
+    // A writer which panicked leaves the maps consistent, so a poisoned lock
+    // is still used
+    fn read(&self) -> RwLockReadGuard<State> {
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<State> {
+        self.state.write().unwrap_or_else(|e| e.into_inner())
     }
 }
 
 impl Catalog for MemCatalog {
     fn show_stats(&self) {
-        info!("Catalog stats: number of inodes: {}", self.inodes.len());
+        let state = self.read();
+        info!("Catalog stats: number of inodes: {}", state.inodes.len());
         info!("Directory entries:");
-        for (k1, v1) in &self.dir_entries {
+        for (k1, v1) in &state.dir_entries {
             for (k2, v2) in v1.iter() {
                 info!("  parent: {}, path: {:?}, inode: {}", k1, k2, v2);
             }
@@ -33,18 +80,34 @@ impl Catalog for MemCatalog {
     }
 
     fn max_index(&self) -> u64 {
-        self.max_index
+        self.read().max_index
     }
 
     fn inode(&self, index: u64) -> DenebResult<INode> {
-        self.inodes
+        self.read()
+            .inodes
             .get(&index)
             .cloned()
             .ok_or_else(|| CatalogError::INodeRead(index).into())
     }
 
+    fn inodes(&self, indices: &[u64]) -> Vec<DenebResult<INode>> {
+        let state = self.read();
+        indices
+            .iter()
+            .map(|index| {
+                state
+                    .inodes
+                    .get(index)
+                    .cloned()
+                    .ok_or_else(|| CatalogError::INodeRead(*index).into())
+            })
+            .collect()
+    }
+
     fn dir_entry_index(&self, parent: u64, name: &Path) -> DenebResult<Option<u64>> {
         Ok(self
+            .read()
             .dir_entries
             .get(&parent)
             .and_then(|entries| entries.get(name))
@@ -52,7 +115,8 @@ impl Catalog for MemCatalog {
     }
 
     fn dir_entries(&self, parent: u64) -> DenebResult<Vec<(PathBuf, u64)>> {
-        self.dir_entries
+        self.read()
+            .dir_entries
             .get(&parent)
             .map(|entries| {
                 entries
@@ -63,36 +127,73 @@ impl Catalog for MemCatalog {
             .ok_or_else(|| CatalogError::DEntryRead(parent).into())
     }
 
-    fn add_inode(&mut self, inode: &INode) -> DenebResult<()> {
+    fn add_inode(&self, inode: &INode) -> DenebResult<()> {
         let index = inode.attributes.index;
-        self.inodes.insert(index, inode.clone());
-        if index > self.max_index {
-            self.max_index = index;
+        let mut state = self.write();
+        state.inodes.insert(index, inode.clone());
+        if index > state.max_index {
+            state.max_index = index;
         }
         Ok(())
     }
 
-    fn add_dir_entry(&mut self, parent: u64, name: &Path, index: u64) -> DenebResult<()> {
-        let dir_entry = self.dir_entries.entry(parent).or_insert_with(|| {
-            let mut dir_entry = HashMap::new();
-            dir_entry.insert(name.to_owned(), index);
-            dir_entry
-        });
-        dir_entry.entry(name.to_owned()).or_insert(index);
+    fn add_dir_entry(&self, parent: u64, name: &Path, index: u64) -> DenebResult<()> {
+        let mut state = self.write();
+        // An entry with the same name is replaced
+        state
+            .dir_entries
+            .entry(parent)
+            .or_insert_with(BTreeMap::new)
+            .insert(name.to_owned(), index);
+        Ok(())
+    }
 
-        let inode = self
-            .inodes
-            .get_mut(&index)
-            .ok_or_else(|| CatalogError::INodeRead(index))?;
+    fn remove_dir_entry(&self, parent: u64, name: &Path) -> DenebResult<()> {
+        if let Some(entries) = self.write().dir_entries.get_mut(&parent) {
+            entries.remove(name);
+        }
+        Ok(())
+    }
 
-        inode.attributes.nlink += 1;
+    fn remove_inode(&self, index: u64) -> DenebResult<()> {
+        let mut state = self.write();
+        state.inodes.remove(&index);
+        state.dir_entries.remove(&index);
+        Ok(())
+    }
 
+    fn root_index(&self) -> DenebResult<u64> {
+        Ok(self.read().root_index.unwrap_or(ROOT_INDEX))
+    }
+
+    fn set_root_index(&self, index: u64) -> DenebResult<()> {
+        self.write().root_index = Some(index);
         Ok(())
     }
 
-    fn remove_inode(&mut self, index: u64) -> DenebResult<()> {
-        self.inodes.remove(&index);
-        self.dir_entries.remove(&index);
+    fn generations(&self) -> DenebResult<Vec<(u64, u64)>> {
+        Ok(self.read().generations.clone())
+    }
+
+    fn set_generations(&self, generations: &[(u64, u64)]) -> DenebResult<()> {
+        self.write().generations = generations.to_vec();
         Ok(())
     }
+
+    fn pins(&self) -> DenebResult<Vec<u64>> {
+        Ok(self.read().pins.clone())
+    }
+
+    fn set_pins(&self, pins: &[u64]) -> DenebResult<()> {
+        self.write().pins = pins.to_vec();
+        Ok(())
+    }
+
+    fn persist(&self) -> DenebResult<()> {
+        if self.read_only {
+            return Err(CatalogError::ReadOnly.into());
+        }
+        let buffer = serialize(&*self.read())?;
+        atomic_write(&self.path, &buffer)
+    }
 }
@@ -1,7 +1,10 @@
 use {
     failure::Fail,
     serde::{Deserialize, Serialize},
-    sodiumoxide::crypto::secretbox::{gen_key, gen_nonce, open, seal, Key, Nonce as SodiumNonce},
+    sodiumoxide::{
+        crypto::secretbox::{gen_key, gen_nonce, open, seal, Key, Nonce as SodiumNonce, MACBYTES},
+        utils::increment_le,
+    },
 };
 
 #[derive(Debug, Fail)]
@@ -15,13 +18,21 @@ pub struct DecryptionError;
 #[derive(Clone, Debug)]
 pub struct EncryptionKey(Key);
 
-#[derive(Deserialize, Serialize)]
+/// Number of bytes by which a ciphertext is larger than the plaintext
+pub const MAC_SIZE: usize = MACBYTES;
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Nonce(SodiumNonce);
 
 impl Nonce {
     pub fn new() -> Nonce {
         Nonce(gen_nonce())
     }
+
+    /// Advance to the next nonce, for encrypting consecutive messages with the same key
+    pub fn increment(&mut self) {
+        increment_le(&mut (self.0).0);
+    }
 }
 
 impl EncryptionKey {
@@ -1,91 +1,196 @@
+#[cfg(not(target_os = "freebsd"))]
+use nix::libc::{XATTR_CREATE, XATTR_REPLACE};
 use {
     self::{
-        protocol::{HandlerProxy, Request, RequestHandler},
+        acl::{acl_xattr, open_permissions, Acl, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR},
+        hooks::DEFAULT_PRE_COMMIT_TIMEOUT,
+        protocol::{
+            request_queues, HandlerProxy, PackagedRequest, Priority, Request, RequestHandler,
+            RequestQueue,
+        },
+        recent::RecentFiles,
         requests::{
-            Commit, CreateDir, CreateFile, GetAttr, Lookup, OpenDir, OpenFile, Ping, ReadData,
-            ReadDir, ReleaseDir, ReleaseFile, RemoveDir, Rename, SetAttr, StopEngine, Unlink,
-            WriteData,
+            Batch, Cancel, CollectGarbage, Commit, CreateDir, CreateFile, Dehydrate, DiffWorkspace,
+            EmptyTrash, Forget, GetAttr, GetAttrBatch, GetFileDigest, GetLog, GetManifest,
+            GetMetrics, GetOperations, GetPreviousVersion, GetRecentFiles, GetRoot, GetXattr,
+            ListXattrs, Lookup, LookupBatch, OpenDir, OpenFile, Pin, Ping, Prefetch, Prune,
+            ReadData, ReadDir, ReadLink, ReadPreviousVersion, Reconfigure, ReleaseDir, ReleaseFile,
+            RemoveDir, RemoveXattr, Rename, ResolvePath, Restore, SetAttr, SetXattr, StopEngine,
+            Unlink, WriteData,
         },
     },
     crate::{
+        cas::Digest,
         catalog::CatalogType,
-        crypt::EncryptionKey,
         errors::{DenebResult, EngineError},
-        store::StoreType,
-        workspace::{CommitSummary, Workspace},
+        inode::{file_type_to_mode, Entry, FileAttributeChanges, FileAttributes, FileType},
+        manifest::Manifest,
+        metrics::{
+            cache_lookups, corrupt_chunks, dedup_hits, remote_transfers, LatencyHistogram, Metrics,
+        },
+        store::{RemoteLimits, StoreType},
+        workspace::{GcSummary, PendingCommit, PendingGc, Workspace},
     },
     crossbeam_channel::bounded as channel,
     failure::{Error, ResultExt},
-    log::info,
+    log::{error, info, warn},
+    nix::libc::O_WRONLY,
     std::{
+        cmp::min,
+        collections::{BTreeMap, HashMap, HashSet},
+        ffi::{OsStr, OsString},
         path::PathBuf,
+        sync::{Arc, Mutex},
         thread::{spawn, JoinHandle},
-        time::Duration,
+        time::{Duration, Instant},
     },
+    time::{now_utc, Timespec},
     timer::{Resolution, Timer},
 };
 
-pub use self::{handle::Handle, requests::RequestId};
+pub use self::{
+    cache::{CacheMode, CachePolicy, OpenFlags},
+    handle::Handle,
+    hooks::CommitHooks,
+    ownership::OwnershipPolicy,
+    protocol::{OverloadPolicy, RequestLimits},
+    requests::{BatchInode, BatchOperation, RequestId},
+    warmup::warm_up,
+};
+pub use crate::workspace::{
+    apply_delta, check_repository, clone_repository, create_bundle, export_delta, init_repository,
+    open_bundle, replicate_repository, verify_repository, CommitSummary, Difference, PendingChange,
+    PendingChanges, PrunePolicy, RetentionPolicy, Snapshot, WorkspaceOptions,
+};
+
+const RECENT_FILES_PATH: &str = "recent_files";
+/// Number of requests handled between two checks of the memory limit
+const MEMORY_CHECK_INTERVAL: u64 = 64;
+/// How long automatic commits are held back after the pre-commit hook
+/// rejected one
+const REJECTED_COMMIT_BACKOFF: Duration = Duration::from_secs(60);
 
+// FreeBSD has no `setxattr`, its FUSE driver passes the flags of the Linux one
+#[cfg(target_os = "freebsd")]
+const XATTR_CREATE: i32 = 1;
+#[cfg(target_os = "freebsd")]
+const XATTR_REPLACE: i32 = 2;
+
+mod acl;
+mod cache;
 mod handle;
+mod hooks;
+mod ownership;
 mod protocol;
+mod recent;
 mod requests;
 mod timer;
+mod warmup;
+
+/// Options of an engine, see `start_engine`
+#[derive(Clone)]
+pub struct EngineOptions {
+    /// Options of the workspace the engine works on
+    pub workspace: WorkspaceOptions,
+    /// Number of requests each queue of the engine can hold
+    pub cmd_queue_size: usize,
+    pub overload_policy: OverloadPolicy,
+    pub request_limits: RequestLimits,
+    /// Interval between automatic commits, in seconds (0 disables them)
+    pub auto_commit_interval: usize,
+    /// Amount of uncommitted data which triggers a commit (0 means unlimited)
+    pub max_dirty_size: u64,
+    /// Memory held by the workspace above which it is shrunk or committed
+    /// (0 means unlimited)
+    pub max_memory: u64,
+    pub cache_policy: CachePolicy,
+    pub hooks: CommitHooks,
+    pub ownership: OwnershipPolicy,
+}
+
+impl Default for EngineOptions {
+    fn default() -> EngineOptions {
+        EngineOptions {
+            workspace: WorkspaceOptions::default(),
+            cmd_queue_size: 1000,
+            overload_policy: OverloadPolicy::Block,
+            request_limits: RequestLimits::default(),
+            auto_commit_interval: 0,
+            max_dirty_size: 0,
+            max_memory: 0,
+            cache_policy: CachePolicy::default(),
+            hooks: CommitHooks::default(),
+            ownership: OwnershipPolicy::default(),
+        }
+    }
+}
 
 /// Start engine with pre-built catalog and store
-#[allow(clippy::too_many_arguments)]
 pub fn start_engine(
     catalog_type: CatalogType,
     store_type: StoreType,
     work_dir: PathBuf,
-    encryption_key: Option<EncryptionKey>,
-    sync_dir: Option<PathBuf>,
-    chunk_size: usize,
-    cmd_queue_size: usize,
-    auto_commit_interval: usize,
+    options: EngineOptions,
 ) -> DenebResult<Handle> {
-    let (cmd_tx, cmd_rx) = channel(cmd_queue_size);
+    let EngineOptions {
+        workspace: workspace_options,
+        cmd_queue_size,
+        overload_policy,
+        request_limits,
+        auto_commit_interval,
+        max_dirty_size,
+        max_memory,
+        cache_policy,
+        hooks,
+        ownership,
+    } = options;
+    let (cmd_tx, cmd_rx) = request_queues(cmd_queue_size, overload_policy, request_limits.timeout);
     let (quit_tx, quit_rx) = channel(1);
-    let engine_hd = Handle::new(cmd_tx, quit_rx);
+    let interrupts = Interrupts::default();
+    let engine_hd = Handle::new(cmd_tx, quit_rx, Arc::clone(&interrupts));
     let timer_engine_hd = engine_hd.clone();
     let _: JoinHandle<DenebResult<()>> = spawn(move || {
-        let ws = Workspace::new(
-            catalog_type,
-            store_type,
-            work_dir,
-            encryption_key,
-            sync_dir,
-            chunk_size,
-        );
+        let recent_files = RecentFiles::load(work_dir.join(RECENT_FILES_PATH));
+        let ws = Workspace::new(catalog_type, store_type, work_dir, workspace_options);
         if ws.is_err() {
             panic!("Could not initialize workspace. Engine will not start.");
         }
         let mut engine = Engine {
             workspace: ws?,
+            cache_policy,
+            max_dirty_size,
+            max_memory,
+            handled: 0,
+            hooks,
+            hook_timeout: request_limits.timeout.unwrap_or(DEFAULT_PRE_COMMIT_TIMEOUT),
+            commits_held_until: None,
+            ownership,
+            requests: cmd_rx,
+            committing: false,
+            collecting: false,
+            running: BTreeMap::new(),
+            cancelled: HashSet::new(),
+            next_token: 0,
+            interrupts,
+            operations: BTreeMap::new(),
+            queue_latency: BTreeMap::new(),
+            slow_threshold: request_limits.slow_threshold,
+            slow_requests: 0,
+            recent_files,
+            auto_commit_interval,
+            auto_commit: schedule_auto_commit(&timer_engine_hd, auto_commit_interval),
+            handle: timer_engine_hd,
             stopped: false,
         };
-        let timer = if auto_commit_interval > 0 {
-            let mut t = Timer::new(Resolution::Second);
-            t.schedule(
-                Duration::from_secs(auto_commit_interval as u64),
-                true,
-                move || {
-                    let _ = timer_engine_hd.commit();
-                },
-            );
-            Some(t)
-        } else {
-            None
-        };
         info!("Starting engine event loop");
-        for request in &cmd_rx {
-            request.run_handler(&mut engine);
+        while let Some(request) = engine.requests.next() {
+            engine.dispatch(request);
             if engine.stopped {
                 break;
             }
         }
         info!("Engine event loop finished.");
-        if let Some(timer) = timer {
+        if let Some(timer) = engine.auto_commit.take() {
             timer.stop();
         }
         quit_tx.send(()).map_err(|_| EngineError::Send).unwrap();
@@ -98,15 +203,561 @@ pub fn start_engine(
     Ok(engine_hd)
 }
 
+/// Settings of a running engine which can be changed with `Handle::reconfigure`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LiveSettings {
+    /// Interval between automatic commits, in seconds (0 disables them)
+    pub auto_commit_interval: usize,
+    /// Amount of uncommitted data which triggers a commit (0 means unlimited)
+    pub max_dirty_size: u64,
+    /// Memory held by the workspace above which it is shrunk or committed
+    /// (0 means unlimited)
+    pub max_memory: u64,
+    /// Capacity of the cache of unpacked chunks
+    pub chunk_cache_size: u64,
+    /// Limits of the transfers from and to the remote store, if there is one
+    pub remote_limits: RemoteLimits,
+}
+
+/// Start a timer which commits the workspace every `interval` seconds
+fn schedule_auto_commit(handle: &Handle, interval: usize) -> Option<Timer> {
+    if interval == 0 {
+        return None;
+    }
+    let handle = handle.clone();
+    let mut timer = Timer::new(Resolution::Second);
+    timer.schedule(Duration::from_secs(interval as u64), true, move || {
+        if let Ok(summary) = handle.commit(None) {
+            log_automatic_commit(&summary);
+        }
+    });
+    Some(timer)
+}
+
+/// Log the summary of a commit which wasn't asked for by a client
+fn log_automatic_commit(summary: &CommitSummary) {
+    if !summary.noop && !summary.clean {
+        info!("Automatic commit:\n{}", summary);
+    }
+}
+
+/// The reads in flight, by unique request id, with whether their callers
+/// interrupted them
+pub(in crate::engine) type Interrupts = Arc<Mutex<HashMap<u64, (RequestId, bool)>>>;
+
 pub(in crate::engine) struct Engine {
     workspace: Workspace,
+    cache_policy: CachePolicy,
+    /// Amount of uncommitted data which triggers a commit (0 means unlimited)
+    max_dirty_size: u64,
+    /// Memory held by the workspace above which it is shrunk or committed
+    /// (0 means unlimited)
+    max_memory: u64,
+    /// Number of requests handled
+    handled: u64,
+    hooks: CommitHooks,
+    /// How long the pre-commit hook may run
+    hook_timeout: Duration,
+    /// Automatic commits are skipped until then, once one was rejected
+    commits_held_until: Option<Instant>,
+    ownership: OwnershipPolicy,
+    requests: RequestQueue<Engine>,
+    /// Set while a commit is handling interactive requests between its steps
+    committing: bool,
+    /// Set while a garbage collection is handling interactive requests between
+    /// its steps
+    collecting: bool,
+    /// Names of the maintenance operations in progress, by operation token
+    running: BTreeMap<u64, &'static str>,
+    /// Tokens of the running operations which should be aborted
+    cancelled: HashSet<u64>,
+    next_token: u64,
+    interrupts: Interrupts,
+    /// Number of requests handled, by request name
+    operations: BTreeMap<&'static str, u64>,
+    /// Time spent by the requests in the queue, by request name
+    queue_latency: BTreeMap<&'static str, LatencyHistogram>,
+    /// Interactive requests taking longer than this are logged
+    slow_threshold: Duration,
+    slow_requests: u64,
+    recent_files: RecentFiles,
+    auto_commit_interval: usize,
+    auto_commit: Option<Timer>,
+    /// Used by the timer of the automatic commits
+    handle: Handle,
     stopped: bool,
 }
 
 impl Engine {
-    fn stop(&mut self) {
+    fn dispatch(&mut self, request: PackagedRequest<Engine>) {
+        *self.operations.entry(request.name()).or_insert(0) += 1;
+        let started = Instant::now();
+        let queued = started.duration_since(request.queued_at());
+        self.queue_latency
+            .entry(request.name())
+            .or_insert_with(LatencyHistogram::default)
+            .record(queued);
+        request.run_handler(self);
+        self.handled += 1;
+        if self.handled % MEMORY_CHECK_INTERVAL == 0 {
+            self.limit_memory();
+        }
+
+        // Maintenance requests are expected to be slow
+        if request.priority() == Priority::Interactive {
+            let handled = started.elapsed();
+            if queued + handled >= self.slow_threshold {
+                self.slow_requests += 1;
+                warn!(
+                    "Slow {} request: {:?} in the queue, {:?} to handle: {} ({:?})",
+                    request.name(),
+                    queued,
+                    handled,
+                    request.describe(),
+                    request.caller()
+                );
+            }
+        }
+    }
+
+    /// Keep the memory held by the workspace below the limit, if there is one
+    ///
+    /// The caches are dropped first, since they are rebuilt from the catalog
+    /// and the store. If that isn't enough, the workspace is committed, which
+    /// moves the data written to the files out of memory. Nothing is done
+    /// while a commit is in progress, since it does that when it finishes, or
+    /// while a garbage collection is.
+    fn limit_memory(&mut self) {
+        if self.max_memory == 0
+            || !self.can_commit_now()
+            || self.workspace.memory_usage().total() < self.max_memory
+        {
+            return;
+        }
+        self.workspace.shrink();
+        let used = self.workspace.memory_usage().total();
+        if used < self.max_memory || !self.workspace.is_dirty() {
+            return;
+        }
+        info!(
+            "Memory limit reached ({} of {} bytes), committing",
+            used, self.max_memory
+        );
+        self.commit_automatically();
+        self.workspace.shrink();
+    }
+
+    /// Handle the interactive requests which are waiting, so that they are not
+    /// delayed by long maintenance tasks
+    fn serve_interactive(&mut self) {
+        while let Some(request) = self.requests.next_interactive() {
+            self.dispatch(request);
+        }
+    }
+
+    // Each entry returned to the kernel counts as a lookup of the inode
+    fn entry(&mut self, attributes: FileAttributes) -> Entry {
+        self.workspace.add_lookup(attributes.index);
+        Entry {
+            attributes: self.ownership.present(attributes),
+            generation: self.workspace.generation(attributes.index),
+        }
+    }
+
+    // Files with an access ACL are only opened by the users it grants the
+    // needed permissions to
+    fn check_acl(&mut self, request: &OpenFile) -> DenebResult<()> {
+        let acl = match self.workspace.get_xattr(request.index, ACL_ACCESS_XATTR)? {
+            Some(acl) => Acl::parse(&acl)?,
+            None => return Ok(()),
+        };
+        let attrs = self
+            .ownership
+            .present(self.workspace.get_attr(request.index)?);
+        let wanted = open_permissions(request.flags);
+        if !acl.permits(request.uid, request.gid, &attrs, wanted) {
+            return Err(EngineError::Access(request.index).into());
+        }
+        Ok(())
+    }
+
+    // New files belong to the owner forced by the ownership policy, if any
+    fn give_ownership(&mut self, attrs: FileAttributes) -> DenebResult<FileAttributes> {
+        match self.ownership.new_owner() {
+            Some(changes) => self.workspace.set_attr(attrs.index, &changes),
+            None => Ok(attrs),
+        }
+    }
+
+    /// Register a maintenance operation, returning the token which can be used
+    /// to cancel it
+    fn start_operation(&mut self, name: &'static str) -> u64 {
+        self.next_token += 1;
+        self.running.insert(self.next_token, name);
+        info!("Started {} (operation {})", name, self.next_token);
+        self.next_token
+    }
+
+    fn finish_operation(&mut self, token: u64) {
+        self.running.remove(&token);
+        self.cancelled.remove(&token);
+    }
+
+    /// Called between the steps of a maintenance operation: handles the waiting
+    /// interactive requests, then fails if the operation has been cancelled
+    fn checkpoint(&mut self, token: u64) -> DenebResult<()> {
+        self.serve_interactive();
+        if self.cancelled.contains(&token) {
+            info!("Operation {} cancelled", token);
+            return Err(EngineError::Cancelled(token).into());
+        }
+        Ok(())
+    }
+
+    /// Commit the workspace, handling interactive requests between the chunks
+    /// which are written
+    ///
+    /// A cancelled commit leaves the workspace dirty, with the files written
+    /// so far being committed by the next commit.
+    fn commit(&mut self, token: u64, message: Option<String>) -> DenebResult<CommitSummary> {
+        let previous = self.workspace.manifest().root_hash;
+        let mut commit = self.workspace.begin_commit(message.clone());
+        self.run_pre_commit_hook(&previous, &commit, message.as_ref().map(String::as_str))?;
+        self.committing = true;
+        let result = self.commit_files(token, &mut commit);
+        self.committing = false;
+        let summary = result.and_then(|_| self.workspace.finish_commit(commit))?;
+        self.run_post_commit_hook(&previous, &summary);
+        Ok(summary)
+    }
+
+    /// Whether the requests may commit the workspace themselves
+    ///
+    /// A commit in progress writes their changes when it finishes. Nothing is
+    /// committed during a garbage collection, which found the chunks to keep
+    /// before it started and would remove those of a new commit.
+    fn can_commit_now(&self) -> bool {
+        !self.committing && !self.collecting
+    }
+
+    /// Commit the workspace for a request which has already been applied
+    ///
+    /// The request succeeded whether or not the commit does, so a failed
+    /// commit is only logged and its changes are left to the next one. When
+    /// the pre-commit hook rejects the commit, the following automatic commits
+    /// are skipped for a while, so that the requests aren't all held up by a
+    /// hook which keeps failing or timing out.
+    fn commit_automatically(&mut self) {
+        if let Some(until) = self.commits_held_until {
+            if Instant::now() < until {
+                return;
+            }
+            self.commits_held_until = None;
+        }
+        match self.commit_now() {
+            Ok(summary) => log_automatic_commit(&summary),
+            Err(e) => match e.downcast_ref::<EngineError>() {
+                Some(EngineError::CommitRejected(..)) => {
+                    warn!(
+                        "Automatic commit rejected, retrying in {:?}: {}",
+                        REJECTED_COMMIT_BACKOFF, e
+                    );
+                    self.commits_held_until = Some(Instant::now() + REJECTED_COMMIT_BACKOFF);
+                }
+                _ => error!("Automatic commit failed: {}", e),
+            },
+        }
+    }
+
+    /// Commit the workspace without handling other requests meanwhile
+    fn commit_now(&mut self) -> DenebResult<CommitSummary> {
+        let previous = self.workspace.manifest().root_hash;
+        let mut commit = self.workspace.begin_commit(None);
+        self.run_pre_commit_hook(&previous, &commit, None)?;
+        while self.workspace.commit_next_step(&mut commit)? {}
+        let summary = self.workspace.finish_commit(commit)?;
+        self.run_post_commit_hook(&previous, &summary);
+        Ok(summary)
+    }
+
+    // The hooks only run when there is something to commit
+    fn run_pre_commit_hook(
+        &self,
+        previous: &Digest,
+        commit: &PendingCommit,
+        message: Option<&str>,
+    ) -> DenebResult<()> {
+        if !self.workspace.is_dirty() {
+            return Ok(());
+        }
+        self.hooks.pre_commit(
+            previous,
+            commit.pending_files(),
+            self.workspace.dirty_bytes(),
+            message,
+            self.hook_timeout,
+        )
+    }
+
+    fn run_post_commit_hook(&self, previous: &Digest, summary: &CommitSummary) {
+        if !summary.noop && !summary.clean {
+            self.hooks.post_commit(previous, summary);
+        }
+    }
+
+    fn commit_files(&mut self, token: u64, commit: &mut PendingCommit) -> DenebResult<()> {
+        while self.workspace.commit_next_step(commit)? {
+            self.checkpoint(token)?;
+        }
+        Ok(())
+    }
+
+    fn collect_garbage(&mut self, token: u64) -> DenebResult<GcSummary> {
+        self.commit(token, None)?;
+        let mut gc = self.workspace.begin_gc()?;
+        self.collecting = true;
+        let result = self.collect_steps(token, &mut gc);
+        self.collecting = false;
+        result.map(|_| self.workspace.finish_gc(gc))
+    }
+
+    fn collect_steps(&mut self, token: u64, gc: &mut PendingGc) -> DenebResult<()> {
+        while self.workspace.gc_next_step(gc)? {
+            self.checkpoint(token)?;
+        }
+        Ok(())
+    }
+
+    fn prune(&mut self, token: u64, policy: PrunePolicy) -> DenebResult<(usize, GcSummary)> {
+        self.commit(token, None)?;
+        let removed = self.workspace.prune_reflog(policy)?;
+        Ok((removed, self.collect_garbage(token)?))
+    }
+
+    /// Check that all the operations of a batch can be applied, before any of
+    /// them is, so that a batch which fails leaves the workspace untouched
+    ///
+    /// The entries created, moved and replaced by the operations are tracked
+    /// on top of the ones of the workspace.
+    fn check_batch(&mut self, operations: &[BatchOperation]) -> DenebResult<()> {
+        let mut kinds = Vec::with_capacity(operations.len());
+        let mut entries = HashMap::new();
+        for (position, operation) in operations.iter().enumerate() {
+            let kind = self
+                .check_operation(operation, &kinds, &mut entries)
+                .context(EngineError::Batch(position))?;
+            kinds.push(kind);
+        }
+        Ok(())
+    }
+
+    /// Check an operation of a batch, returning the type of the inode it
+    /// creates, if any
+    ///
+    /// `kinds` holds the types of the inodes created by the operations before,
+    /// `entries` the entries they changed, which are `None` once removed.
+    fn check_operation(
+        &mut self,
+        operation: &BatchOperation,
+        kinds: &[Option<FileType>],
+        entries: &mut HashMap<(BatchInode, OsString), Option<BatchInode>>,
+    ) -> DenebResult<Option<FileType>> {
+        match operation {
+            BatchOperation::CreateDir { parent, name, .. }
+            | BatchOperation::CreateFile { parent, name, .. } => {
+                self.check_kind(*parent, kinds, FileType::Directory)?;
+                if self.batch_entry(*parent, name, entries)?.is_some() {
+                    return Err(EngineError::BatchInvalid("the name is taken").into());
+                }
+                let kind = if let BatchOperation::CreateDir { .. } = operation {
+                    FileType::Directory
+                } else {
+                    FileType::RegularFile
+                };
+                let created = BatchInode::Created(kinds.len());
+                entries.insert((*parent, name.clone()), Some(created));
+                Ok(Some(kind))
+            }
+            BatchOperation::Write { file, .. } => {
+                self.check_kind(*file, kinds, FileType::RegularFile)?;
+                Ok(None)
+            }
+            BatchOperation::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+            } => {
+                let source = self
+                    .batch_entry(*parent, name, entries)?
+                    .ok_or_else(|| EngineError::BatchInvalid("the entry doesn't exist"))?;
+                self.check_kind(*new_parent, kinds, FileType::Directory)?;
+                match self.batch_entry(*new_parent, new_name, entries)? {
+                    // Only files can be replaced, as by `Workspace::rename`
+                    Some(target) if target != source => {
+                        self.check_kind(target, kinds, FileType::RegularFile)?
+                    }
+                    _ => {}
+                }
+                entries.insert((*parent, name.clone()), None);
+                entries.insert((*new_parent, new_name.clone()), Some(source));
+                Ok(None)
+            }
+        }
+    }
+
+    fn check_kind(
+        &mut self,
+        inode: BatchInode,
+        kinds: &[Option<FileType>],
+        expected: FileType,
+    ) -> DenebResult<()> {
+        let kind = match inode {
+            BatchInode::Index(index) => self.workspace.get_attr(index)?.kind,
+            BatchInode::Created(position) => kinds
+                .get(position)
+                .and_then(|kind| *kind)
+                .ok_or_else(|| EngineError::BatchReference(position))?,
+        };
+        if kind != expected {
+            let reason = match expected {
+                FileType::Directory => "not a directory",
+                _ => "not a regular file",
+            };
+            return Err(EngineError::BatchInvalid(reason).into());
+        }
+        Ok(())
+    }
+
+    // The entries of the inodes created by the batch are all in `entries`
+    fn batch_entry(
+        &mut self,
+        parent: BatchInode,
+        name: &OsStr,
+        entries: &HashMap<(BatchInode, OsString), Option<BatchInode>>,
+    ) -> DenebResult<Option<BatchInode>> {
+        if let Some(entry) = entries.get(&(parent, name.to_owned())) {
+            return Ok(*entry);
+        }
+        match parent {
+            BatchInode::Index(index) => Ok(self
+                .workspace
+                .lookup(index, name)?
+                .map(|attrs| BatchInode::Index(attrs.index))),
+            BatchInode::Created(_) => Ok(None),
+        }
+    }
+
+    /// Apply an operation of a batch, returning the attributes of the inode it
+    /// created, if any
+    ///
+    /// `created` holds the replies of the operations applied before.
+    fn apply(
+        &mut self,
+        operation: BatchOperation,
+        created: &[Option<FileAttributes>],
+    ) -> DenebResult<Option<FileAttributes>> {
+        let resolve = |inode| match inode {
+            BatchInode::Index(index) => Ok(index),
+            BatchInode::Created(position) => created
+                .get(position)
+                .and_then(Option::as_ref)
+                .map(|attrs| attrs.index)
+                .ok_or_else(|| Error::from(EngineError::BatchReference(position))),
+        };
+        match operation {
+            BatchOperation::CreateDir { parent, name, mode } => {
+                let parent = resolve(parent)?;
+                let mode = self.ownership.creation_mode(mode);
+                let attrs = self
+                    .workspace
+                    .create_dir(parent, &name, mode)
+                    .and_then(|attrs| self.give_ownership(attrs))
+                    .context(EngineError::DirCreate(parent, name))?;
+                Ok(Some(self.ownership.present(attrs)))
+            }
+            BatchOperation::CreateFile { parent, name, mode } => {
+                let parent = resolve(parent)?;
+                let mode = self.ownership.creation_mode(mode);
+                let attrs = self
+                    .workspace
+                    .create_file(parent, &name, mode, O_WRONLY as u32)
+                    .and_then(|(_, attrs)| self.give_ownership(attrs))
+                    .context(EngineError::FileCreate(parent, name))?;
+                Ok(Some(self.ownership.present(attrs)))
+            }
+            BatchOperation::Write { file, offset, data } => {
+                let index = resolve(file)?;
+                self.workspace
+                    .open_file(index, O_WRONLY as u32)
+                    .and_then(|_| self.workspace.write_data(index, offset, data))
+                    .and_then(|_| self.workspace.invalidate_data(index))
+                    .context(EngineError::FileWrite(index))?;
+                Ok(None)
+            }
+            BatchOperation::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+            } => {
+                let (parent, new_parent) = (resolve(parent)?, resolve(new_parent)?);
+                self.workspace
+                    .rename(parent, &name, new_parent, &new_name)
+                    .context(EngineError::Rename(parent, name, new_parent, new_name))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Read the requested range one chunk at a time, giving up as soon as the
+    /// request is interrupted
+    fn read_data(&mut self, request: &ReadData) -> DenebResult<Vec<u8>> {
+        let chunk_size = self.workspace.chunk_size() as u32;
+        let mut data = Vec::with_capacity(request.size as usize);
+        let mut offset = request.offset;
+        let mut remaining = request.size;
+        while remaining > 0 {
+            let interrupted = match self.interrupts.lock().unwrap().get(&request.id) {
+                Some(&(_, interrupted)) => interrupted,
+                None => false,
+            };
+            if interrupted {
+                info!("Read of {} interrupted", request.index);
+                return Err(EngineError::Interrupted(request.index).into());
+            }
+            let size = min(remaining, chunk_size);
+            let buffer = self
+                .workspace
+                .read_data(request.index, offset, size)
+                .context(EngineError::FileRead(request.index))?;
+            let read = buffer.len() as u32;
+            data.extend(buffer);
+            if read < size {
+                break;
+            }
+            offset += i64::from(read);
+            remaining -= read;
+        }
+        Ok(data)
+    }
+
+    fn stop(&mut self, checkpoint: bool) {
         info!("Engine stopping...");
-        let _ = self.workspace.commit();
+        // The changes are committed when they can't be saved to a checkpoint
+        let checkpointed =
+            checkpoint && self.workspace.is_dirty() && self.workspace.save_checkpoint().is_ok();
+        if !checkpointed {
+            if let Ok(summary) = self.commit_now() {
+                log_automatic_commit(&summary);
+            }
+        }
+        if let Err(e) = self.workspace.persist() {
+            error!("Could not persist the object store: {}", e);
+        }
+        if let Err(e) = self.recent_files.save() {
+            error!("Could not save the list of recently used files: {}", e);
+        }
         self.stopped = true;
         info!("Engine stopped.");
     }
@@ -116,15 +767,43 @@ impl RequestHandler<GetAttr> for Engine {
     fn handle(&mut self, request: &GetAttr) -> DenebResult<<GetAttr as Request>::Reply> {
         self.workspace
             .get_attr(request.index)
+            .map(|attrs| self.ownership.present(attrs))
             .context(EngineError::GetAttr(request.index))
             .map_err(Error::from)
     }
 }
 
+impl RequestHandler<GetAttrBatch> for Engine {
+    fn handle(&mut self, request: &GetAttrBatch) -> DenebResult<<GetAttrBatch as Request>::Reply> {
+        Ok(request
+            .indices
+            .iter()
+            .map(|&index| {
+                let attrs = self.workspace.get_attr(index).ok();
+                attrs.map(|attrs| self.ownership.present(attrs))
+            })
+            .collect())
+    }
+}
+
+impl RequestHandler<GetRoot> for Engine {
+    fn handle(&mut self, _request: &GetRoot) -> DenebResult<<GetRoot as Request>::Reply> {
+        self.workspace
+            .root_attr()
+            .map(|attrs| self.ownership.present(attrs))
+    }
+}
+
 impl RequestHandler<SetAttr> for Engine {
     fn handle(&mut self, request: &SetAttr) -> DenebResult<<SetAttr as Request>::Reply> {
+        let attrs = self
+            .ownership
+            .present(self.workspace.get_attr(request.index)?);
+        self.ownership
+            .check(request.uid, &attrs, &request.changes)?;
         self.workspace
             .set_attr(request.index, &request.changes)
+            .map(|attrs| self.ownership.present(attrs))
             .context(EngineError::SetAttr(request.index))
             .map_err(Error::from)
     }
@@ -134,11 +813,35 @@ impl RequestHandler<Lookup> for Engine {
     fn handle(&mut self, request: &Lookup) -> DenebResult<<Lookup as Request>::Reply> {
         self.workspace
             .lookup(request.parent, &request.name)
+            .map(|attrs| attrs.map(|attrs| self.entry(attrs)))
             .context(EngineError::Lookup(request.parent, request.name.clone()))
             .map_err(Error::from)
     }
 }
 
+impl RequestHandler<LookupBatch> for Engine {
+    fn handle(&mut self, request: &LookupBatch) -> DenebResult<<LookupBatch as Request>::Reply> {
+        let mut replies = Vec::with_capacity(request.names.len());
+        for name in &request.names {
+            let attrs = self
+                .workspace
+                .lookup(request.parent, name)
+                .context(EngineError::Lookup(request.parent, name.clone()))?;
+            replies.push(attrs.map(|attrs| self.ownership.present(attrs)));
+        }
+        Ok(replies)
+    }
+}
+
+impl RequestHandler<Forget> for Engine {
+    fn handle(&mut self, request: &Forget) -> DenebResult<<Forget as Request>::Reply> {
+        for &(index, nlookup) in &request.inodes {
+            self.workspace.forget(index, nlookup);
+        }
+        Ok(())
+    }
+}
+
 impl RequestHandler<OpenDir> for Engine {
     fn handle(&mut self, request: &OpenDir) -> DenebResult<<OpenDir as Request>::Reply> {
         self.workspace
@@ -159,37 +862,228 @@ impl RequestHandler<ReleaseDir> for Engine {
 
 impl RequestHandler<ReadDir> for Engine {
     fn handle(&mut self, request: &ReadDir) -> DenebResult<<ReadDir as Request>::Reply> {
+        let ownership = self.ownership;
         self.workspace
-            .read_dir(request.index)
+            .read_dir(request.index, request.offset)
+            .map(|entries| {
+                entries
+                    .into_iter()
+                    .map(|(cookie, name, attrs)| (cookie, name, ownership.present(attrs)))
+                    .collect()
+            })
             .context(EngineError::DirRead(request.index))
             .map_err(Error::from)
     }
 }
 
+impl RequestHandler<ReadLink> for Engine {
+    fn handle(&mut self, request: &ReadLink) -> DenebResult<<ReadLink as Request>::Reply> {
+        self.workspace
+            .read_link(request.index)
+            .context(EngineError::ReadLink(request.index))
+            .map_err(Error::from)
+    }
+}
+
+impl RequestHandler<GetFileDigest> for Engine {
+    fn handle(
+        &mut self,
+        request: &GetFileDigest,
+    ) -> DenebResult<<GetFileDigest as Request>::Reply> {
+        self.workspace
+            .file_digest(request.index)
+            .context(EngineError::FileDigest(request.index))
+            .map_err(Error::from)
+    }
+}
+
+impl RequestHandler<GetPreviousVersion> for Engine {
+    fn handle(
+        &mut self,
+        request: &GetPreviousVersion,
+    ) -> DenebResult<<GetPreviousVersion as Request>::Reply> {
+        self.workspace
+            .previous_version(request.index)
+            .map(|version| {
+                version
+                    .map(|(root_hash, inode)| (root_hash, self.ownership.present(inode.attributes)))
+            })
+            .context(EngineError::PreviousVersion(request.index))
+            .map_err(Error::from)
+    }
+}
+
+impl RequestHandler<ReadPreviousVersion> for Engine {
+    fn handle(
+        &mut self,
+        request: &ReadPreviousVersion,
+    ) -> DenebResult<<ReadPreviousVersion as Request>::Reply> {
+        self.workspace
+            .read_previous_version(request.index)
+            .context(EngineError::PreviousVersion(request.index))
+            .map_err(Error::from)
+    }
+}
+
+impl RequestHandler<ResolvePath> for Engine {
+    fn handle(&mut self, request: &ResolvePath) -> DenebResult<<ResolvePath as Request>::Reply> {
+        self.workspace
+            .resolve_path(&request.path)
+            .map(|found| found.map(|(index, attrs)| (index, self.ownership.present(attrs))))
+            .context(EngineError::ResolvePath(request.path.clone()))
+            .map_err(Error::from)
+    }
+}
+
+impl RequestHandler<GetXattr> for Engine {
+    fn handle(&mut self, request: &GetXattr) -> DenebResult<<GetXattr as Request>::Reply> {
+        let name = match acl_xattr(&request.name) {
+            Ok(name) => name,
+            Err(_) => return Ok(None),
+        };
+        self.workspace
+            .get_xattr(request.index, name)
+            .context(EngineError::Xattr(request.index, request.name.clone()))
+            .map_err(Error::from)
+    }
+}
+
+impl RequestHandler<ListXattrs> for Engine {
+    fn handle(&mut self, request: &ListXattrs) -> DenebResult<<ListXattrs as Request>::Reply> {
+        self.workspace
+            .list_xattrs(request.index)
+            .map(|names| names.into_iter().map(OsString::from).collect())
+            .context(EngineError::GetAttr(request.index))
+            .map_err(Error::from)
+    }
+}
+
+impl RequestHandler<SetXattr> for Engine {
+    fn handle(&mut self, request: &SetXattr) -> DenebResult<<SetXattr as Request>::Reply> {
+        let index = request.index;
+        let name = acl_xattr(&request.name)?;
+        let acl = Acl::parse(&request.value)?;
+        let attrs = self.ownership.present(self.workspace.get_attr(index)?);
+        self.ownership.check_owner(request.uid, &attrs)?;
+        if name == ACL_DEFAULT_XATTR && attrs.kind != FileType::Directory {
+            return Err(EngineError::Access(index).into());
+        }
+        let exists = self.workspace.get_xattr(index, name)?.is_some();
+        if exists && request.flags & XATTR_CREATE as u32 != 0 {
+            return Err(EngineError::XattrExists(index, request.name.clone()).into());
+        }
+        if !exists && request.flags & XATTR_REPLACE as u32 != 0 {
+            return Err(EngineError::XattrMissing(index, request.name.clone()).into());
+        }
+        self.workspace
+            .set_xattr(index, name, Some(request.value.clone()))
+            .context(EngineError::Xattr(index, request.name.clone()))?;
+        // The permission bits of a file follow its access ACL
+        if name == ACL_ACCESS_XATTR {
+            let mode = file_type_to_mode(attrs.kind) | u32::from(acl.permissions(attrs.perm));
+            let changes = FileAttributeChanges::new(
+                Some(mode),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+            self.workspace
+                .set_attr(index, &changes)
+                .context(EngineError::SetAttr(index))?;
+        }
+        Ok(())
+    }
+}
+
+impl RequestHandler<RemoveXattr> for Engine {
+    fn handle(&mut self, request: &RemoveXattr) -> DenebResult<<RemoveXattr as Request>::Reply> {
+        let index = request.index;
+        let name = acl_xattr(&request.name)?;
+        let attrs = self.ownership.present(self.workspace.get_attr(index)?);
+        self.ownership.check_owner(request.uid, &attrs)?;
+        if self.workspace.get_xattr(index, name)?.is_none() {
+            return Err(EngineError::XattrMissing(index, request.name.clone()).into());
+        }
+        self.workspace
+            .set_xattr(index, name, None)
+            .context(EngineError::Xattr(index, request.name.clone()))?;
+        Ok(())
+    }
+}
+
 impl RequestHandler<OpenFile> for Engine {
     fn handle(&mut self, request: &OpenFile) -> DenebResult<<OpenFile as Request>::Reply> {
-        self.workspace
+        if self.ownership.enforce_acls && request.uid != 0 {
+            self.check_acl(request)?;
+        }
+        let mut flags = self
+            .workspace
             .open_file(request.index, request.flags)
+            .and_then(|_| self.workspace.get_attr(request.index))
+            .map(|attrs| self.cache_policy.open_flags(attrs.size, request.flags))
+            .and_then(|flags| {
+                self.workspace
+                    .set_uncached(request.index, flags.uncached)
+                    .map(|_| flags)
+            })
+            .context(EngineError::FileOpen(request.index))?;
+        // Without keep_cache, the kernel drops the pages it cached
+        if self.workspace.take_stale_data(request.index) {
+            flags.keep_cache = false;
+        }
+        self.recent_files.touch(request.index);
+        Ok(flags)
+    }
+}
+
+impl RequestHandler<Prefetch> for Engine {
+    fn handle(&mut self, request: &Prefetch) -> DenebResult<<Prefetch as Request>::Reply> {
+        self.workspace
+            .prefetch(request.index)
             .context(EngineError::FileOpen(request.index))
             .map_err(Error::from)
     }
 }
 
+impl RequestHandler<GetRecentFiles> for Engine {
+    fn handle(&mut self, request: &GetRecentFiles) -> DenebResult<Vec<u64>> {
+        Ok(self.recent_files.most_recent(request.count))
+    }
+}
+
 impl RequestHandler<ReadData> for Engine {
     fn handle(&mut self, request: &ReadData) -> DenebResult<<ReadData as Request>::Reply> {
-        self.workspace
-            .read_data(request.index, request.offset, request.size)
-            .context(EngineError::FileRead(request.index))
-            .map_err(Error::from)
+        self.read_data(request)
     }
 }
 
 impl RequestHandler<WriteData> for Engine {
     fn handle(&mut self, request: &WriteData) -> DenebResult<<WriteData as Request>::Reply> {
-        self.workspace
-            .write_data(request.index, request.offset, &request.data)
-            .context(EngineError::FileWrite(request.index))
-            .map_err(Error::from)
+        let written = self
+            .workspace
+            .write_data(request.index, request.offset, request.data.take())
+            .context(EngineError::FileWrite(request.index))?;
+        // Uncommitted data is held in memory. Once the limit is reached, the
+        // write is only acknowledged after the workspace has been committed. A
+        // failed commit doesn't fail the write, whose data is kept for the next.
+        // A commit which is already in progress writes this data when it
+        // finishes, and one during a garbage collection waits for the next.
+        if self.max_dirty_size > 0
+            && self.can_commit_now()
+            && self.workspace.dirty_bytes() >= self.max_dirty_size
+        {
+            info!(
+                "Uncommitted data size limit reached ({} bytes), committing",
+                self.max_dirty_size
+            );
+            self.commit_automatically();
+        }
+        Ok(written)
     }
 }
 
@@ -204,8 +1098,14 @@ impl RequestHandler<ReleaseFile> for Engine {
 
 impl RequestHandler<CreateFile> for Engine {
     fn handle(&mut self, request: &CreateFile) -> DenebResult<<CreateFile as Request>::Reply> {
+        let mode = self.ownership.creation_mode(request.mode);
         self.workspace
-            .create_file(request.parent, &request.name, request.mode, request.flags)
+            .create_file(request.parent, &request.name, mode, request.flags)
+            .and_then(|(_, attrs)| self.give_ownership(attrs))
+            .map(|attrs| {
+                let flags = self.cache_policy.open_flags(attrs.size, request.flags);
+                (self.entry(attrs), flags)
+            })
             .context(EngineError::FileCreate(
                 request.parent,
                 request.name.clone(),
@@ -216,8 +1116,11 @@ impl RequestHandler<CreateFile> for Engine {
 
 impl RequestHandler<CreateDir> for Engine {
     fn handle(&mut self, request: &CreateDir) -> DenebResult<<CreateDir as Request>::Reply> {
+        let mode = self.ownership.creation_mode(request.mode);
         self.workspace
-            .create_dir(request.parent, &request.name, request.mode)
+            .create_dir(request.parent, &request.name, mode)
+            .and_then(|attrs| self.give_ownership(attrs))
+            .map(|attrs| self.entry(attrs))
             .context(EngineError::DirCreate(request.parent, request.name.clone()))
             .map_err(Error::from)
     }
@@ -243,7 +1146,8 @@ impl RequestHandler<RemoveDir> for Engine {
 
 impl RequestHandler<Rename> for Engine {
     fn handle(&mut self, request: &Rename) -> DenebResult<<Rename as Request>::Reply> {
-        self.workspace
+        let replaced = self
+            .workspace
             .rename(
                 request.parent,
                 &request.name,
@@ -255,35 +1159,220 @@ impl RequestHandler<Rename> for Engine {
                 request.name.clone(),
                 request.new_parent,
                 request.new_name.clone(),
-            ))
-            .map_err(Error::from)
+            ))?;
+        // Editors save a file by writing a temporary file and renaming it over
+        // the original one. Such a rename is only acknowledged once it has been
+        // committed, so the saved file survives a crash. During a commit, the
+        // rename is written by the commit in progress instead, and during a
+        // garbage collection by the next commit. The rename was made either
+        // way, so it is acknowledged even if the commit fails.
+        if replaced && self.can_commit_now() {
+            self.commit_automatically();
+        }
+        Ok(())
+    }
+}
+
+impl RequestHandler<Batch> for Engine {
+    fn handle(&mut self, request: &Batch) -> DenebResult<<Batch as Request>::Reply> {
+        let operations = request.operations.take();
+        self.check_batch(&operations)?;
+        let mut replies = Vec::with_capacity(operations.len());
+        for (position, operation) in operations.into_iter().enumerate() {
+            let reply = self
+                .apply(operation, &replies)
+                .context(EngineError::Batch(position))?;
+            replies.push(reply);
+        }
+        // As for single writes, but only once all the operations are applied
+        if self.max_dirty_size > 0
+            && self.can_commit_now()
+            && self.workspace.dirty_bytes() >= self.max_dirty_size
+        {
+            info!(
+                "Uncommitted data size limit reached ({} bytes), committing",
+                self.max_dirty_size
+            );
+            self.commit_automatically();
+        }
+        Ok(replies)
     }
 }
 
 impl RequestHandler<Commit> for Engine {
-    fn handle(&mut self, _request: &Commit) -> DenebResult<CommitSummary> {
+    fn handle(&mut self, request: &Commit) -> DenebResult<CommitSummary> {
+        let token = self.start_operation(Commit::NAME);
+        let result = self.commit(token, request.message.clone());
+        self.finish_operation(token);
+        result.context(EngineError::Commit).map_err(Error::from)
+    }
+}
+
+impl RequestHandler<CollectGarbage> for Engine {
+    fn handle(&mut self, _request: &CollectGarbage) -> DenebResult<GcSummary> {
+        let token = self.start_operation(CollectGarbage::NAME);
+        let result = self.collect_garbage(token);
+        self.finish_operation(token);
+        result.context(EngineError::Gc).map_err(Error::from)
+    }
+}
+
+impl RequestHandler<Prune> for Engine {
+    fn handle(&mut self, request: &Prune) -> DenebResult<(usize, GcSummary)> {
+        let token = self.start_operation(Prune::NAME);
+        let result = self.prune(token, request.policy);
+        self.finish_operation(token);
+        result.context(EngineError::Prune).map_err(Error::from)
+    }
+}
+
+impl RequestHandler<Cancel> for Engine {
+    fn handle(&mut self, request: &Cancel) -> DenebResult<bool> {
+        if self.running.contains_key(&request.token) {
+            self.cancelled.insert(request.token);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+impl RequestHandler<GetOperations> for Engine {
+    fn handle(&mut self, _request: &GetOperations) -> DenebResult<Vec<(u64, String)>> {
+        Ok(self
+            .running
+            .iter()
+            .map(|(token, name)| (*token, name.to_string()))
+            .collect())
+    }
+}
+
+impl RequestHandler<GetManifest> for Engine {
+    fn handle(&mut self, _request: &GetManifest) -> DenebResult<Manifest> {
+        Ok(self.workspace.manifest().clone())
+    }
+}
+
+impl RequestHandler<Restore> for Engine {
+    fn handle(&mut self, request: &Restore) -> DenebResult<PathBuf> {
+        self.workspace
+            .restore(&request.root_hash, &request.path, request.overwrite)
+            .context(EngineError::Restore(request.path.clone()))
+            .map_err(Error::from)
+    }
+}
+
+impl RequestHandler<EmptyTrash> for Engine {
+    fn handle(&mut self, _request: &EmptyTrash) -> DenebResult<usize> {
+        self.workspace
+            .purge_trash(true)
+            .context(EngineError::EmptyTrash)
+            .map_err(Error::from)
+    }
+}
+
+impl RequestHandler<Pin> for Engine {
+    fn handle(&mut self, request: &Pin) -> DenebResult<bool> {
+        self.workspace
+            .set_pinned(request.index, request.pinned)
+            .context(EngineError::Pin(request.index))
+            .map_err(Error::from)
+    }
+}
+
+impl RequestHandler<Dehydrate> for Engine {
+    fn handle(&mut self, request: &Dehydrate) -> DenebResult<(u64, u64)> {
+        let cutoff = request.untouched_for.map(|untouched_for| {
+            let now = now_utc().to_timespec();
+            Timespec::new(now.sec - untouched_for.as_secs() as i64, now.nsec)
+        });
         self.workspace
-            .commit()
-            .context(EngineError::Commit)
+            .dehydrate(request.index, cutoff)
+            .context(EngineError::Dehydrate(request.index))
             .map_err(Error::from)
     }
 }
 
+impl RequestHandler<DiffWorkspace> for Engine {
+    fn handle(&mut self, _request: &DiffWorkspace) -> DenebResult<PendingChanges> {
+        self.workspace.pending_changes()
+    }
+}
+
+impl RequestHandler<GetLog> for Engine {
+    fn handle(&mut self, _request: &GetLog) -> DenebResult<Vec<Manifest>> {
+        self.workspace.log()
+    }
+}
+
 impl RequestHandler<Ping> for Engine {
     fn handle(&mut self, _request: &Ping) -> DenebResult<String> {
         Ok("Pong".to_string())
     }
 }
 
+impl RequestHandler<Reconfigure> for Engine {
+    fn handle(&mut self, request: &Reconfigure) -> DenebResult<()> {
+        let settings = request.settings;
+        // The timer may be waiting for the engine to reply to a commit, so it
+        // can't be joined here
+        if settings.auto_commit_interval != self.auto_commit_interval {
+            if let Some(timer) = self.auto_commit.take() {
+                timer.cancel();
+            }
+            self.auto_commit = schedule_auto_commit(&self.handle, settings.auto_commit_interval);
+            self.auto_commit_interval = settings.auto_commit_interval;
+        }
+        self.max_dirty_size = settings.max_dirty_size;
+        self.max_memory = settings.max_memory;
+        self.workspace
+            .set_store_limits(settings.chunk_cache_size, settings.remote_limits)?;
+        info!("Engine reconfigured: {:?}", settings);
+        Ok(())
+    }
+}
+
+impl RequestHandler<GetMetrics> for Engine {
+    fn handle(&mut self, _request: &GetMetrics) -> DenebResult<Metrics> {
+        let (cache_hits, cache_misses) = cache_lookups();
+        let (open_files, dirty_bytes) = self.workspace.file_stats();
+        let (pinned_entries, pinned_chunks) = self.workspace.pinned();
+        let queues = self.requests.stats();
+        Ok(Metrics {
+            operations: self
+                .operations
+                .iter()
+                .map(|(name, count)| (name.to_string(), *count))
+                .collect(),
+            queue_latency: self
+                .queue_latency
+                .iter()
+                .map(|(name, histogram)| (name.to_string(), histogram.percentiles()))
+                .collect(),
+            cache_hits,
+            cache_misses,
+            corrupt_chunks: corrupt_chunks(),
+            dedup_hits: dedup_hits(),
+            dirty_bytes,
+            open_files,
+            memory: self.workspace.memory_usage(),
+            queue_capacity: queues.capacity,
+            interactive_queued: queues.interactive,
+            maintenance_queued: queues.maintenance,
+            blocked_sends: queues.blocked_sends,
+            shed_requests: queues.shed_requests,
+            slow_requests: self.slow_requests,
+            pinned_entries,
+            pinned_chunks,
+            ..remote_transfers()
+        })
+    }
+}
+
 impl RequestHandler<StopEngine> for Engine {
-    fn handle(&mut self, _request: &StopEngine) -> DenebResult<()> {
+    fn handle(&mut self, request: &StopEngine) -> DenebResult<()> {
         info!("StopEngine request received.");
-        let _ = self
-            .workspace
-            .commit()
-            .context(EngineError::Commit)
-            .map_err(Error::from);
-        self.stop();
+        self.stop(request.checkpoint);
         Ok(())
     }
 }
@@ -0,0 +1,190 @@
+use {
+    crate::{
+        errors::{DenebResult, EngineError},
+        inode::FileAttributes,
+    },
+    nix::libc::{O_ACCMODE, O_RDONLY, O_WRONLY},
+    std::ffi::OsStr,
+};
+
+/// Extended attribute holding the access ACL of a file
+pub(in crate::engine) const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+/// Extended attribute holding the default ACL of a directory
+pub(in crate::engine) const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+// Layout of the ACLs in extended attributes, as defined in the Linux
+// `posix_acl_xattr.h`: a version followed by entries of a tag, permissions
+// and an id, all little-endian
+const ACL_VERSION: u32 = 2;
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// The name of a supported ACL extended attribute
+pub(in crate::engine) fn acl_xattr(name: &OsStr) -> DenebResult<&'static str> {
+    match name.to_str() {
+        Some(ACL_ACCESS_XATTR) => Ok(ACL_ACCESS_XATTR),
+        Some(ACL_DEFAULT_XATTR) => Ok(ACL_DEFAULT_XATTR),
+        _ => Err(EngineError::UnsupportedXattr(name.to_owned()).into()),
+    }
+}
+
+/// The permissions needed to open a file with `flags`
+pub(in crate::engine) fn open_permissions(flags: u32) -> u16 {
+    match flags as i32 & O_ACCMODE {
+        O_RDONLY => 0o4,
+        O_WRONLY => 0o2,
+        _ => 0o6,
+    }
+}
+
+/// A POSIX ACL, as stored in the `system.posix_acl_*` extended attributes
+#[derive(Debug)]
+pub(in crate::engine) struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+#[derive(Debug)]
+struct AclEntry {
+    tag: u16,
+    perm: u16,
+    id: u32,
+}
+
+impl Acl {
+    /// Parse the value of an ACL extended attribute, set by `setfacl`
+    pub(in crate::engine) fn parse(value: &[u8]) -> DenebResult<Acl> {
+        if value.len() < HEADER_SIZE || (value.len() - HEADER_SIZE) % ENTRY_SIZE != 0 {
+            return Err(EngineError::InvalidAcl.into());
+        }
+        let version = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+        if version != ACL_VERSION {
+            return Err(EngineError::InvalidAcl.into());
+        }
+        let entries = value[HEADER_SIZE..]
+            .chunks(ENTRY_SIZE)
+            .map(|e| AclEntry {
+                tag: u16::from_le_bytes([e[0], e[1]]),
+                perm: u16::from_le_bytes([e[2], e[3]]) & 0o7,
+                id: u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+            })
+            .collect::<Vec<_>>();
+        let required = [ACL_USER_OBJ, ACL_GROUP_OBJ, ACL_OTHER];
+        if !required
+            .iter()
+            .all(|tag| entries.iter().filter(|e| e.tag == *tag).count() == 1)
+        {
+            return Err(EngineError::InvalidAcl.into());
+        }
+        Ok(Acl { entries })
+    }
+
+    /// The permission bits of a file with `perm`, once given the ACL
+    ///
+    /// The owner and other classes come from their entries of the ACL, the
+    /// group class from its mask, if any, or from the owning group entry.
+    pub(in crate::engine) fn permissions(&self, perm: u16) -> u16 {
+        let group = self
+            .perm(ACL_MASK)
+            .unwrap_or_else(|| self.perm(ACL_GROUP_OBJ).unwrap_or(0));
+        (perm & 0o7000)
+            | (self.perm(ACL_USER_OBJ).unwrap_or(0) << 6)
+            | (group << 3)
+            | self.perm(ACL_OTHER).unwrap_or(0)
+    }
+
+    /// Whether the user `uid`, of the group `gid`, is granted the `wanted`
+    /// permissions (a combination of 4, 2 and 1) on a file with `attrs`
+    ///
+    /// The supplementary groups of the user are not known, so only the entries
+    /// of its primary group are matched.
+    pub(in crate::engine) fn permits(
+        &self,
+        uid: u32,
+        gid: u32,
+        attrs: &FileAttributes,
+        wanted: u16,
+    ) -> bool {
+        let granted = |perm: u16| perm & wanted == wanted;
+        if uid == attrs.uid {
+            return self.perm(ACL_USER_OBJ).map_or(false, granted);
+        }
+        let mask = self.perm(ACL_MASK).unwrap_or(0o7);
+        if let Some(user) = self
+            .entries
+            .iter()
+            .find(|e| e.tag == ACL_USER && e.id == uid)
+        {
+            return granted(user.perm & mask);
+        }
+        let groups = self
+            .entries
+            .iter()
+            .filter(|e| {
+                (e.tag == ACL_GROUP_OBJ && gid == attrs.gid) || (e.tag == ACL_GROUP && e.id == gid)
+            })
+            .collect::<Vec<_>>();
+        if !groups.is_empty() {
+            return groups.iter().any(|e| granted(e.perm & mask));
+        }
+        self.perm(ACL_OTHER).map_or(false, granted)
+    }
+
+    fn perm(&self, tag: u16) -> Option<u16> {
+        self.entries.iter().find(|e| e.tag == tag).map(|e| e.perm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut value = ACL_VERSION.to_le_bytes().to_vec();
+        for &(tag, perm, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn acls_grant_named_users_and_groups() -> DenebResult<()> {
+        let undefined = u32::max_value();
+        // user::rw- user:1001:rw- group::r-- group:2000:rwx mask::rw- other::---
+        let acl = Acl::parse(&encode(&[
+            (ACL_USER_OBJ, 0o6, undefined),
+            (ACL_USER, 0o6, 1001),
+            (ACL_GROUP_OBJ, 0o4, undefined),
+            (ACL_GROUP, 0o7, 2000),
+            (ACL_MASK, 0o6, undefined),
+            (ACL_OTHER, 0o0, undefined),
+        ]))?;
+        let mut attrs = FileAttributes::default();
+        attrs.uid = 1000;
+        attrs.gid = 1000;
+
+        assert_eq!(acl.permissions(0o1644), 0o1660);
+        assert!(acl.permits(1000, 1000, &attrs, 0o6));
+        assert!(acl.permits(1001, 1001, &attrs, 0o6));
+        assert!(acl.permits(1002, 1000, &attrs, 0o4));
+        assert!(!acl.permits(1002, 1000, &attrs, 0o2));
+        // The mask limits the named groups
+        assert!(acl.permits(1002, 2000, &attrs, 0o6));
+        assert!(!acl.permits(1002, 2000, &attrs, 0o1));
+        assert!(!acl.permits(1002, 1002, &attrs, 0o4));
+
+        assert!(Acl::parse(&encode(&[(ACL_USER_OBJ, 0o6, undefined)])).is_err());
+        assert!(Acl::parse(&[2, 0, 0]).is_err());
+        assert_eq!(acl_xattr(OsStr::new(ACL_ACCESS_XATTR))?, ACL_ACCESS_XATTR);
+        assert!(acl_xattr(OsStr::new("user.comment")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::libc::O_DIRECT;
+use {
+    crate::errors::DenebError,
+    serde::{Deserialize, Serialize},
+    std::str::FromStr,
+};
+
+// There is no O_DIRECT on macOS, where caching is disabled with fcntl instead
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const O_DIRECT: i32 = 0;
+
+/// Selects how the kernel page cache is used for files in the repository
+///
+/// The engine already caches unpacked chunks, so letting the kernel also
+/// cache file contents can result in the same data being held in memory
+/// twice.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    /// Small files use the kernel page cache, large files use direct IO
+    Auto,
+    /// All files use the kernel page cache
+    Kernel,
+    /// All files use direct IO, bypassing the kernel page cache
+    Direct,
+    /// All files use direct IO, and their reads also bypass the chunk cache of
+    /// the engine, e.g. to measure the throughput of the store
+    Uncached,
+}
+
+impl FromStr for CacheMode {
+    type Err = DenebError;
+
+    fn from_str(s: &str) -> Result<CacheMode, DenebError> {
+        match s {
+            "auto" => Ok(CacheMode::Auto),
+            "kernel" => Ok(CacheMode::Kernel),
+            "direct" => Ok(CacheMode::Direct),
+            "uncached" => Ok(CacheMode::Uncached),
+            _ => Err(DenebError::CommandLineParameter(
+                "cache_mode: ".to_string() + s,
+            )),
+        }
+    }
+}
+
+/// Caching behaviour requested for an open file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpenFlags {
+    pub direct_io: bool,
+    pub keep_cache: bool,
+    /// Reads bypass the chunk cache of the engine
+    pub uncached: bool,
+}
+
+/// The kernel caching policy of a mounted repository
+#[derive(Clone, Copy, Debug)]
+pub struct CachePolicy {
+    pub mode: CacheMode,
+    /// Files at least this large use direct IO when `mode` is `CacheMode::Auto`
+    pub direct_io_threshold: u64,
+}
+
+impl CachePolicy {
+    pub fn new(mode: CacheMode, direct_io_threshold: u64) -> CachePolicy {
+        CachePolicy {
+            mode,
+            direct_io_threshold,
+        }
+    }
+
+    /// Returns the flags to be used when opening a file of a given size with
+    /// the flags `flags` of open(2)
+    ///
+    /// Whatever the mode, files opened with `O_DIRECT` bypass both the kernel
+    /// page cache and the chunk cache, for applications which cache the data
+    /// themselves.
+    pub fn open_flags(&self, file_size: u64, flags: u32) -> OpenFlags {
+        let uncached = self.mode == CacheMode::Uncached || flags as i32 & O_DIRECT != 0;
+        let direct_io = uncached
+            || match self.mode {
+                CacheMode::Auto => file_size >= self.direct_io_threshold,
+                CacheMode::Kernel => false,
+                CacheMode::Direct | CacheMode::Uncached => true,
+            };
+        OpenFlags {
+            direct_io,
+            keep_cache: !direct_io,
+            uncached,
+        }
+    }
+}
+
+impl Default for CachePolicy {
+    fn default() -> CachePolicy {
+        CachePolicy::new(CacheMode::Kernel, std::u64::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_mode_uses_threshold() {
+        let policy = CachePolicy::new(CacheMode::Auto, 1024);
+        assert!(policy.open_flags(1023, 0).keep_cache);
+        assert!(!policy.open_flags(1023, 0).direct_io);
+        assert!(policy.open_flags(1024, 0).direct_io);
+        assert!(!policy.open_flags(1024, 0).keep_cache);
+    }
+
+    #[test]
+    fn fixed_modes_ignore_size() {
+        let kernel = CachePolicy::new(CacheMode::Kernel, 0);
+        assert!(!kernel.open_flags(std::u64::MAX, 0).direct_io);
+        let direct = CachePolicy::new(CacheMode::Direct, std::u64::MAX);
+        assert!(direct.open_flags(0, 0).direct_io);
+        assert!(!direct.open_flags(0, 0).uncached);
+    }
+
+    #[test]
+    fn uncached_reads() {
+        let uncached = CachePolicy::new(CacheMode::Uncached, std::u64::MAX);
+        assert!(uncached.open_flags(0, 0).uncached);
+        assert!(uncached.open_flags(0, 0).direct_io);
+        let kernel = CachePolicy::new(CacheMode::Kernel, std::u64::MAX);
+        assert!(!kernel.open_flags(0, 0).uncached);
+        if O_DIRECT != 0 {
+            let flags = kernel.open_flags(0, O_DIRECT as u32);
+            assert!(flags.uncached && flags.direct_io && !flags.keep_cache);
+        }
+    }
+}
@@ -1,49 +1,83 @@
 use {
     super::{
-        protocol::{call, cast, RequestChannel},
+        cache::OpenFlags,
+        protocol::{call, call_as, cast, RequestChannel},
         requests::{
-            Commit, CreateDir, CreateFile, GetAttr, Lookup, OpenDir, OpenFile, Ping, ReadData,
-            ReadDir, ReleaseDir, ReleaseFile, RemoveDir, Rename, RequestId, SetAttr, StopEngine,
-            Unlink, WriteData,
+            Batch, BatchOperation, Cancel, CollectGarbage, Commit, CreateDir, CreateFile,
+            Dehydrate, DiffWorkspace, EmptyTrash, Forget, GetAttr, GetAttrBatch, GetFileDigest,
+            GetLog, GetManifest, GetMetrics, GetOperations, GetPreviousVersion, GetRecentFiles,
+            GetRoot, GetXattr, ListXattrs, Lookup, LookupBatch, OpenDir, OpenFile, Pin, Ping,
+            Prefetch, Prune, ReadData, ReadDir, ReadLink, ReadPreviousVersion, Reconfigure,
+            ReleaseDir, ReleaseFile, RemoveDir, RemoveXattr, Rename, RequestId, ResolvePath,
+            Restore, SetAttr, SetXattr, StopEngine, Unlink, WriteData,
         },
-        Engine,
+        CommitSummary, Engine, Interrupts, LiveSettings, PendingChanges, PrunePolicy,
     },
     crate::{
+        cas::Digest,
         errors::DenebResult,
-        inode::{FileAttributeChanges, FileAttributes, FileType},
+        inode::{Entry, FileAttributeChanges, FileAttributes},
+        manifest::Manifest,
+        metrics::Metrics,
     },
     crossbeam_channel::Receiver,
-    std::{ffi::OsStr, path::PathBuf},
+    std::{
+        cell::Cell,
+        ffi::{OsStr, OsString},
+        path::{Path, PathBuf},
+        time::Duration,
+    },
 };
 
 #[derive(Clone)]
 pub struct Handle {
     cmd_ch: RequestChannel<Engine>,
     stop_ch: Receiver<()>,
+    interrupts: Interrupts,
 }
 
 impl Handle {
     // Client API
-    pub fn get_attr(&self, _id: &RequestId, index: u64) -> DenebResult<FileAttributes> {
-        call(GetAttr { index }, &self.cmd_ch)
+    pub fn get_attr(&self, id: &RequestId, index: u64) -> DenebResult<FileAttributes> {
+        call_as(id, GetAttr { index }, &self.cmd_ch)
+    }
+
+    /// Attributes of several inodes, with a single request
+    ///
+    /// Inodes which can't be found have no attributes.
+    pub fn get_attr_batch(
+        &self,
+        id: &RequestId,
+        indices: Vec<u64>,
+    ) -> DenebResult<Vec<Option<FileAttributes>>> {
+        call_as(id, GetAttrBatch { indices }, &self.cmd_ch)
+    }
+
+    /// Attributes of the root directory of the repository
+    pub fn get_root_attr(&self) -> DenebResult<FileAttributes> {
+        call(GetRoot, &self.cmd_ch)
     }
 
     pub fn set_attr(
         &self,
-        _id: &RequestId,
+        id: &RequestId,
         index: u64,
         changes: FileAttributeChanges,
     ) -> DenebResult<FileAttributes> {
-        call(SetAttr { index, changes }, &self.cmd_ch)
+        call_as(
+            id,
+            SetAttr {
+                index,
+                changes,
+                uid: id.uid,
+            },
+            &self.cmd_ch,
+        )
     }
 
-    pub fn lookup(
-        &self,
-        _id: &RequestId,
-        parent: u64,
-        name: &OsStr,
-    ) -> DenebResult<Option<FileAttributes>> {
-        call(
+    pub fn lookup(&self, id: &RequestId, parent: u64, name: &OsStr) -> DenebResult<Option<Entry>> {
+        call_as(
+            id,
             Lookup {
                 parent,
                 name: name.to_os_string(),
@@ -52,56 +86,211 @@ impl Handle {
         )
     }
 
-    pub fn open_dir(&self, _id: &RequestId, index: u64, flags: u32) -> DenebResult<()> {
-        call(OpenDir { index, flags }, &self.cmd_ch)
+    /// Look up several entries of a directory, with a single request
+    ///
+    /// Unlike `lookup`, the inodes found are not counted as looked up by the
+    /// kernel, so they don't need to be forgotten.
+    pub fn lookup_batch(
+        &self,
+        id: &RequestId,
+        parent: u64,
+        names: Vec<OsString>,
+    ) -> DenebResult<Vec<Option<FileAttributes>>> {
+        call_as(id, LookupBatch { parent, names }, &self.cmd_ch)
+    }
+
+    /// The kernel dropped `nlookup` of the lookups of `index`
+    pub fn forget(&self, index: u64, nlookup: u64) {
+        self.forget_multi(vec![(index, nlookup)])
+    }
+
+    /// The kernel dropped lookups of several inodes, given with the number of
+    /// lookups dropped
+    pub fn forget_multi(&self, inodes: Vec<(u64, u64)>) {
+        cast(Forget { inodes }, &self.cmd_ch)
+    }
+
+    /// Find the file at `path`, relative to the root of the repository,
+    /// without following symbolic links
+    pub fn resolve_path(
+        &self,
+        id: &RequestId,
+        path: &Path,
+    ) -> DenebResult<Option<(u64, FileAttributes)>> {
+        call_as(
+            id,
+            ResolvePath {
+                path: path.to_owned(),
+            },
+            &self.cmd_ch,
+        )
+    }
+
+    pub fn open_dir(&self, id: &RequestId, index: u64, flags: u32) -> DenebResult<()> {
+        call_as(id, OpenDir { index, flags }, &self.cmd_ch)
     }
 
-    pub fn release_dir(&self, _id: &RequestId, index: u64, flags: u32) -> DenebResult<()> {
-        call(ReleaseDir { index, flags }, &self.cmd_ch)
+    pub fn release_dir(&self, id: &RequestId, index: u64, flags: u32) -> DenebResult<()> {
+        call_as(id, ReleaseDir { index, flags }, &self.cmd_ch)
     }
 
+    /// Read the entries of a directory, starting after the one with the
+    /// cookie `offset`
+    ///
+    /// The entries are returned with their cookies and their attributes, so
+    /// listing a directory doesn't need a request per entry. An offset of 0
+    /// starts at the first entry.
     pub fn read_dir(
         &self,
-        _id: &RequestId,
+        id: &RequestId,
         index: u64,
         offset: i64,
-    ) -> DenebResult<Vec<(PathBuf, u64, FileType)>> {
-        call(ReadDir { index, offset }, &self.cmd_ch)
+    ) -> DenebResult<Vec<(i64, PathBuf, FileAttributes)>> {
+        call_as(id, ReadDir { index, offset }, &self.cmd_ch)
+    }
+
+    pub fn read_link(&self, id: &RequestId, index: u64) -> DenebResult<PathBuf> {
+        call_as(id, ReadLink { index }, &self.cmd_ch)
     }
 
-    pub fn open_file(&self, _id: &RequestId, index: u64, flags: u32) -> DenebResult<()> {
-        call(OpenFile { index, flags }, &self.cmd_ch)
+    /// Digest of the whole contents of a regular file
+    ///
+    /// Not known for files with changes which were not written to the store,
+    /// nor for files which weren't written since digests are recorded.
+    pub fn file_digest(&self, id: &RequestId, index: u64) -> DenebResult<Option<Digest>> {
+        call_as(id, GetFileDigest { index }, &self.cmd_ch)
     }
 
+    /// The root hash of the most recent retained commit holding a different
+    /// version of the file, with the attributes of that version
+    pub fn previous_version(
+        &self,
+        id: &RequestId,
+        index: u64,
+    ) -> DenebResult<Option<(Digest, FileAttributes)>> {
+        call_as(id, GetPreviousVersion { index }, &self.cmd_ch)
+    }
+
+    /// The contents of the version of the file returned by `previous_version`
+    pub fn read_previous_version(
+        &self,
+        id: &RequestId,
+        index: u64,
+    ) -> DenebResult<Option<Vec<u8>>> {
+        call_as(id, ReadPreviousVersion { index }, &self.cmd_ch)
+    }
+
+    /// Value of an extended attribute kept with a file, such as its ACLs
+    pub fn get_xattr(
+        &self,
+        id: &RequestId,
+        index: u64,
+        name: &OsStr,
+    ) -> DenebResult<Option<Vec<u8>>> {
+        call_as(
+            id,
+            GetXattr {
+                index,
+                name: name.to_owned(),
+            },
+            &self.cmd_ch,
+        )
+    }
+
+    /// Names of the extended attributes kept with a file
+    pub fn list_xattrs(&self, id: &RequestId, index: u64) -> DenebResult<Vec<OsString>> {
+        call_as(id, ListXattrs { index }, &self.cmd_ch)
+    }
+
+    /// Set an extended attribute of a file; only the POSIX ACLs are supported
+    pub fn set_xattr(
+        &self,
+        id: &RequestId,
+        index: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+    ) -> DenebResult<()> {
+        call_as(
+            id,
+            SetXattr {
+                index,
+                name: name.to_owned(),
+                value: value.to_vec(),
+                flags,
+                uid: id.uid,
+            },
+            &self.cmd_ch,
+        )
+    }
+
+    pub fn remove_xattr(&self, id: &RequestId, index: u64, name: &OsStr) -> DenebResult<()> {
+        call_as(
+            id,
+            RemoveXattr {
+                index,
+                name: name.to_owned(),
+                uid: id.uid,
+            },
+            &self.cmd_ch,
+        )
+    }
+
+    pub fn open_file(&self, id: &RequestId, index: u64, flags: u32) -> DenebResult<OpenFlags> {
+        call_as(
+            id,
+            OpenFile {
+                index,
+                flags,
+                uid: id.uid,
+                gid: id.gid,
+            },
+            &self.cmd_ch,
+        )
+    }
+
+    /// Read from an open file. The read can be abandoned with `interrupt`
+    /// until it returns.
     pub fn read_data(
         &self,
-        _id: &RequestId,
+        id: &RequestId,
         index: u64,
         offset: i64,
         size: u32,
     ) -> DenebResult<Vec<u8>> {
-        call(
+        self.interrupts
+            .lock()
+            .unwrap()
+            .insert(id.unique_id, (*id, false));
+        let result = call_as(
+            id,
             ReadData {
+                id: id.unique_id,
                 index,
                 offset,
                 size,
             },
             &self.cmd_ch,
-        )
+        );
+        self.interrupts.lock().unwrap().remove(&id.unique_id);
+        result
     }
 
+    /// Write to an open file. The engine keeps `data` as it is until the file
+    /// is committed, instead of copying it.
     pub fn write_data(
         &self,
-        _id: &RequestId,
+        id: &RequestId,
         index: u64,
         offset: i64,
-        data: &[u8],
+        data: Vec<u8>,
     ) -> DenebResult<u32> {
-        call(
+        call_as(
+            id,
             WriteData {
                 index,
                 offset,
-                data: data.to_vec(),
+                data: Cell::new(data),
             },
             &self.cmd_ch,
         )
@@ -109,13 +298,14 @@ impl Handle {
 
     pub fn release_file(
         &self,
-        _id: &RequestId,
+        id: &RequestId,
         index: u64,
         flags: u32,
         lock_owner: u64,
         flush: bool,
     ) -> DenebResult<()> {
-        call(
+        call_as(
+            id,
             ReleaseFile {
                 index,
                 flags,
@@ -128,13 +318,14 @@ impl Handle {
 
     pub fn create_file(
         &self,
-        _id: &RequestId,
+        id: &RequestId,
         parent: u64,
         name: &OsStr,
         mode: u32,
         flags: u32,
-    ) -> DenebResult<(u64, FileAttributes)> {
-        call(
+    ) -> DenebResult<(Entry, OpenFlags)> {
+        call_as(
+            id,
             CreateFile {
                 parent,
                 name: name.to_owned(),
@@ -147,12 +338,13 @@ impl Handle {
 
     pub fn create_dir(
         &self,
-        _id: &RequestId,
+        id: &RequestId,
         parent: u64,
         name: &OsStr,
         mode: u32,
-    ) -> DenebResult<FileAttributes> {
-        call(
+    ) -> DenebResult<Entry> {
+        call_as(
+            id,
             CreateDir {
                 parent,
                 name: name.to_owned(),
@@ -162,8 +354,9 @@ impl Handle {
         )
     }
 
-    pub fn unlink(&self, _id: &RequestId, parent: u64, name: &OsStr) -> DenebResult<()> {
-        call(
+    pub fn unlink(&self, id: &RequestId, parent: u64, name: &OsStr) -> DenebResult<()> {
+        call_as(
+            id,
             Unlink {
                 parent,
                 name: name.to_owned(),
@@ -172,8 +365,9 @@ impl Handle {
         )
     }
 
-    pub fn remove_dir(&self, _id: &RequestId, parent: u64, name: &OsStr) -> DenebResult<()> {
-        call(
+    pub fn remove_dir(&self, id: &RequestId, parent: u64, name: &OsStr) -> DenebResult<()> {
+        call_as(
+            id,
             RemoveDir {
                 parent,
                 name: name.to_owned(),
@@ -184,13 +378,14 @@ impl Handle {
 
     pub fn rename(
         &self,
-        _id: &RequestId,
+        id: &RequestId,
         parent: u64,
         name: &OsStr,
         new_parent: u64,
         new_name: &OsStr,
     ) -> DenebResult<()> {
-        call(
+        call_as(
+            id,
             Rename {
                 parent,
                 name: name.to_owned(),
@@ -201,22 +396,185 @@ impl Handle {
         )
     }
 
-    pub fn commit(&self) -> DenebResult<String> {
-        let summary = call(Commit, &self.cmd_ch)?;
+    /// Apply several operations at once, in order, e.g. to import a burst of
+    /// changes
+    ///
+    /// No commit starts or finishes while the operations are applied, so the
+    /// commits see either none or all of them. All the operations are checked
+    /// before the first one is applied, so that a batch which fails, e.g.
+    /// because a name is taken, changes nothing. Returns the attributes of the
+    /// inode created by each operation, if any.
+    pub fn batch(
+        &self,
+        id: &RequestId,
+        operations: Vec<BatchOperation>,
+    ) -> DenebResult<Vec<Option<FileAttributes>>> {
+        call_as(
+            id,
+            Batch {
+                operations: Cell::new(operations),
+            },
+            &self.cmd_ch,
+        )
+    }
+
+    /// Commit the workspace. Without a message, one is generated.
+    pub fn commit(&self, message: Option<String>) -> DenebResult<CommitSummary> {
+        call(Commit { message }, &self.cmd_ch)
+    }
+
+    /// Returns what the next commit would write, relative to the last commit
+    pub fn pending_changes(&self) -> DenebResult<PendingChanges> {
+        call(DiffWorkspace, &self.cmd_ch)
+    }
+
+    /// Copy a file or directory from the commit with the given root hash into
+    /// the workspace, returning the path under which it was restored
+    pub fn restore(&self, root_hash: Digest, path: &Path, overwrite: bool) -> DenebResult<PathBuf> {
+        call(
+            Restore {
+                root_hash,
+                path: path.to_owned(),
+                overwrite,
+            },
+            &self.cmd_ch,
+        )
+    }
+
+    /// Delete all the entries of the trash, returning how many there were
+    pub fn empty_trash(&self) -> DenebResult<usize> {
+        call(EmptyTrash, &self.cmd_ch)
+    }
+
+    /// Commit the workspace and remove the chunks which are no longer referenced
+    pub fn collect_garbage(&self) -> DenebResult<String> {
+        let summary = call(CollectGarbage, &self.cmd_ch)?;
         Ok(format!("{}", summary))
     }
 
+    /// Remove the commits which are not kept by `policy` from the reflog, then
+    /// collect the chunks which are no longer referenced
+    pub fn prune(&self, policy: PrunePolicy) -> DenebResult<String> {
+        let (removed, summary) = call(Prune { policy }, &self.cmd_ch)?;
+        Ok(format!("{} commits pruned\n{}", removed, summary))
+    }
+
+    /// Returns the token and name of each maintenance operation in progress
+    pub fn running_operations(&self) -> DenebResult<Vec<(u64, String)>> {
+        call(GetOperations, &self.cmd_ch)
+    }
+
+    /// Ask for a maintenance operation to be aborted. Returns `false` if no
+    /// operation with the given token is running.
+    pub fn cancel(&self, token: u64) -> DenebResult<bool> {
+        call(Cancel { token }, &self.cmd_ch)
+    }
+
+    /// Pin or unpin the entry `index`: the chunks of a pinned file, or of the
+    /// files under a pinned directory, are kept by the local part of a tiered
+    /// store. Returns whether the entry was pinned before.
+    pub fn set_pinned(&self, index: u64, pinned: bool) -> DenebResult<bool> {
+        call(Pin { index, pinned }, &self.cmd_ch)
+    }
+
+    /// Drop the local copies of the chunks of the files under the entry
+    /// `index`, or only of the ones neither read nor modified for
+    /// `untouched_for`, from a tiered store. They are fetched again from the
+    /// remote store when read. Returns the number of chunks dropped and their
+    /// packed size.
+    pub fn dehydrate(
+        &self,
+        index: u64,
+        untouched_for: Option<Duration>,
+    ) -> DenebResult<(u64, u64)> {
+        call(
+            Dehydrate {
+                index,
+                untouched_for,
+            },
+            &self.cmd_ch,
+        )
+    }
+
+    /// Returns the manifest of the last commit
+    pub fn manifest(&self) -> DenebResult<Manifest> {
+        call(GetManifest, &self.cmd_ch)
+    }
+
+    /// Returns the manifests of the commits recorded in the reflog, newest first
+    pub fn log(&self) -> DenebResult<Vec<Manifest>> {
+        call(GetLog, &self.cmd_ch)
+    }
+
     pub fn ping(&self) -> DenebResult<String> {
         call(Ping, &self.cmd_ch)
     }
 
+    /// Load the chunks of a file into the chunk cache of the store
+    pub fn prefetch(&self, id: &RequestId, index: u64) -> DenebResult<()> {
+        call_as(id, Prefetch { index }, &self.cmd_ch)
+    }
+
+    /// Returns the indices of the files most recently opened, including before
+    /// the last restart
+    pub fn recent_files(&self, count: usize) -> DenebResult<Vec<u64>> {
+        call(GetRecentFiles { count }, &self.cmd_ch)
+    }
+
+    pub fn metrics(&self) -> DenebResult<Metrics> {
+        call(GetMetrics, &self.cmd_ch)
+    }
+
+    /// Apply new values of the settings which can be changed while the engine runs
+    pub fn reconfigure(&self, settings: LiveSettings) -> DenebResult<()> {
+        call(Reconfigure { settings }, &self.cmd_ch)
+    }
+
+    /// Signal that the caller of a request is no longer waiting for its reply
+    ///
+    /// An interrupted read is abandoned before its next chunk is loaded and fails
+    /// with `EngineError::Interrupted`. Requests which have already completed, or
+    /// which are not reads, are not affected.
+    pub fn interrupt(&self, id: &RequestId) {
+        if let Some(read) = self.interrupts.lock().unwrap().get_mut(&id.unique_id) {
+            read.1 = true;
+        }
+    }
+
+    /// Returns the ids of the reads in flight which haven't been interrupted
+    pub fn reads_in_flight(&self) -> Vec<RequestId> {
+        self.interrupts
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|(_, interrupted)| !interrupted)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
     pub fn stop_engine(&self) {
-        cast(StopEngine, &self.cmd_ch);
+        cast(StopEngine { checkpoint: false }, &self.cmd_ch);
+        let _ = self.stop_ch.recv();
+    }
+
+    /// Stop the engine, saving the changes which weren't committed to a
+    /// checkpoint which is restored when the engine is started again. The
+    /// changes are committed if the checkpoint can't be saved.
+    pub fn stop_engine_with_checkpoint(&self) {
+        cast(StopEngine { checkpoint: true }, &self.cmd_ch);
         let _ = self.stop_ch.recv();
     }
 
     // Private functions
-    pub(in crate::engine) fn new(cmd_ch: RequestChannel<Engine>, stop_ch: Receiver<()>) -> Handle {
-        Handle { cmd_ch, stop_ch }
+    pub(in crate::engine) fn new(
+        cmd_ch: RequestChannel<Engine>,
+        stop_ch: Receiver<()>,
+        interrupts: Interrupts,
+    ) -> Handle {
+        Handle {
+            cmd_ch,
+            stop_ch,
+            interrupts,
+        }
     }
 }
@@ -0,0 +1,208 @@
+use {
+    crate::{
+        cas::Digest,
+        errors::{DenebResult, EngineError},
+        workspace::CommitSummary,
+    },
+    failure::ResultExt,
+    log::{info, warn},
+    std::{
+        io,
+        path::{Path, PathBuf},
+        process::{Child, Command, ExitStatus, Stdio},
+        thread::{sleep, spawn},
+        time::{Duration, Instant},
+    },
+};
+
+/// How long the pre-commit hook may run when the engine has no request timeout
+pub(in crate::engine) const DEFAULT_PRE_COMMIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+const HOOK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Scripts run by the engine around the commits which have something to write
+///
+/// The scripts are given the state of the commit in environment variables,
+/// `DENEB_HOOK` holding the name of the hook. A commit is abandoned when its
+/// pre-commit hook fails. The engine serves no requests while the pre-commit
+/// hook runs, so a hook running longer than the request timeout of the engine
+/// (or a minute, without a timeout) is killed and the commit is abandoned as
+/// well. The post-commit hook runs in the background, so that
+/// slow work, such as pushing the new commit off-site, doesn't hold up the
+/// engine; its failures are only logged.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommitHooks {
+    /// Run before the files are written, with `DENEB_ROOT_HASH` (the current
+    /// commit), `DENEB_PENDING_FILES`, `DENEB_PENDING_BYTES` and
+    /// `DENEB_COMMIT_MESSAGE`, when the commit has a message
+    pub pre_commit: Option<PathBuf>,
+    /// Run after the new commit is recorded, with `DENEB_ROOT_HASH` (the new
+    /// commit), `DENEB_PREVIOUS_ROOT_HASH`, `DENEB_FILES_WRITTEN`,
+    /// `DENEB_CHUNKS_WRITTEN` and `DENEB_INODES_DELETED`
+    pub post_commit: Option<PathBuf>,
+}
+
+impl CommitHooks {
+    /// Run the pre-commit hook, if any, and wait for it to finish, for at most
+    /// `timeout`
+    pub(in crate::engine) fn pre_commit(
+        &self,
+        root_hash: &Digest,
+        pending_files: usize,
+        pending_bytes: u64,
+        message: Option<&str>,
+        timeout: Duration,
+    ) -> DenebResult<()> {
+        let script = match self.pre_commit {
+            Some(ref script) => script,
+            None => return Ok(()),
+        };
+        let mut command = hook_command(script, "pre-commit");
+        command
+            .env("DENEB_ROOT_HASH", root_hash.to_string())
+            .env("DENEB_PENDING_FILES", pending_files.to_string())
+            .env("DENEB_PENDING_BYTES", pending_bytes.to_string());
+        if let Some(message) = message {
+            command.env("DENEB_COMMIT_MESSAGE", message);
+        }
+        let mut child = command
+            .spawn()
+            .context(EngineError::Hook(script.to_owned()))?;
+        let status = wait_until(&mut child, Instant::now() + timeout)
+            .context(EngineError::Hook(script.to_owned()))?;
+        match status {
+            Some(status) if status.success() => Ok(()),
+            Some(status) => {
+                Err(EngineError::CommitRejected(script.to_owned(), status.to_string()).into())
+            }
+            None => {
+                warn!("Pre-commit hook {:?} timed out, killing it", script);
+                let _ = child.kill();
+                let _ = child.wait();
+                let reason = format!("timed out after {:?}", timeout);
+                Err(EngineError::CommitRejected(script.to_owned(), reason).into())
+            }
+        }
+    }
+
+    /// Start the post-commit hook, if any, without waiting for it
+    pub(in crate::engine) fn post_commit(&self, previous: &Digest, summary: &CommitSummary) {
+        let script = match self.post_commit {
+            Some(ref script) => script.to_owned(),
+            None => return,
+        };
+        let mut command = hook_command(&script, "post-commit");
+        command
+            .env(
+                "DENEB_ROOT_HASH",
+                summary.new_root_hash.clone().unwrap_or_default(),
+            )
+            .env("DENEB_PREVIOUS_ROOT_HASH", previous.to_string())
+            .env("DENEB_FILES_WRITTEN", summary.files_written.to_string())
+            .env("DENEB_CHUNKS_WRITTEN", summary.chunks_written.to_string())
+            .env("DENEB_INODES_DELETED", summary.inodes_deleted.to_string());
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Could not run the post-commit hook {:?}: {}", script, e);
+                return;
+            }
+        };
+        spawn(move || match child.wait() {
+            Ok(ref status) if status.success() => {
+                info!("Post-commit hook {:?} finished", script);
+            }
+            Ok(status) => warn!("Post-commit hook {:?} failed: {}", script, status),
+            Err(e) => warn!(
+                "Could not wait for the post-commit hook {:?}: {}",
+                script, e
+            ),
+        });
+    }
+}
+
+// Returns `None` if the child is still running at `deadline`
+fn wait_until(child: &mut Child, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        sleep(HOOK_POLL_INTERVAL);
+    }
+}
+
+fn hook_command(script: &Path, hook: &str) -> Command {
+    let mut command = Command::new(script);
+    command.stdin(Stdio::null()).env("DENEB_HOOK", hook);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::cas::hash,
+        std::{
+            fs::{read_to_string, set_permissions, write, Permissions},
+            os::unix::fs::PermissionsExt,
+        },
+        tempdir::TempDir,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn pre_commit_hooks_can_reject_commits() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_hooks")?;
+        let script = tmp.path().join("pre-commit");
+        let out = tmp.path().join("out");
+        write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$DENEB_HOOK $DENEB_PENDING_FILES $DENEB_COMMIT_MESSAGE\" > {:?}\n\
+                 test \"$DENEB_PENDING_BYTES\" -lt 100\n",
+                out
+            ),
+        )?;
+        set_permissions(&script, Permissions::from_mode(0o755))?;
+        let hooks = CommitHooks {
+            pre_commit: Some(script),
+            post_commit: None,
+        };
+        let root_hash = hash(b"alabalaportocala");
+
+        hooks.pre_commit(&root_hash, 2, 16, Some("First commit"), TIMEOUT)?;
+        assert_eq!(read_to_string(&out)?, "pre-commit 2 First commit\n");
+        assert!(hooks
+            .pre_commit(&root_hash, 2, 1024, None, TIMEOUT)
+            .is_err());
+        assert!(CommitHooks::default()
+            .pre_commit(&root_hash, 2, 1024, None, TIMEOUT)
+            .is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn slow_pre_commit_hooks_are_killed() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_hooks_slow")?;
+        let script = tmp.path().join("pre-commit");
+        write(&script, "#!/bin/sh\nsleep 10\n")?;
+        set_permissions(&script, Permissions::from_mode(0o755))?;
+        let hooks = CommitHooks {
+            pre_commit: Some(script),
+            post_commit: None,
+        };
+
+        let start = Instant::now();
+        let timeout = Duration::from_millis(100);
+        assert!(hooks
+            .pre_commit(&hash(b"alabalaportocala"), 1, 16, None, timeout)
+            .is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+}
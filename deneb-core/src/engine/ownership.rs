@@ -0,0 +1,150 @@
+use crate::{
+    errors::{DenebResult, EngineError},
+    inode::{FileAttributeChanges, FileAttributes},
+};
+
+/// Who owns the files and who may change their owner, group and permissions
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OwnershipPolicy {
+    /// Only root may change the owner of a file, and only root or the owner
+    /// of a file may change its group and its permissions. Otherwise anyone
+    /// who can reach the mount point may change them.
+    pub restrict_chown: bool,
+    /// Owner reported for all the files, and given to the new ones
+    pub force_uid: Option<u32>,
+    /// Group reported for all the files, and given to the new ones
+    pub force_gid: Option<u32>,
+    /// Permissions removed from the mode of the new files and directories
+    pub umask: u32,
+    /// Check the access ACLs of the files when they are opened, which are
+    /// otherwise only kept
+    pub enforce_acls: bool,
+}
+
+impl OwnershipPolicy {
+    /// Check that the user `uid` may change the ACLs of a file with `attrs`
+    pub(in crate::engine) fn check_owner(
+        &self,
+        uid: u32,
+        attrs: &FileAttributes,
+    ) -> DenebResult<()> {
+        if self.restrict_chown && uid != 0 && uid != attrs.uid {
+            return Err(EngineError::PermissionDenied(attrs.index).into());
+        }
+        Ok(())
+    }
+
+    /// The attributes of a file as reported to the users of the repository
+    pub(in crate::engine) fn present(&self, mut attrs: FileAttributes) -> FileAttributes {
+        if let Some(uid) = self.force_uid {
+            attrs.uid = uid;
+        }
+        if let Some(gid) = self.force_gid {
+            attrs.gid = gid;
+        }
+        attrs
+    }
+
+    /// The mode of a new file or directory created with `mode`
+    pub(in crate::engine) fn creation_mode(&self, mode: u32) -> u32 {
+        mode & !(self.umask & 0o7777)
+    }
+
+    /// The changes which give a new file its forced owner and group, if any
+    pub(in crate::engine) fn new_owner(&self) -> Option<FileAttributeChanges> {
+        if self.force_uid.is_none() && self.force_gid.is_none() {
+            return None;
+        }
+        Some(FileAttributeChanges::new(
+            None,
+            self.force_uid,
+            self.force_gid,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ))
+    }
+
+    /// Check that the user `uid` may apply `changes` to a file with `attrs`
+    pub(in crate::engine) fn check(
+        &self,
+        uid: u32,
+        attrs: &FileAttributes,
+        changes: &FileAttributeChanges,
+    ) -> DenebResult<()> {
+        if !self.restrict_chown || uid == 0 {
+            return Ok(());
+        }
+        let chown = changes.uid.map_or(false, |owner| owner != attrs.uid);
+        let chgrp = changes.gid.map_or(false, |group| group != attrs.gid);
+        let chmod = changes.mode.is_some();
+        if chown || ((chgrp || chmod) && uid != attrs.uid) {
+            return Err(EngineError::PermissionDenied(attrs.index).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(mode: Option<u32>, uid: Option<u32>, gid: Option<u32>) -> FileAttributeChanges {
+        FileAttributeChanges::new(mode, uid, gid, None, None, None, None, None, None)
+    }
+
+    #[test]
+    fn only_root_changes_owners() {
+        let mut attrs = FileAttributes::default();
+        attrs.uid = 1000;
+        attrs.gid = 1000;
+        let open = OwnershipPolicy::default();
+        let restricted = OwnershipPolicy {
+            restrict_chown: true,
+            ..OwnershipPolicy::default()
+        };
+
+        assert!(open
+            .check(1001, &attrs, &changes(None, Some(0), None))
+            .is_ok());
+        assert!(restricted
+            .check(0, &attrs, &changes(None, Some(1001), Some(1001)))
+            .is_ok());
+        assert!(restricted
+            .check(1000, &attrs, &changes(None, Some(1001), None))
+            .is_err());
+        // Owners may keep their files, change their group and their mode
+        assert!(restricted
+            .check(1000, &attrs, &changes(Some(0o600), Some(1000), Some(100)))
+            .is_ok());
+        assert!(restricted
+            .check(1001, &attrs, &changes(Some(0o600), None, None))
+            .is_err());
+        assert!(restricted
+            .check(1001, &attrs, &changes(None, None, Some(1001)))
+            .is_err());
+    }
+
+    #[test]
+    fn forced_owners_are_reported_and_given_to_new_files() {
+        let mut attrs = FileAttributes::default();
+        attrs.uid = 1000;
+        attrs.gid = 1000;
+        let shared = OwnershipPolicy {
+            force_uid: Some(1001),
+            umask: 0o027,
+            ..OwnershipPolicy::default()
+        };
+
+        let presented = shared.present(attrs);
+        assert_eq!((presented.uid, presented.gid), (1001, 1000));
+        assert_eq!(shared.creation_mode(0o100_666), 0o100_640);
+        let owner = shared.new_owner().unwrap();
+        assert_eq!((owner.uid, owner.gid), (Some(1001), None));
+        assert!(OwnershipPolicy::default().new_owner().is_none());
+        assert_eq!(OwnershipPolicy::default().creation_mode(0o40_755), 0o40_755);
+    }
+}
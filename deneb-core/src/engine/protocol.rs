@@ -1,11 +1,143 @@
 use {
-    crate::errors::{DenebResult, EngineError},
-    crossbeam_channel::{bounded as channel, Sender},
-    std::marker::PhantomData,
+    super::requests::RequestId,
+    crate::errors::{DenebError, DenebResult, EngineError},
+    crossbeam_channel::{
+        bounded as channel, select, Receiver, RecvTimeoutError, SendTimeoutError, Sender,
+        TrySendError,
+    },
+    failure::Error,
+    log::warn,
+    serde::{Deserialize, Serialize},
+    std::{
+        fmt::Debug,
+        marker::PhantomData,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    },
 };
 
-pub trait Request: Send {
+/// Minimum time between two warnings about a full request queue
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interactive requests taking longer than this, including the time spent in
+/// the queue, are reported as slow, unless configured otherwise
+const DEFAULT_SLOW_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Determines the queue on which a request is sent to the engine
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    /// Requests made on behalf of users, such as file system operations
+    Interactive,
+    /// Background work, such as commits and prefetching. These requests are
+    /// only handled when there are no interactive requests waiting.
+    Maintenance,
+}
+
+/// A request handled by the engine
+///
+/// Its `Debug` output is logged when it is slow, so it shouldn't include
+/// bulk data.
+pub trait Request: Send + Debug {
     type Reply: Send;
+
+    /// Name under which the request is counted in the engine metrics
+    const NAME: &'static str;
+
+    const PRIORITY: Priority = Priority::Interactive;
+
+    /// Whether the request can be dropped when its queue is full, if the
+    /// overload policy allows it
+    const SHEDDABLE: bool = false;
+}
+
+/// What happens to a request sent to the engine when its queue is full
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverloadPolicy {
+    /// The sender waits until there is room in the queue
+    Block,
+    /// Sheddable requests, such as prefetching, are dropped. The other
+    /// requests wait, as with `Block`.
+    Shed,
+}
+
+/// Time limits of the requests sent to the engine
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RequestLimits {
+    /// How long callers wait for the reply to an interactive request, including
+    /// the time spent in the queue. Maintenance requests, such as commits, are
+    /// always waited for.
+    pub timeout: Option<Duration>,
+    /// Interactive requests taking longer than this are logged, along with
+    /// their arguments
+    pub slow_threshold: Duration,
+}
+
+impl Default for RequestLimits {
+    fn default() -> RequestLimits {
+        RequestLimits {
+            timeout: None,
+            slow_threshold: DEFAULT_SLOW_THRESHOLD,
+        }
+    }
+}
+
+impl FromStr for OverloadPolicy {
+    type Err = DenebError;
+
+    fn from_str(s: &str) -> Result<OverloadPolicy, DenebError> {
+        match s {
+            "block" => Ok(OverloadPolicy::Block),
+            "shed" => Ok(OverloadPolicy::Shed),
+            _ => Err(DenebError::CommandLineParameter(
+                "overload_policy: ".to_string() + s,
+            )),
+        }
+    }
+}
+
+/// A snapshot of the state of the request queues
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    /// Number of requests each queue can hold
+    pub capacity: u64,
+    /// Number of interactive requests waiting to be handled
+    pub interactive: u64,
+    /// Number of maintenance requests waiting to be handled
+    pub maintenance: u64,
+    /// Number of requests which had to wait for room in a full queue
+    pub blocked_sends: u64,
+    /// Number of requests dropped because their queue was full
+    pub shed_requests: u64,
+}
+
+/// Counters shared by the sending and the receiving sides of the queues
+#[derive(Default)]
+struct QueueCounters {
+    blocked_sends: AtomicUsize,
+    shed_requests: AtomicUsize,
+    last_warning: Mutex<Option<Instant>>,
+}
+
+impl QueueCounters {
+    fn record_blocked_send(&self, priority: Priority) {
+        let blocked = self.blocked_sends.fetch_add(1, Ordering::Relaxed) + 1;
+        // Warnings are rate limited, since a saturated engine can block many
+        // senders every second
+        let mut last_warning = self.last_warning.lock().unwrap();
+        let now = Instant::now();
+        if last_warning.map_or(true, |at| now.duration_since(at) >= WARNING_INTERVAL) {
+            *last_warning = Some(now);
+            warn!(
+                "The {:?} request queue of the engine is full ({} blocked sends so far)",
+                priority, blocked
+            );
+        }
+    }
 }
 
 pub trait RequestHandler<R>
@@ -17,16 +149,205 @@ where
 
 pub trait HandlerProxy: Send {
     type Handler;
+    fn name(&self) -> &'static str;
+    /// The request and its arguments
+    fn describe(&self) -> String;
     fn run_handler(&self, handler: &mut Self::Handler);
 }
 
 pub struct PackagedRequest<H> {
     inner: Box<HandlerProxy<Handler = H>>,
+    priority: Priority,
+    queued_at: Instant,
+    caller: Option<RequestId>,
 }
 
-pub type RequestChannel<H> = Sender<PackagedRequest<H>>;
+impl<H> PackagedRequest<H> {
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// When the request was queued
+    pub fn queued_at(&self) -> Instant {
+        self.queued_at
+    }
+
+    /// The id of the file system request on whose behalf the request was made, if any
+    pub fn caller(&self) -> Option<RequestId> {
+        self.caller
+    }
+}
+
+/// Sending side of the engine request queues
+pub struct RequestChannel<H> {
+    interactive: Sender<PackagedRequest<H>>,
+    maintenance: Sender<PackagedRequest<H>>,
+    policy: OverloadPolicy,
+    timeout: Option<Duration>,
+    counters: Arc<QueueCounters>,
+}
+
+impl<H> RequestChannel<H> {
+    /// Queue a request, waiting for room in the queue if it is full
+    ///
+    /// Fails if the request is shed because of the overload policy, or if
+    /// there is still no room at `deadline`.
+    fn send(
+        &self,
+        sheddable: bool,
+        envelope: PackagedRequest<H>,
+        deadline: Option<Instant>,
+    ) -> DenebResult<()> {
+        let priority = envelope.priority;
+        let ch = match priority {
+            Priority::Interactive => &self.interactive,
+            Priority::Maintenance => &self.maintenance,
+        };
+        let envelope = match ch.try_send(envelope) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(envelope)) => envelope,
+            Err(TrySendError::Disconnected(_)) => panic!("{}", EngineError::Send),
+        };
+        if sheddable && self.policy == OverloadPolicy::Shed {
+            self.counters.shed_requests.fetch_add(1, Ordering::Relaxed);
+            return Err(EngineError::Overloaded.into());
+        }
+        self.counters.record_blocked_send(priority);
+        match deadline {
+            Some(deadline) => {
+                let timeout = remaining(deadline);
+                match ch.send_timeout(envelope, timeout) {
+                    Ok(()) => Ok(()),
+                    Err(SendTimeoutError::Timeout(envelope)) => {
+                        Err(timed_out(envelope.name(), envelope.caller, timeout))
+                    }
+                    Err(SendTimeoutError::Disconnected(_)) => panic!("{}", EngineError::Send),
+                }
+            }
+            None => {
+                ch.send(envelope).map_err(|_| EngineError::Send).unwrap();
+                Ok(())
+            }
+        }
+    }
+
+    /// When the caller of a request with the given priority, sent now, stops waiting
+    fn deadline(&self, priority: Priority) -> Option<Instant> {
+        match priority {
+            Priority::Interactive => self.timeout.map(|timeout| Instant::now() + timeout),
+            Priority::Maintenance => None,
+        }
+    }
+}
+
+impl<H> Clone for RequestChannel<H> {
+    fn clone(&self) -> RequestChannel<H> {
+        RequestChannel {
+            interactive: self.interactive.clone(),
+            maintenance: self.maintenance.clone(),
+            policy: self.policy,
+            timeout: self.timeout,
+            counters: Arc::clone(&self.counters),
+        }
+    }
+}
+
+/// Receiving side of the engine request queues
+pub struct RequestQueue<H> {
+    interactive: Receiver<PackagedRequest<H>>,
+    maintenance: Receiver<PackagedRequest<H>>,
+    capacity: usize,
+    counters: Arc<QueueCounters>,
+}
+
+impl<H> RequestQueue<H> {
+    /// Wait for the next request, preferring interactive requests over maintenance ones
+    ///
+    /// Returns `None` once all the senders have been dropped.
+    pub fn next(&self) -> Option<PackagedRequest<H>> {
+        if let Ok(request) = self.interactive.try_recv() {
+            return Some(request);
+        }
+        // A receive only fails once all the senders are gone, in which case the
+        // requests left in the other queue are still returned
+        select! {
+            recv(self.interactive) -> request => {
+                request.ok().or_else(|| self.maintenance.recv().ok())
+            }
+            recv(self.maintenance) -> request => {
+                request.ok().or_else(|| self.interactive.recv().ok())
+            }
+        }
+    }
+
+    /// Returns a waiting interactive request, if there is one
+    pub fn next_interactive(&self) -> Option<PackagedRequest<H>> {
+        self.interactive.try_recv().ok()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            capacity: self.capacity as u64,
+            interactive: self.interactive.len() as u64,
+            maintenance: self.maintenance.len() as u64,
+            blocked_sends: self.counters.blocked_sends.load(Ordering::Relaxed) as u64,
+            shed_requests: self.counters.shed_requests.load(Ordering::Relaxed) as u64,
+        }
+    }
+}
+
+/// Create the request queues of the engine, each holding up to `capacity` requests
+///
+/// The callers of interactive requests give up after `timeout`, if set.
+pub fn request_queues<H>(
+    capacity: usize,
+    policy: OverloadPolicy,
+    timeout: Option<Duration>,
+) -> (RequestChannel<H>, RequestQueue<H>) {
+    let (interactive_tx, interactive_rx) = channel(capacity);
+    let (maintenance_tx, maintenance_rx) = channel(capacity);
+    let counters = Arc::new(QueueCounters::default());
+    (
+        RequestChannel {
+            interactive: interactive_tx,
+            maintenance: maintenance_tx,
+            policy,
+            timeout,
+            counters: Arc::clone(&counters),
+        },
+        RequestQueue {
+            interactive: interactive_rx,
+            maintenance: maintenance_rx,
+            capacity,
+            counters,
+        },
+    )
+}
 
 pub fn call<R, H>(req: R, ch: &RequestChannel<H>) -> DenebResult<R::Reply>
+where
+    R: Request + 'static,
+    H: RequestHandler<R> + 'static,
+{
+    send_and_wait(req, None, ch)
+}
+
+/// Make a request on behalf of the file system request `id`
+///
+/// The id is reported if the request is slow or times out.
+pub fn call_as<R, H>(id: &RequestId, req: R, ch: &RequestChannel<H>) -> DenebResult<R::Reply>
+where
+    R: Request + 'static,
+    H: RequestHandler<R> + 'static,
+{
+    send_and_wait(req, Some(*id), ch)
+}
+
+fn send_and_wait<R, H>(
+    req: R,
+    caller: Option<RequestId>,
+    ch: &RequestChannel<H>,
+) -> DenebResult<R::Reply>
 where
     R: Request + 'static,
     H: RequestHandler<R> + 'static,
@@ -38,10 +359,42 @@ where
             tx,
             _hd: PhantomData,
         }),
+        priority: R::PRIORITY,
+        queued_at: Instant::now(),
+        caller,
     };
-    ch.send(envelope).map_err(|_| EngineError::Send).unwrap();;
+    let deadline = ch.deadline(R::PRIORITY);
+    ch.send(R::SHEDDABLE, envelope, deadline)?;
 
-    rx.recv().map_err(|_| EngineError::NoReply)?
+    match deadline {
+        // The request is still handled after a timeout, its reply is dropped
+        Some(deadline) => {
+            let timeout = remaining(deadline);
+            match rx.recv_timeout(timeout) {
+                Ok(reply) => reply,
+                Err(RecvTimeoutError::Timeout) => Err(timed_out(R::NAME, caller, timeout)),
+                Err(RecvTimeoutError::Disconnected) => Err(EngineError::NoReply.into()),
+            }
+        }
+        None => rx.recv().map_err(|_| EngineError::NoReply)?,
+    }
+}
+
+fn remaining(deadline: Instant) -> Duration {
+    let now = Instant::now();
+    if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+fn timed_out(name: &'static str, caller: Option<RequestId>, waited: Duration) -> Error {
+    warn!(
+        "Gave up waiting for the {} request ({:?}) after {:?}",
+        name, caller, waited
+    );
+    EngineError::Timeout(name).into()
 }
 
 pub fn cast<R, H>(req: R, ch: &RequestChannel<H>)
@@ -54,8 +407,12 @@ where
             req,
             _hd: PhantomData,
         }),
+        priority: R::PRIORITY,
+        queued_at: Instant::now(),
+        caller: None,
     };
-    ch.send(envelope).map_err(|_| EngineError::Send).unwrap();
+    // A shed request is only counted, there is nobody waiting for its reply
+    let _ = ch.send(R::SHEDDABLE, envelope, None);
 }
 
 struct RequestProxy<R, H>
@@ -74,9 +431,16 @@ where
     H: RequestHandler<R>,
 {
     type Handler = H;
+    fn name(&self) -> &'static str {
+        R::NAME
+    }
+    fn describe(&self) -> String {
+        format!("{:?}", self.req)
+    }
     fn run_handler(&self, hd: &mut Self::Handler) {
         let reply = hd.handle(&self.req);
-        self.tx.send(reply).map_err(|_| EngineError::Send).unwrap();
+        // The caller may have stopped waiting
+        let _ = self.tx.send(reply);
     }
 }
 
@@ -95,6 +459,12 @@ where
     H: RequestHandler<R>,
 {
     type Handler = H;
+    fn name(&self) -> &'static str {
+        R::NAME
+    }
+    fn describe(&self) -> String {
+        format!("{:?}", self.req)
+    }
     fn run_handler(&self, hd: &mut Self::Handler) {
         let _ = hd.handle(&self.req);
     }
@@ -102,7 +472,145 @@ where
 
 impl<H> HandlerProxy for PackagedRequest<H> {
     type Handler = H;
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
     fn run_handler(&self, hd: &mut Self::Handler) {
         self.inner.run_handler(hd);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::thread::{sleep, spawn},
+    };
+
+    #[derive(Default)]
+    struct Recorder {
+        handled: Vec<&'static str>,
+    }
+
+    #[derive(Debug)]
+    struct Read;
+
+    impl Request for Read {
+        type Reply = ();
+        const NAME: &'static str = "read";
+    }
+
+    #[derive(Debug)]
+    struct Commit;
+
+    impl Request for Commit {
+        type Reply = ();
+        const NAME: &'static str = "commit";
+        const PRIORITY: Priority = Priority::Maintenance;
+    }
+
+    #[derive(Debug)]
+    struct Prefetch;
+
+    impl Request for Prefetch {
+        type Reply = ();
+        const NAME: &'static str = "prefetch";
+        const PRIORITY: Priority = Priority::Maintenance;
+        const SHEDDABLE: bool = true;
+    }
+
+    impl RequestHandler<Read> for Recorder {
+        fn handle(&mut self, _request: &Read) -> DenebResult<()> {
+            self.handled.push(Read::NAME);
+            Ok(())
+        }
+    }
+
+    impl RequestHandler<Commit> for Recorder {
+        fn handle(&mut self, _request: &Commit) -> DenebResult<()> {
+            self.handled.push(Commit::NAME);
+            Ok(())
+        }
+    }
+
+    impl RequestHandler<Prefetch> for Recorder {
+        fn handle(&mut self, _request: &Prefetch) -> DenebResult<()> {
+            self.handled.push(Prefetch::NAME);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interactive_requests_are_served_first() {
+        let (ch, queue) = request_queues::<Recorder>(4, OverloadPolicy::Block, None);
+        cast(Commit, &ch);
+        cast(Read, &ch);
+        cast(Commit, &ch);
+        cast(Read, &ch);
+        drop(ch);
+
+        let mut recorder = Recorder::default();
+        let request = queue.next_interactive().unwrap();
+        request.run_handler(&mut recorder);
+        while let Some(request) = queue.next() {
+            request.run_handler(&mut recorder);
+        }
+        assert!(queue.next_interactive().is_none());
+        assert_eq!(recorder.handled, vec!["read", "read", "commit", "commit"]);
+    }
+
+    #[test]
+    fn full_queues_shed_or_block() {
+        let (ch, queue) = request_queues::<Recorder>(1, OverloadPolicy::Shed, None);
+        cast(Prefetch, &ch);
+        assert!(call(Prefetch, &ch).is_err());
+        cast(Read, &ch);
+        let sender = {
+            let ch = ch.clone();
+            spawn(move || cast(Read, &ch))
+        };
+        while queue.stats().blocked_sends == 0 {
+            sleep(Duration::from_millis(1));
+        }
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                capacity: 1,
+                interactive: 1,
+                maintenance: 1,
+                blocked_sends: 1,
+                shed_requests: 1,
+            }
+        );
+        drop(ch);
+
+        // Receiving a request makes room for the blocked one
+        let mut recorder = Recorder::default();
+        queue.next().unwrap().run_handler(&mut recorder);
+        sender.join().unwrap();
+        while let Some(request) = queue.next() {
+            request.run_handler(&mut recorder);
+        }
+        assert_eq!(recorder.handled, vec!["read", "read", "prefetch"]);
+    }
+
+    #[test]
+    fn callers_stop_waiting_after_timeout() {
+        let timeout = Some(Duration::from_millis(10));
+        let (ch, queue) = request_queues::<Recorder>(1, OverloadPolicy::Block, timeout);
+        // The first request is queued but not handled in time, the second one
+        // doesn't find room in the queue
+        assert!(call(Read, &ch).is_err());
+        assert!(call(Read, &ch).is_err());
+        assert_eq!(queue.stats().interactive, 1);
+        assert_eq!(queue.stats().blocked_sends, 1);
+
+        // The reply to the abandoned request is dropped
+        let mut recorder = Recorder::default();
+        queue.next().unwrap().run_handler(&mut recorder);
+        assert_eq!(recorder.handled, vec!["read"]);
+    }
+}
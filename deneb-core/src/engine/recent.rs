@@ -0,0 +1,84 @@
+use {
+    crate::{errors::DenebResult, util::atomic_write},
+    bincode::{deserialize, serialize},
+    std::{
+        collections::VecDeque,
+        fs::File,
+        io::Read,
+        path::{Path, PathBuf},
+    },
+};
+
+const RECENT_FILES_CAPACITY: usize = 256;
+
+/// The list of the most recently opened files
+///
+/// The list is saved when the engine stops and loaded when it is
+/// restarted, allowing the files which were in use at shutdown to be
+/// prefetched after the next mount.
+pub(in crate::engine) struct RecentFiles {
+    indices: VecDeque<u64>,
+    path: PathBuf,
+}
+
+impl RecentFiles {
+    /// Load the list from `path`, or start with an empty list if the file can't be read
+    pub(in crate::engine) fn load(path: PathBuf) -> RecentFiles {
+        let indices = read_indices(&path).unwrap_or_default();
+        RecentFiles { indices, path }
+    }
+
+    pub(in crate::engine) fn save(&self) -> DenebResult<()> {
+        let buffer = serialize(&self.indices)?;
+        atomic_write(&self.path, &buffer)
+    }
+
+    /// Mark a file as the most recently used one
+    pub(in crate::engine) fn touch(&mut self, index: u64) {
+        if let Some(pos) = self.indices.iter().position(|&idx| idx == index) {
+            self.indices.remove(pos);
+        }
+        self.indices.push_front(index);
+        self.indices.truncate(RECENT_FILES_CAPACITY);
+    }
+
+    /// Returns at most `count` indices, starting with the most recently used file
+    pub(in crate::engine) fn most_recent(&self, count: usize) -> Vec<u64> {
+        self.indices.iter().take(count).cloned().collect()
+    }
+}
+
+fn read_indices(path: &Path) -> DenebResult<VecDeque<u64>> {
+    let mut buffer = Vec::new();
+    File::open(path)?.read_to_end(&mut buffer)?;
+    Ok(deserialize(&buffer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    #[test]
+    fn most_recent_first() -> DenebResult<()> {
+        let tmp = TempDir::new("/tmp/deneb_recent_files")?;
+        let path = tmp.path().join("recent_files");
+
+        let mut recent = RecentFiles::load(path.clone());
+        assert!(recent.most_recent(10).is_empty());
+
+        recent.touch(2);
+        recent.touch(3);
+        recent.touch(4);
+        recent.touch(2);
+        assert_eq!(recent.most_recent(10), vec![2, 4, 3]);
+        assert_eq!(recent.most_recent(1), vec![2]);
+
+        recent.save()?;
+        let reloaded = RecentFiles::load(path);
+        assert_eq!(reloaded.most_recent(10), vec![2, 4, 3]);
+
+        Ok(())
+    }
+}
@@ -1,12 +1,26 @@
 use {
-    super::protocol::Request,
+    super::{
+        cache::OpenFlags,
+        protocol::{Priority, Request},
+        LiveSettings,
+    },
     crate::{
-        inode::{FileAttributeChanges, FileAttributes, FileType},
-        workspace::CommitSummary,
+        cas::Digest,
+        inode::{Entry, FileAttributeChanges, FileAttributes},
+        manifest::Manifest,
+        metrics::Metrics,
+        workspace::{CommitSummary, GcSummary, PendingChanges, PrunePolicy},
+    },
+    std::{
+        cell::Cell,
+        ffi::OsString,
+        fmt::{Debug, Formatter, Result as FmtResult},
+        path::PathBuf,
+        time::Duration,
     },
-    std::{ffi::OsString, path::PathBuf},
 };
 
+#[derive(Clone, Copy, Debug, Default)]
 pub struct RequestId {
     pub unique_id: u64,
     pub uid: u32,
@@ -14,32 +28,81 @@ pub struct RequestId {
     pub pid: u32,
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct GetAttr {
     pub index: u64,
 }
 
 impl Request for GetAttr {
     type Reply = FileAttributes;
+    const NAME: &'static str = "getattr";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct GetAttrBatch {
+    pub indices: Vec<u64>,
+}
+
+impl Request for GetAttrBatch {
+    type Reply = Vec<Option<FileAttributes>>;
+    const NAME: &'static str = "getattrbatch";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct GetRoot;
+
+impl Request for GetRoot {
+    type Reply = FileAttributes;
+    const NAME: &'static str = "getroot";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct SetAttr {
     pub index: u64,
     pub changes: FileAttributeChanges,
+    /// The user asking for the changes
+    pub uid: u32,
 }
 
 impl Request for SetAttr {
     type Reply = FileAttributes;
+    const NAME: &'static str = "setattr";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct Lookup {
     pub parent: u64,
     pub name: OsString,
 }
 
 impl Request for Lookup {
-    type Reply = Option<FileAttributes>;
+    type Reply = Option<Entry>;
+    const NAME: &'static str = "lookup";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct LookupBatch {
+    pub parent: u64,
+    pub names: Vec<OsString>,
+}
+
+impl Request for LookupBatch {
+    type Reply = Vec<Option<FileAttributes>>;
+    const NAME: &'static str = "lookupbatch";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct Forget {
+    /// Indices of the inodes, with the number of lookups dropped
+    pub inodes: Vec<(u64, u64)>,
+}
+
+impl Request for Forget {
+    type Reply = ();
+    const NAME: &'static str = "forget";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct OpenDir {
     pub index: u64,
     #[allow(dead_code)]
@@ -48,8 +111,10 @@ pub(in crate::engine) struct OpenDir {
 
 impl Request for OpenDir {
     type Reply = ();
+    const NAME: &'static str = "opendir";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct ReleaseDir {
     pub index: u64,
     #[allow(dead_code)]
@@ -58,29 +123,138 @@ pub(in crate::engine) struct ReleaseDir {
 
 impl Request for ReleaseDir {
     type Reply = ();
+    const NAME: &'static str = "releasedir";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct ReadDir {
     pub index: u64,
-    #[allow(dead_code)]
     pub offset: i64,
 }
 
 impl Request for ReadDir {
-    type Reply = Vec<(PathBuf, u64, FileType)>;
+    type Reply = Vec<(i64, PathBuf, FileAttributes)>;
+    const NAME: &'static str = "readdir";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct ResolvePath {
+    pub path: PathBuf,
+}
+
+impl Request for ResolvePath {
+    type Reply = Option<(u64, FileAttributes)>;
+    const NAME: &'static str = "resolve";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct ReadLink {
+    pub index: u64,
 }
 
+impl Request for ReadLink {
+    type Reply = PathBuf;
+    const NAME: &'static str = "readlink";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct GetFileDigest {
+    pub index: u64,
+}
+
+impl Request for GetFileDigest {
+    type Reply = Option<Digest>;
+    const NAME: &'static str = "getdigest";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct GetPreviousVersion {
+    pub index: u64,
+}
+
+impl Request for GetPreviousVersion {
+    type Reply = Option<(Digest, FileAttributes)>;
+    const NAME: &'static str = "getprevious";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct ReadPreviousVersion {
+    pub index: u64,
+}
+
+impl Request for ReadPreviousVersion {
+    type Reply = Option<Vec<u8>>;
+    const NAME: &'static str = "readprevious";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct GetXattr {
+    pub index: u64,
+    pub name: OsString,
+}
+
+impl Request for GetXattr {
+    type Reply = Option<Vec<u8>>;
+    const NAME: &'static str = "getxattr";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct ListXattrs {
+    pub index: u64,
+}
+
+impl Request for ListXattrs {
+    type Reply = Vec<OsString>;
+    const NAME: &'static str = "listxattr";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct SetXattr {
+    pub index: u64,
+    pub name: OsString,
+    pub value: Vec<u8>,
+    /// `XATTR_CREATE` or `XATTR_REPLACE`, if any
+    pub flags: u32,
+    /// The user setting the attribute
+    pub uid: u32,
+}
+
+impl Request for SetXattr {
+    type Reply = ();
+    const NAME: &'static str = "setxattr";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct RemoveXattr {
+    pub index: u64,
+    pub name: OsString,
+    /// The user removing the attribute
+    pub uid: u32,
+}
+
+impl Request for RemoveXattr {
+    type Reply = ();
+    const NAME: &'static str = "removexattr";
+}
+
+#[derive(Debug)]
 pub(in crate::engine) struct OpenFile {
     pub index: u64,
-    #[allow(dead_code)]
     pub flags: u32,
+    /// The user and the group opening the file
+    pub uid: u32,
+    pub gid: u32,
 }
 
 impl Request for OpenFile {
-    type Reply = ();
+    type Reply = OpenFlags;
+    const NAME: &'static str = "open";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct ReadData {
+    /// Unique id of the request, used to check whether it was interrupted
+    pub id: u64,
     pub index: u64,
     pub offset: i64,
     pub size: u32,
@@ -88,18 +262,32 @@ pub(in crate::engine) struct ReadData {
 
 impl Request for ReadData {
     type Reply = Vec<u8>;
+    const NAME: &'static str = "read";
 }
 
 pub(in crate::engine) struct WriteData {
     pub index: u64,
     pub offset: i64,
-    pub data: Vec<u8>,
+    /// Taken by the handler, so that the data is not copied again
+    pub data: Cell<Vec<u8>>,
+}
+
+// The data is left out, it may already have been taken by the handler
+impl Debug for WriteData {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("WriteData")
+            .field("index", &self.index)
+            .field("offset", &self.offset)
+            .finish()
+    }
 }
 
 impl Request for WriteData {
     type Reply = u32;
+    const NAME: &'static str = "write";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct ReleaseFile {
     pub index: u64,
     #[allow(dead_code)]
@@ -112,8 +300,10 @@ pub(in crate::engine) struct ReleaseFile {
 
 impl Request for ReleaseFile {
     type Reply = ();
+    const NAME: &'static str = "release";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct CreateFile {
     pub parent: u64,
     pub name: OsString,
@@ -122,9 +312,11 @@ pub(in crate::engine) struct CreateFile {
 }
 
 impl Request for CreateFile {
-    type Reply = (u64, FileAttributes);
+    type Reply = (Entry, OpenFlags);
+    const NAME: &'static str = "create";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct CreateDir {
     pub parent: u64,
     pub name: OsString,
@@ -132,9 +324,11 @@ pub(in crate::engine) struct CreateDir {
 }
 
 impl Request for CreateDir {
-    type Reply = FileAttributes;
+    type Reply = Entry;
+    const NAME: &'static str = "mkdir";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct Unlink {
     pub parent: u64,
     pub name: OsString,
@@ -142,8 +336,10 @@ pub(in crate::engine) struct Unlink {
 
 impl Request for Unlink {
     type Reply = ();
+    const NAME: &'static str = "unlink";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct RemoveDir {
     pub parent: u64,
     pub name: OsString,
@@ -151,8 +347,10 @@ pub(in crate::engine) struct RemoveDir {
 
 impl Request for RemoveDir {
     type Reply = ();
+    const NAME: &'static str = "rmdir";
 }
 
+#[derive(Debug)]
 pub(in crate::engine) struct Rename {
     pub parent: u64,
     pub name: OsString,
@@ -162,22 +360,279 @@ pub(in crate::engine) struct Rename {
 
 impl Request for Rename {
     type Reply = ();
+    const NAME: &'static str = "rename";
 }
 
-pub(in crate::engine) struct Commit;
+/// An inode which an operation of a batch applies to
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BatchInode {
+    /// An inode which already exists
+    Index(u64),
+    /// The inode created by an earlier operation of the batch, given by the
+    /// position of the operation
+    Created(usize),
+}
+
+/// An operation of a batch, see `Handle::batch`
+pub enum BatchOperation {
+    CreateDir {
+        parent: BatchInode,
+        name: OsString,
+        mode: u32,
+    },
+    CreateFile {
+        parent: BatchInode,
+        name: OsString,
+        mode: u32,
+    },
+    Write {
+        file: BatchInode,
+        offset: i64,
+        data: Vec<u8>,
+    },
+    Rename {
+        parent: BatchInode,
+        name: OsString,
+        new_parent: BatchInode,
+        new_name: OsString,
+    },
+}
+
+// The data of the writes is left out
+impl Debug for BatchOperation {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            BatchOperation::CreateDir { parent, name, mode } => f
+                .debug_struct("CreateDir")
+                .field("parent", parent)
+                .field("name", name)
+                .field("mode", mode)
+                .finish(),
+            BatchOperation::CreateFile { parent, name, mode } => f
+                .debug_struct("CreateFile")
+                .field("parent", parent)
+                .field("name", name)
+                .field("mode", mode)
+                .finish(),
+            BatchOperation::Write { file, offset, .. } => f
+                .debug_struct("Write")
+                .field("file", file)
+                .field("offset", offset)
+                .finish(),
+            BatchOperation::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+            } => f
+                .debug_struct("Rename")
+                .field("parent", parent)
+                .field("name", name)
+                .field("new_parent", new_parent)
+                .field("new_name", new_name)
+                .finish(),
+        }
+    }
+}
+
+pub(in crate::engine) struct Batch {
+    /// Taken by the handler, so that the data is not copied again
+    pub operations: Cell<Vec<BatchOperation>>,
+}
+
+// The operations are left out, they may already have been taken by the handler
+impl Debug for Batch {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Batch").finish()
+    }
+}
+
+// Maintenance requests are not handled between the steps of a commit, so a
+// batch is never split by one
+impl Request for Batch {
+    type Reply = Vec<Option<FileAttributes>>;
+    const NAME: &'static str = "batch";
+    const PRIORITY: Priority = Priority::Maintenance;
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct Commit {
+    pub message: Option<String>,
+}
 
 impl Request for Commit {
     type Reply = CommitSummary;
+    const NAME: &'static str = "commit";
+    const PRIORITY: Priority = Priority::Maintenance;
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct Restore {
+    pub root_hash: Digest,
+    pub path: PathBuf,
+    pub overwrite: bool,
+}
+
+impl Request for Restore {
+    type Reply = PathBuf;
+    const NAME: &'static str = "restore";
+    const PRIORITY: Priority = Priority::Maintenance;
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct EmptyTrash;
+
+impl Request for EmptyTrash {
+    type Reply = usize;
+    const NAME: &'static str = "empty_trash";
+    const PRIORITY: Priority = Priority::Maintenance;
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct Pin {
+    pub index: u64,
+    pub pinned: bool,
+}
+
+impl Request for Pin {
+    type Reply = bool;
+    const NAME: &'static str = "pin";
+    const PRIORITY: Priority = Priority::Maintenance;
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct Dehydrate {
+    pub index: u64,
+    pub untouched_for: Option<Duration>,
+}
+
+impl Request for Dehydrate {
+    type Reply = (u64, u64);
+    const NAME: &'static str = "dehydrate";
+    const PRIORITY: Priority = Priority::Maintenance;
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct DiffWorkspace;
+
+impl Request for DiffWorkspace {
+    type Reply = PendingChanges;
+    const NAME: &'static str = "diff_workspace";
+    const PRIORITY: Priority = Priority::Maintenance;
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct CollectGarbage;
+
+impl Request for CollectGarbage {
+    type Reply = GcSummary;
+    const NAME: &'static str = "collect_garbage";
+    const PRIORITY: Priority = Priority::Maintenance;
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct Prune {
+    pub policy: PrunePolicy,
+}
+
+impl Request for Prune {
+    type Reply = (usize, GcSummary);
+    const NAME: &'static str = "prune";
+    const PRIORITY: Priority = Priority::Maintenance;
 }
 
+#[derive(Debug)]
+pub(in crate::engine) struct Cancel {
+    pub token: u64,
+}
+
+impl Request for Cancel {
+    type Reply = bool;
+    const NAME: &'static str = "cancel";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct GetOperations;
+
+impl Request for GetOperations {
+    type Reply = Vec<(u64, String)>;
+    const NAME: &'static str = "operations";
+}
+
+#[derive(Debug)]
 pub(in crate::engine) struct Ping;
 
 impl Request for Ping {
     type Reply = String;
+    const NAME: &'static str = "ping";
 }
 
-pub(in crate::engine) struct StopEngine;
+#[derive(Debug)]
+pub(in crate::engine) struct StopEngine {
+    /// Whether the changes which weren't committed are saved to a checkpoint
+    /// instead of being committed
+    pub checkpoint: bool,
+}
 
 impl Request for StopEngine {
     type Reply = ();
+    const NAME: &'static str = "stop";
+    const PRIORITY: Priority = Priority::Maintenance;
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct GetMetrics;
+
+impl Request for GetMetrics {
+    type Reply = Metrics;
+    const NAME: &'static str = "metrics";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct Reconfigure {
+    pub settings: LiveSettings,
+}
+
+impl Request for Reconfigure {
+    type Reply = ();
+    const NAME: &'static str = "reconfigure";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct GetManifest;
+
+impl Request for GetManifest {
+    type Reply = Manifest;
+    const NAME: &'static str = "manifest";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct GetLog;
+
+impl Request for GetLog {
+    type Reply = Vec<Manifest>;
+    const NAME: &'static str = "log";
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct Prefetch {
+    pub index: u64,
+}
+
+impl Request for Prefetch {
+    type Reply = ();
+    const NAME: &'static str = "prefetch";
+    const PRIORITY: Priority = Priority::Maintenance;
+    const SHEDDABLE: bool = true;
+}
+
+#[derive(Debug)]
+pub(in crate::engine) struct GetRecentFiles {
+    pub count: usize,
+}
+
+impl Request for GetRecentFiles {
+    type Reply = Vec<u64>;
+    const NAME: &'static str = "recent";
+    const PRIORITY: Priority = Priority::Maintenance;
 }
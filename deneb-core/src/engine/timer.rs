@@ -100,6 +100,14 @@ impl Timer {
         self.quit.send(()).map_err(|_| EngineError::Send).unwrap();
         let _ = self.joiner.join();
     }
+
+    /// Stop the timer without waiting for the background thread
+    ///
+    /// An action which is running is allowed to finish, so unlike `stop`, this
+    /// can be called by a thread which the action may be waiting for.
+    pub(crate) fn cancel(self) {
+        let _ = self.quit.send(());
+    }
 }
 
 struct Event {
@@ -312,4 +320,17 @@ mod tests {
         // (1 * 2) + 1
         assert_eq!(res, 3);
     }
+
+    #[test]
+    fn timer_cancel() {
+        let (tx, rx) = unbounded();
+        let mut timer = Timer::new(Resolution::Ms);
+        timer.schedule(Duration::from_millis(1), true, move || {
+            let _ = tx.send(1);
+        });
+        assert_eq!(rx.recv(), Ok(1));
+        timer.cancel();
+        // Only returns once the timer thread has exited, dropping the action
+        for _ in rx.iter() {}
+    }
 }
@@ -0,0 +1,85 @@
+use {
+    super::{handle::Handle, requests::RequestId},
+    crate::inode::FileType,
+    log::{info, warn},
+    std::path::Path,
+};
+
+/// Prefetch the contents of a set of files, to avoid cold reads after mount
+///
+/// Each path in `paths`, relative to the root of the repository, is either
+/// a file, which is prefetched, or a directory, whose files are all
+/// prefetched. The `num_recent` files most recently opened before the last
+/// shutdown are prefetched as well. Paths which can't be found are skipped.
+pub fn warm_up<P: AsRef<Path>>(handle: &Handle, paths: &[P], num_recent: usize) {
+    let id = warm_up_id();
+    let mut num_files = 0;
+
+    for path in paths {
+        match handle.resolve_path(&id, path.as_ref()) {
+            Ok(Some((index, attrs))) => num_files += prefetch(handle, &id, index, attrs.kind),
+            Ok(None) => warn!("Warm-up path not found: {:?}", path.as_ref()),
+            Err(e) => warn!("Could not resolve warm-up path {:?}: {}", path.as_ref(), e),
+        }
+    }
+
+    if num_recent > 0 {
+        // Recent files may have been removed since they were recorded
+        match handle
+            .recent_files(num_recent)
+            .and_then(|indices| handle.get_attr_batch(&id, indices))
+        {
+            Ok(attrs) => {
+                for attrs in attrs.into_iter().flatten() {
+                    if attrs.kind == FileType::RegularFile {
+                        num_files += prefetch(handle, &id, attrs.index, attrs.kind);
+                    }
+                }
+            }
+            Err(e) => warn!("Could not retrieve the recently used files: {}", e),
+        }
+    }
+
+    info!("Warm-up finished: {} files prefetched", num_files);
+}
+
+fn warm_up_id() -> RequestId {
+    RequestId {
+        unique_id: 0,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    }
+}
+
+// Prefetch a file or, recursively, the files in a directory, returning the
+// number of files which were prefetched
+fn prefetch(handle: &Handle, id: &RequestId, index: u64, kind: FileType) -> usize {
+    match kind {
+        FileType::RegularFile => match handle.prefetch(id, index) {
+            Ok(()) => 1,
+            Err(e) => {
+                warn!("Could not prefetch file {}: {}", index, e);
+                0
+            }
+        },
+        FileType::Directory => {
+            let entries = handle
+                .open_dir(id, index, 0)
+                .and_then(|_| handle.read_dir(id, index, 0));
+            let _ = handle.release_dir(id, index, 0);
+            match entries {
+                Ok(entries) => entries
+                    .into_iter()
+                    .filter(|(_, name, _)| name != Path::new(".") && name != Path::new(".."))
+                    .map(|(_, _, attrs)| prefetch(handle, id, attrs.index, attrs.kind))
+                    .sum(),
+                Err(e) => {
+                    warn!("Could not read directory {}: {}", index, e);
+                    0
+                }
+            }
+        }
+        _ => 0,
+    }
+}
@@ -36,6 +36,12 @@ pub enum DenebError {
     InvalidPath(PathBuf),
     #[fail(display = "Digest read error")]
     DigestFromSlice,
+    #[fail(display = "Unknown digest algorithm: {}", _0)]
+    UnknownAlgorithm(String),
+    #[fail(display = "Invalid reflog entry: {}", _0)]
+    ReflogParse(String),
+    #[fail(display = "Unsupported file type: {:?}", _0)]
+    UnsupportedFileType(PathBuf),
 }
 
 // Object store errors
@@ -48,6 +54,29 @@ pub enum StoreError {
     ChunkPut(String),
     #[fail(display = "Get error for file: {:?}", _0)]
     FileGet(PathBuf),
+    #[fail(display = "Chunk cache lock poisoned")]
+    CacheLock,
+    #[fail(display = "Corrupt chunk: {}", _0)]
+    CorruptChunk(String),
+    #[fail(display = "Gateway error: {}", _0)]
+    Gateway(String),
+    #[fail(display = "Operation not supported by the store: {}", _0)]
+    Unsupported(&'static str),
+    #[fail(
+        display = "Store format version {} does not match version {}, run `deneb-cli store upgrade`",
+        _0, _1
+    )]
+    Format(u32, u32),
+    #[fail(display = "Unsupported chunk version {} for: {}", _0, _1)]
+    ChunkVersion(u8, String),
+    #[fail(display = "Store relocation error: {}", _0)]
+    Relocation(String),
+    #[fail(display = "Invalid store layout: {}", _0)]
+    Layout(String),
+    #[fail(display = "Not a bundle: {:?}", _0)]
+    InvalidBundle(PathBuf),
+    #[fail(display = "The store is opened read-only")]
+    ReadOnly,
 }
 
 // Catalog errors
@@ -76,6 +105,8 @@ pub enum CatalogError {
     Version(u32),
     #[fail(display = "Could not update max index")]
     MaxIndexUpdate,
+    #[fail(display = "The catalog is opened read-only")]
+    ReadOnly,
 }
 
 // Engine errors
@@ -92,6 +123,10 @@ pub enum EngineError {
     Send,
     #[fail(display = "No reply received from engine")]
     NoReply,
+    #[fail(display = "Request dropped, the engine is overloaded")]
+    Overloaded,
+    #[fail(display = "Timed out waiting for the reply to a {} request", _0)]
+    Timeout(&'static str),
     #[fail(display = "Could not open directory: {}", _0)]
     DirOpen(u64),
     #[fail(display = "Could not close directory: {}", _0)]
@@ -121,8 +156,54 @@ pub enum EngineError {
     Rename(u64, OsString, u64, OsString),
     #[fail(display = "Access error for: {}", _0)]
     Access(u64),
+    #[fail(display = "Operation not permitted on: {}", _0)]
+    PermissionDenied(u64),
+    #[fail(display = "Could not access the extended attribute {:?} of: {}", _1, _0)]
+    Xattr(u64, OsString),
+    #[fail(display = "Unsupported extended attribute: {:?}", _0)]
+    UnsupportedXattr(OsString),
+    #[fail(display = "Extended attribute {:?} of {} already exists", _1, _0)]
+    XattrExists(u64, OsString),
+    #[fail(display = "Extended attribute {:?} of {} doesn't exist", _1, _0)]
+    XattrMissing(u64, OsString),
+    #[fail(display = "Invalid POSIX ACL")]
+    InvalidAcl,
+    #[fail(display = "Operation {} of the batch failed, none were applied", _0)]
+    Batch(usize),
+    #[fail(display = "The batch can't be applied, {}", _0)]
+    BatchInvalid(&'static str),
+    #[fail(display = "No inode was created by operation {} of the batch", _0)]
+    BatchReference(usize),
     #[fail(display = "Workspace commit error")]
     Commit,
+    #[fail(display = "Garbage collection error")]
+    Gc,
+    #[fail(display = "Prune error")]
+    Prune,
+    #[fail(display = "Operation cancelled: {}", _0)]
+    Cancelled(u64),
+    #[fail(display = "Read interrupted: {}", _0)]
+    Interrupted(u64),
+    #[fail(display = "Could not read symbolic link: {}", _0)]
+    ReadLink(u64),
+    #[fail(display = "Could not retrieve the digest of: {}", _0)]
+    FileDigest(u64),
+    #[fail(display = "Could not look up the previous version of: {}", _0)]
+    PreviousVersion(u64),
+    #[fail(display = "Could not resolve path: {:?}", _0)]
+    ResolvePath(PathBuf),
+    #[fail(display = "Could not restore: {:?}", _0)]
+    Restore(PathBuf),
+    #[fail(display = "Could not empty the trash")]
+    EmptyTrash,
+    #[fail(display = "Could not pin or unpin: {}", _0)]
+    Pin(u64),
+    #[fail(display = "Could not dehydrate: {}", _0)]
+    Dehydrate(u64),
+    #[fail(display = "Could not run the commit hook: {:?}", _0)]
+    Hook(PathBuf),
+    #[fail(display = "The pre-commit hook {:?} rejected the commit: {}", _0, _1)]
+    CommitRejected(PathBuf, String),
 }
 
 #[derive(Debug, Fail)]
@@ -133,6 +214,43 @@ pub enum WorkspaceError {
     DirLookup(u64),
     #[fail(display = "Could not retrieve inode workspace: {}", _0)]
     INodeLookup(u64),
+    #[fail(display = "A repository already exists in: {:?}", _0)]
+    AlreadyInitialized(PathBuf),
+    #[fail(display = "No repository was initialized in: {:?}", _0)]
+    NotInitialized(PathBuf),
+    #[fail(display = "Not a symbolic link: {}", _0)]
+    NotSymlink(u64),
+    #[fail(display = "The root directory {} is missing or not a directory", _0)]
+    InvalidRoot(u64),
+    #[fail(
+        display = "Entry {:?} at {} differs only in case from another one",
+        _1, _0
+    )]
+    NameConflict(u64, OsString),
+    #[fail(display = "Commit {} is not retained", _0)]
+    UnknownCommit(String),
+    #[fail(display = "{:?} not found in commit {}", _0, _1)]
+    RestoreLookup(PathBuf, String),
+    #[fail(display = "Could not restore {:?}, the path is taken", _0)]
+    RestoreConflict(PathBuf),
+    #[fail(display = "Not a delta file: {:?}", _0)]
+    InvalidDelta(PathBuf),
+    #[fail(
+        display = "The delta applies to commit {}, which is not the current one",
+        _0
+    )]
+    DeltaBase(String),
+    #[fail(display = "Entry {:?} of the trash is not a directory", _0)]
+    TrashConflict(OsString),
+    #[fail(display = "The work dir holds another repository than the bundle {:?}", _0)]
+    BundleMismatch(PathBuf),
+    #[fail(display = "Not a checkpoint file: {:?}", _0)]
+    InvalidCheckpoint(PathBuf),
+    #[fail(
+        display = "The checkpoint was saved on commit {}, which is not the current one",
+        _0
+    )]
+    CheckpointBase(String),
 }
 
 #[derive(Debug, Fail)]
@@ -0,0 +1,301 @@
+//! HTTP gateway serving the chunks of a repository to other instances
+//!
+//! The gateway exposes the packed chunks and the manifest of the repository
+//! found in a work dir:
+//!
+//! - `GET /manifest` returns the current manifest
+//! - `GET /chunk/<digest>` returns a packed chunk
+//! - `PUT /chunk/<digest>` stores a packed chunk, after verifying its digest
+//! - `POST /chunks/missing` returns which of the digests in the body, one per
+//!   line, are not stored, in the same form
+//!
+//! Chunks are served in packed form, so they remain encrypted in transit and
+//! the gateway does not need the encryption key of the repository. When a
+//! token is configured, requests need to present it in an
+//! `Authorization: Bearer <token>` header. Without a token, the gateway is
+//! read-only: the `PUT` and `POST` requests are refused. The gateway only
+//! speaks plain HTTP/1.1, with one request per connection; TLS is expected to
+//! be handled by a reverse proxy or CDN placed in front of it.
+//!
+//! At most `MAX_CONNECTIONS` connections are answered at once, the others are
+//! closed right away. Connections which stall for `IO_TIMEOUT` are dropped,
+//! and requests with a larger head or body than the limits are refused.
+
+use {
+    crate::{
+        cas::{digest_from_slice, Digest},
+        errors::{DenebResult, StoreError},
+        store::{open_remote_store, Store},
+        workspace::MANIFEST_PATH,
+    },
+    log::{error, info, trace, warn},
+    sodiumoxide::utils::memcmp,
+    std::{
+        cmp::max,
+        collections::HashMap,
+        fs::read,
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread::spawn,
+        time::Duration,
+    },
+};
+
+const GATEWAY_PATH: &str = "scratch/gateway";
+/// Number of connections answered at the same time
+const MAX_CONNECTIONS: usize = 64;
+/// How long a connection may stall while a message is read or written
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Size of the start line and the headers of a message
+const MAX_HEAD_SIZE: u64 = 16 * 1024;
+/// Size of the bodies accepted with any chunk size, enough for the chunks of
+/// the catalog and for long lists of digests
+const MIN_BODY_LIMIT: u64 = 16 * 1024 * 1024;
+
+struct Gateway {
+    store: Mutex<Box<dyn Store>>,
+    manifest_path: PathBuf,
+    token: Option<String>,
+    max_body_size: u64,
+    connections: AtomicUsize,
+}
+
+/// Serve the repository in `work_dir` to the clients connecting to `listener`
+///
+/// Each connection is answered on its own thread, up to `MAX_CONNECTIONS` at
+/// once. `chunk_size` bounds the size of the chunks which can be stored.
+pub fn start_gateway(
+    listener: TcpListener,
+    work_dir: PathBuf,
+    token: Option<String>,
+    chunk_size: usize,
+) -> DenebResult<()> {
+    let store = open_remote_store(&work_dir, &work_dir.join(GATEWAY_PATH), None, 0)?;
+    let gateway = Arc::new(Gateway {
+        store: Mutex::new(store),
+        manifest_path: work_dir.join(MANIFEST_PATH),
+        token,
+        max_body_size: body_limit(chunk_size),
+        connections: AtomicUsize::new(0),
+    });
+
+    let address = listener.local_addr()?;
+    spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if gateway.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        gateway.connections.fetch_sub(1, Ordering::SeqCst);
+                        warn!("Too many gateway connections, closing a new one");
+                        continue;
+                    }
+                    let gateway = Arc::clone(&gateway);
+                    spawn(move || {
+                        if let Err(e) = gateway.answer(stream) {
+                            error!("Could not answer gateway request: {}", e);
+                        }
+                        gateway.connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(e) => error!("Could not accept gateway connection: {}", e),
+            }
+        }
+    });
+
+    info!("Started chunk gateway on {}", address);
+
+    Ok(())
+}
+
+impl Gateway {
+    fn answer(&self, stream: TcpStream) -> DenebResult<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let (request_line, headers) = read_head(&mut reader)?;
+        trace!("Gateway request: {}", request_line);
+
+        let mut fields = request_line.split_whitespace();
+        let method = fields.next().unwrap_or_default();
+        let target = fields.next().unwrap_or_default();
+
+        let (status, body) = if !self.authorized(&headers) {
+            (401, Vec::new())
+        } else if self.token.is_none() && method != "GET" {
+            (403, Vec::new())
+        } else {
+            match (method, target) {
+                ("GET", "/manifest") => match read(&self.manifest_path) {
+                    Ok(manifest) => (200, manifest),
+                    Err(_) => (404, Vec::new()),
+                },
+                ("GET", target) if target.starts_with("/chunk/") => match parse_digest(target) {
+                    Some(digest) => match self.store.lock() {
+                        Ok(store) => match store.packed_chunk(&digest) {
+                            Ok(packed) => (200, packed),
+                            Err(_) => (404, Vec::new()),
+                        },
+                        Err(_) => (500, Vec::new()),
+                    },
+                    None => (400, Vec::new()),
+                },
+                ("PUT", target) if target.starts_with("/chunk/") => {
+                    match read_body(&mut reader, &headers, self.max_body_size) {
+                        Ok(packed) => match parse_digest(target) {
+                            Some(digest) => match self.put(&digest, &packed) {
+                                Ok(()) => (200, Vec::new()),
+                                Err(_) => (400, Vec::new()),
+                            },
+                            None => (400, Vec::new()),
+                        },
+                        Err(_) => (413, Vec::new()),
+                    }
+                }
+                ("POST", "/chunks/missing") => {
+                    match read_body(&mut reader, &headers, self.max_body_size) {
+                        Ok(body) => match self.missing(&body) {
+                            Ok(missing) => (200, missing),
+                            Err(_) => (400, Vec::new()),
+                        },
+                        Err(_) => (413, Vec::new()),
+                    }
+                }
+                _ => (404, Vec::new()),
+            }
+        };
+
+        write_response(stream, status, &body)
+    }
+
+    fn authorized(&self, headers: &HashMap<String, String>) -> bool {
+        match self.token {
+            Some(ref token) => headers
+                .get("authorization")
+                .map(|value| memcmp(value.as_bytes(), format!("Bearer {}", token).as_bytes()))
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+
+    fn put(&self, digest: &Digest, packed: &[u8]) -> DenebResult<()> {
+        let mut store = self.store.lock().map_err(|_| StoreError::CacheLock)?;
+        store.put_packed_chunk(digest, packed)?;
+        store.sync()
+    }
+
+    fn missing(&self, body: &[u8]) -> DenebResult<Vec<u8>> {
+        let digests = decode_digests(body)?;
+        let store = self.store.lock().map_err(|_| StoreError::CacheLock)?;
+        Ok(encode_digests(&store.missing_chunks(&digests)?))
+    }
+}
+
+/// Size of the largest message body exchanged with the gateway, given the
+/// size of the chunks. Packed chunks may be a bit larger than the chunks.
+pub(crate) fn body_limit(chunk_size: usize) -> u64 {
+    max(2 * chunk_size as u64, MIN_BODY_LIMIT)
+}
+
+fn parse_digest(target: &str) -> Option<Digest> {
+    digest_from_slice(target["/chunk/".len()..].as_bytes()).ok()
+}
+
+/// Digests in the body of a message, one per line
+pub(crate) fn encode_digests(digests: &[Digest]) -> Vec<u8> {
+    digests
+        .iter()
+        .map(|digest| format!("{}\n", digest))
+        .collect::<String>()
+        .into_bytes()
+}
+
+pub(crate) fn decode_digests(body: &[u8]) -> DenebResult<Vec<Digest>> {
+    body.split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(digest_from_slice)
+        .collect()
+}
+
+fn write_response(mut stream: TcpStream, status: u16, body: &[u8]) -> DenebResult<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Read the start line and the headers of an HTTP message
+///
+/// Header names are converted to lower case. Heads larger than
+/// `MAX_HEAD_SIZE` are refused.
+pub(crate) fn read_head(
+    reader: &mut dyn BufRead,
+) -> DenebResult<(String, HashMap<String, String>)> {
+    let mut reader = Read::take(reader, MAX_HEAD_SIZE);
+    let mut start_line = String::new();
+    reader.read_line(&mut start_line)?;
+    if start_line.is_empty() {
+        return Err(StoreError::Gateway("empty message".to_owned()).into());
+    }
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            headers.insert(
+                line[..colon].trim().to_lowercase(),
+                line[colon + 1..].trim().to_owned(),
+            );
+        }
+    }
+    if reader.limit() == 0 {
+        return Err(StoreError::Gateway("message head too large".to_owned()).into());
+    }
+    Ok((start_line.trim_end().to_owned(), headers))
+}
+
+/// Read the body of an HTTP message, whose length is given by its headers,
+/// refusing bodies larger than `limit`
+pub(crate) fn read_body(
+    reader: &mut dyn BufRead,
+    headers: &HashMap<String, String>,
+    limit: u64,
+) -> DenebResult<Vec<u8>> {
+    let length = match headers.get("content-length") {
+        Some(length) => length
+            .parse::<u64>()
+            .map_err(|_| StoreError::Gateway(format!("invalid content length: {}", length)))?,
+        None => 0,
+    };
+    if length > limit {
+        return Err(StoreError::Gateway(format!("message body too large: {}", length)).into());
+    }
+    let mut body = Vec::new();
+    Read::take(reader, length).read_to_end(&mut body)?;
+    if body.len() as u64 != length {
+        return Err(StoreError::Gateway("truncated message body".to_owned()).into());
+    }
+    Ok(body)
+}
@@ -0,0 +1,308 @@
+//! Patterns of the files which are left out when a directory is imported
+//!
+//! The patterns follow the syntax of `.gitignore` files:
+//!
+//! - blank lines and lines starting with `#` are skipped
+//! - `*` matches any sequence of characters except `/`, `?` matches a single
+//!   character except `/` and `[...]` matches a character class (negated with
+//!   `[!...]` or `[^...]`)
+//! - `**` matches any sequence of characters, including `/`
+//! - a pattern without a `/` (other than a trailing one) is matched against
+//!   the file name, at any depth; other patterns are matched against the path
+//!   relative to the imported directory
+//! - a trailing `/` only matches directories
+//! - a leading `!` includes again the files excluded by previous patterns
+//!
+//! The last pattern matching a path decides whether it is ignored. The
+//! contents of ignored directories are never visited, so they can't be
+//! included again.
+
+use {
+    crate::errors::DenebResult,
+    std::{fs::read_to_string, os::unix::ffi::OsStrExt, path::Path},
+};
+
+/// Name of the file, in the root of an imported directory, holding the
+/// patterns of the files to leave out
+pub const IGNORE_FILE_NAME: &str = ".denebignore";
+
+#[derive(Clone, Debug, Default)]
+pub struct IgnorePatterns {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Clone, Debug)]
+struct Pattern {
+    glob: Vec<u8>,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl IgnorePatterns {
+    pub fn new<S: AsRef<str>>(lines: &[S]) -> IgnorePatterns {
+        IgnorePatterns {
+            patterns: lines
+                .iter()
+                .filter_map(|line| Pattern::parse(line.as_ref()))
+                .collect(),
+        }
+    }
+
+    /// Combine the given patterns with the ones found in the ignore file of
+    /// `dir`, which take precedence
+    pub fn load<S: AsRef<str>>(dir: &Path, lines: &[S]) -> DenebResult<IgnorePatterns> {
+        let mut ignore = IgnorePatterns::new(lines);
+        let file_name = dir.join(IGNORE_FILE_NAME);
+        if file_name.exists() {
+            let contents = read_to_string(file_name)?;
+            ignore
+                .patterns
+                .extend(contents.lines().filter_map(Pattern::parse));
+        }
+        Ok(ignore)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Returns true if the file found at `path`, relative to the imported
+    /// directory, should be left out
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let path = path.as_os_str().as_bytes();
+        let name = path.rsplit(|c| *c == b'/').next().unwrap_or(path);
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            if pattern.negated != ignored || (pattern.dir_only && !is_dir) {
+                continue;
+            }
+            let subject = if pattern.anchored { path } else { name };
+            if glob_match(&pattern.glob, subject) {
+                ignored = !pattern.negated;
+            }
+        }
+        ignored
+    }
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Pattern> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.trim_start_matches('/');
+        if line.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            glob: line.as_bytes().to_vec(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+}
+
+fn glob_match(glob: &[u8], subject: &[u8]) -> bool {
+    match glob.first() {
+        None => subject.is_empty(),
+        Some(b'*') if glob.get(1) == Some(&b'*') => {
+            // "**/" also matches no directory at all
+            let (rest, at_dir) = match glob[2..].first() {
+                Some(b'/') => (&glob[3..], true),
+                _ => (&glob[2..], false),
+            };
+            (0..=subject.len()).any(|i| {
+                (!at_dir || i == 0 || subject[i - 1] == b'/') && glob_match(rest, &subject[i..])
+            })
+        }
+        Some(b'*') => {
+            let end = subject
+                .iter()
+                .position(|c| *c == b'/')
+                .unwrap_or(subject.len());
+            (0..=end).any(|i| glob_match(&glob[1..], &subject[i..]))
+        }
+        Some(b'?') => match subject.first() {
+            Some(c) if *c != b'/' => glob_match(&glob[1..], &subject[1..]),
+            _ => false,
+        },
+        Some(b'[') => match (class_match(&glob[1..], subject.first()), subject.first()) {
+            (Some((matched, len)), _) => matched && glob_match(&glob[len + 1..], &subject[1..]),
+            // Without a closing bracket, the bracket is matched literally
+            (None, Some(b'[')) => glob_match(&glob[1..], &subject[1..]),
+            (None, _) => false,
+        },
+        Some(b'\\') if glob.len() > 1 => {
+            subject.first() == Some(&glob[1]) && glob_match(&glob[2..], &subject[1..])
+        }
+        Some(c) => subject.first() == Some(c) && glob_match(&glob[1..], &subject[1..]),
+    }
+}
+
+// Match a character against the class starting after an opening bracket.
+// Returns whether it matched and the length of the class, including the
+// closing bracket, or None if the class isn't closed.
+fn class_match(class: &[u8], c: Option<&u8>) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some(b'!') | Some(b'^'));
+    let start = if negated { 1 } else { 0 };
+    // A closing bracket right after the opening one is part of the class
+    let end = start + 1 + class.get(start + 1..)?.iter().position(|c| *c == b']')?;
+    let c = match c {
+        Some(c) if *c != b'/' => *c,
+        _ => return Some((false, end + 1)),
+    };
+
+    let members = &class[start..end];
+    let mut found = false;
+    let mut i = 0;
+    while i < members.len() {
+        if i + 2 < members.len() && members[i + 1] == b'-' {
+            found |= members[i] <= c && c <= members[i + 2];
+            i += 3;
+        } else {
+            found |= members[i] == c;
+            i += 1;
+        }
+    }
+    Some((found != negated, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            catalog::{open_catalog, CatalogType},
+            populate_with_dir,
+            store::{open_store, StoreType},
+            ImportOptions,
+        },
+        std::{
+            fs::{create_dir_all, write},
+            os::unix::net::UnixListener,
+            path::PathBuf,
+        },
+        tempdir::TempDir,
+    };
+
+    fn ignored(patterns: &[&str], path: &str, is_dir: bool) -> bool {
+        IgnorePatterns::new(patterns).is_ignored(Path::new(path), is_dir)
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_match(b"*.o", b"main.o"));
+        assert!(!glob_match(b"*.o", b"main.c"));
+        assert!(!glob_match(b"*.o", b"src/main.o"));
+        assert!(glob_match(b"?.txt", b"a.txt"));
+        assert!(!glob_match(b"?.txt", b"ab.txt"));
+        assert!(glob_match(b"[abc].rs", b"b.rs"));
+        assert!(glob_match(b"[a-c].rs", b"c.rs"));
+        assert!(!glob_match(b"[!a-c].rs", b"c.rs"));
+        assert!(glob_match(b"[]].rs", b"].rs"));
+        assert!(glob_match(b"[.rs", b"[.rs"));
+        assert!(glob_match(b"\\*.rs", b"*.rs"));
+        assert!(!glob_match(b"\\*.rs", b"a.rs"));
+        assert!(glob_match(b"**/cache", b"cache"));
+        assert!(glob_match(b"**/cache", b"a/b/cache"));
+        assert!(!glob_match(b"**/cache", b"a/bcache"));
+        assert!(glob_match(b"target/**", b"target/debug/deneb"));
+        assert!(glob_match(b"a/**/z", b"a/z"));
+        assert!(glob_match(b"a/**/z", b"a/b/c/z"));
+    }
+
+    #[test]
+    fn ignore_patterns() {
+        // Unanchored patterns match the file name at any depth
+        assert!(ignored(&["*.o"], "main.o", false));
+        assert!(ignored(&["*.o"], "src/main.o", false));
+        // Anchored patterns match the whole path
+        assert!(ignored(&["/build"], "build", true));
+        assert!(!ignored(&["/build"], "src/build", true));
+        assert!(ignored(&["src/*.tmp"], "src/a.tmp", false));
+        assert!(!ignored(&["src/*.tmp"], "lib/src/a.tmp", false));
+        // Directory patterns
+        assert!(ignored(&["cache/"], "a/cache", true));
+        assert!(!ignored(&["cache/"], "a/cache", false));
+        // Negation and precedence
+        assert!(!ignored(&["*.log", "!keep.log"], "keep.log", false));
+        assert!(ignored(&["*.log", "!keep.log"], "drop.log", false));
+        assert!(ignored(&["!keep.log", "*.log"], "keep.log", false));
+        // Comments and blank lines
+        assert!(!ignored(&["# *.o", "", "   "], "main.o", false));
+    }
+
+    #[test]
+    fn ignored_files_are_not_imported() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_ignore_populate")?;
+        create_dir_all(tmp.path().join("src"))?;
+        create_dir_all(tmp.path().join("target/debug"))?;
+        write(tmp.path().join(IGNORE_FILE_NAME), "/target/\n")?;
+        write(tmp.path().join("src/main.rs"), "fn main() {}")?;
+        write(tmp.path().join("src/main.rs~"), "fn main() {}")?;
+        write(tmp.path().join("target/debug/deneb"), "binary")?;
+        let _socket = UnixListener::bind(tmp.path().join("deneb.sock"))?;
+
+        let catalog = open_catalog(CatalogType::InMemory, "/", true)?;
+        let mut store = open_store(StoreType::InMemory, "/", None, 1024, 0, false)?;
+        let mut options = ImportOptions {
+            ignore_patterns: vec!["*~".to_owned()],
+            strict: true,
+        };
+        // Sockets can't be imported
+        assert!(populate_with_dir(&*catalog, &mut *store, tmp.path(), 1024, &options).is_err());
+
+        let catalog = open_catalog(CatalogType::InMemory, "/", true)?;
+        options.strict = false;
+        populate_with_dir(&*catalog, &mut *store, tmp.path(), 1024, &options)?;
+
+        let names = |index| -> DenebResult<Vec<PathBuf>> {
+            let mut names = catalog
+                .dir_entries(index)?
+                .into_iter()
+                .map(|(name, _)| name)
+                .filter(|name| name != Path::new(".") && name != Path::new(".."))
+                .collect::<Vec<_>>();
+            names.sort();
+            Ok(names)
+        };
+        assert_eq!(
+            names(1)?,
+            vec![PathBuf::from(IGNORE_FILE_NAME), PathBuf::from("src")]
+        );
+        let src = catalog
+            .dir_entry_index(1, Path::new("src"))?
+            .unwrap_or_default();
+        assert_eq!(names(src)?, vec![PathBuf::from("main.rs")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_file_overrides_patterns() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_ignore")?;
+        write(
+            tmp.path().join(IGNORE_FILE_NAME),
+            "# keep it\n!important.bak\n",
+        )?;
+        let ignore = IgnorePatterns::load(tmp.path(), &["*.bak"])?;
+        assert!(ignore.is_ignored(Path::new("old.bak"), false));
+        assert!(!ignore.is_ignored(Path::new("important.bak"), false));
+
+        let ignore = IgnorePatterns::load(&tmp.path().join("missing"), &["*.bak"])?;
+        assert!(ignore.is_ignored(Path::new("important.bak"), false));
+        Ok(())
+    }
+}
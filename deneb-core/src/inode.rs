@@ -7,11 +7,20 @@ use {
     serde::{Deserialize, Serialize},
     std::{
         cmp::{max, min},
+        collections::BTreeMap,
         i32, u16,
     },
-    time::Timespec,
+    time::{now_utc, Timespec},
 };
 
+/// Unit of the `blocks` attribute, as of `st_blocks`
+pub const STAT_BLOCK_SIZE: u64 = 512;
+
+/// Number of blocks taken by `size` bytes of file contents
+pub fn blocks_for(size: u64) -> u64 {
+    (size + STAT_BLOCK_SIZE - 1) / STAT_BLOCK_SIZE
+}
+
 #[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum FileType {
     NamedPipe,
@@ -22,7 +31,7 @@ pub enum FileType {
     Symlink,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct FileAttributes {
     pub index: u64,
     pub size: u64,
@@ -64,6 +73,7 @@ impl FileAttributes {
         }
         if let Some(size) = changes.size {
             self.size = size;
+            self.blocks = blocks_for(size);
         }
         if let Some(atime) = changes.atime {
             self.atime = atime;
@@ -76,6 +86,15 @@ impl FileAttributes {
         }
         if let Some(chgtime) = changes.chgtime {
             self.ctime = chgtime;
+        } else if changes.mode.is_some()
+            || changes.uid.is_some()
+            || changes.gid.is_some()
+            || changes.size.is_some()
+            || changes.atime.is_some()
+            || changes.mtime.is_some()
+        {
+            // The status of the inode changed
+            self.ctime = now_utc().to_timespec();
         }
     }
 }
@@ -127,10 +146,10 @@ impl From<FileStat> for FileAttributes {
             nlink: 0,
             uid: stats.st_uid,
             gid: stats.st_gid,
-            rdev: 0,
+            rdev: stats.st_rdev as u32,
             flags: 0,
         };
-        #[cfg(target_os = "macos")]
+        #[cfg(any(target_os = "macos", target_os = "freebsd"))]
         {
             _attributes.crtime = Timespec {
                 sec: stats.st_birthtime,
@@ -141,10 +160,22 @@ impl From<FileStat> for FileAttributes {
     }
 }
 
+/// The attributes of a looked up or created inode, with the generation of its
+/// index
+///
+/// An index and a generation never identify two different inodes, even when
+/// the index is reused after a restart.
+#[derive(Copy, Clone, Debug)]
+pub struct Entry {
+    pub attributes: FileAttributes,
+    pub generation: u64,
+}
+
+#[derive(Debug)]
 pub struct FileAttributeChanges {
-    mode: Option<u32>,
-    uid: Option<u32>,
-    gid: Option<u32>,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
     pub size: Option<u64>,
     atime: Option<Timespec>,
     mtime: Option<Timespec>,
@@ -181,21 +212,60 @@ impl FileAttributeChanges {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct ChunkDescriptor {
     pub digest: Digest,
     pub size: usize,
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
 pub struct INode {
     pub attributes: FileAttributes,
     pub chunks: Vec<ChunkDescriptor>,
+    /// Digest of the whole contents of a regular file, recorded when the file
+    /// is written to the store
+    pub digest: Option<Digest>,
+    /// Extended attributes kept with the inode, such as its POSIX ACLs
+    pub xattrs: BTreeMap<String, Vec<u8>>,
 }
 
 impl INode {
     pub fn new(attributes: FileAttributes, chunks: Vec<ChunkDescriptor>) -> INode {
-        INode { attributes, chunks }
+        INode {
+            attributes,
+            chunks,
+            digest: None,
+            xattrs: BTreeMap::new(),
+        }
+    }
+}
+
+/// Inodes as they were stored before the digest of the contents was recorded
+#[derive(Deserialize)]
+pub(crate) struct LegacyINode {
+    attributes: FileAttributes,
+    chunks: Vec<ChunkDescriptor>,
+}
+
+impl From<LegacyINode> for INode {
+    fn from(inode: LegacyINode) -> INode {
+        INode::new(inode.attributes, inode.chunks)
+    }
+}
+
+/// Inodes as they were stored before their extended attributes were kept
+#[derive(Deserialize)]
+pub(crate) struct LegacyDigestINode {
+    attributes: FileAttributes,
+    chunks: Vec<ChunkDescriptor>,
+    digest: Option<Digest>,
+}
+
+impl From<LegacyDigestINode> for INode {
+    fn from(inode: LegacyDigestINode) -> INode {
+        let mut new = INode::new(inode.attributes, inode.chunks);
+        new.digest = inode.digest;
+        new
     }
 }
 
@@ -219,6 +289,17 @@ pub(crate) fn mode_to_file_type(mode: mode_t) -> FileType {
     }
 }
 
+pub(crate) fn file_type_to_mode(kind: FileType) -> mode_t {
+    match kind {
+        FileType::Directory => SFlag::S_IFDIR.bits(),
+        FileType::CharDevice => SFlag::S_IFCHR.bits(),
+        FileType::BlockDevice => SFlag::S_IFBLK.bits(),
+        FileType::RegularFile => SFlag::S_IFREG.bits(),
+        FileType::Symlink => SFlag::S_IFLNK.bits(),
+        FileType::NamedPipe => SFlag::S_IFIFO.bits(),
+    }
+}
+
 pub(crate) fn mode_to_permissions(mode: mode_t) -> u16 {
     #[cfg(target_os = "linux")]
     debug_assert!(mode <= u16::MAX as u32);
@@ -0,0 +1,337 @@
+//! I/O instrumentation for stores and catalogs
+//!
+//! This module is only available when the `instrumentation` feature is enabled.
+//! In that case, all the stores and catalogs created with `open_store` and
+//! `open_catalog` are wrapped in types which count the operations performed on
+//! them. The benchmark suite uses these counters to report the amount of I/O
+//! done in each scenario.
+
+use {
+    crate::{
+        cas::{Algorithm, Digest},
+        catalog::Catalog,
+        errors::DenebResult,
+        inode::{ChunkDescriptor, INode},
+        store::{Chunk, RemoteLimits, Store},
+    },
+    std::{
+        collections::HashSet,
+        io::Read,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    },
+};
+
+/// A snapshot of the I/O operation counters
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IoStats {
+    pub chunk_reads: usize,
+    pub chunk_writes: usize,
+    pub bytes_written: usize,
+    pub special_file_reads: usize,
+    pub special_file_writes: usize,
+    pub inode_reads: usize,
+    pub inode_writes: usize,
+    pub inode_removals: usize,
+    pub dir_entry_reads: usize,
+    pub dir_entry_writes: usize,
+}
+
+struct IoCounters {
+    chunk_reads: AtomicUsize,
+    chunk_writes: AtomicUsize,
+    bytes_written: AtomicUsize,
+    special_file_reads: AtomicUsize,
+    special_file_writes: AtomicUsize,
+    inode_reads: AtomicUsize,
+    inode_writes: AtomicUsize,
+    inode_removals: AtomicUsize,
+    dir_entry_reads: AtomicUsize,
+    dir_entry_writes: AtomicUsize,
+}
+
+static COUNTERS: IoCounters = IoCounters {
+    chunk_reads: AtomicUsize::new(0),
+    chunk_writes: AtomicUsize::new(0),
+    bytes_written: AtomicUsize::new(0),
+    special_file_reads: AtomicUsize::new(0),
+    special_file_writes: AtomicUsize::new(0),
+    inode_reads: AtomicUsize::new(0),
+    inode_writes: AtomicUsize::new(0),
+    inode_removals: AtomicUsize::new(0),
+    dir_entry_reads: AtomicUsize::new(0),
+    dir_entry_writes: AtomicUsize::new(0),
+};
+
+/// Returns the values of the I/O counters accumulated since the last reset
+pub fn io_stats() -> IoStats {
+    IoStats {
+        chunk_reads: COUNTERS.chunk_reads.load(Ordering::Relaxed),
+        chunk_writes: COUNTERS.chunk_writes.load(Ordering::Relaxed),
+        bytes_written: COUNTERS.bytes_written.load(Ordering::Relaxed),
+        special_file_reads: COUNTERS.special_file_reads.load(Ordering::Relaxed),
+        special_file_writes: COUNTERS.special_file_writes.load(Ordering::Relaxed),
+        inode_reads: COUNTERS.inode_reads.load(Ordering::Relaxed),
+        inode_writes: COUNTERS.inode_writes.load(Ordering::Relaxed),
+        inode_removals: COUNTERS.inode_removals.load(Ordering::Relaxed),
+        dir_entry_reads: COUNTERS.dir_entry_reads.load(Ordering::Relaxed),
+        dir_entry_writes: COUNTERS.dir_entry_writes.load(Ordering::Relaxed),
+    }
+}
+
+/// Set all the I/O counters to zero
+pub fn reset_io_stats() {
+    for counter in &[
+        &COUNTERS.chunk_reads,
+        &COUNTERS.chunk_writes,
+        &COUNTERS.bytes_written,
+        &COUNTERS.special_file_reads,
+        &COUNTERS.special_file_writes,
+        &COUNTERS.inode_reads,
+        &COUNTERS.inode_writes,
+        &COUNTERS.inode_removals,
+        &COUNTERS.dir_entry_reads,
+        &COUNTERS.dir_entry_writes,
+    ] {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+fn bump(counter: &AtomicUsize, value: usize) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+/// A `Store` wrapper which counts the operations performed on the inner store
+pub(crate) struct InstrumentedStore {
+    inner: Box<dyn Store>,
+}
+
+impl InstrumentedStore {
+    pub(crate) fn new(inner: Box<dyn Store>) -> InstrumentedStore {
+        InstrumentedStore { inner }
+    }
+}
+
+impl Store for InstrumentedStore {
+    fn chunk_size(&self) -> usize {
+        self.inner.chunk_size()
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        self.inner.algorithm()
+    }
+
+    fn set_algorithm(&mut self, algorithm: Algorithm) {
+        self.inner.set_algorithm(algorithm)
+    }
+
+    fn chunk(&self, digest: &Digest) -> DenebResult<Arc<dyn Chunk>> {
+        bump(&COUNTERS.chunk_reads, 1);
+        self.inner.chunk(digest)
+    }
+
+    fn read_at(&self, digest: &Digest, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        bump(&COUNTERS.chunk_reads, 1);
+        self.inner.read_at(digest, offset, buf)
+    }
+
+    fn read_uncached(&self, digest: &Digest, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        bump(&COUNTERS.chunk_reads, 1);
+        self.inner.read_uncached(digest, offset, buf)
+    }
+
+    fn prefetch(&self, digest: &Digest) {
+        self.inner.prefetch(digest)
+    }
+
+    fn put_chunk(&mut self, contents: &[u8]) -> DenebResult<ChunkDescriptor> {
+        bump(&COUNTERS.chunk_writes, 1);
+        bump(&COUNTERS.bytes_written, contents.len());
+        self.inner.put_chunk(contents)
+    }
+
+    fn put_file(&mut self, data: &mut dyn Read) -> DenebResult<ChunkDescriptor> {
+        let descriptor = self.inner.put_file(data)?;
+        bump(&COUNTERS.chunk_writes, 1);
+        bump(&COUNTERS.bytes_written, descriptor.size);
+        Ok(descriptor)
+    }
+
+    fn put_file_chunked(&mut self, data: &mut dyn Read) -> DenebResult<Vec<ChunkDescriptor>> {
+        let descriptors = self.inner.put_file_chunked(data)?;
+        bump(&COUNTERS.chunk_writes, descriptors.len());
+        bump(
+            &COUNTERS.bytes_written,
+            descriptors.iter().map(|d| d.size).sum(),
+        );
+        Ok(descriptors)
+    }
+
+    fn packed_chunk(&self, digest: &Digest) -> DenebResult<Vec<u8>> {
+        bump(&COUNTERS.chunk_reads, 1);
+        self.inner.packed_chunk(digest)
+    }
+
+    fn packed_size(&self, digest: &Digest) -> DenebResult<u64> {
+        self.inner.packed_size(digest)
+    }
+
+    fn missing_chunks(&self, digests: &[Digest]) -> DenebResult<Vec<Digest>> {
+        self.inner.missing_chunks(digests)
+    }
+
+    fn put_packed_chunk(&mut self, digest: &Digest, packed: &[u8]) -> DenebResult<()> {
+        bump(&COUNTERS.chunk_writes, 1);
+        bump(&COUNTERS.bytes_written, packed.len());
+        self.inner.put_packed_chunk(digest, packed)
+    }
+
+    fn chunk_digests(&self) -> DenebResult<Vec<Digest>> {
+        self.inner.chunk_digests()
+    }
+
+    fn remove_chunk(&mut self, digest: &Digest) -> DenebResult<()> {
+        self.inner.remove_chunk(digest)
+    }
+
+    fn read_special_file(&self, file_name: &Path) -> DenebResult<Vec<u8>> {
+        bump(&COUNTERS.special_file_reads, 1);
+        self.inner.read_special_file(file_name)
+    }
+
+    fn write_special_file(
+        &mut self,
+        file_name: &Path,
+        data: &mut dyn Read,
+        append: bool,
+    ) -> DenebResult<()> {
+        bump(&COUNTERS.special_file_writes, 1);
+        self.inner.write_special_file(file_name, data, append)
+    }
+
+    fn rename_special_file(&mut self, from: &Path, to: &Path) -> DenebResult<()> {
+        bump(&COUNTERS.special_file_writes, 1);
+        self.inner.rename_special_file(from, to)
+    }
+
+    fn remove_special_file(&mut self, file_name: &Path) -> DenebResult<()> {
+        bump(&COUNTERS.special_file_writes, 1);
+        self.inner.remove_special_file(file_name)
+    }
+
+    fn sync(&mut self) -> DenebResult<()> {
+        self.inner.sync()
+    }
+
+    fn persist(&mut self) -> DenebResult<()> {
+        self.inner.persist()
+    }
+
+    fn set_cache_size(&mut self, cache_size: u64) -> DenebResult<()> {
+        self.inner.set_cache_size(cache_size)
+    }
+
+    fn set_remote_limits(&mut self, limits: RemoteLimits) -> DenebResult<()> {
+        self.inner.set_remote_limits(limits)
+    }
+
+    fn set_pinned(&mut self, digests: &HashSet<Digest>) -> DenebResult<()> {
+        self.inner.set_pinned(digests)
+    }
+
+    fn dehydrate(&mut self, digests: &HashSet<Digest>) -> DenebResult<(u64, u64)> {
+        self.inner.dehydrate(digests)
+    }
+}
+
+/// A `Catalog` wrapper which counts the operations performed on the inner catalog
+pub(crate) struct InstrumentedCatalog {
+    inner: Box<dyn Catalog>,
+}
+
+impl InstrumentedCatalog {
+    pub(crate) fn new(inner: Box<dyn Catalog>) -> InstrumentedCatalog {
+        InstrumentedCatalog { inner }
+    }
+}
+
+impl Catalog for InstrumentedCatalog {
+    fn show_stats(&self) {
+        self.inner.show_stats()
+    }
+
+    fn max_index(&self) -> u64 {
+        self.inner.max_index()
+    }
+
+    fn inode(&self, index: u64) -> DenebResult<INode> {
+        bump(&COUNTERS.inode_reads, 1);
+        self.inner.inode(index)
+    }
+
+    fn inodes(&self, indices: &[u64]) -> Vec<DenebResult<INode>> {
+        bump(&COUNTERS.inode_reads, indices.len());
+        self.inner.inodes(indices)
+    }
+
+    fn dir_entry_index(&self, parent: u64, name: &Path) -> DenebResult<Option<u64>> {
+        bump(&COUNTERS.dir_entry_reads, 1);
+        self.inner.dir_entry_index(parent, name)
+    }
+
+    fn dir_entries(&self, parent: u64) -> DenebResult<Vec<(PathBuf, u64)>> {
+        bump(&COUNTERS.dir_entry_reads, 1);
+        self.inner.dir_entries(parent)
+    }
+
+    fn add_inode(&self, inode: &INode) -> DenebResult<()> {
+        bump(&COUNTERS.inode_writes, 1);
+        self.inner.add_inode(inode)
+    }
+
+    fn add_dir_entry(&self, parent: u64, name: &Path, index: u64) -> DenebResult<()> {
+        bump(&COUNTERS.dir_entry_writes, 1);
+        self.inner.add_dir_entry(parent, name, index)
+    }
+
+    fn remove_dir_entry(&self, parent: u64, name: &Path) -> DenebResult<()> {
+        bump(&COUNTERS.dir_entry_writes, 1);
+        self.inner.remove_dir_entry(parent, name)
+    }
+
+    fn remove_inode(&self, index: u64) -> DenebResult<()> {
+        bump(&COUNTERS.inode_removals, 1);
+        self.inner.remove_inode(index)
+    }
+
+    fn root_index(&self) -> DenebResult<u64> {
+        self.inner.root_index()
+    }
+
+    fn set_root_index(&self, index: u64) -> DenebResult<()> {
+        self.inner.set_root_index(index)
+    }
+
+    fn generations(&self) -> DenebResult<Vec<(u64, u64)>> {
+        self.inner.generations()
+    }
+
+    fn set_generations(&self, generations: &[(u64, u64)]) -> DenebResult<()> {
+        self.inner.set_generations(generations)
+    }
+
+    fn pins(&self) -> DenebResult<Vec<u64>> {
+        self.inner.pins()
+    }
+
+    fn set_pins(&self, pins: &[u64]) -> DenebResult<()> {
+        self.inner.set_pins(pins)
+    }
+
+    fn persist(&self) -> DenebResult<()> {
+        self.inner.persist()
+    }
+}
@@ -1,16 +1,25 @@
 use {
     crate::{
-        catalog::{Catalog, IndexGenerator},
+        cas::HashingReader,
+        catalog::{Catalog, IndexGenerator, ROOT_INDEX},
         errors::{DenebError, DenebResult},
-        inode::{FileAttributes, INode},
+        ignore::IgnorePatterns,
+        inode::{blocks_for, FileAttributes, FileType, INode},
         store::Store,
     },
     failure::{Fail, ResultExt},
-    nix::sys::stat::lstat,
+    log::{debug, warn},
+    nix::{
+        sys::stat::{lstat, SFlag},
+        unistd::{getgid, getuid},
+    },
     std::{
-        fs::{read_dir, File},
+        collections::HashMap,
+        fs::{read_dir, read_link, File},
+        os::unix::ffi::OsStrExt,
         path::Path,
     },
+    time::get_time,
 };
 
 pub mod cas;
@@ -18,9 +27,16 @@ pub mod catalog;
 pub mod crypt;
 pub mod engine;
 pub mod errors;
+pub mod gateway;
+pub mod ignore;
 pub mod inode;
+#[cfg(feature = "instrumentation")]
+pub mod instrument;
 pub mod manifest;
+pub mod metrics;
 pub mod store;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod util;
 
 mod workspace;
@@ -38,15 +54,49 @@ pub fn init() -> DenebResult<()> {
     Ok(())
 }
 
+/// Options of the import of a directory into a repository
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    /// Patterns of the files which are left out, in addition to the ones
+    /// found in the ignore file of the directory
+    pub ignore_patterns: Vec<String>,
+    /// Fail when a file which can't be stored (a socket) is found, instead of
+    /// skipping it
+    pub strict: bool,
+}
+
+struct Import<'a> {
+    root: &'a Path,
+    ignore: IgnorePatterns,
+    strict: bool,
+    // Index of the files with several hard links, by device and inode number
+    links: HashMap<(u64, u64), u64>,
+}
+
+/// Add the contents of `dir` to the catalog and the store
+///
+/// Regular files, directories, symbolic links (with their targets), FIFOs and
+/// device files are recorded along with their permissions and ownership.
+/// Symbolic links are never followed. Hard links to the same file share a
+/// single inode and its data. Files matching the ignore patterns are left
+/// out, as are sockets, unless the import is strict.
 pub fn populate_with_dir(
-    catalog: &mut dyn Catalog,
+    catalog: &dyn Catalog,
     store: &mut dyn Store,
     dir: &Path,
     chunk_size: usize,
+    options: &ImportOptions,
 ) -> DenebResult<()> {
-    let attrs = FileAttributes::with_stats(lstat(dir)?, 1);
+    let attrs = FileAttributes::with_stats(lstat(dir)?, ROOT_INDEX);
     catalog.add_inode(&INode::new(attrs, vec![]))?;
+    catalog.set_root_index(ROOT_INDEX)?;
 
+    let mut import = Import {
+        root: dir,
+        ignore: IgnorePatterns::load(dir, &options.ignore_patterns)?,
+        strict: options.strict,
+        links: HashMap::new(),
+    };
     let mut buffer = vec![0 as u8; chunk_size as usize];
     let mut index_generator = IndexGenerator::starting_at(catalog.max_index());
     visit_dirs(
@@ -54,19 +104,45 @@ pub fn populate_with_dir(
         store,
         &mut index_generator,
         buffer.as_mut_slice(),
+        &mut import,
         dir,
-        1,
-        1,
+        ROOT_INDEX,
+        ROOT_INDEX,
     )?;
 
     Ok(())
 }
 
+/// Add an empty root directory, owned by the current user, to the catalog
+pub fn populate_empty(catalog: &dyn Catalog) -> DenebResult<()> {
+    let now = get_time();
+    let attrs = FileAttributes {
+        index: ROOT_INDEX,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind: FileType::Directory,
+        perm: 0o755,
+        nlink: 2,
+        uid: getuid().as_raw(),
+        gid: getgid().as_raw(),
+        ..FileAttributes::default()
+    };
+    catalog.add_inode(&INode::new(attrs, vec![]))?;
+    catalog.add_dir_entry(ROOT_INDEX, Path::new("."), ROOT_INDEX)?;
+    catalog.add_dir_entry(ROOT_INDEX, Path::new(".."), ROOT_INDEX)?;
+    catalog.set_root_index(ROOT_INDEX)?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn visit_dirs(
-    catalog: &mut dyn Catalog,
+    catalog: &dyn Catalog,
     store: &mut dyn Store,
     index_generator: &mut IndexGenerator,
     buffer: &mut [u8],
+    import: &mut Import,
     dir: &Path,
     dir_index: u64,
     parent_index: u64,
@@ -74,6 +150,7 @@ fn visit_dirs(
     catalog.add_dir_entry(dir_index, Path::new("."), dir_index)?;
     catalog.add_dir_entry(dir_index, Path::new(".."), parent_index)?;
 
+    let mut subdirs = 0;
     for entry in read_dir(dir)? {
         let path = (entry?).path();
         let fname = Path::new(
@@ -82,26 +159,67 @@ fn visit_dirs(
                 .ok_or_else(|| DenebError::InvalidPath(path.clone()))?,
         );
 
-        let descriptors = if path.is_file() {
-            let mut abs_path = dir.to_path_buf();
-            abs_path.push(fname);
-            let mut f = File::open(abs_path)?;
-            store.put_file_chunked(&mut f)?
+        let stats = lstat(&path)?;
+        let relative_path = path.strip_prefix(import.root).unwrap_or(fname);
+        let file_type = SFlag::from_bits_truncate(stats.st_mode & SFlag::S_IFMT.bits());
+        if file_type == SFlag::S_IFSOCK {
+            if import.strict {
+                return Err(DenebError::UnsupportedFileType(path).into());
+            }
+            warn!("Skipping socket {:?}", relative_path);
+            continue;
+        }
+        let is_dir = file_type == SFlag::S_IFDIR;
+        if import.ignore.is_ignored(relative_path, is_dir) {
+            debug!("Ignoring {:?}", relative_path);
+            continue;
+        }
+
+        // Other links to an already imported file only add a directory entry
+        let link_id = (stats.st_dev, stats.st_ino);
+        if !is_dir && stats.st_nlink > 1 {
+            if let Some(&index) = import.links.get(&link_id) {
+                let mut inode = catalog.inode(index)?;
+                inode.attributes.nlink += 1;
+                catalog.add_inode(&inode)?;
+                catalog.add_dir_entry(dir_index, fname, index)?;
+                continue;
+            }
+        }
+
+        // The target of a symbolic link is stored as its contents
+        let (descriptors, digest) = if file_type == SFlag::S_IFREG {
+            let mut f = HashingReader::new(File::open(&path)?);
+            (store.put_file_chunked(&mut f)?, Some(f.finish()))
+        } else if file_type == SFlag::S_IFLNK {
+            let target = read_link(&path)?;
+            let descriptors = store.put_file_chunked(&mut target.as_os_str().as_bytes())?;
+            (descriptors, None)
         } else {
-            Vec::new()
+            (Vec::new(), None)
         };
 
         let index = index_generator.next();
-        let attrs = FileAttributes::with_stats(lstat(&path)?, index);
-        catalog.add_inode(&INode::new(attrs, descriptors))?;
+        let mut attrs = FileAttributes::with_stats(stats, index);
+        attrs.nlink = 1;
+        // The blocks taken in the store, rather than in the imported file system
+        attrs.blocks = blocks_for(descriptors.iter().map(|d| d.size as u64).sum());
+        let mut inode = INode::new(attrs, descriptors);
+        inode.digest = digest;
+        catalog.add_inode(&inode)?;
         catalog.add_dir_entry(dir_index, fname, index)?;
+        if !is_dir && stats.st_nlink > 1 {
+            import.links.insert(link_id, index);
+        }
 
-        if path.is_dir() {
+        if is_dir {
+            subdirs += 1;
             visit_dirs(
                 catalog,
                 store,
                 index_generator,
                 buffer,
+                import,
                 &path,
                 index,
                 dir_index,
@@ -109,5 +227,274 @@ fn visit_dirs(
             .context(DenebError::DirectoryVisit(dir.to_path_buf()))?;
         }
     }
+
+    // A directory is linked from its parent, from its "." entry and from the
+    // ".." entries of its subdirectories
+    let mut inode = catalog.inode(dir_index)?;
+    inode.attributes.nlink = 2 + subdirs;
+    catalog.add_inode(&inode)?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            catalog::CatalogType,
+            store::StoreType,
+            workspace::{Workspace, WorkspaceOptions},
+        },
+        nix::{sys::stat::Mode, unistd::mkfifo},
+        std::{
+            ffi::OsStr,
+            fs::{create_dir_all, hard_link, write},
+            os::unix::fs::symlink,
+            path::PathBuf,
+        },
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn special_files_are_imported() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_import_special")?;
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(sync_dir.join("dir"))?;
+        write(sync_dir.join("file"), "alabalaportocala")?;
+        symlink("file", sync_dir.join("link"))?;
+        symlink("dir", sync_dir.join("dir_link"))?;
+        symlink("/nowhere", sync_dir.join("dangling"))?;
+        mkfifo(&sync_dir.join("fifo"), Mode::S_IRUSR | Mode::S_IWUSR)?;
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().join("work"),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir.clone()),
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+
+        let mut attrs = |name: &str| -> DenebResult<FileAttributes> {
+            ws.lookup(1, OsStr::new(name))?
+                .ok_or_else(|| DenebError::InvalidPath(PathBuf::from(name)).into())
+        };
+        let file = attrs("file")?;
+        assert_eq!(file.kind, FileType::RegularFile);
+        let stats = lstat(&sync_dir.join("file"))?;
+        assert_eq!((file.uid, file.gid), (stats.st_uid, stats.st_gid));
+        assert_eq!(attrs("fifo")?.kind, FileType::NamedPipe);
+        let link = attrs("link")?;
+        let dir_link = attrs("dir_link")?;
+        let dangling = attrs("dangling")?;
+        assert_eq!(dir_link.kind, FileType::Symlink);
+
+        assert_eq!(ws.read_link(link.index)?, PathBuf::from("file"));
+        assert_eq!(ws.read_link(dir_link.index)?, PathBuf::from("dir"));
+        assert_eq!(ws.read_link(dangling.index)?, PathBuf::from("/nowhere"));
+        assert!(ws.read_link(file.index).is_err());
+
+        Ok(())
+    }
+    #[test]
+    fn hard_links_share_an_inode() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_import_hard_links")?;
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(sync_dir.join("dir"))?;
+        write(sync_dir.join("file"), "alabalaportocala")?;
+        hard_link(sync_dir.join("file"), sync_dir.join("dir/link"))?;
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().join("work"),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir.clone()),
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+
+        let file = ws.lookup(1, OsStr::new("file"))?.unwrap_or_default();
+        let dir = ws.lookup(1, OsStr::new("dir"))?.unwrap_or_default();
+        let link = ws
+            .lookup(dir.index, OsStr::new("link"))?
+            .unwrap_or_default();
+        assert_eq!(file.index, link.index);
+        assert_eq!(file.nlink, 2);
+        assert_eq!(ws.get_attr(1)?.nlink, 3);
+        assert_eq!(dir.nlink, 2);
+
+        // Removing one of the links keeps the file
+        ws.remove(1, OsStr::new("file"))?;
+        assert_eq!(ws.get_attr(link.index)?.nlink, 1);
+        ws.open_file(link.index, 0)?;
+        let data = ws.read_data(link.index, 0, 100)?;
+        assert_eq!(&data[..], &b"alabalaportocala"[..]);
+
+        Ok(())
+    }
+    #[test]
+    fn paths_are_resolved() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_resolve_path")?;
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(sync_dir.join("a/b"))?;
+        write(sync_dir.join("a/b/file"), "alabalaportocala")?;
+        symlink("b", sync_dir.join("a/link"))?;
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().join("work"),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir),
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+
+        let mut resolve = |path: &str| -> DenebResult<Option<u64>> {
+            Ok(ws.resolve_path(Path::new(path))?.map(|(index, _)| index))
+        };
+        assert_eq!(resolve("/")?, Some(1));
+        let file = resolve("/a/b/file")?;
+        assert!(file.is_some());
+        assert_eq!(resolve("a/b/../b/./file")?, file);
+        assert_eq!(resolve("a/b/missing")?, None);
+        assert_eq!(resolve("a/b/file/file")?, None);
+        // Symbolic links are not followed
+        assert_eq!(resolve("a/link/file")?, None);
+
+        // Files which are not committed yet are found as well
+        let b = resolve("a/b")?.unwrap_or_default();
+        let (index, _) = ws.create_file(b, OsStr::new("new"), 0o644, 0)?;
+        let (found, attrs) = ws
+            .resolve_path(Path::new("a/b/new"))?
+            .ok_or_else(|| DenebError::InvalidPath(PathBuf::from("a/b/new")))?;
+        assert_eq!((found, attrs.kind), (index, FileType::RegularFile));
+
+        Ok(())
+    }
+    #[test]
+    fn names_are_looked_up_ignoring_case() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_case_insensitive")?;
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(sync_dir.join("Docs"))?;
+        write(sync_dir.join("Docs/ReadMe.txt"), "alabalaportocala")?;
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().join("work"),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir),
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                case_insensitive: true,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+
+        let docs = ws.lookup(1, OsStr::new("DOCS"))?.unwrap_or_default();
+        assert_eq!(docs.kind, FileType::Directory);
+        let readme = ws.lookup(docs.index, OsStr::new("readme.TXT"))?;
+        assert!(readme.is_some());
+
+        // The case of the names is kept, and the entries come with their attributes
+        let entries = ws.read_dir(docs.index, 0)?;
+        let (_, _, attrs) = entries
+            .iter()
+            .find(|(_, name, _)| name == Path::new("ReadMe.txt"))
+            .unwrap();
+        assert_eq!(attrs.index, readme.unwrap().index);
+        assert_eq!(attrs.size, 16);
+
+        // Names differing only in case conflict
+        assert!(ws
+            .create_file(docs.index, OsStr::new("README.txt"), 0o644, 0)
+            .is_err());
+        assert!(ws.create_dir(1, OsStr::new("docs"), 0o755).is_err());
+
+        // Entries are renamed and removed under any case
+        ws.rename(
+            docs.index,
+            OsStr::new("readme.txt"),
+            docs.index,
+            OsStr::new("README.md"),
+        )?;
+        assert!(ws.lookup(docs.index, OsStr::new("ReadMe.txt"))?.is_none());
+        assert!(ws.lookup(docs.index, OsStr::new("readme.md"))?.is_some());
+        ws.remove(docs.index, OsStr::new("readme.MD"))?;
+        assert!(ws.lookup(docs.index, OsStr::new("README.md"))?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_names_are_found_once_created() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_negative_lookups")?;
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(sync_dir.join("include"))?;
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().join("work"),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir),
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+
+        let include = ws.lookup(1, OsStr::new("include"))?.unwrap_or_default();
+        for _ in 0..2 {
+            assert!(ws.lookup(include.index, OsStr::new("stdio.h"))?.is_none());
+        }
+        let (index, _) = ws.create_file(include.index, OsStr::new("stdio.h"), 0o644, 0)?;
+        let found = ws.lookup(include.index, OsStr::new("stdio.h"))?;
+        assert_eq!(found.map(|attrs| attrs.index), Some(index));
+
+        Ok(())
+    }
+
+    #[test]
+    fn inodes_are_looked_up_by_index() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_lookup_by_index")?;
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(&sync_dir)?;
+        write(sync_dir.join("file"), "alabalaportocala")?;
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().join("work"),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir),
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+
+        let file = ws.lookup(1, OsStr::new("file"))?.unwrap_or_default();
+        let found = ws.lookup(file.index, OsStr::new("."))?;
+        assert_eq!(found.map(|attrs| attrs.size), Some(16));
+        assert!(ws.lookup(1000, OsStr::new(".")).is_err());
+
+        ws.add_lookup(file.index);
+        ws.add_lookup(file.index);
+        assert_eq!(ws.forget(file.index, 1), 1);
+        assert_eq!(ws.forget(file.index, 1), 0);
+        assert_eq!(ws.forget(file.index, 1), 0);
+
+        Ok(())
+    }
+}
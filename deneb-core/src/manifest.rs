@@ -1,15 +1,68 @@
 use {
-    crate::{cas::Digest, errors::DenebResult, util::atomic_write},
+    crate::{
+        cas::{Algorithm, Digest},
+        errors::DenebResult,
+        util::atomic_write,
+    },
     serde::{Deserialize, Serialize},
-    std::{fs::File, io::Read, path::Path},
+    std::{
+        fmt::{Display, Formatter, Result as FmtResult},
+        fs::File,
+        io::Read,
+        path::Path,
+    },
     time::Tm,
 };
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Manifest {
     pub root_hash: Digest,
     #[serde(with = "serde_tm")]
     pub timestamp: Tm,
+    /// Size of the chunks the files are split into, fixed when the repository
+    /// is created. Manifests written by older versions don't record it.
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    #[serde(default)]
+    pub chunking: Option<Chunking>,
+    /// Algorithm of the digests of the chunks, fixed when the repository is
+    /// created. Manifests written by older versions don't record it, their
+    /// digests being SHA-512.
+    #[serde(default)]
+    pub digest: Option<Algorithm>,
+    /// Statistics of the repository at the time of the commit. Manifests
+    /// written by older versions don't have any.
+    #[serde(default)]
+    pub metadata: Option<Metadata>,
+}
+
+/// How the files of a repository are split into chunks
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Chunking {
+    /// Chunks of the same size, except for the last chunk of each file
+    Fixed,
+}
+
+/// Statistics and provenance of the repository state referenced by a manifest
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub files: u64,
+    pub dirs: u64,
+    /// Sum of the sizes of all the files
+    pub logical_size: u64,
+    /// Number of chunks referenced by the files, counting the chunks shared
+    /// by several files once for each of them
+    pub chunks: u64,
+    /// Name of the host on which the commit was made
+    pub hostname: String,
+    /// Version of Deneb which made the commit
+    pub version: String,
+    #[serde(default)]
+    pub message: String,
+    /// User who made the commit, as user@host
+    #[serde(default)]
+    pub author: String,
 }
 
 impl Manifest {
@@ -17,9 +70,29 @@ impl Manifest {
         Manifest {
             root_hash: hash,
             timestamp,
+            chunk_size: None,
+            chunking: None,
+            digest: None,
+            metadata: None,
         }
     }
 
+    pub fn with_chunking(mut self, chunk_size: usize, chunking: Chunking) -> Manifest {
+        self.chunk_size = Some(chunk_size);
+        self.chunking = Some(chunking);
+        self
+    }
+
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Manifest {
+        self.digest = Some(algorithm);
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Manifest {
+        self.metadata = Some(metadata);
+        self
+    }
+
     pub fn save(&self, manifest_file: &Path) -> DenebResult<()> {
         let m = toml::to_string(self)?;
         atomic_write(manifest_file, m.as_bytes())?;
@@ -42,6 +115,35 @@ impl Manifest {
     }
 }
 
+impl Display for Manifest {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        writeln!(f, "Root hash: {}", self.root_hash)?;
+        writeln!(f, "Committed: {}", self.timestamp.rfc822())?;
+        if let Some(chunk_size) = self.chunk_size {
+            writeln!(f, "Chunk size: {} bytes", chunk_size)?;
+        }
+        if let Some(digest) = self.digest {
+            writeln!(f, "Digest: {}", digest)?;
+        }
+        if let Some(ref metadata) = self.metadata {
+            write!(f, "{}", metadata)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Metadata {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        writeln!(f, "Author: {}, version: {}", self.author, self.version)?;
+        writeln!(
+            f,
+            "Files: {}, directories: {}, size: {} bytes, chunks: {}",
+            self.files, self.dirs, self.logical_size, self.chunks
+        )?;
+        writeln!(f, "\n    {}", self.message)
+    }
+}
+
 mod serde_tm {
     use serde::de::{Error, Visitor};
     use serde::{Deserializer, Serializer};
@@ -95,7 +197,9 @@ mod tests {
     fn manifest_serde() -> DenebResult<()> {
         let fake_stuff = vec![0 as u8; 100];
         let digest = hash(fake_stuff.as_slice());
-        let mut manifest = Manifest::new(digest, now_utc());
+        let mut manifest = Manifest::new(digest, now_utc())
+            .with_chunking(4096, Chunking::Fixed)
+            .with_algorithm(Algorithm::Blake3);
         // Set to zero the fields which are not serialized
         {
             let ts = &mut manifest.timestamp;
@@ -134,4 +238,31 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn manifest_metadata_is_optional() -> DenebResult<()> {
+        let digest = hash(b"catalog");
+        let mut manifest = Manifest::new(digest, now_utc());
+        manifest.timestamp.tm_nsec = 0;
+        let old_format = manifest.serialize()?;
+        assert_eq!(Manifest::deserialize(&old_format)?.metadata, None);
+        assert_eq!(Manifest::deserialize(&old_format)?.chunk_size, None);
+        assert_eq!(Manifest::deserialize(&old_format)?.digest, None);
+
+        let metadata = Metadata {
+            files: 3,
+            dirs: 1,
+            logical_size: 1024,
+            chunks: 4,
+            hostname: "localhost".to_owned(),
+            version: "0.1.0".to_owned(),
+            message: "First commit".to_owned(),
+            author: "user@localhost".to_owned(),
+        };
+        let manifest = manifest.with_metadata(metadata.clone());
+        let recovered = Manifest::deserialize(&manifest.serialize()?)?;
+        assert_eq!(recovered.metadata, Some(metadata));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,258 @@
+//! Runtime metrics of the engine
+//!
+//! The engine counts the requests it handles and can report, on demand, the
+//! amount of data not yet committed, an estimate of the memory held by its
+//! workspace and the state of its request queues. The time requests spend in
+//! the queues tells a saturated engine apart from slow store operations.
+//! Lookups in the chunk cache of the object store, corrupt chunks, chunks
+//! which were already stored when they were written and transfers from and to
+//! remote stores are counted by process-wide counters, since the store doesn't
+//! know which engine it belongs to.
+
+use {
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    },
+};
+
+/// Number of buckets of a latency histogram. Bucket `i` holds the latencies of
+/// less than 2^i microseconds, down to 2^(i-1), and the last one also all the
+/// longer latencies.
+const LATENCY_BUCKETS: usize = 32;
+
+static CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+static CACHE_MISSES: AtomicUsize = AtomicUsize::new(0);
+static CORRUPT_CHUNKS: AtomicUsize = AtomicUsize::new(0);
+static DEDUP_HITS: AtomicUsize = AtomicUsize::new(0);
+static REMOTE_BYTES_DOWNLOADED: AtomicUsize = AtomicUsize::new(0);
+static REMOTE_BYTES_UPLOADED: AtomicUsize = AtomicUsize::new(0);
+static REMOTE_RETRIES: AtomicUsize = AtomicUsize::new(0);
+static REMOTE_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// A snapshot of the metrics of a running engine
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Metrics {
+    /// Number of requests handled by the engine, by request type
+    pub operations: BTreeMap<String, u64>,
+    /// Time spent by the requests in the queue before being handled, by
+    /// request type
+    pub queue_latency: BTreeMap<String, LatencyPercentiles>,
+    /// Number of chunk lookups served from the chunk cache
+    pub cache_hits: u64,
+    /// Number of chunk lookups which required unpacking the chunk
+    pub cache_misses: u64,
+    /// Number of chunks found to be corrupt when they were read
+    pub corrupt_chunks: u64,
+    /// Number of chunks which were already in the store when they were
+    /// written, and weren't packed again
+    pub dedup_hits: u64,
+    /// Amount of data written to files and not yet committed
+    pub dirty_bytes: u64,
+    /// Number of files with an active file workspace
+    pub open_files: u64,
+    /// Estimate of the memory held by the workspace
+    pub memory: MemoryUsage,
+    /// Number of requests each engine request queue can hold
+    pub queue_capacity: u64,
+    /// Number of interactive requests waiting to be handled
+    pub interactive_queued: u64,
+    /// Number of maintenance requests waiting to be handled
+    pub maintenance_queued: u64,
+    /// Number of requests which had to wait for room in a full queue
+    pub blocked_sends: u64,
+    /// Number of requests dropped because their queue was full
+    pub shed_requests: u64,
+    /// Number of interactive requests which took too long to be served
+    pub slow_requests: u64,
+    /// Amount of packed chunk data fetched from the remote store
+    pub remote_bytes_downloaded: u64,
+    /// Amount of packed chunk data written to the remote store
+    pub remote_bytes_uploaded: u64,
+    /// Number of failed transfers from or to the remote store which were retried
+    pub remote_retries: u64,
+    /// Number of transfers from or to the remote store which failed after all retries
+    pub remote_failures: u64,
+    /// Number of pinned files and directories
+    pub pinned_entries: u64,
+    /// Number of chunks kept locally because they are pinned
+    pub pinned_chunks: u64,
+}
+
+/// Estimate of the memory held by the workspace of an engine
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct MemoryUsage {
+    /// Data written to the open files and not yet committed
+    pub file_data: u64,
+    /// Piece tables of the open files
+    pub piece_tables: u64,
+    /// Inodes kept in the inode cache
+    pub inodes: u64,
+    /// Entries of the directories loaded from the catalog
+    pub dirs: u64,
+    /// Chunks held in memory by the open files
+    pub chunks: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.file_data + self.piece_tables + self.inodes + self.dirs + self.chunks
+    }
+}
+
+/// Percentiles of the latencies of a request type, in microseconds
+///
+/// They are the upper bounds of the histogram buckets into which they fall,
+/// so they are overestimated by less than a factor of two.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LatencyPercentiles {
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+}
+
+/// Distribution of latencies, in buckets whose bounds are powers of two
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+    count: u64,
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let micros = latency.as_secs() * 1_000_000 + u64::from(latency.subsec_micros());
+        let bucket = (64 - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+        self.count += 1;
+    }
+
+    pub(crate) fn percentiles(&self) -> LatencyPercentiles {
+        LatencyPercentiles {
+            p50: self.percentile(50),
+            p95: self.percentile(95),
+            p99: self.percentile(99),
+        }
+    }
+
+    fn percentile(&self, percent: u64) -> u64 {
+        // The rank of the percentile, rounded up
+        let rank = (self.count * percent + 99) / 100;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank && seen > 0 {
+                return (1 << bucket) - 1;
+            }
+        }
+        0
+    }
+}
+
+impl Metrics {
+    /// Fraction of the chunk lookups served from the cache, if any lookups were done
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let lookups = self.cache_hits + self.cache_misses;
+        if lookups > 0 {
+            Some(self.cache_hits as f64 / lookups as f64)
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) fn record_cache_lookup(hit: bool) {
+    let counter = if hit { &CACHE_HITS } else { &CACHE_MISSES };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of chunk cache hits and misses
+pub(crate) fn cache_lookups() -> (u64, u64) {
+    (
+        CACHE_HITS.load(Ordering::Relaxed) as u64,
+        CACHE_MISSES.load(Ordering::Relaxed) as u64,
+    )
+}
+
+pub(crate) fn record_corrupt_chunk() {
+    CORRUPT_CHUNKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of chunks found to be corrupt
+pub(crate) fn corrupt_chunks() -> u64 {
+    CORRUPT_CHUNKS.load(Ordering::Relaxed) as u64
+}
+
+pub(crate) fn record_dedup_hit() {
+    DEDUP_HITS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of chunks which were already stored when they were written
+pub(crate) fn dedup_hits() -> u64 {
+    DEDUP_HITS.load(Ordering::Relaxed) as u64
+}
+
+pub(crate) fn record_remote_transfer(download: bool, bytes: usize) {
+    let counter = if download {
+        &REMOTE_BYTES_DOWNLOADED
+    } else {
+        &REMOTE_BYTES_UPLOADED
+    };
+    counter.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub(crate) fn record_remote_retry() {
+    REMOTE_RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_remote_failure() {
+    REMOTE_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns metrics with only the counters of the remote store transfers set
+pub(crate) fn remote_transfers() -> Metrics {
+    Metrics {
+        remote_bytes_downloaded: REMOTE_BYTES_DOWNLOADED.load(Ordering::Relaxed) as u64,
+        remote_bytes_uploaded: REMOTE_BYTES_UPLOADED.load(Ordering::Relaxed) as u64,
+        remote_retries: REMOTE_RETRIES.load(Ordering::Relaxed) as u64,
+        remote_failures: REMOTE_FAILURES.load(Ordering::Relaxed) as u64,
+        ..Metrics::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_rate() {
+        let mut metrics = Metrics::default();
+        assert_eq!(metrics.cache_hit_rate(), None);
+
+        metrics.cache_hits = 3;
+        metrics.cache_misses = 1;
+        let rate = metrics.cache_hit_rate().unwrap();
+        assert!((rate - 0.75).abs() < std::f64::EPSILON);
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentiles(), LatencyPercentiles::default());
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(10));
+        }
+        for _ in 0..8 {
+            histogram.record(Duration::from_millis(2));
+        }
+        histogram.record(Duration::from_secs(1));
+        histogram.record(Duration::from_secs(1 << 40));
+        let percentiles = histogram.percentiles();
+        assert_eq!(percentiles.p50, 15);
+        assert_eq!(percentiles.p95, 2047);
+        assert_eq!(percentiles.p99, (1 << 20) - 1);
+        assert_eq!(histogram.percentile(100), (1 << 31) - 1);
+    }
+}
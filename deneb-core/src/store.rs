@@ -1,23 +1,66 @@
 use {
     crate::{
-        cas::{read_chunked, Digest},
+        cas::{read_chunked, Algorithm, Digest},
         crypt::EncryptionKey,
         errors::DenebResult,
         inode::ChunkDescriptor,
     },
-    std::{io::Read, path::Path, sync::Arc},
+    std::{
+        collections::HashSet,
+        io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write},
+        path::{Path, PathBuf},
+        sync::Arc,
+    },
 };
 
-pub(crate) use self::chunk::{Chunk, DiskChunk, MemChunk};
+pub(crate) use self::{
+    bundle::{write_bundle, BundleStore},
+    chunk::{read_chunk_at, Chunk, ChunkReader, DiskChunk, MemChunk},
+};
+pub use self::{
+    disk::{PlacementPolicy, StoreLayout},
+    remote::RemoteLimits,
+};
 
+mod bundle;
 mod chunk;
 mod disk;
+mod ephemeral;
+mod http;
 mod mem;
+mod remote;
+mod tiered;
 
-#[derive(Clone, Copy)]
+const REMOTE_CACHE_PATH: &str = "remote_cache";
+
+#[derive(Clone)]
 pub enum StoreType {
     InMemory,
     OnDisk,
+    /// In-memory store which is saved to disk when the engine is stopped
+    Ephemeral,
+    /// On-disk store holding local copies of the chunks of a remote store.
+    /// At most `capacity` bytes of chunks are kept locally, and the transfers
+    /// from and to the remote store are subject to `limits`.
+    Tiered {
+        remote: RemoteStore,
+        capacity: u64,
+        limits: RemoteLimits,
+    },
+}
+
+/// Location of the chunks of a tiered store
+#[derive(Clone, Debug)]
+pub enum RemoteStore {
+    /// On-disk store of another instance, located in its work dir
+    Dir(PathBuf),
+    /// Gateway of another instance, listening on `address`
+    Gateway {
+        address: String,
+        token: Option<String>,
+    },
+    /// Bundle written by `create_bundle`, which is read-only
+    Bundle(PathBuf),
 }
 
 pub fn open_store<P: AsRef<Path>>(
@@ -25,15 +68,147 @@ pub fn open_store<P: AsRef<Path>>(
     dir: P,
     encryption_key: Option<EncryptionKey>,
     chunk_size: usize,
+    cache_size: u64,
+    verify_reads: bool,
 ) -> DenebResult<Box<dyn Store>> {
-    Ok(match store_type {
+    let store: Box<dyn Store> = match store_type {
         StoreType::InMemory => Box::new(mem::MemStore::new(encryption_key, chunk_size)),
-        StoreType::OnDisk => Box::new(disk::DiskStore::try_new(
+        StoreType::OnDisk => {
+            let mut store =
+                disk::DiskStore::try_new(dir.as_ref(), encryption_key, chunk_size, cache_size)?;
+            store.set_verify_reads(verify_reads);
+            Box::new(store)
+        }
+        StoreType::Ephemeral => Box::new(ephemeral::EphemeralStore::try_new(
             dir.as_ref(),
             encryption_key,
             chunk_size,
         )?),
-    })
+        StoreType::Tiered {
+            remote,
+            capacity,
+            limits,
+        } => {
+            let mut local = disk::DiskStore::try_new(
+                dir.as_ref(),
+                encryption_key.clone(),
+                chunk_size,
+                cache_size,
+            )?;
+            local.set_verify_reads(verify_reads);
+            let remote: Box<dyn Store> = match remote {
+                RemoteStore::Dir(remote_dir) => {
+                    open_remote_store(&remote_dir, dir.as_ref(), encryption_key, chunk_size)?
+                }
+                RemoteStore::Gateway { address, token } => Box::new(http::HttpStore::new(
+                    &address,
+                    token,
+                    chunk_size,
+                    limits.timeout,
+                )),
+                RemoteStore::Bundle(path) => {
+                    Box::new(BundleStore::open(&path, encryption_key, chunk_size)?)
+                }
+            };
+            let remote = Box::new(remote::LimitedStore::new(remote, limits));
+            Box::new(tiered::TieredStore::try_new(
+                Box::new(local),
+                remote,
+                capacity,
+            )?)
+        }
+    };
+
+    #[cfg(feature = "instrumentation")]
+    let store: Box<dyn Store> = Box::new(crate::instrument::InstrumentedStore::new(store));
+
+    Ok(store)
+}
+
+/// Open the on-disk store of another instance, located in `remote_dir`
+///
+/// Chunks are only copied from the remote store in packed form, so it gets
+/// an empty chunk cache in `local_dir` rather than sharing the cache of the
+/// other instance.
+pub(crate) fn open_remote_store(
+    remote_dir: &Path,
+    local_dir: &Path,
+    encryption_key: Option<EncryptionKey>,
+    chunk_size: usize,
+) -> DenebResult<Box<dyn Store>> {
+    Ok(Box::new(disk::DiskStore::with_cache_dir(
+        remote_dir,
+        &local_dir.join(REMOTE_CACHE_PATH),
+        encryption_key,
+        chunk_size,
+        0,
+    )?))
+}
+
+/// Open the on-disk store in `dir` for reading only, e.g. while its engine
+/// runs in another process
+///
+/// The chunks are unpacked into `cache_dir`, which is private to the reader,
+/// and every write to the store fails.
+pub(crate) fn open_read_only_store(
+    dir: &Path,
+    cache_dir: &Path,
+    encryption_key: Option<EncryptionKey>,
+    chunk_size: usize,
+) -> DenebResult<Box<dyn Store>> {
+    let store: Box<dyn Store> = Box::new(disk::DiskStore::read_only(
+        dir,
+        cache_dir,
+        encryption_key,
+        chunk_size,
+    )?);
+
+    #[cfg(feature = "instrumentation")]
+    let store: Box<dyn Store> = Box::new(crate::instrument::InstrumentedStore::new(store));
+
+    Ok(store)
+}
+
+/// Upgrade the on-disk store in `dir` to the current format
+///
+/// The store must not be in use. Returns the format version of the store
+/// before the upgrade and the current format version.
+pub fn upgrade_store(dir: &Path) -> DenebResult<(u32, u32)> {
+    disk::DiskStore::upgrade(dir)
+}
+
+/// Move the data area of the on-disk store in `dir` to `new_dir`, e.g. on a
+/// larger disk
+///
+/// The store must not be in use. The chunks are copied and verified before
+/// the data area is replaced by a link to `new_dir`, and an interrupted move
+/// is completed or abandoned when the store is next opened. Returns the
+/// number of chunks moved.
+pub fn relocate_store(dir: &Path, new_dir: &Path) -> DenebResult<usize> {
+    disk::DiskStore::relocate(dir, new_dir)
+}
+
+/// Returns the data areas of the on-disk store in `dir` besides its main one
+pub fn store_layout(dir: &Path) -> DenebResult<StoreLayout> {
+    disk::DiskStore::layout(dir)
+}
+
+/// Change the data areas of the on-disk store in `dir`
+///
+/// The store must not be in use. The chunks of the data areas which are
+/// removed are moved to the remaining ones first; the chunks of the other
+/// areas stay where they are until `rebalance_store` is called. Returns the
+/// number of chunks moved.
+pub fn set_store_layout(dir: &Path, layout: &StoreLayout) -> DenebResult<usize> {
+    disk::DiskStore::set_layout(dir, layout)
+}
+
+/// Move the chunks of the on-disk store in `dir` to the data areas picked by
+/// its placement policy, e.g. after an area was added
+///
+/// The store must not be in use. Returns the number of chunks moved.
+pub fn rebalance_store(dir: &Path) -> DenebResult<usize> {
+    disk::DiskStore::rebalance(dir)
 }
 
 /// Types which can perform IO into repository storage
@@ -42,18 +217,59 @@ pub trait Store: Send {
     /// Returns the chunk size used by the store
     fn chunk_size(&self) -> usize;
 
+    /// Returns the algorithm of the digests of the chunks written to the store
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::Sha512
+    }
+
+    /// Change the algorithm of the digests of the chunks which are written
+    ///
+    /// Chunks are read and copied whatever the algorithm of their digests, so
+    /// this only concerns new chunks. This is a no-op for stores which don't
+    /// write chunks themselves.
+    fn set_algorithm(&mut self, _algorithm: Algorithm) {}
+
     /// Returns a buffer with the contents of the requested chunk
     ///
     /// The method returns the "unpacked" chunks wrapped in an `Arc`,
     /// allowing implementations to cache the results.
     fn chunk(&self, digest: &Digest) -> DenebResult<Arc<dyn Chunk>>;
 
+    /// Read the contents of a chunk starting at `offset` into `buf`
+    ///
+    /// Returns the number of bytes read, which is less than the size of `buf`
+    /// when the end of the chunk is reached. The default implementation reads
+    /// from the unpacked chunk; stores which can serve small reads without
+    /// unpacking the whole chunk should override it.
+    fn read_at(&self, digest: &Digest, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        read_chunk_at(&*self.chunk(digest)?, offset, buf)
+    }
+
+    /// Read the contents of a chunk like `read_at`, bypassing the cache of
+    /// unpacked chunks
+    ///
+    /// The chunk is neither looked up in the cache nor added to it, so every
+    /// read goes to the store. The default implementation is `read_at`, for
+    /// stores which don't cache chunks.
+    fn read_uncached(&self, digest: &Digest, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        self.read_at(digest, offset, buf)
+    }
+
+    /// Hint that a chunk will be read soon
+    ///
+    /// Stores may use it to fetch or unpack the chunk ahead of the reads;
+    /// errors are only reported by the reads. This is a no-op for stores
+    /// which serve all chunks equally fast.
+    fn prefetch(&self, _digest: &Digest) {}
+
     /// Write a single chunk into the repository
     ///
     fn put_chunk(&mut self, contents: &[u8]) -> DenebResult<ChunkDescriptor>;
 
     /// Write a file into the repository without chunking
     ///
+    /// The default implementation reads the whole file into memory; stores
+    /// which can write it using bounded buffers should override it.
     fn put_file(&mut self, data: &mut dyn Read) -> DenebResult<ChunkDescriptor> {
         let mut buf = vec![];
         data.read_to_end(&mut buf)?;
@@ -72,6 +288,40 @@ pub trait Store: Send {
         Ok(descriptors)
     }
 
+    /// Returns a chunk as it is stored, i.e. compressed and encrypted
+    ///
+    /// Packed chunks can be copied between stores with `put_packed_chunk`
+    /// without changing their digests, as long as the stores use the same
+    /// encryption key.
+    fn packed_chunk(&self, digest: &Digest) -> DenebResult<Vec<u8>>;
+
+    /// Returns the size of a chunk as it is stored
+    fn packed_size(&self, digest: &Digest) -> DenebResult<u64> {
+        Ok(self.packed_chunk(digest)?.len() as u64)
+    }
+
+    /// Returns the digests, among `digests`, of the chunks which the store
+    /// doesn't hold
+    ///
+    /// Stores for which each lookup is a round trip should override it to
+    /// check all the digests at once.
+    fn missing_chunks(&self, digests: &[Digest]) -> DenebResult<Vec<Digest>> {
+        Ok(digests
+            .iter()
+            .filter(|digest| self.packed_size(digest).is_err())
+            .cloned()
+            .collect())
+    }
+
+    /// Write a chunk obtained with `packed_chunk` from another store
+    fn put_packed_chunk(&mut self, digest: &Digest, packed: &[u8]) -> DenebResult<()>;
+
+    /// Returns the digests of all the chunks in the store
+    fn chunk_digests(&self) -> DenebResult<Vec<Digest>>;
+
+    /// Remove a chunk from the store
+    fn remove_chunk(&mut self, digest: &Digest) -> DenebResult<()>;
+
     /// Read a special file from outside of the content-addressed area of the store
     fn read_special_file(&self, file_name: &Path) -> DenebResult<Vec<u8>>;
 
@@ -84,4 +334,112 @@ pub trait Store: Send {
         data: &mut dyn Read,
         append: bool,
     ) -> DenebResult<()>;
+
+    /// Atomically replace a special file with another special file
+    fn rename_special_file(&mut self, from: &Path, to: &Path) -> DenebResult<()>;
+
+    /// Remove a special file
+    fn remove_special_file(&mut self, file_name: &Path) -> DenebResult<()>;
+
+    /// Ensure that all the chunks written so far are durably stored
+    ///
+    /// This is a no-op for stores which are not backed by disk.
+    fn sync(&mut self) -> DenebResult<()> {
+        Ok(())
+    }
+
+    /// Save any contents of the store which are only held in memory
+    ///
+    /// This is a no-op for stores which write everything directly to disk.
+    fn persist(&mut self) -> DenebResult<()> {
+        Ok(())
+    }
+
+    /// Change the capacity of the cache of unpacked chunks
+    ///
+    /// This is a no-op for stores without a chunk cache.
+    fn set_cache_size(&mut self, _cache_size: u64) -> DenebResult<()> {
+        Ok(())
+    }
+
+    /// Change the limits of the transfers from and to the remote store
+    ///
+    /// This is a no-op for stores without a remote store.
+    fn set_remote_limits(&mut self, _limits: RemoteLimits) -> DenebResult<()> {
+        Ok(())
+    }
+
+    /// Keep local copies of the chunks `digests`, fetching the missing ones
+    ///
+    /// The chunks pinned before are released. This is a no-op for stores
+    /// which hold all their chunks locally.
+    fn set_pinned(&mut self, _digests: &HashSet<Digest>) -> DenebResult<()> {
+        Ok(())
+    }
+
+    /// Remove the local copies of the chunks `digests`, which are fetched
+    /// again when they are next read
+    ///
+    /// Returns the number of chunks removed and their packed size. Pinned
+    /// chunks are kept. This is a no-op for stores which hold all their
+    /// chunks locally.
+    fn dehydrate(&mut self, _digests: &HashSet<Digest>) -> DenebResult<(u64, u64)> {
+        Ok((0, 0))
+    }
+}
+
+/// Size of the pieces in which `SpecialFileWriter` writes a special file
+const SPECIAL_FILE_PIECE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Writes a special file of a store piece by piece, so that a large file
+/// isn't held in memory as a whole
+///
+/// The first piece replaces the file, the others are appended to it. The last
+/// piece is only written by `finish`.
+pub(crate) struct SpecialFileWriter<'a> {
+    store: &'a mut dyn Store,
+    file_name: PathBuf,
+    buffer: Vec<u8>,
+    started: bool,
+}
+
+impl<'a> SpecialFileWriter<'a> {
+    pub(crate) fn new(store: &'a mut dyn Store, file_name: &Path) -> SpecialFileWriter<'a> {
+        SpecialFileWriter {
+            store,
+            file_name: file_name.to_owned(),
+            buffer: Vec::new(),
+            started: false,
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> DenebResult<()> {
+        self.write_piece()
+    }
+
+    fn write_piece(&mut self) -> DenebResult<()> {
+        self.store.write_special_file(
+            &self.file_name,
+            &mut self.buffer.as_slice(),
+            self.started,
+        )?;
+        self.buffer.clear();
+        self.started = true;
+        Ok(())
+    }
+}
+
+impl<'a> Write for SpecialFileWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= SPECIAL_FILE_PIECE_SIZE {
+            self.write_piece()
+                .map_err(|e| IoError::new(ErrorKind::Other, e.compat()))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
 }
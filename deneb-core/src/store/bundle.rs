@@ -0,0 +1,248 @@
+use {
+    super::{disk::unpack_in_memory, Chunk, MemChunk, Store},
+    crate::{
+        cas::Digest,
+        crypt::EncryptionKey,
+        errors::{DenebResult, StoreError},
+        inode::ChunkDescriptor,
+    },
+    bincode::{deserialize, serialize_into},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        fs::File,
+        io::{BufWriter, Read, Seek, SeekFrom, Write},
+        os::unix::fs::FileExt,
+        path::{Path, PathBuf},
+        sync::Arc,
+    },
+};
+
+/// Prefix of a bundle
+const BUNDLE_MARKER: &[u8] = b"deneb-bundle\n";
+/// Size of the offset of the index, which ends a bundle
+const FOOTER_SIZE: u64 = 8;
+
+/// Index of a bundle, written after the packed chunks
+#[derive(Deserialize, Serialize)]
+struct Index {
+    /// The serialized manifest of the repository
+    manifest: Vec<u8>,
+    /// Offset and size of each packed chunk
+    chunks: Vec<(Digest, u64, u64)>,
+}
+
+/// Write a bundle holding `manifest` and the packed chunks `digests` of `store`
+///
+/// A bundle starts with `BUNDLE_MARKER`, followed by the packed chunks, the
+/// index and the offset of the index, as a little-endian `u64`. The chunks
+/// can be read in any order once the index is loaded.
+pub(crate) fn write_bundle(
+    out: &Path,
+    manifest: &[u8],
+    store: &dyn Store,
+    digests: &[Digest],
+) -> DenebResult<()> {
+    let mut writer = BufWriter::new(File::create(out)?);
+    writer.write_all(BUNDLE_MARKER)?;
+    let mut offset = BUNDLE_MARKER.len() as u64;
+    let mut chunks = Vec::with_capacity(digests.len());
+    for digest in digests {
+        let packed = store.packed_chunk(digest)?;
+        writer.write_all(&packed)?;
+        chunks.push((*digest, offset, packed.len() as u64));
+        offset += packed.len() as u64;
+    }
+    let index = Index {
+        manifest: manifest.to_vec(),
+        chunks,
+    };
+    serialize_into(&mut writer, &index)?;
+    writer.write_all(&offset.to_le_bytes())?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+/// A read-only store serving the packed chunks of a bundle
+///
+/// Like `HttpStore`, it's meant to be used as the remote store of a
+/// `TieredStore`, which unpacks the chunks once they are copied locally.
+/// Chunks are verified against their digests when they are stored locally.
+/// Chunks can also be unpacked in memory, e.g. to read the catalog index of
+/// the bundle before anything is copied.
+pub(crate) struct BundleStore {
+    path: PathBuf,
+    file: File,
+    encryption_key: Option<EncryptionKey>,
+    chunk_size: usize,
+    manifest: Vec<u8>,
+    chunks: HashMap<Digest, (u64, u64)>,
+}
+
+impl BundleStore {
+    pub(crate) fn open(
+        path: &Path,
+        encryption_key: Option<EncryptionKey>,
+        chunk_size: usize,
+    ) -> DenebResult<BundleStore> {
+        let invalid = || StoreError::InvalidBundle(path.to_owned());
+        let mut file = File::open(path)?;
+        let mut marker = vec![0; BUNDLE_MARKER.len()];
+        file.read_exact(&mut marker)?;
+        if marker != BUNDLE_MARKER {
+            return Err(invalid().into());
+        }
+
+        let size = file.seek(SeekFrom::End(0))?;
+        if size < BUNDLE_MARKER.len() as u64 + FOOTER_SIZE {
+            return Err(invalid().into());
+        }
+        let mut footer = [0; FOOTER_SIZE as usize];
+        file.read_exact_at(&mut footer, size - FOOTER_SIZE)?;
+        let index_offset = u64::from_le_bytes(footer);
+        if index_offset < BUNDLE_MARKER.len() as u64 || index_offset > size - FOOTER_SIZE {
+            return Err(invalid().into());
+        }
+        let mut buf = vec![0; (size - FOOTER_SIZE - index_offset) as usize];
+        file.read_exact_at(&mut buf, index_offset)?;
+        let index: Index = deserialize(&buf)?;
+
+        Ok(BundleStore {
+            path: path.to_owned(),
+            file,
+            encryption_key,
+            chunk_size,
+            manifest: index.manifest,
+            chunks: index
+                .chunks
+                .into_iter()
+                .map(|(digest, offset, size)| (digest, (offset, size)))
+                .collect(),
+        })
+    }
+
+    /// The serialized manifest of the repository held by the bundle
+    pub(crate) fn manifest(&self) -> &[u8] {
+        &self.manifest
+    }
+
+    fn locate(&self, digest: &Digest) -> DenebResult<(u64, u64)> {
+        self.chunks
+            .get(digest)
+            .cloned()
+            .ok_or_else(|| StoreError::ChunkGet(digest.to_string()).into())
+    }
+}
+
+impl Store for BundleStore {
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn chunk(&self, digest: &Digest) -> DenebResult<Arc<dyn Chunk>> {
+        let packed = self.packed_chunk(digest)?;
+        if !digest.matches(&packed) {
+            return Err(StoreError::CorruptChunk(digest.to_string()).into());
+        }
+        let key = self.encryption_key.as_ref();
+        let contents = unpack_in_memory(digest, &packed, key, self.chunk_size)?;
+        Ok(Arc::new(MemChunk::new(&contents)))
+    }
+
+    fn put_chunk(&mut self, _contents: &[u8]) -> DenebResult<ChunkDescriptor> {
+        Err(StoreError::Unsupported("put_chunk").into())
+    }
+
+    fn packed_chunk(&self, digest: &Digest) -> DenebResult<Vec<u8>> {
+        let (offset, size) = self.locate(digest)?;
+        let mut packed = vec![0; size as usize];
+        self.file
+            .read_exact_at(&mut packed, offset)
+            .map_err(|_| StoreError::FileGet(self.path.clone()))?;
+        Ok(packed)
+    }
+
+    fn packed_size(&self, digest: &Digest) -> DenebResult<u64> {
+        Ok(self.locate(digest)?.1)
+    }
+
+    fn put_packed_chunk(&mut self, _digest: &Digest, _packed: &[u8]) -> DenebResult<()> {
+        Err(StoreError::Unsupported("put_packed_chunk").into())
+    }
+
+    fn missing_chunks(&self, digests: &[Digest]) -> DenebResult<Vec<Digest>> {
+        Ok(digests
+            .iter()
+            .filter(|digest| !self.chunks.contains_key(digest))
+            .cloned()
+            .collect())
+    }
+
+    fn chunk_digests(&self) -> DenebResult<Vec<Digest>> {
+        Ok(self.chunks.keys().cloned().collect())
+    }
+
+    fn remove_chunk(&mut self, _digest: &Digest) -> DenebResult<()> {
+        Err(StoreError::Unsupported("remove_chunk").into())
+    }
+
+    fn read_special_file(&self, _file_name: &Path) -> DenebResult<Vec<u8>> {
+        Err(StoreError::Unsupported("read_special_file").into())
+    }
+
+    fn write_special_file(
+        &mut self,
+        _file_name: &Path,
+        _data: &mut dyn Read,
+        _append: bool,
+    ) -> DenebResult<()> {
+        Err(StoreError::Unsupported("write_special_file").into())
+    }
+
+    fn rename_special_file(&mut self, _from: &Path, _to: &Path) -> DenebResult<()> {
+        Err(StoreError::Unsupported("rename_special_file").into())
+    }
+
+    fn remove_special_file(&mut self, _file_name: &Path) -> DenebResult<()> {
+        Err(StoreError::Unsupported("remove_special_file").into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{super::disk::DiskStore, *},
+        std::fs::write,
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn packed_chunks_are_read_from_bundles() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_bundle_store")?;
+        let mut source = DiskStore::try_new(&tmp.path().join("source"), None, 16, 1024)?;
+        let descriptors =
+            source.put_file_chunked(&mut &b"alabalaportocala, 40 de turcaleti"[..])?;
+        let digests = descriptors.iter().map(|d| d.digest).collect::<Vec<_>>();
+
+        let path = tmp.path().join("bundle.deneb");
+        write_bundle(&path, b"manifest", &source, &digests[1..])?;
+        let bundle = BundleStore::open(&path, None, 16)?;
+        assert_eq!(bundle.manifest(), b"manifest");
+        assert_eq!(bundle.chunk_digests()?.len(), digests.len() - 1);
+        for digest in &digests[1..] {
+            assert_eq!(bundle.packed_chunk(digest)?, source.packed_chunk(digest)?);
+            assert_eq!(bundle.packed_size(digest)?, source.packed_size(digest)?);
+        }
+        let mut contents = vec![0; 16];
+        bundle.chunk(&digests[1])?.read_at(&mut contents, 0)?;
+        assert_eq!(contents, b", 40 de turcalet".to_vec());
+        assert!(bundle.packed_chunk(&digests[0]).is_err());
+        assert_eq!(bundle.missing_chunks(&digests)?, vec![digests[0]]);
+
+        let other = tmp.path().join("other");
+        write(&other, "alabalaportocala")?;
+        assert!(BundleStore::open(&other, None, 16).is_err());
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
 use {
     crate::errors::DenebResult,
-    log::trace,
     std::{
-        fs::{remove_file, File},
+        cmp::min,
+        fs::File,
+        io::{self, ErrorKind, Read},
         os::unix::fs::FileExt,
-        path::PathBuf,
+        path::Path,
     },
 };
 
@@ -14,29 +15,64 @@ pub trait Chunk: Send + Sync {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> DenebResult<usize>;
 
     fn size(&self) -> usize;
+
+    /// Amount of the contents of the chunk which is held in memory
+    fn memory_size(&self) -> usize {
+        0
+    }
+}
+
+/// Read the contents of a chunk starting at `offset`, stopping at its end
+pub(crate) fn read_chunk_at(chunk: &dyn Chunk, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+    let remaining = chunk.size().saturating_sub(offset as usize);
+    let len = min(buf.len(), remaining);
+    if len == 0 {
+        return Ok(0);
+    }
+    chunk.read_at(&mut buf[..len], offset)
+}
+
+/// Reads the contents of a chunk sequentially
+pub(crate) struct ChunkReader<'a> {
+    chunk: &'a dyn Chunk,
+    offset: u64,
+}
+
+impl<'a> ChunkReader<'a> {
+    pub(crate) fn new(chunk: &'a dyn Chunk) -> ChunkReader<'a> {
+        ChunkReader { chunk, offset: 0 }
+    }
+}
+
+impl<'a> Read for ChunkReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.chunk.size().saturating_sub(self.offset as usize);
+        let len = min(buf.len(), remaining);
+        if len == 0 {
+            return Ok(0);
+        }
+        let n = self
+            .chunk
+            .read_at(&mut buf[..len], self.offset)
+            .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+        self.offset += n as u64;
+        Ok(n)
+    }
 }
 
+/// An unpacked chunk stored in a file
+///
+/// The lifetime of the backing file is managed by the chunk cache of the
+/// store, not by the chunk itself.
 pub(crate) struct DiskChunk {
     size: usize,
-    disk_path: PathBuf,
     file_handle: File,
 }
 
 impl DiskChunk {
-    pub(crate) fn try_new(size: usize, disk_path: PathBuf) -> DenebResult<DiskChunk> {
-        let file_handle = File::open(&disk_path)?;
-        Ok(DiskChunk {
-            size,
-            disk_path,
-            file_handle,
-        })
-    }
-}
-
-impl Drop for DiskChunk {
-    fn drop(&mut self) {
-        trace!("Removing chunk file: {:?}", &self.disk_path);
-        let _ = remove_file(&self.disk_path);
+    pub(crate) fn try_new(size: usize, disk_path: &Path) -> DenebResult<DiskChunk> {
+        let file_handle = File::open(disk_path)?;
+        Ok(DiskChunk { size, file_handle })
     }
 }
 
@@ -73,14 +109,21 @@ impl Chunk for MemChunk {
     fn size(&self) -> usize {
         self.data.len()
     }
+
+    fn memory_size(&self) -> usize {
+        self.data.len()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::OpenOptions, io::Write};
+    use std::{
+        fs::OpenOptions,
+        io::{Read, Write},
+    };
     use tempdir::TempDir;
 
-    use super::{Chunk, DiskChunk, MemChunk};
+    use super::{Chunk, ChunkReader, DiskChunk, MemChunk};
 
     use crate::errors::DenebResult;
 
@@ -96,7 +139,7 @@ mod tests {
             .create(true)
             .open(&fname)?;
         f.write(MSG)?;
-        let cnk = DiskChunk::try_new(MSG.len(), fname.clone())?;
+        let cnk = DiskChunk::try_new(MSG.len(), &fname)?;
         let cnk = Box::new(cnk);
         let mut buf = vec![0; cnk.size()];
         cnk.read_at(&mut buf, 0)?;
@@ -114,4 +157,22 @@ mod tests {
         assert_eq!(MSG, buf.as_slice());
         Ok(())
     }
+
+    #[test]
+    fn chunk_reader() -> DenebResult<()> {
+        let data = (0..10000).map(|i| i as u8).collect::<Vec<u8>>();
+        let chunk = MemChunk::new(&data);
+        let mut rdr = ChunkReader::new(&chunk);
+        let mut buf = [0 as u8; 3000];
+        let mut read_back = Vec::new();
+        loop {
+            let n = rdr.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            read_back.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(data, read_back);
+        Ok(())
+    }
 }
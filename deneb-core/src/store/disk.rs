@@ -1,31 +1,51 @@
+mod areas;
+mod cache;
+mod format;
 mod pack;
+mod pool;
+mod relocate;
+
+pub use self::areas::{PlacementPolicy, StoreLayout};
 
 use {
-    self::pack::{pack_chunk, unpack_chunk},
-    super::{Chunk, DiskChunk, Store},
+    self::{
+        areas::{read_layout, write_layout, DataAreas},
+        cache::ChunkCache,
+        format::{check_format, upgrade_format},
+        pack::{
+            chunk_dir, pack_chunk, pack_stream, packed_size, read_packed, read_packed_range,
+            remove_packed, unpack_chunk, unpack_chunk_in_memory, unpack_packed, write_packed,
+        },
+        pool::BufferPool,
+        relocate::{finish_relocation, relocate},
+    },
+    super::{read_chunk_at, Chunk, Store},
     crate::{
-        cas::Digest,
+        cas::{Algorithm, Digest},
         crypt::EncryptionKey,
         errors::{DenebResult, StoreError},
         inode::ChunkDescriptor,
-        util::atomic_write,
+        metrics::{record_cache_lookup, record_corrupt_chunk, record_dedup_hit},
+        util::{atomic_write, sync_dir},
     },
     log::trace,
-    lru::LruCache,
-    nix::sys::stat::stat,
     std::{
-        cell::RefCell,
-        fs::{create_dir_all, File, OpenOptions},
+        cmp::min,
+        collections::{BTreeSet, HashMap, HashSet},
+        fs::{create_dir_all, remove_file, rename, File, OpenOptions},
         io::{Read, Write},
         path::{Path, PathBuf},
-        sync::Arc,
+        sync::{Arc, Condvar, Mutex},
     },
 };
 
 const OBJECT_PATH: &str = "data";
 const SCRATCH_PATH: &str = "scratch";
-const CACHE_MAX_OBJECTS: usize = 100;
+const CACHE_PATH: &str = "cache";
 const MIN_COMPRESSION_THRESHOLD: usize = 1024 * 1024;
+/// Number of chunks remembered by the hash of their contents, to avoid packing
+/// them again when the same contents are written
+const DEDUP_INDEX_CAPACITY: usize = 65536;
 
 /// A disk-based implementation of the `Store` trait.
 ///
@@ -36,13 +56,36 @@ const MIN_COMPRESSION_THRESHOLD: usize = 1024 * 1024;
 /// For example:
 /// The full path at which a file with the digest "abcdefg123456" is stored is:
 /// "`root_dir`/data/ab/cdefg123456"
+///
+/// The chunks can be spread over other data areas, e.g. on other disks, as
+/// listed by the layout of the store, using the same naming scheme.
 pub(super) struct DiskStore {
     encryption_key: Option<EncryptionKey>,
     chunk_size: usize,
     root_dir: PathBuf,
-    object_dir: PathBuf,
+    /// Data areas holding the packed chunks, the first of which is in `root_dir`
+    areas: DataAreas,
     scratch_dir: PathBuf,
-    cache: RefCell<LruCache<Digest, Arc<dyn Chunk>>>,
+    cache_dir: PathBuf,
+    cache: Mutex<ChunkCache>,
+    /// Chunks which are being unpacked
+    in_flight: Mutex<HashMap<Digest, Arc<PendingUnpack>>>,
+    /// Directories of the data area with chunks written since the last sync
+    unsynced_dirs: HashSet<PathBuf>,
+    /// Whether packed chunks are verified against their digests when unpacked
+    verify_reads: bool,
+    /// Algorithm of the digests of the chunks which are written
+    algorithm: Algorithm,
+    /// Buffers reused for packing and unpacking chunks
+    buffers: BufferPool,
+    /// Digests of the chunks written since the store was opened, by the hash
+    /// of their contents. Packing a chunk involves compressing and encrypting
+    /// it, and encrypted chunks get a different digest each time they are
+    /// packed, so the contents are looked up here first.
+    written: HashMap<Digest, Digest>,
+    /// Whether the store refuses writes, e.g. when it's read by another
+    /// process than the one of its engine
+    read_only: bool,
 }
 
 impl DiskStore {
@@ -50,24 +93,190 @@ impl DiskStore {
         dir: &Path,
         encryption_key: Option<EncryptionKey>,
         chunk_size: usize,
+        cache_size: u64,
+    ) -> DenebResult<DiskStore> {
+        finish_relocation(dir)?;
+        DiskStore::with_cache_dir(
+            dir,
+            &dir.join(CACHE_PATH),
+            encryption_key,
+            chunk_size,
+            cache_size,
+        )
+    }
+
+    /// Open a store whose unpacked chunks are cached outside of its root dir
+    ///
+    /// This allows the store of another instance to be used without touching
+    /// the cache of that instance.
+    pub(super) fn with_cache_dir(
+        dir: &Path,
+        cache_dir: &Path,
+        encryption_key: Option<EncryptionKey>,
+        chunk_size: usize,
+        cache_size: u64,
+    ) -> DenebResult<DiskStore> {
+        DiskStore::open(
+            dir,
+            cache_dir,
+            encryption_key,
+            chunk_size,
+            cache_size,
+            false,
+        )
+    }
+
+    /// Open a store for reading only, while it may be used by another process
+    ///
+    /// The unpacked chunks are cached in `cache_dir`, which must not be used
+    /// by anyone else. Nothing else is written, and an interrupted move of
+    /// the data area is left for its owner to complete.
+    pub(super) fn read_only(
+        dir: &Path,
+        cache_dir: &Path,
+        encryption_key: Option<EncryptionKey>,
+        chunk_size: usize,
+    ) -> DenebResult<DiskStore> {
+        DiskStore::open(dir, cache_dir, encryption_key, chunk_size, 0, true)
+    }
+
+    fn open(
+        dir: &Path,
+        cache_dir: &Path,
+        encryption_key: Option<EncryptionKey>,
+        chunk_size: usize,
+        cache_size: u64,
+        read_only: bool,
     ) -> DenebResult<DiskStore> {
         let root_dir = dir;
         let object_dir = root_dir.join(OBJECT_PATH);
         let scratch_dir = root_dir.join(SCRATCH_PATH);
+        let cache_dir = cache_dir.to_owned();
 
         // Create object dir
-        create_dir_all(&object_dir)?;
-        create_dir_all(&scratch_dir)?;
+        if !read_only {
+            create_dir_all(&object_dir)?;
+            create_dir_all(&scratch_dir)?;
+        }
+        check_format(&object_dir, !read_only)?;
 
         Ok(DiskStore {
             encryption_key,
             chunk_size,
             root_dir: root_dir.to_owned(),
-            object_dir,
+            areas: DataAreas::open(&object_dir)?,
             scratch_dir,
-            cache: RefCell::new(LruCache::new(CACHE_MAX_OBJECTS)),
+            cache: Mutex::new(ChunkCache::open(cache_dir.clone(), cache_size)?),
+            cache_dir,
+            in_flight: Mutex::new(HashMap::new()),
+            unsynced_dirs: HashSet::new(),
+            verify_reads: false,
+            algorithm: Algorithm::default(),
+            buffers: BufferPool::new(chunk_size),
+            written: HashMap::new(),
+            read_only,
         })
     }
+
+    /// Bring the data area of the store in `dir` to the current format
+    pub(super) fn upgrade(dir: &Path) -> DenebResult<(u32, u32)> {
+        finish_relocation(dir)?;
+        upgrade_format(&dir.join(OBJECT_PATH))
+    }
+
+    /// Move the data area of the store in `dir` to `new_dir`
+    pub(super) fn relocate(dir: &Path, new_dir: &Path) -> DenebResult<usize> {
+        relocate(dir, new_dir)
+    }
+
+    /// Returns the data areas of the store in `dir` besides its main one
+    pub(super) fn layout(dir: &Path) -> DenebResult<StoreLayout> {
+        read_layout(&dir.join(OBJECT_PATH))
+    }
+
+    /// Change the data areas of the store in `dir`, moving the chunks out of
+    /// the areas which are removed
+    pub(super) fn set_layout(dir: &Path, layout: &StoreLayout) -> DenebResult<usize> {
+        finish_relocation(dir)?;
+        write_layout(&dir.join(OBJECT_PATH), &dir.join(SCRATCH_PATH), layout)
+    }
+
+    /// Move the chunks of the store in `dir` to the data areas its placement
+    /// policy picks for them
+    pub(super) fn rebalance(dir: &Path) -> DenebResult<usize> {
+        finish_relocation(dir)?;
+        DataAreas::open(&dir.join(OBJECT_PATH))?.rebalance(&dir.join(SCRATCH_PATH))
+    }
+
+    /// Verify the packed chunks against their digests before unpacking them
+    ///
+    /// The digest of a chunk is the hash of its packed form, so this detects
+    /// any corruption of the data area, at the cost of reading each chunk
+    /// twice when it's unpacked. Chunks served from the chunk cache are not
+    /// verified again.
+    pub(super) fn set_verify_reads(&mut self, verify: bool) {
+        self.verify_reads = verify;
+    }
+
+    fn check_writable(&self) -> DenebResult<()> {
+        if self.read_only {
+            return Err(StoreError::ReadOnly.into());
+        }
+        Ok(())
+    }
+
+    // Unpack a chunk and add it to the chunk cache
+    fn unpack(&self, digest: &Digest) -> DenebResult<Arc<dyn Chunk>> {
+        if self.verify_reads && !digest.matches(&read_packed(self.areas.locate(digest), digest)?) {
+            record_corrupt_chunk();
+            return Err(StoreError::CorruptChunk(digest.to_string()).into());
+        }
+        let (full_path, content_hash) = unpack_chunk(
+            digest,
+            self.areas.locate(digest),
+            &self.cache_dir,
+            self.encryption_key.as_ref(),
+            &self.buffers,
+        )?;
+        self.cache
+            .lock()
+            .map_err(|_| StoreError::CacheLock)?
+            .insert(*digest, full_path, content_hash)
+    }
+}
+
+/// An unpack operation in progress
+///
+/// Readers requesting a chunk which is already being unpacked wait for the
+/// result of the pending operation, instead of unpacking the chunk again.
+struct PendingUnpack {
+    result: Mutex<Option<Option<Arc<dyn Chunk>>>>,
+    done: Condvar,
+}
+
+impl PendingUnpack {
+    fn new() -> PendingUnpack {
+        PendingUnpack {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        }
+    }
+
+    fn complete(&self, chunk: Option<Arc<dyn Chunk>>) {
+        if let Ok(mut result) = self.result.lock() {
+            *result = Some(chunk);
+            self.done.notify_all();
+        }
+    }
+
+    // Returns `None` if the unpack operation failed
+    fn wait(&self) -> Option<Arc<dyn Chunk>> {
+        let mut result = self.result.lock().ok()?;
+        while result.is_none() {
+            result = self.done.wait(result).ok()?;
+        }
+        result.as_ref().and_then(Option::clone)
+    }
 }
 
 impl Store for DiskStore {
@@ -75,45 +284,210 @@ impl Store for DiskStore {
         self.chunk_size
     }
 
+    fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    fn set_algorithm(&mut self, algorithm: Algorithm) {
+        if algorithm != self.algorithm {
+            self.algorithm = algorithm;
+            self.written.clear();
+        }
+    }
+
     fn chunk(&self, digest: &Digest) -> DenebResult<Arc<dyn Chunk>> {
-        let mut cache = self.cache.borrow_mut();
-        if cache.contains(digest) {
-            cache
-                .get(digest)
-                .map(Arc::clone)
-                .ok_or_else(|| StoreError::ChunkGet(digest.to_string()).into())
-        } else {
-            let full_path = unpack_chunk(
+        // Either find the chunk in the cache, join a pending unpack operation
+        // or register a new one
+        let (pending, owner) = {
+            let mut cache = self.cache.lock().map_err(|_| StoreError::CacheLock)?;
+            let cached = cache.get(digest);
+            record_cache_lookup(cached.is_some());
+            if let Some(chunk) = cached {
+                return Ok(chunk);
+            }
+            let mut in_flight = self.in_flight.lock().map_err(|_| StoreError::CacheLock)?;
+            match in_flight.get(digest) {
+                Some(pending) => (Arc::clone(pending), false),
+                None => {
+                    let pending = Arc::new(PendingUnpack::new());
+                    in_flight.insert(*digest, Arc::clone(&pending));
+                    (pending, true)
+                }
+            }
+        };
+
+        if !owner {
+            trace!("Waiting for pending unpack of chunk {}", digest);
+            return pending
+                .wait()
+                .ok_or_else(|| StoreError::ChunkGet(digest.to_string()).into());
+        }
+
+        let result = self.unpack(digest);
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            in_flight.remove(digest);
+        }
+        pending.complete(result.as_ref().ok().cloned());
+        result
+    }
+
+    // Small reads of chunks which aren't unpacked yet only decrypt the blocks
+    // they need, when the chunk supports it. The contents are authenticated
+    // block by block, but the digest of the whole chunk can only be verified
+    // by unpacking it.
+    fn read_at(&self, digest: &Digest, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        let cached = self
+            .cache
+            .lock()
+            .map_err(|_| StoreError::CacheLock)?
+            .get(digest);
+        if let Some(chunk) = cached {
+            record_cache_lookup(true);
+            return read_chunk_at(&*chunk, offset, buf);
+        }
+        if !self.verify_reads {
+            let ranged = read_packed_range(
+                self.areas.locate(digest),
                 digest,
-                &self.object_dir,
-                &self.scratch_dir,
                 self.encryption_key.as_ref(),
+                offset,
+                buf,
+                &self.buffers,
             )?;
-            let file_stats = stat(full_path.as_path())?;
-            let chunk = DiskChunk::try_new(file_stats.st_size as usize, full_path)?;
-            cache.put(*digest, Arc::new(chunk));
-            cache
-                .get(digest)
-                .map(Arc::clone)
-                .ok_or_else(|| StoreError::ChunkGet(digest.to_string()).into())
+            if let Some(n) = ranged {
+                record_cache_lookup(false);
+                return Ok(n);
+            }
+        }
+        read_chunk_at(&*self.chunk(digest)?, offset, buf)
+    }
+
+    // Chunks which can't be read by range are unpacked in memory and dropped
+    // after the read
+    fn read_uncached(&self, digest: &Digest, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        record_cache_lookup(false);
+        let packed_root = self.areas.locate(digest);
+        if self.verify_reads {
+            if !digest.matches(&read_packed(packed_root, digest)?) {
+                record_corrupt_chunk();
+                return Err(StoreError::CorruptChunk(digest.to_string()).into());
+            }
+        } else {
+            let key = self.encryption_key.as_ref();
+            if let Some(n) =
+                read_packed_range(packed_root, digest, key, offset, buf, &self.buffers)?
+            {
+                return Ok(n);
+            }
+        }
+        let contents = unpack_chunk_in_memory(
+            digest,
+            packed_root,
+            self.encryption_key.as_ref(),
+            &self.buffers,
+        )?;
+        let start = min(offset as usize, contents.len());
+        let len = min(buf.len(), contents.len() - start);
+        buf[..len].copy_from_slice(&contents[start..start + len]);
+        Ok(len)
+    }
+
+    // Unpack the chunk into the chunk cache, ahead of the reads
+    fn prefetch(&self, digest: &Digest) {
+        if let Err(e) = self.chunk(digest) {
+            trace!("Prefetch of chunk {} failed: {}", digest, e);
         }
     }
 
     fn put_chunk(&mut self, contents: &[u8]) -> DenebResult<ChunkDescriptor> {
+        self.check_writable()?;
+        // Chunks written before are reused, as long as they weren't removed since
+        let contents_hash = self.algorithm.hash(contents);
+        if let Some(digest) = self.written.get(&contents_hash) {
+            if packed_size(self.areas.locate(digest), digest).is_ok() {
+                record_dedup_hit();
+                return Ok(ChunkDescriptor {
+                    digest: *digest,
+                    size: contents.len(),
+                });
+            }
+        }
+
         let compressed = contents.len() > MIN_COMPRESSION_THRESHOLD;
         let digest = pack_chunk(
             contents,
-            &self.object_dir,
+            &self.areas,
             &self.scratch_dir,
             compressed,
             self.encryption_key.as_ref(),
+            self.algorithm,
+            &self.buffers,
         )?;
+        self.unsynced_dirs
+            .insert(chunk_dir(self.areas.locate(&digest), &digest));
+        if self.written.len() >= DEDUP_INDEX_CAPACITY {
+            self.written.clear();
+        }
+        self.written.insert(contents_hash, digest);
         Ok(ChunkDescriptor {
             digest,
             size: contents.len(),
         })
     }
 
+    fn put_file(&mut self, data: &mut dyn Read) -> DenebResult<ChunkDescriptor> {
+        self.check_writable()?;
+        let (digest, size) = pack_stream(
+            data,
+            &self.areas,
+            &self.scratch_dir,
+            true,
+            self.encryption_key.as_ref(),
+            self.algorithm,
+            &self.buffers,
+        )?;
+        self.unsynced_dirs
+            .insert(chunk_dir(self.areas.locate(&digest), &digest));
+        Ok(ChunkDescriptor { digest, size })
+    }
+
+    fn packed_chunk(&self, digest: &Digest) -> DenebResult<Vec<u8>> {
+        read_packed(self.areas.locate(digest), digest)
+    }
+
+    fn packed_size(&self, digest: &Digest) -> DenebResult<u64> {
+        packed_size(self.areas.locate(digest), digest)
+    }
+
+    fn put_packed_chunk(&mut self, digest: &Digest, packed: &[u8]) -> DenebResult<()> {
+        self.check_writable()?;
+        write_packed(packed, digest, &self.areas, &self.scratch_dir)?;
+        self.unsynced_dirs
+            .insert(chunk_dir(self.areas.locate(digest), digest));
+        Ok(())
+    }
+
+    fn chunk_digests(&self) -> DenebResult<Vec<Digest>> {
+        self.areas.digests()
+    }
+
+    fn remove_chunk(&mut self, digest: &Digest) -> DenebResult<()> {
+        self.check_writable()?;
+        // A chunk is in several data areas only after an interrupted move
+        let holding = self.areas.holding(digest);
+        if holding.is_empty() {
+            remove_packed(self.areas.main(), digest)?;
+        }
+        for dir in holding {
+            remove_packed(dir, digest)?;
+        }
+        self.cache
+            .lock()
+            .map_err(|_| StoreError::CacheLock)?
+            .remove(digest);
+        Ok(())
+    }
+
     fn read_special_file(&self, file_name: &Path) -> DenebResult<Vec<u8>> {
         let mut body = Vec::new();
         let full_path = self.root_dir.join(file_name);
@@ -129,6 +503,7 @@ impl Store for DiskStore {
         data: &mut dyn Read,
         append: bool,
     ) -> DenebResult<()> {
+        self.check_writable()?;
         let mut body = Vec::new();
         data.read_to_end(&mut body)?;
         let full_path = self.root_dir.join(file_name);
@@ -139,16 +514,79 @@ impl Store for DiskStore {
                 .create(true)
                 .open(&full_path)?;
             f.write_all(&body)?;
+            f.sync_all()?;
         } else {
             atomic_write(full_path.as_path(), body.as_slice())?;
         }
         trace!("Special file written: {:?}", full_path);
         Ok(())
     }
+
+    fn rename_special_file(&mut self, from: &Path, to: &Path) -> DenebResult<()> {
+        self.check_writable()?;
+        let to = self.root_dir.join(to);
+        rename(self.root_dir.join(from), &to)?;
+        if let Some(dir) = to.parent() {
+            sync_dir(dir)?;
+        }
+        trace!("Special file renamed: {:?}", to);
+        Ok(())
+    }
+
+    fn remove_special_file(&mut self, file_name: &Path) -> DenebResult<()> {
+        self.check_writable()?;
+        let full_path = self.root_dir.join(file_name);
+        remove_file(&full_path)?;
+        trace!("Special file removed: {:?}", full_path);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> DenebResult<()> {
+        // Sync the chunk directories, followed by their parents, which may
+        // have been created since the last sync
+        let mut parents = BTreeSet::new();
+        for dir in self.unsynced_dirs.drain() {
+            sync_dir(&dir)?;
+            if let Some(parent) = dir.parent() {
+                parents.insert(parent.to_owned());
+            }
+        }
+        for dir in parents {
+            sync_dir(&dir)?;
+        }
+        for dir in self.areas.dirs() {
+            sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    fn persist(&mut self) -> DenebResult<()> {
+        self.check_writable()?;
+        self.cache.lock().map_err(|_| StoreError::CacheLock)?.save()
+    }
+
+    fn set_cache_size(&mut self, cache_size: u64) -> DenebResult<()> {
+        self.cache
+            .lock()
+            .map_err(|_| StoreError::CacheLock)?
+            .set_capacity(cache_size);
+        Ok(())
+    }
+}
+
+/// Unpack a chunk which isn't held by a disk store, e.g. one read from a bundle
+pub(super) fn unpack_in_memory(
+    digest: &Digest,
+    packed: &[u8],
+    encryption_key: Option<&EncryptionKey>,
+    chunk_size: usize,
+) -> DenebResult<Vec<u8>> {
+    unpack_packed(digest, packed, encryption_key, &BufferPool::new(chunk_size))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::thread::spawn;
     use tempdir::TempDir;
 
     use super::*;
@@ -157,7 +595,7 @@ mod tests {
     fn diskstore_create_put_get() -> DenebResult<()> {
         const BYTES: &[u8] = b"alabalaportocala";
         let temp_dir = TempDir::new("/tmp/deneb_test_diskstore")?;
-        let mut store = DiskStore::try_new(temp_dir.path(), None, 10000)?;
+        let mut store = DiskStore::try_new(temp_dir.path(), None, 10000, 1_000_000)?;
         let mut v1: &[u8] = BYTES;
         let descriptors = store.put_file_chunked(&mut v1)?;
         let v2 = store.chunk(&descriptors[0].digest)?;
@@ -166,4 +604,98 @@ mod tests {
         assert_eq!(BYTES, buf.as_slice());
         Ok(())
     }
+
+    #[test]
+    fn identical_chunks_are_packed_once() -> DenebResult<()> {
+        let temp_dir = TempDir::new("/tmp/deneb_test_diskstore")?;
+        let key = EncryptionKey::new();
+        let mut store = DiskStore::try_new(temp_dir.path(), Some(key), 10000, 1_000_000)?;
+        let digest = store.put_chunk(b"alabalaportocala")?.digest;
+        // Encrypted chunks would get another digest if they were packed again
+        assert_eq!(store.put_chunk(b"alabalaportocala")?.digest, digest);
+        assert_ne!(store.put_chunk(b"40 de turcaleti")?.digest, digest);
+        assert_eq!(store.chunk_digests()?.len(), 2);
+
+        // Removed chunks are written again
+        store.remove_chunk(&digest)?;
+        assert_eq!(store.missing_chunks(&[digest])?, vec![digest]);
+        let digest = store.put_chunk(b"alabalaportocala")?.digest;
+        assert!(store.missing_chunks(&[digest])?.is_empty());
+        let mut buf = vec![0; 16];
+        store.read_at(&digest, 0, &mut buf)?;
+        assert_eq!(b"alabalaportocala", buf.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn uncached_reads_skip_the_cache() -> DenebResult<()> {
+        for key in vec![None, Some(EncryptionKey::new())] {
+            let temp_dir = TempDir::new("/tmp/deneb_test_diskstore")?;
+            let mut store = DiskStore::try_new(temp_dir.path(), key, 10000, 1_000_000)?;
+            let digest = store.put_chunk(b"alabalaportocala")?.digest;
+
+            let mut buf = vec![0; 8];
+            assert_eq!(store.read_uncached(&digest, 6, &mut buf)?, 8);
+            assert_eq!(b"aportoca", buf.as_slice());
+            assert_eq!(store.read_uncached(&digest, 12, &mut buf)?, 4);
+            assert_eq!(b"cala", &buf[..4]);
+            assert!(store.cache.lock().unwrap().get(&digest).is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_reads_share_unpack() -> DenebResult<()> {
+        const BYTES: &[u8] = b"alabalaportocala";
+        let temp_dir = TempDir::new("/tmp/deneb_test_diskstore")?;
+        let mut store = DiskStore::try_new(temp_dir.path(), None, 10000, 1_000_000)?;
+        let mut v1: &[u8] = BYTES;
+        let digest = store.put_file(&mut v1)?.digest;
+
+        let store = Arc::new(store);
+        let readers = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                spawn(move || store.chunk(&digest).ok())
+            })
+            .collect::<Vec<_>>();
+        let chunks = readers
+            .into_iter()
+            .map(|r| r.join().unwrap().unwrap())
+            .collect::<Vec<_>>();
+
+        // All readers receive the same unpacked chunk
+        assert!(chunks.iter().all(|c| Arc::ptr_eq(c, &chunks[0])));
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_chunks_are_detected() -> DenebResult<()> {
+        let temp_dir = TempDir::new("/tmp/deneb_test_diskstore")?;
+        let mut store = DiskStore::try_new(temp_dir.path(), None, 10000, 1_000_000)?;
+        store.set_verify_reads(true);
+        let digest = store.put_chunk(b"alabalaportocala")?.digest;
+
+        // Flip a bit of the packed chunk, in place
+        let mut packed = store.packed_chunk(&digest)?;
+        let last = packed.len() - 1;
+        packed[last] ^= 1;
+        let digest_str = digest.to_string();
+        let path = temp_dir
+            .path()
+            .join(OBJECT_PATH)
+            .join(&digest_str[..2])
+            .join(&digest_str[2..4])
+            .join(&digest_str[4..]);
+        atomic_write(&path, &packed)?;
+
+        let err = store.chunk(&digest).err().unwrap();
+        match err.downcast_ref::<StoreError>() {
+            Some(StoreError::CorruptChunk(d)) => assert_eq!(*d, digest_str),
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,359 @@
+use {
+    super::pack::{
+        chunk_dir, chunk_path, packed_digests, read_packed, remove_packed, write_packed,
+    },
+    crate::{
+        cas::Digest,
+        errors::{DenebResult, StoreError},
+        util::{atomic_write, sync_dir},
+    },
+    log::info,
+    nix::sys::statvfs::statvfs,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashSet,
+        fmt::{Display, Formatter, Result as FmtResult},
+        fs::{create_dir_all, read_to_string},
+        io::ErrorKind,
+        path::{Path, PathBuf},
+        str::FromStr,
+    },
+};
+
+/// File of the main data area listing the other data areas of the store
+const LAYOUT_PATH: &str = "layout";
+
+/// How new chunks are spread over the data areas of a store
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlacementPolicy {
+    /// Each chunk goes to the data area picked by the first byte of its
+    /// digest, which spreads the chunks evenly over the areas
+    HashPrefix,
+    /// Chunks go to the first data area whose disk has more free space than
+    /// the reserve, so an area is only used once the previous ones are full
+    FillThenSpill,
+}
+
+impl Default for PlacementPolicy {
+    fn default() -> PlacementPolicy {
+        PlacementPolicy::FillThenSpill
+    }
+}
+
+impl Display for PlacementPolicy {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            PlacementPolicy::HashPrefix => write!(f, "hash-prefix"),
+            PlacementPolicy::FillThenSpill => write!(f, "fill-then-spill"),
+        }
+    }
+}
+
+impl FromStr for PlacementPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PlacementPolicy, String> {
+        match s {
+            "hash-prefix" => Ok(PlacementPolicy::HashPrefix),
+            "fill-then-spill" => Ok(PlacementPolicy::FillThenSpill),
+            _ => Err(format!(
+                "Unknown placement policy: {} (expected hash-prefix or fill-then-spill)",
+                s
+            )),
+        }
+    }
+}
+
+/// The data areas of a store besides its main one, e.g. on other disks
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct StoreLayout {
+    /// Directories of the other data areas, in the order they are filled
+    pub dirs: Vec<PathBuf>,
+    pub placement: PlacementPolicy,
+    /// Free space, in bytes, kept on the disks of the data areas when the
+    /// chunks fill them one after the other
+    pub reserve: u64,
+}
+
+/// Chooses the data area in which a packed chunk is written
+pub(super) trait PackedRoot {
+    fn for_chunk(&self, digest: &Digest) -> &Path;
+}
+
+impl PackedRoot for Path {
+    fn for_chunk(&self, _digest: &Digest) -> &Path {
+        self
+    }
+}
+
+impl PackedRoot for PathBuf {
+    fn for_chunk(&self, _digest: &Digest) -> &Path {
+        self
+    }
+}
+
+/// The data areas of a store: the main one, in the work dir, which also holds
+/// the manifest and the format of the store, followed by the ones of its layout
+pub(super) struct DataAreas {
+    dirs: Vec<PathBuf>,
+    placement: PlacementPolicy,
+    reserve: u64,
+}
+
+impl DataAreas {
+    pub(super) fn open(object_dir: &Path) -> DenebResult<DataAreas> {
+        let layout = read_layout(object_dir)?;
+        for dir in &layout.dirs {
+            create_dir_all(dir)?;
+        }
+        Ok(DataAreas::with_layout(object_dir, layout))
+    }
+
+    fn with_layout(object_dir: &Path, layout: StoreLayout) -> DataAreas {
+        let mut dirs = vec![object_dir.to_owned()];
+        dirs.extend(layout.dirs);
+        DataAreas {
+            dirs,
+            placement: layout.placement,
+            reserve: layout.reserve,
+        }
+    }
+
+    pub(super) fn main(&self) -> &Path {
+        &self.dirs[0]
+    }
+
+    pub(super) fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    /// The data area holding a chunk, or the one it would be written to
+    pub(super) fn locate(&self, digest: &Digest) -> &Path {
+        if self.dirs.len() == 1 {
+            return self.main();
+        }
+        self.holding(digest)
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| self.preferred(digest))
+    }
+
+    /// The data areas holding a chunk, which is in several of them only if a
+    /// move between areas was interrupted
+    pub(super) fn holding(&self, digest: &Digest) -> Vec<&Path> {
+        if self.dirs.len() == 1 {
+            return vec![self.main()];
+        }
+        self.dirs
+            .iter()
+            .map(PathBuf::as_path)
+            .filter(|dir| chunk_path(dir, digest).exists())
+            .collect()
+    }
+
+    /// The digests of the chunks of all the data areas
+    pub(super) fn digests(&self) -> DenebResult<Vec<Digest>> {
+        if self.dirs.len() == 1 {
+            return packed_digests(self.main());
+        }
+        let mut digests = HashSet::new();
+        for dir in &self.dirs {
+            digests.extend(packed_digests(dir)?);
+        }
+        Ok(digests.into_iter().collect())
+    }
+
+    /// Move the chunks to the data areas the placement policy picks for them
+    ///
+    /// With `FillThenSpill`, chunks are only moved to earlier areas which have
+    /// room for them. Returns the number of chunks moved.
+    pub(super) fn rebalance(&self, scratch_dir: &Path) -> DenebResult<usize> {
+        let mut moved = 0;
+        for (index, dir) in self.dirs.iter().enumerate() {
+            for digest in packed_digests(dir)? {
+                let target = match self.placement {
+                    PlacementPolicy::HashPrefix => self.by_prefix(&digest),
+                    PlacementPolicy::FillThenSpill => self.dirs[..index]
+                        .iter()
+                        .map(PathBuf::as_path)
+                        .find(|area| self.has_room(area))
+                        .unwrap_or_else(|| dir.as_path()),
+                };
+                if target != dir.as_path() {
+                    move_chunk(&digest, dir, target, scratch_dir)?;
+                    moved += 1;
+                }
+            }
+        }
+        info!("Store rebalanced: {} chunks moved", moved);
+        Ok(moved)
+    }
+
+    // The data area a new chunk is written to
+    fn preferred(&self, digest: &Digest) -> &Path {
+        match self.placement {
+            PlacementPolicy::HashPrefix => self.by_prefix(digest),
+            PlacementPolicy::FillThenSpill => self
+                .dirs
+                .iter()
+                .map(PathBuf::as_path)
+                .find(|dir| self.has_room(dir))
+                .unwrap_or_else(|| self.dirs[self.dirs.len() - 1].as_path()),
+        }
+    }
+
+    fn by_prefix(&self, digest: &Digest) -> &Path {
+        let prefix = u8::from_str_radix(&digest.to_string()[..2], 16).unwrap_or(0);
+        &self.dirs[prefix as usize % self.dirs.len()]
+    }
+
+    fn has_room(&self, dir: &Path) -> bool {
+        match statvfs(dir) {
+            Ok(stats) => {
+                let free = stats.blocks_available() as u64 * stats.fragment_size() as u64;
+                free > self.reserve
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl PackedRoot for DataAreas {
+    // A chunk which is already stored is written again in the same data area
+    fn for_chunk(&self, digest: &Digest) -> &Path {
+        self.locate(digest)
+    }
+}
+
+/// Returns the layout of the store whose main data area is `object_dir`
+pub(super) fn read_layout(object_dir: &Path) -> DenebResult<StoreLayout> {
+    match read_to_string(object_dir.join(LAYOUT_PATH)) {
+        Ok(contents) => Ok(toml::from_str(&contents)?),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(StoreLayout::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Change the layout of the store whose main data area is `object_dir`
+///
+/// The chunks of the data areas which are left out of the new layout are
+/// first moved to the remaining areas, so an interrupted change leaves them
+/// in one of the areas of the old layout. Returns the number of chunks moved.
+pub(super) fn write_layout(
+    object_dir: &Path,
+    scratch_dir: &Path,
+    layout: &StoreLayout,
+) -> DenebResult<usize> {
+    let mut seen = HashSet::new();
+    seen.insert(object_dir.canonicalize()?);
+    for dir in &layout.dirs {
+        if !dir.is_absolute() {
+            return Err(StoreError::Layout(format!("{:?} is not an absolute path", dir)).into());
+        }
+        create_dir_all(dir)?;
+        if !seen.insert(dir.canonicalize()?) {
+            return Err(StoreError::Layout(format!("{:?} is used twice", dir)).into());
+        }
+    }
+
+    let old = read_layout(object_dir)?;
+    let areas = DataAreas::with_layout(object_dir, layout.clone());
+    let mut moved = 0;
+    for dir in old.dirs.iter().filter(|dir| !layout.dirs.contains(dir)) {
+        for digest in packed_digests(dir)? {
+            move_chunk(&digest, dir, areas.preferred(&digest), scratch_dir)?;
+            moved += 1;
+        }
+        info!("Data area {:?} emptied", dir);
+    }
+
+    atomic_write(
+        &object_dir.join(LAYOUT_PATH),
+        toml::to_string(layout)?.as_bytes(),
+    )?;
+    Ok(moved)
+}
+
+// Copy a chunk to another data area, where it's verified and synced before
+// being removed from the first area
+fn move_chunk(digest: &Digest, from: &Path, to: &Path, scratch_dir: &Path) -> DenebResult<()> {
+    if !chunk_path(to, digest).exists() {
+        write_packed(&read_packed(from, digest)?, digest, to, scratch_dir)?;
+        sync_dir(&chunk_dir(to, digest))?;
+    }
+    remove_packed(from, digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{super::DiskStore, *},
+        crate::store::Store,
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn chunks_are_spread_over_data_areas_and_rebalanced() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_store_areas")?;
+        let root_dir = tmp.path().join("work");
+        let object_dir = root_dir.join("data");
+        let scratch_dir = root_dir.join("scratch");
+        let second = tmp.path().join("disk2");
+
+        let mut store = DiskStore::try_new(&root_dir, None, 16, 0)?;
+        let mut digests = Vec::new();
+        for i in 0..32u8 {
+            digests.push(store.put_chunk(&[i; 16])?.digest);
+        }
+        store.sync()?;
+        drop(store);
+
+        // Spreading the chunks by digest moves some of them to the new area
+        let layout = StoreLayout {
+            dirs: vec![second.clone()],
+            placement: PlacementPolicy::HashPrefix,
+            reserve: 0,
+        };
+        assert_eq!(write_layout(&object_dir, &scratch_dir, &layout)?, 0);
+        assert_eq!(read_layout(&object_dir)?, layout);
+        let areas = DataAreas::open(&object_dir)?;
+        let moved = areas.rebalance(&scratch_dir)?;
+        assert!(moved > 0 && moved < digests.len());
+        assert_eq!(packed_digests(&second)?.len(), moved);
+        assert_eq!(areas.rebalance(&scratch_dir)?, 0);
+
+        let mut store = DiskStore::try_new(&root_dir, None, 16, 0)?;
+        assert_eq!(store.chunk_digests()?.len(), digests.len());
+        let digest = store.put_chunk(b"alabalaportocala")?.digest;
+        assert_eq!(
+            store.packed_size(&digest)?,
+            store.packed_chunk(&digest)?.len() as u64
+        );
+        for (i, digest) in digests.iter().enumerate() {
+            let mut buf = [0; 16];
+            store.chunk(digest)?.read_at(&mut buf, 0)?;
+            assert_eq!(buf, [i as u8; 16]);
+        }
+        drop(store);
+
+        // Dropping the area moves its chunks back to the main one
+        let moved = write_layout(&object_dir, &scratch_dir, &StoreLayout::default())?;
+        assert!(moved > 0);
+        assert!(packed_digests(&second)?.is_empty());
+        assert_eq!(packed_digests(&object_dir)?.len(), digests.len() + 1);
+
+        let relative = StoreLayout {
+            dirs: vec![PathBuf::from("disk2")],
+            ..StoreLayout::default()
+        };
+        assert!(write_layout(&object_dir, &scratch_dir, &relative).is_err());
+        let twice = StoreLayout {
+            dirs: vec![second.clone(), second.join(".")],
+            ..StoreLayout::default()
+        };
+        assert!(write_layout(&object_dir, &scratch_dir, &twice).is_err());
+
+        Ok(())
+    }
+}
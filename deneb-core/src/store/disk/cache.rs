@@ -0,0 +1,321 @@
+use {
+    super::super::{Chunk, DiskChunk},
+    crate::{
+        cas::{hash, Digest},
+        errors::DenebResult,
+        util::atomic_write,
+    },
+    bincode::{deserialize, serialize},
+    log::{info, trace, warn},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{HashMap, HashSet},
+        fs::{create_dir_all, metadata, read, read_dir, remove_file},
+        path::{Path, PathBuf},
+        sync::Arc,
+    },
+};
+
+const INDEX_FILE_NAME: &str = "index";
+
+/// A size-limited cache of unpacked chunks, which is kept across restarts
+///
+/// The cache keeps track of the total size of the unpacked chunk files and,
+/// when the size exceeds the capacity of the cache, the least recently used
+/// files are deleted.
+///
+/// When the store is persisted, an index with the size and content hash of
+/// each unpacked file is saved. After a restart, only the files listed in
+/// the index are reused, and only once their contents are verified against
+/// the index. If the index is missing, for example after a crash, the whole
+/// cache is discarded.
+pub(super) struct ChunkCache {
+    dir: PathBuf,
+    capacity: u64,
+    size: u64,
+    tick: u64,
+    entries: HashMap<Digest, Entry>,
+}
+
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    content_hash: Digest,
+    last_used: u64,
+    /// `None` for files restored from the index, until they are verified
+    chunk: Option<Arc<dyn Chunk>>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct IndexEntry {
+    digest: Digest,
+    path: PathBuf,
+    size: u64,
+    content_hash: Digest,
+}
+
+impl ChunkCache {
+    pub(super) fn open(dir: PathBuf, capacity: u64) -> DenebResult<ChunkCache> {
+        create_dir_all(&dir)?;
+
+        // The index is removed once loaded: it only describes the cache
+        // contents at the time it was saved
+        let index_path = dir.join(INDEX_FILE_NAME);
+        let index = read(&index_path)
+            .ok()
+            .and_then(|buffer| deserialize::<Vec<IndexEntry>>(&buffer).ok())
+            .unwrap_or_default();
+        let _ = remove_file(&index_path);
+
+        let mut cache = ChunkCache {
+            dir,
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+        };
+        for entry in index {
+            let on_disk = metadata(&entry.path).map(|m| m.len()).ok();
+            if on_disk == Some(entry.size) {
+                cache.size += entry.size;
+                cache.entries.insert(
+                    entry.digest,
+                    Entry {
+                        path: entry.path,
+                        size: entry.size,
+                        content_hash: entry.content_hash,
+                        last_used: 0,
+                        chunk: None,
+                    },
+                );
+            }
+        }
+
+        let known = cache
+            .entries
+            .values()
+            .map(|e| e.path.clone())
+            .collect::<HashSet<_>>();
+        remove_unknown_files(&cache.dir, &known)?;
+
+        info!(
+            "Chunk cache: {} unpacked chunks restored, {} bytes",
+            cache.entries.len(),
+            cache.size
+        );
+
+        cache.evict(None);
+
+        Ok(cache)
+    }
+
+    /// Look up the unpacked chunk with the given digest
+    pub(super) fn get(&mut self, digest: &Digest) -> Option<Arc<dyn Chunk>> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(digest)?;
+        entry.last_used = self.tick;
+        if entry.chunk.is_none() {
+            entry.chunk = verify(entry);
+        }
+        match entry.chunk {
+            Some(ref chunk) => Some(Arc::clone(chunk)),
+            None => {
+                warn!("Discarding invalid unpacked chunk {}", digest);
+                self.remove(digest);
+                None
+            }
+        }
+    }
+
+    /// Add a newly unpacked chunk file to the cache
+    pub(super) fn insert(
+        &mut self,
+        digest: Digest,
+        path: PathBuf,
+        content_hash: Digest,
+    ) -> DenebResult<Arc<dyn Chunk>> {
+        let size = metadata(&path)?.len();
+        let chunk: Arc<dyn Chunk> = Arc::new(DiskChunk::try_new(size as usize, &path)?);
+        if let Some(old) = self.entries.remove(&digest) {
+            self.size -= old.size;
+        }
+        self.tick += 1;
+        self.size += size;
+        self.entries.insert(
+            digest,
+            Entry {
+                path,
+                size,
+                content_hash,
+                last_used: self.tick,
+                chunk: Some(Arc::clone(&chunk)),
+            },
+        );
+        self.evict(Some(&digest));
+        Ok(chunk)
+    }
+
+    /// Change the capacity of the cache, evicting chunks if it shrinks
+    pub(super) fn set_capacity(&mut self, capacity: u64) {
+        self.capacity = capacity;
+        self.evict(None);
+    }
+
+    /// Save the index of the cache, allowing the unpacked chunks to be reused
+    /// after a restart
+    pub(super) fn save(&self) -> DenebResult<()> {
+        let index = self
+            .entries
+            .iter()
+            .map(|(digest, entry)| IndexEntry {
+                digest: *digest,
+                path: entry.path.clone(),
+                size: entry.size,
+                content_hash: entry.content_hash,
+            })
+            .collect::<Vec<_>>();
+        atomic_write(&self.dir.join(INDEX_FILE_NAME), &serialize(&index)?)
+    }
+
+    // Remove least recently used chunks until the size of the cache is below
+    // its capacity. The chunk identified by `keep` is never removed.
+    fn evict(&mut self, keep: Option<&Digest>) {
+        while self.size > self.capacity {
+            let victim = self
+                .entries
+                .iter()
+                .filter(|(digest, _)| Some(*digest) != keep)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(digest, _)| *digest);
+            match victim {
+                Some(digest) => self.remove(&digest),
+                None => break,
+            }
+        }
+    }
+
+    // Chunks which are still referenced remain readable after their file is
+    // removed, since they hold an open handle to it
+    pub(super) fn remove(&mut self, digest: &Digest) {
+        if let Some(entry) = self.entries.remove(digest) {
+            self.size -= entry.size;
+            let _ = remove_file(&entry.path);
+            trace!("Unpacked chunk evicted: {:?}", entry.path);
+        }
+    }
+}
+
+fn verify(entry: &Entry) -> Option<Arc<dyn Chunk>> {
+    let contents = read(&entry.path).ok()?;
+    if contents.len() as u64 != entry.size || hash(&contents) != entry.content_hash {
+        return None;
+    }
+    DiskChunk::try_new(contents.len(), &entry.path)
+        .ok()
+        .map(|chunk| Arc::new(chunk) as Arc<dyn Chunk>)
+}
+
+fn remove_unknown_files(dir: &Path, known: &HashSet<PathBuf>) -> DenebResult<()> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_unknown_files(&path, known)?;
+        } else if !known.contains(&path) {
+            remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::write;
+    use tempdir::TempDir;
+
+    fn unpacked(dir: &Path, name: &str, contents: &[u8]) -> DenebResult<(Digest, PathBuf, Digest)> {
+        let path = dir.join(name);
+        write(&path, contents)?;
+        Ok((hash(name.as_bytes()), path, hash(contents)))
+    }
+
+    #[test]
+    fn evicts_least_recently_used() -> DenebResult<()> {
+        let tmp = TempDir::new("/tmp/deneb_test_chunk_cache")?;
+        let mut cache = ChunkCache::open(tmp.path().to_owned(), 10)?;
+
+        let (d1, p1, h1) = unpacked(tmp.path(), "one", b"12345")?;
+        let (d2, p2, h2) = unpacked(tmp.path(), "two", b"12345")?;
+        let (d3, p3, h3) = unpacked(tmp.path(), "three", b"12345")?;
+        cache.insert(d1, p1.clone(), h1)?;
+        cache.insert(d2, p2.clone(), h2)?;
+        assert!(cache.get(&d1).is_some());
+        cache.insert(d3, p3, h3)?;
+
+        assert!(cache.get(&d1).is_some());
+        assert!(cache.get(&d2).is_none());
+        assert!(cache.get(&d3).is_some());
+        assert!(p1.exists());
+        assert!(!p2.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn evicts_when_shrunk() -> DenebResult<()> {
+        let tmp = TempDir::new("/tmp/deneb_test_chunk_cache")?;
+        let mut cache = ChunkCache::open(tmp.path().to_owned(), 10)?;
+
+        let (d1, p1, h1) = unpacked(tmp.path(), "one", b"12345")?;
+        let (d2, p2, h2) = unpacked(tmp.path(), "two", b"12345")?;
+        cache.insert(d1, p1.clone(), h1)?;
+        cache.insert(d2, p2, h2)?;
+
+        cache.set_capacity(5);
+        assert!(cache.get(&d1).is_none());
+        assert!(cache.get(&d2).is_some());
+        assert!(!p1.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reused_after_restart() -> DenebResult<()> {
+        let tmp = TempDir::new("/tmp/deneb_test_chunk_cache")?;
+        let (d1, d2) = {
+            let mut cache = ChunkCache::open(tmp.path().to_owned(), 100)?;
+            let (d1, p1, h1) = unpacked(tmp.path(), "one", b"12345")?;
+            let (d2, p2, h2) = unpacked(tmp.path(), "two", b"67890")?;
+            cache.insert(d1, p1, h1)?;
+            cache.insert(d2, p2.clone(), h2)?;
+            cache.save()?;
+            // Corrupt one of the unpacked files
+            write(&p2, b"abcde")?;
+            (d1, d2)
+        };
+
+        let mut cache = ChunkCache::open(tmp.path().to_owned(), 100)?;
+        assert!(cache.get(&d1).is_some());
+        assert!(cache.get(&d2).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn discarded_without_index() -> DenebResult<()> {
+        let tmp = TempDir::new("/tmp/deneb_test_chunk_cache")?;
+        let (d1, p1) = {
+            let mut cache = ChunkCache::open(tmp.path().to_owned(), 100)?;
+            let (d1, p1, h1) = unpacked(tmp.path(), "one", b"12345")?;
+            cache.insert(d1, p1.clone(), h1)?;
+            (d1, p1)
+        };
+
+        let mut cache = ChunkCache::open(tmp.path().to_owned(), 100)?;
+        assert!(cache.get(&d1).is_none());
+        assert!(!p1.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use {
+    super::pack::packed_digests,
+    crate::{
+        errors::{DenebResult, StoreError},
+        util::atomic_write,
+    },
+    log::info,
+    std::{fs::read_to_string, io::ErrorKind, path::Path},
+};
+
+/// Version of the layout of the data area. Stores written before the version
+/// was recorded have no version file, and are considered to be at version 0.
+///
+/// Version history:
+/// - 0: chunks start directly with their header
+/// - 1: chunks start with a version byte (`CHUNK_VERSION`)
+pub(super) const FORMAT_VERSION: u32 = 1;
+
+const FORMAT_PATH: &str = "format";
+
+/// Migration from each version to the next one, indexed by the older version
+const MIGRATIONS: [fn(&Path) -> DenebResult<()>; FORMAT_VERSION as usize] = [upgrade_from_0];
+
+/// Check that the data area in `object_dir` can be used with this version
+///
+/// A new data area is stamped with the current version, unless `stamp` is
+/// false.
+pub(super) fn check_format(object_dir: &Path, stamp: bool) -> DenebResult<()> {
+    let version = match read_format(object_dir)? {
+        Some(version) => version,
+        None if packed_digests(object_dir)?.is_empty() => {
+            if stamp {
+                write_format(object_dir, FORMAT_VERSION)?;
+            }
+            FORMAT_VERSION
+        }
+        None => 0,
+    };
+    if version != FORMAT_VERSION {
+        return Err(StoreError::Format(version, FORMAT_VERSION).into());
+    }
+    Ok(())
+}
+
+/// Bring the data area in `object_dir` to the current version
+///
+/// Returns the version the data area was at before the upgrade and the
+/// current version.
+pub(super) fn upgrade_format(object_dir: &Path) -> DenebResult<(u32, u32)> {
+    let from = read_format(object_dir)?.unwrap_or(0);
+    if from > FORMAT_VERSION {
+        return Err(StoreError::Format(from, FORMAT_VERSION).into());
+    }
+    for version in from..FORMAT_VERSION {
+        MIGRATIONS[version as usize](object_dir)?;
+        // Recorded after each step, so an interrupted upgrade can be resumed
+        write_format(object_dir, version + 1)?;
+        info!(
+            "Data area {:?} upgraded to version {}",
+            object_dir,
+            version + 1
+        );
+    }
+    Ok((from, FORMAT_VERSION))
+}
+
+fn read_format(object_dir: &Path) -> DenebResult<Option<u32>> {
+    match read_to_string(object_dir.join(FORMAT_PATH)) {
+        Ok(contents) => Ok(Some(contents.trim().parse::<u32>()?)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_format(object_dir: &Path, version: u32) -> DenebResult<()> {
+    atomic_write(
+        &object_dir.join(FORMAT_PATH),
+        format!("{}\n", version).as_bytes(),
+    )
+}
+
+/// Chunks without a version byte are still unpacked, and their digests are
+/// referenced by the catalogs of the retained commits, so they are kept as
+/// they are. Only the version of the data area is recorded.
+fn upgrade_from_0(_object_dir: &Path) -> DenebResult<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::fs::create_dir_all, tempdir::TempDir};
+
+    #[test]
+    fn data_areas_are_versioned() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_store_format")?;
+        let object_dir = tmp.path().join("data");
+
+        // A new data area gets the current version
+        create_dir_all(&object_dir)?;
+        check_format(&object_dir, true)?;
+        assert_eq!(read_format(&object_dir)?, Some(FORMAT_VERSION));
+
+        // Older data areas need to be upgraded
+        write_format(&object_dir, 0)?;
+        assert!(check_format(&object_dir, true).is_err());
+        assert_eq!(upgrade_format(&object_dir)?, (0, FORMAT_VERSION));
+        check_format(&object_dir, true)?;
+        assert_eq!(
+            upgrade_format(&object_dir)?,
+            (FORMAT_VERSION, FORMAT_VERSION)
+        );
+
+        // Newer data areas are refused
+        write_format(&object_dir, FORMAT_VERSION + 1)?;
+        assert!(check_format(&object_dir, true).is_err());
+        assert!(upgrade_format(&object_dir).is_err());
+
+        Ok(())
+    }
+}
@@ -1,18 +1,22 @@
 use {
+    super::{areas::PackedRoot, pool::BufferPool},
     crate::{
-        cas::{hash, Digest},
-        crypt::{decrypt, encrypt, EncryptionKey, Nonce},
-        errors::DenebResult,
+        cas::{digest_from_slice, Algorithm, Digest, Hasher},
+        crypt::{decrypt, encrypt, EncryptionKey, Nonce, MAC_SIZE},
+        errors::{DenebResult, StoreError},
         util::create_temp_file,
     },
     failure::{Fail, ResultExt},
     log::trace,
+    nix::libc::EXDEV,
     scopeguard::defer,
     serde::{Deserialize, Serialize},
     std::{
         cell::Cell,
-        fs::{create_dir_all, remove_file, rename, File},
-        io::{Read, Write},
+        cmp::min,
+        fs::{create_dir_all, metadata, read_dir, remove_file, rename, File},
+        io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
+        os::unix::fs::FileExt,
         path::{Path, PathBuf},
     },
 };
@@ -21,20 +25,48 @@ use {
 /// chunks in the the object store.
 ///
 /// Packed chunks are typically stored in the "data" subdir of the store, while
-/// the unpacked chunks are stored in the "scratch". An unpacked chunk is
-/// created in response to a call to Store::chunk and only lives while there are
-/// active references to the chunk - the backing file of the unpacked chunk is
-/// deleted when the chunk is no longer referenced.
+/// the unpacked chunks are stored in the "cache" subdir. An unpacked chunk is
+/// created in response to a call to Store::chunk and is kept by the chunk cache
+/// of the store until it is evicted.
 ///
 /// A call to Store::put_file or Store::put_file_chunked will create a packed
 /// chunk in the data area of the store. The original data is hashed, compressed
-/// and encrypted in the packed chunk.
+/// and encrypted in the packed chunk. Packed chunks are synced to disk before
+/// being moved into the data area; syncing the directories of the data area is
+/// left to Store::sync.
 ///
 /// The process to unpack the chunk involves saving a decrypted and decompressed
-/// copy of the chunk data into the "scratch" area of the store.
+/// copy of the chunk data into the "cache" area of the store.
+///
+/// Chunks written with `pack_stream` are encrypted in blocks of bounded size,
+/// so that neither packing nor unpacking them requires holding the entire
+/// chunk in memory. They are marked by a leading `STREAM_MARKER` byte, which
+/// can not be the first byte of a `Header`.
+///
+/// Chunks start with a `VERSION_MARKER` byte followed by the version of their
+/// layout. Chunks written before the version was introduced start directly
+/// with a `Header` or with `STREAM_MARKER`, and are still unpacked.
+///
+/// Uncompressed chunks written with `pack_chunk` and an encryption key are
+/// encrypted in blocks of `RANGED_BLOCK_SIZE` bytes, such that any range of
+/// their contents can be read by decrypting only the blocks holding it. They
+/// are marked by `RANGED_MARKER`, which versions before `RANGED_VERSION` don't
+/// know about. Uncompressed and unencrypted chunks are read in ranges as well.
 
 const PREFIX_SIZE: usize = 2;
 
+const STREAM_MARKER: u8 = 2;
+const VERSION_MARKER: u8 = 3;
+const RANGED_MARKER: u8 = 4;
+const PLAIN_VERSION: u8 = 1;
+const RANGED_VERSION: u8 = 2;
+/// The newest chunk layout which can be unpacked
+const CHUNK_VERSION: u8 = RANGED_VERSION;
+const STREAM_BLOCK_SIZE: usize = 1024 * 1024; // 1 MB
+const RANGED_BLOCK_SIZE: usize = 64 * 1024; // 64 KB
+/// Upper bound of the size of the version and of the headers of a chunk
+const MAX_PREFIX_SIZE: usize = 128;
+
 #[derive(Debug, Fail)]
 #[fail(display = "Missing encryption key")]
 pub struct MissingKeyError;
@@ -53,43 +85,172 @@ struct Header {
     nonce: Option<Nonce>,
 }
 
+/// Header of chunks written with `pack_stream`. Each block of `block_size`
+/// bytes is encrypted separately; the first block uses `nonce`, and each of
+/// the following blocks uses the increment of the previous nonce.
+#[derive(Deserialize, Serialize)]
+struct StreamHeader {
+    compressed: bool,
+    nonce: Option<Nonce>,
+    block_size: u32,
+}
+
+/// Header of chunks which support ranged access. Each block of `block_size`
+/// bytes is encrypted separately, as for streamed chunks; `size` is the size
+/// of the contents.
+#[derive(Deserialize, Serialize)]
+struct RangedHeader {
+    nonce: Nonce,
+    block_size: u32,
+    size: u64,
+}
+
+/// A writer which computes the digest of everything written through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W, algorithm: Algorithm) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Hasher::with_algorithm(algorithm),
+        }
+    }
+
+    fn finish(mut self) -> DenebResult<Digest> {
+        self.inner.flush().context(ChunkIOError)?;
+        Ok(self.hasher.finish())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 pub(super) fn pack_chunk(
     contents: &[u8],
-    packed_root: &Path,
+    packed_root: &(impl PackedRoot + ?Sized),
     scratch_root: &Path,
     compressed: bool,
     encryption_key: Option<&EncryptionKey>,
+    algorithm: Algorithm,
+    buffers: &BufferPool,
 ) -> DenebResult<Digest> {
+    if let (false, Some(key)) = (compressed, encryption_key) {
+        return pack_ranged(contents, packed_root, scratch_root, key, algorithm, buffers);
+    }
+
     // Optionally encrypt the body of the chunk
-    let (contents, nonce) = if let Some(key) = encryption_key {
+    let (ciphertext, nonce) = if let Some(key) = encryption_key {
         let nonce = Nonce::new();
         let ciphertext = encrypt(contents, &nonce, key);
-        (ciphertext, Some(nonce))
+        (Some(ciphertext), Some(nonce))
     } else {
-        (contents.to_owned(), None)
+        (None, None)
     };
+    let contents = ciphertext.as_ref().map_or(contents, Vec::as_slice);
 
     // the header contains the packing parameters (compression, encryption
     // nonce)
     let header = Header { compressed, nonce };
 
-    let mut buffer = Vec::new();
+    let mut buffer = buffers.take();
 
-    // the header is written without compression or encryption
-    let header = bincode::serialize(&header)?;
-    std::io::copy(&mut header.as_slice(), &mut buffer).context("could not write chunk header")?;
+    // the version and the header are written without compression or encryption
+    buffer.extend_from_slice(&[VERSION_MARKER, PLAIN_VERSION]);
+    bincode::serialize_into(&mut *buffer, &header)?;
 
     if compressed {
-        copy_body(
-            &mut contents.as_slice(),
-            &mut snap::Writer::new(&mut buffer),
-        )?;
+        copy_body(&mut &contents[..], &mut snap::Writer::new(&mut *buffer))?;
     } else {
-        copy_body(&mut contents.as_slice(), &mut buffer)?;
+        buffer.extend_from_slice(contents);
     }
 
-    let digest = hash(buffer.as_slice());
-    let (path_suffix, directory) = digest_to_path(&digest);
+    let digest = algorithm.hash(buffer.as_slice());
+    write_packed_file(&buffer, &digest, packed_root, scratch_root)?;
+    Ok(digest)
+}
+
+// Pack an uncompressed chunk encrypted in blocks, which supports ranged access
+fn pack_ranged(
+    contents: &[u8],
+    packed_root: &(impl PackedRoot + ?Sized),
+    scratch_root: &Path,
+    key: &EncryptionKey,
+    algorithm: Algorithm,
+    buffers: &BufferPool,
+) -> DenebResult<Digest> {
+    let nonce = Nonce::new();
+    let header = RangedHeader {
+        nonce: nonce.clone(),
+        block_size: RANGED_BLOCK_SIZE as u32,
+        size: contents.len() as u64,
+    };
+
+    let mut buffer = buffers.take();
+    buffer.extend_from_slice(&[VERSION_MARKER, RANGED_VERSION, RANGED_MARKER]);
+    bincode::serialize_into(&mut *buffer, &header)?;
+    write_blocks(
+        &mut &contents[..],
+        &mut *buffer,
+        Some(nonce),
+        Some(key),
+        RANGED_BLOCK_SIZE,
+        buffers,
+    )?;
+
+    let digest = algorithm.hash(buffer.as_slice());
+    write_packed_file(&buffer, &digest, packed_root, scratch_root)?;
+    Ok(digest)
+}
+
+/// Store a packed chunk obtained from another store, without unpacking it
+///
+/// The packed contents are verified against the digest before being written.
+pub(super) fn write_packed(
+    packed: &[u8],
+    digest: &Digest,
+    packed_root: &(impl PackedRoot + ?Sized),
+    scratch_root: &Path,
+) -> DenebResult<()> {
+    if !digest.matches(packed) {
+        return Err(StoreError::ChunkPut(digest.to_string()).into());
+    }
+    write_packed_file(packed, digest, packed_root, scratch_root)
+}
+
+/// Returns the contents of a packed chunk, as stored in the data area
+pub(super) fn read_packed(packed_root: &Path, digest: &Digest) -> DenebResult<Vec<u8>> {
+    let (path_suffix, _) = digest_to_path(digest);
+    let mut packed = Vec::new();
+    File::open(packed_root.join(path_suffix))?.read_to_end(&mut packed)?;
+    Ok(packed)
+}
+
+/// Returns the size of a packed chunk in the data area
+pub(super) fn packed_size(packed_root: &Path, digest: &Digest) -> DenebResult<u64> {
+    let (path_suffix, _) = digest_to_path(digest);
+    Ok(metadata(packed_root.join(path_suffix))?.len())
+}
+
+fn write_packed_file(
+    packed: &[u8],
+    digest: &Digest,
+    packed_root: &(impl PackedRoot + ?Sized),
+    scratch_root: &Path,
+) -> DenebResult<()> {
+    let packed_root = packed_root.for_chunk(digest);
+    let (path_suffix, directory) = digest_to_path(digest);
     let full_path = packed_root.join(path_suffix);
     // ensure all needed dirs are created in the data dir
     create_dir_all(packed_root.join(directory))?;
@@ -104,62 +265,480 @@ pub(super) fn pack_chunk(
         }
     }}
 
-    copy_body(&mut buffer.as_slice(), &mut f)?;
-    rename(&temp_path, &full_path)?;
+    copy_body(&mut &packed[..], &mut f)?;
+    f.sync_all().context(ChunkIOError)?;
+    move_packed_file(&temp_path, &full_path)?;
 
     // Packing was successful. Disable RAII cleanup guard
     cleanup.set(false);
 
     trace!("Chunk written: {:?}", full_path);
-    Ok(digest)
+    Ok(())
+}
+
+/// Move a packed chunk from the scratch area to its place in the data area
+///
+/// The data area is on another file system than the scratch area when it was
+/// moved with `relocate`, in which case the chunk is copied into a temporary
+/// file next to its place, which is then renamed.
+fn move_packed_file(temp_path: &Path, full_path: &Path) -> DenebResult<()> {
+    match rename(temp_path, full_path) {
+        Err(ref e) if e.raw_os_error() == Some(EXDEV) => {}
+        r => return Ok(r?),
+    }
+
+    let cleanup = Cell::new(true);
+    let (mut f, local_path) = create_temp_file(full_path)?;
+    defer! {{
+        if cleanup.get() {
+            remove_file(&local_path).expect("could not delete temporary file");
+        }
+    }}
+    copy_body(&mut File::open(temp_path)?, &mut f)?;
+    f.sync_all().context(ChunkIOError)?;
+    rename(&local_path, full_path)?;
+    cleanup.set(false);
+
+    // The chunk is in place, the copy in the scratch area is only garbage
+    let _ = remove_file(temp_path);
+    Ok(())
+}
+
+/// Pack a chunk whose contents are read from a stream
+///
+/// The contents are read, encrypted and written in blocks of `STREAM_BLOCK_SIZE`
+/// bytes, and the digest of the packed chunk is computed as it is written,
+/// so memory usage does not depend on the size of the chunk. Returns the
+/// digest of the packed chunk and the size of its contents.
+pub(super) fn pack_stream(
+    data: &mut dyn Read,
+    packed_root: &(impl PackedRoot + ?Sized),
+    scratch_root: &Path,
+    compressed: bool,
+    encryption_key: Option<&EncryptionKey>,
+    algorithm: Algorithm,
+    buffers: &BufferPool,
+) -> DenebResult<(Digest, usize)> {
+    // Create the temporary file and set up an RAII guard to delete it
+    // in case of errors
+    let cleanup = Cell::new(true);
+    let (f, temp_path) = create_temp_file(&scratch_root.join("chunk"))?;
+    defer! {{
+        if cleanup.get() {
+            remove_file(&temp_path).expect("could not delete temporary file");
+        }
+    }}
+
+    let nonce = encryption_key.map(|_| Nonce::new());
+    let header = StreamHeader {
+        compressed,
+        nonce: nonce.clone(),
+        block_size: STREAM_BLOCK_SIZE as u32,
+    };
+
+    // the version, the marker and the header are written without compression
+    // or encryption
+    let mut packed = HashingWriter::new(BufWriter::new(&f), algorithm);
+    packed
+        .write_all(&[VERSION_MARKER, PLAIN_VERSION, STREAM_MARKER])
+        .context("could not write chunk header")?;
+    bincode::serialize_into(&mut packed, &header)?;
+
+    let size = if compressed {
+        let mut writer = snap::Writer::new(&mut packed);
+        let size = write_blocks(
+            data,
+            &mut writer,
+            nonce,
+            encryption_key,
+            STREAM_BLOCK_SIZE,
+            buffers,
+        )?;
+        writer.flush().context(ChunkIOError)?;
+        size
+    } else {
+        write_blocks(
+            data,
+            &mut packed,
+            nonce,
+            encryption_key,
+            STREAM_BLOCK_SIZE,
+            buffers,
+        )?
+    };
+    let digest = packed.finish()?;
+    f.sync_all().context(ChunkIOError)?;
+
+    let packed_root = packed_root.for_chunk(&digest);
+    let (path_suffix, directory) = digest_to_path(&digest);
+    let full_path = packed_root.join(path_suffix);
+    // ensure all needed dirs are created in the data dir
+    create_dir_all(packed_root.join(directory))?;
+    move_packed_file(&temp_path, &full_path)?;
+
+    // Packing was successful. Disable RAII cleanup guard
+    cleanup.set(false);
+
+    trace!("Chunk written: {:?}", full_path);
+    Ok((digest, size))
 }
 
+/// Unpack a chunk, returning the path of the unpacked file and the hash of its contents
 pub(super) fn unpack_chunk(
     digest: &Digest,
     packed_root: &Path,
     unpacked_root: &Path,
     encryption_key: Option<&EncryptionKey>,
-) -> DenebResult<PathBuf> {
+    buffers: &BufferPool,
+) -> DenebResult<(PathBuf, Digest)> {
     let (path_suffix, dir) = digest_to_path(digest);
     let unpacked_file_name = unpacked_root.join(&path_suffix);
     create_dir_all(unpacked_root.join(dir))?;
 
-    let mut packed = File::open(packed_root.join(&path_suffix))?;
-
-    let header = bincode::deserialize_from::<_, Header>(Read::by_ref(&mut packed))?;
-
-    let mut buffer = Vec::new();
-    if header.compressed {
-        copy_body(&mut snap::Reader::new(packed), &mut buffer)?;
-    } else {
-        copy_body(&mut packed, &mut buffer)?;
-    }
-
-    let body = if let Some(nonce) = header.nonce {
-        let key = encryption_key.ok_or(MissingKeyError)?;
-        decrypt(&buffer, &nonce, &key)
-    } else {
-        Ok(buffer)
-    }?;
+    let mut packed = BufReader::new(File::open(packed_root.join(&path_suffix))?);
 
     // Create the temporary file and set up an RAII guard to delete it
     // in case of errors
     let cleanup = Cell::new(true);
-    let (mut unpacked, temp_path) = create_temp_file(&unpacked_file_name)?;
+    let (f, temp_path) = create_temp_file(&unpacked_file_name)?;
     defer! {{
         if cleanup.get() {
             remove_file(&temp_path).expect("could not delete temporary file");
         }
     }}
 
-    copy_body(&mut body.as_slice(), &mut unpacked)?;
+    let mut unpacked = HashingWriter::new(BufWriter::new(f), Algorithm::Sha512);
+    unpack_body(digest, &mut packed, &mut unpacked, encryption_key, buffers)?;
+
+    let content_hash = unpacked.finish()?;
 
     rename(&temp_path, &unpacked_file_name)?;
 
     // Packing was successful. Disable RAII cleanup guard
     cleanup.set(false);
 
-    Ok(unpacked_file_name)
+    Ok((unpacked_file_name, content_hash))
+}
+
+/// Unpack a chunk into memory, without writing it to the directory of the
+/// unpacked chunks
+pub(super) fn unpack_chunk_in_memory(
+    digest: &Digest,
+    packed_root: &Path,
+    encryption_key: Option<&EncryptionKey>,
+    buffers: &BufferPool,
+) -> DenebResult<Vec<u8>> {
+    let (path_suffix, _) = digest_to_path(digest);
+    let mut packed = BufReader::new(File::open(packed_root.join(&path_suffix))?);
+    let mut unpacked = Vec::new();
+    unpack_body(digest, &mut packed, &mut unpacked, encryption_key, buffers)?;
+    Ok(unpacked)
+}
+
+/// Unpack a chunk whose packed contents were read into memory
+pub(super) fn unpack_packed(
+    digest: &Digest,
+    packed: &[u8],
+    encryption_key: Option<&EncryptionKey>,
+    buffers: &BufferPool,
+) -> DenebResult<Vec<u8>> {
+    let mut unpacked = Vec::new();
+    unpack_body(
+        digest,
+        &mut &packed[..],
+        &mut unpacked,
+        encryption_key,
+        buffers,
+    )?;
+    Ok(unpacked)
+}
+
+// Write the contents of the packed chunk `packed` into `unpacked`
+fn unpack_body(
+    digest: &Digest,
+    packed: &mut impl BufRead,
+    unpacked: &mut dyn Write,
+    encryption_key: Option<&EncryptionKey>,
+    buffers: &BufferPool,
+) -> DenebResult<()> {
+    if packed.fill_buf().context(ChunkIOError)?.first() == Some(&VERSION_MARKER) {
+        let mut version = [0; 2];
+        packed.read_exact(&mut version).context(ChunkIOError)?;
+        if version[1] > CHUNK_VERSION {
+            return Err(StoreError::ChunkVersion(version[1], digest.to_string()).into());
+        }
+    }
+    let marker = packed.fill_buf().context(ChunkIOError)?.first().cloned();
+    if marker == Some(RANGED_MARKER) {
+        packed.consume(1);
+        let header = bincode::deserialize_from::<_, RangedHeader>(&mut *packed)?;
+        let key = encryption_key.ok_or(MissingKeyError)?;
+        let header = StreamHeader {
+            compressed: false,
+            nonce: Some(header.nonce),
+            block_size: header.block_size,
+        };
+        read_blocks(packed, unpacked, &header, Some(key), buffers)?;
+    } else if marker == Some(STREAM_MARKER) {
+        packed.consume(1);
+        let header = bincode::deserialize_from::<_, StreamHeader>(&mut *packed)?;
+        let key = match header.nonce {
+            Some(_) => Some(encryption_key.ok_or(MissingKeyError)?),
+            None => None,
+        };
+        if header.compressed {
+            let mut src = snap::Reader::new(packed);
+            read_blocks(&mut src, unpacked, &header, key, buffers)?;
+        } else {
+            read_blocks(packed, unpacked, &header, key, buffers)?;
+        }
+    } else {
+        let header = bincode::deserialize_from::<_, Header>(&mut *packed)?;
+
+        let mut buffer = buffers.take();
+        if header.compressed {
+            copy_body(&mut snap::Reader::new(packed), &mut *buffer)?;
+        } else {
+            copy_body(packed, &mut *buffer)?;
+        }
+
+        if let Some(nonce) = header.nonce {
+            let key = encryption_key.ok_or(MissingKeyError)?;
+            let body = decrypt(&buffer, &nonce, &key)?;
+            unpacked.write_all(&body).context(ChunkIOError)?;
+        } else {
+            unpacked.write_all(&buffer).context(ChunkIOError)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the contents of a chunk in blocks of `block_size` bytes, optionally
+/// encrypting each block before writing it. Returns the number of bytes read.
+fn write_blocks(
+    data: &mut dyn Read,
+    dst: &mut dyn Write,
+    mut nonce: Option<Nonce>,
+    encryption_key: Option<&EncryptionKey>,
+    block_size: usize,
+    buffers: &BufferPool,
+) -> DenebResult<usize> {
+    let mut buffer = buffers.take();
+    buffer.resize(block_size, 0);
+    let mut size = 0;
+    loop {
+        let n = read_block(data, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        size += n;
+        if let (Some(nonce), Some(key)) = (nonce.as_mut(), encryption_key) {
+            dst.write_all(&encrypt(&buffer[..n], nonce, key))
+                .context(ChunkIOError)?;
+            nonce.increment();
+        } else {
+            dst.write_all(&buffer[..n]).context(ChunkIOError)?;
+        }
+        if n < buffer.len() {
+            break;
+        }
+    }
+    Ok(size)
+}
+
+/// Read the body of a streamed chunk in blocks, optionally decrypting each
+/// block before writing it
+fn read_blocks(
+    src: &mut dyn Read,
+    dst: &mut dyn Write,
+    header: &StreamHeader,
+    encryption_key: Option<&EncryptionKey>,
+    buffers: &BufferPool,
+) -> DenebResult<()> {
+    let mut nonce = header.nonce.clone();
+    let block_size = match nonce {
+        Some(_) => header.block_size as usize + MAC_SIZE,
+        None => header.block_size as usize,
+    };
+    let mut buffer = buffers.take();
+    buffer.resize(block_size, 0);
+    loop {
+        let n = read_block(src, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        if let (Some(nonce), Some(key)) = (nonce.as_mut(), encryption_key) {
+            let block = decrypt(&buffer[..n], nonce, key)?;
+            dst.write_all(&block).context(ChunkIOError)?;
+            nonce.increment();
+        } else {
+            dst.write_all(&buffer[..n]).context(ChunkIOError)?;
+        }
+        if n < buffer.len() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Read a range of the contents of a packed chunk, without unpacking it
+///
+/// Returns the number of bytes read into `buf`, starting at `offset`, or
+/// `None` if the chunk doesn't support ranged access, i.e. it is compressed
+/// or encrypted as a whole.
+pub(super) fn read_packed_range(
+    packed_root: &Path,
+    digest: &Digest,
+    encryption_key: Option<&EncryptionKey>,
+    offset: u64,
+    buf: &mut [u8],
+    buffers: &BufferPool,
+) -> DenebResult<Option<usize>> {
+    let (path_suffix, _) = digest_to_path(digest);
+    let packed = File::open(packed_root.join(&path_suffix))?;
+    let mut prefix = [0; MAX_PREFIX_SIZE];
+    let prefix_len = read_block_at(&packed, &mut prefix, 0)?;
+    let mut rest = &prefix[..prefix_len];
+
+    if rest.first() == Some(&VERSION_MARKER) {
+        if rest.len() < 2 {
+            return Err(ChunkIOError.into());
+        }
+        if rest[1] > CHUNK_VERSION {
+            return Err(StoreError::ChunkVersion(rest[1], digest.to_string()).into());
+        }
+        rest = &rest[2..];
+    }
+    match rest.first() {
+        Some(&RANGED_MARKER) => {
+            rest = &rest[1..];
+            let header = bincode::deserialize_from::<_, RangedHeader>(&mut rest)?;
+            let key = encryption_key.ok_or(MissingKeyError)?;
+            let body_start = (prefix_len - rest.len()) as u64;
+            read_ranged(&packed, body_start, &header, key, offset, buf, buffers).map(Some)
+        }
+        Some(&STREAM_MARKER) => Ok(None),
+        _ => {
+            let header = bincode::deserialize_from::<_, Header>(&mut rest)?;
+            if header.compressed || header.nonce.is_some() {
+                return Ok(None);
+            }
+            let body_start = (prefix_len - rest.len()) as u64;
+            read_block_at(&packed, buf, body_start + offset).map(Some)
+        }
+    }
+}
+
+// Decrypt the blocks of a ranged chunk holding the contents at `offset`
+fn read_ranged(
+    packed: &File,
+    body_start: u64,
+    header: &RangedHeader,
+    key: &EncryptionKey,
+    offset: u64,
+    buf: &mut [u8],
+    buffers: &BufferPool,
+) -> DenebResult<usize> {
+    let block_size = u64::from(header.block_size);
+    let sealed_size = header.block_size as usize + MAC_SIZE;
+    let len = min(buf.len() as u64, header.size.saturating_sub(offset)) as usize;
+    let mut sealed = buffers.take();
+    sealed.resize(sealed_size, 0);
+
+    let mut done = 0;
+    while done < len {
+        let position = offset + done as u64;
+        let block = position / block_size;
+        let n = read_block_at(packed, &mut sealed, body_start + block * sealed_size as u64)?;
+        let mut nonce = header.nonce.clone();
+        for _ in 0..block {
+            nonce.increment();
+        }
+        let plain = decrypt(&sealed[..n], &nonce, key)?;
+        let start = (position - block * block_size) as usize;
+        if start >= plain.len() {
+            return Err(ChunkIOError.into());
+        }
+        let count = min(len - done, plain.len() - start);
+        buf[done..done + count].copy_from_slice(&plain[start..start + count]);
+        done += count;
+    }
+    Ok(done)
+}
+
+/// Fill the buffer from `position` in a file, stopping early only at its end
+fn read_block_at(file: &File, buffer: &mut [u8], position: u64) -> DenebResult<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read_at(&mut buffer[filled..], position + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.context(ChunkIOError).into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// Fill the buffer from the source, stopping early only at the end of the input
+fn read_block(src: &mut dyn Read, buffer: &mut [u8]) -> DenebResult<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match src.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.context(ChunkIOError).into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// Returns the digests of all the packed chunks in the data area
+pub(super) fn packed_digests(packed_root: &Path) -> DenebResult<Vec<Digest>> {
+    let mut digests = Vec::new();
+    for prefix1 in read_dir(packed_root)? {
+        let prefix1 = prefix1?;
+        if !prefix1.file_type()?.is_dir() {
+            continue;
+        }
+        for prefix2 in read_dir(prefix1.path())? {
+            let prefix2 = prefix2?;
+            if !prefix2.file_type()?.is_dir() {
+                continue;
+            }
+            for file in read_dir(prefix2.path())? {
+                let name = format!(
+                    "{}{}{}",
+                    prefix1.file_name().to_string_lossy(),
+                    prefix2.file_name().to_string_lossy(),
+                    file?.file_name().to_string_lossy()
+                );
+                if let Ok(digest) = digest_from_slice(name.as_bytes()) {
+                    digests.push(digest);
+                }
+            }
+        }
+    }
+    Ok(digests)
+}
+
+/// Remove a packed chunk from the data area
+pub(super) fn remove_packed(packed_root: &Path, digest: &Digest) -> DenebResult<()> {
+    let (path_suffix, _) = digest_to_path(digest);
+    remove_file(packed_root.join(path_suffix))?;
+    trace!("Chunk removed: {:?}", digest);
+    Ok(())
+}
+
+/// Returns the path of the packed chunk in the data area
+pub(super) fn chunk_path(packed_root: &Path, digest: &Digest) -> PathBuf {
+    packed_root.join(digest_to_path(digest).0)
+}
+
+/// Returns the directory of the data area in which the packed chunk is stored
+pub(super) fn chunk_dir(packed_root: &Path, digest: &Digest) -> PathBuf {
+    packed_root.join(digest_to_path(digest).1)
 }
 
 /// Given a Digest, returns the absolute file path and the directory path
@@ -198,13 +777,23 @@ mod tests {
         create_dir_all(&packed_root)?;
         create_dir_all(&unpacked_root)?;
         create_dir_all(&scratch_root)?;
+        let buffers = BufferPool::new(TEST_CHUNK_SIZE);
 
         let mut data = vec![0 as u8; TEST_CHUNK_SIZE];
         thread_rng().fill_bytes(data.as_mut());
         let hash_in = hash(&data);
 
-        let digest = pack_chunk(&data, &packed_root, &scratch_root, false, None)?;
-        let unpacked = unpack_chunk(&digest, &packed_root, &unpacked_root, None)?;
+        let digest = pack_chunk(
+            &data,
+            &packed_root,
+            &scratch_root,
+            false,
+            None,
+            Algorithm::Sha512,
+            &buffers,
+        )?;
+        let (unpacked, content_hash) =
+            unpack_chunk(&digest, &packed_root, &unpacked_root, None, &buffers)?;
 
         let mut f = File::open(unpacked)?;
         let mut read_back = vec![];
@@ -213,6 +802,7 @@ mod tests {
         let hash_out = hash(&read_back);
 
         assert_eq!(hash_in, hash_out);
+        assert_eq!(hash_in, content_hash);
 
         Ok(())
     }
@@ -226,6 +816,7 @@ mod tests {
         create_dir_all(&packed_root)?;
         create_dir_all(&unpacked_root)?;
         create_dir_all(&scratch_root)?;
+        let buffers = BufferPool::new(TEST_CHUNK_SIZE);
 
         let mut data = vec![0 as u8; TEST_CHUNK_SIZE];
         thread_rng().fill_bytes(data.as_mut());
@@ -233,8 +824,22 @@ mod tests {
 
         let key = Some(EncryptionKey::new());
 
-        let digest = pack_chunk(&data, &packed_root, &scratch_root, false, key.as_ref())?;
-        let unpacked = unpack_chunk(&digest, &packed_root, &unpacked_root, key.as_ref())?;
+        let digest = pack_chunk(
+            &data,
+            &packed_root,
+            &scratch_root,
+            false,
+            key.as_ref(),
+            Algorithm::Sha512,
+            &buffers,
+        )?;
+        let (unpacked, content_hash) = unpack_chunk(
+            &digest,
+            &packed_root,
+            &unpacked_root,
+            key.as_ref(),
+            &buffers,
+        )?;
 
         let mut f = File::open(unpacked)?;
         let mut read_back = vec![];
@@ -243,6 +848,7 @@ mod tests {
         let hash_out = hash(&read_back);
 
         assert_eq!(hash_in, hash_out);
+        assert_eq!(hash_in, content_hash);
 
         Ok(())
     }
@@ -256,13 +862,23 @@ mod tests {
         create_dir_all(&packed_root)?;
         create_dir_all(&unpacked_root)?;
         create_dir_all(&scratch_root)?;
+        let buffers = BufferPool::new(TEST_CHUNK_SIZE);
 
         let mut data = vec![0 as u8; TEST_CHUNK_SIZE];
         thread_rng().fill_bytes(data.as_mut());
         let hash_in = hash(&data);
 
-        let digest = pack_chunk(&data, &packed_root, &scratch_root, true, None)?;
-        let unpacked = unpack_chunk(&digest, &packed_root, &unpacked_root, None)?;
+        let digest = pack_chunk(
+            &data,
+            &packed_root,
+            &scratch_root,
+            true,
+            None,
+            Algorithm::Sha512,
+            &buffers,
+        )?;
+        let (unpacked, content_hash) =
+            unpack_chunk(&digest, &packed_root, &unpacked_root, None, &buffers)?;
 
         let mut f = File::open(unpacked)?;
         let mut read_back = vec![];
@@ -271,6 +887,7 @@ mod tests {
         let hash_out = hash(&read_back);
 
         assert_eq!(hash_in, hash_out);
+        assert_eq!(hash_in, content_hash);
 
         Ok(())
     }
@@ -284,6 +901,7 @@ mod tests {
         create_dir_all(&packed_root)?;
         create_dir_all(&unpacked_root)?;
         create_dir_all(&scratch_root)?;
+        let buffers = BufferPool::new(TEST_CHUNK_SIZE);
 
         let mut data = vec![0 as u8; TEST_CHUNK_SIZE];
         thread_rng().fill_bytes(data.as_mut());
@@ -291,8 +909,22 @@ mod tests {
 
         let key = Some(EncryptionKey::new());
 
-        let digest = pack_chunk(&data, &packed_root, &scratch_root, true, key.as_ref())?;
-        let unpacked = unpack_chunk(&digest, &packed_root, &unpacked_root, key.as_ref())?;
+        let digest = pack_chunk(
+            &data,
+            &packed_root,
+            &scratch_root,
+            true,
+            key.as_ref(),
+            Algorithm::Sha512,
+            &buffers,
+        )?;
+        let (unpacked, content_hash) = unpack_chunk(
+            &digest,
+            &packed_root,
+            &unpacked_root,
+            key.as_ref(),
+            &buffers,
+        )?;
 
         let mut f = File::open(unpacked)?;
         let mut read_back = vec![];
@@ -301,6 +933,247 @@ mod tests {
         let hash_out = hash(&read_back);
 
         assert_eq!(hash_in, hash_out);
+        assert_eq!(hash_in, content_hash);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pack_unpack_stream() -> DenebResult<()> {
+        let tmp = TempDir::new("chunk_packing_stream")?;
+        let packed_root = tmp.path().join("packed");
+        let unpacked_root = tmp.path().join("unpacked");
+        let scratch_root = tmp.path().join("scratch");
+        create_dir_all(&packed_root)?;
+        create_dir_all(&unpacked_root)?;
+        create_dir_all(&scratch_root)?;
+        let buffers = BufferPool::new(TEST_CHUNK_SIZE);
+
+        let key = EncryptionKey::new();
+        for &size in &[0, STREAM_BLOCK_SIZE, 5 * STREAM_BLOCK_SIZE / 2] {
+            let mut data = vec![0 as u8; size];
+            thread_rng().fill_bytes(data.as_mut());
+            let hash_in = hash(&data);
+
+            for &compressed in &[false, true] {
+                for &encryption_key in &[None, Some(&key)] {
+                    let (digest, size_out) = pack_stream(
+                        &mut data.as_slice(),
+                        &packed_root,
+                        &scratch_root,
+                        compressed,
+                        encryption_key,
+                        Algorithm::Sha512,
+                        &buffers,
+                    )?;
+                    assert_eq!(size, size_out);
+
+                    let (unpacked, content_hash) = unpack_chunk(
+                        &digest,
+                        &packed_root,
+                        &unpacked_root,
+                        encryption_key,
+                        &buffers,
+                    )?;
+
+                    let mut f = File::open(unpacked)?;
+                    let mut read_back = vec![];
+                    f.read_to_end(read_back.as_mut())?;
+
+                    assert_eq!(hash_in, hash(&read_back));
+                    assert_eq!(hash_in, content_hash);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn pack_unpack_blake3() -> DenebResult<()> {
+        let tmp = TempDir::new("chunk_packing_blake3")?;
+        let packed_root = tmp.path().join("packed");
+        let unpacked_root = tmp.path().join("unpacked");
+        let scratch_root = tmp.path().join("scratch");
+        create_dir_all(&packed_root)?;
+        create_dir_all(&unpacked_root)?;
+        create_dir_all(&scratch_root)?;
+        let buffers = BufferPool::new(TEST_CHUNK_SIZE);
+
+        let mut data = vec![0 as u8; TEST_CHUNK_SIZE];
+        thread_rng().fill_bytes(data.as_mut());
+        let key = EncryptionKey::new();
+
+        let digest = pack_chunk(
+            &data,
+            &packed_root,
+            &scratch_root,
+            true,
+            Some(&key),
+            Algorithm::Blake3,
+            &buffers,
+        )?;
+        assert_eq!(digest.algorithm(), Algorithm::Blake3);
+        assert!(digest.matches(&read_packed(&packed_root, &digest)?));
+        let (_, content_hash) =
+            unpack_chunk(&digest, &packed_root, &unpacked_root, Some(&key), &buffers)?;
+        assert_eq!(content_hash, hash(&data));
+
+        // Chunks of either algorithm can be copied between stores
+        let packed = read_packed(&packed_root, &digest)?;
+        let other_root = tmp.path().join("other");
+        create_dir_all(&other_root)?;
+        write_packed(&packed, &digest, &other_root, &scratch_root)?;
+        let mismatched = Algorithm::Blake3.hash(&data);
+        assert!(write_packed(&packed, &mismatched, &other_root, &scratch_root).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unpack_stream_requires_key() -> DenebResult<()> {
+        let tmp = TempDir::new("chunk_packing_stream_key")?;
+        let packed_root = tmp.path().join("packed");
+        let unpacked_root = tmp.path().join("unpacked");
+        let scratch_root = tmp.path().join("scratch");
+        create_dir_all(&packed_root)?;
+        create_dir_all(&unpacked_root)?;
+        create_dir_all(&scratch_root)?;
+        let buffers = BufferPool::new(TEST_CHUNK_SIZE);
+
+        let data = vec![1 as u8; TEST_CHUNK_SIZE];
+        let key = EncryptionKey::new();
+        let (digest, _) = pack_stream(
+            &mut data.as_slice(),
+            &packed_root,
+            &scratch_root,
+            false,
+            Some(&key),
+            Algorithm::Sha512,
+            &buffers,
+        )?;
+
+        assert!(unpack_chunk(&digest, &packed_root, &unpacked_root, None, &buffers).is_err());
+        let wrong_key = EncryptionKey::new();
+        assert!(unpack_chunk(
+            &digest,
+            &packed_root,
+            &unpacked_root,
+            Some(&wrong_key),
+            &buffers
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unpack_versioned_chunks() -> DenebResult<()> {
+        let tmp = TempDir::new("chunk_packing_versions")?;
+        let packed_root = tmp.path().join("packed");
+        let unpacked_root = tmp.path().join("unpacked");
+        let scratch_root = tmp.path().join("scratch");
+        create_dir_all(&packed_root)?;
+        create_dir_all(&unpacked_root)?;
+        create_dir_all(&scratch_root)?;
+        let buffers = BufferPool::new(TEST_CHUNK_SIZE);
+
+        let data = b"alabalaportocala";
+        let header = Header {
+            compressed: false,
+            nonce: None,
+        };
+
+        // Chunks written before the version byte was introduced
+        let mut legacy = bincode::serialize(&header)?;
+        legacy.extend_from_slice(data);
+        let digest = hash(&legacy);
+        write_packed(&legacy, &digest, &packed_root, &scratch_root)?;
+        let (_, content_hash) =
+            unpack_chunk(&digest, &packed_root, &unpacked_root, None, &buffers)?;
+        assert_eq!(content_hash, hash(data));
+
+        // Chunks written by a newer version
+        let mut newer = vec![VERSION_MARKER, CHUNK_VERSION + 1];
+        newer.extend_from_slice(&legacy);
+        let digest = hash(&newer);
+        write_packed(&newer, &digest, &packed_root, &scratch_root)?;
+        assert!(unpack_chunk(&digest, &packed_root, &unpacked_root, None, &buffers).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_ranges_of_packed_chunks() -> DenebResult<()> {
+        let tmp = TempDir::new("chunk_packing_ranges")?;
+        let packed_root = tmp.path().join("packed");
+        let unpacked_root = tmp.path().join("unpacked");
+        let scratch_root = tmp.path().join("scratch");
+        create_dir_all(&packed_root)?;
+        create_dir_all(&unpacked_root)?;
+        create_dir_all(&scratch_root)?;
+        let buffers = BufferPool::new(TEST_CHUNK_SIZE);
+
+        let size = 3 * RANGED_BLOCK_SIZE + 100;
+        let mut data = vec![0 as u8; size];
+        thread_rng().fill_bytes(data.as_mut());
+        let key = EncryptionKey::new();
+
+        for &encryption_key in &[None, Some(&key)] {
+            let digest = pack_chunk(
+                &data,
+                &packed_root,
+                &scratch_root,
+                false,
+                encryption_key,
+                Algorithm::Sha512,
+                &buffers,
+            )?;
+            let (_, content_hash) = unpack_chunk(
+                &digest,
+                &packed_root,
+                &unpacked_root,
+                encryption_key,
+                &buffers,
+            )?;
+            assert_eq!(content_hash, hash(&data));
+
+            // Ranges within a block, across blocks and past the end
+            for &(offset, len) in &[
+                (0, 1),
+                (RANGED_BLOCK_SIZE - 10, 20),
+                (10, 2 * RANGED_BLOCK_SIZE),
+                (size - 50, 100),
+                (size + 1, 10),
+            ] {
+                let mut buf = vec![0; len];
+                let n = read_packed_range(
+                    &packed_root,
+                    &digest,
+                    encryption_key,
+                    offset as u64,
+                    &mut buf,
+                    &buffers,
+                )?
+                .unwrap();
+                let expected = &data[min(offset, size)..min(offset + len, size)];
+                assert_eq!(&buf[..n], expected);
+            }
+        }
+
+        // Compressed chunks are only read whole
+        let digest = pack_chunk(
+            &data,
+            &packed_root,
+            &scratch_root,
+            true,
+            Some(&key),
+            Algorithm::Sha512,
+            &buffers,
+        )?;
+        let mut buf = [0; 10];
+        assert!(
+            read_packed_range(&packed_root, &digest, Some(&key), 0, &mut buf, &buffers)?.is_none()
+        );
 
         Ok(())
     }
@@ -0,0 +1,103 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+/// Number of idle buffers kept by a pool
+const MAX_IDLE_BUFFERS: usize = 4;
+
+/// A pool of reusable buffers for packing and unpacking chunks
+///
+/// Buffers taken from the pool are empty and have room for at least
+/// `buffer_size` bytes. They are given back to the pool when dropped, unless
+/// they grew much larger than `buffer_size` or enough idle buffers are kept
+/// already, so that packing one chunk after another doesn't allocate a new
+/// buffer each time.
+pub(super) struct BufferPool {
+    buffer_size: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub(super) fn new(buffer_size: usize) -> BufferPool {
+        BufferPool {
+            buffer_size,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(super) fn take(&self) -> PooledBuffer {
+        let buffer = self
+            .idle
+            .lock()
+            .ok()
+            .and_then(|mut idle| idle.pop())
+            .unwrap_or_else(|| Vec::with_capacity(self.buffer_size));
+        PooledBuffer { buffer, pool: self }
+    }
+
+    fn give_back(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > 2 * self.buffer_size {
+            return;
+        }
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < MAX_IDLE_BUFFERS {
+                buffer.clear();
+                idle.push(buffer);
+            }
+        }
+    }
+}
+
+/// A buffer which returns to its pool when dropped
+pub(super) struct PooledBuffer<'a> {
+    buffer: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl<'a> Deref for PooledBuffer<'a> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl<'a> DerefMut for PooledBuffer<'a> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl<'a> Drop for PooledBuffer<'a> {
+    fn drop(&mut self) {
+        self.pool
+            .give_back(std::mem::replace(&mut self.buffer, Vec::new()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused() {
+        let pool = BufferPool::new(16);
+
+        let address = {
+            let mut buffer = pool.take();
+            assert!(buffer.capacity() >= 16);
+            buffer.extend_from_slice(b"alabalaportocala");
+            buffer.as_ptr()
+        };
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), address);
+
+        // Buffers which grew too much are not kept
+        let mut other = pool.take();
+        other.resize(64, 0);
+        drop(other);
+        assert!(pool.idle.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+use {
+    super::{
+        pack::{packed_digests, read_packed},
+        OBJECT_PATH,
+    },
+    crate::{
+        errors::{DenebResult, StoreError},
+        util::sync_dir,
+    },
+    log::info,
+    std::{
+        env::current_dir,
+        ffi::OsString,
+        fs::{
+            copy, create_dir_all, read_dir, read_link, remove_dir_all, remove_file, rename,
+            symlink_metadata, File,
+        },
+        io::ErrorKind,
+        os::unix::fs::symlink,
+        path::{Path, PathBuf},
+    },
+};
+
+/// Link to the new location of the data area, before it replaces the old one
+const RELOCATED_PATH: &str = "data.relocated";
+/// The old data area, once it's been replaced
+const REPLACED_PATH: &str = "data.replaced";
+
+/// Move the data area of the store in `root_dir` to `new_dir`
+///
+/// The data area is copied into a staging directory next to `new_dir`, the
+/// copied chunks are verified against their digests and the staging directory
+/// is renamed to `new_dir`. The data area is then replaced by a link to
+/// `new_dir`, so the references to the store in the configuration and to the
+/// manifest, which is kept in the data area, stay valid. An interrupted
+/// relocation leaves the store either at its old location or at the new one,
+/// and is completed by `finish_relocation`. Returns the number of chunks moved.
+pub(super) fn relocate(root_dir: &Path, new_dir: &Path) -> DenebResult<usize> {
+    finish_relocation(root_dir)?;
+
+    let object_dir = root_dir.join(OBJECT_PATH);
+    let source = object_dir.canonicalize()?;
+    let new_dir = current_dir()?.join(new_dir);
+    if symlink_metadata(&new_dir).is_ok() {
+        return Err(StoreError::Relocation(format!("{:?} already exists", new_dir)).into());
+    }
+    if new_dir.starts_with(&source) {
+        return Err(StoreError::Relocation(format!("{:?} is inside the store", new_dir)).into());
+    }
+
+    // Left by an interrupted relocation, which never reached the swap
+    let staging = staging_dir(&new_dir)?;
+    if symlink_metadata(&staging).is_ok() {
+        remove_dir_all(&staging)?;
+    }
+    create_dir_all(&staging)?;
+    copy_dir(&source, &staging)?;
+
+    let digests = packed_digests(&source)?;
+    let copied = packed_digests(&staging)?;
+    if copied.len() != digests.len() {
+        return Err(StoreError::Relocation(format!(
+            "{} of {} chunks copied",
+            copied.len(),
+            digests.len()
+        ))
+        .into());
+    }
+    for digest in &copied {
+        if !digest.matches(&read_packed(&staging, digest)?) {
+            return Err(StoreError::CorruptChunk(digest.to_string()).into());
+        }
+    }
+
+    rename(&staging, &new_dir)?;
+    if let Some(parent) = new_dir.parent() {
+        sync_dir(parent)?;
+    }
+
+    // The swap: after the first rename the data area is only reachable
+    // through the link, which `finish_relocation` moves into place
+    symlink(&new_dir, root_dir.join(RELOCATED_PATH))?;
+    sync_dir(root_dir)?;
+    rename(&object_dir, root_dir.join(REPLACED_PATH))?;
+    finish_relocation(root_dir)?;
+
+    info!(
+        "Data area {:?} moved to {:?}: {} chunks",
+        source,
+        new_dir,
+        copied.len()
+    );
+
+    Ok(copied.len())
+}
+
+/// Complete a relocation of the data area of the store in `root_dir` which
+/// was interrupted
+///
+/// If the data area hadn't been replaced yet, the relocation is abandoned and
+/// the store stays at its old location.
+pub(super) fn finish_relocation(root_dir: &Path) -> DenebResult<()> {
+    let object_dir = root_dir.join(OBJECT_PATH);
+    let relocated = root_dir.join(RELOCATED_PATH);
+    let replaced = root_dir.join(REPLACED_PATH);
+
+    if symlink_metadata(&relocated).is_ok() {
+        if symlink_metadata(&object_dir).is_ok() {
+            remove_file(&relocated)?;
+        } else {
+            rename(&relocated, &object_dir)?;
+        }
+        sync_dir(root_dir)?;
+    }
+
+    if let Ok(meta) = symlink_metadata(&replaced) {
+        // The data area was already moved once, its previous copy is removed
+        // along with the link to it
+        if meta.file_type().is_symlink() {
+            match remove_dir_all(read_link(&replaced)?) {
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                r => r?,
+            }
+            remove_file(&replaced)?;
+        } else {
+            remove_dir_all(&replaced)?;
+        }
+        sync_dir(root_dir)?;
+    }
+
+    Ok(())
+}
+
+fn staging_dir(new_dir: &Path) -> DenebResult<PathBuf> {
+    let mut name = new_dir
+        .file_name()
+        .map(OsString::from)
+        .ok_or_else(|| StoreError::Relocation(format!("{:?} has no name", new_dir)))?;
+    name.push(".partial");
+    Ok(new_dir.with_file_name(name))
+}
+
+// Copy the files of `from` into `to`, syncing each of them
+fn copy_dir(from: &Path, to: &Path) -> DenebResult<()> {
+    for entry in read_dir(from)? {
+        let entry = entry?;
+        let dest = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            create_dir_all(&dest)?;
+            copy_dir(&entry.path(), &dest)?;
+        } else {
+            copy(entry.path(), &dest)?;
+            File::open(&dest)?.sync_all()?;
+        }
+    }
+    sync_dir(to)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{super::DiskStore, *},
+        crate::store::Store,
+        std::fs::write,
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn data_areas_are_moved_and_interrupted_moves_completed() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_store_relocate")?;
+        let root_dir = tmp.path().join("work");
+        let mut store = DiskStore::try_new(&root_dir, None, 16, 0)?;
+        let digest = store.put_chunk(b"alabalaportocala")?.digest;
+        store.sync()?;
+        drop(store);
+
+        let first = tmp.path().join("disk1/data");
+        assert_eq!(relocate(&root_dir, &first)?, 1);
+        assert_eq!(read_link(root_dir.join(OBJECT_PATH))?, first);
+        assert!(relocate(&root_dir, &first).is_err());
+
+        // Moving it again removes the previous copy, and what was left of an
+        // interrupted copy
+        let second = tmp.path().join("disk2");
+        create_dir_all(tmp.path().join("disk2.partial"))?;
+        write(tmp.path().join("disk2.partial/format"), "0")?;
+        assert_eq!(relocate(&root_dir, &second)?, 1);
+        assert!(!first.exists());
+        assert!(!tmp.path().join("disk2.partial").exists());
+
+        // Interrupted between the renames of the swap
+        let third = tmp.path().join("disk3");
+        create_dir_all(&third)?;
+        copy_dir(&second, &third)?;
+        symlink(&third, root_dir.join(RELOCATED_PATH))?;
+        rename(root_dir.join(OBJECT_PATH), root_dir.join(REPLACED_PATH))?;
+
+        let store = DiskStore::try_new(&root_dir, None, 16, 0)?;
+        assert_eq!(read_link(root_dir.join(OBJECT_PATH))?, third);
+        assert!(!second.exists());
+        let mut buf = [0; 16];
+        store.chunk(&digest)?.read_at(&mut buf, 0)?;
+        assert_eq!(&buf, b"alabalaportocala");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use {
+    super::{mem::MemStore, Chunk, Store},
+    crate::{
+        cas::{Algorithm, Digest},
+        crypt::EncryptionKey,
+        errors::DenebResult,
+        inode::ChunkDescriptor,
+        util::atomic_write,
+    },
+    bincode::{deserialize, serialize},
+    log::info,
+    std::{
+        collections::HashMap,
+        fs::{create_dir_all, read_dir, remove_file, File},
+        io::Read,
+        path::{Path, PathBuf},
+        sync::Arc,
+    },
+};
+
+const DUMP_PATH: &str = "ephemeral";
+const OBJECTS_PATH: &str = "objects";
+const SPECIAL_FILES_NAME: &str = "special_files";
+
+/// An in-memory implementation of the `Store` trait which can be saved to disk
+///
+/// All chunks and special files are held in memory, as in the `MemStore`. When
+/// `persist` is called, the contents of the store are dumped into the
+/// `root_dir`/ephemeral directory, from where they are loaded back the next time
+/// the store is opened. This allows tests and benchmarks to avoid disk IO, while
+/// still being able to restart an engine on the same repository.
+pub(super) struct EphemeralStore {
+    inner: MemStore,
+    dump_dir: PathBuf,
+}
+
+impl EphemeralStore {
+    pub(super) fn try_new(
+        dir: &Path,
+        encryption_key: Option<EncryptionKey>,
+        chunk_size: usize,
+    ) -> DenebResult<EphemeralStore> {
+        let mut store = EphemeralStore {
+            inner: MemStore::new(encryption_key, chunk_size),
+            dump_dir: dir.join(DUMP_PATH),
+        };
+        if store.dump_dir.exists() {
+            store.load()?;
+        }
+        Ok(store)
+    }
+
+    fn load(&mut self) -> DenebResult<()> {
+        let mut num_objects = 0;
+        for entry in read_dir(self.dump_dir.join(OBJECTS_PATH))? {
+            let body = read_file(&entry?.path())?;
+            self.inner.put_chunk(&body)?;
+            num_objects += 1;
+        }
+
+        let buf = read_file(&self.dump_dir.join(SPECIAL_FILES_NAME))?;
+        let special = deserialize::<HashMap<PathBuf, Vec<u8>>>(&buf)?;
+        for (name, body) in special {
+            self.inner
+                .write_special_file(&name, &mut body.as_slice(), false)?;
+        }
+
+        info!(
+            "Loaded {} objects into the ephemeral store from {:?}",
+            num_objects, self.dump_dir
+        );
+        Ok(())
+    }
+}
+
+impl Store for EphemeralStore {
+    fn chunk_size(&self) -> usize {
+        self.inner.chunk_size()
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        self.inner.algorithm()
+    }
+
+    fn set_algorithm(&mut self, algorithm: Algorithm) {
+        self.inner.set_algorithm(algorithm)
+    }
+
+    fn chunk(&self, digest: &Digest) -> DenebResult<Arc<dyn Chunk>> {
+        self.inner.chunk(digest)
+    }
+
+    fn put_chunk(&mut self, contents: &[u8]) -> DenebResult<ChunkDescriptor> {
+        self.inner.put_chunk(contents)
+    }
+
+    fn packed_chunk(&self, digest: &Digest) -> DenebResult<Vec<u8>> {
+        self.inner.packed_chunk(digest)
+    }
+
+    fn missing_chunks(&self, digests: &[Digest]) -> DenebResult<Vec<Digest>> {
+        self.inner.missing_chunks(digests)
+    }
+
+    fn put_packed_chunk(&mut self, digest: &Digest, packed: &[u8]) -> DenebResult<()> {
+        self.inner.put_packed_chunk(digest, packed)
+    }
+
+    fn chunk_digests(&self) -> DenebResult<Vec<Digest>> {
+        self.inner.chunk_digests()
+    }
+
+    fn remove_chunk(&mut self, digest: &Digest) -> DenebResult<()> {
+        self.inner.remove_chunk(digest)?;
+        let object_path = self.dump_dir.join(OBJECTS_PATH).join(digest.to_string());
+        if object_path.exists() {
+            remove_file(object_path)?;
+        }
+        Ok(())
+    }
+
+    fn read_special_file(&self, file_name: &Path) -> DenebResult<Vec<u8>> {
+        self.inner.read_special_file(file_name)
+    }
+
+    fn write_special_file(
+        &mut self,
+        file_name: &Path,
+        data: &mut dyn Read,
+        append: bool,
+    ) -> DenebResult<()> {
+        self.inner.write_special_file(file_name, data, append)
+    }
+
+    fn rename_special_file(&mut self, from: &Path, to: &Path) -> DenebResult<()> {
+        self.inner.rename_special_file(from, to)
+    }
+
+    fn remove_special_file(&mut self, file_name: &Path) -> DenebResult<()> {
+        self.inner.remove_special_file(file_name)
+    }
+
+    fn persist(&mut self) -> DenebResult<()> {
+        let objects_dir = self.dump_dir.join(OBJECTS_PATH);
+        create_dir_all(&objects_dir)?;
+
+        // Chunks are immutable, so only the new ones need to be written
+        for (digest, chunk) in self.inner.objects() {
+            let object_path = objects_dir.join(digest.to_string());
+            if !object_path.exists() {
+                let mut buf = vec![0; chunk.size()];
+                chunk.read_at(&mut buf, 0)?;
+                atomic_write(&object_path, &buf)?;
+            }
+        }
+
+        let special = serialize(self.inner.special_files())?;
+        atomic_write(&self.dump_dir.join(SPECIAL_FILES_NAME), &special)?;
+
+        info!("Ephemeral store saved to {:?}", self.dump_dir);
+        Ok(())
+    }
+}
+
+fn read_file(path: &Path) -> DenebResult<Vec<u8>> {
+    let mut f = File::open(path)?;
+    let mut body = Vec::new();
+    f.read_to_end(&mut body)?;
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn ephemeral_store_persist_and_reload() -> DenebResult<()> {
+        const BYTES: &[u8] = b"alabalaportocala";
+        let temp_dir = TempDir::new("/tmp/deneb_test_ephemeral")?;
+        let descriptors = {
+            let mut store = EphemeralStore::try_new(temp_dir.path(), None, 10000)?;
+            let mut v1: &[u8] = BYTES;
+            let descriptors = store.put_file_chunked(&mut v1)?;
+            store.write_special_file(Path::new("manifest"), &mut &b"m1"[..], false)?;
+            store.write_special_file(Path::new("reflog"), &mut &b"r1"[..], true)?;
+            store.persist()?;
+            descriptors
+        };
+
+        let store = EphemeralStore::try_new(temp_dir.path(), None, 10000)?;
+        let chunk = store.chunk(&descriptors[0].digest)?;
+        let mut buf = vec![0; chunk.size()];
+        chunk.read_at(&mut buf, 0)?;
+        assert_eq!(BYTES, buf.as_slice());
+        let manifest = store.read_special_file(Path::new("manifest"))?;
+        assert_eq!(b"m1", manifest.as_slice());
+        let reflog = store.read_special_file(Path::new("reflog"))?;
+        assert_eq!(b"r1", reflog.as_slice());
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use {
+    super::{Chunk, Store},
+    crate::{
+        cas::Digest,
+        errors::{DenebResult, StoreError},
+        gateway::{body_limit, decode_digests, encode_digests, read_body, read_head},
+        inode::ChunkDescriptor,
+    },
+    log::trace,
+    std::{
+        io::{BufReader, Read, Write},
+        net::{TcpStream, ToSocketAddrs},
+        path::Path,
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+/// A store whose chunks are served by the gateway of another instance
+///
+/// Only packed chunks can be transferred through the gateway, so this store
+/// is meant to be used as the remote store of a `TieredStore`. Packed chunks
+/// received from the gateway are verified against their digests, since the
+/// gateway may be fronted by a cache outside of our control.
+pub(super) struct HttpStore {
+    address: String,
+    token: Option<String>,
+    chunk_size: usize,
+    timeout: Duration,
+}
+
+impl HttpStore {
+    pub(super) fn new(
+        address: &str,
+        token: Option<String>,
+        chunk_size: usize,
+        timeout: Duration,
+    ) -> HttpStore {
+        HttpStore {
+            address: address.to_owned(),
+            token,
+            chunk_size,
+            timeout,
+        }
+    }
+
+    fn connect(&self) -> DenebResult<TcpStream> {
+        let mut last_error = None;
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(match last_error {
+            Some(e) => e.into(),
+            None => StoreError::Gateway(format!("could not resolve {}", self.address)).into(),
+        })
+    }
+
+    // Send a request to the gateway, returning the body of a successful reply
+    fn request(&self, method: &str, target: &str, body: &[u8]) -> DenebResult<Vec<u8>> {
+        let mut stream = self.connect()?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            method,
+            target,
+            self.address,
+            body.len()
+        )?;
+        if let Some(ref token) = self.token {
+            write!(stream, "Authorization: Bearer {}\r\n", token)?;
+        }
+        stream.write_all(b"\r\n")?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let (status_line, headers) = read_head(&mut reader)?;
+        trace!("Gateway reply to {} {}: {}", method, target, status_line);
+        if status_line.split_whitespace().nth(1) != Some("200") {
+            return Err(
+                StoreError::Gateway(format!("{} {}: {}", method, target, status_line)).into(),
+            );
+        }
+        read_body(&mut reader, &headers, body_limit(self.chunk_size))
+    }
+}
+
+impl Store for HttpStore {
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn chunk(&self, _digest: &Digest) -> DenebResult<Arc<dyn Chunk>> {
+        Err(StoreError::Unsupported("chunk").into())
+    }
+
+    fn put_chunk(&mut self, _contents: &[u8]) -> DenebResult<ChunkDescriptor> {
+        Err(StoreError::Unsupported("put_chunk").into())
+    }
+
+    fn packed_chunk(&self, digest: &Digest) -> DenebResult<Vec<u8>> {
+        let packed = self.request("GET", &format!("/chunk/{}", digest), &[])?;
+        if !digest.matches(&packed) {
+            return Err(StoreError::ChunkGet(digest.to_string()).into());
+        }
+        Ok(packed)
+    }
+
+    fn put_packed_chunk(&mut self, digest: &Digest, packed: &[u8]) -> DenebResult<()> {
+        self.request("PUT", &format!("/chunk/{}", digest), packed)?;
+        Ok(())
+    }
+
+    // Gateways which can't answer, e.g. older ones, are assumed to be missing
+    // all the chunks, which are then uploaded as before
+    fn missing_chunks(&self, digests: &[Digest]) -> DenebResult<Vec<Digest>> {
+        if digests.is_empty() {
+            return Ok(Vec::new());
+        }
+        match self.request("POST", "/chunks/missing", &encode_digests(digests)) {
+            Ok(reply) => decode_digests(&reply),
+            Err(e) => {
+                trace!("Lookup of {} chunks failed: {}", digests.len(), e);
+                Ok(digests.to_vec())
+            }
+        }
+    }
+
+    fn chunk_digests(&self) -> DenebResult<Vec<Digest>> {
+        Err(StoreError::Unsupported("chunk_digests").into())
+    }
+
+    fn remove_chunk(&mut self, _digest: &Digest) -> DenebResult<()> {
+        Err(StoreError::Unsupported("remove_chunk").into())
+    }
+
+    fn read_special_file(&self, _file_name: &Path) -> DenebResult<Vec<u8>> {
+        Err(StoreError::Unsupported("read_special_file").into())
+    }
+
+    fn write_special_file(
+        &mut self,
+        _file_name: &Path,
+        _data: &mut dyn Read,
+        _append: bool,
+    ) -> DenebResult<()> {
+        Err(StoreError::Unsupported("write_special_file").into())
+    }
+
+    fn rename_special_file(&mut self, _from: &Path, _to: &Path) -> DenebResult<()> {
+        Err(StoreError::Unsupported("rename_special_file").into())
+    }
+
+    fn remove_special_file(&mut self, _file_name: &Path) -> DenebResult<()> {
+        Err(StoreError::Unsupported("remove_special_file").into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{super::disk::DiskStore, *},
+        crate::{cas::hash, gateway::start_gateway, workspace::MANIFEST_PATH},
+        std::{fs::write, net::TcpListener},
+        tempdir::TempDir,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn chunks_are_transferred_through_the_gateway() -> DenebResult<()> {
+        let work_dir = TempDir::new("deneb_gateway")?;
+        let mut served = DiskStore::try_new(work_dir.path(), None, 16, 1024)?;
+        let digest = served.put_chunk(b"alabalaportocala")?.digest;
+        write(work_dir.path().join(MANIFEST_PATH), b"manifest")?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?.to_string();
+        start_gateway(
+            listener,
+            work_dir.path().to_owned(),
+            Some("secret".to_owned()),
+            16,
+        )?;
+
+        let mut store = HttpStore::new(&address, Some("secret".to_owned()), 16, TIMEOUT);
+        assert_eq!(store.packed_chunk(&digest)?, served.packed_chunk(&digest)?);
+        assert_eq!(
+            store.request("GET", "/manifest", &[])?,
+            b"manifest".to_vec()
+        );
+        assert!(store.packed_chunk(&hash(b"missing")).is_err());
+
+        // Chunks are only accepted if they match their digest
+        let other_dir = TempDir::new("deneb_gateway_other")?;
+        let mut other = DiskStore::try_new(other_dir.path(), None, 16, 1024)?;
+        let new_digest = other.put_chunk(b"40 de turcaleti")?.digest;
+        let packed = other.packed_chunk(&new_digest)?;
+        assert!(store.put_packed_chunk(&digest, &packed).is_err());
+        store.put_packed_chunk(&new_digest, &packed)?;
+        assert_eq!(served.packed_chunk(&new_digest)?, packed);
+
+        let missing = hash(b"missing");
+        assert_eq!(
+            store.missing_chunks(&[digest, missing, new_digest])?,
+            vec![missing]
+        );
+
+        let unauthorized = HttpStore::new(&address, Some("wrong".to_owned()), 16, TIMEOUT);
+        assert!(unauthorized.packed_chunk(&digest).is_err());
+
+        // Without a token, the gateway only serves chunks
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let open_address = listener.local_addr()?.to_string();
+        start_gateway(listener, work_dir.path().to_owned(), None, 16)?;
+        let mut open = HttpStore::new(&open_address, None, 16, TIMEOUT);
+        assert_eq!(open.packed_chunk(&digest)?, served.packed_chunk(&digest)?);
+        assert!(open.put_packed_chunk(&new_digest, &packed).is_err());
+        // Lookups are refused too, so all the chunks are assumed missing
+        assert_eq!(open.missing_chunks(&[digest])?, vec![digest]);
+
+        Ok(())
+    }
+}
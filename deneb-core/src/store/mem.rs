@@ -1,10 +1,11 @@
 use {
     super::{Chunk, MemChunk, Store},
     crate::{
-        cas::{hash, Digest},
+        cas::{Algorithm, Digest},
         crypt::EncryptionKey,
         errors::{DenebResult, StoreError},
         inode::ChunkDescriptor,
+        metrics::record_dedup_hit,
     },
     std::{
         collections::HashMap,
@@ -17,6 +18,7 @@ use {
 #[derive(Default)]
 pub(super) struct MemStore {
     chunk_size: usize,
+    algorithm: Algorithm,
     objects: HashMap<Digest, Arc<dyn Chunk>>,
     special: HashMap<PathBuf, Vec<u8>>,
 }
@@ -25,10 +27,28 @@ impl MemStore {
     pub(super) fn new(_encryption_key: Option<EncryptionKey>, chunk_size: usize) -> MemStore {
         MemStore {
             chunk_size,
+            algorithm: Algorithm::default(),
             objects: HashMap::new(),
             special: HashMap::new(),
         }
     }
+
+    pub(super) fn objects(&self) -> &HashMap<Digest, Arc<dyn Chunk>> {
+        &self.objects
+    }
+
+    pub(super) fn special_files(&self) -> &HashMap<PathBuf, Vec<u8>> {
+        &self.special
+    }
+
+    fn insert(&mut self, digest: Digest, contents: &[u8]) {
+        if self.objects.contains_key(&digest) {
+            record_dedup_hit();
+        } else {
+            self.objects
+                .insert(digest, Arc::new(MemChunk::new(contents)));
+        }
+    }
 }
 
 impl Store for MemStore {
@@ -36,6 +56,14 @@ impl Store for MemStore {
         self.chunk_size
     }
 
+    fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    fn set_algorithm(&mut self, algorithm: Algorithm) {
+        self.algorithm = algorithm;
+    }
+
     fn chunk(&self, digest: &Digest) -> DenebResult<Arc<dyn Chunk>> {
         self.objects
             .get(digest)
@@ -46,16 +74,49 @@ impl Store for MemStore {
     // Note: can this be improved by inserting chunks as the become available from
     //       read_chunks?
     fn put_chunk(&mut self, contents: &[u8]) -> DenebResult<ChunkDescriptor> {
-        let digest = hash(contents);
-        self.objects
-            .entry(digest)
-            .or_insert_with(|| Arc::new(MemChunk::new(contents)));
+        let digest = self.algorithm.hash(contents);
+        self.insert(digest, contents);
         Ok(ChunkDescriptor {
             digest,
             size: contents.len(),
         })
     }
 
+    // Chunks are held unpacked, their digest being the hash of their contents
+    fn packed_chunk(&self, digest: &Digest) -> DenebResult<Vec<u8>> {
+        let chunk = self.chunk(digest)?;
+        let mut packed = vec![0; chunk.size()];
+        chunk.read_at(&mut packed, 0)?;
+        Ok(packed)
+    }
+
+    fn put_packed_chunk(&mut self, digest: &Digest, packed: &[u8]) -> DenebResult<()> {
+        if !digest.matches(packed) {
+            return Err(StoreError::ChunkPut(digest.to_string()).into());
+        }
+        self.insert(*digest, packed);
+        Ok(())
+    }
+
+    fn missing_chunks(&self, digests: &[Digest]) -> DenebResult<Vec<Digest>> {
+        Ok(digests
+            .iter()
+            .filter(|digest| !self.objects.contains_key(digest))
+            .cloned()
+            .collect())
+    }
+
+    fn chunk_digests(&self) -> DenebResult<Vec<Digest>> {
+        Ok(self.objects.keys().cloned().collect())
+    }
+
+    fn remove_chunk(&mut self, digest: &Digest) -> DenebResult<()> {
+        self.objects
+            .remove(digest)
+            .map(|_| ())
+            .ok_or_else(|| StoreError::ChunkGet(digest.to_string()).into())
+    }
+
     fn read_special_file(&self, file_name: &Path) -> DenebResult<Vec<u8>> {
         self.special
             .get(&file_name.to_owned())
@@ -73,15 +134,31 @@ impl Store for MemStore {
         let mut body = Vec::new();
         data.read_to_end(&mut body)?;
         if append {
-            if let Some(mut existing) = self.special.get(&name).cloned() {
-                existing.append(&mut body);
-                self.special.insert(name, body);
-            }
+            self.special
+                .entry(name)
+                .or_insert_with(Vec::new)
+                .append(&mut body);
         } else {
             self.special.insert(name, body);
         }
         Ok(())
     }
+
+    fn rename_special_file(&mut self, from: &Path, to: &Path) -> DenebResult<()> {
+        let body = self
+            .special
+            .remove(from)
+            .ok_or_else(|| StoreError::FileGet(from.to_owned()))?;
+        self.special.insert(to.to_owned(), body);
+        Ok(())
+    }
+
+    fn remove_special_file(&mut self, file_name: &Path) -> DenebResult<()> {
+        self.special
+            .remove(file_name)
+            .map(|_| ())
+            .ok_or_else(|| StoreError::FileGet(file_name.to_owned()).into())
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,313 @@
+use {
+    super::{Chunk, Store},
+    crate::{
+        cas::{Algorithm, Digest},
+        errors::{DenebResult, StoreError},
+        inode::ChunkDescriptor,
+        metrics::{record_remote_failure, record_remote_retry, record_remote_transfer},
+    },
+    log::warn,
+    std::{
+        io::Read,
+        path::Path,
+        sync::{Arc, Condvar, Mutex},
+        thread::sleep,
+        time::{Duration, Instant},
+    },
+};
+
+/// Limits of the transfers between a tiered store and its remote store
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RemoteLimits {
+    /// Maximum download rate in bytes per second (0 means unlimited)
+    pub download_rate: u64,
+    /// Maximum upload rate in bytes per second (0 means unlimited)
+    pub upload_rate: u64,
+    /// Timeout of a single request to a remote gateway
+    pub timeout: Duration,
+    /// Number of times a failed transfer is retried
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled before each of the following ones
+    pub retry_delay: Duration,
+    /// Maximum number of concurrent transfers (0 means unlimited)
+    pub max_transfers: usize,
+}
+
+impl Default for RemoteLimits {
+    fn default() -> RemoteLimits {
+        RemoteLimits {
+            download_rate: 0,
+            upload_rate: 0,
+            timeout: Duration::from_secs(30),
+            max_retries: 3,
+            retry_delay: Duration::from_millis(100),
+            max_transfers: 4,
+        }
+    }
+}
+
+/// A store wrapper applying `RemoteLimits` to the transfers of packed chunks
+///
+/// Only `packed_chunk` and `put_packed_chunk`, which are used by the tiered
+/// store, are limited; all the other operations are forwarded as they are.
+pub(super) struct LimitedStore {
+    inner: Box<dyn Store>,
+    transfers: Transfers,
+    downloads: RateLimiter,
+    uploads: RateLimiter,
+}
+
+impl LimitedStore {
+    pub(super) fn new(inner: Box<dyn Store>, limits: RemoteLimits) -> LimitedStore {
+        LimitedStore {
+            inner,
+            transfers: Transfers {
+                limits,
+                active: Mutex::new(0),
+                done: Condvar::new(),
+            },
+            downloads: RateLimiter::new(limits.download_rate),
+            uploads: RateLimiter::new(limits.upload_rate),
+        }
+    }
+}
+
+/// Bounds the number of concurrent transfers and retries the failed ones
+struct Transfers {
+    limits: RemoteLimits,
+    active: Mutex<usize>,
+    done: Condvar,
+}
+
+impl Transfers {
+    // Perform a transfer, retrying it with an exponential backoff if it fails.
+    // Waits for a free transfer slot before each attempt.
+    fn run<T, F>(&self, description: &str, mut attempt: F) -> DenebResult<T>
+    where
+        F: FnMut() -> DenebResult<T>,
+    {
+        let mut delay = self.limits.retry_delay;
+        let mut retries = 0;
+        loop {
+            self.acquire()?;
+            let result = attempt();
+            self.release();
+            match result {
+                Ok(value) => return Ok(value),
+                Err(e) if retries < self.limits.max_retries => {
+                    warn!("{} failed, retrying in {:?}: {}", description, delay, e);
+                    record_remote_retry();
+                    sleep(delay);
+                    delay *= 2;
+                    retries += 1;
+                }
+                Err(e) => {
+                    record_remote_failure();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn acquire(&self) -> DenebResult<()> {
+        let mut active = self.active.lock().map_err(|_| StoreError::CacheLock)?;
+        while self.limits.max_transfers > 0 && *active >= self.limits.max_transfers {
+            active = self.done.wait(active).map_err(|_| StoreError::CacheLock)?;
+        }
+        *active += 1;
+        Ok(())
+    }
+
+    fn release(&self) {
+        if let Ok(mut active) = self.active.lock() {
+            *active -= 1;
+            self.done.notify_one();
+        }
+    }
+}
+
+/// Delays the callers such that the average rate of the transfers stays below
+/// the configured number of bytes per second
+struct RateLimiter {
+    rate: u64,
+    available_at: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate,
+            available_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Account for a transfer of `bytes`, sleeping until the limiter allows
+    // the next transfer
+    fn throttle(&self, bytes: usize) {
+        if self.rate == 0 {
+            return;
+        }
+        let cost = Duration::from_micros(bytes as u64 * 1_000_000 / self.rate);
+        let wait = match self.available_at.lock() {
+            Ok(mut available_at) => {
+                let now = Instant::now();
+                if *available_at < now {
+                    *available_at = now;
+                }
+                *available_at += cost;
+                *available_at - now
+            }
+            Err(_) => cost,
+        };
+        sleep(wait);
+    }
+}
+
+impl Store for LimitedStore {
+    fn chunk_size(&self) -> usize {
+        self.inner.chunk_size()
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        self.inner.algorithm()
+    }
+
+    fn set_algorithm(&mut self, algorithm: Algorithm) {
+        self.inner.set_algorithm(algorithm)
+    }
+
+    fn chunk(&self, digest: &Digest) -> DenebResult<Arc<dyn Chunk>> {
+        self.inner.chunk(digest)
+    }
+
+    fn read_at(&self, digest: &Digest, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        self.inner.read_at(digest, offset, buf)
+    }
+
+    fn read_uncached(&self, digest: &Digest, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        self.inner.read_uncached(digest, offset, buf)
+    }
+
+    fn prefetch(&self, digest: &Digest) {
+        self.inner.prefetch(digest)
+    }
+
+    fn put_chunk(&mut self, contents: &[u8]) -> DenebResult<ChunkDescriptor> {
+        self.inner.put_chunk(contents)
+    }
+
+    fn packed_chunk(&self, digest: &Digest) -> DenebResult<Vec<u8>> {
+        let description = format!("Download of chunk {}", digest);
+        let packed = self
+            .transfers
+            .run(&description, || self.inner.packed_chunk(digest))?;
+        record_remote_transfer(true, packed.len());
+        self.downloads.throttle(packed.len());
+        Ok(packed)
+    }
+
+    fn packed_size(&self, digest: &Digest) -> DenebResult<u64> {
+        self.inner.packed_size(digest)
+    }
+
+    fn missing_chunks(&self, digests: &[Digest]) -> DenebResult<Vec<Digest>> {
+        let description = format!("Lookup of {} chunks", digests.len());
+        self.transfers
+            .run(&description, || self.inner.missing_chunks(digests))
+    }
+
+    fn put_packed_chunk(&mut self, digest: &Digest, packed: &[u8]) -> DenebResult<()> {
+        let description = format!("Upload of chunk {}", digest);
+        let inner = &mut self.inner;
+        self.transfers
+            .run(&description, || inner.put_packed_chunk(digest, packed))?;
+        record_remote_transfer(false, packed.len());
+        self.uploads.throttle(packed.len());
+        Ok(())
+    }
+
+    fn chunk_digests(&self) -> DenebResult<Vec<Digest>> {
+        self.inner.chunk_digests()
+    }
+
+    fn remove_chunk(&mut self, digest: &Digest) -> DenebResult<()> {
+        self.inner.remove_chunk(digest)
+    }
+
+    fn read_special_file(&self, file_name: &Path) -> DenebResult<Vec<u8>> {
+        self.inner.read_special_file(file_name)
+    }
+
+    fn write_special_file(
+        &mut self,
+        file_name: &Path,
+        data: &mut dyn Read,
+        append: bool,
+    ) -> DenebResult<()> {
+        self.inner.write_special_file(file_name, data, append)
+    }
+
+    fn rename_special_file(&mut self, from: &Path, to: &Path) -> DenebResult<()> {
+        self.inner.rename_special_file(from, to)
+    }
+
+    fn remove_special_file(&mut self, file_name: &Path) -> DenebResult<()> {
+        self.inner.remove_special_file(file_name)
+    }
+
+    fn sync(&mut self) -> DenebResult<()> {
+        self.inner.sync()
+    }
+
+    fn persist(&mut self) -> DenebResult<()> {
+        self.inner.persist()
+    }
+
+    // The timeout of the requests to a gateway is set when its store is opened
+    // and doesn't change
+    fn set_remote_limits(&mut self, limits: RemoteLimits) -> DenebResult<()> {
+        self.transfers.limits = limits;
+        self.downloads = RateLimiter::new(limits.download_rate);
+        self.uploads = RateLimiter::new(limits.upload_rate);
+        self.transfers.done.notify_all();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{super::disk::DiskStore, *},
+        crate::cas::hash,
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn transfers_are_retried_and_throttled() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_remote_limits")?;
+        let mut inner = DiskStore::try_new(tmp.path(), None, 16, 1024)?;
+        let digest = inner.put_chunk(b"alabalaportocala")?.digest;
+        let size = inner.packed_size(&digest)?;
+
+        let limits = RemoteLimits {
+            download_rate: size * 10,
+            retry_delay: Duration::from_millis(10),
+            ..RemoteLimits::default()
+        };
+        let store = LimitedStore::new(Box::new(inner), limits);
+
+        // Three retries, after 10, 20 and 40 ms
+        let start = Instant::now();
+        assert!(store.packed_chunk(&hash(b"missing")).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(70));
+
+        // Each download takes at least a tenth of a second
+        let start = Instant::now();
+        for _ in 0..3 {
+            store.packed_chunk(&digest)?;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,534 @@
+use {
+    super::{Chunk, RemoteLimits, Store},
+    crate::{
+        cas::{Algorithm, Digest},
+        errors::{DenebResult, StoreError},
+        inode::ChunkDescriptor,
+    },
+    log::{info, trace, warn},
+    std::{
+        collections::{HashMap, HashSet},
+        io::Read,
+        path::Path,
+        sync::{Arc, Mutex, MutexGuard},
+    },
+};
+
+/// A store which keeps local copies of the chunks of a slower, remote store
+///
+/// Chunks which are missing from the local store are fetched from the remote
+/// store when they are first read. New chunks are written to both stores, so
+/// the local copies can be evicted at any time: once the packed chunks held
+/// locally exceed `capacity` bytes, the least recently used ones are removed.
+/// Pinned chunks are fetched as soon as they are pinned and never removed,
+/// even when they alone exceed the capacity.
+///
+/// Chunks are copied between the stores in their packed form, so both stores
+/// need to pack chunks the same way and use the same encryption key. Special
+/// files (manifest, reflog etc.) are only kept in the local store, and chunks
+/// are never removed from the remote store, which may be shared with other
+/// repositories: `chunk_digests` and `remove_chunk` only concern the local
+/// copies.
+pub(super) struct TieredStore {
+    local: Mutex<Box<dyn Store>>,
+    remote: Box<dyn Store>,
+    usage: Mutex<LocalUsage>,
+}
+
+/// Size and last use of the chunks held by the local store
+struct LocalUsage {
+    capacity: u64,
+    size: u64,
+    tick: u64,
+    entries: HashMap<Digest, Entry>,
+    pinned: HashSet<Digest>,
+}
+
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+impl TieredStore {
+    pub(super) fn try_new(
+        local: Box<dyn Store>,
+        remote: Box<dyn Store>,
+        capacity: u64,
+    ) -> DenebResult<TieredStore> {
+        let mut usage = LocalUsage {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            pinned: HashSet::new(),
+        };
+        for digest in local.chunk_digests()? {
+            usage.insert(digest, local.packed_size(&digest)?);
+        }
+        info!(
+            "Tiered store: {} chunks held locally, {} bytes",
+            usage.entries.len(),
+            usage.size
+        );
+
+        let store = TieredStore {
+            local: Mutex::new(local),
+            remote,
+            usage: Mutex::new(usage),
+        };
+        store.evict(None)?;
+        Ok(store)
+    }
+
+    // Fetch a chunk missing from the local store
+    fn fetch(&self, digest: &Digest) -> DenebResult<()> {
+        let packed = self.remote.packed_chunk(digest)?;
+        self.lock_local()?.put_packed_chunk(digest, &packed)?;
+        self.lock_usage()?.insert(*digest, packed.len() as u64);
+        trace!("Chunk fetched from the remote store: {}", digest);
+        self.evict(Some(digest))
+    }
+
+    // Copy a chunk which was just written to the local store to the remote store
+    fn write_through(&mut self, digest: &Digest) -> DenebResult<()> {
+        let packed = self.lock_local()?.packed_chunk(digest)?;
+        self.remote.put_packed_chunk(digest, &packed)?;
+        self.lock_usage()?.insert(*digest, packed.len() as u64);
+        self.evict(Some(digest))
+    }
+
+    // Remove the least recently used local chunks until the local store is
+    // below its capacity. The chunk identified by `keep` and the pinned ones
+    // are never removed.
+    fn evict(&self, keep: Option<&Digest>) -> DenebResult<()> {
+        let mut usage = self.lock_usage()?;
+        while usage.size > usage.capacity {
+            let victim = usage
+                .entries
+                .iter()
+                .filter(|(digest, _)| Some(*digest) != keep && !usage.pinned.contains(*digest))
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(digest, _)| *digest);
+            match victim {
+                Some(digest) => {
+                    self.lock_local()?.remove_chunk(&digest)?;
+                    usage.remove(&digest);
+                    trace!("Local copy of chunk evicted: {}", digest);
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn lock_local(&self) -> DenebResult<MutexGuard<Box<dyn Store>>> {
+        Ok(self.local.lock().map_err(|_| StoreError::CacheLock)?)
+    }
+
+    fn lock_usage(&self) -> DenebResult<MutexGuard<LocalUsage>> {
+        Ok(self.usage.lock().map_err(|_| StoreError::CacheLock)?)
+    }
+}
+
+impl LocalUsage {
+    fn insert(&mut self, digest: Digest, size: u64) {
+        self.remove(&digest);
+        self.tick += 1;
+        self.size += size;
+        self.entries.insert(
+            digest,
+            Entry {
+                size,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    // Returns false if the chunk is not held locally
+    fn touch(&mut self, digest: &Digest) -> bool {
+        self.tick += 1;
+        match self.entries.get_mut(digest) {
+            Some(entry) => {
+                entry.last_used = self.tick;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, digest: &Digest) {
+        if let Some(entry) = self.entries.remove(digest) {
+            self.size -= entry.size;
+        }
+    }
+}
+
+impl Store for TieredStore {
+    fn chunk_size(&self) -> usize {
+        self.remote.chunk_size()
+    }
+
+    // New chunks are packed by the local store
+    fn algorithm(&self) -> Algorithm {
+        match self.lock_local() {
+            Ok(local) => local.algorithm(),
+            Err(_) => self.remote.algorithm(),
+        }
+    }
+
+    fn set_algorithm(&mut self, algorithm: Algorithm) {
+        if let Ok(local) = self.local.get_mut() {
+            local.set_algorithm(algorithm);
+        }
+        self.remote.set_algorithm(algorithm);
+    }
+
+    fn chunk(&self, digest: &Digest) -> DenebResult<Arc<dyn Chunk>> {
+        if !self.lock_usage()?.touch(digest) {
+            self.fetch(digest)?;
+        }
+        self.lock_local()?.chunk(digest)
+    }
+
+    fn read_at(&self, digest: &Digest, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        if !self.lock_usage()?.touch(digest) {
+            self.fetch(digest)?;
+        }
+        self.lock_local()?.read_at(digest, offset, buf)
+    }
+
+    // Chunks missing from the local store are still fetched, only the cache
+    // of the local store is bypassed
+    fn read_uncached(&self, digest: &Digest, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        if !self.lock_usage()?.touch(digest) {
+            self.fetch(digest)?;
+        }
+        self.lock_local()?.read_uncached(digest, offset, buf)
+    }
+
+    // Fetch the chunk from the remote store, ahead of the reads
+    fn prefetch(&self, digest: &Digest) {
+        let held = match self.lock_usage() {
+            Ok(usage) => usage.entries.contains_key(digest),
+            Err(_) => return,
+        };
+        if !held {
+            if let Err(e) = self.fetch(digest) {
+                trace!("Prefetch of chunk {} failed: {}", digest, e);
+            }
+        }
+    }
+
+    // Chunks held locally were already written to the remote store
+    fn put_chunk(&mut self, contents: &[u8]) -> DenebResult<ChunkDescriptor> {
+        let descriptor = self.lock_local()?.put_chunk(contents)?;
+        if !self.lock_usage()?.touch(&descriptor.digest) {
+            self.write_through(&descriptor.digest)?;
+        }
+        Ok(descriptor)
+    }
+
+    // The remote store is asked at once which of the new chunks it's missing
+    fn put_file_chunked(&mut self, data: &mut dyn Read) -> DenebResult<Vec<ChunkDescriptor>> {
+        let descriptors = self.lock_local()?.put_file_chunked(data)?;
+        let mut new = Vec::new();
+        {
+            let mut usage = self.lock_usage()?;
+            for descriptor in &descriptors {
+                if !usage.touch(&descriptor.digest) && !new.contains(&descriptor.digest) {
+                    new.push(descriptor.digest);
+                }
+            }
+        }
+        let missing = self.remote.missing_chunks(&new)?;
+        for digest in &new {
+            if missing.contains(digest) {
+                self.write_through(digest)?;
+            } else {
+                let size = self.lock_local()?.packed_size(digest)?;
+                self.lock_usage()?.insert(*digest, size);
+            }
+        }
+        self.evict(None)?;
+        Ok(descriptors)
+    }
+
+    fn put_file(&mut self, data: &mut dyn Read) -> DenebResult<ChunkDescriptor> {
+        let descriptor = self.lock_local()?.put_file(data)?;
+        self.write_through(&descriptor.digest)?;
+        Ok(descriptor)
+    }
+
+    fn packed_chunk(&self, digest: &Digest) -> DenebResult<Vec<u8>> {
+        if self.lock_usage()?.touch(digest) {
+            self.lock_local()?.packed_chunk(digest)
+        } else {
+            self.remote.packed_chunk(digest)
+        }
+    }
+
+    fn put_packed_chunk(&mut self, digest: &Digest, packed: &[u8]) -> DenebResult<()> {
+        self.lock_local()?.put_packed_chunk(digest, packed)?;
+        self.write_through(digest)
+    }
+
+    // Chunks held locally are also held by the remote store
+    fn missing_chunks(&self, digests: &[Digest]) -> DenebResult<Vec<Digest>> {
+        let unknown = {
+            let usage = self.lock_usage()?;
+            digests
+                .iter()
+                .filter(|digest| !usage.entries.contains_key(digest))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        self.remote.missing_chunks(&unknown)
+    }
+
+    fn chunk_digests(&self) -> DenebResult<Vec<Digest>> {
+        self.lock_local()?.chunk_digests()
+    }
+
+    fn remove_chunk(&mut self, digest: &Digest) -> DenebResult<()> {
+        self.lock_local()?.remove_chunk(digest)?;
+        self.lock_usage()?.remove(digest);
+        Ok(())
+    }
+
+    fn read_special_file(&self, file_name: &Path) -> DenebResult<Vec<u8>> {
+        self.lock_local()?.read_special_file(file_name)
+    }
+
+    fn write_special_file(
+        &mut self,
+        file_name: &Path,
+        data: &mut dyn Read,
+        append: bool,
+    ) -> DenebResult<()> {
+        self.lock_local()?
+            .write_special_file(file_name, data, append)
+    }
+
+    fn rename_special_file(&mut self, from: &Path, to: &Path) -> DenebResult<()> {
+        self.lock_local()?.rename_special_file(from, to)
+    }
+
+    fn remove_special_file(&mut self, file_name: &Path) -> DenebResult<()> {
+        self.lock_local()?.remove_special_file(file_name)
+    }
+
+    fn sync(&mut self) -> DenebResult<()> {
+        self.remote.sync()?;
+        self.lock_local()?.sync()
+    }
+
+    fn persist(&mut self) -> DenebResult<()> {
+        self.remote.persist()?;
+        self.lock_local()?.persist()
+    }
+
+    fn set_cache_size(&mut self, cache_size: u64) -> DenebResult<()> {
+        self.lock_local()?.set_cache_size(cache_size)
+    }
+
+    fn set_remote_limits(&mut self, limits: RemoteLimits) -> DenebResult<()> {
+        self.remote.set_remote_limits(limits)
+    }
+
+    // Chunks which can't be fetched now are fetched when they are read, or
+    // when the pins are next set
+    fn set_pinned(&mut self, digests: &HashSet<Digest>) -> DenebResult<()> {
+        let missing = {
+            let mut usage = self.lock_usage()?;
+            usage.pinned = digests.clone();
+            digests
+                .iter()
+                .filter(|digest| !usage.entries.contains_key(*digest))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        for digest in &missing {
+            if let Err(e) = self.fetch(digest) {
+                warn!("Pinned chunk {} could not be fetched: {}", digest, e);
+            }
+        }
+        // The chunks which were unpinned may exceed the capacity
+        self.evict(None)
+    }
+
+    // Chunks which were held locally before the remote store was configured
+    // may be missing from it, and are kept
+    fn dehydrate(&mut self, digests: &HashSet<Digest>) -> DenebResult<(u64, u64)> {
+        let mut removed = (0, 0);
+        for digest in digests {
+            let size = {
+                let usage = self.lock_usage()?;
+                if usage.pinned.contains(digest) {
+                    continue;
+                }
+                match usage.entries.get(digest) {
+                    Some(entry) => entry.size,
+                    None => continue,
+                }
+            };
+            if self.remote.packed_size(digest).is_err() {
+                warn!("Chunk {} is missing from the remote store, kept", digest);
+                continue;
+            }
+            self.remove_chunk(digest)?;
+            removed.0 += 1;
+            removed.1 += size;
+            trace!("Local copy of chunk dehydrated: {}", digest);
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{super::disk::DiskStore, *},
+        crate::cas::hash,
+        tempdir::TempDir,
+    };
+
+    fn read_chunk(store: &dyn Store, digest: &Digest) -> DenebResult<Vec<u8>> {
+        let chunk = store.chunk(digest)?;
+        let mut buf = vec![0; chunk.size()];
+        chunk.read_at(&mut buf, 0)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn chunks_are_fetched_and_evicted() -> DenebResult<()> {
+        let remote_dir = TempDir::new("deneb_tiered_remote")?;
+        let mut remote = DiskStore::try_new(remote_dir.path(), None, 16, 1024)?;
+        let first = remote.put_chunk(b"alabalaportocala")?.digest;
+        let second = remote.put_chunk(b"40 de turcaleti")?.digest;
+
+        let local_dir = TempDir::new("deneb_tiered_local")?;
+        let local = DiskStore::try_new(local_dir.path(), None, 16, 1024)?;
+        // Room for a single packed chunk
+        let mut store = TieredStore::try_new(Box::new(local), Box::new(remote), 32)?;
+        assert_eq!(read_chunk(&store, &first)?, b"alabalaportocala".to_vec());
+        assert_eq!(read_chunk(&store, &second)?, b"40 de turcaleti".to_vec());
+        assert_eq!(store.lock_local()?.chunk_digests()?, vec![second]);
+
+        // New chunks are written to both stores
+        let third = store.put_chunk(b"zece pitpalaci")?.digest;
+        assert!(store.remote.chunk(&third).is_ok());
+        assert_eq!(store.lock_local()?.chunk_digests()?, vec![third]);
+        assert_eq!(read_chunk(&store, &first)?, b"alabalaportocala".to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_missing_chunks_are_uploaded() -> DenebResult<()> {
+        let remote_dir = TempDir::new("deneb_tiered_remote")?;
+        let mut remote = DiskStore::try_new(remote_dir.path(), None, 16, 1024)?;
+        let first = remote.put_chunk(b"alabalaportocala")?.digest;
+
+        let local_dir = TempDir::new("deneb_tiered_local")?;
+        let local = DiskStore::try_new(local_dir.path(), None, 16, 1024)?;
+        let mut store = TieredStore::try_new(Box::new(local), Box::new(remote), 1024)?;
+        let mut data: &[u8] = b"alabalaportocalaalabalaportocala40 de turcaleti";
+        let descriptors = store.put_file_chunked(&mut data)?;
+        assert_eq!(descriptors.len(), 3);
+        assert_eq!(descriptors[0].digest, first);
+        assert_eq!(descriptors[1].digest, first);
+        let second = descriptors[2].digest;
+
+        assert_eq!(store.remote.chunk_digests()?.len(), 2);
+        assert!(store.remote.chunk(&second).is_ok());
+        assert_eq!(store.lock_local()?.chunk_digests()?.len(), 2);
+        let unknown = hash(b"zece pitpalaci");
+        assert_eq!(
+            store.missing_chunks(&[first, second, unknown])?,
+            vec![unknown]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn pinned_chunks_are_kept() -> DenebResult<()> {
+        let remote_dir = TempDir::new("deneb_tiered_remote")?;
+        let mut remote = DiskStore::try_new(remote_dir.path(), None, 16, 1024)?;
+        let first = remote.put_chunk(b"alabalaportocala")?.digest;
+        let second = remote.put_chunk(b"40 de turcaleti")?.digest;
+
+        let local_dir = TempDir::new("deneb_tiered_local")?;
+        let local = DiskStore::try_new(local_dir.path(), None, 16, 1024)?;
+        // Room for a single packed chunk
+        let mut store = TieredStore::try_new(Box::new(local), Box::new(remote), 32)?;
+        let pinned = vec![first].into_iter().collect::<HashSet<_>>();
+        store.set_pinned(&pinned)?;
+        assert_eq!(store.lock_local()?.chunk_digests()?, vec![first]);
+
+        // The pinned chunk stays, even beyond the capacity
+        assert_eq!(read_chunk(&store, &second)?, b"40 de turcaleti".to_vec());
+        assert_eq!(store.lock_local()?.chunk_digests()?.len(), 2);
+
+        store.set_pinned(&HashSet::new())?;
+        assert_eq!(store.lock_local()?.chunk_digests()?, vec![second]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dehydrated_chunks_are_fetched_again() -> DenebResult<()> {
+        let remote_dir = TempDir::new("deneb_tiered_remote")?;
+        let remote = DiskStore::try_new(remote_dir.path(), None, 16, 1024)?;
+
+        let local_dir = TempDir::new("deneb_tiered_local")?;
+        let mut local = DiskStore::try_new(local_dir.path(), None, 16, 1024)?;
+        // Held locally before the remote store was configured
+        let local_only = local.put_chunk(b"40 de turcaleti")?.digest;
+        let mut store = TieredStore::try_new(Box::new(local), Box::new(remote), 1024)?;
+        let first = store.put_chunk(b"alabalaportocala")?.digest;
+        let second = store.put_chunk(b"zece pitpalaci")?.digest;
+        let pinned = vec![second].into_iter().collect::<HashSet<_>>();
+        store.set_pinned(&pinned)?;
+
+        let all = vec![first, second, local_only]
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let (chunks, bytes) = store.dehydrate(&all)?;
+        assert_eq!(chunks, 1);
+        assert!(bytes > 0);
+        let held = store.lock_local()?.chunk_digests()?;
+        assert_eq!(
+            held.into_iter().collect::<HashSet<_>>(),
+            vec![second, local_only].into_iter().collect()
+        );
+
+        assert_eq!(read_chunk(&store, &first)?, b"alabalaportocala".to_vec());
+        assert_eq!(store.lock_local()?.chunk_digests()?.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunks_are_prefetched_and_read_in_parts() -> DenebResult<()> {
+        let remote_dir = TempDir::new("deneb_tiered_remote")?;
+        let mut remote = DiskStore::try_new(remote_dir.path(), None, 16, 1024)?;
+        let digest = remote.put_chunk(b"alabalaportocala")?.digest;
+
+        let local_dir = TempDir::new("deneb_tiered_local")?;
+        let local = DiskStore::try_new(local_dir.path(), None, 16, 1024)?;
+        let store = TieredStore::try_new(Box::new(local), Box::new(remote), 1024)?;
+        store.prefetch(&digest);
+        assert_eq!(store.lock_local()?.chunk_digests()?, vec![digest]);
+
+        let mut buf = [0; 8];
+        assert_eq!(store.read_at(&digest, 3, &mut buf)?, 8);
+        assert_eq!(&buf, b"balaport");
+        // Reads stop at the end of the chunk
+        assert_eq!(store.read_at(&digest, 12, &mut buf)?, 4);
+        assert_eq!(&buf[..4], b"cala");
+        assert_eq!(store.read_at(&digest, 20, &mut buf)?, 0);
+
+        Ok(())
+    }
+}
@@ -1,7 +1,11 @@
-#![allow(dead_code)]
+//! Helpers for writing tests against Deneb repositories
+//!
+//! This module is only available when the `testing` feature is enabled. It
+//! allows crates depending on `deneb-core` to generate input directory trees and
+//! compare them with the contents of a repository.
 
 use {
-    deneb_core::{cas::hash, errors::DenebResult, util::atomic_write},
+    crate::{cas::hash, errors::DenebResult, util::atomic_write},
     failure::bail,
     quickcheck::{Arbitrary, Gen, RngCore},
     rand::{thread_rng, Rng},
@@ -128,13 +132,6 @@ impl DirTree {
         }
         Ok(())
     }
-
-    fn new() -> DirTree {
-        DirTree {
-            root: PathBuf::from(""),
-            entries: Vec::new(),
-        }
-    }
 }
 
 fn compare_files(fn1: &Path, fn2: &Path) -> bool {
@@ -2,13 +2,14 @@ use {
     crate::errors::{DenebResult, UnixError},
     nix::{
         libc::{getegid, geteuid, gid_t, uid_t},
-        unistd::mkstemp,
+        unistd::{gethostname, mkstemp},
     },
     scopeguard::defer,
     std::{
         cell::Cell,
+        env::var,
         fs::{remove_file, rename, File},
-        io::Write,
+        io::{copy, Read},
         os::unix::io::FromRawFd,
         path::{Path, PathBuf},
     },
@@ -18,8 +19,17 @@ use {
 /// Atomically writes a buffer to a file
 ///
 /// The buffer is first written to a temporary file, then, upon success,
-/// the temporary file is atomically renamed to the final file name.
+/// the temporary file is atomically renamed to the final file name. Both
+/// the file and the directory containing it are synced to disk.
 pub fn atomic_write(file_name: &Path, bytes: &[u8]) -> DenebResult<()> {
+    atomic_write_from(file_name, &mut &bytes[..])
+}
+
+/// Atomically writes the data read from a source to a file
+///
+/// Same as `atomic_write`, but the data is copied to the temporary file in
+/// small pieces instead of having to be held in memory at once.
+pub(crate) fn atomic_write_from(file_name: &Path, data: &mut dyn Read) -> DenebResult<()> {
     let (mut f, temp_path) = create_temp_file(file_name)?;
     let cleanup = Cell::new(true);
     defer! {{
@@ -27,9 +37,21 @@ pub fn atomic_write(file_name: &Path, bytes: &[u8]) -> DenebResult<()> {
             remove_file(&temp_path).expect("could not delete temporary file");
         }
     }};
-    f.write_all(bytes)?;
+    copy(data, &mut f)?;
+    f.sync_all()?;
     rename(&temp_path, file_name)?;
     cleanup.set(false);
+    if let Some(dir) = file_name.parent() {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Flush the entries of a directory to disk
+///
+/// Needed for files created in, or renamed into, the directory to survive a crash.
+pub(crate) fn sync_dir(dir: &Path) -> DenebResult<()> {
+    File::open(dir)?.sync_all()?;
     Ok(())
 }
 
@@ -50,6 +72,22 @@ pub(crate) fn get_euid() -> uid_t {
     unsafe { geteuid() }
 }
 
+/// Returns the name of the user running the process, or its numeric id if the
+/// name isn't known
+pub(crate) fn username() -> String {
+    var("USER")
+        .or_else(|_| var("LOGNAME"))
+        .unwrap_or_else(|_| get_euid().to_string())
+}
+
+/// Returns the name of the host, or an empty string if it can't be determined
+pub(crate) fn hostname() -> String {
+    let mut buf = [0 as u8; 256];
+    gethostname(&mut buf)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
 // Can this be made faster? Is it worth it?
 pub(crate) fn create_temp_file(prefix: &Path) -> Result<(File, PathBuf), UnixError> {
     let mut template = prefix.as_os_str().to_os_string();
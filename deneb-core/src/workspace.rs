@@ -1,55 +1,176 @@
+mod bundle;
+mod checkpoint;
+mod clone;
 mod commit;
+mod dehydrate;
+mod delta;
+mod diff;
 mod dir;
 mod file;
+mod gc;
+mod history;
+mod init;
 mod inode;
+mod negative;
+mod pin;
+mod reflog;
+mod replicate;
+mod restore;
+mod root;
+mod snapshot;
+mod trash;
+mod verify;
 
-pub(in crate) use commit::Summary as CommitSummary;
+pub(in crate) use self::{
+    commit::PendingCommit,
+    gc::{PendingGc, Summary as GcSummary},
+};
+pub use self::{
+    bundle::{create_bundle, open_bundle},
+    clone::clone_repository,
+    commit::Summary as CommitSummary,
+    delta::{apply_delta, export_delta},
+    diff::{PendingChange, PendingChanges},
+    init::{check_repository, init_repository},
+    reflog::{PrunePolicy, RetentionPolicy},
+    replicate::replicate_repository,
+    snapshot::Snapshot,
+    verify::{verify_repository, Difference},
+};
 
 use {
     self::{
-        commit::commit_workspace,
+        checkpoint::{load_checkpoint, save_checkpoint},
+        commit::{begin_commit, commit_next_step, finish_commit, flush_file},
+        dehydrate::dehydrate,
+        diff::pending_changes,
         dir::{DirEntry, Workspace as DirWorkspace},
         file::Workspace as FileWorkspace,
-        inode::Workspace as INodeWorkspace,
+        gc::{begin_gc, finish_gc, gc_next_step},
+        history::History,
+        init::create_repository,
+        inode::{Cache as INodeCache, Workspace as INodeWorkspace},
+        negative::NegativeCache,
+        pin::{refresh_pins, set_pinned},
+        restore::restore,
+        root::{catalog_chunks, read_catalog},
+        trash::{in_trash, move_to_trash, purge_trash},
     },
     crate::{
-        catalog::{open_catalog, Catalog, CatalogType, IndexGenerator},
+        cas::Digest,
+        catalog::{open_catalog, Catalog, CatalogType, Generations, IndexGenerator},
         crypt::EncryptionKey,
         errors::{DenebResult, DirWorkspaceEntryLookupError, EngineError, WorkspaceError},
         inode::{mode_to_permissions, FileAttributeChanges, FileAttributes, FileType, INode},
         manifest::Manifest,
-        populate_with_dir,
-        store::{open_store, Store, StoreType},
-        util::{atomic_write, get_egid, get_euid},
+        metrics::MemoryUsage,
+        store::{open_store, RemoteLimits, Store, StoreType},
+        util::{get_egid, get_euid},
+        ImportOptions,
     },
     failure::ResultExt,
-    log::{error, info},
+    log::{error, info, warn},
     nix::libc::mode_t,
     std::{
         cell::RefCell,
         collections::{HashMap, HashSet},
-        ffi::OsStr,
-        fs::{create_dir_all, remove_dir_all, File},
-        path::{Path, PathBuf},
+        ffi::{OsStr, OsString},
+        fs::create_dir_all,
+        os::unix::ffi::OsStringExt,
+        path::{Component, Path, PathBuf},
         rc::Rc,
     },
-    time::now_utc,
+    time::{at, now_utc, Timespec},
 };
 
-const MANIFEST_PATH: &str = "data/manifest";
+pub(in crate) const MANIFEST_PATH: &str = "data/manifest";
+const MANIFEST_PENDING_PATH: &str = "data/manifest.pending";
 const REFLOG_PATH: &str = "data/reflog";
+/// Number of inodes kept in memory, not counting the ones modified since the
+/// last commit
+const INODE_CACHE_CAPACITY: usize = 100_000;
+/// Number of names remembered as missing from the directories which aren't open
+const NEGATIVE_CACHE_CAPACITY: usize = 10_000;
+/// Number of catalogs of earlier commits kept open to look up previous versions
+const HISTORY_CAPACITY: usize = 4;
+
+/// Options of a workspace, see `start_engine`
+#[derive(Clone)]
+pub struct WorkspaceOptions {
+    pub encryption_key: Option<EncryptionKey>,
+    /// Directory whose contents the repository is created from, if it doesn't
+    /// exist yet
+    pub sync_dir: Option<PathBuf>,
+    /// Size of the chunks of a new repository; existing repositories keep
+    /// their own
+    pub chunk_size: usize,
+    /// Capacity of the cache of unpacked chunks
+    pub chunk_cache_size: u64,
+    /// Which of the commits recorded in the reflog are kept
+    pub retention: RetentionPolicy,
+    /// Whether chunks are verified against their digests when read
+    pub verify_reads: bool,
+    /// Whether names are looked up case-insensitively (their case is kept)
+    pub case_insensitive: bool,
+    /// Number of seconds for which removed entries are kept in the trash, or
+    /// `None` when the trash is disabled (0 keeps them until it's emptied)
+    pub trash_retention: Option<u64>,
+    /// Options of the import of `sync_dir`
+    pub import: ImportOptions,
+}
+
+impl Default for WorkspaceOptions {
+    fn default() -> WorkspaceOptions {
+        WorkspaceOptions {
+            encryption_key: None,
+            sync_dir: None,
+            chunk_size: 4 * 1024 * 1024,
+            chunk_cache_size: 1024 * 1024 * 1024,
+            retention: RetentionPolicy::default(),
+            verify_reads: false,
+            case_insensitive: false,
+            trash_retention: None,
+            import: ImportOptions::default(),
+        }
+    }
+}
 
 pub(in crate) struct Workspace {
     catalog: Box<dyn Catalog>,
+    catalog_type: CatalogType,
     store: Rc<RefCell<Box<dyn Store>>>,
     manifest: Manifest,
+    /// Index of the root directory
+    root: u64,
     index_generator: IndexGenerator,
+    generations: Generations,
     dirs: HashMap<u64, DirWorkspace>,
     files: HashMap<u64, FileWorkspace>,
-    inodes: HashMap<u64, INodeWorkspace>,
+    inodes: INodeCache,
+    negative_lookups: NegativeCache,
+    /// Number of times each inode was returned to the kernel by a lookup,
+    /// less the number of times the kernel forgot it
+    lookup_counts: HashMap<u64, u64>,
+    /// Files known to the kernel whose contents were changed by the engine
+    /// itself, so the pages cached by the kernel are outdated
+    stale_data: HashSet<u64>,
     deleted_inodes: HashSet<u64>,
+    history: History,
     work_dir: PathBuf,
     dirty: bool,
+    /// Amount of data written to the file workspaces since the last commit
+    dirty_bytes: u64,
+    /// Which of the commits recorded in the reflog are kept
+    retention: RetentionPolicy,
+    /// Whether names are looked up case-insensitively (their case is kept)
+    case_insensitive: bool,
+    /// Number of seconds for which removed entries are kept in the trash, or
+    /// `None` when the trash is disabled (0 keeps them until it's emptied)
+    trash_retention: Option<u64>,
+    /// Number of pinned entries and of the chunks held under them
+    pinned: (u64, u64),
+    /// Whether the changes which weren't committed were saved to a checkpoint
+    checkpointed: bool,
 }
 
 impl Workspace {
@@ -57,12 +178,29 @@ impl Workspace {
         catalog_type: CatalogType,
         store_type: StoreType,
         work_dir: PathBuf,
-        encryption_key: Option<EncryptionKey>,
-        sync_dir: Option<PathBuf>,
-        chunk_size: usize,
+        options: WorkspaceOptions,
     ) -> DenebResult<Workspace> {
+        let WorkspaceOptions {
+            encryption_key,
+            sync_dir,
+            chunk_size,
+            chunk_cache_size,
+            retention,
+            verify_reads,
+            case_insensitive,
+            trash_retention,
+            import,
+        } = options;
+
         // Create an object store
-        let mut store = open_store(store_type, &work_dir, encryption_key, chunk_size)?;
+        let mut store = open_store(
+            store_type.clone(),
+            &work_dir,
+            encryption_key.clone(),
+            chunk_size,
+            chunk_cache_size,
+            verify_reads,
+        )?;
 
         let catalog_root = work_dir.join("scratch");
         create_dir_all(catalog_root.as_path())?;
@@ -72,67 +210,174 @@ impl Workspace {
         let manifest_path = work_dir.to_path_buf().join(MANIFEST_PATH);
         info!("Manifest path: {:?}", manifest_path);
 
+        recover_manifest(
+            &mut *store,
+            manifest_path.as_path(),
+            work_dir.join(MANIFEST_PENDING_PATH).as_path(),
+        )?;
+
         // Create the file metadata catalog and populate it with the contents of "sync_dir"
         if let Some(sync_dir) = sync_dir {
-            init(
+            create_repository(
                 &mut *store,
                 catalog_type,
                 catalog_path.as_path(),
                 manifest_path.as_path(),
-                sync_dir.as_path(),
+                Some(sync_dir.as_path()),
                 chunk_size,
+                &import,
             )?;
         }
 
-        // If there is no work dir yet (first start, no sync_dir) create and initialize the repository
-        if !manifest_path.exists() {
-            let empty_dir = work_dir.join("empty_dir");
-            create_dir_all(&empty_dir)?;
-            init(
-                &mut *store,
-                catalog_type,
-                catalog_path.as_path(),
-                manifest_path.as_path(),
-                empty_dir.as_path(),
-                chunk_size,
-            )?;
-            remove_dir_all(&empty_dir)?;
+        // Repositories are created explicitly, with `init_repository` or `clone_repository`
+        if store.read_special_file(&manifest_path).is_err() {
+            return Err(WorkspaceError::NotInitialized(work_dir).into());
         }
 
         // Load the repository manifest
         let buf = store.read_special_file(&manifest_path)?;
         let manifest = Manifest::deserialize(&buf)?;
 
-        // Get the catalog out of storage and open it
-        {
-            let root_hash = manifest.root_hash;
-            let chunk = store.chunk(&root_hash)?;
-            let mut buf = vec![0; chunk.size()];
-            chunk.read_at(&mut buf, 0)?;
-            atomic_write(catalog_path.as_path(), buf.as_slice())?;
+        // The chunk size of the repository takes precedence over the configured one
+        if let Some(repository_chunk_size) = manifest.chunk_size {
+            if repository_chunk_size != chunk_size {
+                warn!(
+                    "Repository chunk size is {} bytes, ignoring the configured {} bytes",
+                    repository_chunk_size, chunk_size
+                );
+                store = open_store(
+                    store_type,
+                    &work_dir,
+                    encryption_key,
+                    repository_chunk_size,
+                    chunk_cache_size,
+                    verify_reads,
+                )?;
+            }
         }
 
+        // Chunks are written with the digest algorithm of the repository
+        store.set_algorithm(manifest.digest.unwrap_or_default());
+
+        // Get the catalog out of storage and open it
+        read_catalog(&*store, &manifest.root_hash, catalog_path.as_path())?;
+
         let catalog = open_catalog(catalog_type, catalog_path.as_path(), false)?;
         catalog.show_stats();
 
+        let root = catalog.root_index()?;
+        match catalog.inode(root) {
+            Ok(ref inode) if inode.attributes.kind == FileType::Directory => {}
+            _ => return Err(WorkspaceError::InvalidRoot(root).into()),
+        }
+
         let index_generator = IndexGenerator::starting_at(catalog.max_index());
+        let generations = Generations::start(&*catalog)?;
 
-        let ws = Workspace {
+        let mut ws = Workspace {
             catalog,
+            catalog_type,
             store: Rc::new(RefCell::new(store)),
             manifest,
+            root,
             index_generator,
+            generations,
             dirs: HashMap::new(),
             files: HashMap::new(),
-            inodes: HashMap::new(),
+            inodes: INodeCache::new(INODE_CACHE_CAPACITY),
+            negative_lookups: NegativeCache::new(NEGATIVE_CACHE_CAPACITY),
+            lookup_counts: HashMap::new(),
+            stale_data: HashSet::new(),
             deleted_inodes: HashSet::new(),
+            history: History::new(HISTORY_CAPACITY, &catalog_root),
             work_dir,
             dirty: false,
+            dirty_bytes: 0,
+            retention,
+            case_insensitive,
+            trash_retention,
+            pinned: (0, 0),
+            checkpointed: false,
         };
+        load_checkpoint(&mut ws);
+        refresh_pins(&mut ws)?;
 
         Ok(ws)
     }
 
+    pub(in crate) fn root_attr(&mut self) -> DenebResult<FileAttributes> {
+        let root = self.root;
+        self.get_attr(root)
+    }
+
+    /// Record that the inode `index` was returned to the kernel by a lookup
+    pub(in crate) fn add_lookup(&mut self, index: u64) {
+        *self.lookup_counts.entry(index).or_insert(0) += 1;
+    }
+
+    /// Record that the kernel dropped `nlookup` of its lookups of `index`,
+    /// returning the number of lookups left
+    ///
+    /// Once the kernel forgot an inode, its workspaces are dropped, unless
+    /// they hold changes which weren't committed yet. They are loaded from the
+    /// catalog again when needed.
+    pub(in crate) fn forget(&mut self, index: u64, nlookup: u64) -> u64 {
+        let left = self
+            .lookup_counts
+            .get(&index)
+            .map_or(0, |count| count.saturating_sub(nlookup));
+        if left == 0 {
+            self.lookup_counts.remove(&index);
+            self.stale_data.remove(&index);
+            self.evict(index);
+        } else {
+            self.lookup_counts.insert(index, left);
+        }
+        left
+    }
+
+    /// Record that the contents of the file `index` changed other than through
+    /// the writes of the kernel, e.g. by a batch
+    ///
+    /// Its modification time is updated, so the kernel drops the pages it
+    /// cached when it next fetches the attributes, and the cached pages are
+    /// also dropped when the file is next opened. Files the kernel doesn't
+    /// know about have no cached pages.
+    pub(in crate) fn invalidate_data(&mut self, index: u64) -> DenebResult<()> {
+        self.inode_ws_mut(index)?.touch_data();
+        if self.lookup_counts.contains_key(&index) {
+            self.stale_data.insert(index);
+        }
+        Ok(())
+    }
+
+    /// Whether the pages of the file `index` cached by the kernel are
+    /// outdated, which is only reported once
+    pub(in crate) fn take_stale_data(&mut self, index: u64) -> bool {
+        self.stale_data.remove(&index)
+    }
+
+    // Directories are dirty from their first change until they are dropped,
+    // so only the ones matching the catalog are evicted
+    fn evict(&mut self, index: u64) {
+        if index == self.root || self.deleted_inodes.contains(&index) {
+            return;
+        }
+        if self.files.get(&index).map_or(false, |ws| !ws.dirty) {
+            self.files.remove(&index);
+        }
+        if self.dirs.get(&index).map_or(false, |ws| !ws.dirty) {
+            self.dirs.remove(&index);
+        }
+        self.inodes.remove_clean(index);
+        self.negative_lookups.invalidate(index);
+    }
+
+    /// Generation number of the inode `index`, which is returned to the kernel
+    pub(in crate) fn generation(&self, index: u64) -> u64 {
+        self.generations.of(index)
+    }
+
     pub(in crate) fn get_attr(&mut self, index: u64) -> DenebResult<FileAttributes> {
         let ws = self.inode_ws(index)?;
         Ok(ws.inode().attributes)
@@ -143,18 +388,26 @@ impl Workspace {
         index: u64,
         changes: &FileAttributeChanges,
     ) -> DenebResult<FileAttributes> {
-        let ws = self.inode_ws_mut(index)?;
-        ws.update_attributes(changes);
-        let attrs = ws.inode().attributes;
-
+        // The file workspace is loaded with the size of the inode, so it's
+        // truncated before the size of the inode changes
         if let Some(new_size) = changes.size {
+            // Files can be truncated by path, without being open
+            let was_open = self.files.contains_key(&index);
+            self.open_file(index, 0)?;
             if let Some(ref mut ws) = self.files.get_mut(&index) {
                 ws.truncate(new_size);
             } else {
                 return Err(WorkspaceError::FileLookup(index).into());
             }
+            if !was_open {
+                self.release_file(index)?;
+            }
         }
 
+        let ws = self.inode_ws_mut(index)?;
+        ws.update_attributes(changes);
+        let attrs = ws.inode().attributes;
+
         self.dirty = true;
 
         Ok(attrs)
@@ -165,14 +418,25 @@ impl Workspace {
         parent: u64,
         name: &OsStr,
     ) -> DenebResult<Option<FileAttributes>> {
+        // Looking up "." finds any inode by its index alone, which is how the
+        // kernel resolves the file handles of NFS clients
+        if name == "." {
+            return self.get_attr(parent).map(Some);
+        }
+        let name = self.entry_name(parent, name)?;
         let index = if let Some(ws) = self.dirs.get(&parent) {
             ws.entries()
                 .iter()
-                .find(|DirEntry { name: ref n, .. }| n == &PathBuf::from(name))
+                .find(|DirEntry { name: ref n, .. }| n == &name)
                 .map(|&DirEntry { index, .. }| index)
+        } else if self.negative_lookups.contains(parent, &name) {
+            None
         } else {
-            self.catalog
-                .dir_entry_index(parent, PathBuf::from(name).as_path())?
+            let index = self.catalog.dir_entry_index(parent, name.as_path())?;
+            if index.is_none() {
+                self.negative_lookups.insert(parent, &name);
+            }
+            index
         };
         if let Some(index) = index {
             self.get_attr(index).map(Some)
@@ -181,25 +445,67 @@ impl Workspace {
         }
     }
 
+    /// Find the file at `path`, relative to the root of the repository
+    ///
+    /// Symbolic links are not followed.
+    pub(in crate) fn resolve_path(
+        &mut self,
+        path: &Path,
+    ) -> DenebResult<Option<(u64, FileAttributes)>> {
+        let mut attrs = self.root_attr()?;
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                Component::ParentDir => OsStr::new(".."),
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => continue,
+            };
+            if attrs.kind != FileType::Directory {
+                return Ok(None);
+            }
+            match self.lookup(attrs.index, name)? {
+                Some(entry_attrs) => attrs = entry_attrs,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some((attrs.index, attrs)))
+    }
+
     // Note: We perform inefficient double lookups since Catalog::dir_entries returns
     //       a Result and can't be used inside Entry::or_insert_with
     #[allow(clippy::map_entry)]
+    /// Load the entries of a directory, and the inodes of its children
+    ///
+    /// The inodes which aren't cached are read from the catalog at once. The
+    /// entries whose inodes can't be read are left out, with a warning.
     pub(in crate) fn open_dir(&mut self, index: u64) -> DenebResult<()> {
-        if !self.dirs.contains_key(&index) {
-            let entries = self
-                .catalog
-                .dir_entries(index)?
-                .iter()
-                .map(|&(ref name, idx)| {
-                    if let Ok(ws) = self.inode_ws(idx) {
-                        DirEntry::new(idx, name.clone(), ws.inode().attributes.kind)
-                    } else {
-                        panic!("Fatal engine error. Could not retrieve inode {}", idx)
-                    }
-                })
-                .collect::<Vec<_>>();
-            self.dirs.insert(index, DirWorkspace::new(&entries));
+        if self.dirs.contains_key(&index) {
+            return Ok(());
+        }
+        let children = self.catalog.dir_entries(index)?;
+        let mut kinds = HashMap::new();
+        let mut missing = Vec::new();
+        for &(_, idx) in &children {
+            match self.inodes.get(idx) {
+                Some(ws) => {
+                    kinds.insert(idx, ws.inode().attributes.kind);
+                }
+                None => missing.push(idx),
+            }
+        }
+        for (idx, inode) in missing.iter().zip(self.catalog.inodes(&missing)) {
+            match inode {
+                Ok(inode) => {
+                    kinds.insert(*idx, inode.attributes.kind);
+                    self.inodes.insert(*idx, INodeWorkspace::new(inode, false));
+                }
+                Err(e) => warn!("Could not read inode {} of directory {}: {}", idx, index, e),
+            }
         }
+        let entries = children
+            .into_iter()
+            .filter_map(|(name, idx)| kinds.get(&idx).map(|&kind| DirEntry::new(idx, name, kind)))
+            .collect::<Vec<_>>();
+        self.dirs.insert(index, DirWorkspace::new(&entries));
         Ok(())
     }
 
@@ -208,11 +514,27 @@ impl Workspace {
         Ok(())
     }
 
-    pub(in crate) fn read_dir(&self, index: u64) -> DenebResult<Vec<(PathBuf, u64, FileType)>> {
-        self.dirs
+    /// The entries of the directory following the one with the cookie
+    /// `offset`, with their cookies and their attributes
+    pub(in crate) fn read_dir(
+        &mut self,
+        index: u64,
+        offset: i64,
+    ) -> DenebResult<Vec<(i64, PathBuf, FileAttributes)>> {
+        let entries = self
+            .dirs
             .get(&index)
-            .map(DirWorkspace::entries_tuple)
-            .ok_or_else(|| WorkspaceError::DirLookup(index).into())
+            .map(|ws| {
+                ws.entries_after(offset)
+                    .iter()
+                    .map(|e| (e.cookie, e.name.clone(), e.index))
+                    .collect::<Vec<_>>()
+            })
+            .ok_or_else(|| WorkspaceError::DirLookup(index))?;
+        entries
+            .into_iter()
+            .map(|(cookie, name, index)| Ok((cookie, name, self.get_attr(index)?)))
+            .collect()
     }
 
     // Note: We perform inefficient double lookups since Catalog::inode returns
@@ -228,6 +550,41 @@ impl Workspace {
         Ok(())
     }
 
+    /// Make the reads of an open file bypass the caches of chunks, or use them
+    /// again
+    pub(in crate) fn set_uncached(&mut self, index: u64, uncached: bool) -> DenebResult<()> {
+        let ws = self
+            .files
+            .get(&index)
+            .ok_or_else(|| WorkspaceError::FileLookup(index))?;
+        ws.set_uncached(uncached);
+        Ok(())
+    }
+
+    /// Load all the chunks of a file into the cache of the store
+    ///
+    /// The workspace releases the chunks again, so that warming up files
+    /// doesn't hold them in memory; the cache of the store evicts them as
+    /// needed.
+    pub(in crate) fn prefetch(&mut self, index: u64) -> DenebResult<()> {
+        let was_open = self.files.contains_key(&index);
+        self.open_file(index, 0)?;
+        let ws = self
+            .files
+            .get(&index)
+            .ok_or_else(|| WorkspaceError::FileLookup(index))?;
+        let result = ws.prefetch();
+        ws.unload();
+        if !was_open {
+            self.files.remove(&index);
+        }
+        result
+    }
+
+    pub(in crate) fn chunk_size(&self) -> usize {
+        self.store.borrow().chunk_size()
+    }
+
     pub(in crate) fn read_data(&self, index: u64, offset: i64, size: u32) -> DenebResult<Vec<u8>> {
         let offset = ::std::cmp::max(offset, 0) as usize;
         let ws = self
@@ -241,7 +598,7 @@ impl Workspace {
         &mut self,
         index: u64,
         offset: i64,
-        data: &[u8],
+        data: Vec<u8>,
     ) -> DenebResult<u32> {
         let offset = ::std::cmp::max(offset, 0) as usize;
         let (written, new_size) = {
@@ -249,7 +606,10 @@ impl Workspace {
                 .files
                 .get_mut(&index)
                 .ok_or_else(|| WorkspaceError::FileLookup(index))?;
-            ws.write_at(offset, data)
+            let dirty_before = ws.dirty_bytes();
+            let res = ws.write_at(offset, data);
+            self.dirty_bytes += ws.dirty_bytes() - dirty_before;
+            res
         };
         let ws = self.inode_ws_mut(index)?;
         ws.update_size(new_size);
@@ -259,6 +619,127 @@ impl Workspace {
         Ok(written)
     }
 
+    /// Pin or unpin the entry `index`, whose chunks, and the ones of the
+    /// entries under it, are then kept locally by the store. Returns whether
+    /// the entry was pinned before.
+    pub(in crate) fn set_pinned(&mut self, index: u64, pinned: bool) -> DenebResult<bool> {
+        log_error("pinning", set_pinned(self, index, pinned))
+    }
+
+    /// Returns the number of pinned entries and of the chunks held under them
+    pub(in crate) fn pinned(&self) -> (u64, u64) {
+        self.pinned
+    }
+
+    /// Drop the local copies of the chunks of the files under the entry
+    /// `index`, or only of the ones untouched since `cutoff`. Returns the
+    /// number of chunks dropped and their packed size.
+    pub(in crate) fn dehydrate(
+        &mut self,
+        index: u64,
+        cutoff: Option<Timespec>,
+    ) -> DenebResult<(u64, u64)> {
+        log_error("dehydration", dehydrate(self, index, cutoff))
+    }
+
+    /// Returns the number of file workspaces and the amount of uncommitted data they hold
+    pub(in crate) fn file_stats(&self) -> (u64, u64) {
+        let dirty_bytes = self.files.values().map(FileWorkspace::dirty_bytes).sum();
+        (self.files.len() as u64, dirty_bytes)
+    }
+
+    /// Estimate of the memory held by the workspace
+    ///
+    /// The catalog and the store are not accounted for: LMDB maps the catalog
+    /// in memory, but its pages are managed by the kernel, and the chunk cache
+    /// of the on-disk store holds the unpacked chunks in files.
+    pub(in crate) fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            inodes: self.inodes.memory_size(),
+            dirs: self.dirs.values().map(DirWorkspace::memory_size).sum(),
+            ..MemoryUsage::default()
+        };
+        for ws in self.files.values() {
+            let (file_data, piece_tables) = ws.memory_size();
+            usage.file_data += file_data;
+            usage.piece_tables += piece_tables;
+            usage.chunks += ws.chunk_memory_size();
+        }
+        usage
+    }
+
+    /// Release the memory which isn't needed to keep the uncommitted changes
+    ///
+    /// The clean inodes and directories are dropped, as by `evict`, and the
+    /// chunks loaded by the open files unloaded; they are read again from the
+    /// catalog and the store when needed.
+    pub(in crate) fn shrink(&mut self) {
+        let root = self.root;
+        let deleted = &self.deleted_inodes;
+        self.dirs
+            .retain(|index, ws| ws.dirty || *index == root || deleted.contains(index));
+        self.inodes.evict_clean(deleted);
+        for ws in self.files.values() {
+            ws.unload();
+        }
+    }
+
+    /// Change the capacity of the chunk cache and the limits of the transfers
+    /// from and to the remote store
+    pub(in crate) fn set_store_limits(
+        &mut self,
+        chunk_cache_size: u64,
+        remote_limits: RemoteLimits,
+    ) -> DenebResult<()> {
+        let mut store = self.store.borrow_mut();
+        store.set_cache_size(chunk_cache_size)?;
+        store.set_remote_limits(remote_limits)
+    }
+
+    /// Whether the workspace changed since the last commit
+    pub(in crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Returns the amount of data written since the last commit
+    pub(in crate) fn dirty_bytes(&self) -> u64 {
+        self.dirty_bytes
+    }
+
+    /// Returns the target of a symbolic link, which is stored as its contents
+    pub(in crate) fn read_link(&mut self, index: u64) -> DenebResult<PathBuf> {
+        let inode = self.inode_ws(index)?.inode().clone();
+        if inode.attributes.kind != FileType::Symlink {
+            return Err(WorkspaceError::NotSymlink(index).into());
+        }
+        let fws = FileWorkspace::try_new(&inode, Rc::clone(&self.store), false)?;
+        let target = fws.read_at(0, inode.attributes.size as usize)?;
+        Ok(PathBuf::from(OsString::from_vec(target)))
+    }
+
+    /// Value of the extended attribute `name` of the inode `index`
+    pub(in crate) fn get_xattr(&mut self, index: u64, name: &str) -> DenebResult<Option<Vec<u8>>> {
+        Ok(self.inode_ws(index)?.inode().xattrs.get(name).cloned())
+    }
+
+    /// Names of the extended attributes of the inode `index`
+    pub(in crate) fn list_xattrs(&mut self, index: u64) -> DenebResult<Vec<String>> {
+        Ok(self.inode_ws(index)?.inode().xattrs.keys().cloned().collect())
+    }
+
+    /// Set or, without a value, remove the extended attribute `name` of the
+    /// inode `index`, returning its previous value
+    pub(in crate) fn set_xattr(
+        &mut self,
+        index: u64,
+        name: &str,
+        value: Option<Vec<u8>>,
+    ) -> DenebResult<Option<Vec<u8>>> {
+        let previous = self.inode_ws_mut(index)?.set_xattr(name, value);
+        self.dirty = true;
+        Ok(previous)
+    }
+
     pub(in crate) fn release_file(&mut self, index: u64) -> DenebResult<()> {
         let ws = self
             .files
@@ -275,6 +756,7 @@ impl Workspace {
         mode: u32,
         _flags: u32,
     ) -> DenebResult<(u64, FileAttributes)> {
+        self.check_name_conflict(parent, name)?;
         let index = self.index_generator.next();
 
         // Create new inode
@@ -298,6 +780,7 @@ impl Workspace {
         self.files.insert(index, ws);
 
         // Update the parent directory workspace
+        self.negative_lookups.invalidate(parent);
         self.open_dir(parent)?;
 
         if let Some(ws) = self.dirs.get_mut(&parent) {
@@ -317,6 +800,7 @@ impl Workspace {
         name: &OsStr,
         mode: u32,
     ) -> DenebResult<FileAttributes> {
+        self.check_name_conflict(parent, name)?;
         let index = self.index_generator.next();
 
         // Create new inode
@@ -343,6 +827,7 @@ impl Workspace {
         self.dirs.insert(index, ws);
 
         // Update the parent directory workspace
+        self.negative_lookups.invalidate(parent);
         self.open_dir(parent)?;
 
         if let Some(ws) = self.dirs.get_mut(&parent) {
@@ -356,20 +841,44 @@ impl Workspace {
         Ok(attributes)
     }
 
+    /// Remove the entry `name` of `parent`, moving it to the trash when the
+    /// trash is enabled and the entry isn't already in it
     pub(in crate) fn remove(&mut self, parent: u64, name: &OsStr) -> DenebResult<()> {
+        if self.trash_retention.is_some() && !in_trash(self, parent, name)? {
+            move_to_trash(self, parent, name)
+        } else {
+            self.delete(parent, name)
+        }
+    }
+
+    /// Delete the entries of the trash which are older than the retention
+    /// period, or all of them when `all` is set, returning how many there were
+    pub(in crate) fn purge_trash(&mut self, all: bool) -> DenebResult<usize> {
+        purge_trash(self, all)
+    }
+
+    // Unlink the entry `name` of `parent`, bypassing the trash
+    fn delete(&mut self, parent: u64, name: &OsStr) -> DenebResult<()> {
+        self.negative_lookups.invalidate(parent);
         self.open_dir(parent)?;
-        if let Some(ws) = self.dirs.get_mut(&parent) {
-            let pname = PathBuf::from(name);
+        let pname = self.entry_name(parent, name)?;
+        let index = if let Some(ws) = self.dirs.get_mut(&parent) {
             let index = ws
                 .entry_index(&pname)
                 .ok_or_else(|| DirWorkspaceEntryLookupError {
                     parent,
                     name: name.to_owned(),
                 })?;
-            self.deleted_inodes.insert(index);
-            ws.remove_entry(&PathBuf::from(name));
+            ws.remove_entry(&pname);
+            index
         } else {
             return Err(WorkspaceError::DirLookup(parent).into());
+        };
+
+        // The inode is only deleted together with its last hard link
+        let ws = self.inode_ws_mut(index)?;
+        if ws.inode().attributes.kind == FileType::Directory || ws.unlink() == 0 {
+            self.deleted_inodes.insert(index);
         }
 
         self.dirty = true;
@@ -378,13 +887,22 @@ impl Workspace {
     }
 
     // Note: this implementation isn't atomic
+    /// Move an entry, replacing the file which has the new name, if any.
+    /// Returns whether a file was replaced.
+    ///
+    /// The data of a file which is moved is written to the store before the
+    /// rename becomes visible, so that a file saved by writing a temporary
+    /// file and renaming it over the original one never replaces the original
+    /// with data which is only held in memory.
     pub(in crate) fn rename(
         &mut self,
         parent: u64,
         name: &OsStr,
         new_parent: u64,
         new_name: &OsStr,
-    ) -> DenebResult<()> {
+    ) -> DenebResult<bool> {
+        self.negative_lookups.invalidate(parent);
+        self.negative_lookups.invalidate(new_parent);
         self.open_dir(parent).context(EngineError::Rename(
             parent,
             name.to_owned(),
@@ -398,32 +916,37 @@ impl Workspace {
             new_name.to_owned(),
         ))?;
 
-        let src_entry = if let Some(ws) = self.dirs.get_mut(&parent) {
-            let pname = PathBuf::from(name);
-            let entry = ws
-                .entry(&pname)
+        let pname = self.entry_name(parent, name)?;
+        let src_entry = if let Some(ws) = self.dirs.get(&parent) {
+            ws.entry(&pname)
                 .cloned()
                 .ok_or_else(|| DirWorkspaceEntryLookupError {
                     parent,
                     name: name.to_owned(),
-                })?;
-            ws.remove_entry(&pname);
-            entry
+                })?
         } else {
             return Err(WorkspaceError::DirLookup(parent).into());
         };
+        if src_entry.entry_type == FileType::RegularFile {
+            flush_file(self, src_entry.index)?;
+        }
+        if let Some(ws) = self.dirs.get_mut(&parent) {
+            ws.remove_entry(&pname);
+        }
 
+        // The entry which is replaced may differ in case from the new name
+        let old_name = self.entry_name(new_parent, new_name)?;
         let new_name = PathBuf::from(new_name);
 
         let old_entry_type = self
             .dirs
             .get(&new_parent)
-            .and_then(|ws| ws.entry(&new_name))
+            .and_then(|ws| ws.entry(&old_name))
             .map(|&DirEntry { entry_type, .. }| entry_type);
 
         if let Some(entry_type) = old_entry_type {
             if entry_type == FileType::RegularFile {
-                self.remove(new_parent, new_name.as_os_str())?;
+                self.delete(new_parent, old_name.as_os_str())?;
             } else {
                 panic!(
                     "Entry {:?} has unsupported file type {:?}",
@@ -440,65 +963,252 @@ impl Workspace {
 
         self.dirty = true;
 
-        Ok(())
+        Ok(old_entry_type.is_some())
     }
 
+    // The engine commits step by step, with `begin_commit`
+    #[cfg(test)]
     pub(in crate) fn commit(&mut self) -> DenebResult<CommitSummary> {
-        match commit_workspace(self) {
-            Ok(summary) => Ok(summary),
-            Err(e) => {
-                error!("Error encountered during commit: {}", e);
-                Err(e)
+        self.expire_trash();
+        log_error("commit", self::commit::commit_workspace(self))
+    }
+
+    /// Returns what the next commit would write, without writing anything
+    /// Save the changes which weren't committed, instead of committing them
+    pub(in crate) fn save_checkpoint(&mut self) -> DenebResult<()> {
+        log_error("checkpoint", save_checkpoint(self))
+    }
+
+    pub(in crate) fn pending_changes(&self) -> DenebResult<PendingChanges> {
+        pending_changes(self)
+    }
+
+    /// Copy the entry at `path` in the commit with root hash `root_hash` into
+    /// the workspace, returning the path under which it was restored
+    pub(in crate) fn restore(
+        &mut self,
+        root_hash: &Digest,
+        path: &Path,
+        overwrite: bool,
+    ) -> DenebResult<PathBuf> {
+        restore(self, root_hash, path, overwrite)
+    }
+
+    /// Digest of the whole contents of the file `index`
+    ///
+    /// Only known for regular files without changes since they were last
+    /// written to the store, which were written since digests are recorded.
+    pub(in crate) fn file_digest(&mut self, index: u64) -> DenebResult<Option<Digest>> {
+        if self.files.get(&index).map_or(false, |fws| fws.dirty) {
+            return Ok(None);
+        }
+        let inode = self.inode_ws(index)?.inode();
+        if inode.attributes.kind != FileType::RegularFile {
+            return Ok(None);
+        }
+        Ok(inode.digest)
+    }
+
+    /// The previous committed version of the file `index`
+    ///
+    /// This is the most recent retained commit in which the contents of the
+    /// file differ from the ones in the current commit, along with the inode
+    /// of the file in that commit. Changes which were not committed yet are
+    /// not taken into account.
+    pub(in crate) fn previous_version(
+        &mut self,
+        index: u64,
+    ) -> DenebResult<Option<(Digest, INode)>> {
+        let digests = |inode: &INode| -> Vec<Digest> {
+            inode.chunks.iter().map(|chunk| chunk.digest).collect()
+        };
+        let mut current = None;
+        for manifest in self.log()? {
+            let inode = match self.history.inode(
+                &**self.store.borrow(),
+                self.catalog_type,
+                &manifest.root_hash,
+                index,
+            )? {
+                Some(ref inode) if inode.attributes.kind == FileType::RegularFile => inode.clone(),
+                _ => break,
+            };
+            match current {
+                None => current = Some(digests(&inode)),
+                Some(ref current) if *current != digests(&inode) => {
+                    return Ok(Some((manifest.root_hash, inode)));
+                }
+                _ => {}
             }
         }
+        Ok(None)
     }
 
-    // Note: We perform inefficient double lookups since Catalog::inode returns a Result
-    //       and can't be used inside Entry::or_insert_with
-    #[allow(clippy::map_entry)]
-    fn inode_ws(&mut self, index: u64) -> DenebResult<&INodeWorkspace> {
-        if !self.inodes.contains_key(&index) {
-            let inode = self.catalog.inode(index)?;
-            self.inodes.insert(index, INodeWorkspace::new(inode, false));
+    /// The contents of the previous committed version of the file `index`
+    pub(in crate) fn read_previous_version(
+        &mut self,
+        index: u64,
+    ) -> DenebResult<Option<Vec<u8>>> {
+        match self.previous_version(index)? {
+            Some((_, inode)) => {
+                let fws = FileWorkspace::try_new(&inode, Rc::clone(&self.store), false)?;
+                Ok(Some(fws.read_at(0, inode.attributes.size as usize)?))
+            }
+            None => Ok(None),
         }
-        self.inodes
-            .get(&index)
-            .ok_or_else(|| WorkspaceError::INodeLookup(index).into())
     }
 
-    // Note: We perform inefficient double lookups since Catalog::inode returns a Result
-    //       and can't be used inside Entry::or_insert_with
-    #[allow(clippy::map_entry)]
+    /// Start a commit which is then performed one chunk at a time, with `commit_next_step`
+    /// and `finish_commit`
+    ///
+    /// The entries of the trash which are older than the retention period are
+    /// deleted first.
+    pub(in crate) fn begin_commit(&mut self, message: Option<String>) -> PendingCommit {
+        self.expire_trash();
+        begin_commit(self, message)
+    }
+
+    /// Write the next chunk of a pending commit. Returns `false` when no chunks are left.
+    pub(in crate) fn commit_next_step(&mut self, commit: &mut PendingCommit) -> DenebResult<bool> {
+        log_error("commit", commit_next_step(self, commit))
+    }
+
+    pub(in crate) fn finish_commit(&mut self, commit: PendingCommit) -> DenebResult<CommitSummary> {
+        log_error("commit", finish_commit(self, commit))
+    }
+
+    /// Start a garbage collection which is then performed in steps, with `gc_next_step`
+    /// and `finish_gc`
+    pub(in crate) fn begin_gc(&mut self) -> DenebResult<PendingGc> {
+        log_error("garbage collection", begin_gc(self))
+    }
+
+    /// Perform the next step of a garbage collection. Returns `false` when it is complete.
+    pub(in crate) fn gc_next_step(&mut self, gc: &mut PendingGc) -> DenebResult<bool> {
+        log_error("garbage collection", gc_next_step(self, gc))
+    }
+
+    pub(in crate) fn finish_gc(&mut self, gc: PendingGc) -> GcSummary {
+        finish_gc(gc)
+    }
+
+    /// Remove the commits which are not kept by `policy` from the reflog, so
+    /// that the next garbage collection reclaims their chunks. Returns the
+    /// number of removed commits.
+    pub(in crate) fn prune_reflog(&mut self, policy: PrunePolicy) -> DenebResult<usize> {
+        let removed = reflog::prune(
+            &mut **self.store.borrow_mut(),
+            &PathBuf::from(REFLOG_PATH),
+            policy,
+            now_utc().to_timespec().sec,
+        )?;
+        info!("Pruned {} commits from the reflog", removed);
+        Ok(removed)
+    }
+
+    pub(in crate) fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Returns the current manifest followed by the ones recorded in the
+    /// reflog, newest first
+    ///
+    /// Reflog entries written by older versions don't record the replaced
+    /// manifest; it is approximated by one without metadata, timestamped with
+    /// the time at which it was replaced.
+    pub(in crate) fn log(&self) -> DenebResult<Vec<Manifest>> {
+        let entries = reflog::read(&**self.store.borrow(), &PathBuf::from(REFLOG_PATH))?;
+        let mut manifests = vec![self.manifest.clone()];
+        manifests.extend(entries.into_iter().rev().map(|entry| match entry.manifest {
+            Some(manifest) => manifest,
+            None => Manifest::new(entry.root_hash, at(Timespec::new(entry.timestamp, 0))),
+        }));
+        Ok(manifests)
+    }
+
+    pub(in crate) fn persist(&mut self) -> DenebResult<()> {
+        self.store.borrow_mut().persist()
+    }
+
+    // The trash is purged again by the next commit when this fails
+    fn expire_trash(&mut self) {
+        if self.trash_retention.unwrap_or(0) > 0 {
+            if let Err(e) = self.purge_trash(false) {
+                warn!("Could not purge the trash: {}", e);
+            }
+        }
+    }
+
+    // The name under which the entry `name` of `parent` is stored, which differs
+    // from `name` only in case-insensitive mode
+    fn entry_name(&mut self, parent: u64, name: &OsStr) -> DenebResult<PathBuf> {
+        if self.case_insensitive {
+            self.open_dir(parent)?;
+            if let Some(entry) = self
+                .dirs
+                .get(&parent)
+                .and_then(|ws| ws.entry_ignoring_case(Path::new(name)))
+            {
+                return Ok(entry.name.clone());
+            }
+        }
+        Ok(PathBuf::from(name))
+    }
+
+    // In case-insensitive mode, names which only differ in case can't be added
+    // to the same directory
+    fn check_name_conflict(&mut self, parent: u64, name: &OsStr) -> DenebResult<()> {
+        if self.case_insensitive && self.entry_name(parent, name)? != Path::new(name) {
+            return Err(WorkspaceError::NameConflict(parent, name.to_owned()).into());
+        }
+        Ok(())
+    }
+
+    fn inode_ws(&mut self, index: u64) -> DenebResult<&INodeWorkspace> {
+        self.inode_ws_mut(index).map(|ws| &*ws)
+    }
+
+    // Inodes which are not cached are read from the catalog
     fn inode_ws_mut(&mut self, index: u64) -> DenebResult<&mut INodeWorkspace> {
-        if !self.inodes.contains_key(&index) {
+        if self.inodes.get(index).is_none() {
             let inode = self.catalog.inode(index)?;
             self.inodes.insert(index, INodeWorkspace::new(inode, false));
         }
         self.inodes
-            .get_mut(&index)
+            .get_mut(index)
             .ok_or_else(|| WorkspaceError::INodeLookup(index).into())
     }
 }
 
-fn init(
+/// Complete or roll back a commit which was interrupted after its manifest was prepared
+///
+/// The prepared manifest is activated only if all the chunks of the catalog it
+/// points to can be retrieved from the store, otherwise it is discarded.
+fn recover_manifest(
     store: &mut dyn Store,
-    catalog_type: CatalogType,
-    catalog_path: &Path,
     manifest_path: &Path,
-    sync_dir: &Path,
-    chunk_size: usize,
+    pending_path: &Path,
 ) -> DenebResult<()> {
-    let mut catalog = open_catalog(catalog_type, catalog_path, true)?;
-    populate_with_dir(&mut *catalog, store, sync_dir, chunk_size)?;
-    info!("Catalog populated with contents of {:?}", sync_dir);
-
-    // Save the generated catalog as a content-addressed chunk in the store.
-    let mut f = File::open(catalog_path)?;
-    let chunk_descriptor = store.put_file(&mut f)?;
-
-    // Create and save the repository manifest
-    let manifest = Manifest::new(chunk_descriptor.digest, now_utc()).serialize()?;
-    store.write_special_file(&manifest_path, &mut &manifest[..], false)?;
+    let buf = match store.read_special_file(pending_path) {
+        Ok(buf) => buf,
+        Err(_) => return Ok(()),
+    };
+    let complete = Manifest::deserialize(&buf)
+        .and_then(|manifest| catalog_chunks(store, &manifest.root_hash))
+        .map(|digests| digests.iter().all(|digest| store.chunk(digest).is_ok()))
+        .unwrap_or(false);
+    if complete {
+        warn!("Activating the manifest of an interrupted commit");
+        store.rename_special_file(pending_path, manifest_path)
+    } else {
+        warn!("Discarding the manifest of an interrupted commit");
+        store.remove_special_file(pending_path)
+    }
+}
 
-    Ok(())
+fn log_error<T>(operation: &str, result: DenebResult<T>) -> DenebResult<T> {
+    if let Err(ref e) = result {
+        error!("Error encountered during {}: {}", operation, e);
+    }
+    result
 }
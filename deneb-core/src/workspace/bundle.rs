@@ -0,0 +1,202 @@
+use {
+    super::{delta::referenced_chunks, root::catalog_chunks, MANIFEST_PATH},
+    crate::{
+        crypt::EncryptionKey,
+        errors::{DenebResult, WorkspaceError},
+        manifest::Manifest,
+        store::{open_store, write_bundle, BundleStore, Store, StoreType},
+    },
+    log::info,
+    std::{fs::create_dir_all, path::Path, process},
+};
+
+/// Write the current commit of the repository in `work_dir` into the bundle
+/// `out`, a single file holding its manifest, its catalog and the chunks of
+/// its files
+///
+/// The chunks are written in their packed form, so a bundle of an encrypted
+/// repository can only be read with its encryption key. Returns the number of
+/// chunks written.
+pub fn create_bundle(
+    work_dir: &Path,
+    encryption_key: Option<EncryptionKey>,
+    chunk_size: usize,
+    out: &Path,
+) -> DenebResult<usize> {
+    let store = open_store(
+        StoreType::OnDisk,
+        work_dir,
+        encryption_key,
+        chunk_size,
+        0,
+        false,
+    )?;
+    let buf = store.read_special_file(&work_dir.join(MANIFEST_PATH))?;
+    let manifest = Manifest::deserialize(&buf)?;
+
+    // The catalog is unpacked next to the one of a running engine
+    let scratch_dir = work_dir.join("scratch");
+    create_dir_all(&scratch_dir)?;
+    let catalog_path = scratch_dir.join(format!("bundle_catalog.{}", process::id()));
+    let mut chunks = referenced_chunks(&*store, &manifest.root_hash, &catalog_path)?
+        .into_iter()
+        .collect::<Vec<_>>();
+    chunks.sort_by_key(|digest| digest.to_string());
+
+    write_bundle(out, &buf, &*store, &chunks)?;
+
+    info!(
+        "Bundle of commit {} written to {:?}: {} chunks",
+        manifest.root_hash,
+        out,
+        chunks.len()
+    );
+
+    Ok(chunks.len())
+}
+
+/// Prepare `work_dir` for mounting the bundle `bundle`
+///
+/// As after a thin clone, only the manifest and the catalog are copied into
+/// `work_dir`; the file contents are read from the bundle by a tiered store.
+/// A work dir prepared before is used as it is, as long as it holds the
+/// repository of the bundle. Returns the manifest of the bundle.
+pub fn open_bundle(
+    bundle: &Path,
+    work_dir: &Path,
+    encryption_key: Option<EncryptionKey>,
+    chunk_size: usize,
+) -> DenebResult<Manifest> {
+    let remote = BundleStore::open(bundle, encryption_key, chunk_size)?;
+    let manifest = Manifest::deserialize(remote.manifest())?;
+
+    let manifest_path = work_dir.join(MANIFEST_PATH);
+    if manifest_path.exists() {
+        let local = open_store(StoreType::OnDisk, work_dir, None, chunk_size, 0, false)?;
+        let current = Manifest::deserialize(&local.read_special_file(&manifest_path)?)?;
+        if current.root_hash != manifest.root_hash {
+            return Err(WorkspaceError::BundleMismatch(bundle.to_owned()).into());
+        }
+        return Ok(manifest);
+    }
+
+    let mut local = open_store(
+        StoreType::OnDisk,
+        work_dir,
+        None,
+        manifest.chunk_size.unwrap_or(chunk_size),
+        0,
+        false,
+    )?;
+    for digest in &catalog_chunks(&remote, &manifest.root_hash)? {
+        local.put_packed_chunk(digest, &remote.packed_chunk(digest)?)?;
+    }
+
+    // The manifest is only written once everything it refers to is stored
+    local.sync()?;
+    local.write_special_file(&manifest_path, &mut remote.manifest(), false)?;
+
+    info!("Bundle {:?} opened in {:?}", bundle, work_dir);
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            cas::Algorithm,
+            catalog::CatalogType,
+            store::{RemoteLimits, RemoteStore},
+            workspace::{init_repository, Snapshot, Workspace, WorkspaceOptions},
+            ImportOptions,
+        },
+        std::{ffi::OsStr, fs::write},
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn bundles_are_mounted_from_a_single_file() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_bundle")?;
+        let work_dir = tmp.path().join("work");
+        let mount_dir = tmp.path().join("mount");
+        let bundle = tmp.path().join("dataset.deneb");
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(&sync_dir)?;
+        write(sync_dir.join("file"), "alabalaportocala")?;
+
+        let key = Some(EncryptionKey::new());
+        let open_workspace = |work_dir: &Path, store_type: StoreType| {
+            Workspace::new(
+                CatalogType::Lmdb,
+                store_type,
+                work_dir.to_owned(),
+                WorkspaceOptions {
+                    encryption_key: key.clone(),
+                    chunk_size: 16,
+                    chunk_cache_size: 1024 * 1024,
+                    ..WorkspaceOptions::default()
+                },
+            )
+        };
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            work_dir.clone(),
+            WorkspaceOptions {
+                encryption_key: key.clone(),
+                sync_dir: Some(sync_dir),
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+        let (index, _) = ws.create_file(1, OsStr::new("new"), 0o644, 0)?;
+        ws.write_data(index, 0, b"40 de turcaleti".to_vec())?;
+        ws.commit()?;
+        let root_hash = ws.manifest().root_hash;
+        drop(ws);
+
+        assert!(create_bundle(&work_dir, key.clone(), 16, &bundle)? > 0);
+        let manifest = open_bundle(&bundle, &mount_dir, key.clone(), 16)?;
+        assert_eq!(manifest.root_hash, root_hash);
+        assert_eq!(
+            open_bundle(&bundle, &mount_dir, key.clone(), 16)?.root_hash,
+            root_hash
+        );
+        assert!(open_bundle(&bundle, &work_dir, key.clone(), 16).is_ok());
+
+        let store_type = StoreType::Tiered {
+            remote: RemoteStore::Bundle(bundle.clone()),
+            capacity: 1024 * 1024,
+            limits: RemoteLimits::default(),
+        };
+        let mut ws = open_workspace(&mount_dir, store_type)?;
+        let file = ws
+            .lookup(1, OsStr::new("file"))?
+            .ok_or_else(|| WorkspaceError::FileLookup(0))?;
+        ws.open_file(file.index, 0)?;
+        assert_eq!(
+            ws.read_data(file.index, 0, 64)?,
+            b"alabalaportocala".to_vec()
+        );
+        drop(ws);
+        let snapshot = Snapshot::open(&mount_dir, key.clone(), 16)?;
+        assert_eq!(snapshot.manifest().root_hash, root_hash);
+
+        // Bundles only open in work dirs of the same repository
+        let other_dir = tmp.path().join("other");
+        init_repository(
+            &other_dir,
+            key.clone(),
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default(),
+        )?;
+        assert!(open_bundle(&bundle, &other_dir, key.clone(), 16).is_err());
+
+        Ok(())
+    }
+}
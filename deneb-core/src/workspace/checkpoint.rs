@@ -0,0 +1,330 @@
+use {
+    super::{
+        dir::{DirEntry, Workspace as DirWorkspace},
+        file::{Saved as SavedFile, Workspace as FileWorkspace},
+        inode::Workspace as INodeWorkspace,
+        Workspace,
+    },
+    crate::{
+        cas::Digest,
+        catalog::IndexGenerator,
+        errors::{DenebResult, WorkspaceError},
+        inode::{FileType, INode},
+        store::SpecialFileWriter,
+    },
+    bincode::{deserialize_from, serialize_into},
+    log::{error, info},
+    serde::{Deserialize, Serialize},
+    std::{
+        cmp::max,
+        io::{Read, Write},
+        path::{Path, PathBuf},
+        rc::Rc,
+    },
+};
+
+/// Changes which weren't committed when the workspace was closed
+const CHECKPOINT_PATH: &str = "data/checkpoint";
+const CHECKPOINT_PENDING_PATH: &str = "data/checkpoint.pending";
+/// Where a checkpoint which couldn't be restored is set aside
+const CHECKPOINT_FAILED_PATH: &str = "data/checkpoint.failed";
+
+/// Prefix of a checkpoint
+const CHECKPOINT_MARKER: &[u8] = b"deneb-checkpoint\n";
+
+/// Start of a checkpoint, followed by `files` pairs of an index and the saved
+/// state of a file workspace
+#[derive(Deserialize, Serialize)]
+struct Header {
+    /// Root hash of the commit the changes were made on
+    root_hash: Digest,
+    /// Last inode index handed out
+    last_index: u64,
+    pins: Vec<u64>,
+    /// The dirty inodes
+    inodes: Vec<INode>,
+    /// The entries of the dirty directories
+    dirs: Vec<(u64, Vec<(PathBuf, u64, FileType)>)>,
+    deleted: Vec<u64>,
+    dirty_bytes: u64,
+    files: u64,
+}
+
+/// Save the changes which weren't committed to a checkpoint, from which they
+/// are restored when the workspace is opened again
+///
+/// The dirty files are saved with their piece tables and the data written to
+/// them. The checkpoint is replaced atomically, and it's kept until the
+/// changes are committed, so they also survive a crash after the restart.
+pub(super) fn save_checkpoint(ws: &mut Workspace) -> DenebResult<()> {
+    let files = ws
+        .files
+        .iter()
+        .filter(|(idx, fws)| fws.dirty && !ws.deleted_inodes.contains(idx))
+        .collect::<Vec<_>>();
+    let header = Header {
+        root_hash: ws.manifest.root_hash,
+        last_index: ws.index_generator.current(),
+        pins: ws.catalog.pins()?,
+        inodes: ws.inodes.dirty().map(|iws| iws.inode().clone()).collect(),
+        dirs: ws
+            .dirs
+            .iter()
+            .filter(|(_, dws)| dws.dirty)
+            .map(|(idx, dws)| (*idx, dws.entries_tuple()))
+            .collect(),
+        deleted: ws.deleted_inodes.iter().cloned().collect(),
+        dirty_bytes: ws.dirty_bytes,
+        files: files.len() as u64,
+    };
+
+    // The data written to the files is streamed to the checkpoint, since it
+    // may not fit into memory a second time
+    let pending_path = ws.work_dir.join(CHECKPOINT_PENDING_PATH);
+    let mut store = ws.store.borrow_mut();
+    let mut writer = SpecialFileWriter::new(&mut **store, &pending_path);
+    writer.write_all(CHECKPOINT_MARKER)?;
+    serialize_into(&mut writer, &header)?;
+    for (idx, fws) in files {
+        serialize_into(&mut writer, idx)?;
+        fws.save(&mut writer)?;
+    }
+    writer.finish()?;
+    store.rename_special_file(&pending_path, &ws.work_dir.join(CHECKPOINT_PATH))?;
+    drop(store);
+    ws.checkpointed = true;
+    info!(
+        "Checkpoint saved: {} inodes, {} files",
+        header.inodes.len(),
+        header.files
+    );
+    Ok(())
+}
+
+/// Restore the changes saved to a checkpoint, if there is one
+///
+/// A checkpoint which can't be restored, e.g. because the repository was
+/// committed to by another process since it was saved, is set aside and the
+/// workspace is left as it was committed.
+pub(super) fn load_checkpoint(ws: &mut Workspace) {
+    let path = ws.work_dir.join(CHECKPOINT_PATH);
+    let buf = match ws.store.borrow().read_special_file(&path) {
+        Ok(buf) => buf,
+        Err(_) => return,
+    };
+    let restored =
+        read_checkpoint(&path, &buf).and_then(|(header, files)| restore(ws, header, files));
+    match restored {
+        Ok(files) => {
+            ws.checkpointed = true;
+            info!(
+                "Restored the uncommitted changes of {} files from {:?}",
+                files, path
+            );
+        }
+        Err(e) => {
+            let failed_path = ws.work_dir.join(CHECKPOINT_FAILED_PATH);
+            error!(
+                "Could not restore checkpoint {:?}, moving it to {:?}: {}",
+                path, failed_path, e
+            );
+            if let Err(e) = ws
+                .store
+                .borrow_mut()
+                .rename_special_file(&path, &failed_path)
+            {
+                error!("Could not move checkpoint {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Remove the checkpoint once the changes it holds are committed
+pub(super) fn discard_checkpoint(ws: &mut Workspace) -> DenebResult<()> {
+    if ws.checkpointed {
+        ws.store
+            .borrow_mut()
+            .remove_special_file(&ws.work_dir.join(CHECKPOINT_PATH))?;
+        ws.checkpointed = false;
+    }
+    Ok(())
+}
+
+fn read_checkpoint(path: &Path, mut reader: &[u8]) -> DenebResult<(Header, Vec<(u64, SavedFile)>)> {
+    let mut marker = vec![0; CHECKPOINT_MARKER.len()];
+    reader.read_exact(&mut marker)?;
+    if marker != CHECKPOINT_MARKER {
+        return Err(WorkspaceError::InvalidCheckpoint(path.to_owned()).into());
+    }
+    let header: Header = deserialize_from(&mut reader)?;
+    let mut files = Vec::new();
+    for _ in 0..header.files {
+        files.push(deserialize_from(&mut reader)?);
+    }
+    Ok((header, files))
+}
+
+// Nothing is changed unless the whole checkpoint could be read and it was
+// saved on the current commit
+fn restore(ws: &mut Workspace, header: Header, files: Vec<(u64, SavedFile)>) -> DenebResult<usize> {
+    if header.root_hash != ws.manifest.root_hash {
+        return Err(WorkspaceError::CheckpointBase(header.root_hash.to_string()).into());
+    }
+    ws.catalog.set_pins(&header.pins)?;
+    for inode in header.inodes {
+        ws.inodes
+            .insert(inode.attributes.index, INodeWorkspace::new(inode, true));
+    }
+    for (idx, entries) in header.dirs {
+        let entries = entries
+            .into_iter()
+            .map(|(name, index, kind)| DirEntry::new(index, name, kind))
+            .collect::<Vec<_>>();
+        let mut dws = DirWorkspace::new(&entries);
+        dws.dirty = true;
+        ws.dirs.insert(idx, dws);
+    }
+    let restored = files.len();
+    for (idx, saved) in files {
+        ws.files
+            .insert(idx, FileWorkspace::restore(saved, Rc::clone(&ws.store)));
+    }
+    ws.deleted_inodes.extend(header.deleted);
+    ws.index_generator =
+        IndexGenerator::starting_at(max(ws.index_generator.current(), header.last_index));
+    ws.dirty_bytes = header.dirty_bytes;
+    ws.dirty = true;
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            cas::Algorithm,
+            catalog::CatalogType,
+            store::StoreType,
+            workspace::{init_repository, WorkspaceOptions},
+            ImportOptions,
+        },
+        std::ffi::OsStr,
+        tempdir::TempDir,
+    };
+
+    fn open_workspace(work_dir: &Path) -> DenebResult<Workspace> {
+        Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            work_dir.to_owned(),
+            WorkspaceOptions {
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )
+    }
+
+    #[test]
+    fn uncommitted_changes_are_restored_from_a_checkpoint() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_checkpoint")?;
+        init_repository(
+            tmp.path(),
+            None,
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default(),
+        )?;
+        let (kept, removed, created) = {
+            let mut ws = open_workspace(tmp.path())?;
+            let (kept, _) = ws.create_file(1, OsStr::new("kept"), 0o644, 0)?;
+            let (removed, _) = ws.create_file(1, OsStr::new("removed"), 0o644, 0)?;
+            ws.write_data(kept, 0, b"alabalaportocala".to_vec())?;
+            ws.commit()?;
+
+            ws.write_data(kept, 5, b"PORTOCALA, 40 de turcaleti".to_vec())?;
+            ws.remove(1, OsStr::new("removed"))?;
+            assert!(ws.lookup(1, OsStr::new("removed"))?.is_none());
+            let (created, _) = ws.create_file(1, OsStr::new("created"), 0o644, 0)?;
+            ws.write_data(created, 0, b"new".to_vec())?;
+            ws.save_checkpoint()?;
+            (kept, removed, created)
+        };
+
+        let mut ws = open_workspace(tmp.path())?;
+        assert!(ws.is_dirty());
+        let data = ws.read_data(kept, 0, 64)?;
+        assert_eq!(b"alabaPORTOCALA, 40 de turcaleti", data.as_slice());
+        assert_eq!(b"new", ws.read_data(created, 0, 64)?.as_slice());
+        assert!(ws.lookup(1, OsStr::new("removed"))?.is_none());
+        let (next, _) = ws.create_file(1, OsStr::new("next"), 0o644, 0)?;
+        assert!(next > created);
+
+        // The checkpoint is gone once the changes are committed
+        ws.commit()?;
+        drop(ws);
+        let mut ws = open_workspace(tmp.path())?;
+        assert!(!ws.is_dirty());
+        ws.open_file(created, 0)?;
+        assert_eq!(b"new", ws.read_data(created, 0, 64)?.as_slice());
+        // The removal was restored and committed too
+        assert!(ws.lookup(1, OsStr::new("removed"))?.is_none());
+        assert!(ws.get_attr(removed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn large_checkpoints_are_restored() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_checkpoint_large")?;
+        init_repository(
+            tmp.path(),
+            None,
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default(),
+        )?;
+        // Written to the checkpoint in more than one piece
+        let data = (0..5 * 1024 * 1024).map(|i| i as u8).collect::<Vec<u8>>();
+        let index = {
+            let mut ws = open_workspace(tmp.path())?;
+            let (index, _) = ws.create_file(1, OsStr::new("large"), 0o644, 0)?;
+            ws.write_data(index, 0, data.clone())?;
+            ws.save_checkpoint()?;
+            index
+        };
+
+        let ws = open_workspace(tmp.path())?;
+        assert_eq!(ws.read_data(index, 0, data.len() as u32)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoints_of_other_commits_are_set_aside() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_checkpoint_stale")?;
+        init_repository(
+            tmp.path(),
+            None,
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default(),
+        )?;
+        {
+            let mut ws = open_workspace(tmp.path())?;
+            let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+            ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+            ws.save_checkpoint()?;
+            // The changes are committed without the checkpoint being discarded
+            ws.checkpointed = false;
+            ws.commit()?;
+        }
+
+        let ws = open_workspace(tmp.path())?;
+        assert!(!ws.is_dirty());
+        assert!(tmp.path().join(CHECKPOINT_FAILED_PATH).exists());
+        assert!(!tmp.path().join(CHECKPOINT_PATH).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use {
+    super::{
+        delta::referenced_chunks,
+        reflog::{self, Entry},
+        root::catalog_chunks,
+        MANIFEST_PATH, REFLOG_PATH,
+    },
+    crate::{
+        crypt::EncryptionKey,
+        errors::{DenebResult, WorkspaceError},
+        manifest::Manifest,
+        store::{open_remote_store, open_store, StoreType},
+    },
+    log::info,
+    std::{
+        collections::HashSet,
+        fs::create_dir_all,
+        path::{Path, PathBuf},
+        process,
+    },
+};
+
+/// Initialize a work dir with the current state of the repository in `remote_dir`
+///
+/// The manifest and the catalog of the remote repository are copied into
+/// `work_dir`. A full clone also copies all the chunks and the reflog, while
+/// a thin clone is meant to be used with a tiered store, which fetches the
+/// chunks of the files from the remote repository when they are first read.
+///
+/// With a `depth`, the clone is shallow: only the current commit and the
+/// `depth - 1` most recent commits of the reflog are kept, together with the
+/// chunks they reference. Returns the number of chunks copied.
+pub fn clone_repository(
+    remote_dir: &Path,
+    work_dir: &Path,
+    encryption_key: Option<EncryptionKey>,
+    chunk_size: usize,
+    thin: bool,
+    depth: Option<usize>,
+) -> DenebResult<usize> {
+    let manifest_path = work_dir.join(MANIFEST_PATH);
+    if manifest_path.exists() {
+        return Err(WorkspaceError::AlreadyInitialized(work_dir.to_owned()).into());
+    }
+
+    let remote = open_remote_store(remote_dir, work_dir, encryption_key.clone(), chunk_size)?;
+    let mut local = open_store(
+        StoreType::OnDisk,
+        work_dir,
+        encryption_key,
+        chunk_size,
+        0,
+        false,
+    )?;
+
+    let buf = remote.read_special_file(&remote_dir.join(MANIFEST_PATH))?;
+    let manifest = Manifest::deserialize(&buf)?;
+
+    let remote_reflog = remote.read_special_file(&remote_dir.join(REFLOG_PATH)).ok();
+    let digests = match depth {
+        Some(depth) => {
+            // The reflog lists the replaced commits from the oldest to the newest
+            let entries = match remote_reflog {
+                Some(ref buf) => reflog::parse(buf)?,
+                None => Vec::new(),
+            };
+            let kept = &entries[entries.len().saturating_sub(depth.max(1) - 1)..];
+            let mut root_hashes = kept.iter().map(|entry| entry.root_hash).collect::<Vec<_>>();
+            root_hashes.push(manifest.root_hash);
+
+            let mut digests = HashSet::new();
+            let scratch_dir = work_dir.join("scratch");
+            create_dir_all(&scratch_dir)?;
+            let catalog_path = scratch_dir.join(format!("clone_catalog.{}", process::id()));
+            for root_hash in &root_hashes {
+                if thin {
+                    digests.extend(catalog_chunks(&*remote, root_hash)?);
+                } else {
+                    digests.extend(referenced_chunks(&*remote, root_hash, &catalog_path)?);
+                }
+            }
+
+            let buf = kept.iter().map(Entry::to_string).collect::<String>();
+            local.write_special_file(&PathBuf::from(REFLOG_PATH), &mut buf.as_bytes(), false)?;
+            digests.into_iter().collect()
+        }
+        None if thin => catalog_chunks(&*remote, &manifest.root_hash)?,
+        None => {
+            if let Some(ref reflog) = remote_reflog {
+                local.write_special_file(&PathBuf::from(REFLOG_PATH), &mut &reflog[..], false)?;
+            }
+            remote.chunk_digests()?
+        }
+    };
+    for digest in &digests {
+        local.put_packed_chunk(digest, &remote.packed_chunk(digest)?)?;
+    }
+
+    // The manifest is only written once everything it refers to is stored
+    local.sync()?;
+    local.write_special_file(&manifest_path, &mut &buf[..], false)?;
+
+    info!(
+        "Cloned {:?} into {:?}: {} chunks copied",
+        remote_dir,
+        work_dir,
+        digests.len()
+    );
+
+    Ok(digests.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            cas::Algorithm,
+            catalog::CatalogType,
+            store::{RemoteLimits, RemoteStore},
+            workspace::{init_repository, Workspace, WorkspaceOptions},
+            ImportOptions,
+        },
+        std::ffi::OsStr,
+        tempdir::TempDir,
+    };
+
+    fn open_workspace(work_dir: &Path, store_type: StoreType) -> DenebResult<Workspace> {
+        Workspace::new(
+            CatalogType::Lmdb,
+            store_type,
+            work_dir.to_owned(),
+            WorkspaceOptions {
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )
+    }
+
+    #[test]
+    fn thin_clone_fetches_chunks_on_demand() -> DenebResult<()> {
+        let remote_dir = TempDir::new("deneb_clone_remote")?;
+        init_repository(
+            remote_dir.path(),
+            None,
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default(),
+        )?;
+        let index = {
+            let mut ws = open_workspace(remote_dir.path(), StoreType::OnDisk)?;
+            let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+            ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+            ws.commit()?;
+            index
+        };
+
+        let work_dir = TempDir::new("deneb_clone_local")?;
+        let copied = clone_repository(remote_dir.path(), work_dir.path(), None, 16, true, None)?;
+        assert_eq!(copied, 2);
+        assert!(
+            clone_repository(remote_dir.path(), work_dir.path(), None, 16, true, None).is_err()
+        );
+
+        let store_type = StoreType::Tiered {
+            remote: RemoteStore::Dir(remote_dir.path().to_owned()),
+            capacity: 1024 * 1024,
+            limits: RemoteLimits::default(),
+        };
+        let mut ws = open_workspace(work_dir.path(), store_type)?;
+        ws.open_file(index, 0)?;
+        assert_eq!(ws.read_data(index, 0, 16)?, b"alabalaportocala".to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn shallow_clone_keeps_the_recent_commits() -> DenebResult<()> {
+        let remote_dir = TempDir::new("deneb_shallow_remote")?;
+        init_repository(
+            remote_dir.path(),
+            None,
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default(),
+        )?;
+        let (index, root_hashes) = {
+            let mut ws = open_workspace(remote_dir.path(), StoreType::OnDisk)?;
+            let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+            for data in &[
+                b"alabalaportocala",
+                b"40 de turcaleti!",
+                b"ana are mere ...",
+            ] {
+                ws.write_data(index, 0, data.to_vec())?;
+                ws.commit()?;
+            }
+            let root_hashes = ws
+                .log()?
+                .iter()
+                .map(|manifest| manifest.root_hash)
+                .collect::<Vec<_>>();
+            (index, root_hashes)
+        };
+
+        let full_dir = TempDir::new("deneb_shallow_full")?;
+        let full = clone_repository(remote_dir.path(), full_dir.path(), None, 16, false, None)?;
+        let work_dir = TempDir::new("deneb_shallow_local")?;
+        let copied =
+            clone_repository(remote_dir.path(), work_dir.path(), None, 16, false, Some(2))?;
+        assert!(copied < full);
+
+        let mut ws = open_workspace(work_dir.path(), StoreType::OnDisk)?;
+        let log = ws
+            .log()?
+            .iter()
+            .map(|manifest| manifest.root_hash)
+            .collect::<Vec<_>>();
+        assert_eq!(log, root_hashes[..2].to_vec());
+        ws.open_file(index, 0)?;
+        assert_eq!(ws.read_data(index, 0, 16)?, b"ana are mere ...".to_vec());
+
+        Ok(())
+    }
+}
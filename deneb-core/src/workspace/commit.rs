@@ -1,27 +1,46 @@
 use {
-    super::{Workspace, MANIFEST_PATH, REFLOG_PATH},
+    super::{
+        checkpoint::discard_checkpoint, pin::refresh_pins, reflog, root::write_catalog, Workspace,
+        MANIFEST_PATH, MANIFEST_PENDING_PATH, REFLOG_PATH,
+    },
     crate::{
-        errors::DenebResult, inode::ChunkDescriptor, workspace::inode::Workspace as INodeWorkspace,
+        cas::{read_chunked, Digest, Hasher},
+        errors::DenebResult,
+        inode::{blocks_for, ChunkDescriptor, FileType, INode},
+        manifest::{Chunking, Manifest, Metadata},
+        util::{hostname, username},
+        workspace::{file::Workspace as FileWorkspace, inode::Workspace as INodeWorkspace},
     },
+    log::warn,
+    serde::{Deserialize, Serialize},
     std::{
-        collections::HashMap,
+        collections::BTreeMap,
         fmt::{Display, Formatter, Result as FmtResult},
-        fs::File,
-        io::Write,
         path::PathBuf,
+        rc::Rc,
     },
     time::now_utc,
 };
 
-#[derive(Debug)]
-pub(in crate) struct Summary {
-    noop: bool,
-    inodes_deleted: usize,
-    inodes_updated: usize,
-    files_written: usize,
-    chunks_written: usize,
-    dir_entries_added: usize,
-    new_root_hash: Option<String>,
+/// What was written by a commit
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Summary {
+    /// Whether there was nothing to commit
+    pub noop: bool,
+    /// Whether the changes left the catalog as it was in the previous commit,
+    /// in which case no new commit was recorded
+    pub clean: bool,
+    pub inodes_deleted: usize,
+    pub inodes_updated: usize,
+    pub files_written: usize,
+    pub chunks_written: usize,
+    pub dir_entries_added: usize,
+    /// Catalog chunks put into the store
+    pub catalog_chunks_written: usize,
+    /// Catalog chunks unchanged since the previous commit, which were not written again
+    pub catalog_chunks_reused: usize,
+    pub catalog_bytes_reused: usize,
+    pub new_root_hash: Option<String>,
 }
 
 impl Summary {
@@ -34,11 +53,15 @@ impl Default for Summary {
     fn default() -> Summary {
         Summary {
             noop: true,
+            clean: false,
             inodes_deleted: 0,
             inodes_updated: 0,
             files_written: 0,
             chunks_written: 0,
             dir_entries_added: 0,
+            catalog_chunks_written: 0,
+            catalog_chunks_reused: 0,
+            catalog_bytes_reused: 0,
             new_root_hash: None,
         }
     }
@@ -46,17 +69,148 @@ impl Default for Summary {
 
 impl Display for Summary {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        write!(f, "{:#?}", self)
+        if self.noop {
+            return writeln!(f, "Nothing to commit");
+        }
+        if self.clean {
+            return writeln!(f, "Nothing changed since the last commit");
+        }
+        if let Some(ref root_hash) = self.new_root_hash {
+            writeln!(f, "Root hash: {}", root_hash)?;
+        }
+        writeln!(
+            f,
+            "Files written: {}, chunks: {}",
+            self.files_written, self.chunks_written
+        )?;
+        writeln!(
+            f,
+            "Inodes updated: {}, deleted: {}, directory entries added: {}",
+            self.inodes_updated, self.inodes_deleted, self.dir_entries_added
+        )?;
+        writeln!(
+            f,
+            "Catalog chunks written: {}, reused: {} ({} bytes)",
+            self.catalog_chunks_written, self.catalog_chunks_reused, self.catalog_bytes_reused
+        )
     }
 }
 
-struct Updates {
-    delete_indices: Vec<u64>,
-    new_chunks: HashMap<u64, (u64, Vec<ChunkDescriptor>)>,
+/// A commit which is performed one chunk at a time
+///
+/// Other requests can be handled between the steps of the commit. The dirty
+/// files are frozen when the commit starts, so the commit writes their
+/// contents as they were then while they keep being written to. Files
+/// modified in the meantime are written again when the commit is finished.
+pub(in crate) struct PendingCommit {
+    message: Option<String>,
+    files: Vec<(u64, FileWorkspace)>,
+    current: Option<FrozenFile>,
+    summary: Summary,
+}
+
+/// A frozen file which is being written to the store
+struct FrozenFile {
+    index: u64,
+    contents: FileWorkspace,
+    /// Offset of the next chunk
+    offset: u64,
+    chunks: Vec<ChunkDescriptor>,
+    hasher: Hasher,
+}
+
+impl PendingCommit {
+    /// Number of files left to write
+    pub(in crate) fn pending_files(&self) -> usize {
+        self.files.len() + self.current.iter().count()
+    }
 }
 
+#[cfg(test)]
 pub(super) fn commit_workspace(ws: &mut Workspace) -> DenebResult<Summary> {
-    let mut summary = Summary::new();
+    let mut commit = begin_commit(ws, None);
+    while commit_next_step(ws, &mut commit)? {}
+    finish_commit(ws, commit)
+}
+
+/// Start a commit, freezing the dirty files. Commits without a message are
+/// given a generated one.
+pub(super) fn begin_commit(ws: &Workspace, message: Option<String>) -> PendingCommit {
+    PendingCommit {
+        message,
+        files: dirty_files(ws)
+            .into_iter()
+            .filter_map(|idx| ws.files.get(&idx).map(|fws| (idx, fws.freeze())))
+            .collect(),
+        current: None,
+        summary: Summary::new(),
+    }
+}
+
+/// Write the next chunk of the frozen files to the store
+///
+/// Returns `false` once there are no chunks left. Files which were removed
+/// since the commit started are skipped.
+pub(super) fn commit_next_step(
+    ws: &mut Workspace,
+    commit: &mut PendingCommit,
+) -> DenebResult<bool> {
+    let current = commit
+        .current
+        .take()
+        .filter(|file| !ws.deleted_inodes.contains(&file.index));
+    let mut file = match current {
+        Some(file) => file,
+        None => loop {
+            match commit.files.pop() {
+                Some((index, _)) if ws.deleted_inodes.contains(&index) => continue,
+                Some((index, contents)) => {
+                    break FrozenFile {
+                        index,
+                        contents,
+                        offset: 0,
+                        chunks: Vec::new(),
+                        hasher: Hasher::new(),
+                    }
+                }
+                None => return Ok(false),
+            }
+        },
+    };
+
+    let chunk_size = ws.store.borrow().chunk_size();
+    let data = file.contents.read_at(file.offset as usize, chunk_size)?;
+    if !data.is_empty() {
+        file.hasher.update(&data);
+        file.chunks.push(ws.store.borrow_mut().put_chunk(&data)?);
+        file.offset += data.len() as u64;
+    }
+    if file.offset < file.contents.size {
+        commit.current = Some(file);
+        return Ok(true);
+    }
+
+    commit.summary.chunks_written += file.chunks.len();
+    commit.summary.files_written += 1;
+    // Files modified since they were frozen keep their changes
+    let unchanged = ws
+        .files
+        .get(&file.index)
+        .map_or(false, |fws| fws.dirty && fws.stamp == file.contents.stamp);
+    if unchanged {
+        let digest = file.hasher.finish();
+        replace_contents(ws, file.index, file.contents.size, file.chunks, digest)?;
+    }
+    Ok(true)
+}
+
+pub(super) fn finish_commit(ws: &mut Workspace, commit: PendingCommit) -> DenebResult<Summary> {
+    let PendingCommit {
+        message,
+        mut summary,
+        ..
+    } = commit;
+    let mut buffer = vec![0 as u8; ws.store.borrow().chunk_size()];
 
     if !ws.dirty {
         return Ok(summary);
@@ -64,17 +218,25 @@ pub(super) fn commit_workspace(ws: &mut Workspace) -> DenebResult<Summary> {
 
     summary.noop = false;
 
-    prune_inodes(ws, &mut summary)?;
+    let mut changes = Changes::default();
+    prune_inodes(ws, &mut summary, &mut changes)?;
 
-    let updates = write_file_data(ws, &mut summary)?;
-    update_chunks(ws, &updates.new_chunks, &mut summary)?;
+    // Files which were modified after the commit was started
+    for idx in dirty_files(ws) {
+        write_file_data(ws, idx, &mut buffer, &mut summary)?;
+    }
 
-    write_inodes(ws, &mut summary)?;
+    write_inodes(ws, &mut summary, &mut changes)?;
     write_dirs(ws, &mut summary)?;
 
-    finalize(ws, &mut summary)?;
+    finalize(ws, message, &changes, &mut summary)?;
 
-    cleanup_workspace(ws, &updates.delete_indices)?;
+    cleanup_workspace(ws)?;
+
+    // The chunks under the pinned entries may have changed
+    if let Err(e) = refresh_pins(ws) {
+        warn!("Could not refresh the pinned chunks: {}", e);
+    }
 
     Ok(summary)
 }
@@ -83,11 +245,18 @@ pub(super) fn commit_workspace(ws: &mut Workspace) -> DenebResult<Summary> {
 /// - remove the corresponding file, directory and inode workspaces
 /// - update any dir workspaces that had the inodes as children
 /// - remove the inode entries from the catalog
-fn prune_inodes(ws: &mut Workspace, summary: &mut Summary) -> DenebResult<()> {
+fn prune_inodes(
+    ws: &mut Workspace,
+    summary: &mut Summary,
+    changes: &mut Changes,
+) -> DenebResult<()> {
     for idx in &ws.deleted_inodes {
+        if let Ok(stored) = ws.catalog.inode(*idx) {
+            changes.count(&stored, -1);
+        }
         ws.files.remove(idx);
         ws.dirs.remove(idx);
-        ws.inodes.remove(idx);
+        ws.inodes.remove(*idx);
         ws.dirs.iter_mut().for_each(|(_, dws)| {
             dws.remove_entry_idx(*idx);
         });
@@ -97,103 +266,594 @@ fn prune_inodes(ws: &mut Workspace, summary: &mut Summary) -> DenebResult<()> {
     Ok(())
 }
 
-/// Iterate over the remaining dirty file workspaces and write them to the
-/// store. For each file, the resulting chunks should be associated with the
-/// inode workspace. Once written, the file workspace should be deleted, as
-/// it needs to be rebuilt with the new chunks as lower level
-fn write_file_data(ws: &mut Workspace, summary: &mut Summary) -> DenebResult<Updates> {
-    let mut delete_indices = Vec::new();
-    let mut new_chunks = HashMap::new();
-    let mut store = ws.store.borrow_mut();
-    for (idx, fws) in &ws.files {
-        if fws.dirty {
-            let mut rdr = fws.reader();
-            let chunks = store.put_file_chunked(&mut rdr)?;
-            summary.chunks_written += chunks.len();
-            new_chunks.insert(*idx, (fws.size, chunks));
-            delete_indices.push(*idx);
-            summary.files_written += 1;
-        }
-    }
+/// Write the data of a file modified since the last commit to the store, and
+/// sync the store
+///
+/// The inode of the file is still written by the next commit, but its
+/// contents no longer depend on the file workspace.
+pub(super) fn flush_file(ws: &mut Workspace, idx: u64) -> DenebResult<()> {
+    let mut buffer = vec![0 as u8; ws.store.borrow().chunk_size()];
+    write_file_data(ws, idx, &mut buffer, &mut Summary::new())?;
+    ws.store.borrow_mut().sync()
+}
 
-    Ok(Updates {
-        delete_indices,
-        new_chunks,
-    })
+fn dirty_files(ws: &Workspace) -> Vec<u64> {
+    ws.files
+        .iter()
+        .filter(|(idx, fws)| fws.dirty && !ws.deleted_inodes.contains(idx))
+        .map(|(idx, _)| *idx)
+        .collect()
 }
 
-fn update_chunks(
+/// Write a dirty file workspace to the store. The data of the file is read
+/// and written one chunk at a time, so only a single chunk needs to be held
+/// in memory. Files which are no longer dirty, or which were removed, are
+/// skipped.
+fn write_file_data(
     ws: &mut Workspace,
-    new_chunks: &HashMap<u64, (u64, Vec<ChunkDescriptor>)>,
-    _summary: &mut Summary,
+    idx: u64,
+    buffer: &mut [u8],
+    summary: &mut Summary,
 ) -> DenebResult<()> {
-    for (idx, (file_size, chunks)) in new_chunks {
-        let mut inode = ws.inode_ws(*idx)?.inode().clone();
-        inode.attributes.size = *file_size;
-        inode.chunks = chunks.clone();
-        ws.inodes.insert(*idx, INodeWorkspace::new(inode, true));
-    }
+    let (file_size, chunks, digest) = match ws.files.get(&idx) {
+        Some(fws) if fws.dirty && !ws.deleted_inodes.contains(&idx) => {
+            let store = &ws.store;
+            let mut chunks = Vec::new();
+            let mut hasher = Hasher::new();
+            // The store is only borrowed while a chunk is written, since reading
+            // from the file workspace may need to load chunks from the store
+            read_chunked(fws.reader(), buffer, |s| {
+                hasher.update(s);
+                chunks.push(store.borrow_mut().put_chunk(s)?);
+                Ok(())
+            })?;
+            (fws.size, chunks, hasher.finish())
+        }
+        _ => return Ok(()),
+    };
+    summary.chunks_written += chunks.len();
+    summary.files_written += 1;
+    replace_contents(ws, idx, file_size, chunks, digest)
+}
+
+/// Associate the chunks written for a file with its inode workspace, and
+/// rebuild its file workspace with the new chunks as lower level, which
+/// releases the memory held by its upper level. The digest of the whole
+/// contents is recorded in the inode.
+fn replace_contents(
+    ws: &mut Workspace,
+    idx: u64,
+    file_size: u64,
+    chunks: Vec<ChunkDescriptor>,
+    digest: Digest,
+) -> DenebResult<()> {
+    let mut inode = ws.inode_ws(idx)?.inode().clone();
+    inode.attributes.size = file_size;
+    inode.attributes.blocks = blocks_for(chunks.iter().map(|chunk| chunk.size as u64).sum());
+    inode.chunks = chunks;
+    inode.digest = Some(digest);
+    let fws = FileWorkspace::try_new(&inode, Rc::clone(&ws.store), false)?;
+    ws.files.insert(idx, fws);
+    ws.inodes.insert(idx, INodeWorkspace::new(inode, true));
     Ok(())
 }
 
-// Write directory workspaces to the catalog
+// Write directory workspaces to the catalog, unlinking the entries which were
+// moved elsewhere. Entries which are already in the catalog are not written
+// again, so that the catalog is left as it was when nothing changed.
 fn write_dirs(ws: &mut Workspace, summary: &mut Summary) -> DenebResult<()> {
     for (idx, dws) in &ws.dirs {
         if dws.dirty {
+            let stored = ws
+                .catalog
+                .dir_entries(*idx)
+                .unwrap_or_default()
+                .into_iter()
+                .collect::<BTreeMap<_, _>>();
+            for name in stored.keys().filter(|name| dws.entry(name).is_none()) {
+                ws.catalog.remove_dir_entry(*idx, name)?;
+            }
             for (name, entry_index, _) in dws.entries_tuple() {
-                ws.catalog.add_dir_entry(*idx, &name, entry_index)?;
-                summary.dir_entries_added += 1;
+                if stored.get(&name) != Some(&entry_index) {
+                    ws.catalog.add_dir_entry(*idx, &name, entry_index)?;
+                    summary.dir_entries_added += 1;
+                }
             }
         }
     }
     Ok(())
 }
 
-// Write inode workspaces to the catalog
-fn write_inodes(ws: &mut Workspace, summary: &mut Summary) -> DenebResult<()> {
-    for iws in ws.inodes.values() {
-        if iws.dirty {
-            ws.catalog.add_inode(iws.inode())?;
-            summary.inodes_updated += 1;
+// Write dirty inode workspaces to the catalog, after which they can be evicted.
+// As with the directories, unchanged inodes are not written again.
+fn write_inodes(
+    ws: &mut Workspace,
+    summary: &mut Summary,
+    changes: &mut Changes,
+) -> DenebResult<()> {
+    for iws in ws.inodes.dirty() {
+        let inode = iws.inode();
+        match ws.catalog.inode(inode.attributes.index) {
+            Ok(ref stored) if stored == inode => continue,
+            Ok(ref stored) => changes.count(stored, -1),
+            Err(_) => {}
         }
+        ws.catalog.add_inode(inode)?;
+        changes.count(inode, 1);
+        summary.inodes_updated += 1;
     }
+    ws.inodes.mark_clean();
     Ok(())
 }
 
 // Finalize commit: write the new catalog into storage, write the old root hash
 // to the reflog, write the new manifest
-fn finalize(ws: &mut Workspace, summary: &mut Summary) -> DenebResult<()> {
+//
+// The steps are ordered such that a crash at any point leaves a manifest
+// pointing to a catalog which is in the store:
+// - the new catalog is written and the store is synced
+// - the old root hash is appended to the reflog
+// - the new manifest is written under a temporary name (prepare)
+// - the temporary manifest replaces the current one (activate)
+// A commit interrupted after the prepare step is completed when the workspace
+// is next opened.
+fn finalize(
+    ws: &mut Workspace,
+    message: Option<String>,
+    changes: &Changes,
+    summary: &mut Summary,
+) -> DenebResult<()> {
     let mut store = ws.store.borrow_mut();
 
-    // Save the generated catalog as a content-addressed chunk in the store.
+    // Save the generated catalog as content-addressed chunks in the store,
+    // skipping the chunks which didn't change since the previous commit
     let catalog_path = ws.work_dir.join("scratch/current_catalog");
-    let mut f = File::open(catalog_path.as_path())?;
+    ws.catalog.persist()?;
+    let catalog = write_catalog(
+        &mut **store,
+        catalog_path.as_path(),
+        Some(&ws.manifest.root_hash),
+    )?;
+    store.sync()?;
+    summary.catalog_chunks_written = catalog.chunks_written;
+    summary.catalog_chunks_reused = catalog.chunks_reused;
+    summary.catalog_bytes_reused = catalog.bytes_reused;
 
-    let chunk_descriptor = store.put_file(&mut f)?;
+    // Changes which were reverted, e.g. a file written back with its previous
+    // contents and attributes, don't make a new commit
+    if catalog.root_hash == ws.manifest.root_hash {
+        summary.clean = true;
+        return Ok(());
+    }
 
-    // Write the old root hash to the reflog
+    // Write the old root hash to the reflog and drop the entries which are
+    // no longer retained
     let ref_log_path = PathBuf::from(REFLOG_PATH);
-    let mut ref_log = Vec::new();
-    writeln!(ref_log, "{}", ws.manifest.root_hash)?;
-    store.write_special_file(&ref_log_path, &mut (&ref_log[..]), true)?;
+    let now = now_utc();
+    let now_secs = now.to_timespec().sec;
+    let entry = reflog::Entry::replacing(&ws.manifest, now_secs);
+    reflog::append(&mut **store, &ref_log_path, &entry)?;
+    reflog::compact(&mut **store, &ref_log_path, ws.retention, now_secs)?;
 
     // Create and save the repository manifest
-    ws.manifest.root_hash = chunk_descriptor.digest;
-    ws.manifest.timestamp = now_utc();
+    let message = message.unwrap_or_else(|| {
+        format!(
+            "Automatic commit: {} files written, {} inodes deleted",
+            summary.files_written, summary.inodes_deleted
+        )
+    });
+    let manifest = Manifest::new(catalog.root_hash, now)
+        .with_chunking(store.chunk_size(), Chunking::Fixed)
+        .with_algorithm(store.algorithm())
+        .with_metadata(metadata(ws, message, changes));
+    let pending_path = ws.work_dir.join(MANIFEST_PENDING_PATH);
+    let buf = manifest.serialize()?;
+    store.write_special_file(&pending_path, &mut (&buf[..]), false)?;
+
     let manifest_path = ws.work_dir.join(MANIFEST_PATH);
-    let buf = ws.manifest.serialize()?;
-    store.write_special_file(&manifest_path, &mut (&buf[..]), false)?;
+    store.rename_special_file(&pending_path, &manifest_path)?;
+    ws.manifest = manifest;
 
     summary.new_root_hash = Some(ws.manifest.root_hash.to_string());
 
     Ok(())
 }
 
-fn cleanup_workspace(ws: &mut Workspace, delete_idx: &[u64]) -> DenebResult<()> {
-    for idx in delete_idx {
-        ws.files.remove(&idx);
+/// How the statistics of the catalog were changed by a commit
+#[derive(Default)]
+struct Changes {
+    files: i64,
+    dirs: i64,
+    logical_size: i64,
+    chunks: i64,
+}
+
+impl Changes {
+    /// Count an inode written to the catalog (`sign` of 1) or one replaced or
+    /// removed from it (`sign` of -1)
+    fn count(&mut self, inode: &INode, sign: i64) {
+        if inode.attributes.kind == FileType::Directory {
+            self.dirs += sign;
+        } else {
+            self.files += sign;
+            self.logical_size += sign * inode.attributes.size as i64;
+        }
+        self.chunks += sign * inode.chunks.len() as i64;
+    }
+
+    fn apply(&self, metadata: &mut Metadata) {
+        let apply = |count: &mut u64, change: i64| *count = (*count as i64 + change).max(0) as u64;
+        apply(&mut metadata.files, self.files);
+        apply(&mut metadata.dirs, self.dirs);
+        apply(&mut metadata.logical_size, self.logical_size);
+        apply(&mut metadata.chunks, self.chunks);
     }
+}
+
+// Gather the statistics of the committed catalog, from those of the previous
+// commit and the changes made since. The whole catalog is only read when the
+// previous manifest has no statistics.
+fn metadata(ws: &Workspace, message: String, changes: &Changes) -> Metadata {
+    let mut metadata = match ws.manifest.metadata {
+        Some(ref previous) => {
+            let mut metadata = previous.clone();
+            changes.apply(&mut metadata);
+            metadata
+        }
+        None => {
+            let mut totals = Changes::default();
+            for index in 1..=ws.catalog.max_index() {
+                if let Ok(inode) = ws.catalog.inode(index) {
+                    totals.count(&inode, 1);
+                }
+            }
+            let mut metadata = Metadata::default();
+            totals.apply(&mut metadata);
+            metadata
+        }
+    };
+    metadata.hostname = hostname();
+    metadata.author = format!("{}@{}", username(), metadata.hostname);
+    metadata.version = env!("CARGO_PKG_VERSION").to_owned();
+    metadata.message = message;
+    metadata
+}
+
+fn cleanup_workspace(ws: &mut Workspace) -> DenebResult<()> {
     ws.deleted_inodes.clear();
+    ws.dirty_bytes = 0;
     ws.dirty = false;
+    // The changes saved to the checkpoint are committed now
+    if let Err(e) = discard_checkpoint(ws) {
+        warn!("Could not remove the checkpoint: {}", e);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            cas::{hash, Algorithm},
+            catalog::CatalogType,
+            inode::FileAttributeChanges,
+            store::StoreType,
+            util::atomic_write,
+            workspace::{init_repository, WorkspaceOptions},
+            ImportOptions,
+        },
+        std::{ffi::OsStr, path::Path},
+        tempdir::TempDir,
+        time::Duration,
+    };
+
+    fn open_workspace(work_dir: &Path) -> DenebResult<Workspace> {
+        Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            work_dir.to_owned(),
+            WorkspaceOptions {
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )
+    }
+
+    fn init_workspace(work_dir: &Path) -> DenebResult<Workspace> {
+        init_repository(
+            work_dir,
+            None,
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default(),
+        )?;
+        open_workspace(work_dir)
+    }
+
+    #[test]
+    fn committed_files_remain_readable() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_commit")?;
+        let mut ws = init_workspace(tmp.path())?;
+
+        let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+        ws.write_data(index, 0, b"alabalaportocala, 40 de turcaleti".to_vec())?;
+        assert_eq!(ws.dirty_bytes(), 33);
+
+        let summary = ws.commit()?;
+        assert_eq!(summary.files_written, 1);
+        assert_eq!(summary.chunks_written, 3);
+        assert!(summary
+            .to_string()
+            .contains("Files written: 1, chunks: 3\n"));
+        assert_eq!(ws.dirty_bytes(), 0);
+        assert_eq!(ws.commit()?.to_string(), "Nothing to commit\n");
+
+        let data = ws.read_data(index, 7, 8)?;
+        assert_eq!(b"portocal", data.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reverted_changes_are_not_committed() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_commit_clean")?;
+        let mut ws = init_workspace(tmp.path())?;
+
+        let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+        ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+        ws.commit()?;
+        let root_hash = ws.manifest.root_hash;
+        let log = ws.log()?.len();
+
+        // Writing the file and then writing its previous contents back leaves
+        // it unchanged
+        ws.write_data(index, 0, b"portocalaalabala".to_vec())?;
+        ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+        let summary = ws.commit()?;
+        assert!(!summary.noop && summary.clean);
+        assert_eq!(
+            summary.to_string(),
+            "Nothing changed since the last commit\n"
+        );
+        assert_eq!(ws.manifest.root_hash, root_hash);
+        assert_eq!(ws.log()?.len(), log);
+
+        let later = ws.get_attr(index)?.mtime + Duration::seconds(60);
+        let touch =
+            FileAttributeChanges::new(None, None, None, None, None, Some(later), None, None, None);
+        ws.set_attr(index, &touch)?;
+        let summary = ws.commit()?;
+        assert!(!summary.clean);
+        assert_ne!(ws.manifest.root_hash, root_hash);
+        Ok(())
+    }
+
+    #[test]
+    fn digests_of_written_files_are_recorded() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_commit_digest")?;
+        let mut ws = init_workspace(tmp.path())?;
+
+        let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+        ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+        assert!(ws.file_digest(index)?.is_none());
+        ws.commit()?;
+        assert_eq!(ws.file_digest(index)?, Some(hash(b"alabalaportocala")));
+
+        // Truncated files are written again
+        let truncate =
+            FileAttributeChanges::new(None, None, None, Some(0), None, None, None, None, None);
+        ws.set_attr(index, &truncate)?;
+        assert!(ws.file_digest(index)?.is_none());
+        ws.commit()?;
+        assert_eq!(ws.file_digest(index)?, Some(hash(b"")));
+        assert!(ws.file_digest(1)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn forgotten_inodes_are_evicted_once_committed() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_commit_forget")?;
+        let mut ws = init_workspace(tmp.path())?;
+
+        let dir = ws.create_dir(1, OsStr::new("d1"), 0o755)?.index;
+        let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+        ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+        ws.add_lookup(index);
+
+        // Changes which weren't committed are kept
+        assert_eq!(ws.forget(index, 1), 0);
+        assert!(ws.files.contains_key(&index));
+        ws.commit()?;
+        ws.add_lookup(index);
+        ws.add_lookup(index);
+        assert_eq!(ws.forget(index, 1), 1);
+        assert!(ws.files.contains_key(&index));
+        assert_eq!(ws.forget(index, 1), 0);
+        assert!(!ws.files.contains_key(&index));
+        assert!(ws.inodes.get(index).is_none());
+
+        // The directory was changed when it was created
+        ws.add_lookup(dir);
+        ws.forget(dir, 1);
+        assert!(ws.dirs.contains_key(&dir));
+
+        ws.open_file(index, 0)?;
+        assert_eq!(ws.read_data(index, 0, 16)?, b"alabalaportocala");
+
+        Ok(())
+    }
+
+    #[test]
+    fn commits_record_metadata() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_commit_metadata")?;
+        let mut ws = init_workspace(tmp.path())?;
+
+        let dir = ws.create_dir(1, OsStr::new("d1"), 0o755)?.index;
+        let (index, _) = ws.create_file(dir, OsStr::new("f1"), 0o644, 0)?;
+        ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+        let commit = begin_commit(&ws, Some("First commit".to_owned()));
+        finish_commit(&mut ws, commit)?;
+
+        let metadata = ws.manifest.metadata.clone().unwrap_or_default();
+        assert_eq!(metadata.message, "First commit");
+        assert_eq!(metadata.author, format!("{}@{}", username(), hostname()));
+        assert_eq!((metadata.files, metadata.dirs), (1, 2));
+        assert_eq!((metadata.logical_size, metadata.chunks), (16, 1));
+
+        // The replaced manifest is recorded in the reflog
+        ws.write_data(index, 0, b"40 de turcaleti".to_vec())?;
+        ws.commit()?;
+        let log = ws.log()?;
+        let message = log[0].metadata.as_ref().map(|m| m.message.as_str());
+        assert!(message.unwrap_or_default().starts_with("Automatic commit"));
+        assert_eq!(log[1].metadata, Some(metadata));
+
+        // The statistics follow the files written and removed since
+        let (other, _) = ws.create_file(dir, OsStr::new("f2"), 0o644, 0)?;
+        ws.write_data(other, 0, vec![b'a'; 40])?;
+        ws.remove(dir, OsStr::new("f1"))?;
+        ws.commit()?;
+        let metadata = ws.manifest.metadata.clone().unwrap_or_default();
+        assert_eq!((metadata.files, metadata.dirs), (1, 2));
+        assert_eq!((metadata.logical_size, metadata.chunks), (40, 3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_size_is_recorded_in_manifest() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_commit_chunk_size")?;
+        {
+            let mut ws = init_workspace(tmp.path())?;
+            assert_eq!(ws.manifest.chunk_size, Some(16));
+            let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+            ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+            ws.commit()?;
+            assert_eq!(ws.manifest.chunking, Some(Chunking::Fixed));
+        }
+
+        // The configured chunk size is ignored
+        let ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().to_owned(),
+            WorkspaceOptions {
+                chunk_size: 8,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+        assert_eq!(ws.chunk_size(), 16);
+        assert_eq!(ws.manifest.chunk_size, Some(16));
+
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_commit_is_recovered() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_commit_recovery")?;
+        let pending_path = tmp.path().join(MANIFEST_PENDING_PATH);
+
+        let (first_root, second_root) = {
+            let mut ws = init_workspace(tmp.path())?;
+            let first_root = ws.manifest.root_hash;
+            let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+            ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+            ws.commit()?;
+            (first_root, ws.manifest.root_hash)
+        };
+
+        // A prepared manifest pointing to a missing catalog is discarded
+        let manifest = Manifest::new(hash(b"missing catalog"), now_utc());
+        atomic_write(&pending_path, &manifest.serialize()?)?;
+        {
+            let ws = open_workspace(tmp.path())?;
+            assert_eq!(ws.manifest.root_hash, second_root);
+        }
+        assert!(!pending_path.exists());
+
+        // A prepared manifest pointing to a stored catalog is activated
+        let manifest = Manifest::new(first_root, now_utc());
+        atomic_write(&pending_path, &manifest.serialize()?)?;
+        {
+            let ws = open_workspace(tmp.path())?;
+            assert_eq!(ws.manifest.root_hash, first_root);
+        }
+        assert!(!pending_path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn abandoned_commit_is_completed_by_next_commit() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_commit_abandoned")?;
+        let mut ws = init_workspace(tmp.path())?;
+        let root_hash = ws.manifest.root_hash;
+
+        let (f1, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+        let (f2, _) = ws.create_file(1, OsStr::new("f2"), 0o644, 0)?;
+        ws.write_data(f1, 0, b"alabalaportocala".to_vec())?;
+        ws.write_data(f2, 0, b"40 de turcaleti".to_vec())?;
+
+        // A cancelled commit stops after writing some of the files
+        let mut commit = begin_commit(&ws, None);
+        assert!(commit_next_step(&mut ws, &mut commit)?);
+        drop(commit);
+        assert_eq!(ws.manifest.root_hash, root_hash);
+        assert!(ws.dirty);
+
+        let summary = commit_workspace(&mut ws)?;
+        assert_eq!(summary.files_written, 1);
+        assert_ne!(ws.manifest.root_hash, root_hash);
+        assert_eq!(ws.read_data(f1, 0, 16)?, b"alabalaportocala".to_vec());
+        assert_eq!(ws.read_data(f2, 0, 15)?, b"40 de turcaleti".to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn files_written_during_a_commit_keep_their_changes() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_commit_frozen")?;
+        let mut ws = init_workspace(tmp.path())?;
+
+        let (f1, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+        let (f2, _) = ws.create_file(1, OsStr::new("f2"), 0o644, 0)?;
+        ws.write_data(f1, 0, b"alabalaportocala, 40 de turcaleti".to_vec())?;
+        ws.write_data(f2, 0, b"zece pitpalaci".to_vec())?;
+
+        // The files are frozen, and written one chunk at a time
+        let mut commit = begin_commit(&ws, None);
+        assert_eq!(commit.pending_files(), 2);
+        while ws.files[&f1].dirty && ws.files[&f2].dirty {
+            assert!(commit_next_step(&mut ws, &mut commit)?);
+        }
+        let (written, other) = if ws.files[&f1].dirty {
+            (f2, f1)
+        } else {
+            (f1, f2)
+        };
+        // The first file was written unchanged, the other one is changed
+        // before it's written
+        assert_eq!(commit.pending_files(), 1);
+        ws.write_data(other, 0, b"ALA".to_vec())?;
+        while commit_next_step(&mut ws, &mut commit)? {}
+        assert!(!ws.files[&written].dirty);
+        assert!(ws.files[&other].dirty);
+
+        let summary = finish_commit(&mut ws, commit)?;
+        assert_eq!(summary.files_written, 3);
+        assert!(!ws.files[&other].dirty);
+        let expected = if other == f1 {
+            b"ALAbalaportocala, 40 de turcaleti".to_vec()
+        } else {
+            b"ALAe pitpalaci".to_vec()
+        };
+        assert_eq!(ws.read_data(other, 0, 33)?, expected);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+use {
+    super::{pin::visit_subtrees, Workspace},
+    crate::errors::DenebResult,
+    std::collections::HashSet,
+    time::Timespec,
+};
+
+/// Drop the local copies of the chunks of the files under the entry `index`
+///
+/// With a `cutoff`, only the files which were neither read nor modified since
+/// then are dehydrated, and the chunks they share with the other files are
+/// kept. The times are the committed ones, as are the chunks: the files open
+/// in the workspace count as recently used. Returns the number of chunks
+/// dropped and their packed size.
+pub(super) fn dehydrate(
+    ws: &mut Workspace,
+    index: u64,
+    cutoff: Option<Timespec>,
+) -> DenebResult<(u64, u64)> {
+    // The entry has to exist
+    ws.get_attr(index)?;
+    let mut cold = HashSet::new();
+    let mut warm = HashSet::new();
+    visit_subtrees(ws, &[index], |inode| {
+        let attrs = &inode.attributes;
+        let is_cold = match cutoff {
+            Some(cutoff) => {
+                attrs.atime.max(attrs.mtime) < cutoff && !ws.files.contains_key(&attrs.index)
+            }
+            None => true,
+        };
+        let digests = inode.chunks.iter().map(|chunk| chunk.digest);
+        if is_cold {
+            cold.extend(digests);
+        } else {
+            warm.extend(digests);
+        }
+    })?;
+    let digests = cold.difference(&warm).cloned().collect();
+    ws.store.borrow_mut().dehydrate(&digests)
+}
@@ -0,0 +1,259 @@
+use {
+    super::{
+        gc::add_referenced_chunks,
+        reflog::{self, Entry},
+        root::{catalog_chunks, read_catalog},
+        MANIFEST_PATH, MANIFEST_PENDING_PATH, REFLOG_PATH,
+    },
+    crate::{
+        cas::Digest,
+        catalog::{open_catalog, CatalogType},
+        crypt::EncryptionKey,
+        errors::{DenebResult, WorkspaceError},
+        manifest::Manifest,
+        store::{open_store, Store, StoreType},
+    },
+    bincode::{deserialize_from, serialize_into},
+    log::info,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashSet,
+        fs::{create_dir_all, remove_file, File},
+        io::{BufReader, BufWriter, Read, Write},
+        path::Path,
+        process,
+    },
+    time::{at, now_utc, Timespec},
+};
+
+/// Prefix of a delta file
+const DELTA_MARKER: &[u8] = b"deneb-delta\n";
+
+/// Start of a delta file, followed by `chunks` pairs of a digest and a packed
+/// chunk
+#[derive(Deserialize, Serialize)]
+struct Header {
+    /// Root hash of the commit the delta is applied to
+    from: Digest,
+    /// Manifest of the commit the delta leads to
+    to: Manifest,
+    chunks: u64,
+}
+
+/// Write the chunks needed to go from the commit with root hash `from` to the
+/// one with root hash `to` into the file `out`
+///
+/// These are the chunks of the files and of the catalog of `to` which are not
+/// referenced by `from`. Both commits need to be retained. The chunks are
+/// written in their packed form, so the delta can be applied without the
+/// encryption key. Returns the number of chunks written.
+pub fn export_delta(
+    work_dir: &Path,
+    encryption_key: Option<EncryptionKey>,
+    chunk_size: usize,
+    from: &Digest,
+    to: &Digest,
+    out: &Path,
+) -> DenebResult<usize> {
+    let store = open_store(
+        StoreType::OnDisk,
+        work_dir,
+        encryption_key,
+        chunk_size,
+        0,
+        false,
+    )?;
+    let log = retained_manifests(&*store, work_dir)?;
+    let manifest = match log.iter().find(|m| m.root_hash == *to) {
+        Some(manifest) => manifest.clone(),
+        None => return Err(WorkspaceError::UnknownCommit(to.to_string()).into()),
+    };
+    if !log.iter().any(|m| m.root_hash == *from) {
+        return Err(WorkspaceError::UnknownCommit(from.to_string()).into());
+    }
+
+    // The catalogs are unpacked next to the one of a running engine
+    let scratch_dir = work_dir.join("scratch");
+    create_dir_all(&scratch_dir)?;
+    let catalog_path = scratch_dir.join(format!("delta_catalog.{}", process::id()));
+    let known = referenced_chunks(&*store, from, &catalog_path)?;
+    let chunks = referenced_chunks(&*store, to, &catalog_path)?
+        .into_iter()
+        .filter(|digest| !known.contains(digest))
+        .collect::<Vec<_>>();
+
+    let mut writer = BufWriter::new(File::create(out)?);
+    writer.write_all(DELTA_MARKER)?;
+    let header = Header {
+        from: *from,
+        to: manifest,
+        chunks: chunks.len() as u64,
+    };
+    serialize_into(&mut writer, &header)?;
+    for digest in &chunks {
+        serialize_into(&mut writer, &(digest, store.packed_chunk(digest)?))?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    info!(
+        "Exported the delta from {} to {} into {:?}: {} chunks",
+        from,
+        to,
+        out,
+        chunks.len()
+    );
+
+    Ok(chunks.len())
+}
+
+/// Apply a delta written by `export_delta` to the repository in `work_dir`
+///
+/// The current commit of the repository must be the one the delta was
+/// exported from. The chunks are stored, then the commit the delta leads to
+/// becomes the current one, and the replaced commit is recorded in the
+/// reflog. A delta which was already applied is skipped. Returns the number
+/// of chunks stored.
+pub fn apply_delta(work_dir: &Path, delta: &Path, chunk_size: usize) -> DenebResult<usize> {
+    let mut store = open_store(StoreType::OnDisk, work_dir, None, chunk_size, 0, false)?;
+
+    let mut reader = BufReader::new(File::open(delta)?);
+    let mut marker = vec![0; DELTA_MARKER.len()];
+    reader.read_exact(&mut marker)?;
+    if marker != DELTA_MARKER {
+        return Err(WorkspaceError::InvalidDelta(delta.to_owned()).into());
+    }
+    let header: Header = deserialize_from(&mut reader)?;
+
+    let current = Manifest::deserialize(&store.read_special_file(&work_dir.join(MANIFEST_PATH))?)?;
+    if current.root_hash == header.to.root_hash {
+        info!("Delta {:?} was already applied", delta);
+        return Ok(0);
+    }
+    if current.root_hash != header.from {
+        return Err(WorkspaceError::DeltaBase(header.from.to_string()).into());
+    }
+
+    // Every chunk is checked against its digest before it is stored
+    for _ in 0..header.chunks {
+        let (digest, packed): (Digest, Vec<u8>) = deserialize_from(&mut reader)?;
+        store.put_packed_chunk(&digest, &packed)?;
+    }
+    store.sync()?;
+
+    // The new manifest replaces the current one as it does after a commit
+    let entry = Entry::replacing(&current, now_utc().to_timespec().sec);
+    reflog::append(&mut *store, Path::new(REFLOG_PATH), &entry)?;
+    let pending_path = work_dir.join(MANIFEST_PENDING_PATH);
+    let buf = header.to.serialize()?;
+    store.write_special_file(&pending_path, &mut &buf[..], false)?;
+    store.rename_special_file(&pending_path, &work_dir.join(MANIFEST_PATH))?;
+
+    info!(
+        "Applied the delta from {} to {} to {:?}: {} chunks",
+        header.from, header.to.root_hash, work_dir, header.chunks
+    );
+
+    Ok(header.chunks as usize)
+}
+
+// The current manifest followed by the ones of the commits in the reflog
+fn retained_manifests(store: &dyn Store, work_dir: &Path) -> DenebResult<Vec<Manifest>> {
+    let buf = store.read_special_file(&work_dir.join(MANIFEST_PATH))?;
+    let mut manifests = vec![Manifest::deserialize(&buf)?];
+    let entries = reflog::read(store, Path::new(REFLOG_PATH))?;
+    manifests.extend(entries.into_iter().rev().map(|entry| match entry.manifest {
+        Some(manifest) => manifest,
+        None => Manifest::new(entry.root_hash, at(Timespec::new(entry.timestamp, 0))),
+    }));
+    Ok(manifests)
+}
+
+// The chunks of the catalog with root `root_hash` and of the files it holds
+pub(super) fn referenced_chunks(
+    store: &dyn Store,
+    root_hash: &Digest,
+    catalog_path: &Path,
+) -> DenebResult<HashSet<Digest>> {
+    let mut chunks = catalog_chunks(store, root_hash)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    read_catalog(store, root_hash, catalog_path)?;
+    let result = open_catalog(CatalogType::Lmdb, catalog_path, false)
+        .and_then(|catalog| add_referenced_chunks(&*catalog, &mut chunks));
+
+    let _ = remove_file(catalog_path);
+    let mut lock_path = catalog_path.to_owned().into_os_string();
+    lock_path.push("-lock");
+    let _ = remove_file(lock_path);
+
+    result.map(|_| chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::workspace::{replicate_repository, Snapshot, Workspace, WorkspaceOptions},
+        std::{ffi::OsStr, fs::write},
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn deltas_bring_replicas_up_to_date() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_delta")?;
+        let work_dir = tmp.path().join("work");
+        let replica_dir = tmp.path().join("replica");
+        let stale_dir = tmp.path().join("stale");
+        let delta_path = tmp.path().join("delta.pack");
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(&sync_dir)?;
+        write(sync_dir.join("file"), "alabalaportocala")?;
+
+        let key = Some(EncryptionKey::new());
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            work_dir.clone(),
+            WorkspaceOptions {
+                encryption_key: key.clone(),
+                sync_dir: Some(sync_dir),
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+        let from = ws.manifest().root_hash;
+        replicate_repository(&work_dir, &replica_dir, 16)?;
+        replicate_repository(&work_dir, &stale_dir, 16)?;
+
+        let (index, _) = ws.create_file(1, OsStr::new("new"), 0o644, 0)?;
+        ws.write_data(index, 0, b"40 de turcaleti".to_vec())?;
+        ws.commit()?;
+        let to = ws.manifest().root_hash;
+
+        // The new file chunk, the catalog chunk and the catalog root
+        let exported = export_delta(&work_dir, key.clone(), 16, &from, &to, &delta_path)?;
+        assert_eq!(exported, 3);
+        assert!(apply_delta(&replica_dir, &delta_path, 16)? > 0);
+        assert_eq!(apply_delta(&replica_dir, &delta_path, 16)?, 0);
+
+        let snapshot = Snapshot::open(&replica_dir, key.clone(), 16)?;
+        assert_eq!(snapshot.manifest().root_hash, to);
+        let attrs = snapshot.resolve(Path::new("new"))?.unwrap();
+        let mut contents = Vec::new();
+        snapshot.read_file(attrs.index, &mut contents)?;
+        assert_eq!(contents, b"40 de turcaleti".to_vec());
+
+        // Deltas only apply to the commit they were exported from
+        ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+        ws.commit()?;
+        let next = ws.manifest().root_hash;
+        export_delta(&work_dir, key, 16, &to, &next, &delta_path)?;
+        assert!(apply_delta(&stale_dir, &delta_path, 16).is_err());
+        // The replica already holds the chunk of the new contents
+        assert_eq!(apply_delta(&replica_dir, &delta_path, 16)?, 2);
+
+        Ok(())
+    }
+}
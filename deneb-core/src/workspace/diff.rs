@@ -0,0 +1,226 @@
+use {
+    super::Workspace,
+    crate::{errors::DenebResult, inode::FileType},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{BTreeMap, BTreeSet, HashSet},
+        fmt::{Display, Formatter, Result as FmtResult},
+        path::{Path, PathBuf},
+    },
+};
+
+/// A change made to the repository since the last commit
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum PendingChange {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Deleted(PathBuf),
+}
+
+impl Display for PendingChange {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            PendingChange::Created(path) => write!(f, "created: {}", path.display()),
+            PendingChange::Modified(path) => write!(f, "modified: {}", path.display()),
+            PendingChange::Deleted(path) => write!(f, "deleted: {}", path.display()),
+        }
+    }
+}
+
+/// What the next commit would write
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PendingChanges {
+    /// The changed paths, in the order in which the directories are visited
+    pub changes: Vec<PendingChange>,
+    /// Size of the files whose data would be written
+    pub bytes_to_write: u64,
+}
+
+/// Compare the workspace with the last commit, without changing either
+///
+/// The whole tree is visited. A renamed entry shows up as deleted at its old
+/// path and created at its new one, together with everything under it.
+pub(super) fn pending_changes(ws: &Workspace) -> DenebResult<PendingChanges> {
+    let mut changed = ws
+        .inodes
+        .dirty()
+        .map(|iws| iws.inode().attributes.index)
+        .collect::<HashSet<_>>();
+    let mut bytes_to_write = 0;
+    for (index, fws) in &ws.files {
+        if fws.dirty && !ws.deleted_inodes.contains(index) {
+            changed.insert(*index);
+            bytes_to_write += fws.size;
+        }
+    }
+
+    let mut diff = Diff {
+        ws,
+        changed,
+        changes: Vec::new(),
+    };
+    if ws.dirty {
+        diff.visit_dir(Path::new(""), Some(ws.root), Some(ws.root))?;
+    }
+    Ok(PendingChanges {
+        changes: diff.changes,
+        bytes_to_write,
+    })
+}
+
+type Entries = BTreeMap<PathBuf, (u64, FileType)>;
+
+struct Diff<'a> {
+    ws: &'a Workspace,
+    /// Inodes modified since the last commit
+    changed: HashSet<u64>,
+    changes: Vec<PendingChange>,
+}
+
+impl<'a> Diff<'a> {
+    fn visit_dir(
+        &mut self,
+        dir: &Path,
+        committed: Option<u64>,
+        current: Option<u64>,
+    ) -> DenebResult<()> {
+        let committed = match committed {
+            Some(index) => self.committed_entries(index)?,
+            None => Entries::new(),
+        };
+        let current = match current {
+            Some(index) => self.current_entries(index)?,
+            None => Entries::new(),
+        };
+
+        let names = committed
+            .keys()
+            .chain(current.keys())
+            .collect::<BTreeSet<_>>();
+        for name in names {
+            let path = dir.join(name);
+            match (committed.get(name), current.get(name)) {
+                (Some(&(old, _)), Some(&(new, kind))) if old == new => {
+                    if self.changed.contains(&new) {
+                        self.changes.push(PendingChange::Modified(path.clone()));
+                    }
+                    if kind == FileType::Directory {
+                        self.visit_dir(&path, Some(old), Some(new))?;
+                    }
+                }
+                (old, new) => {
+                    if let Some(&(old, kind)) = old {
+                        self.changes.push(PendingChange::Deleted(path.clone()));
+                        if kind == FileType::Directory {
+                            self.visit_dir(&path, Some(old), None)?;
+                        }
+                    }
+                    if let Some(&(new, kind)) = new {
+                        self.changes.push(PendingChange::Created(path.clone()));
+                        if kind == FileType::Directory {
+                            self.visit_dir(&path, None, Some(new))?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The entries of a directory as of the last commit
+    fn committed_entries(&self, index: u64) -> DenebResult<Entries> {
+        let catalog = &self.ws.catalog;
+        catalog
+            .dir_entries(index)?
+            .into_iter()
+            .filter(|(name, _)| !is_special(name))
+            .map(|(name, index)| Ok((name, (index, catalog.inode(index)?.attributes.kind))))
+            .collect()
+    }
+
+    // Directories which weren't opened since the last commit are unchanged
+    fn current_entries(&self, index: u64) -> DenebResult<Entries> {
+        match self.ws.dirs.get(&index) {
+            Some(dws) => Ok(dws
+                .entries_tuple()
+                .into_iter()
+                .filter(|(name, _, _)| !is_special(name))
+                .map(|(name, index, kind)| (name, (index, kind)))
+                .collect()),
+            None => self.committed_entries(index),
+        }
+    }
+}
+
+fn is_special(name: &Path) -> bool {
+    name == Path::new(".") || name == Path::new("..")
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{catalog::CatalogType, store::StoreType, workspace::WorkspaceOptions},
+        std::{
+            ffi::OsStr,
+            fs::{create_dir_all, write},
+        },
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn pending_changes_are_listed() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_diff")?;
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(sync_dir.join("dir"))?;
+        write(sync_dir.join("dir/changed"), "alabala")?;
+        write(sync_dir.join("dir/removed"), "portocala")?;
+        write(sync_dir.join("same"), "alabalaportocala")?;
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().join("work"),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir),
+                chunk_size: 8,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+        assert_eq!(ws.pending_changes()?, PendingChanges::default());
+
+        let dir = ws
+            .resolve_path(Path::new("dir"))?
+            .map(|(index, _)| index)
+            .unwrap_or_default();
+        let changed = ws
+            .resolve_path(Path::new("dir/changed"))?
+            .map(|(index, _)| index)
+            .unwrap_or_default();
+        ws.open_file(changed, 0)?;
+        ws.write_data(changed, 7, b"portocala".to_vec())?;
+        ws.remove(dir, OsStr::new("removed"))?;
+        let new_dir = ws.create_dir(1, OsStr::new("new"), 0o755)?.index;
+        ws.create_file(new_dir, OsStr::new("empty"), 0o644, 0)?;
+
+        assert_eq!(
+            ws.pending_changes()?,
+            PendingChanges {
+                changes: vec![
+                    PendingChange::Modified(PathBuf::from("dir/changed")),
+                    PendingChange::Deleted(PathBuf::from("dir/removed")),
+                    PendingChange::Created(PathBuf::from("new")),
+                    PendingChange::Created(PathBuf::from("new/empty")),
+                ],
+                bytes_to_write: 16,
+            }
+        );
+
+        // Nothing was changed by the comparison
+        ws.commit()?;
+        assert_eq!(ws.pending_changes()?, PendingChanges::default());
+
+        Ok(())
+    }
+}
@@ -1,26 +1,64 @@
 use {
     crate::inode::FileType,
-    std::path::{Path, PathBuf},
+    std::{
+        mem::size_of,
+        path::{Path, PathBuf},
+    },
 };
 
+/// The entries of a directory, in readdir order
+///
+/// Each entry has a cookie, which is the offset passed by the kernel to
+/// continue reading the directory after the entry. The entries loaded from the
+/// catalog come first, "." and ".." followed by the others sorted by name, and
+/// the entries added afterwards follow in the order in which they were added.
+/// Removing entries doesn't change the cookies of the others, so reading a
+/// directory which is modified meanwhile never skips or repeats entries which
+/// were there all along.
 #[derive(Clone, Debug)]
 pub(super) struct Workspace {
     entries: Vec<DirEntry>,
+    next_cookie: i64,
     pub(in crate) dirty: bool,
 }
 
 impl Workspace {
     pub(super) fn new(entries: &[DirEntry]) -> Workspace {
+        let mut entries = entries.to_vec();
+        entries.sort_by(|a, b| sort_key(&a.name).cmp(&sort_key(&b.name)));
+        for (entry, cookie) in entries.iter_mut().zip(1..) {
+            entry.cookie = cookie;
+        }
         Workspace {
-            entries: entries.to_vec(),
+            next_cookie: entries.len() as i64 + 1,
+            entries,
             dirty: false,
         }
     }
 
+    /// Estimate of the memory held by the entries of the directory
+    pub(super) fn memory_size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| size_of::<DirEntry>() + entry.name.as_os_str().len())
+            .sum::<usize>() as u64
+    }
+
     pub(super) fn entries(&self) -> &[DirEntry] {
         &self.entries
     }
 
+    /// The entries following the one whose cookie is `cookie`, or all of them
+    /// when it's 0
+    pub(super) fn entries_after(&self, cookie: i64) -> &[DirEntry] {
+        let start = self
+            .entries
+            .iter()
+            .position(|entry| entry.cookie > cookie)
+            .unwrap_or_else(|| self.entries.len());
+        &self.entries[start..]
+    }
+
     pub(super) fn entries_tuple(&self) -> Vec<(PathBuf, u64, FileType)> {
         self.entries
             .iter()
@@ -39,13 +77,25 @@ impl Workspace {
         self.entries.iter().find(|e| e.name == name)
     }
 
+    /// Find an entry whose name is equal to `name` when compared
+    /// case-insensitively, preferring an exact match
+    pub(super) fn entry_ignoring_case(&self, name: &Path) -> Option<&DirEntry> {
+        self.entry(name).or_else(|| {
+            let folded = fold_case(name)?;
+            self.entries
+                .iter()
+                .find(|e| fold_case(&e.name).as_ref() == Some(&folded))
+        })
+    }
+
     pub(super) fn add_entry(&mut self, index: u64, name: PathBuf, entry_type: FileType) {
         self.entries.push(DirEntry {
             index,
             name,
             entry_type,
+            cookie: self.next_cookie,
         });
-        self.entries.sort_by_key(|de| de.index);
+        self.next_cookie += 1;
 
         self.dirty = true;
     }
@@ -76,11 +126,28 @@ impl Workspace {
     }
 }
 
+// "." and ".." come before all other names
+fn sort_key(name: &Path) -> (u8, &Path) {
+    if name == Path::new(".") {
+        (0, name)
+    } else if name == Path::new("..") {
+        (1, name)
+    } else {
+        (2, name)
+    }
+}
+
+// Names which are not valid UTF-8 are only compared exactly
+fn fold_case(name: &Path) -> Option<String> {
+    name.to_str().map(str::to_lowercase)
+}
+
 #[derive(Clone, Debug)]
 pub(super) struct DirEntry {
     pub(super) index: u64,
     pub(super) name: PathBuf,
     pub(super) entry_type: FileType,
+    pub(super) cookie: i64,
 }
 
 impl DirEntry {
@@ -89,6 +156,65 @@ impl DirEntry {
             index,
             name,
             entry_type,
+            cookie: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_found_ignoring_case() {
+        let mut ws = Workspace::new(&[]);
+        ws.add_entry(2, PathBuf::from("Straße.TXT"), FileType::RegularFile);
+        ws.add_entry(3, PathBuf::from("ÉTÉ"), FileType::Directory);
+        ws.add_entry(4, PathBuf::from("été"), FileType::Directory);
+
+        let index = |name: &str| ws.entry_ignoring_case(Path::new(name)).map(|e| e.index);
+        assert_eq!(index("straße.txt"), Some(2));
+        assert_eq!(index("STRAßE.txt"), Some(2));
+        assert_eq!(index("été"), Some(4));
+        assert_eq!(index("Été"), Some(3));
+        assert_eq!(index("ete"), None);
+        assert!(ws.entry(Path::new("straße.txt")).is_none());
+    }
+
+    #[test]
+    fn cookies_are_stable() {
+        let entries = ["b", "..", "a", "."]
+            .iter()
+            .zip(1..)
+            .map(|(name, index)| DirEntry::new(index, PathBuf::from(name), FileType::Directory))
+            .collect::<Vec<_>>();
+        let mut ws = Workspace::new(&entries);
+        let names = |entries: &[DirEntry]| {
+            entries
+                .iter()
+                .map(|e| (e.cookie, e.name.to_string_lossy().into_owned()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(ws.entries_after(0)),
+            vec![
+                (1, ".".to_owned()),
+                (2, "..".to_owned()),
+                (3, "a".to_owned()),
+                (4, "b".to_owned())
+            ]
+        );
+
+        // Entries removed and added while the directory is read
+        ws.add_entry(5, PathBuf::from("0"), FileType::RegularFile);
+        ws.remove_entry(Path::new("a"));
+        assert_eq!(
+            names(ws.entries_after(3)),
+            vec![(4, "b".to_owned()), (5, "0".to_owned())]
+        );
+        ws.remove_entry(Path::new("0"));
+        assert!(ws.entries_after(4).is_empty());
+        ws.add_entry(6, PathBuf::from("c"), FileType::RegularFile);
+        assert_eq!(names(ws.entries_after(4)), vec![(6, "c".to_owned())]);
+    }
+}
@@ -6,16 +6,28 @@ use {
         store::{Chunk, Store},
     },
     log::trace,
+    serde::{Deserialize, Serialize},
     std::{
         cell::RefCell,
         cmp::min,
         collections::HashMap,
-        io::{Error as IoError, ErrorKind, Read, Result as IoResult},
+        io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write},
+        mem::size_of,
         rc::Rc,
-        sync::Arc,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
     },
 };
 
+/// Buffers smaller than this are appended to the last segment of the upper
+/// layer, when that segment is small as well
+const MIN_SEGMENT_SIZE: usize = 64 * 1024;
+
+/// Source of the stamps which identify the states of the workspaces
+static NEXT_STAMP: AtomicU64 = AtomicU64::new(1);
+
 /// A type which offers read/write operations on a file in the repository
 ///
 /// A `Workspace` represents a superposition of a lower layer,
@@ -27,12 +39,40 @@ use {
 /// Its implementation is based on interior mutability - caching of
 /// unpackaged chunks in the lower layer is done transparently to the
 /// client of the `Workspace`.
+///
+/// The upper layer is made of segments. Buffers written into the workspace
+/// become segments of their own, without being copied, unless they are small,
+/// in which case they are copied to the end of the last segment. Segments are
+/// shared with the frozen copies of the workspace, and copied before being
+/// appended to when they are.
 pub(crate) struct Workspace {
     lower: RefCell<Lower>,
-    upper: Vec<u8>,
+    upper: Vec<Rc<Vec<u8>>>,
+    /// Total size of the segments of the upper layer
+    upper_size: usize,
     piece_table: Vec<Piece>,
     pub(crate) size: u64,
     pub(crate) dirty: bool,
+    /// Changes with each write or truncation, and is unique to this state of
+    /// the contents among all the workspaces
+    pub(crate) stamp: u64,
+}
+
+/// The state of a dirty workspace, as written by `Workspace::save`
+#[derive(Deserialize)]
+pub(crate) struct Saved {
+    lower: Vec<Digest>,
+    upper: Vec<Vec<u8>>,
+    piece_table: Vec<Piece>,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct SavedRef<'a> {
+    lower: &'a [Digest],
+    upper: Vec<&'a [u8]>,
+    piece_table: &'a [Piece],
+    size: u64,
 }
 
 pub(crate) struct WorkspaceReader<'reader> {
@@ -75,12 +115,74 @@ impl Workspace {
         Ok(Workspace {
             lower: RefCell::new(lower),
             upper: vec![],
+            upper_size: 0,
             piece_table,
             size: inode.attributes.size,
             dirty,
+            stamp: next_stamp(),
         })
     }
 
+    /// A copy of the workspace, which keeps the current contents while the
+    /// workspace is changed
+    ///
+    /// The copy shares the segments of the upper layer, so freezing a
+    /// workspace only copies its piece table.
+    pub(crate) fn freeze(&self) -> Workspace {
+        let lower = self.lower.borrow();
+        Workspace {
+            lower: RefCell::new(Lower {
+                digests: lower.digests.clone(),
+                store: Rc::clone(&lower.store),
+                chunks: lower.chunks.clone(),
+                uncached: lower.uncached,
+            }),
+            upper: self.upper.clone(),
+            upper_size: self.upper_size,
+            piece_table: self.piece_table.clone(),
+            size: self.size,
+            dirty: self.dirty,
+            stamp: self.stamp,
+        }
+    }
+
+    /// Write the piece table and the upper layer of the workspace, which is
+    /// restored by `restore`
+    pub(crate) fn save(&self, writer: &mut dyn Write) -> DenebResult<()> {
+        let lower = self.lower.borrow();
+        let saved = SavedRef {
+            lower: &lower.digests,
+            upper: self
+                .upper
+                .iter()
+                .map(|segment| segment.as_slice())
+                .collect(),
+            piece_table: &self.piece_table,
+            size: self.size,
+        };
+        bincode::serialize_into(writer, &saved)?;
+        Ok(())
+    }
+
+    /// Rebuild a dirty workspace saved by `save`
+    pub(crate) fn restore(saved: Saved, store: Rc<RefCell<Box<dyn Store>>>) -> Workspace {
+        let upper_size = saved.upper.iter().map(Vec::len).sum();
+        Workspace {
+            lower: RefCell::new(Lower {
+                digests: saved.lower,
+                store,
+                chunks: HashMap::new(),
+                uncached: false,
+            }),
+            upper: saved.upper.into_iter().map(Rc::new).collect(),
+            upper_size,
+            piece_table: saved.piece_table,
+            size: saved.size,
+            dirty: true,
+            stamp: next_stamp(),
+        }
+    }
+
     pub(crate) fn reader(&self) -> WorkspaceReader {
         WorkspaceReader {
             ws: self,
@@ -90,6 +192,9 @@ impl Workspace {
 
     /// Read `size` number of bytes, starting at `offset`
     pub(crate) fn read_at(&self, offset: usize, size: usize) -> DenebResult<Vec<u8>> {
+        if offset as u64 >= self.size {
+            return Ok(vec![]);
+        }
         let slices = lookup_pieces(offset, size, &self.piece_table);
         let buffer = self.fill_buffer(&slices)?;
         Ok(buffer)
@@ -105,6 +210,9 @@ impl Workspace {
             self.size = 0;
             self.piece_table.clear();
             self.upper.clear();
+            self.upper_size = 0;
+            self.dirty = true;
+            self.stamp = next_stamp();
             return;
         }
 
@@ -124,23 +232,17 @@ impl Workspace {
         self.size = new_size;
 
         self.dirty = true;
+        self.stamp = next_stamp();
     }
 
     /// Write the contents of buffer into the workspace, starting at `offset`
     ///
     /// Write the buffer into the workspace at `offset`, returning a tuple with the number of bytes
     /// written and the new file size
-    pub(crate) fn write_at(&mut self, offset: usize, buffer: &[u8]) -> (u32, u64) {
-        // Append buffer to the upper layer
+    pub(crate) fn write_at(&mut self, offset: usize, buffer: Vec<u8>) -> (u32, u64) {
         let buf_size = buffer.len();
-        let offset_in_upper = self.upper.len();
-        self.upper.extend_from_slice(buffer);
-
-        let new_piece = Piece {
-            target: PieceTarget::Upper,
-            offset: offset_in_upper,
-            size: buf_size,
-        };
+        let new_piece = self.append_to_upper(buffer);
+        self.stamp = next_stamp();
 
         // Corner cases: writing into an empty file or appending to the file
         if self.piece_table.is_empty() || (offset as u64 >= self.size) {
@@ -151,8 +253,9 @@ impl Workspace {
                     size: offset - self.size as usize,
                 });
             }
-            self.piece_table.push(new_piece);
+            push_piece(&mut self.piece_table, new_piece);
             self.size = (offset + buf_size) as u64;
+            self.dirty = true;
             return (buf_size as u32, self.size);
         }
 
@@ -172,13 +275,14 @@ impl Workspace {
         }
 
         // Add the new piece
-        new_piece_table.push(new_piece);
+        push_piece(&mut new_piece_table, new_piece);
 
         // Corner case: the buffer to be written extends to the end of the file
         //              or beyond it
         if (offset + buf_size) as u64 >= self.size {
             self.size = (offset + buf_size) as u64;
             self.piece_table = new_piece_table;
+            self.dirty = true;
             return (buf_size as u32, self.size);
         }
 
@@ -201,6 +305,47 @@ impl Workspace {
         (buf_size as u32, self.size)
     }
 
+    /// Load all the chunks of the lower layer, which also unpacks them into the
+    /// cache of the store
+    pub(crate) fn prefetch(&self) -> DenebResult<()> {
+        let mut lower = self.lower.borrow_mut();
+        for index in 0..lower.digests.len() {
+            lower.load_chunk(index)?;
+        }
+        Ok(())
+    }
+
+    /// Make the reads of the lower layer bypass the cache of unpacked chunks of
+    /// the store, and the chunks loaded by `prefetch`
+    pub(crate) fn set_uncached(&self, uncached: bool) {
+        self.lower.borrow_mut().uncached = uncached;
+    }
+
+    /// Size of the data written into the upper layer, if the workspace is dirty
+    pub(crate) fn dirty_bytes(&self) -> u64 {
+        if self.dirty {
+            self.upper_size as u64
+        } else {
+            0
+        }
+    }
+
+    /// Estimate of the memory held by the upper layer and the piece table
+    pub(crate) fn memory_size(&self) -> (u64, u64) {
+        let pieces = self.piece_table.len() * size_of::<Piece>();
+        (self.upper_size as u64, pieces as u64)
+    }
+
+    /// Amount of chunk data of the lower layer which is held in memory
+    pub(crate) fn chunk_memory_size(&self) -> u64 {
+        self.lower
+            .borrow()
+            .chunks
+            .values()
+            .map(|chunk| chunk.memory_size() as u64)
+            .sum()
+    }
+
     /// Unload the lower layer from memory
     ///
     /// Forces the lower layer of the workspace to be unloaded from
@@ -210,26 +355,53 @@ impl Workspace {
         self.lower.borrow_mut().unload();
     }
 
+    // Add a buffer to the upper layer, returning the piece which references it
+    fn append_to_upper(&mut self, buffer: Vec<u8>) -> Piece {
+        let size = buffer.len();
+        self.upper_size += size;
+        let last = self.upper.len().wrapping_sub(1);
+        match self.upper.last_mut() {
+            Some(segment) if size < MIN_SEGMENT_SIZE && segment.len() < MIN_SEGMENT_SIZE => {
+                let offset = segment.len();
+                Rc::make_mut(segment).extend_from_slice(&buffer);
+                Piece {
+                    target: PieceTarget::Upper(last),
+                    offset,
+                    size,
+                }
+            }
+            _ => {
+                self.upper.push(Rc::new(buffer));
+                Piece {
+                    target: PieceTarget::Upper(self.upper.len() - 1),
+                    offset: 0,
+                    size,
+                }
+            }
+        }
+    }
+
     fn fill_buffer(&self, slices: &[PieceSlice]) -> DenebResult<Vec<u8>> {
         let mut buffer = vec![];
         for &PieceSlice { index, begin, end } in slices {
             let piece = &self.piece_table[index];
             match piece.target {
                 PieceTarget::Lower(chunk_index) => {
-                    let mut lower = self.lower.borrow_mut();
-                    lower.load_chunk(chunk_index)?;
-                    let chunk = &lower.chunks[&chunk_index];
                     let old_size = buffer.len();
                     buffer.resize(buffer.len() + end - begin, 0);
-                    chunk.read_at(&mut buffer[old_size..], piece.offset as u64)?;
+                    self.lower.borrow().read_at(
+                        chunk_index,
+                        (piece.offset + begin) as u64,
+                        &mut buffer[old_size..],
+                    )?;
                 }
-                PieceTarget::Upper => {
+                PieceTarget::Upper(segment) => {
                     buffer.extend_from_slice(
-                        &self.upper[(piece.offset + begin)..(piece.offset + end)],
+                        &self.upper[segment][(piece.offset + begin)..(piece.offset + end)],
                     );
                 }
                 PieceTarget::Zero => {
-                    buffer.append(&mut vec![0; piece.size]);
+                    buffer.resize(buffer.len() + end - begin, 0);
                 }
             }
         }
@@ -253,11 +425,12 @@ impl<'reader> Read for WorkspaceReader<'reader> {
 }
 
 /// Target of the piece, either the lower or the upper layer of the workspace
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 enum PieceTarget {
     /// The index represents which chunk of the lower layer this piece is related to
     Lower(usize),
-    Upper,
+    /// The index represents which segment of the upper layer this piece is related to
+    Upper(usize),
     Zero,
 }
 
@@ -265,7 +438,7 @@ enum PieceTarget {
 ///
 /// If a piece points the lower layer, and index is provided which identifies which
 /// chunk in the lower layer is referenced.
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 struct Piece {
     /// Target of piece
     target: PieceTarget,
@@ -295,6 +468,8 @@ struct Lower {
     digests: Vec<Digest>,
     store: Rc<RefCell<Box<dyn Store>>>,
     chunks: HashMap<usize, Arc<dyn Chunk>>,
+    /// Whether reads go to the store every time
+    uncached: bool,
 }
 
 impl Lower {
@@ -311,6 +486,7 @@ impl Lower {
             digests,
             chunks: HashMap::new(),
             store,
+            uncached: false,
         })
     }
 
@@ -325,12 +501,54 @@ impl Lower {
         Ok(())
     }
 
+    /// Read from a chunk, without loading it if it isn't loaded already
+    ///
+    /// Reads of chunks which aren't loaded are served by the store, which
+    /// may avoid unpacking the whole chunk for them.
+    fn read_at(&self, index: usize, offset: u64, buf: &mut [u8]) -> DenebResult<usize> {
+        if self.uncached {
+            return self
+                .store
+                .borrow()
+                .read_uncached(&self.digests[index], offset, buf);
+        }
+        match self.chunks.get(&index) {
+            Some(chunk) => chunk.read_at(buf, offset),
+            None => self
+                .store
+                .borrow()
+                .read_at(&self.digests[index], offset, buf),
+        }
+    }
+
     /// Release the chunks that make up the lower layer
     fn unload(&mut self) {
         self.chunks.clear();
     }
 }
 
+fn next_stamp() -> u64 {
+    NEXT_STAMP.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Append a piece to the piece table, coalescing it with the last piece if possible
+///
+/// Sequential writes are appended one after the other to the upper layer. When the
+/// new piece continues the region of the upper layer referenced by the last piece,
+/// the last piece is extended instead, so that a stream of small sequential writes
+/// results in a single piece.
+fn push_piece(piece_table: &mut Vec<Piece>, piece: Piece) {
+    if let (Some(last), PieceTarget::Upper(segment)) = (piece_table.last_mut(), &piece.target) {
+        if let PieceTarget::Upper(last_segment) = last.target {
+            if last_segment == *segment && last.offset + last.size == piece.offset {
+                last.size += piece.size;
+                return;
+            }
+        }
+    }
+    piece_table.push(piece);
+}
+
 /// Lookup a subset of pieces corresponding to a memory slice
 ///
 /// Given a piece table and a segment identified by `offset` - the
@@ -379,11 +597,14 @@ fn piece_idx_for_offset(offset: usize, piece_table: &[Piece]) -> (usize, usize)
 mod tests {
     use super::*;
 
+    use quickcheck::{Arbitrary, Gen, QuickCheck, StdGen};
+    use rand::{thread_rng, Rng};
+
     use crate::inode::FileAttributes;
     use crate::store::{open_store, StoreType};
 
     fn make_test_workspace() -> DenebResult<Workspace> {
-        let mut store = open_store(StoreType::InMemory, "/", None, 10000)?;
+        let mut store = open_store(StoreType::InMemory, "/", None, 10000, 0, false)?;
 
         let mut names: Vec<&[u8]> = vec![b"ala", b"bala", b"portocala"];
         let mut chunks = vec![];
@@ -392,7 +613,7 @@ mod tests {
         }
         let mut attributes = FileAttributes::default();
         attributes.size = 16;
-        let inode = INode { attributes, chunks };
+        let inode = INode::new(attributes, chunks);
         Workspace::try_new(&inode, Rc::new(RefCell::new(store)), false)
     }
 
@@ -411,15 +632,12 @@ mod tests {
 
     #[test]
     fn write_into_empty() -> DenebResult<()> {
-        let store = open_store(StoreType::InMemory, "/", None, 10000)?;
+        let store = open_store(StoreType::InMemory, "/", None, 10000, 0, false)?;
 
-        let inode = INode {
-            attributes: FileAttributes::default(),
-            chunks: vec![],
-        };
+        let inode = INode::new(FileAttributes::default(), vec![]);
         let mut ws = Workspace::try_new(&inode, Rc::new(RefCell::new(store)), false)?;
 
-        assert_eq!(ws.write_at(0, b"written"), (7, 7));
+        assert_eq!(ws.write_at(0, b"written".to_vec()), (7, 7));
 
         let res = ws.read_at(0, 7)?;
         assert_eq!(b"written", res.as_slice());
@@ -436,12 +654,12 @@ mod tests {
         let res0 = ws.read_at(0, 16)?;
         assert_eq!(b"alabalaportocala", res0.as_slice());
 
-        assert_eq!(ws.write_at(2, b"written"), (7, 16));
+        assert_eq!(ws.write_at(2, b"written".to_vec()), (7, 16));
 
         let res1 = ws.read_at(0, 16)?;
         assert_eq!(b"alwrittenrtocala", res1.as_slice());
 
-        assert_eq!(ws.write_at(6, b"again"), (5, 16));
+        assert_eq!(ws.write_at(6, b"again".to_vec()), (5, 16));
 
         ws.unload();
 
@@ -455,7 +673,7 @@ mod tests {
     fn write_at_beginning() -> DenebResult<()> {
         let mut ws = make_test_workspace()?;
 
-        assert_eq!(ws.write_at(0, b"written"), (7, 16));
+        assert_eq!(ws.write_at(0, b"written".to_vec()), (7, 16));
 
         let res = ws.read_at(0, 16)?;
 
@@ -470,7 +688,7 @@ mod tests {
     fn write_at_end() -> DenebResult<()> {
         let mut ws = make_test_workspace()?;
 
-        assert_eq!(ws.write_at(9, b"written"), (7, 16));
+        assert_eq!(ws.write_at(9, b"written".to_vec()), (7, 16));
 
         let res = ws.read_at(0, 16)?;
 
@@ -485,7 +703,7 @@ mod tests {
     fn write_extends_the_file() -> DenebResult<()> {
         let mut ws = make_test_workspace()?;
 
-        assert_eq!(ws.write_at(12, b"written"), (7, 19));
+        assert_eq!(ws.write_at(12, b"written".to_vec()), (7, 19));
 
         let res = ws.read_at(0, 19)?;
 
@@ -500,7 +718,7 @@ mod tests {
     fn append_to_file() -> DenebResult<()> {
         let mut ws = make_test_workspace()?;
 
-        assert_eq!(ws.write_at(16, b"written"), (7, 23));
+        assert_eq!(ws.write_at(16, b"written".to_vec()), (7, 23));
 
         let res = ws.read_at(0, 23)?;
 
@@ -515,7 +733,7 @@ mod tests {
     fn write_beyond_end() -> DenebResult<()> {
         let mut ws = make_test_workspace()?;
 
-        assert_eq!(ws.write_at(20, b"written"), (7, 27));
+        assert_eq!(ws.write_at(20, b"written".to_vec()), (7, 27));
 
         let res = ws.read_at(0, 27)?;
 
@@ -532,6 +750,219 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn sequential_writes_are_coalesced() -> DenebResult<()> {
+        let store = open_store(StoreType::InMemory, "/", None, 10000, 0, false)?;
+
+        let inode = INode::new(FileAttributes::default(), vec![]);
+        let mut ws = Workspace::try_new(&inode, Rc::new(RefCell::new(store)), false)?;
+
+        assert_eq!(ws.write_at(0, b"ala".to_vec()), (3, 3));
+        assert_eq!(ws.write_at(3, b"bala".to_vec()), (4, 7));
+        assert_eq!(ws.write_at(7, b"portocala".to_vec()), (9, 16));
+
+        let res = ws.read_at(0, 16)?;
+        assert_eq!(b"alabalaportocala", res.as_slice());
+        assert_eq!(ws.piece_table.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn large_writes_are_not_copied() -> DenebResult<()> {
+        let mut ws = make_test_workspace()?;
+
+        let size = MIN_SEGMENT_SIZE as u64;
+        let data = vec![1; MIN_SEGMENT_SIZE];
+        let address = data.as_ptr();
+        assert_eq!(ws.write_at(4, data), (size as u32, 4 + size));
+        assert_eq!(ws.write_at(0, b"ALA".to_vec()), (3, 4 + size));
+
+        // The large buffer is kept as it is, the small one gets its own segment
+        assert_eq!(ws.upper.len(), 2);
+        assert_eq!(ws.upper[0].as_ptr(), address);
+        assert_eq!(ws.dirty_bytes(), 3 + size);
+        assert_eq!(ws.read_at(0, 6)?, b"ALAb\x01\x01".to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn frozen_copies_keep_their_contents() -> DenebResult<()> {
+        let mut ws = make_test_workspace()?;
+        assert_eq!(ws.write_at(16, b"40".to_vec()), (2, 18));
+
+        let frozen = ws.freeze();
+        assert_eq!(frozen.stamp, ws.stamp);
+        // Appended to the shared segment, and over it
+        assert_eq!(ws.write_at(18, b" de turcaleti".to_vec()), (13, 31));
+        assert_eq!(ws.write_at(0, b"ALA".to_vec()), (3, 31));
+        assert_ne!(frozen.stamp, ws.stamp);
+
+        assert_eq!(frozen.read_at(0, 31)?, b"alabalaportocala40".to_vec());
+        assert_eq!(
+            ws.read_at(0, 31)?,
+            b"ALAbalaportocala40 de turcaleti".to_vec()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sequential_overwrites_are_coalesced() -> DenebResult<()> {
+        let mut ws = make_test_workspace()?;
+
+        assert_eq!(ws.write_at(2, b"wr".to_vec()), (2, 16));
+        assert_eq!(ws.write_at(4, b"it".to_vec()), (2, 16));
+        assert_eq!(ws.write_at(6, b"ten".to_vec()), (3, 16));
+
+        let res = ws.read_at(0, 16)?;
+        assert_eq!(b"alwrittenrtocala", res.as_slice());
+        assert_eq!(ws.piece_table.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_inside_lower_piece() -> DenebResult<()> {
+        let ws = make_test_workspace()?;
+
+        let res = ws.read_at(5, 6)?;
+        assert_eq!(b"laport", res.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_inside_zero_piece() -> DenebResult<()> {
+        let mut ws = make_test_workspace()?;
+
+        ws.truncate(20);
+
+        let res0 = ws.read_at(18, 2)?;
+        assert_eq!([0, 0], res0.as_slice());
+
+        let res1 = ws.read_at(14, 4)?;
+        assert_eq!([108, 97, 0, 0], res1.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_beyond_end() -> DenebResult<()> {
+        let ws = make_test_workspace()?;
+
+        assert!(ws.read_at(16, 10)?.is_empty());
+        assert!(ws.read_at(100, 1)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_into_upper() -> DenebResult<()> {
+        let mut ws = make_test_workspace()?;
+
+        assert_eq!(ws.write_at(16, b"written".to_vec()), (7, 23));
+
+        ws.truncate(18);
+
+        let res0 = ws.read_at(0, 100)?;
+        assert_eq!(b"alabalaportocalawr", res0.as_slice());
+
+        assert_eq!(ws.write_at(18, b"ong".to_vec()), (3, 21));
+
+        let res1 = ws.read_at(0, 100)?;
+        assert_eq!(b"alabalaportocalawrong", res1.as_slice());
+
+        Ok(())
+    }
+
+    /// An operation applied both to a `Workspace` and to its reference model
+    #[derive(Clone, Debug)]
+    enum Op {
+        Write(usize, Vec<u8>),
+        Truncate(u64),
+        Read(usize, usize),
+    }
+
+    impl Arbitrary for Op {
+        fn arbitrary<G: Gen>(g: &mut G) -> Op {
+            let max_size = g.size() + 1;
+            match g.gen_range(0, 3) {
+                0 => {
+                    let mut data = vec![0 as u8; g.gen_range(0, max_size)];
+                    g.fill_bytes(data.as_mut());
+                    Op::Write(g.gen_range(0, max_size * 2), data)
+                }
+                1 => Op::Truncate(g.gen_range(0, max_size * 2) as u64),
+                _ => Op::Read(g.gen_range(0, max_size * 2), g.gen_range(0, max_size * 2)),
+            }
+        }
+    }
+
+    // Apply a sequence of operations to a workspace whose lower layer is made of
+    // `chunks`, checking after each step that the workspace has the same size and
+    // contents as a plain byte vector subjected to the same operations
+    fn matches_model(chunks: &[Vec<u8>], ops: &[Op]) -> DenebResult<bool> {
+        let mut store = open_store(StoreType::InMemory, "/", None, 10000, 0, false)?;
+
+        let mut model = vec![];
+        let mut descriptors = vec![];
+        for chunk in chunks.iter().filter(|c| !c.is_empty()) {
+            descriptors.push(store.put_file(&mut chunk.as_slice())?);
+            model.extend_from_slice(chunk);
+        }
+        let mut attributes = FileAttributes::default();
+        attributes.size = model.len() as u64;
+        let inode = INode::new(attributes, descriptors);
+        let mut ws = Workspace::try_new(&inode, Rc::new(RefCell::new(store)), false)?;
+
+        for op in ops {
+            match op {
+                Op::Write(offset, data) => {
+                    let end = offset + data.len();
+                    if model.len() < end {
+                        model.resize(end, 0);
+                    }
+                    model[*offset..end].copy_from_slice(data);
+                    let written = ws.write_at(*offset, data.clone());
+                    if written != (data.len() as u32, model.len() as u64) {
+                        return Ok(false);
+                    }
+                }
+                Op::Truncate(size) => {
+                    model.resize(*size as usize, 0);
+                    ws.truncate(*size);
+                }
+                Op::Read(offset, size) => {
+                    let begin = min(*offset, model.len());
+                    let end = min(offset + size, model.len());
+                    if ws.read_at(*offset, *size)? != &model[begin..end] {
+                        return Ok(false);
+                    }
+                }
+            }
+            if ws.size != model.len() as u64 {
+                return Ok(false);
+            }
+        }
+
+        let mut contents = vec![];
+        ws.reader().read_to_end(&mut contents)?;
+        Ok(contents == model)
+    }
+
+    #[test]
+    fn workspace_prop_matches_model() {
+        fn prop(chunks: Vec<Vec<u8>>, ops: Vec<Op>) -> bool {
+            matches_model(&chunks, &ops).unwrap_or(false)
+        }
+        QuickCheck::new()
+            .tests(500)
+            .gen(StdGen::new(thread_rng(), 100))
+            .quickcheck(prop as fn(Vec<Vec<u8>>, Vec<Op>) -> bool);
+    }
+
     #[test]
     fn locate_slice() {
         let input_size = 20;
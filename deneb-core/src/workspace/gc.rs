@@ -0,0 +1,199 @@
+use {
+    super::{
+        reflog,
+        root::{catalog_chunks, read_catalog},
+        Workspace, REFLOG_PATH,
+    },
+    crate::{
+        cas::Digest,
+        catalog::{open_catalog, Catalog},
+        errors::DenebResult,
+    },
+    log::info,
+    std::{
+        collections::HashSet,
+        fmt::{Display, Formatter, Result as FmtResult},
+        fs::remove_file,
+        path::PathBuf,
+    },
+    time::now_utc,
+};
+
+#[derive(Debug, Default)]
+pub(in crate) struct Summary {
+    roots_kept: usize,
+    chunks_kept: usize,
+    chunks_removed: usize,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{:#?}", self)
+    }
+}
+
+/// A garbage collection which is performed in steps
+///
+/// The catalogs of the retained commits are loaded one per step, then each
+/// step removes a single unreferenced chunk.
+pub(in crate) struct PendingGc {
+    live: HashSet<Digest>,
+    roots: Vec<Digest>,
+    candidates: Option<Vec<Digest>>,
+    summary: Summary,
+}
+
+/// Start a garbage collection, which removes the chunks that are not referenced
+/// by the current catalog, nor by the catalogs of the commits retained in the reflog
+///
+/// The reflog is compacted according to the retention policy of the workspace
+/// before the referenced chunks are collected. The workspace is expected to
+/// have been committed, since the chunks written by a commit in progress would
+/// not be referenced yet.
+pub(super) fn begin_gc(ws: &mut Workspace) -> DenebResult<PendingGc> {
+    let entries = reflog::compact(
+        &mut **ws.store.borrow_mut(),
+        &PathBuf::from(REFLOG_PATH),
+        ws.retention,
+        now_utc().to_timespec().sec,
+    )?;
+
+    let mut gc = PendingGc {
+        live: HashSet::new(),
+        roots: entries.into_iter().rev().map(|e| e.root_hash).collect(),
+        candidates: None,
+        summary: Summary::default(),
+    };
+    gc.live.extend(catalog_chunks(
+        &**ws.store.borrow(),
+        &ws.manifest.root_hash,
+    )?);
+    add_referenced_chunks(&*ws.catalog, &mut gc.live)?;
+    gc.summary.roots_kept += 1;
+
+    Ok(gc)
+}
+
+/// Perform the next step of a garbage collection. Returns `false` once all
+/// the unreferenced chunks have been removed.
+pub(super) fn gc_next_step(ws: &mut Workspace, gc: &mut PendingGc) -> DenebResult<bool> {
+    let catalog_path = ws.work_dir.join("scratch/gc_catalog");
+    if let Some(root_hash) = gc.roots.pop() {
+        if gc.live.insert(root_hash) {
+            {
+                let store = ws.store.borrow();
+                gc.live.extend(catalog_chunks(&**store, &root_hash)?);
+                read_catalog(&**store, &root_hash, &catalog_path)?;
+            }
+            let catalog = open_catalog(ws.catalog_type, &catalog_path, false)?;
+            add_referenced_chunks(&*catalog, &mut gc.live)?;
+            gc.summary.roots_kept += 1;
+        }
+        return Ok(true);
+    }
+
+    let mut store = ws.store.borrow_mut();
+    if gc.candidates.is_none() {
+        if catalog_path.exists() {
+            remove_file(&catalog_path)?;
+        }
+        gc.candidates = Some(store.chunk_digests()?);
+    }
+
+    let candidates = gc.candidates.as_mut().unwrap();
+    while let Some(digest) = candidates.pop() {
+        if gc.live.contains(&digest) {
+            gc.summary.chunks_kept += 1;
+        } else {
+            store.remove_chunk(&digest)?;
+            gc.summary.chunks_removed += 1;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub(super) fn finish_gc(gc: PendingGc) -> Summary {
+    info!(
+        "Garbage collection removed {} chunks",
+        gc.summary.chunks_removed
+    );
+    gc.summary
+}
+
+pub(super) fn add_referenced_chunks(
+    catalog: &dyn Catalog,
+    live: &mut HashSet<Digest>,
+) -> DenebResult<()> {
+    for index in 1..=catalog.max_index() {
+        // Indices of removed inodes are not reused
+        if let Ok(inode) = catalog.inode(index) {
+            live.extend(inode.chunks.iter().map(|c| c.digest));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            cas::Algorithm,
+            catalog::CatalogType,
+            store::StoreType,
+            workspace::{init_repository, RetentionPolicy, WorkspaceOptions},
+            ImportOptions,
+        },
+        std::ffi::OsStr,
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn unreferenced_chunks_are_removed() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_gc")?;
+        init_repository(
+            tmp.path(),
+            None,
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default(),
+        )?;
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().to_owned(),
+            WorkspaceOptions {
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                retention: RetentionPolicy::new(Some(1), None),
+                ..WorkspaceOptions::default()
+            },
+        )?;
+
+        let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+        for contents in &[
+            b"0123456789abcdef",
+            b"fedcba9876543210",
+            b"FEDCBA9876543210",
+        ] {
+            ws.write_data(index, 0, contents.to_vec())?;
+            ws.commit()?;
+        }
+
+        // Only the last two commits are kept: the current one and the single
+        // one retained in the reflog
+        let mut gc = ws.begin_gc()?;
+        while ws.gc_next_step(&mut gc)? {}
+        let summary = ws.finish_gc(gc);
+        assert_eq!(summary.roots_kept, 2);
+        // Each root is made of an index and a single catalog chunk
+        assert_eq!(summary.chunks_kept, 6);
+        assert_eq!(summary.chunks_removed, 5);
+
+        assert_eq!(ws.read_data(index, 0, 16)?, b"FEDCBA9876543210".to_vec());
+
+        Ok(())
+    }
+}
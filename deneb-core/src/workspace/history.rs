@@ -0,0 +1,142 @@
+use {
+    super::root::read_catalog,
+    crate::{
+        cas::Digest,
+        catalog::{open_catalog, Catalog, CatalogType},
+        errors::DenebResult,
+        inode::INode,
+        store::Store,
+    },
+    std::{
+        collections::HashMap,
+        fs::remove_file,
+        path::{Path, PathBuf},
+    },
+};
+
+/// Catalogs of retained commits, used to look up earlier versions of inodes
+///
+/// The catalog of a commit never changes, so it's unpacked once into the
+/// scratch dir and kept open. Once `capacity` catalogs are open they're all
+/// closed and removed.
+pub(super) struct History {
+    capacity: usize,
+    scratch_dir: PathBuf,
+    catalogs: HashMap<Digest, (Box<dyn Catalog>, PathBuf)>,
+}
+
+impl History {
+    pub(super) fn new(capacity: usize, scratch_dir: &Path) -> History {
+        History {
+            capacity,
+            scratch_dir: scratch_dir.to_owned(),
+            catalogs: HashMap::new(),
+        }
+    }
+
+    /// The inode `index` as it was in the commit with `root_hash`, if it existed
+    pub(super) fn inode(
+        &mut self,
+        store: &dyn Store,
+        catalog_type: CatalogType,
+        root_hash: &Digest,
+        index: u64,
+    ) -> DenebResult<Option<INode>> {
+        if !self.catalogs.contains_key(root_hash) {
+            if self.catalogs.len() >= self.capacity {
+                self.clear();
+            }
+            let path = self
+                .scratch_dir
+                .join(format!("history_catalog.{}", root_hash));
+            read_catalog(store, root_hash, &path)?;
+            let catalog = open_catalog(catalog_type, &path, false)?;
+            self.catalogs.insert(*root_hash, (catalog, path));
+        }
+        let (catalog, _) = &self.catalogs[root_hash];
+        if index > catalog.max_index() {
+            return Ok(None);
+        }
+        Ok(catalog.inode(index).ok())
+    }
+
+    fn clear(&mut self) {
+        for (_, (catalog, path)) in self.catalogs.drain() {
+            drop(catalog);
+            let _ = remove_file(&path);
+            let mut lock_path = path.into_os_string();
+            lock_path.push("-lock");
+            let _ = remove_file(lock_path);
+        }
+    }
+}
+
+impl Drop for History {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::{
+            cas::Algorithm,
+            catalog::CatalogType,
+            errors::DenebResult,
+            store::StoreType,
+            workspace::{init_repository, Workspace, WorkspaceOptions},
+            ImportOptions,
+        },
+        std::ffi::OsStr,
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn previous_versions_are_read_from_earlier_commits() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_history")?;
+        init_repository(
+            tmp.path(),
+            None,
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default(),
+        )?;
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().to_owned(),
+            WorkspaceOptions {
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+
+        let (index, _) = ws.create_file(1, OsStr::new("f1"), 0o644, 0)?;
+        ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+        ws.commit()?;
+        let first = ws.manifest().root_hash;
+        assert!(ws.previous_version(index)?.is_none());
+
+        ws.write_data(index, 0, b"portocala".to_vec())?;
+        ws.commit()?;
+        let (root_hash, inode) = ws.previous_version(index)?.unwrap();
+        assert_eq!(root_hash, first);
+        assert_eq!(inode.attributes.size, 16);
+        assert_eq!(
+            ws.read_previous_version(index)?,
+            Some(b"alabalaportocala".to_vec())
+        );
+
+        // Commits which don't change the file are skipped
+        let (other, _) = ws.create_file(1, OsStr::new("f2"), 0o644, 0)?;
+        ws.write_data(other, 0, b"40 de turcaleti".to_vec())?;
+        ws.commit()?;
+        assert_eq!(ws.previous_version(index)?.unwrap().0, first);
+        assert!(ws.previous_version(other)?.is_none());
+
+        Ok(())
+    }
+}
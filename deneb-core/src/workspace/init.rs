@@ -0,0 +1,240 @@
+use {
+    super::{root::write_catalog, MANIFEST_PATH},
+    crate::{
+        cas::Algorithm,
+        catalog::{open_catalog, CatalogType},
+        crypt::EncryptionKey,
+        errors::{DenebResult, WorkspaceError},
+        manifest::{Chunking, Manifest},
+        populate_empty, populate_with_dir,
+        store::{open_store, Store, StoreType},
+        ImportOptions,
+    },
+    log::info,
+    std::{
+        fs::{create_dir_all, remove_dir_all},
+        path::Path,
+    },
+    time::now_utc,
+};
+
+/// Create a new repository in `work_dir`
+///
+/// The store is laid out and the initial commit is written, with the contents
+/// of `sync_dir` or with an empty root directory. Fails if `work_dir` already
+/// holds a repository. The digests of the chunks are computed with `algorithm`,
+/// which can't be changed afterwards. Returns the manifest of the initial
+/// commit.
+pub fn init_repository(
+    work_dir: &Path,
+    encryption_key: Option<EncryptionKey>,
+    chunk_size: usize,
+    algorithm: Algorithm,
+    sync_dir: Option<&Path>,
+    import: &ImportOptions,
+) -> DenebResult<Manifest> {
+    let manifest_path = work_dir.join(MANIFEST_PATH);
+    if manifest_path.exists() {
+        return Err(WorkspaceError::AlreadyInitialized(work_dir.to_owned()).into());
+    }
+
+    let mut store = open_store(
+        StoreType::OnDisk,
+        work_dir,
+        encryption_key,
+        chunk_size,
+        0,
+        false,
+    )?;
+    store.set_algorithm(algorithm);
+
+    let scratch_dir = work_dir.join("scratch/init");
+    create_dir_all(&scratch_dir)?;
+    let manifest = create_repository(
+        &mut *store,
+        CatalogType::Lmdb,
+        &scratch_dir.join("catalog"),
+        &manifest_path,
+        sync_dir,
+        chunk_size,
+        import,
+    );
+    let _ = remove_dir_all(&scratch_dir);
+
+    info!("Repository initialized in {:?}", work_dir);
+    manifest
+}
+
+/// Fail with `WorkspaceError::NotInitialized` unless `work_dir` holds a repository
+///
+/// Repositories are created by `init_repository` or `clone_repository`.
+pub fn check_repository(work_dir: &Path) -> DenebResult<()> {
+    if work_dir.join(MANIFEST_PATH).exists() {
+        Ok(())
+    } else {
+        Err(WorkspaceError::NotInitialized(work_dir.to_owned()).into())
+    }
+}
+
+/// Write a commit holding the contents of `sync_dir`, or an empty root
+/// directory, as the current state of the repository
+pub(super) fn create_repository(
+    store: &mut dyn Store,
+    catalog_type: CatalogType,
+    catalog_path: &Path,
+    manifest_path: &Path,
+    sync_dir: Option<&Path>,
+    chunk_size: usize,
+    import: &ImportOptions,
+) -> DenebResult<Manifest> {
+    let catalog = open_catalog(catalog_type, catalog_path, true)?;
+    if let Some(sync_dir) = sync_dir {
+        populate_with_dir(&*catalog, store, sync_dir, chunk_size, import)?;
+        info!("Catalog populated with contents of {:?}", sync_dir);
+    } else {
+        populate_empty(&*catalog)?;
+    }
+
+    // Save the generated catalog as content-addressed chunks in the store.
+    catalog.persist()?;
+    let catalog = write_catalog(store, catalog_path, None)?;
+
+    // Create and save the repository manifest
+    store.sync()?;
+    let manifest = Manifest::new(catalog.root_hash, now_utc())
+        .with_chunking(chunk_size, Chunking::Fixed)
+        .with_algorithm(store.algorithm());
+    store.write_special_file(&manifest_path, &mut &manifest.serialize()?[..], false)?;
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            inode::FileType,
+            workspace::{Workspace, WorkspaceOptions},
+        },
+        std::{ffi::OsStr, fs::write},
+        tempdir::TempDir,
+    };
+
+    fn open_workspace(work_dir: &Path) -> DenebResult<Workspace> {
+        Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            work_dir.to_owned(),
+            WorkspaceOptions {
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )
+    }
+
+    #[test]
+    fn repositories_are_initialized_once() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_init")?;
+        let work_dir = tmp.path().join("work");
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(&sync_dir)?;
+        write(sync_dir.join("file"), "alabalaportocala")?;
+
+        // Mounting needs an initialized repository
+        assert!(check_repository(&work_dir).is_err());
+        assert!(open_workspace(&work_dir).is_err());
+
+        let key = Some(EncryptionKey::new());
+        let manifest = init_repository(
+            &work_dir,
+            key.clone(),
+            16,
+            Algorithm::Sha512,
+            Some(&sync_dir),
+            &ImportOptions::default(),
+        )?;
+        assert_eq!(manifest.chunk_size, Some(16));
+        check_repository(&work_dir)?;
+        assert!(!work_dir.join("scratch/init").exists());
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            work_dir.clone(),
+            WorkspaceOptions {
+                encryption_key: key.clone(),
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+        assert_eq!(ws.manifest.root_hash, manifest.root_hash);
+        assert!(ws.resolve_path(Path::new("file"))?.is_some());
+        assert_eq!(ws.root_attr()?.kind, FileType::Directory);
+
+        // An existing repository is never replaced
+        assert!(init_repository(
+            &work_dir,
+            key,
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default()
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_repositories_have_a_root_directory() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_init_empty")?;
+        init_repository(
+            tmp.path(),
+            None,
+            16,
+            Algorithm::Sha512,
+            None,
+            &ImportOptions::default(),
+        )?;
+
+        let mut ws = open_workspace(tmp.path())?;
+        let root = ws.root_attr()?;
+        assert_eq!(root.kind, FileType::Directory);
+        assert_eq!(root.nlink, 2);
+        assert!(ws.resolve_path(Path::new(".."))?.is_some());
+        assert!(ws.resolve_path(Path::new("file"))?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn blake3_repositories_keep_their_algorithm() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_init_blake3")?;
+        let manifest = init_repository(
+            tmp.path(),
+            None,
+            16,
+            Algorithm::Blake3,
+            None,
+            &ImportOptions::default(),
+        )?;
+        assert_eq!(manifest.digest, Some(Algorithm::Blake3));
+        assert_eq!(manifest.root_hash.algorithm(), Algorithm::Blake3);
+
+        let mut ws = open_workspace(tmp.path())?;
+        let (index, _) = ws.create_file(1, OsStr::new("file"), 0o644, 0)?;
+        ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+        ws.commit()?;
+        assert_eq!(ws.manifest.digest, Some(Algorithm::Blake3));
+        assert_eq!(ws.manifest.root_hash.algorithm(), Algorithm::Blake3);
+        drop(ws);
+
+        let mut ws = open_workspace(tmp.path())?;
+        ws.open_file(index, 0)?;
+        assert_eq!(b"alabalaportocala", ws.read_data(index, 0, 64)?.as_slice());
+        Ok(())
+    }
+}
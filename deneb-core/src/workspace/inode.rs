@@ -1,4 +1,11 @@
-use crate::inode::{FileAttributeChanges, INode};
+use {
+    crate::inode::{blocks_for, ChunkDescriptor, FileAttributeChanges, INode},
+    std::{
+        collections::{HashMap, HashSet},
+        mem::size_of,
+    },
+    time::now_utc,
+};
 
 #[derive(Clone)]
 pub(super) struct Workspace {
@@ -20,10 +27,212 @@ impl Workspace {
         self.dirty = true;
     }
 
+    /// Record a change of the contents made without the kernel, which sets
+    /// the times itself for its writes
+    pub(super) fn touch_data(&mut self) {
+        let now = now_utc().to_timespec();
+        self.inode.attributes.mtime = now;
+        self.inode.attributes.ctime = now;
+        self.dirty = true;
+    }
+
+    /// Drop one of the hard links to the inode, returning the number left
+    pub(super) fn unlink(&mut self) -> u32 {
+        self.inode.attributes.nlink = self.inode.attributes.nlink.saturating_sub(1);
+        self.dirty = true;
+        self.inode.attributes.nlink
+    }
+
+    /// Set or, without a value, remove an extended attribute of the inode,
+    /// returning its previous value
+    pub(super) fn set_xattr(&mut self, name: &str, value: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        let previous = match value {
+            Some(value) => self.inode.xattrs.insert(name.to_owned(), value),
+            None => self.inode.xattrs.remove(name),
+        };
+        self.inode.attributes.ctime = now_utc().to_timespec();
+        self.dirty = true;
+        previous
+    }
+
+    /// Estimate of the memory held by the inode
+    pub(super) fn memory_size(&self) -> u64 {
+        let chunks = self.inode.chunks.len() * size_of::<ChunkDescriptor>();
+        let xattrs = self
+            .inode
+            .xattrs
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum::<usize>();
+        (size_of::<Entry>() + chunks + xattrs) as u64
+    }
+
     pub(super) fn update_size(&mut self, size: u64) {
         if self.inode.attributes.size != size {
             self.inode.attributes.size = size;
+            self.inode.attributes.blocks = blocks_for(size);
             self.dirty = true;
         }
     }
 }
+
+/// A bounded cache of inode workspaces, shared by all the requests
+///
+/// Inodes which were modified since the last commit are dirty and stay in the
+/// cache until they are written to the catalog. Once the cache holds more than
+/// `capacity` inodes, the least recently used clean ones are evicted; they are
+/// read again from the catalog when needed.
+pub(super) struct Cache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<u64, Entry>,
+}
+
+struct Entry {
+    ws: Workspace,
+    last_used: u64,
+}
+
+impl Cache {
+    pub(super) fn new(capacity: usize) -> Cache {
+        Cache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub(super) fn get(&mut self, index: u64) -> Option<&Workspace> {
+        self.get_mut(index).map(|ws| &*ws)
+    }
+
+    pub(super) fn get_mut(&mut self, index: u64) -> Option<&mut Workspace> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(&index)?;
+        entry.last_used = self.tick;
+        Some(&mut entry.ws)
+    }
+
+    pub(super) fn insert(&mut self, index: u64, ws: Workspace) {
+        self.tick += 1;
+        self.entries.insert(
+            index,
+            Entry {
+                ws,
+                last_used: self.tick,
+            },
+        );
+        if self.entries.len() > self.capacity {
+            self.evict(Some(index));
+        }
+    }
+
+    pub(super) fn remove(&mut self, index: u64) {
+        self.entries.remove(&index);
+    }
+
+    /// Remove the inode unless it's dirty
+    pub(super) fn remove_clean(&mut self, index: u64) {
+        if self
+            .entries
+            .get(&index)
+            .map_or(false, |entry| !entry.ws.dirty)
+        {
+            self.entries.remove(&index);
+        }
+    }
+
+    pub(super) fn dirty(&self) -> impl Iterator<Item = &Workspace> {
+        self.entries
+            .values()
+            .map(|entry| &entry.ws)
+            .filter(|ws| ws.dirty)
+    }
+
+    /// Estimate of the memory held by the cached inodes
+    pub(super) fn memory_size(&self) -> u64 {
+        self.entries
+            .values()
+            .map(|entry| entry.ws.memory_size())
+            .sum()
+    }
+
+    /// Evict all the clean inodes, except for the ones in `keep`
+    pub(super) fn evict_clean(&mut self, keep: &HashSet<u64>) {
+        self.entries
+            .retain(|index, entry| entry.ws.dirty || keep.contains(index));
+    }
+
+    /// Mark all the inodes as clean, once they are written to the catalog
+    pub(super) fn mark_clean(&mut self) {
+        for entry in self.entries.values_mut() {
+            entry.ws.dirty = false;
+        }
+        if self.entries.len() > self.capacity {
+            self.evict(None);
+        }
+    }
+
+    // Evict clean inodes until the cache is a tenth below its capacity, so that
+    // the cost of finding the least recently used ones is shared by many inserts.
+    // The inode identified by `keep` is never evicted.
+    fn evict(&mut self, keep: Option<u64>) {
+        let target = self.capacity - self.capacity / 10;
+        let mut clean = self
+            .entries
+            .iter()
+            .filter(|(index, entry)| !entry.ws.dirty && Some(**index) != keep)
+            .map(|(index, entry)| (entry.last_used, *index))
+            .collect::<Vec<_>>();
+        clean.sort_unstable();
+        let excess = self.entries.len().saturating_sub(target);
+        for (_, index) in clean.into_iter().take(excess) {
+            self.entries.remove(&index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::inode::FileAttributes};
+
+    fn inode_ws(index: u64, dirty: bool) -> Workspace {
+        let mut attributes = FileAttributes::default();
+        attributes.index = index;
+        Workspace::new(INode::new(attributes, vec![]), dirty)
+    }
+
+    #[test]
+    fn evicts_least_recently_used_clean_inodes() {
+        let mut cache = Cache::new(3);
+        cache.insert(1, inode_ws(1, false));
+        cache.insert(2, inode_ws(2, true));
+        cache.insert(3, inode_ws(3, false));
+        assert!(cache.get(1).is_some());
+        cache.insert(4, inode_ws(4, false));
+
+        // The dirty inode is kept even though it's the least recently used
+        assert!(cache.get(3).is_none());
+        assert!(cache.get(1).is_some());
+        assert!(cache.get(2).is_some());
+        assert!(cache.get(4).is_some());
+        assert_eq!(cache.entries.len(), 3);
+
+        cache.insert(5, inode_ws(5, true));
+        cache.insert(6, inode_ws(6, true));
+        cache.insert(7, inode_ws(7, true));
+        assert_eq!(cache.entries.len(), 4);
+        assert_eq!(cache.dirty().count(), 4);
+
+        cache.mark_clean();
+        assert_eq!(cache.dirty().count(), 0);
+        assert_eq!(cache.entries.len(), 3);
+        assert!(cache.get(7).is_some());
+
+        cache.insert(8, inode_ws(8, true));
+        cache.remove_clean(7);
+        cache.remove_clean(8);
+        assert!(cache.get(7).is_none());
+        assert!(cache.get(8).is_some());
+    }
+}
@@ -0,0 +1,82 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+/// Names which were looked up and not found, by parent directory
+///
+/// Lookups in directories which aren't open go to the catalog; workloads like
+/// compilers probing for headers look up the same missing names many times.
+/// The entries of a directory are dropped whenever the directory is changed.
+/// Once the cache holds `capacity` names it's emptied.
+pub(super) struct NegativeCache {
+    capacity: usize,
+    len: usize,
+    misses: HashMap<u64, HashSet<PathBuf>>,
+}
+
+impl NegativeCache {
+    pub(super) fn new(capacity: usize) -> NegativeCache {
+        NegativeCache {
+            capacity,
+            len: 0,
+            misses: HashMap::new(),
+        }
+    }
+
+    pub(super) fn contains(&self, parent: u64, name: &Path) -> bool {
+        self.misses
+            .get(&parent)
+            .map_or(false, |names| names.contains(name))
+    }
+
+    pub(super) fn insert(&mut self, parent: u64, name: &Path) {
+        if self.len >= self.capacity {
+            self.misses.clear();
+            self.len = 0;
+        }
+        if self
+            .misses
+            .entry(parent)
+            .or_insert_with(HashSet::new)
+            .insert(name.to_owned())
+        {
+            self.len += 1;
+        }
+    }
+
+    /// Forget the missing names of the directory `parent`
+    pub(super) fn invalidate(&mut self, parent: u64) {
+        if let Some(names) = self.misses.remove(&parent) {
+            self.len -= names.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misses_are_forgotten_when_the_parent_changes() {
+        let mut cache = NegativeCache::new(3);
+        cache.insert(1, Path::new("a.h"));
+        cache.insert(1, Path::new("b.h"));
+        cache.insert(1, Path::new("b.h"));
+        cache.insert(2, Path::new("a.h"));
+        assert!(cache.contains(1, Path::new("a.h")));
+        assert!(cache.contains(2, Path::new("a.h")));
+        assert!(!cache.contains(2, Path::new("b.h")));
+
+        cache.invalidate(1);
+        assert!(!cache.contains(1, Path::new("a.h")));
+        assert!(cache.contains(2, Path::new("a.h")));
+
+        // Full caches are emptied
+        cache.insert(3, Path::new("a.h"));
+        cache.insert(3, Path::new("b.h"));
+        cache.insert(3, Path::new("c.h"));
+        assert!(!cache.contains(2, Path::new("a.h")));
+        assert!(cache.contains(3, Path::new("c.h")));
+    }
+}
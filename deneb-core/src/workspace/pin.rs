@@ -0,0 +1,75 @@
+use {
+    super::Workspace,
+    crate::{
+        errors::DenebResult,
+        inode::{FileType, INode},
+    },
+    std::{collections::HashSet, path::Path},
+};
+
+/// Pin or unpin the entry `index`
+///
+/// The pins are recorded in the catalog, and committed with it. Returns
+/// whether the entry was pinned before.
+pub(super) fn set_pinned(ws: &mut Workspace, index: u64, pinned: bool) -> DenebResult<bool> {
+    // The entry has to exist
+    ws.get_attr(index)?;
+    let mut pins = ws.catalog.pins()?;
+    let was_pinned = pins.binary_search(&index);
+    match (was_pinned, pinned) {
+        (Err(position), true) => pins.insert(position, index),
+        (Ok(position), false) => {
+            pins.remove(position);
+        }
+        _ => return Ok(was_pinned.is_ok()),
+    }
+    ws.catalog.set_pins(&pins)?;
+    ws.dirty = true;
+    refresh_pins(ws)?;
+    Ok(was_pinned.is_ok())
+}
+
+/// Pass the chunks of the pinned entries, and of everything under the pinned
+/// directories, to the store
+///
+/// Only the committed entries are visited: the data which wasn't committed
+/// yet is held by the workspace, and its chunks are pinned once it's
+/// committed.
+pub(super) fn refresh_pins(ws: &mut Workspace) -> DenebResult<()> {
+    let pins = ws.catalog.pins()?;
+    let mut digests = HashSet::new();
+    visit_subtrees(ws, &pins, |inode| {
+        digests.extend(inode.chunks.iter().map(|chunk| chunk.digest))
+    })?;
+    ws.pinned = (pins.len() as u64, digests.len() as u64);
+    ws.store.borrow_mut().set_pinned(&digests)
+}
+
+/// Call `visit` with the committed inodes of the entries `roots` and of
+/// everything under them, each once
+pub(super) fn visit_subtrees<F: FnMut(&INode)>(
+    ws: &Workspace,
+    roots: &[u64],
+    mut visit: F,
+) -> DenebResult<()> {
+    let mut seen = HashSet::new();
+    let mut pending = roots.to_vec();
+    while let Some(index) = pending.pop() {
+        if !seen.insert(index) {
+            continue;
+        }
+        let inode = match ws.catalog.inode(index) {
+            Ok(inode) => inode,
+            Err(_) => continue,
+        };
+        visit(&inode);
+        if inode.attributes.kind == FileType::Directory {
+            for (name, child) in ws.catalog.dir_entries(index)? {
+                if name != Path::new(".") && name != Path::new("..") {
+                    pending.push(child);
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,366 @@
+use {
+    crate::{
+        cas::{digest_from_slice, Digest},
+        errors::{DenebError, DenebResult},
+        manifest::Manifest,
+        store::Store,
+    },
+    bincode::{deserialize, serialize},
+    data_encoding::HEXLOWER,
+    std::{
+        fmt::{Display, Error as FmtError, Formatter, Result as FmtResult},
+        path::Path,
+        str::from_utf8,
+    },
+};
+
+/// An entry of the reflog, recording a root hash which was replaced by a commit
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct Entry {
+    pub(super) root_hash: Digest,
+    /// Time at which the root hash was replaced, in seconds since the epoch
+    pub(super) timestamp: i64,
+    /// The manifest which was replaced, if it had any metadata
+    pub(super) manifest: Option<Manifest>,
+}
+
+impl Entry {
+    #[cfg(test)]
+    pub(super) fn new(root_hash: Digest, timestamp: i64) -> Entry {
+        Entry {
+            root_hash,
+            timestamp,
+            manifest: None,
+        }
+    }
+
+    /// Create the entry recording the replacement of `manifest`
+    pub(super) fn replacing(manifest: &Manifest, timestamp: i64) -> Entry {
+        Entry {
+            root_hash: manifest.root_hash,
+            timestamp,
+            manifest: manifest.metadata.as_ref().map(|_| manifest.clone()),
+        }
+    }
+}
+
+// The manifest is appended to the line of the entry in hex-encoded bincode
+impl Display for Entry {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{} {}", self.root_hash, self.timestamp)?;
+        if let Some(ref manifest) = self.manifest {
+            let encoded = serialize(manifest).map_err(|_| FmtError)?;
+            write!(f, " {}", HEXLOWER.encode(&encoded))?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Determines which entries are kept when the reflog is compacted
+///
+/// An entry is kept if it is one of the `max_entries` most recent entries,
+/// or if it is younger than `max_age` seconds. When neither limit is set,
+/// all the entries are kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RetentionPolicy {
+    pub max_entries: Option<usize>,
+    pub max_age: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn new(max_entries: Option<usize>, max_age: Option<u64>) -> RetentionPolicy {
+        RetentionPolicy {
+            max_entries,
+            max_age,
+        }
+    }
+
+    /// Returns the entries which should be kept at time `now`
+    pub(super) fn retain(&self, entries: Vec<Entry>, now: i64) -> Vec<Entry> {
+        if self.max_entries.is_none() && self.max_age.is_none() {
+            return entries;
+        }
+        let first_recent = self
+            .max_entries
+            .map(|max| entries.len().saturating_sub(max))
+            .unwrap_or_else(|| entries.len());
+        entries
+            .into_iter()
+            .enumerate()
+            .filter(|(i, entry)| {
+                *i >= first_recent
+                    || self
+                        .max_age
+                        .map(|max_age| now - entry.timestamp < max_age as i64)
+                        .unwrap_or(false)
+            })
+            .map(|(_, entry)| entry)
+            .collect()
+    }
+}
+
+/// Determines which entries are kept when the reflog is pruned
+///
+/// An entry is kept if it is one of the `keep_last` most recent entries, if it
+/// is the most recent entry of one of the last `keep_daily` days, or if it is
+/// the most recent entry of one of the last `keep_weekly` weeks. Days and weeks
+/// are counted in UTC from the epoch. When no limit is set, all the entries
+/// are kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PrunePolicy {
+    pub keep_last: Option<usize>,
+    pub keep_daily: Option<u64>,
+    pub keep_weekly: Option<u64>,
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+const SECONDS_PER_WEEK: i64 = 7 * SECONDS_PER_DAY;
+
+impl PrunePolicy {
+    pub fn new(
+        keep_last: Option<usize>,
+        keep_daily: Option<u64>,
+        keep_weekly: Option<u64>,
+    ) -> PrunePolicy {
+        PrunePolicy {
+            keep_last,
+            keep_daily,
+            keep_weekly,
+        }
+    }
+
+    /// Returns the entries which should be kept at time `now`
+    pub(super) fn retain(&self, entries: Vec<Entry>, now: i64) -> Vec<Entry> {
+        if *self == PrunePolicy::default() {
+            return entries;
+        }
+        let first_recent = entries.len().saturating_sub(self.keep_last.unwrap_or(0));
+        // Entries are ordered from the oldest to the newest, so an entry is the
+        // most recent one of its period if the next entry is in a later period
+        let newest_of_period = |i: usize, period: i64, count: Option<u64>| {
+            let count = count.unwrap_or(0) as i64;
+            let bucket = entries[i].timestamp / period;
+            bucket > now / period - count
+                && entries
+                    .get(i + 1)
+                    .map(|next| next.timestamp / period > bucket)
+                    .unwrap_or(true)
+        };
+        let kept = (0..entries.len())
+            .map(|i| {
+                i >= first_recent
+                    || newest_of_period(i, SECONDS_PER_DAY, self.keep_daily)
+                    || newest_of_period(i, SECONDS_PER_WEEK, self.keep_weekly)
+            })
+            .collect::<Vec<_>>();
+        entries
+            .into_iter()
+            .zip(kept)
+            .filter(|(_, kept)| *kept)
+            .map(|(entry, _)| entry)
+            .collect()
+    }
+}
+
+/// Parse the contents of the reflog
+///
+/// Entries written by older versions only contain the root hash; these are
+/// given a timestamp of 0. The replaced manifest is only recorded if it had
+/// any metadata.
+pub(super) fn parse(buf: &[u8]) -> DenebResult<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for line in from_utf8(buf)?.lines() {
+        let mut fields = line.split_whitespace();
+        let root_hash = match fields.next() {
+            Some(hash) => digest_from_slice(hash.as_bytes())?,
+            None => continue,
+        };
+        let timestamp = match fields.next() {
+            Some(timestamp) => timestamp
+                .parse::<i64>()
+                .map_err(|_| DenebError::ReflogParse(line.to_owned()))?,
+            None => 0,
+        };
+        let manifest = match fields.next() {
+            Some(encoded) => Some(
+                HEXLOWER
+                    .decode(encoded.as_bytes())
+                    .ok()
+                    .and_then(|buf| deserialize::<Manifest>(&buf).ok())
+                    .ok_or_else(|| DenebError::ReflogParse(line.to_owned()))?,
+            ),
+            None => None,
+        };
+        entries.push(Entry {
+            root_hash,
+            timestamp,
+            manifest,
+        });
+    }
+    Ok(entries)
+}
+
+/// Read the entries of the reflog stored in `path`
+pub(super) fn read(store: &dyn Store, path: &Path) -> DenebResult<Vec<Entry>> {
+    match store.read_special_file(path) {
+        Ok(buf) => parse(&buf),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+/// Append an entry to the reflog stored in `path`
+pub(super) fn append(store: &mut dyn Store, path: &Path, entry: &Entry) -> DenebResult<()> {
+    let buf = entry.to_string();
+    store.write_special_file(path, &mut buf.as_bytes(), true)
+}
+
+/// Remove the entries of the reflog which are not retained by the policy
+///
+/// Returns the retained entries. The reflog is only rewritten if any entries
+/// were removed.
+pub(super) fn compact(
+    store: &mut dyn Store,
+    path: &Path,
+    policy: RetentionPolicy,
+    now: i64,
+) -> DenebResult<Vec<Entry>> {
+    let entries = read(store, path)?;
+    let num_entries = entries.len();
+    let entries = policy.retain(entries, now);
+    if entries.len() < num_entries {
+        rewrite(store, path, &entries)?;
+    }
+    Ok(entries)
+}
+
+/// Remove the entries of the reflog which are not kept by the prune policy
+///
+/// Returns the number of removed entries, whose commits are then reclaimed by
+/// the next garbage collection.
+pub(super) fn prune(
+    store: &mut dyn Store,
+    path: &Path,
+    policy: PrunePolicy,
+    now: i64,
+) -> DenebResult<usize> {
+    let entries = read(store, path)?;
+    let num_entries = entries.len();
+    let entries = policy.retain(entries, now);
+    if entries.len() < num_entries {
+        rewrite(store, path, &entries)?;
+    }
+    Ok(num_entries - entries.len())
+}
+
+fn rewrite(store: &mut dyn Store, path: &Path, entries: &[Entry]) -> DenebResult<()> {
+    let buf = entries.iter().map(Entry::to_string).collect::<String>();
+    store.write_special_file(path, &mut buf.as_bytes(), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{cas::hash, manifest::Metadata},
+        time::now_utc,
+    };
+
+    fn make_entries(timestamps: &[i64]) -> Vec<Entry> {
+        timestamps
+            .iter()
+            .map(|ts| Entry::new(hash(&ts.to_le_bytes()), *ts))
+            .collect()
+    }
+
+    #[test]
+    fn parse_entries() -> DenebResult<()> {
+        let entries = make_entries(&[10, 20]);
+        let mut buf = entries.iter().map(Entry::to_string).collect::<String>();
+        // Entry without a timestamp
+        buf.push_str(&format!("{}\n", hash(b"old")));
+        // Entry with the replaced manifest
+        let mut manifest = Manifest::new(hash(b"new"), now_utc());
+        manifest.timestamp.tm_nsec = 0;
+        let manifest = manifest.with_metadata(Metadata::default());
+        buf.push_str(&Entry::replacing(&manifest, 30).to_string());
+
+        let parsed = parse(buf.as_bytes())?;
+        assert_eq!(&parsed[..2], entries.as_slice());
+        assert_eq!(parsed[2], Entry::new(hash(b"old"), 0));
+        assert_eq!(parsed[3].root_hash, hash(b"new"));
+        assert_eq!(parsed[3].timestamp, 30);
+        assert_eq!(
+            parsed[3].manifest.as_ref().and_then(|m| m.metadata.clone()),
+            Some(Metadata::default())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retention_policies() {
+        let entries = make_entries(&[10, 20, 30, 40, 50]);
+        let retained = |policy: RetentionPolicy| {
+            policy
+                .retain(entries.clone(), 60)
+                .iter()
+                .map(|e| e.timestamp)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            retained(RetentionPolicy::default()),
+            vec![10, 20, 30, 40, 50]
+        );
+        assert_eq!(retained(RetentionPolicy::new(Some(2), None)), vec![40, 50]);
+        assert_eq!(
+            retained(RetentionPolicy::new(None, Some(35))),
+            vec![30, 40, 50]
+        );
+        assert_eq!(
+            retained(RetentionPolicy::new(Some(1), Some(25))),
+            vec![40, 50]
+        );
+        assert_eq!(
+            retained(RetentionPolicy::new(Some(4), Some(15))),
+            vec![20, 30, 40, 50]
+        );
+    }
+
+    #[test]
+    fn prune_policies() {
+        let day = SECONDS_PER_DAY;
+        // Two commits a day, for the last 20 days
+        let timestamps = (0..40).map(|i| i / 2 * day + i % 2).collect::<Vec<_>>();
+        let entries = make_entries(&timestamps);
+        let now = 20 * day;
+        let retained = |policy: PrunePolicy| {
+            policy
+                .retain(entries.clone(), now)
+                .iter()
+                .map(|e| e.timestamp)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(retained(PrunePolicy::default()), timestamps);
+        assert_eq!(
+            retained(PrunePolicy::new(Some(3), None, None)),
+            vec![18 * day + 1, 19 * day, 19 * day + 1]
+        );
+        assert_eq!(
+            retained(PrunePolicy::new(None, Some(3), None)),
+            vec![18 * day + 1, 19 * day + 1]
+        );
+        assert_eq!(
+            retained(PrunePolicy::new(Some(1), Some(2), None)),
+            vec![19 * day + 1]
+        );
+        assert_eq!(
+            retained(PrunePolicy::new(None, None, Some(3))),
+            vec![6 * day + 1, 13 * day + 1, 19 * day + 1]
+        );
+        assert_eq!(
+            retained(PrunePolicy::new(Some(1), Some(1), Some(1))),
+            vec![19 * day + 1]
+        );
+    }
+}
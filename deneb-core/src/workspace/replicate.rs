@@ -0,0 +1,102 @@
+use {
+    super::{MANIFEST_PATH, REFLOG_PATH},
+    crate::{
+        errors::DenebResult,
+        manifest::Manifest,
+        store::{open_remote_store, open_store, StoreType},
+    },
+    log::info,
+    std::{collections::HashSet, path::Path},
+};
+
+/// Copy the committed state of the repository in `work_dir` into `dest_dir`
+///
+/// Chunks, which also hold the catalog snapshots, are copied in their packed
+/// form, so the encryption key is not needed. The digest of every packed chunk
+/// is checked before it is stored. Only the chunks missing from `dest_dir` are
+/// copied, which makes repeated replication to the same destination
+/// incremental. Returns the number of chunks copied.
+pub fn replicate_repository(
+    work_dir: &Path,
+    dest_dir: &Path,
+    chunk_size: usize,
+) -> DenebResult<usize> {
+    let source = open_remote_store(work_dir, dest_dir, None, chunk_size)?;
+    let mut dest = open_store(StoreType::OnDisk, dest_dir, None, chunk_size, 0, false)?;
+
+    // The manifest is read first, the chunks it refers to are never removed
+    // while it is current
+    let buf = source.read_special_file(&work_dir.join(MANIFEST_PATH))?;
+    Manifest::deserialize(&buf)?;
+
+    let present = dest.chunk_digests()?.into_iter().collect::<HashSet<_>>();
+    let mut copied = 0;
+    for digest in source.chunk_digests()? {
+        if !present.contains(&digest) {
+            dest.put_packed_chunk(&digest, &source.packed_chunk(&digest)?)?;
+            copied += 1;
+        }
+    }
+
+    if let Ok(reflog) = source.read_special_file(&work_dir.join(REFLOG_PATH)) {
+        dest.write_special_file(&dest_dir.join(REFLOG_PATH), &mut &reflog[..], false)?;
+    }
+
+    // The manifest is only written once everything it refers to is stored
+    dest.sync()?;
+    dest.write_special_file(&dest_dir.join(MANIFEST_PATH), &mut &buf[..], false)?;
+
+    info!(
+        "Replicated {:?} into {:?}: {} chunks copied",
+        work_dir, dest_dir, copied
+    );
+
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            cas::Algorithm,
+            crypt::EncryptionKey,
+            workspace::{init_repository, Snapshot},
+            ImportOptions,
+        },
+        std::fs::{create_dir_all, write},
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn replicas_are_made_without_the_key() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_replicate")?;
+        let work_dir = tmp.path().join("work");
+        let dest_dir = tmp.path().join("backup");
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(&sync_dir)?;
+        write(sync_dir.join("file"), "alabalaportocala")?;
+
+        let key = Some(EncryptionKey::new());
+        init_repository(
+            &work_dir,
+            key.clone(),
+            16,
+            Algorithm::Sha512,
+            Some(&sync_dir),
+            &ImportOptions::default(),
+        )?;
+
+        let copied = replicate_repository(&work_dir, &dest_dir, 16)?;
+        assert!(copied > 0);
+        assert_eq!(replicate_repository(&work_dir, &dest_dir, 16)?, 0);
+
+        let snapshot = Snapshot::open(&dest_dir, key, 16)?;
+        let attrs = snapshot.resolve(Path::new("file"))?.unwrap();
+        let mut contents = Vec::new();
+        snapshot.read_file(attrs.index, &mut contents)?;
+        assert_eq!(contents, b"alabalaportocala".to_vec());
+
+        Ok(())
+    }
+}
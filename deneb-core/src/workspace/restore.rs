@@ -0,0 +1,232 @@
+use {
+    super::{
+        dir::Workspace as DirWorkspace, inode::Workspace as INodeWorkspace, root::read_catalog,
+        Workspace,
+    },
+    crate::{
+        cas::Digest,
+        catalog::{open_catalog, Catalog},
+        errors::{DenebResult, WorkspaceError},
+        inode::{FileType, INode},
+    },
+    std::{
+        ffi::{OsStr, OsString},
+        fs::remove_file,
+        path::{Component, Path, PathBuf},
+    },
+};
+
+/// Suffix of the name under which an entry is restored next to the current one
+const RESTORED_SUFFIX: &str = ".restored";
+
+/// Copy the file or directory found at `path` in the commit with root hash
+/// `root_hash` into the workspace
+///
+/// The entry is restored as `<name>.restored`, next to the current one, or in
+/// its place when `overwrite` is set. Only regular files and symbolic links
+/// can be overwritten. The data of the restored files isn't copied, the new
+/// inodes refer to the chunks of the old ones. Returns the path of the
+/// restored entry.
+pub(super) fn restore(
+    ws: &mut Workspace,
+    root_hash: &Digest,
+    path: &Path,
+    overwrite: bool,
+) -> DenebResult<PathBuf> {
+    // Only the retained commits are guaranteed to have all their chunks
+    if !ws.log()?.iter().any(|m| m.root_hash == *root_hash) {
+        return Err(WorkspaceError::UnknownCommit(root_hash.to_string()).into());
+    }
+
+    let catalog_path = ws.work_dir.join("scratch/restore_catalog");
+    read_catalog(&**ws.store.borrow(), root_hash, &catalog_path)?;
+    let result = open_catalog(ws.catalog_type, &catalog_path, false)
+        .and_then(|catalog| restore_from(ws, &*catalog, root_hash, path, overwrite));
+
+    let _ = remove_file(&catalog_path);
+    let mut lock_path = catalog_path.into_os_string();
+    lock_path.push("-lock");
+    let _ = remove_file(lock_path);
+
+    result
+}
+
+fn restore_from(
+    ws: &mut Workspace,
+    old: &dyn Catalog,
+    root_hash: &Digest,
+    path: &Path,
+    overwrite: bool,
+) -> DenebResult<PathBuf> {
+    let not_found = || WorkspaceError::RestoreLookup(path.to_owned(), root_hash.to_string());
+    let old_index = resolve(old, path)?.ok_or_else(not_found)?;
+    let (parent_path, name) = match (path.parent(), path.file_name()) {
+        (Some(parent_path), Some(name)) => (parent_path, name),
+        _ => return Err(not_found().into()),
+    };
+    let parent = match ws.resolve_path(parent_path)? {
+        Some((parent, attrs)) if attrs.kind == FileType::Directory => parent,
+        _ => return Err(WorkspaceError::RestoreConflict(path.to_owned()).into()),
+    };
+
+    let name = if overwrite {
+        name.to_owned()
+    } else {
+        let mut name = name.to_owned();
+        name.push(RESTORED_SUFFIX);
+        name
+    };
+    let restored_path = parent_path.join(&name);
+    if let Some(attrs) = ws.lookup(parent, &name)? {
+        if !overwrite || attrs.kind == FileType::Directory {
+            return Err(WorkspaceError::RestoreConflict(restored_path).into());
+        }
+        ws.delete(parent, &name)?;
+    }
+    ws.check_name_conflict(parent, &name)?;
+
+    copy_entry(ws, old, old_index, parent, name)?;
+    Ok(restored_path)
+}
+
+/// Copy an inode of `old`, and everything under it, into the directory `parent`
+///
+/// Hard links are not preserved, each of them becomes a separate file.
+fn copy_entry(
+    ws: &mut Workspace,
+    old: &dyn Catalog,
+    old_index: u64,
+    parent: u64,
+    name: OsString,
+) -> DenebResult<()> {
+    let old_inode = old.inode(old_index)?;
+    let index = ws.index_generator.next();
+    let mut attributes = old_inode.attributes;
+    attributes.index = index;
+    attributes.nlink = 1;
+    let kind = attributes.kind;
+    let mut inode = INode::new(attributes, old_inode.chunks);
+    inode.digest = old_inode.digest;
+    ws.inodes.insert(index, INodeWorkspace::new(inode, true));
+
+    ws.negative_lookups.invalidate(parent);
+    ws.open_dir(parent)?;
+    ws.dirs
+        .get_mut(&parent)
+        .ok_or_else(|| WorkspaceError::DirLookup(parent))?
+        .add_entry(index, PathBuf::from(name), kind);
+    ws.dirty = true;
+
+    if kind == FileType::Directory {
+        let mut dws = DirWorkspace::new(&[]);
+        dws.add_entry(index, PathBuf::from("."), FileType::Directory);
+        dws.add_entry(parent, PathBuf::from(".."), FileType::Directory);
+        ws.dirs.insert(index, dws);
+        for (child_name, child_index) in old.dir_entries(old_index)? {
+            if child_name != Path::new(".") && child_name != Path::new("..") {
+                copy_entry(ws, old, child_index, index, child_name.into_os_string())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Symbolic links are not followed
+fn resolve(catalog: &dyn Catalog, path: &Path) -> DenebResult<Option<u64>> {
+    let mut index = catalog.root_index()?;
+    for component in path.components() {
+        let name = match component {
+            Component::Normal(name) => name,
+            Component::ParentDir => OsStr::new(".."),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => continue,
+        };
+        if catalog.inode(index)?.attributes.kind != FileType::Directory {
+            return Ok(None);
+        }
+        match catalog.dir_entry_index(index, Path::new(name))? {
+            Some(entry_index) => index = entry_index,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{catalog::CatalogType, store::StoreType, workspace::WorkspaceOptions},
+        std::fs::{create_dir_all, write},
+        tempdir::TempDir,
+    };
+
+    fn read_path(ws: &mut Workspace, path: &str) -> DenebResult<Vec<u8>> {
+        let (index, attrs) = ws
+            .resolve_path(Path::new(path))?
+            .ok_or_else(|| WorkspaceError::FileLookup(0))?;
+        ws.open_file(index, 0)?;
+        ws.read_data(index, 0, attrs.size as u32)
+    }
+
+    #[test]
+    fn files_are_restored_from_older_commits() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_restore")?;
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(sync_dir.join("dir/sub"))?;
+        write(sync_dir.join("dir/file"), "alabalaportocala")?;
+        write(sync_dir.join("dir/sub/other"), "40 de turcaleti")?;
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().join("work"),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir),
+                chunk_size: 8,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+        let first_root = ws.manifest.root_hash;
+
+        let (dir, _) = ws
+            .resolve_path(Path::new("dir"))?
+            .ok_or_else(|| WorkspaceError::DirLookup(0))?;
+        let (file, _) = ws
+            .resolve_path(Path::new("dir/file"))?
+            .ok_or_else(|| WorkspaceError::FileLookup(0))?;
+        ws.open_file(file, 0)?;
+        ws.write_data(file, 0, b"ALABALA".to_vec())?;
+        let (sub, _) = ws
+            .resolve_path(Path::new("dir/sub"))?
+            .ok_or_else(|| WorkspaceError::DirLookup(0))?;
+        ws.remove(sub, OsStr::new("other"))?;
+        ws.remove(dir, OsStr::new("sub"))?;
+        ws.commit()?;
+
+        // Next to the current file
+        let restored = restore(&mut ws, &first_root, Path::new("/dir/file"), false)?;
+        assert_eq!(restored, PathBuf::from("/dir/file.restored"));
+        assert_eq!(
+            read_path(&mut ws, "dir/file.restored")?,
+            b"alabalaportocala"
+        );
+        assert_eq!(read_path(&mut ws, "dir/file")?, b"ALABALAportocala");
+        assert!(restore(&mut ws, &first_root, Path::new("dir/file"), false).is_err());
+
+        // In place of the current file
+        restore(&mut ws, &first_root, Path::new("dir/file"), true)?;
+        assert_eq!(read_path(&mut ws, "dir/file")?, b"alabalaportocala");
+
+        // Whole directories
+        restore(&mut ws, &first_root, Path::new("dir/sub"), true)?;
+        ws.commit()?;
+        assert_eq!(read_path(&mut ws, "dir/sub/other")?, b"40 de turcaleti");
+
+        assert!(restore(&mut ws, &first_root, Path::new("dir/missing"), false).is_err());
+        let unknown = crate::cas::hash(b"unknown commit");
+        assert!(restore(&mut ws, &unknown, Path::new("dir/file"), false).is_err());
+
+        Ok(())
+    }
+}
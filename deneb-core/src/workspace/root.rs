@@ -0,0 +1,254 @@
+use {
+    crate::{
+        cas::{hash, read_chunked, Digest},
+        errors::DenebResult,
+        inode::ChunkDescriptor,
+        store::{Chunk, ChunkReader, Store},
+        util::atomic_write_from,
+    },
+    bincode::{deserialize, serialize},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        fs::File,
+        io::{empty, Read},
+        path::Path,
+        sync::Arc,
+    },
+};
+
+/// Prefix of a root chunk which lists the chunks of the catalog
+///
+/// Repositories created before the catalog was chunked have the whole
+/// catalog file as their root chunk, which never starts with this marker.
+const INDEX_MARKER: &[u8] = b"deneb-catalog-index\n";
+
+/// Size of the chunks the catalog is split into
+///
+/// It doesn't depend on the chunk size of the files, which can be much smaller.
+const CATALOG_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// A chunk of a catalog file
+#[derive(Deserialize, Serialize)]
+pub(super) struct CatalogChunk {
+    pub(super) descriptor: ChunkDescriptor,
+    /// Hash of the contents of the chunk
+    ///
+    /// Unlike the digest of the chunk, it doesn't change when the same contents
+    /// are packed again with another nonce.
+    pub(super) contents: Digest,
+}
+
+/// The chunks a catalog file is split into, in order
+#[derive(Deserialize, Serialize)]
+pub(super) struct CatalogIndex {
+    pub(super) chunks: Vec<CatalogChunk>,
+}
+
+impl CatalogIndex {
+    /// Load the index found at `root`, or `None` if the root chunk is an unchunked catalog
+    pub(super) fn load(store: &dyn Store, root: &Digest) -> DenebResult<Option<CatalogIndex>> {
+        let chunk = store.chunk(root)?;
+        if chunk.size() < INDEX_MARKER.len() {
+            return Ok(None);
+        }
+        let mut buf = vec![0; chunk.size()];
+        chunk.read_at(&mut buf, 0)?;
+        if !buf.starts_with(INDEX_MARKER) {
+            return Ok(None);
+        }
+        Ok(Some(deserialize(&buf[INDEX_MARKER.len()..])?))
+    }
+
+    fn save(&self, store: &mut dyn Store) -> DenebResult<Digest> {
+        let mut buf = INDEX_MARKER.to_vec();
+        buf.extend(serialize(self)?);
+        Ok(store.put_chunk(&buf)?.digest)
+    }
+}
+
+/// The outcome of writing a catalog into the store
+#[derive(Debug)]
+pub(super) struct WrittenCatalog {
+    pub(super) root_hash: Digest,
+    /// Number of catalog chunks which were put into the store
+    pub(super) chunks_written: usize,
+    /// Number of catalog chunks taken over from the previous catalog
+    pub(super) chunks_reused: usize,
+    /// Size of the chunks taken over from the previous catalog
+    pub(super) bytes_reused: usize,
+}
+
+/// Write the catalog file at `catalog_path` into the store
+///
+/// The catalog is split in chunks, so that it isn't held in memory as a whole
+/// and can be copied chunk by chunk. The root chunk lists these chunks. The
+/// chunks whose contents are found in the catalog at `previous` are not
+/// written again, the new root lists the stored ones instead.
+pub(super) fn write_catalog(
+    store: &mut dyn Store,
+    catalog_path: &Path,
+    previous: Option<&Digest>,
+) -> DenebResult<WrittenCatalog> {
+    write_catalog_chunked(store, catalog_path, previous, CATALOG_CHUNK_SIZE)
+}
+
+fn write_catalog_chunked(
+    store: &mut dyn Store,
+    catalog_path: &Path,
+    previous: Option<&Digest>,
+    chunk_size: usize,
+) -> DenebResult<WrittenCatalog> {
+    // A previous catalog which can't be read only means that nothing is reused
+    let mut known = previous
+        .and_then(|root| CatalogIndex::load(&*store, root).ok())
+        .and_then(|index| index)
+        .map(|index| {
+            index
+                .chunks
+                .into_iter()
+                .map(|chunk| (chunk.contents, chunk.descriptor))
+                .collect::<HashMap<_, _>>()
+        })
+        .unwrap_or_default();
+
+    let mut chunks = vec![];
+    let mut chunks_written = 0;
+    let mut chunks_reused = 0;
+    let mut bytes_reused = 0;
+    let mut buffer = vec![0; chunk_size];
+    read_chunked(File::open(catalog_path)?, &mut buffer, |data| {
+        let contents = hash(data);
+        let descriptor = match known.get(&contents) {
+            Some(descriptor) => {
+                chunks_reused += 1;
+                bytes_reused += descriptor.size;
+                descriptor.clone()
+            }
+            None => {
+                chunks_written += 1;
+                let descriptor = store.put_chunk(data)?;
+                known.insert(contents, descriptor.clone());
+                descriptor
+            }
+        };
+        chunks.push(CatalogChunk {
+            descriptor,
+            contents,
+        });
+        Ok(())
+    })?;
+
+    let root_hash = CatalogIndex { chunks }.save(store)?;
+    Ok(WrittenCatalog {
+        root_hash,
+        chunks_written,
+        chunks_reused,
+        bytes_reused,
+    })
+}
+
+/// Reassemble the catalog found at `root` into the file at `catalog_path`
+pub(super) fn read_catalog(
+    store: &dyn Store,
+    root: &Digest,
+    catalog_path: &Path,
+) -> DenebResult<()> {
+    let index = match CatalogIndex::load(store, root)? {
+        Some(index) => index,
+        None => {
+            let chunk = store.chunk(root)?;
+            return atomic_write_from(catalog_path, &mut ChunkReader::new(&*chunk));
+        }
+    };
+    let chunks = index
+        .chunks
+        .iter()
+        .map(|chunk| store.chunk(&chunk.descriptor.digest))
+        .collect::<DenebResult<Vec<Arc<dyn Chunk>>>>()?;
+    let mut reader = chunks
+        .iter()
+        .fold(Box::new(empty()) as Box<dyn Read>, |reader, chunk| {
+            Box::new(reader.chain(ChunkReader::new(&**chunk)))
+        });
+    atomic_write_from(catalog_path, &mut reader)
+}
+
+/// Returns the digests of all the chunks holding the catalog found at `root`,
+/// starting with `root` itself
+pub(super) fn catalog_chunks(store: &dyn Store, root: &Digest) -> DenebResult<Vec<Digest>> {
+    let mut digests = vec![*root];
+    if let Some(index) = CatalogIndex::load(store, root)? {
+        digests.extend(index.chunks.iter().map(|chunk| chunk.descriptor.digest));
+    }
+    Ok(digests)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            crypt::EncryptionKey,
+            store::{open_store, StoreType},
+        },
+        std::fs::{read, write},
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn catalog_is_written_in_chunks() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_catalog_root")?;
+        let mut store = open_store(StoreType::OnDisk, tmp.path(), None, 8, 0, false)?;
+        let contents = b"alabalaportocala, portocala".to_vec();
+        let catalog_path = tmp.path().join("catalog");
+        write(&catalog_path, &contents)?;
+
+        let root = write_catalog_chunked(&mut *store, &catalog_path, None, 8)?.root_hash;
+        assert_eq!(catalog_chunks(&*store, &root)?.len(), 5);
+
+        let restored_path = tmp.path().join("restored");
+        read_catalog(&*store, &root, &restored_path)?;
+        assert_eq!(read(&restored_path)?, contents);
+
+        // A catalog written as a single chunk is still read
+        let legacy = store.put_chunk(&contents)?.digest;
+        assert_eq!(catalog_chunks(&*store, &legacy)?, vec![legacy]);
+        read_catalog(&*store, &legacy, &restored_path)?;
+        assert_eq!(read(&restored_path)?, contents);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unchanged_chunks_are_reused() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_catalog_reuse")?;
+        let key = Some(EncryptionKey::new());
+        let mut store = open_store(StoreType::OnDisk, tmp.path(), key, 8, 0, false)?;
+        let catalog_path = tmp.path().join("catalog");
+        write(&catalog_path, b"alabalaportocala, portocala")?;
+
+        let first = write_catalog_chunked(&mut *store, &catalog_path, None, 8)?;
+        assert_eq!((first.chunks_written, first.chunks_reused), (4, 0));
+
+        // Encrypted chunks get new digests when packed again, so they are
+        // only shared if they are reused
+        write(&catalog_path, b"alabalapORTOCALA, portocala")?;
+        let second = write_catalog_chunked(&mut *store, &catalog_path, Some(&first.root_hash), 8)?;
+        assert_eq!((second.chunks_written, second.chunks_reused), (1, 3));
+        assert_eq!(second.bytes_reused, 19);
+        let first_chunks = catalog_chunks(&*store, &first.root_hash)?;
+        let second_chunks = catalog_chunks(&*store, &second.root_hash)?;
+        assert_eq!(first_chunks[1], second_chunks[1]);
+        assert_ne!(first_chunks[2], second_chunks[2]);
+
+        let restored_path = tmp.path().join("restored");
+        read_catalog(&*store, &second.root_hash, &restored_path)?;
+        assert_eq!(
+            read(&restored_path)?,
+            b"alabalapORTOCALA, portocala".to_vec()
+        );
+
+        Ok(())
+    }
+}
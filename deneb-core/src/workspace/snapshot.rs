@@ -0,0 +1,318 @@
+use {
+    super::{root::read_catalog, MANIFEST_PATH},
+    crate::{
+        cas::Digest,
+        catalog::{open_catalog, Catalog, CatalogType},
+        crypt::EncryptionKey,
+        errors::{DenebResult, WorkspaceError},
+        inode::{FileAttributes, FileType, INode},
+        manifest::Manifest,
+        store::{open_read_only_store, ChunkReader, Store},
+    },
+    std::{
+        ffi::OsStr,
+        fs::{create_dir_all, remove_dir_all, remove_file},
+        io::{copy, Write},
+        path::{Component, Path, PathBuf},
+        process,
+    },
+};
+
+/// A read-only view of the committed state of a repository
+///
+/// The catalog and the chunks are read directly from the work dir, without
+/// starting an engine, so a snapshot can be opened while the repository is
+/// mounted by another process. Changes which were not yet committed are not
+/// visible.
+///
+/// Nothing of the repository is written: the store is opened read-only, and
+/// the catalog and the chunks are unpacked into copies private to the
+/// snapshot. Catalogs of older versions are migrated in the copy. The chunks
+/// of the commit may still be removed by a garbage collection of the engine
+/// once the commit is no longer retained.
+pub struct Snapshot {
+    catalog: Box<dyn Catalog>,
+    store: Box<dyn Store>,
+    manifest: Manifest,
+    catalog_path: PathBuf,
+    cache_dir: PathBuf,
+}
+
+impl Snapshot {
+    pub fn open(
+        work_dir: &Path,
+        encryption_key: Option<EncryptionKey>,
+        chunk_size: usize,
+    ) -> DenebResult<Snapshot> {
+        // The catalog and the chunks are unpacked next to the ones of a
+        // running engine
+        let scratch_dir = work_dir.join("scratch");
+        create_dir_all(&scratch_dir)?;
+        let cache_dir = scratch_dir.join(format!("snapshot_cache.{}", process::id()));
+        let store = open_read_only_store(work_dir, &cache_dir, encryption_key, chunk_size)?;
+        let buf = store.read_special_file(&work_dir.join(MANIFEST_PATH))?;
+        let manifest = Manifest::deserialize(&buf)?;
+
+        let catalog_path = scratch_dir.join(format!("snapshot_catalog.{}", process::id()));
+        read_catalog(&*store, &manifest.root_hash, &catalog_path)?;
+        // The unpacked catalog belongs to the snapshot alone, so it can be
+        // migrated if it was committed in an older format
+        let catalog = open_catalog(CatalogType::Lmdb, &catalog_path, false)?;
+
+        Ok(Snapshot {
+            catalog,
+            store,
+            manifest,
+            catalog_path,
+            cache_dir,
+        })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Index of the root directory
+    pub fn root_index(&self) -> DenebResult<u64> {
+        self.catalog.root_index()
+    }
+
+    /// Find the file at `path`, relative to the root of the repository
+    ///
+    /// Symbolic links are not followed.
+    pub fn resolve(&self, path: &Path) -> DenebResult<Option<FileAttributes>> {
+        let mut index = self.catalog.root_index()?;
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                Component::ParentDir => OsStr::new(".."),
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => continue,
+            };
+            if self.catalog.inode(index)?.attributes.kind != FileType::Directory {
+                return Ok(None);
+            }
+            match self.catalog.dir_entry_index(index, Path::new(name))? {
+                Some(entry_index) => index = entry_index,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(self.catalog.inode(index)?.attributes))
+    }
+
+    /// List the entries of a directory, without "." and ".."
+    pub fn read_dir(&self, index: u64) -> DenebResult<Vec<(PathBuf, FileAttributes)>> {
+        let mut entries = self
+            .catalog
+            .dir_entries(index)?
+            .into_iter()
+            .filter(|(name, _)| name != Path::new(".") && name != Path::new(".."))
+            .map(|(name, index)| Ok((name, self.catalog.inode(index)?.attributes)))
+            .collect::<DenebResult<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    /// Write the contents of a file (or the target of a symbolic link) to `out`
+    pub fn read_file(&self, index: u64, out: &mut dyn Write) -> DenebResult<()> {
+        let inode = self.catalog.inode(index)?;
+        if inode.attributes.kind == FileType::Directory {
+            return Err(WorkspaceError::FileLookup(index).into());
+        }
+        for descriptor in &inode.chunks {
+            let chunk = self.store.chunk(&descriptor.digest)?;
+            copy(&mut ChunkReader::new(&*chunk), out)?;
+        }
+        Ok(())
+    }
+
+    /// Digest of the whole contents of a regular file, if it was recorded
+    pub fn file_digest(&self, index: u64) -> DenebResult<Option<Digest>> {
+        let inode = self.catalog.inode(index)?;
+        if inode.attributes.kind != FileType::RegularFile {
+            return Ok(None);
+        }
+        Ok(inode.digest)
+    }
+
+    pub(super) fn catalog(&self) -> &dyn Catalog {
+        &*self.catalog
+    }
+
+    pub(super) fn store(&self) -> &dyn Store {
+        &*self.store
+    }
+
+    pub(super) fn inode(&self, index: u64) -> DenebResult<INode> {
+        self.catalog.inode(index)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.catalog_path);
+        let mut lock_path = self.catalog_path.clone().into_os_string();
+        lock_path.push("-lock");
+        let _ = remove_file(lock_path);
+        let _ = remove_dir_all(&self.cache_dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            catalog::write_decimal_keys,
+            store::StoreType,
+            workspace::{root::write_catalog, Workspace, WorkspaceOptions},
+        },
+        std::fs::{read_dir, write},
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn committed_files_are_browsed() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_snapshot")?;
+        let sync_dir = tmp.path().join("sync");
+        let work_dir = tmp.path().join("work");
+        create_dir_all(sync_dir.join("dir"))?;
+        write(sync_dir.join("dir/file"), "alabalaportocala")?;
+        write(sync_dir.join("dir/empty"), "")?;
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            work_dir.clone(),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir),
+                chunk_size: 8,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+        // Uncommitted files are not visible
+        ws.create_file(1, OsStr::new("new"), 0o644, 0)?;
+
+        let snapshot = Snapshot::open(&work_dir, None, 8)?;
+        assert!(snapshot.resolve(Path::new("new"))?.is_none());
+        assert!(snapshot.resolve(Path::new("dir/missing"))?.is_none());
+        assert!(snapshot.resolve(Path::new("dir/file/file"))?.is_none());
+        assert_eq!(snapshot.resolve(Path::new("/"))?.map(|a| a.index), Some(1));
+
+        let dir = snapshot
+            .resolve(Path::new("/dir/"))?
+            .ok_or_else(|| WorkspaceError::DirLookup(0))?;
+        let names = snapshot
+            .read_dir(dir.index)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![PathBuf::from("empty"), PathBuf::from("file")]);
+
+        let file = snapshot
+            .resolve(Path::new("dir/../dir/file"))?
+            .ok_or_else(|| WorkspaceError::FileLookup(0))?;
+        assert_eq!(file.size, 16);
+        let mut contents = Vec::new();
+        snapshot.read_file(file.index, &mut contents)?;
+        assert_eq!(contents, b"alabalaportocala".to_vec());
+        assert!(snapshot.read_file(dir.index, &mut contents).is_err());
+
+        let catalog_path = snapshot.catalog_path.clone();
+        drop(snapshot);
+        assert!(!catalog_path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_leave_a_live_repository_alone() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_snapshot_live")?;
+        let sync_dir = tmp.path().join("sync");
+        let work_dir = tmp.path().join("work");
+        create_dir_all(&sync_dir)?;
+        write(sync_dir.join("file"), "alabalaportocala")?;
+
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            work_dir.clone(),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir),
+                chunk_size: 8,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+        let file = ws
+            .lookup(1, OsStr::new("file"))?
+            .ok_or_else(|| WorkspaceError::FileLookup(0))?;
+        ws.open_file(file.index, 0)?;
+        ws.read_data(file.index, 0, 64)?;
+        let cache_entries =
+            || -> DenebResult<usize> { Ok(read_dir(work_dir.join("cache"))?.count()) };
+        let cached = cache_entries()?;
+        assert!(cached > 0);
+
+        // The snapshot neither touches the cache of the engine nor writes
+        let mut snapshot = Snapshot::open(&work_dir, None, 8)?;
+        let mut contents = Vec::new();
+        snapshot.read_file(file.index, &mut contents)?;
+        assert_eq!(contents, b"alabalaportocala".to_vec());
+        assert_eq!(cache_entries()?, cached);
+        assert!(snapshot.store.put_chunk(b"40 de turcaleti").is_err());
+
+        let cache_dir = snapshot.cache_dir.clone();
+        drop(snapshot);
+        assert!(!cache_dir.exists());
+        assert_eq!(
+            ws.read_data(file.index, 0, 64)?,
+            b"alabalaportocala".to_vec()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn older_catalogs_are_migrated() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_snapshot_migrate")?;
+        let sync_dir = tmp.path().join("sync");
+        let work_dir = tmp.path().join("work");
+        create_dir_all(&sync_dir)?;
+        write(sync_dir.join("file"), "alabalaportocala")?;
+
+        let ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            work_dir.clone(),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir),
+                chunk_size: 8,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+
+        // Commit the catalog again, in the version 2 format
+        let old_catalog = tmp.path().join("old_catalog");
+        let mut store = ws.store.borrow_mut();
+        read_catalog(&**store, &ws.manifest.root_hash, &old_catalog)?;
+        write_decimal_keys(&old_catalog)?;
+        let mut manifest = ws.manifest.clone();
+        manifest.root_hash = write_catalog(&mut **store, &old_catalog, None)?.root_hash;
+        store.write_special_file(
+            &work_dir.join(MANIFEST_PATH),
+            &mut &manifest.serialize()?[..],
+            false,
+        )?;
+        drop(store);
+
+        let snapshot = Snapshot::open(&work_dir, None, 8)?;
+        let file = snapshot
+            .resolve(Path::new("file"))?
+            .ok_or_else(|| WorkspaceError::FileLookup(0))?;
+        let mut contents = Vec::new();
+        snapshot.read_file(file.index, &mut contents)?;
+        assert_eq!(contents, b"alabalaportocala".to_vec());
+        Ok(())
+    }
+}
@@ -0,0 +1,252 @@
+use {
+    super::Workspace,
+    crate::{
+        errors::{DenebResult, DirWorkspaceEntryLookupError, WorkspaceError},
+        inode::FileType,
+    },
+    std::{
+        collections::HashSet,
+        ffi::OsStr,
+        path::{Path, PathBuf},
+    },
+    time::now_utc,
+};
+
+/// Directory at the root of the repository which holds the removed entries
+pub(super) const TRASH_DIR: &str = ".deneb-trash";
+/// Permissions of the trash directory and of its buckets
+const TRASH_MODE: u32 = 0o700;
+
+/// Move the entry `name` of `parent` into the trash instead of deleting it
+///
+/// The entries removed during the same second are kept together, in a bucket
+/// of the trash named after that second. An entry is renamed to `<name>.<n>`
+/// when the bucket already holds one with its name. The data of the entry is
+/// left as it is, only the entry is moved.
+pub(super) fn move_to_trash(ws: &mut Workspace, parent: u64, name: &OsStr) -> DenebResult<()> {
+    ws.negative_lookups.invalidate(parent);
+    ws.open_dir(parent)?;
+    let pname = ws.entry_name(parent, name)?;
+    let entry = ws
+        .dirs
+        .get(&parent)
+        .and_then(|dws| dws.entry(&pname))
+        .cloned()
+        .ok_or_else(|| DirWorkspaceEntryLookupError {
+            parent,
+            name: name.to_owned(),
+        })?;
+
+    let root = ws.root;
+    let trash = trash_subdir(ws, root, OsStr::new(TRASH_DIR))?;
+    let now = now_utc().to_timespec().sec.to_string();
+    let bucket = trash_subdir(ws, trash, OsStr::new(&now))?;
+    let trash_name = free_name(ws, bucket, &pname)?;
+
+    if let Some(dws) = ws.dirs.get_mut(&parent) {
+        dws.remove_entry(&pname);
+    }
+    ws.negative_lookups.invalidate(bucket);
+    ws.dirs
+        .get_mut(&bucket)
+        .ok_or_else(|| WorkspaceError::DirLookup(bucket))?
+        .add_entry(entry.index, trash_name, entry.entry_type);
+    if entry.entry_type == FileType::Directory {
+        ws.open_dir(entry.index)?;
+        if let Some(dws) = ws.dirs.get_mut(&entry.index) {
+            dws.remove_entry(Path::new(".."));
+            dws.add_entry(bucket, PathBuf::from(".."), FileType::Directory);
+        }
+    }
+
+    ws.dirty = true;
+
+    Ok(())
+}
+
+/// Whether the entry `name` of `parent` is the trash or is found under it
+pub(super) fn in_trash(ws: &mut Workspace, parent: u64, name: &OsStr) -> DenebResult<bool> {
+    let trash = match trash_dir(ws)? {
+        Some(trash) => trash,
+        None => return Ok(false),
+    };
+    if parent == ws.root && ws.entry_name(parent, name)? == Path::new(TRASH_DIR) {
+        return Ok(true);
+    }
+    // The ".." entries lead to the root, whose ".." entry points to itself
+    let mut index = parent;
+    let mut seen = HashSet::new();
+    while seen.insert(index) {
+        if index == trash {
+            return Ok(true);
+        }
+        match ws.lookup(index, OsStr::new(".."))? {
+            Some(attrs) => index = attrs.index,
+            None => break,
+        }
+    }
+    Ok(false)
+}
+
+/// Delete the buckets of the trash which are older than the retention period,
+/// or all of them when `all` is set
+///
+/// Returns the number of entries deleted, not counting the buckets.
+pub(super) fn purge_trash(ws: &mut Workspace, all: bool) -> DenebResult<usize> {
+    let trash = match trash_dir(ws)? {
+        Some(trash) => trash,
+        None => return Ok(0),
+    };
+    let now = now_utc().to_timespec().sec;
+    let retention = ws.trash_retention;
+    let expired = |name: &Path| {
+        all || match (retention, name.to_str().and_then(|n| n.parse::<i64>().ok())) {
+            (Some(retention), Some(removed)) => retention > 0 && now - removed >= retention as i64,
+            _ => false,
+        }
+    };
+
+    let mut deleted = 0;
+    for (name, kind, index) in children(ws, trash)? {
+        if expired(&name) {
+            if kind == FileType::Directory {
+                deleted += delete_children(ws, index)?;
+            } else {
+                deleted += 1;
+            }
+            ws.delete(trash, name.as_os_str())?;
+        }
+    }
+    Ok(deleted)
+}
+
+// The trash directory, if any entry was moved to it
+fn trash_dir(ws: &mut Workspace) -> DenebResult<Option<u64>> {
+    let root = ws.root;
+    Ok(ws
+        .lookup(root, OsStr::new(TRASH_DIR))?
+        .filter(|attrs| attrs.kind == FileType::Directory)
+        .map(|attrs| attrs.index))
+}
+
+// Find or create the directory `name` of `parent`, leaving it open
+fn trash_subdir(ws: &mut Workspace, parent: u64, name: &OsStr) -> DenebResult<u64> {
+    let index = match ws.lookup(parent, name)? {
+        Some(ref attrs) if attrs.kind == FileType::Directory => attrs.index,
+        Some(_) => return Err(WorkspaceError::TrashConflict(name.to_owned()).into()),
+        None => ws.create_dir(parent, name, TRASH_MODE)?.index,
+    };
+    ws.open_dir(index)?;
+    Ok(index)
+}
+
+// `name`, or `name` followed by the first number which makes it unique in `dir`
+fn free_name(ws: &mut Workspace, dir: u64, name: &Path) -> DenebResult<PathBuf> {
+    let mut candidate = name.to_owned();
+    let mut n = 1;
+    while ws.lookup(dir, candidate.as_os_str())?.is_some() {
+        let mut numbered = name.as_os_str().to_owned();
+        numbered.push(format!(".{}", n));
+        candidate = PathBuf::from(numbered);
+        n += 1;
+    }
+    Ok(candidate)
+}
+
+// The entries of the directory `index`, without "." and ".."
+fn children(ws: &mut Workspace, index: u64) -> DenebResult<Vec<(PathBuf, FileType, u64)>> {
+    ws.open_dir(index)?;
+    Ok(ws
+        .dirs
+        .get(&index)
+        .ok_or_else(|| WorkspaceError::DirLookup(index))?
+        .entries()
+        .iter()
+        .filter(|e| e.name != Path::new(".") && e.name != Path::new(".."))
+        .map(|e| (e.name.clone(), e.entry_type, e.index))
+        .collect())
+}
+
+// Delete everything found under the directory `index`, returning the number
+// of entries deleted
+fn delete_children(ws: &mut Workspace, index: u64) -> DenebResult<usize> {
+    let mut deleted = 0;
+    for (name, kind, child) in children(ws, index)? {
+        if kind == FileType::Directory {
+            deleted += delete_children(ws, child)?;
+        }
+        ws.delete(index, name.as_os_str())?;
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{catalog::CatalogType, store::StoreType, workspace::WorkspaceOptions},
+        std::fs::create_dir_all,
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn removed_entries_are_kept_in_the_trash() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_trash")?;
+        let sync_dir = tmp.path().join("sync");
+        create_dir_all(&sync_dir)?;
+        let mut ws = Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            tmp.path().join("work"),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir),
+                chunk_size: 16,
+                chunk_cache_size: 1024 * 1024,
+                trash_retention: Some(0),
+                ..WorkspaceOptions::default()
+            },
+        )?;
+        let root = ws.root;
+        let (index, _) = ws.create_file(root, OsStr::new("file"), 0o644, 0)?;
+        ws.write_data(index, 0, b"alabalaportocala".to_vec())?;
+        let dir = ws.create_dir(root, OsStr::new("dir"), 0o755)?.index;
+        ws.commit()?;
+
+        ws.remove(root, OsStr::new("file"))?;
+        ws.remove(root, OsStr::new("dir"))?;
+        assert!(ws.deleted_inodes.is_empty());
+        ws.commit()?;
+        assert!(ws.lookup(root, OsStr::new("file"))?.is_none());
+        assert!(!ws
+            .catalog
+            .dir_entries(root)?
+            .iter()
+            .any(|(name, _)| name == Path::new("file")));
+        assert!(!in_trash(&mut ws, root, OsStr::new("other"))?);
+        assert!(in_trash(&mut ws, root, OsStr::new(TRASH_DIR))?);
+
+        // The entries are kept until the trash is emptied
+        let trash = trash_dir(&mut ws)?.unwrap();
+        let buckets = children(&mut ws, trash)?;
+        assert_eq!(buckets.len(), 1);
+        let bucket = buckets[0].2;
+        let attrs = ws.lookup(bucket, OsStr::new("file"))?.unwrap();
+        assert_eq!(attrs.index, index);
+        ws.open_file(index, 0)?;
+        assert_eq!(ws.read_data(index, 0, 16)?, b"alabalaportocala".to_vec());
+        assert_eq!(ws.lookup(dir, OsStr::new(".."))?.unwrap().index, bucket);
+        assert!(in_trash(&mut ws, bucket, OsStr::new("dir"))?);
+        assert_eq!(purge_trash(&mut ws, false)?, 0);
+
+        // Entries removed from the trash are deleted
+        ws.remove(bucket, OsStr::new("dir"))?;
+        assert!(ws.deleted_inodes.contains(&dir));
+        assert_eq!(purge_trash(&mut ws, true)?, 1);
+        assert!(ws.deleted_inodes.contains(&index));
+        assert!(children(&mut ws, trash)?.is_empty());
+        ws.commit()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use {
+    super::snapshot::Snapshot,
+    crate::{
+        cas::{read_chunked, Digest, Hasher},
+        crypt::EncryptionKey,
+        errors::{DenebError, DenebResult},
+        ignore::IgnorePatterns,
+        inode::{mode_to_file_type, INode},
+        store::ChunkReader,
+        ImportOptions,
+    },
+    nix::sys::stat::{lstat, SFlag},
+    serde::Serialize,
+    std::{
+        collections::HashMap,
+        fmt::{Display, Formatter, Result as FmtResult},
+        fs::{read_dir, read_link, File},
+        os::unix::ffi::OsStrExt,
+        path::{Path, PathBuf},
+    },
+};
+
+/// A difference between a directory and the contents of a repository
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Difference {
+    /// The file isn't found in the repository
+    Missing(PathBuf),
+    /// The file is only found in the repository
+    Extra(PathBuf),
+    /// The file has another type in the repository
+    Kind(PathBuf),
+    /// The file has another size in the repository
+    Size {
+        path: PathBuf,
+        local: u64,
+        repository: u64,
+    },
+    /// The file has the same size, but other contents, in the repository
+    Contents(PathBuf),
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Difference::Missing(path) => write!(f, "missing: {}", path.display()),
+            Difference::Extra(path) => write!(f, "extra: {}", path.display()),
+            Difference::Kind(path) => write!(f, "type differs: {}", path.display()),
+            Difference::Size {
+                path,
+                local,
+                repository,
+            } => write!(
+                f,
+                "size differs: {} ({} bytes, {} in the repository)",
+                path.display(),
+                local,
+                repository
+            ),
+            Difference::Contents(path) => write!(f, "contents differ: {}", path.display()),
+        }
+    }
+}
+
+/// Compare the contents of `dir` with the current state of the repository in `work_dir`
+///
+/// Only the committed state of the repository is compared, as seen by a
+/// `Snapshot`. The files left out by the
+/// import options are not expected in the repository. Returns the differences
+/// found, in the order in which the directories are visited.
+pub fn verify_repository(
+    work_dir: &Path,
+    encryption_key: Option<EncryptionKey>,
+    chunk_size: usize,
+    dir: &Path,
+    options: &ImportOptions,
+) -> DenebResult<Vec<Difference>> {
+    let snapshot = Snapshot::open(work_dir, encryption_key, chunk_size)?;
+    let mut verify = Verify {
+        snapshot: &snapshot,
+        root: dir,
+        ignore: IgnorePatterns::load(dir, &options.ignore_patterns)?,
+        buffer: vec![0; chunk_size],
+        differences: Vec::new(),
+    };
+    verify.visit_dir(dir, snapshot.root_index()?)?;
+    Ok(verify.differences)
+}
+
+struct Verify<'a> {
+    snapshot: &'a Snapshot,
+    root: &'a Path,
+    ignore: IgnorePatterns,
+    buffer: Vec<u8>,
+    differences: Vec<Difference>,
+}
+
+impl<'a> Verify<'a> {
+    fn visit_dir(&mut self, dir: &Path, dir_index: u64) -> DenebResult<()> {
+        let mut entries = self
+            .snapshot
+            .catalog()
+            .dir_entries(dir_index)?
+            .into_iter()
+            .filter(|(name, _)| name != Path::new(".") && name != Path::new(".."))
+            .collect::<HashMap<_, _>>();
+
+        let mut paths = read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+
+        for path in paths {
+            let fname = PathBuf::from(
+                path.file_name()
+                    .ok_or_else(|| DenebError::InvalidPath(path.clone()))?,
+            );
+            let relative_path = path.strip_prefix(self.root).unwrap_or(&fname).to_owned();
+
+            let stats = lstat(&path)?;
+            let file_type = SFlag::from_bits_truncate(stats.st_mode & SFlag::S_IFMT.bits());
+            let is_dir = file_type == SFlag::S_IFDIR;
+            if file_type == SFlag::S_IFSOCK || self.ignore.is_ignored(&relative_path, is_dir) {
+                continue;
+            }
+
+            let index = match entries.remove(&fname) {
+                Some(index) => index,
+                None => {
+                    self.differences.push(Difference::Missing(relative_path));
+                    continue;
+                }
+            };
+            let inode = self.snapshot.inode(index)?;
+            if inode.attributes.kind != mode_to_file_type(stats.st_mode) {
+                self.differences.push(Difference::Kind(relative_path));
+                continue;
+            }
+
+            if is_dir {
+                self.visit_dir(&path, index)?;
+                continue;
+            }
+            if file_type != SFlag::S_IFREG && file_type != SFlag::S_IFLNK {
+                continue;
+            }
+
+            let local = stats.st_size as u64;
+            if local != inode.attributes.size {
+                self.differences.push(Difference::Size {
+                    path: relative_path,
+                    local,
+                    repository: inode.attributes.size,
+                });
+            } else if self.local_digest(&path, file_type)? != self.repository_digest(&inode)? {
+                self.differences.push(Difference::Contents(relative_path));
+            }
+        }
+
+        // Whatever wasn't matched by a local file
+        let mut extra = entries
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        extra.sort();
+        let relative_dir = dir.strip_prefix(self.root).unwrap_or(dir);
+        self.differences.extend(
+            extra
+                .into_iter()
+                .map(|name| Difference::Extra(relative_dir.join(name))),
+        );
+
+        Ok(())
+    }
+
+    // The target of a symbolic link is its contents
+    fn local_digest(&mut self, path: &Path, file_type: SFlag) -> DenebResult<Digest> {
+        let mut hasher = Hasher::new();
+        if file_type == SFlag::S_IFLNK {
+            hasher.update(read_link(path)?.as_os_str().as_bytes());
+        } else {
+            read_chunked(File::open(path)?, &mut self.buffer, |data| {
+                hasher.update(data);
+                Ok(())
+            })?;
+        }
+        Ok(hasher.finish())
+    }
+
+    fn repository_digest(&mut self, inode: &INode) -> DenebResult<Digest> {
+        let mut hasher = Hasher::new();
+        for descriptor in &inode.chunks {
+            let chunk = self.snapshot.store().chunk(&descriptor.digest)?;
+            read_chunked(ChunkReader::new(&*chunk), &mut self.buffer, |data| {
+                hasher.update(data);
+                Ok(())
+            })?;
+        }
+        Ok(hasher.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            catalog::CatalogType,
+            store::StoreType,
+            workspace::{Workspace, WorkspaceOptions},
+        },
+        std::fs::{create_dir_all, remove_file, write},
+        tempdir::TempDir,
+    };
+
+    #[test]
+    fn directory_is_compared_with_repository() -> DenebResult<()> {
+        let tmp = TempDir::new("deneb_verify")?;
+        let sync_dir = tmp.path().join("sync");
+        let work_dir = tmp.path().join("work");
+        create_dir_all(sync_dir.join("dir"))?;
+        write(sync_dir.join("same"), "alabalaportocala")?;
+        write(sync_dir.join("changed"), "alabalaportocala")?;
+        write(sync_dir.join("grown"), "alabala")?;
+        write(sync_dir.join("dir/removed"), "portocala")?;
+
+        Workspace::new(
+            CatalogType::Lmdb,
+            StoreType::OnDisk,
+            work_dir.clone(),
+            WorkspaceOptions {
+                sync_dir: Some(sync_dir.clone()),
+                chunk_size: 8,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+        )?;
+
+        let options = ImportOptions::default();
+        assert!(verify_repository(&work_dir, None, 8, &sync_dir, &options)?.is_empty());
+
+        write(sync_dir.join("changed"), "alabalaPORTOCALA")?;
+        write(sync_dir.join("grown"), "alabalaportocala")?;
+        remove_file(sync_dir.join("dir/removed"))?;
+        write(sync_dir.join("dir/added"), "portocala")?;
+        write(sync_dir.join("ignored.tmp"), "portocala")?;
+
+        let options = ImportOptions {
+            ignore_patterns: vec!["*.tmp".to_owned()],
+            strict: false,
+        };
+        assert_eq!(
+            verify_repository(&work_dir, None, 8, &sync_dir, &options)?,
+            vec![
+                Difference::Contents(PathBuf::from("changed")),
+                Difference::Missing(PathBuf::from("dir/added")),
+                Difference::Extra(PathBuf::from("dir/removed")),
+                Difference::Size {
+                    path: PathBuf::from("grown"),
+                    local: 16,
+                    repository: 7,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use {
+    deneb_core::{
+        catalog::CatalogType,
+        engine::{
+            start_engine, CommitHooks, EngineOptions, Handle, RequestId, Snapshot, WorkspaceOptions,
+        },
+        errors::DenebResult,
+        inode::FileAttributeChanges,
+        store::StoreType,
+    },
+    std::{
+        ffi::OsStr,
+        fs::{create_dir_all, read_to_string, set_permissions, write, Permissions},
+        os::unix::fs::PermissionsExt,
+        path::Path,
+    },
+    tempdir::TempDir,
+};
+
+const CHUNK_SIZE: usize = 16;
+const ROOT_INDEX: u64 = 1;
+
+// The committed state of the repository is what is found after a crash
+fn contents_after_crash(work_dir: &Path, path: &str) -> DenebResult<Option<Vec<u8>>> {
+    let snapshot = Snapshot::open(work_dir, None, CHUNK_SIZE)?;
+    match snapshot.resolve(Path::new(path))? {
+        Some(attrs) => {
+            let mut contents = Vec::new();
+            snapshot.read_file(attrs.index, &mut contents)?;
+            Ok(Some(contents))
+        }
+        None => Ok(None),
+    }
+}
+
+fn start(work_dir: &Path, sync_dir: &Path, hooks: CommitHooks) -> DenebResult<Handle> {
+    start_engine(
+        CatalogType::Lmdb,
+        StoreType::OnDisk,
+        work_dir.to_owned(),
+        EngineOptions {
+            workspace: WorkspaceOptions {
+                sync_dir: Some(sync_dir.to_owned()),
+                chunk_size: CHUNK_SIZE,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+            hooks,
+            ..EngineOptions::default()
+        },
+    )
+}
+
+// An editor saves a file by writing the new contents to a temporary file,
+// syncing it and renaming it over the original file
+#[test]
+fn saved_files_survive_a_crash() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_atomic_save")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(sync_dir.join("notes.txt"), "alabalaportocala")?;
+
+    let handle = start(&work_dir, &sync_dir, CommitHooks::default())?;
+    let id = RequestId::default();
+
+    let (entry, _) = handle.create_file(&id, ROOT_INDEX, OsStr::new(".notes.txt.swp"), 0o644, 0)?;
+    let temp = entry.attributes.index;
+    handle.write_data(&id, temp, 0, b"40 de turcaleti, ".to_vec())?;
+    handle.write_data(&id, temp, 17, b"40 de turcaleti".to_vec())?;
+    handle.release_file(&id, temp, 0, 0, true)?;
+
+    // A crash before the rename keeps the original file
+    assert_eq!(
+        contents_after_crash(&work_dir, "notes.txt")?,
+        Some(b"alabalaportocala".to_vec())
+    );
+    assert_eq!(contents_after_crash(&work_dir, ".notes.txt.swp")?, None);
+
+    // A crash after the rename keeps the saved file
+    handle.rename(
+        &id,
+        ROOT_INDEX,
+        OsStr::new(".notes.txt.swp"),
+        ROOT_INDEX,
+        OsStr::new("notes.txt"),
+    )?;
+    assert_eq!(
+        contents_after_crash(&work_dir, "notes.txt")?,
+        Some(b"40 de turcaleti, 40 de turcaleti".to_vec())
+    );
+    assert_eq!(contents_after_crash(&work_dir, ".notes.txt.swp")?, None);
+
+    handle.stop_engine();
+    Ok(())
+}
+
+// Shells truncate a file by path, e.g. with `> notes.txt`, without opening it
+#[test]
+fn files_are_truncated_without_being_open() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_truncate")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(sync_dir.join("notes.txt"), "alabalaportocala")?;
+
+    let handle = start(&work_dir, &sync_dir, CommitHooks::default())?;
+    let id = RequestId::default();
+
+    let index = handle
+        .lookup(&id, ROOT_INDEX, OsStr::new("notes.txt"))?
+        .unwrap()
+        .attributes
+        .index;
+    let truncate = |size| {
+        FileAttributeChanges::new(None, None, None, Some(size), None, None, None, None, None)
+    };
+    let attrs = handle.set_attr(&id, index, truncate(5))?;
+    assert_eq!(attrs.size, 5);
+    handle.commit(None)?;
+    assert_eq!(
+        contents_after_crash(&work_dir, "notes.txt")?,
+        Some(b"alaba".to_vec())
+    );
+
+    handle.set_attr(&id, index, truncate(0))?;
+    handle.commit(None)?;
+    assert_eq!(contents_after_crash(&work_dir, "notes.txt")?, Some(vec![]));
+
+    handle.stop_engine();
+    Ok(())
+}
+
+// The rename has been made when its commit is rejected, so it still succeeds
+#[test]
+fn saves_are_acknowledged_when_their_commit_fails() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_atomic_save_rejected")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(sync_dir.join("notes.txt"), "alabalaportocala")?;
+    let script = tmp.path().join("pre-commit");
+    write(&script, "#!/bin/sh\nexit 1\n")?;
+    set_permissions(&script, Permissions::from_mode(0o755))?;
+
+    let hooks = CommitHooks {
+        pre_commit: Some(script),
+        post_commit: None,
+    };
+    let handle = start(&work_dir, &sync_dir, hooks)?;
+    let id = RequestId::default();
+
+    let (entry, _) = handle.create_file(&id, ROOT_INDEX, OsStr::new(".notes.txt.swp"), 0o644, 0)?;
+    let temp = entry.attributes.index;
+    handle.write_data(&id, temp, 0, b"40 de turcaleti".to_vec())?;
+    handle.release_file(&id, temp, 0, 0, true)?;
+    handle.rename(
+        &id,
+        ROOT_INDEX,
+        OsStr::new(".notes.txt.swp"),
+        ROOT_INDEX,
+        OsStr::new("notes.txt"),
+    )?;
+    let saved = handle.lookup(&id, ROOT_INDEX, OsStr::new("notes.txt"))?;
+    assert_eq!(saved.map(|e| e.attributes.index), Some(temp));
+    assert_eq!(
+        contents_after_crash(&work_dir, "notes.txt")?,
+        Some(b"alabalaportocala".to_vec())
+    );
+
+    handle.stop_engine();
+    Ok(())
+}
+
+// A rejected commit isn't retried by every save, each waiting for the hook
+#[test]
+fn rejected_saves_are_not_committed_again_right_away() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_atomic_save_backoff")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(sync_dir.join("notes.txt"), "alabalaportocala")?;
+    let runs = tmp.path().join("runs");
+    let script = tmp.path().join("pre-commit");
+    write(
+        &script,
+        format!("#!/bin/sh\necho run >> {:?}\nexit 1\n", runs),
+    )?;
+    set_permissions(&script, Permissions::from_mode(0o755))?;
+
+    let hooks = CommitHooks {
+        pre_commit: Some(script),
+        post_commit: None,
+    };
+    let handle = start(&work_dir, &sync_dir, hooks)?;
+    let id = RequestId::default();
+
+    for _ in 0..3 {
+        let (entry, _) =
+            handle.create_file(&id, ROOT_INDEX, OsStr::new(".notes.txt.swp"), 0o644, 0)?;
+        handle.write_data(&id, entry.attributes.index, 0, b"40 de turcaleti".to_vec())?;
+        handle.release_file(&id, entry.attributes.index, 0, 0, true)?;
+        handle.rename(
+            &id,
+            ROOT_INDEX,
+            OsStr::new(".notes.txt.swp"),
+            ROOT_INDEX,
+            OsStr::new("notes.txt"),
+        )?;
+    }
+    assert_eq!(read_to_string(&runs)?, "run\n");
+
+    handle.stop_engine();
+    Ok(())
+}
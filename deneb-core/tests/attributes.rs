@@ -0,0 +1,221 @@
+use {
+    deneb_core::{
+        catalog::CatalogType,
+        engine::{
+            start_engine, EngineOptions, Handle, OwnershipPolicy, RequestId, WorkspaceOptions,
+        },
+        errors::{DenebResult, EngineError},
+        inode::FileAttributeChanges,
+        store::StoreType,
+    },
+    nix::libc::{O_RDONLY, O_WRONLY, XATTR_CREATE},
+    std::{
+        ffi::{OsStr, OsString},
+        fs::{create_dir_all, write},
+        path::Path,
+    },
+    tempdir::TempDir,
+};
+
+const CHUNK_SIZE: usize = 16;
+const ROOT_INDEX: u64 = 1;
+
+fn start(work_dir: &Path, sync_dir: Option<&Path>) -> DenebResult<Handle> {
+    start_engine(
+        CatalogType::Lmdb,
+        StoreType::OnDisk,
+        work_dir.to_owned(),
+        EngineOptions {
+            workspace: WorkspaceOptions {
+                sync_dir: sync_dir.map(Path::to_owned),
+                chunk_size: CHUNK_SIZE,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+            ownership: OwnershipPolicy {
+                restrict_chown: true,
+                enforce_acls: true,
+                ..OwnershipPolicy::default()
+            },
+            ..EngineOptions::default()
+        },
+    )
+}
+
+fn user(uid: u32) -> RequestId {
+    RequestId {
+        uid,
+        gid: uid,
+        ..RequestId::default()
+    }
+}
+
+fn chown(uid: Option<u32>, gid: Option<u32>) -> FileAttributeChanges {
+    FileAttributeChanges::new(None, uid, gid, None, None, None, None, None, None)
+}
+
+fn chmod(mode: u32) -> FileAttributeChanges {
+    FileAttributeChanges::new(Some(mode), None, None, None, None, None, None, None, None)
+}
+
+// Changes to the owner and the mode of a file are kept after a remount
+#[test]
+fn ownership_changes_are_committed() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_attributes")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(sync_dir.join("notes.txt"), "alabalaportocala")?;
+
+    let handle = start(&work_dir, Some(&sync_dir))?;
+    let root = user(0);
+    let index = handle
+        .lookup(&root, ROOT_INDEX, OsStr::new("notes.txt"))?
+        .unwrap()
+        .attributes
+        .index;
+    let before = handle.get_attr(&root, index)?;
+
+    let attrs = handle.set_attr(&root, index, chown(Some(1000), Some(1000)))?;
+    assert_eq!((attrs.uid, attrs.gid), (1000, 1000));
+    assert!(attrs.ctime >= before.ctime);
+    let attrs = handle.set_attr(&user(1000), index, chmod(0o100_600))?;
+    assert_eq!(attrs.perm, 0o600);
+
+    // Only root gives files away, and only owners change their mode
+    let denied = handle
+        .set_attr(&user(1000), index, chown(Some(1001), None))
+        .unwrap_err();
+    assert!(denied.iter_chain().any(|cause| matches!(
+        cause.downcast_ref::<EngineError>(),
+        Some(EngineError::PermissionDenied(..))
+    )));
+    assert!(handle
+        .set_attr(&user(1001), index, chmod(0o100_666))
+        .is_err());
+
+    let expected = handle.get_attr(&root, index)?;
+    assert!(!handle.commit(None)?.noop);
+    handle.stop_engine();
+
+    let handle = start(&work_dir, None)?;
+    let attrs = handle.get_attr(&root, index)?;
+    assert_eq!(
+        (attrs.uid, attrs.gid, attrs.perm, attrs.ctime),
+        (expected.uid, expected.gid, expected.perm, expected.ctime)
+    );
+    handle.stop_engine();
+    Ok(())
+}
+
+// The value of an ACL with an entry for the user 1001, as written by `setfacl`
+fn acl(owner: u16, user: u16, group: u16, mask: u16, other: u16) -> Vec<u8> {
+    let undefined = u32::max_value();
+    let entries: [(u16, u16, u32); 5] = [
+        (0x01, owner, undefined),
+        (0x02, user, 1001),
+        (0x04, group, undefined),
+        (0x10, mask, undefined),
+        (0x20, other, undefined),
+    ];
+    let mut value = 2u32.to_le_bytes().to_vec();
+    for &(tag, perm, id) in &entries {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&perm.to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    value
+}
+
+// The ACLs set with `setfacl` are kept, and grant access to the files
+#[test]
+fn acls_are_kept_and_enforced() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_acls")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(sync_dir.join("notes.txt"), "alabalaportocala")?;
+
+    let handle = start(&work_dir, Some(&sync_dir))?;
+    let root = user(0);
+    let index = handle
+        .lookup(&root, ROOT_INDEX, OsStr::new("notes.txt"))?
+        .unwrap()
+        .attributes
+        .index;
+    handle.set_attr(&root, index, chown(Some(1000), Some(1000)))?;
+    let access = OsStr::new("system.posix_acl_access");
+    let value = acl(0o6, 0o6, 0o4, 0o6, 0o4);
+
+    assert!(handle
+        .set_xattr(&user(1001), index, access, &value, 0)
+        .is_err());
+    handle.set_xattr(&user(1000), index, access, &value, 0)?;
+    assert!(handle
+        .set_xattr(&user(1000), index, access, &value, XATTR_CREATE as u32)
+        .is_err());
+    assert!(handle
+        .set_xattr(&root, index, OsStr::new("user.comment"), b"", 0)
+        .is_err());
+    // The permission bits follow the ACL
+    assert_eq!(handle.get_attr(&root, index)?.perm, 0o664);
+
+    handle.open_file(&user(1001), index, O_WRONLY as u32)?;
+    handle.open_file(&user(1002), index, O_RDONLY as u32)?;
+    assert!(handle
+        .open_file(&user(1002), index, O_WRONLY as u32)
+        .is_err());
+    handle.stop_engine();
+
+    let handle = start(&work_dir, None)?;
+    assert_eq!(handle.get_xattr(&root, index, access)?, Some(value));
+    assert_eq!(
+        handle.list_xattrs(&root, index)?,
+        vec![OsString::from(access)]
+    );
+    handle.remove_xattr(&user(1000), index, access)?;
+    assert_eq!(handle.get_xattr(&root, index, access)?, None);
+    assert!(handle.remove_xattr(&user(1000), index, access).is_err());
+    handle.stop_engine();
+    Ok(())
+}
+
+// `du` reports the blocks of the files, which follow their sizes as they are
+// written, truncated and committed
+#[test]
+fn blocks_follow_the_size_of_files() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_blocks")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(sync_dir.join("imported"), vec![1; 600])?;
+
+    let handle = start(&work_dir, Some(&sync_dir))?;
+    let root = RequestId::default();
+    let imported = handle
+        .lookup(&root, ROOT_INDEX, OsStr::new("imported"))?
+        .unwrap()
+        .attributes;
+    assert_eq!((imported.size, imported.blocks), (600, 2));
+
+    let (entry, _) = handle.create_file(&root, ROOT_INDEX, OsStr::new("new"), 0o644, 0)?;
+    let index = entry.attributes.index;
+    assert_eq!(entry.attributes.blocks, 0);
+    handle.write_data(&root, index, 0, vec![1; 1025])?;
+    assert_eq!(handle.get_attr(&root, index)?.blocks, 3);
+    let truncate =
+        FileAttributeChanges::new(None, None, None, Some(10), None, None, None, None, None);
+    assert_eq!(handle.set_attr(&root, index, truncate)?.blocks, 1);
+    handle.release_file(&root, index, O_WRONLY as u32, 0, true)?;
+    handle.commit(None)?;
+    assert_eq!(handle.get_attr(&root, index)?.blocks, 1);
+    handle.stop_engine();
+
+    let handle = start(&work_dir, None)?;
+    assert_eq!(handle.get_attr(&root, index)?.blocks, 1);
+    handle.stop_engine();
+    Ok(())
+}
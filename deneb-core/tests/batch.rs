@@ -0,0 +1,179 @@
+use {
+    deneb_core::{
+        catalog::CatalogType,
+        engine::{
+            start_engine, BatchInode, BatchOperation, EngineOptions, Handle, RequestId, Snapshot,
+            WorkspaceOptions,
+        },
+        errors::{DenebResult, EngineError},
+        store::StoreType,
+    },
+    failure::Context,
+    std::{
+        ffi::{OsStr, OsString},
+        fs::create_dir_all,
+        path::Path,
+    },
+    tempdir::TempDir,
+};
+
+const CHUNK_SIZE: usize = 16;
+const ROOT_INDEX: u64 = 1;
+
+fn start(work_dir: &Path, sync_dir: &Path) -> DenebResult<Handle> {
+    start_engine(
+        CatalogType::Lmdb,
+        StoreType::OnDisk,
+        work_dir.to_owned(),
+        EngineOptions {
+            workspace: WorkspaceOptions {
+                sync_dir: Some(sync_dir.to_owned()),
+                chunk_size: CHUNK_SIZE,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+            ..EngineOptions::default()
+        },
+    )
+}
+
+// The operations of a batch refer to the inodes created before them, and are
+// committed together
+#[test]
+fn batches_are_applied_in_order() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_batch")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+
+    let handle = start(&work_dir, &sync_dir)?;
+    let id = RequestId::default();
+    let replies = handle.batch(
+        &id,
+        vec![
+            BatchOperation::CreateDir {
+                parent: BatchInode::Index(ROOT_INDEX),
+                name: OsString::from("docs"),
+                mode: 0o40_755,
+            },
+            BatchOperation::CreateFile {
+                parent: BatchInode::Created(0),
+                name: OsString::from("notes.txt.tmp"),
+                mode: 0o100_644,
+            },
+            BatchOperation::Write {
+                file: BatchInode::Created(1),
+                offset: 0,
+                data: b"alabalaportocala".to_vec(),
+            },
+            BatchOperation::Rename {
+                parent: BatchInode::Created(0),
+                name: OsString::from("notes.txt.tmp"),
+                new_parent: BatchInode::Created(0),
+                new_name: OsString::from("notes.txt"),
+            },
+        ],
+    )?;
+    assert_eq!(replies.len(), 4);
+    assert!(replies[0].is_some() && replies[1].is_some());
+    assert!(replies[2].is_none() && replies[3].is_none());
+    let dir = replies[0].unwrap().index;
+    let file = replies[1].unwrap().index;
+
+    let entry = handle.lookup(&id, dir, OsStr::new("notes.txt"))?.unwrap();
+    assert_eq!(entry.attributes.index, file);
+    assert_eq!(entry.attributes.size, 16);
+    assert!(handle
+        .lookup(&id, dir, OsStr::new("notes.txt.tmp"))?
+        .is_none());
+
+    // Nothing is applied when one of the operations fails
+    let failed = handle
+        .batch(
+            &id,
+            vec![
+                BatchOperation::CreateFile {
+                    parent: BatchInode::Index(dir),
+                    name: OsString::from("todo.txt"),
+                    mode: 0o100_644,
+                },
+                BatchOperation::Write {
+                    file: BatchInode::Created(3),
+                    offset: 0,
+                    data: b"x".to_vec(),
+                },
+                BatchOperation::CreateDir {
+                    parent: BatchInode::Index(dir),
+                    name: OsString::from("drafts"),
+                    mode: 0o40_755,
+                },
+            ],
+        )
+        .unwrap_err();
+    assert!(failed.iter_chain().any(|cause| matches!(
+        cause
+            .downcast_ref::<Context<EngineError>>()
+            .map(Context::get_context),
+        Some(EngineError::Batch(1))
+    )));
+    assert!(handle.lookup(&id, dir, OsStr::new("todo.txt"))?.is_none());
+    assert!(handle.lookup(&id, dir, OsStr::new("drafts"))?.is_none());
+
+    handle.commit(None)?;
+    let snapshot = Snapshot::open(&work_dir, None, CHUNK_SIZE)?;
+    let attrs = snapshot.resolve(Path::new("docs/notes.txt"))?.unwrap();
+    let mut committed = Vec::new();
+    snapshot.read_file(attrs.index, &mut committed)?;
+    assert_eq!(committed, b"alabalaportocala");
+
+    handle.stop_engine();
+    Ok(())
+}
+
+// The pages cached by the kernel for a file written by a batch are dropped
+#[test]
+fn batch_writes_invalidate_cached_data() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_batch_invalidate")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+
+    let handle = start(&work_dir, &sync_dir)?;
+    let id = RequestId::default();
+    let replies = handle.batch(
+        &id,
+        vec![BatchOperation::CreateFile {
+            parent: BatchInode::Index(ROOT_INDEX),
+            name: OsString::from("notes.txt"),
+            mode: 0o100_644,
+        }],
+    )?;
+    let file = replies[0].unwrap().index;
+    // Looked up and opened by the kernel
+    handle.lookup(&id, ROOT_INDEX, OsStr::new("notes.txt"))?;
+    assert!(handle.open_file(&id, file, 0)?.keep_cache);
+    handle.release_file(&id, file, 0, 0, false)?;
+    let before = handle.get_attr(&id, file)?;
+
+    handle.batch(
+        &id,
+        vec![BatchOperation::Write {
+            file: BatchInode::Index(file),
+            offset: 0,
+            data: b"alabalaportocala".to_vec(),
+        }],
+    )?;
+    let after = handle.get_attr(&id, file)?;
+    assert!(after.mtime > before.mtime);
+    assert_eq!(after.size, 16);
+    // Only the next open drops the cached pages
+    assert!(!handle.open_file(&id, file, 0)?.keep_cache);
+    handle.release_file(&id, file, 0, 0, false)?;
+    assert!(handle.open_file(&id, file, 0)?.keep_cache);
+    handle.release_file(&id, file, 0, 0, false)?;
+
+    handle.stop_engine();
+    Ok(())
+}
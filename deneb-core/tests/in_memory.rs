@@ -0,0 +1,109 @@
+use {
+    deneb_core::{
+        catalog::CatalogType,
+        engine::{start_engine, EngineOptions, Handle, RequestId, WorkspaceOptions},
+        errors::DenebResult,
+        store::StoreType,
+    },
+    nix::fcntl::OFlag,
+    std::{
+        ffi::OsStr,
+        fs::{create_dir_all, write},
+        path::Path,
+    },
+    tempdir::TempDir,
+};
+
+const CHUNK_SIZE: usize = 16;
+const ROOT_INDEX: u64 = 1;
+
+fn start(work_dir: &Path, sync_dir: &Path) -> DenebResult<Handle> {
+    start_engine(
+        CatalogType::InMemory,
+        StoreType::InMemory,
+        work_dir.to_owned(),
+        EngineOptions {
+            workspace: WorkspaceOptions {
+                sync_dir: Some(sync_dir.to_owned()),
+                chunk_size: CHUNK_SIZE,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+            ..EngineOptions::default()
+        },
+    )
+}
+
+// The catalogs of the previous commits are read back from the in-memory store
+#[test]
+fn in_memory_engines_keep_their_history() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_in_memory")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(sync_dir.join("notes.txt"), "alabalaportocala")?;
+
+    let handle = start(&work_dir, &sync_dir)?;
+    let id = RequestId::default();
+    let index = handle
+        .lookup(&id, ROOT_INDEX, OsStr::new("notes.txt"))?
+        .unwrap()
+        .attributes
+        .index;
+    let flags = OFlag::O_WRONLY.bits() as u32;
+    handle.open_file(&id, index, flags)?;
+    handle.write_data(&id, index, 0, b"40 de turcaleti.".to_vec())?;
+    handle.release_file(&id, index, flags, 0, true)?;
+    handle.commit(None)?;
+
+    let (root_hash, attrs) = handle.previous_version(&id, index)?.unwrap();
+    assert_eq!(attrs.size, 16);
+    assert_eq!(
+        handle.read_previous_version(&id, index)?,
+        Some(b"alabalaportocala".to_vec())
+    );
+
+    handle.restore(root_hash, Path::new("notes.txt"), true)?;
+    handle.commit(None)?;
+    handle.collect_garbage()?;
+    let index = handle
+        .lookup(&id, ROOT_INDEX, OsStr::new("notes.txt"))?
+        .unwrap()
+        .attributes
+        .index;
+    handle.open_file(&id, index, 0)?;
+    assert_eq!(handle.read_data(&id, index, 0, 16)?, b"alabalaportocala");
+    handle.release_file(&id, index, 0, 0, false)?;
+
+    handle.stop_engine();
+    Ok(())
+}
+
+// Pins are recorded in the catalog, so pinning makes a new commit
+#[test]
+fn pinned_dirs_are_committed() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_in_memory_pins")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(sync_dir.join("photos"))?;
+    write(sync_dir.join("photos/beach.jpg"), "alabalaportocala")?;
+
+    let handle = start(&work_dir, &sync_dir)?;
+    let id = RequestId::default();
+    let (index, _) = handle.resolve_path(&id, Path::new("/photos"))?.unwrap();
+    assert!(!handle.set_pinned(index, true)?);
+    assert!(handle.set_pinned(index, true)?);
+    assert!(handle.commit(None)?.new_root_hash.is_some());
+    let metrics = handle.metrics()?;
+    assert_eq!(metrics.pinned_entries, 1);
+    assert_eq!(metrics.pinned_chunks, 1);
+
+    assert!(handle.set_pinned(index, false)?);
+    assert!(handle.commit(None)?.new_root_hash.is_some());
+    assert_eq!(handle.metrics()?.pinned_entries, 0);
+
+    handle.stop_engine();
+    Ok(())
+}
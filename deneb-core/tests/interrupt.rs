@@ -0,0 +1,82 @@
+use {
+    deneb_core::{
+        catalog::CatalogType,
+        engine::{start_engine, EngineOptions, Handle, RequestId, WorkspaceOptions},
+        errors::{DenebResult, EngineError},
+        store::StoreType,
+    },
+    nix::fcntl::OFlag,
+    std::{
+        ffi::OsStr,
+        fs::{create_dir_all, write},
+        path::Path,
+        thread::{spawn, yield_now},
+    },
+    tempdir::TempDir,
+};
+
+const CHUNK_SIZE: usize = 16;
+const ROOT_INDEX: u64 = 1;
+const FILE_SIZE: usize = 128 * 1024;
+
+fn start(work_dir: &Path, sync_dir: &Path) -> DenebResult<Handle> {
+    start_engine(
+        CatalogType::Lmdb,
+        StoreType::OnDisk,
+        work_dir.to_owned(),
+        EngineOptions {
+            workspace: WorkspaceOptions {
+                sync_dir: Some(sync_dir.to_owned()),
+                chunk_size: CHUNK_SIZE,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+            ..EngineOptions::default()
+        },
+    )
+}
+
+// A read of many chunks is abandoned once its caller interrupts it, and the
+// interrupts of reads which are not in flight are ignored
+#[test]
+fn interrupted_reads_are_abandoned() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_interrupt")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(sync_dir.join("large"), vec![b'a'; FILE_SIZE])?;
+
+    let handle = start(&work_dir, &sync_dir)?;
+    let id = RequestId {
+        unique_id: 42,
+        ..RequestId::default()
+    };
+    let index = handle
+        .lookup(&id, ROOT_INDEX, OsStr::new("large"))?
+        .unwrap()
+        .attributes
+        .index;
+    handle.open_file(&id, index, OFlag::O_RDONLY.bits() as u32)?;
+
+    let reader = handle.clone();
+    let read = spawn(move || reader.read_data(&id, index, 0, FILE_SIZE as u32));
+    while handle.reads_in_flight().is_empty() {
+        yield_now();
+    }
+    assert_eq!(handle.reads_in_flight()[0].unique_id, 42);
+    handle.interrupt(&id);
+    let error = read.join().unwrap().unwrap_err();
+    match error.downcast_ref::<EngineError>() {
+        Some(EngineError::Interrupted(interrupted)) => assert_eq!(*interrupted, index),
+        _ => panic!("Unexpected error: {}", error),
+    }
+    assert!(handle.reads_in_flight().is_empty());
+
+    // Interrupting a request which isn't a read in flight has no effect
+    handle.interrupt(&id);
+    assert_eq!(handle.read_data(&id, index, 0, 16)?, vec![b'a'; 16]);
+
+    handle.stop_engine();
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use {
+    deneb_core::{
+        catalog::CatalogType,
+        engine::{start_engine, EngineOptions, Handle, RequestId, Snapshot, WorkspaceOptions},
+        errors::DenebResult,
+        store::StoreType,
+    },
+    std::{
+        ffi::OsStr,
+        fs::{create_dir_all, write},
+        path::Path,
+    },
+    tempdir::TempDir,
+};
+
+const CHUNK_SIZE: usize = 16;
+const ROOT_INDEX: u64 = 1;
+
+fn start(
+    work_dir: &Path,
+    sync_dir: &Path,
+    store_type: StoreType,
+    max_memory: u64,
+) -> DenebResult<Handle> {
+    start_engine(
+        CatalogType::Lmdb,
+        store_type,
+        work_dir.to_owned(),
+        EngineOptions {
+            workspace: WorkspaceOptions {
+                sync_dir: Some(sync_dir.to_owned()),
+                chunk_size: CHUNK_SIZE,
+                chunk_cache_size: 1024 * 1024,
+                ..WorkspaceOptions::default()
+            },
+            max_memory,
+            ..EngineOptions::default()
+        },
+    )
+}
+
+// Data written beyond the memory limit is committed without being asked to
+#[test]
+fn memory_limit_triggers_commits() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_memory")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(sync_dir.join("notes.txt"), "alabalaportocala")?;
+
+    let handle = start(&work_dir, &sync_dir, StoreType::OnDisk, 1024)?;
+    let id = RequestId::default();
+    let (entry, _) = handle.create_file(&id, ROOT_INDEX, OsStr::new("log.txt"), 0o644, 0)?;
+    let index = entry.attributes.index;
+    let line = [b'x'; 64];
+    let mut written = Vec::new();
+    for _ in 0..100 {
+        handle.write_data(&id, index, written.len() as i64, line.to_vec())?;
+        written.extend_from_slice(&line);
+    }
+
+    let metrics = handle.metrics()?;
+    assert!(metrics.memory.file_data < written.len() as u64);
+
+    // Part of the file was committed, without a commit being requested
+    let snapshot = Snapshot::open(&work_dir, None, CHUNK_SIZE)?;
+    let attrs = snapshot.resolve(Path::new("log.txt"))?.unwrap();
+    let mut committed = Vec::new();
+    snapshot.read_file(attrs.index, &mut committed)?;
+    assert!(!committed.is_empty());
+    assert!(written.starts_with(&committed));
+
+    handle.stop_engine();
+    Ok(())
+}
+
+// Warming up a file leaves its chunks to the cache of the store
+#[test]
+fn prefetched_chunks_are_not_held() -> DenebResult<()> {
+    deneb_core::init()?;
+    let tmp = TempDir::new("deneb_memory_prefetch")?;
+    let work_dir = tmp.path().join("work");
+    let sync_dir = tmp.path().join("sync");
+    create_dir_all(&sync_dir)?;
+    write(
+        sync_dir.join("notes.txt"),
+        "alabalaportocala, 40 de turcaleti",
+    )?;
+
+    // Chunks of the in-memory store are held in memory
+    let handle = start(&work_dir, &sync_dir, StoreType::InMemory, 0)?;
+    let id = RequestId::default();
+    let entry = handle
+        .lookup(&id, ROOT_INDEX, OsStr::new("notes.txt"))?
+        .unwrap();
+    handle.prefetch(&id, entry.attributes.index)?;
+    let metrics = handle.metrics()?;
+    assert_eq!(metrics.memory.chunks, 0);
+    assert_eq!(metrics.open_files, 0);
+
+    handle.stop_engine();
+    Ok(())
+}
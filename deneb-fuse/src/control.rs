@@ -0,0 +1,191 @@
+//! The `.deneb` directory at the root of the mount
+//!
+//! Like the `.zfs` directory of ZFS, it isn't stored in the repository and
+//! isn't listed in the root directory, so it isn't copied along with the
+//! files, but it can be entered. It holds:
+//!
+//! * `status` - the last commit and the number of pending changes
+//! * `stats` - the metrics of the engine
+//! * `commit` - a write to it commits the pending changes, with the written
+//!   text as the message unless it's `1`, and reading it shows the summary of
+//!   that commit
+//!
+//! The contents of `status` and `stats` are taken when they are opened.
+
+use {
+    deneb_core::{
+        engine::Handle,
+        errors::DenebResult,
+        inode::{FileAttributes, FileType},
+    },
+    fuser::FUSE_ROOT_ID,
+    std::{collections::HashMap, ffi::OsStr},
+};
+
+/// Name of the control directory, in the root directory
+pub(crate) const CONTROL_DIR_NAME: &str = ".deneb";
+
+// The indices of the catalog count up from the root, so the ones at the top
+// of the range are left for the control entries
+const DIR_INDEX: u64 = u64::max_value() - 3;
+const STATUS_INDEX: u64 = u64::max_value() - 2;
+const STATS_INDEX: u64 = u64::max_value() - 1;
+const COMMIT_INDEX: u64 = u64::max_value();
+
+/// Message of the commits written to `commit` with a `1`
+const COMMIT_MESSAGE: &str = "Commit requested through the control directory";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Node {
+    Dir,
+    Status,
+    Stats,
+    Commit,
+}
+
+impl Node {
+    pub(crate) fn from_index(index: u64) -> Option<Node> {
+        match index {
+            DIR_INDEX => Some(Node::Dir),
+            STATUS_INDEX => Some(Node::Status),
+            STATS_INDEX => Some(Node::Stats),
+            COMMIT_INDEX => Some(Node::Commit),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn index(self) -> u64 {
+        match self {
+            Node::Dir => DIR_INDEX,
+            Node::Status => STATUS_INDEX,
+            Node::Stats => STATS_INDEX,
+            Node::Commit => COMMIT_INDEX,
+        }
+    }
+
+    pub(crate) fn kind(self) -> FileType {
+        match self {
+            Node::Dir => FileType::Directory,
+            _ => FileType::RegularFile,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Node::Dir => CONTROL_DIR_NAME,
+            Node::Status => "status",
+            Node::Stats => "stats",
+            Node::Commit => "commit",
+        }
+    }
+
+    /// Find an entry of the control directory
+    pub(crate) fn lookup(name: &OsStr) -> Option<Node> {
+        [Node::Status, Node::Stats, Node::Commit]
+            .iter()
+            .cloned()
+            .find(|node| OsStr::new(node.name()) == name)
+    }
+
+    /// The entries of the control directory, with the cookies of the
+    /// directory listing, starting after `offset`
+    pub(crate) fn entries(offset: i64) -> Vec<(i64, Node, &'static str)> {
+        vec![
+            (1, Node::Dir, "."),
+            (2, Node::Dir, ".."),
+            (3, Node::Status, Node::Status.name()),
+            (4, Node::Stats, Node::Stats.name()),
+            (5, Node::Commit, Node::Commit.name()),
+        ]
+        .into_iter()
+        .filter(|(cookie, _, _)| *cookie > offset)
+        .collect()
+    }
+
+    /// The attributes of the entry, owned by the owner of the root directory.
+    /// Files are shown as empty, their contents are only known once opened.
+    pub(crate) fn attributes(self, root: &FileAttributes) -> FileAttributes {
+        let mut attrs = root.clone();
+        attrs.index = self.index();
+        attrs.size = 0;
+        attrs.blocks = 0;
+        attrs.flags = 0;
+        attrs.kind = self.kind();
+        attrs.nlink = 1;
+        attrs.perm = match self {
+            Node::Dir => 0o555,
+            Node::Status | Node::Stats => 0o444,
+            Node::Commit => 0o644,
+        };
+        if self == Node::Dir {
+            attrs.nlink = 2;
+        }
+        attrs
+    }
+}
+
+/// The open control files
+#[derive(Default)]
+pub(crate) struct Control {
+    /// Contents of the open files, by file handle
+    open: HashMap<u64, Vec<u8>>,
+    next_handle: u64,
+    /// Summary of the last commit requested through `commit`
+    last_commit: String,
+}
+
+impl Control {
+    /// Take the contents of a control file, returning its file handle
+    pub(crate) fn open(&mut self, handle: &Handle, node: Node) -> DenebResult<u64> {
+        let contents = match node {
+            Node::Status => status(handle)?,
+            Node::Stats => format!("{:#?}\n", handle.metrics()?),
+            Node::Commit => self.last_commit.clone(),
+            Node::Dir => String::new(),
+        };
+        self.next_handle += 1;
+        self.open.insert(self.next_handle, contents.into_bytes());
+        Ok(self.next_handle)
+    }
+
+    pub(crate) fn read(&self, fh: u64, offset: i64, size: u32) -> &[u8] {
+        let contents = self.open.get(&fh).map_or(&[][..], Vec::as_slice);
+        let start = (offset.max(0) as usize).min(contents.len());
+        let end = start.saturating_add(size as usize).min(contents.len());
+        &contents[start..end]
+    }
+
+    pub(crate) fn release(&mut self, fh: u64) {
+        self.open.remove(&fh);
+    }
+
+    /// Commit the pending changes. Writes are expected to hold the whole
+    /// message, like the ones of `echo`.
+    pub(crate) fn commit(&mut self, handle: &Handle, data: &[u8]) -> DenebResult<()> {
+        let text = String::from_utf8_lossy(data);
+        let message = match text.trim() {
+            "" | "1" => COMMIT_MESSAGE.to_owned(),
+            message => message.to_owned(),
+        };
+        let summary = handle.commit(Some(message))?;
+        self.last_commit = summary.to_string();
+        Ok(())
+    }
+}
+
+fn status(handle: &Handle) -> DenebResult<String> {
+    let manifest = handle.manifest()?;
+    let pending = handle.pending_changes()?;
+    Ok(format!(
+        "{}\nPending changes: {}, bytes to write: {}\n",
+        manifest,
+        pending.changes.len(),
+        pending.bytes_to_write
+    ))
+}
+
+/// Whether the entry is the control directory or one of its entries, which
+/// can't be created, removed or renamed
+pub(crate) fn is_control_entry(parent: u64, name: &OsStr) -> bool {
+    (parent == FUSE_ROOT_ID && name == OsStr::new(CONTROL_DIR_NAME)) || parent == DIR_INDEX
+}
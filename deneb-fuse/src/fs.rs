@@ -1,3 +1,7 @@
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+use nix::libc::ENOATTR;
+#[cfg(target_os = "linux")]
+use nix::libc::ENODATA as ENOATTR;
 #[cfg(target_os = "linux")]
 use nix::mount::{umount2, MntFlags};
 #[cfg(any(target_os = "macos", target_os = "freebsd"))]
@@ -6,35 +10,101 @@ use nix::{
     NixPath,
 };
 use {
+    crate::{
+        control::{is_control_entry, Control, Node, CONTROL_DIR_NAME},
+        interrupt::InterruptWatcher,
+    },
     deneb_core::{
-        engine::{Handle, RequestId},
-        errors::{print_error_with_causes, DenebResult, EngineError, UnixError},
+        engine::{Handle, OpenFlags, RequestId},
+        errors::{
+            print_error_with_causes, CatalogError, DenebResult, EngineError, UnixError,
+            WorkspaceError,
+        },
         inode::{FileAttributeChanges, FileAttributes, FileType as FT},
     },
-    fuse::{
-        mount, spawn_mount, BackgroundSession, FileAttr, FileType, Filesystem, ReplyAttr,
-        ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite,
-        Request,
+    failure::Error,
+    fuser::{
+        consts::{
+            FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE, FUSE_ASYNC_READ, FUSE_AUTO_INVAL_DATA,
+            FUSE_BIG_WRITES, FUSE_DO_READDIRPLUS, FUSE_READDIRPLUS_AUTO,
+        },
+        mount, spawn_mount, BackgroundSession, FileAttr, FileType, Filesystem, KernelConfig,
+        ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty,
+        ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+        FUSE_ROOT_ID,
+    },
+    log::{info, warn},
+    nix::{
+        libc::{
+            c_int, E2BIG, EACCES, EEXIST, EINTR, EINVAL, EIO, ENOTSUP, EOPNOTSUPP, EPERM, ERANGE,
+            ESTALE, O_ACCMODE, O_RDONLY,
+        },
+        sys::statvfs::statvfs,
     },
-    nix::libc::{EACCES, EINVAL, ENOENT},
     std::{
         ffi::OsStr,
         iter::Iterator,
+        os::unix::ffi::OsStrExt,
         path::{Path, PathBuf},
+        thread::spawn,
+        time::{Duration, SystemTime, UNIX_EPOCH},
     },
     time::Timespec,
 };
 
-pub struct Session<'a> {
-    fuse_session: BackgroundSession<'a>,
+/// How long the kernel keeps the attributes and the entries it was given
+const TTL: Duration = Duration::from_secs(1);
+/// How long the kernel remembers that a name doesn't exist
+const NEGATIVE_TTL: Duration = Duration::from_secs(1);
+/// Block size reported for all the files, for the buffers of the programs
+/// reading and writing them
+const BLOCK_SIZE: u32 = 4096;
+/// Longest name of an entry
+const NAME_MAX: u32 = 255;
+/// Mode of `fallocate` which only reserves space, without changing the size
+const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+
+/// Extended attribute holding the root hash of the most recent retained commit
+/// with a different version of a file
+const PREV_ROOT_XATTR: &str = "user.deneb.prev_root";
+/// Extended attribute holding the contents of that version of the file
+const PREV_XATTR: &str = "user.deneb.prev";
+/// Extended attribute holding the digest of the whole contents of a file, as
+/// a hexadecimal SHA-512
+const SHA_XATTR: &str = "user.deneb.sha";
+/// Largest value of an extended attribute accepted by the kernel
+const XATTR_SIZE_MAX: u64 = 64 * 1024;
+
+/// What is asked of and reported to the kernel once the file system is mounted
+#[derive(Clone, Debug)]
+pub struct KernelOptions {
+    /// Largest write request, in bytes. Without it, writes are split by the
+    /// kernel into page sized requests.
+    pub max_write: u32,
+    /// Largest read-ahead, in bytes
+    pub max_readahead: u32,
+    /// Directory whose file system's size and free space are reported for the
+    /// mounted one, i.e. the work dir
+    pub stats_dir: Option<PathBuf>,
+}
+
+impl Default for KernelOptions {
+    fn default() -> KernelOptions {
+        KernelOptions {
+            max_write: 128 * 1024,
+            max_readahead: 1024 * 1024,
+            stats_dir: None,
+        }
+    }
+}
+
+pub struct Session {
+    fuse_session: BackgroundSession,
     mount_point: PathBuf,
 }
 
-impl<'a> Session<'a> {
-    pub fn new<P: AsRef<Path>>(
-        fuse_session: BackgroundSession<'a>,
-        mount_point: &P,
-    ) -> Session<'a> {
+impl Session {
+    pub fn new<P: AsRef<Path>>(fuse_session: BackgroundSession, mount_point: &P) -> Session {
         Session {
             fuse_session,
             mount_point: mount_point.as_ref().to_owned(),
@@ -60,36 +130,81 @@ impl<'a> Session<'a> {
 
 pub struct Fs {
     engine_handle: Handle,
+    kernel_options: KernelOptions,
+    control: Control,
+    /// Kept until the file system is unmounted
+    _interrupts: InterruptWatcher,
+}
+
+// The FUSE device only exists once the fusefs kernel module is loaded, which
+// isn't done by the mount on FreeBSD
+#[cfg(target_os = "freebsd")]
+fn check_fuse_device() -> DenebResult<()> {
+    if !Path::new("/dev/fuse").exists() {
+        return Err(failure::err_msg(
+            "/dev/fuse is missing, load the fusefs kernel module with `kldload fusefs`",
+        ));
+    }
+    Ok(())
 }
 
-impl<'a> Fs {
+#[cfg(not(target_os = "freebsd"))]
+fn check_fuse_device() -> DenebResult<()> {
+    Ok(())
+}
+
+// Inode numbers are passed to the kernel as they are, so the root directory
+// has to be the one the kernel asks for
+fn check_root(engine_handle: &Handle) -> DenebResult<()> {
+    let root = engine_handle.get_root_attr()?;
+    if root.index != FUSE_ROOT_ID {
+        return Err(WorkspaceError::InvalidRoot(root.index).into());
+    }
+    Ok(())
+}
+
+impl Fs {
     pub fn spawn_mount<P: AsRef<Path>>(
         mount_point: &P,
         engine_handle: Handle,
         options: &[String],
-    ) -> DenebResult<Session<'a>> {
+        kernel_options: KernelOptions,
+    ) -> DenebResult<Session> {
         let opts = options
             .iter()
             .map(std::convert::AsRef::as_ref)
             .collect::<Vec<&OsStr>>();
-        let fs = Fs { engine_handle };
-        unsafe {
-            spawn_mount(fs, mount_point, &opts)
-                .map(|s| Session::new(s, mount_point))
-                .map_err(std::convert::Into::into)
-        }
+        check_fuse_device()?;
+        check_root(&engine_handle)?;
+        let fs = Fs {
+            _interrupts: InterruptWatcher::start(engine_handle.clone()),
+            engine_handle,
+            kernel_options,
+            control: Control::default(),
+        };
+        spawn_mount(fs, mount_point, &opts)
+            .map(|s| Session::new(s, mount_point))
+            .map_err(std::convert::Into::into)
     }
 
     pub fn mount<P: AsRef<Path>>(
         mount_point: &P,
         engine_handle: Handle,
         options: &[String],
+        kernel_options: KernelOptions,
     ) -> DenebResult<()> {
         let opts = options
             .iter()
             .map(std::convert::AsRef::as_ref)
             .collect::<Vec<&OsStr>>();
-        let fs = Fs { engine_handle };
+        check_fuse_device()?;
+        check_root(&engine_handle)?;
+        let fs = Fs {
+            _interrupts: InterruptWatcher::start(engine_handle.clone()),
+            engine_handle,
+            kernel_options,
+            control: Control::default(),
+        };
         mount(fs, mount_point, &opts).map_err(std::convert::Into::into)
     }
 
@@ -98,18 +213,76 @@ impl<'a> Fs {
             .flat_map(|o| vec!["-o".to_owned(), o.clone()])
             .collect::<Vec<String>>()
     }
+
+    fn control_attr(&self, node: Node) -> DenebResult<FileAttr> {
+        let root = self.engine_handle.get_root_attr()?;
+        Ok(to_fuse_file_attr(node.attributes(&root)))
+    }
+
+    fn reply_control_attr(&self, node: Node, reply: ReplyAttr) {
+        match self.control_attr(node) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => {
+                print_error_with_causes(&e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn reply_control_entry(&self, node: Node, reply: ReplyEntry) {
+        match self.control_attr(node) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => {
+                print_error_with_causes(&e);
+                reply.error(EIO);
+            }
+        }
+    }
 }
 
 impl Filesystem for Fs {
+    // Directory listings carry the attributes of their entries, so listing a
+    // directory doesn't need a lookup per entry, and reads are sent while the
+    // previous ones are handled. The capabilities and sizes which the kernel
+    // doesn't support are left out.
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        // With FUSE_AUTO_INVAL_DATA, the kernel drops the cached pages of the
+        // files whose size or modification time changed when their attributes
+        // are fetched again, including the ones of files which are open
+        let wanted = FUSE_ASYNC_READ
+            | FUSE_AUTO_INVAL_DATA
+            | FUSE_BIG_WRITES
+            | FUSE_DO_READDIRPLUS
+            | FUSE_READDIRPLUS_AUTO;
+        if let Err(unsupported) = config.add_capabilities(wanted) {
+            warn!("Kernel capabilities not supported: {:#x}", unsupported);
+            let _ = config.add_capabilities(wanted & !unsupported);
+        }
+        let max_write = config
+            .set_max_write(self.kernel_options.max_write)
+            .or_else(|nearest| config.set_max_write(nearest).map(|_| nearest));
+        let max_readahead = config
+            .set_max_readahead(self.kernel_options.max_readahead)
+            .or_else(|nearest| config.set_max_readahead(nearest).map(|_| nearest));
+        if max_write.is_err() || max_readahead.is_err() {
+            warn!("The maximum write and read-ahead sizes could not be negotiated");
+        }
+        info!("FUSE session initialized");
+        Ok(())
+    }
+
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        if let Some(node) = Node::from_index(ino) {
+            self.reply_control_attr(node, reply);
+            return;
+        }
         match self.engine_handle.get_attr(&to_request_id(req), ino) {
             Ok(attrs) => {
-                let ttl = Timespec::new(1, 0);
-                reply.attr(&ttl, &to_fuse_file_attr(attrs));
+                reply.attr(&TTL, &to_fuse_file_attr(attrs));
             }
             Err(e) => {
                 print_error_with_causes(&e);
-                reply.error(EINVAL);
+                reply.error(stale_errno(&e));
             }
         }
     }
@@ -122,51 +295,101 @@ impl Filesystem for Fs {
         uid: Option<u32>,
         gid: Option<u32>,
         size: Option<u64>,
-        atime: Option<Timespec>,
-        mtime: Option<Timespec>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
         _fh: Option<u64>,
-        crtime: Option<Timespec>,
-        chgtime: Option<Timespec>,
-        _bkuptime: Option<Timespec>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
         flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        let changes =
-            FileAttributeChanges::new(mode, uid, gid, size, atime, mtime, crtime, chgtime, flags);
+        // The commit file is truncated by the shell before it's written
+        if let Some(node) = Node::from_index(ino) {
+            let truncated = size.map_or(true, |size| size == 0);
+            if node == Node::Commit && mode.is_none() && uid.is_none() && gid.is_none() && truncated
+            {
+                self.reply_control_attr(node, reply);
+            } else {
+                reply.error(EPERM);
+            }
+            return;
+        }
+        let changes = FileAttributeChanges::new(
+            mode,
+            uid,
+            gid,
+            size,
+            atime.map(from_time_or_now),
+            mtime.map(from_time_or_now),
+            crtime.map(from_system_time),
+            chgtime.map(from_system_time),
+            flags,
+        );
         match self
             .engine_handle
             .set_attr(&to_request_id(req), ino, changes)
         {
             Ok(attrs) => {
-                let ttl = Timespec::new(1, 0);
-                reply.attr(&ttl, &to_fuse_file_attr(attrs));
+                reply.attr(&TTL, &to_fuse_file_attr(attrs));
             }
             Err(e) => {
                 print_error_with_causes(&e);
-                reply.error(EINVAL);
+                reply.error(setattr_errno(&e));
             }
         }
     }
 
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        if Node::from_index(ino).is_some() {
+            return;
+        }
+        self.engine_handle.forget(ino, nlookup);
+    }
+
+    // The control directory hides any entry with the same name in the root
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent == FUSE_ROOT_ID && name == OsStr::new(CONTROL_DIR_NAME) {
+            self.reply_control_entry(Node::Dir, reply);
+            return;
+        }
+        if Node::from_index(parent).is_some() {
+            match Node::lookup(name) {
+                Some(node) => self.reply_control_entry(node, reply),
+                None => {
+                    let attrs = FileAttributes::default();
+                    reply.entry(&NEGATIVE_TTL, &to_fuse_file_attr(attrs), 0);
+                }
+            }
+            return;
+        }
         match self.engine_handle.lookup(&to_request_id(req), parent, name) {
-            Ok(Some(attrs)) => {
-                let ttl = Timespec::new(1, 0);
-                reply.entry(&ttl, &to_fuse_file_attr(attrs), 0);
+            Ok(Some(entry)) => {
+                reply.entry(&TTL, &to_fuse_file_attr(entry.attributes), entry.generation);
             }
             Ok(None) => {
-                reply.error(ENOENT);
-                return;
+                // A missing name is remembered by the kernel for the duration
+                // of the entry timeout, like any other entry
+                let attrs = FileAttributes::default();
+                reply.entry(&NEGATIVE_TTL, &to_fuse_file_attr(attrs), 0);
             }
             Err(e) => {
                 print_error_with_causes(&e);
-                reply.error(EINVAL);
+                reply.error(stale_errno(&e));
             }
         }
     }
 
-    fn opendir(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        match self.engine_handle.open_dir(&to_request_id(req), ino, flags) {
+    fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        if Node::from_index(ino).is_some() {
+            reply.opened(ino, 0);
+            return;
+        }
+        match self
+            .engine_handle
+            .open_dir(&to_request_id(req), ino, flags as u32)
+        {
             Ok(()) => {
                 reply.opened(ino, 0);
             }
@@ -177,10 +400,14 @@ impl Filesystem for Fs {
         }
     }
 
-    fn releasedir(&mut self, req: &Request, _ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+    fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        if Node::from_index(ino).is_some() {
+            reply.ok();
+            return;
+        }
         match self
             .engine_handle
-            .release_dir(&to_request_id(req), fh, flags)
+            .release_dir(&to_request_id(req), fh, flags as u32)
         {
             Ok(_) => {
                 reply.ok();
@@ -195,19 +422,29 @@ impl Filesystem for Fs {
     fn readdir(
         &mut self,
         req: &Request,
-        _ino: u64,
+        ino: u64,
         fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
+        if Node::from_index(ino).is_some() {
+            for (cookie, node, name) in Node::entries(offset) {
+                let index = if name == ".." {
+                    FUSE_ROOT_ID
+                } else {
+                    node.index()
+                };
+                if reply.add(index, cookie, to_fuse_file_type(node.kind()), name) {
+                    break;
+                }
+            }
+            reply.ok();
+            return;
+        }
         match self.engine_handle.read_dir(&to_request_id(req), fh, offset) {
             Ok(entries) => {
-                let mut index = ::std::cmp::max(offset, 0) as usize;
-                while index < entries.len() {
-                    let (ref name, idx, ftype) = entries[index];
-                    if !reply.add(idx, index as i64 + 1, to_fuse_file_type(ftype), name) {
-                        index += 1;
-                    } else {
+                for (cookie, name, attrs) in entries {
+                    if reply.add(attrs.index, cookie, to_fuse_file_type(attrs.kind), name) {
                         break;
                     }
                 }
@@ -220,13 +457,105 @@ impl Filesystem for Fs {
         }
     }
 
-    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+    // Each entry other than "." and ".." counts as a lookup of its inode, as
+    // if the kernel had looked it up, so it's looked up in the engine
+    fn readdirplus(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        if Node::from_index(ino).is_some() {
+            for (cookie, node, name) in Node::entries(offset) {
+                // The parent of the control directory is the root
+                let attr = match (node, name) {
+                    (Node::Dir, "..") => self.engine_handle.get_root_attr().map(to_fuse_file_attr),
+                    _ => self.control_attr(node),
+                };
+                let attr = match attr {
+                    Ok(attr) => attr,
+                    Err(e) => {
+                        print_error_with_causes(&e);
+                        reply.error(EIO);
+                        return;
+                    }
+                };
+                if reply.add(attr.ino, cookie, name, &TTL, &attr, 0) {
+                    break;
+                }
+            }
+            reply.ok();
+            return;
+        }
+        let id = to_request_id(req);
+        let entries = match self.engine_handle.read_dir(&id, fh, offset) {
+            Ok(entries) => entries,
+            Err(e) => {
+                print_error_with_causes(&e);
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        for (cookie, name, attrs) in entries {
+            if name == Path::new(".") || name == Path::new("..") {
+                if reply.add(
+                    attrs.index,
+                    cookie,
+                    &name,
+                    &TTL,
+                    &to_fuse_file_attr(attrs),
+                    0,
+                ) {
+                    break;
+                }
+                continue;
+            }
+            let entry = match self.engine_handle.lookup(&id, ino, name.as_os_str()) {
+                Ok(Some(entry)) => entry,
+                // Removed since the directory was read
+                Ok(None) => continue,
+                Err(e) => {
+                    print_error_with_causes(&e);
+                    reply.error(stale_errno(&e));
+                    return;
+                }
+            };
+            let index = entry.attributes.index;
+            let attr = to_fuse_file_attr(entry.attributes);
+            if reply.add(index, cookie, &name, &TTL, &attr, entry.generation) {
+                // Left for the next request, which looks it up again
+                self.engine_handle.forget(index, 1);
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    // The contents of the control files are generated, so they are read
+    // directly, regardless of their size
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        if let Some(node) = Node::from_index(ino) {
+            if node != Node::Commit && flags & O_ACCMODE != O_RDONLY {
+                reply.error(EACCES);
+                return;
+            }
+            match self.control.open(&self.engine_handle, node) {
+                Ok(fh) => reply.opened(fh, FOPEN_DIRECT_IO),
+                Err(e) => {
+                    print_error_with_causes(&e);
+                    reply.error(EIO);
+                }
+            }
+            return;
+        }
         match self
             .engine_handle
-            .open_file(&to_request_id(req), ino, flags)
+            .open_file(&to_request_id(req), ino, flags as u32)
         {
-            Ok(_) => {
-                reply.opened(ino, 0);
+            Ok(open_flags) => {
+                reply.opened(ino, to_fuse_open_flags(open_flags));
             }
             Err(e) => {
                 if let Some(engine_error) = e.downcast_ref::<EngineError>() {
@@ -247,39 +576,68 @@ impl Filesystem for Fs {
     fn read(
         &mut self,
         req: &Request,
-        _ino: u64,
+        ino: u64,
         fh: u64,
         offset: i64,
         size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        match self
-            .engine_handle
-            .read_data(&to_request_id(req), fh, offset, size)
-        {
+        if Node::from_index(ino).is_some() {
+            reply.data(self.control.read(fh, offset, size));
+            return;
+        }
+        // fuser dispatches the requests one at a time, so the reads, which
+        // may have to fetch chunks, are served on threads of their own. Reads
+        // abandoned after their caller was signalled fail with EINTR.
+        let id = to_request_id(req);
+        let engine_handle = self.engine_handle.clone();
+        spawn(move || match engine_handle.read_data(&id, fh, offset, size) {
             Ok(buffer) => {
                 reply.data(&buffer);
             }
-            Err(e) => {
-                print_error_with_causes(&e);
-                reply.error(EINVAL);
-            }
-        }
+            Err(e) => match e.downcast_ref::<EngineError>() {
+                Some(EngineError::Interrupted(_)) => {
+                    reply.error(EINTR);
+                }
+                _ => {
+                    print_error_with_causes(&e);
+                    reply.error(EINVAL);
+                }
+            },
+        });
     }
 
     fn write(
         &mut self,
         req: &Request,
-        _ino: u64,
+        ino: u64,
         fh: u64,
         offset: i64,
         data: &[u8],
-        _flags: u32,
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
+        if let Some(node) = Node::from_index(ino) {
+            if node != Node::Commit {
+                reply.error(EACCES);
+                return;
+            }
+            match self.control.commit(&self.engine_handle, data) {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(e) => {
+                    print_error_with_causes(&e);
+                    reply.error(EIO);
+                }
+            }
+            return;
+        }
         match self
             .engine_handle
-            .write_data(&to_request_id(req), fh, offset, data)
+            .write_data(&to_request_id(req), fh, offset, data.to_vec())
         {
             Ok(num_bytes_written) => {
                 reply.written(num_bytes_written);
@@ -294,17 +652,25 @@ impl Filesystem for Fs {
     fn release(
         &mut self,
         req: &Request,
-        _ino: u64,
+        ino: u64,
         fh: u64,
-        flags: u32,
-        lock_owner: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
         flush: bool,
         reply: ReplyEmpty,
     ) {
-        match self
-            .engine_handle
-            .release_file(&to_request_id(req), fh, flags, lock_owner, flush)
-        {
+        if Node::from_index(ino).is_some() {
+            self.control.release(fh);
+            reply.ok();
+            return;
+        }
+        match self.engine_handle.release_file(
+            &to_request_id(req),
+            fh,
+            flags as u32,
+            lock_owner.unwrap_or(0),
+            flush,
+        ) {
             Ok(_) => {
                 reply.ok();
             }
@@ -321,41 +687,67 @@ impl Filesystem for Fs {
         parent: u64,
         name: &OsStr,
         mode: u32,
-        flags: u32,
+        _umask: u32,
+        flags: i32,
         reply: ReplyCreate,
     ) {
+        if is_control_entry(parent, name) {
+            reply.error(EEXIST);
+            return;
+        }
         match self
             .engine_handle
-            .create_file(&to_request_id(req), parent, name, mode, flags)
+            .create_file(&to_request_id(req), parent, name, mode, flags as u32)
         {
-            Ok((ino, attr)) => {
-                let ttl = Timespec::new(1, 0);
-                reply.created(&ttl, &to_fuse_file_attr(attr), 0, ino, 0);
+            Ok((entry, open_flags)) => {
+                reply.created(
+                    &TTL,
+                    &to_fuse_file_attr(entry.attributes),
+                    entry.generation,
+                    entry.attributes.index,
+                    to_fuse_open_flags(open_flags),
+                );
             }
             Err(e) => {
                 print_error_with_causes(&e);
-                reply.error(EINVAL);
+                reply.error(create_errno(&e));
             }
         }
     }
 
-    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+    // The kernel already applied the umask to the mode
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        if is_control_entry(parent, name) {
+            reply.error(EEXIST);
+            return;
+        }
         match self
             .engine_handle
             .create_dir(&to_request_id(req), parent, name, mode)
         {
-            Ok(attr) => {
-                let ttl = Timespec::new(1, 0);
-                reply.entry(&ttl, &to_fuse_file_attr(attr), 0);
+            Ok(entry) => {
+                reply.entry(&TTL, &to_fuse_file_attr(entry.attributes), entry.generation);
             }
             Err(e) => {
                 print_error_with_causes(&e);
-                reply.error(EINVAL);
+                reply.error(create_errno(&e));
             }
         }
     }
 
     fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if is_control_entry(parent, name) {
+            reply.error(EPERM);
+            return;
+        }
         match self.engine_handle.unlink(&to_request_id(req), parent, name) {
             Ok(()) => {
                 reply.ok();
@@ -368,6 +760,10 @@ impl Filesystem for Fs {
     }
 
     fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if is_control_entry(parent, name) {
+            reply.error(EPERM);
+            return;
+        }
         match self
             .engine_handle
             .remove_dir(&to_request_id(req), parent, name)
@@ -389,8 +785,19 @@ impl Filesystem for Fs {
         name: &OsStr,
         new_parent: u64,
         new_name: &OsStr,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
+        // Renames which don't replace or which exchange their targets are not
+        // supported
+        if flags != 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        if is_control_entry(parent, name) || is_control_entry(new_parent, new_name) {
+            reply.error(EPERM);
+            return;
+        }
         match self
             .engine_handle
             .rename(&to_request_id(req), parent, name, new_parent, new_name)
@@ -405,11 +812,224 @@ impl Filesystem for Fs {
         }
     }
 
-    /*
-    fn readlink(&mut self, _req: &Request, _ino: u64, reply: ReplyData) {}
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        match self.engine_handle.read_link(&to_request_id(req), ino) {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(e) => {
+                print_error_with_causes(&e);
+                reply.error(EINVAL);
+            }
+        }
+    }
+
+    // The size and free space are the ones of the file system holding the work
+    // dir. On macOS the block size is shown as the I/O size, by which Finder
+    // sizes its copies and their progress, and the fragment size as the block
+    // size, in which the sizes are counted.
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let stats = match self.kernel_options.stats_dir {
+            Some(ref dir) => match statvfs(dir.as_path()) {
+                Ok(stats) => stats,
+                Err(e) => {
+                    warn!("Could not read the size of {:?}: {}", dir, e);
+                    reply.error(EIO);
+                    return;
+                }
+            },
+            None => {
+                reply.statfs(0, 0, 0, 0, 0, BLOCK_SIZE, NAME_MAX, BLOCK_SIZE);
+                return;
+            }
+        };
+        #[cfg(target_os = "macos")]
+        let bsize = self.kernel_options.max_write;
+        #[cfg(not(target_os = "macos"))]
+        let bsize = stats.block_size() as u32;
+        reply.statfs(
+            stats.blocks() as u64,
+            stats.blocks_free() as u64,
+            stats.blocks_available() as u64,
+            stats.files() as u64,
+            stats.files_free() as u64,
+            bsize,
+            NAME_MAX,
+            stats.fragment_size() as u32,
+        );
+    }
+
+    // Space is only taken when the data is committed, so pre-allocating a file
+    // only extends it. Punching holes isn't supported.
+    fn fallocate(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        if Node::from_index(ino).is_some() || mode & !FALLOC_FL_KEEP_SIZE != 0 {
+            reply.error(EOPNOTSUPP);
+            return;
+        }
+        if offset < 0 || length <= 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        if mode & FALLOC_FL_KEEP_SIZE != 0 {
+            reply.ok();
+            return;
+        }
+        let id = to_request_id(req);
+        let end = (offset + length) as u64;
+        let result = self.engine_handle.get_attr(&id, ino).and_then(|attrs| {
+            if end > attrs.size {
+                let changes = FileAttributeChanges::new(
+                    None,
+                    None,
+                    None,
+                    Some(end),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                );
+                self.engine_handle.set_attr(&id, ino, changes).map(|_| ())
+            } else {
+                Ok(())
+            }
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                print_error_with_causes(&e);
+                reply.error(setattr_errno(&e));
+            }
+        }
+    }
+
+    // The previous versions and the digests of the files are exposed as
+    // extended attributes which are not listed, so they aren't copied along
+    // with the files. The other attributes, i.e. the POSIX ACLs, are kept by
+    // the engine.
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        if Node::from_index(ino).is_some() {
+            reply.error(ENOATTR);
+            return;
+        }
+        let id = to_request_id(req);
+        let value = if name == OsStr::new(PREV_ROOT_XATTR) {
+            self.engine_handle
+                .previous_version(&id, ino)
+                .map(|version| version.map(|(root_hash, _)| root_hash.to_string().into_bytes()))
+        } else if name == OsStr::new(SHA_XATTR) {
+            self.engine_handle
+                .file_digest(&id, ino)
+                .map(|digest| digest.map(|digest| digest.to_string().into_bytes()))
+        } else if name == OsStr::new(PREV_XATTR) {
+            match self.engine_handle.previous_version(&id, ino) {
+                Ok(Some((_, attrs))) if attrs.size > XATTR_SIZE_MAX => {
+                    reply.error(E2BIG);
+                    return;
+                }
+                Ok(Some(_)) => self.engine_handle.read_previous_version(&id, ino),
+                Ok(None) => Ok(None),
+                Err(e) => Err(e),
+            }
+        } else {
+            self.engine_handle.get_xattr(&id, ino, name)
+        };
+        match value {
+            Ok(Some(value)) => {
+                if size == 0 {
+                    reply.size(value.len() as u32);
+                } else if value.len() > size as usize {
+                    reply.error(ERANGE);
+                } else {
+                    reply.data(&value);
+                }
+            }
+            Ok(None) => reply.error(ENOATTR),
+            Err(e) => {
+                print_error_with_causes(&e);
+                reply.error(EINVAL);
+            }
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        if Node::from_index(ino).is_some() {
+            reply.size(0);
+            return;
+        }
+        match self.engine_handle.list_xattrs(&to_request_id(req), ino) {
+            Ok(names) => {
+                let mut list = Vec::new();
+                for name in &names {
+                    list.extend_from_slice(name.as_bytes());
+                    list.push(0);
+                }
+                if size == 0 {
+                    reply.size(list.len() as u32);
+                } else if list.len() > size as usize {
+                    reply.error(ERANGE);
+                } else {
+                    reply.data(&list);
+                }
+            }
+            Err(e) => {
+                print_error_with_causes(&e);
+                reply.error(stale_errno(&e));
+            }
+        }
+    }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {}
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        if Node::from_index(ino).is_some() {
+            reply.error(ENOTSUP);
+            return;
+        }
+        match self
+            .engine_handle
+            .set_xattr(&to_request_id(req), ino, name, value, flags as u32)
+        {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                print_error_with_causes(&e);
+                reply.error(xattr_errno(&e));
+            }
+        }
+    }
 
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        if Node::from_index(ino).is_some() {
+            reply.error(ENOTSUP);
+            return;
+        }
+        match self
+            .engine_handle
+            .remove_xattr(&to_request_id(req), ino, name)
+        {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                print_error_with_causes(&e);
+                reply.error(xattr_errno(&e));
+            }
+        }
+    }
+
+    /*
     fn mknod(&mut self,
              _req: &Request,
              _parent: u64,
@@ -439,30 +1059,10 @@ impl Filesystem for Fs {
     /*
     // Other callbacks
 
-    fn init(&mut self, _req: &Request) -> Result<(), c_int> { Ok(()) }
     fn destroy(&mut self, _req: &Request) { }
 
     fn access(&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {}
 
-    fn getxattr(&mut self,
-                _req: &Request,
-                _ino: u64,
-                _name: &OsStr,
-                _size: u32,
-                reply: ReplyXattr) {
-    }
-    fn listxattr(&mut self, _req: &Request, _ino: u64, _size: u32, reply: ReplyXattr) {}
-    fn setxattr(&mut self,
-                _req: &Request,
-                _ino: u64,
-                _name: &OsStr,
-                _value: &[u8],
-                _flags: u32,
-                _position: u32,
-                reply: ReplyEmpty) {
-    }
-    fn removexattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {}
-
     fn getlk(&mut self,
              _req: &Request,
              _ino: u64,
@@ -487,8 +1087,6 @@ impl Filesystem for Fs {
              reply: ReplyEmpty) {
     }
 
-    fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {}
-
     fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {}
     fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {}
     fn fsyncdir(&mut self,
@@ -519,20 +1117,66 @@ fn to_fuse_file_attr(fattr: FileAttributes) -> FileAttr {
         ino: fattr.index,
         size: fattr.size,
         blocks: fattr.blocks,
-        atime: fattr.atime,
-        mtime: fattr.mtime,
-        ctime: fattr.ctime,
-        crtime: fattr.crtime,
+        atime: to_system_time(fattr.atime),
+        mtime: to_system_time(fattr.mtime),
+        ctime: to_system_time(fattr.ctime),
+        crtime: to_system_time(fattr.crtime),
         kind: to_fuse_file_type(fattr.kind),
         perm: fattr.perm,
         nlink: fattr.nlink,
         uid: fattr.uid,
         gid: fattr.gid,
         rdev: fattr.rdev,
+        blksize: BLOCK_SIZE,
         flags: fattr.flags,
+        padding: 0,
+    }
+}
+
+// The times of the engine are kept as `Timespec`s, including the ones before
+// the epoch
+fn to_system_time(time: Timespec) -> SystemTime {
+    let nsec = Duration::from_nanos(time.nsec as u64);
+    if time.sec >= 0 {
+        UNIX_EPOCH + Duration::from_secs(time.sec as u64) + nsec
+    } else {
+        UNIX_EPOCH - Duration::from_secs((-time.sec) as u64) + nsec
+    }
+}
+
+fn from_system_time(time: SystemTime) -> Timespec {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => Timespec::new(since.as_secs() as i64, since.subsec_nanos() as i32),
+        Err(e) => {
+            let before = e.duration();
+            let mut time = Timespec::new(-(before.as_secs() as i64), 0);
+            if before.subsec_nanos() > 0 {
+                time.sec -= 1;
+                time.nsec = 1_000_000_000 - before.subsec_nanos() as i32;
+            }
+            time
+        }
+    }
+}
+
+fn from_time_or_now(time: TimeOrNow) -> Timespec {
+    match time {
+        TimeOrNow::SpecificTime(time) => from_system_time(time),
+        TimeOrNow::Now => from_system_time(SystemTime::now()),
     }
 }
 
+fn to_fuse_open_flags(flags: OpenFlags) -> u32 {
+    let mut fuse_flags = 0;
+    if flags.direct_io {
+        fuse_flags |= FOPEN_DIRECT_IO;
+    }
+    if flags.keep_cache {
+        fuse_flags |= FOPEN_KEEP_CACHE;
+    }
+    fuse_flags
+}
+
 fn to_request_id(req: &Request) -> RequestId {
     RequestId {
         unique_id: req.unique(),
@@ -541,3 +1185,66 @@ fn to_request_id(req: &Request) -> RequestId {
         pid: req.pid(),
     }
 }
+
+// Names which differ only in case from existing ones can't be created when
+// the repository is case-insensitive
+// Inodes which don't exist anymore, e.g. in the file handles of NFS clients,
+// are stale
+fn stale_errno(error: &Error) -> c_int {
+    let missing = error.iter_chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<CatalogError>(),
+            Some(CatalogError::INodeRead(..))
+        )
+    });
+    if missing {
+        ESTALE
+    } else {
+        EINVAL
+    }
+}
+
+// Owners and modes are only changed by the users allowed to by the
+// ownership policy of the engine
+fn setattr_errno(error: &Error) -> c_int {
+    let denied = error.iter_chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<EngineError>(),
+            Some(EngineError::PermissionDenied(..))
+        )
+    });
+    if denied {
+        EPERM
+    } else {
+        stale_errno(error)
+    }
+}
+
+// Only the ACLs can be set, by the users allowed to by the ownership policy
+fn xattr_errno(error: &Error) -> c_int {
+    let errno = error
+        .iter_chain()
+        .find_map(|cause| match cause.downcast_ref::<EngineError>() {
+            Some(EngineError::UnsupportedXattr(..)) => Some(ENOTSUP),
+            Some(EngineError::XattrExists(..)) => Some(EEXIST),
+            Some(EngineError::XattrMissing(..)) => Some(ENOATTR),
+            Some(EngineError::PermissionDenied(..)) => Some(EPERM),
+            Some(EngineError::Access(..)) => Some(EACCES),
+            _ => None,
+        });
+    errno.unwrap_or_else(|| stale_errno(error))
+}
+
+fn create_errno(error: &Error) -> c_int {
+    let conflict = error.iter_chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<WorkspaceError>(),
+            Some(WorkspaceError::NameConflict(..))
+        )
+    });
+    if conflict {
+        EEXIST
+    } else {
+        EINVAL
+    }
+}
@@ -0,0 +1,77 @@
+//! Interrupts of the reads whose callers were signalled
+//!
+//! fuser answers the INTERRUPT requests of the kernel itself, as not
+//! supported, after which the kernel stops sending them. The caller of a slow
+//! read then waits for it to finish, even after a Ctrl-C. Instead, the callers
+//! of the reads in flight are checked for the signals the kernel would have
+//! reported, and the reads of those with a pending signal are interrupted.
+
+use {
+    deneb_core::engine::Handle,
+    log::info,
+    std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread::{sleep, spawn},
+        time::Duration,
+    },
+};
+
+/// How often the callers of the reads in flight are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interrupts the reads whose callers have a pending signal, until dropped
+pub(crate) struct InterruptWatcher {
+    stopped: Arc<AtomicBool>,
+}
+
+impl InterruptWatcher {
+    pub(crate) fn start(handle: Handle) -> InterruptWatcher {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let watching = Arc::clone(&stopped);
+        spawn(move || {
+            while !watching.load(Ordering::SeqCst) {
+                for id in handle.reads_in_flight() {
+                    if is_signalled(id.pid) {
+                        info!("Caller {} of read {} was signalled", id.pid, id.unique_id);
+                        handle.interrupt(&id);
+                    }
+                }
+                sleep(POLL_INTERVAL);
+            }
+        });
+        InterruptWatcher { stopped }
+    }
+}
+
+impl Drop for InterruptWatcher {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Whether the process `pid` has a pending signal which it doesn't block or
+/// ignore. Fatal signals are pending as SIGKILL, which can't be either.
+#[cfg(target_os = "linux")]
+fn is_signalled(pid: u32) -> bool {
+    let status = match std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        Err(_) => return false,
+    };
+    let mask = |field: &str| {
+        status
+            .lines()
+            .find(|line| line.starts_with(field))
+            .and_then(|line| u64::from_str_radix(line[field.len()..].trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    let pending = mask("SigPnd:") | mask("ShdPnd:");
+    pending & !mask("SigBlk:") & !mask("SigIgn:") != 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_signalled(_pid: u32) -> bool {
+    false
+}
@@ -1 +1,3 @@
+mod control;
 pub mod fs;
+mod interrupt;
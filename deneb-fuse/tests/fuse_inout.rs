@@ -1,18 +1,23 @@
 use {
-    crate::common::*,
     copy_dir::copy_dir,
     deneb_core::{
-        catalog::CatalogType, engine::start_engine, errors::DenebResult, store::StoreType,
+        catalog::CatalogType,
+        engine::{start_engine, EngineOptions, WorkspaceOptions},
+        errors::DenebResult,
+        store::StoreType,
+        testing::{DirEntry, DirTree},
     },
-    deneb_fuse::fs::{Fs, Session},
+    deneb_fuse::fs::{Fs, KernelOptions, Session},
     quickcheck::{QuickCheck, StdGen},
-    std::{fs::create_dir_all, path::Path},
+    std::{
+        fs::{create_dir_all, read_dir, read_to_string, write},
+        path::Path,
+    },
     tempdir::TempDir,
 };
 
-mod common;
-
 const DEFAULT_CHUNK_SIZE: usize = 4_194_304; // 4MB default;
+const CHUNK_CACHE_SIZE: u64 = 1_073_741_824; // 1GB
 
 #[derive(Clone, Copy)]
 enum TestType {
@@ -53,12 +58,12 @@ fn make_test_dir_tree(prefix: &Path) -> DenebResult<DirTree> {
 }
 
 // Initialize a Deneb repo with the input directory
-fn init_test<'a>(
+fn init_test(
     test_type: TestType,
     input: &Path,
     prefix: &Path,
     chunk_size: usize,
-) -> DenebResult<Session<'a>> {
+) -> DenebResult<Session> {
     let mount_point = prefix.join("mount");
     create_dir_all(&mount_point)?;
     let work_dir = prefix.join("internal");
@@ -70,24 +75,32 @@ fn init_test<'a>(
             CatalogType::InMemory,
             StoreType::InMemory,
             work_dir,
-            None,
-            Some(input.to_owned()),
-            chunk_size,
-            1000,
-            0,
+            EngineOptions {
+                workspace: WorkspaceOptions {
+                    sync_dir: Some(input.to_owned()),
+                    chunk_size,
+                    chunk_cache_size: CHUNK_CACHE_SIZE,
+                    ..WorkspaceOptions::default()
+                },
+                ..EngineOptions::default()
+            },
         ),
         TestType::OnDisk => start_engine(
             CatalogType::Lmdb,
             StoreType::OnDisk,
             work_dir,
-            None,
-            Some(input.to_owned()),
-            chunk_size,
-            1000,
-            0,
+            EngineOptions {
+                workspace: WorkspaceOptions {
+                    sync_dir: Some(input.to_owned()),
+                    chunk_size,
+                    chunk_cache_size: CHUNK_CACHE_SIZE,
+                    ..WorkspaceOptions::default()
+                },
+                ..EngineOptions::default()
+            },
         ),
     }?;
-    Fs::spawn_mount(&mount_point, handle, &options)
+    Fs::spawn_mount(&mount_point, handle, &options, KernelOptions::default())
 }
 
 // Simple integration test
@@ -154,6 +167,38 @@ fn multiple_chunks_per_file_disk() {
     single_fuse_test(TestType::OnDisk, 4); // test with 4B chunk size (multiple chunks per file are needed)
 }
 
+// The control directory isn't listed, but its files can be read and written
+#[ignore]
+#[test]
+fn control_dir() -> DenebResult<()> {
+    let prefix = TempDir::new("/tmp/deneb_fuse_control_dir")?;
+    let dt = make_test_dir_tree(prefix.path())?;
+    let session = init_test(
+        TestType::InMemory,
+        dt.root.as_path(),
+        prefix.path(),
+        DEFAULT_CHUNK_SIZE,
+    )?;
+    let mount_point = prefix.path().join("mount");
+    let control = mount_point.join(".deneb");
+
+    let names = read_dir(&mount_point)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert!(!names.iter().any(|name| name == ".deneb"));
+    assert!(read_to_string(control.join("status"))?.contains("Root hash"));
+    assert!(read_to_string(control.join("stats"))?.contains("operations"));
+
+    write(mount_point.join("a.txt"), b"goodbye\n")?;
+    write(control.join("commit"), b"Say goodbye\n")?;
+    assert!(read_to_string(control.join("commit"))?.contains("Files written: 1"));
+    assert!(read_to_string(control.join("status"))?.contains("Say goodbye"));
+    assert!(write(control.join("status"), b"1\n").is_err());
+
+    session.force_unmount()?;
+    Ok(())
+}
+
 #[ignore]
 #[test]
 fn prop_inout_unchanged_mem() {
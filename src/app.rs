@@ -1,34 +1,97 @@
 use {
-    self::config::{CommandLine, ConfigFile},
-    deneb_core::{crypt::EncryptionKey, errors::DenebResult},
+    self::config::{CommandLine, ConfigFile, EffectiveConfig, Subcommand},
+    crate::logging::{LogFormat, LogOptions},
+    deneb_core::{
+        cas::{Algorithm, Digest},
+        crypt::EncryptionKey,
+        engine::{
+            apply_delta, clone_repository, create_bundle, export_delta, init_repository,
+            replicate_repository, verify_repository, CacheMode, CachePolicy, CommitHooks,
+            Difference, LiveSettings, OverloadPolicy, OwnershipPolicy, PrunePolicy, RequestLimits,
+            RetentionPolicy, Snapshot,
+        },
+        errors::DenebResult,
+        manifest::Manifest,
+        store::{
+            rebalance_store, relocate_store, set_store_layout, store_layout, upgrade_store,
+            RemoteLimits, StoreLayout,
+        },
+        ImportOptions,
+    },
     directories::ProjectDirs,
     dirs::home_dir,
     failure::err_msg,
     log::{info, LevelFilter},
     std::{
-        fs::{create_dir_all, File, OpenOptions},
+        collections::BTreeMap,
+        fs::{create_dir_all, remove_file, File, OpenOptions},
         io::{Read, Write},
         os::unix::fs::OpenOptionsExt,
         path::{Path, PathBuf},
+        time::Duration,
     },
 };
 
 mod config;
 
 const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+const DEFAULT_LOG_FORMAT: LogFormat = LogFormat::Text;
+const DEFAULT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024; // 10 MB
+const DEFAULT_LOG_ROTATION_INTERVAL: u64 = 0; // only rotate by size
+const DEFAULT_LOG_MAX_FILES: u32 = 5;
 const DEFAULT_CHUNK_SIZE: usize = 4_194_304;
+const MIN_CHUNK_SIZE: usize = 4096;
+const MAX_CHUNK_SIZE: usize = 1_073_741_824; // 1 GB
+const DEFAULT_CHUNK_CACHE_SIZE: u64 = 1_073_741_824; // 1 GB
 const DEFAULT_AUTO_COMMIT_INTERVAL: usize = 5; // 5 sec interval
+const DEFAULT_MAX_DIRTY_SIZE: u64 = 1_073_741_824; // 1 GB
+const DEFAULT_MAX_MEMORY: u64 = 0; // unlimited
+const DEFAULT_QUEUE_SIZE: usize = 1000;
+const DEFAULT_OVERLOAD_POLICY: OverloadPolicy = OverloadPolicy::Block;
+const DEFAULT_REQUEST_TIMEOUT: u64 = 0; // wait forever
+const DEFAULT_SLOW_REQUEST_THRESHOLD: u64 = 1000; // 1 sec
+const DEFAULT_CACHE_MODE: CacheMode = CacheMode::Auto;
+const DEFAULT_DIRECT_IO_THRESHOLD: u64 = 16 * 1024 * 1024; // 16 MB
+const DEFAULT_MAX_WRITE: u32 = 128 * 1024; // 128 KB
+const DEFAULT_MAX_READAHEAD: u32 = 1024 * 1024; // 1 MB
+const DEFAULT_WARMUP_RECENT: usize = 0;
+const DEFAULT_LOCAL_STORE_SIZE: u64 = 10 * 1_073_741_824; // 10 GB
 
 pub struct App {
     pub settings: Settings,
     pub directories: Directories,
+    pub action: Action,
+    config: EffectiveConfig,
+}
+
+/// What the application was asked to do with the instance
+pub enum Action {
+    /// Create the repository, from the contents of `sync_dir` if given
+    Init {
+        sync_dir: Option<PathBuf>,
+        encrypted: bool,
+        digest: Algorithm,
+    },
+    /// Mount the file system, which needs an initialized repository
+    Mount,
+    /// Print the settings and where they come from
+    DumpConfig,
 }
 
 impl App {
     pub fn init() -> DenebResult<App> {
+        App::with_command_line(CommandLine::read())
+    }
+
+    /// Load the settings of another instance, as if it was started without
+    /// any command line parameters
+    pub fn for_instance(instance_name: &str) -> DenebResult<App> {
+        App::with_command_line(CommandLine::for_instance(instance_name))
+    }
+
+    fn with_command_line(mut cmd_line: CommandLine) -> DenebResult<App> {
         // Read application parameters, configure directories etc.
 
-        let mut cmd_line = CommandLine::read();
         let mut directories = Directories::with_name(&cmd_line.instance_name)?;
 
         if let Some(config_dir) = cmd_line.config_dir.clone() {
@@ -38,32 +101,125 @@ impl App {
 
         let config_file_name = directories.config.join("config.toml");
         let mut cfg_file = ConfigFile::load(&config_file_name)?;
+        let file_values = cfg_file.values()?;
+        let cmd_line_values = ConfigFile::from_command_line(&cmd_line).values()?;
 
         let settings = Settings::merge(&mut cmd_line, &mut cfg_file, &mut directories);
+        settings.validate()?;
+        let config = EffectiveConfig::new(cmd_line_values, file_values, cfg_file.values()?);
+
+        let action = match cmd_line.command.take() {
+            Some(Subcommand::Init {
+                sync_dir,
+                encrypted,
+                digest,
+            }) => Action::Init {
+                sync_dir,
+                encrypted,
+                digest,
+            },
+            Some(Subcommand::Mount) | None => Action::Mount,
+            Some(Subcommand::DumpConfig) => Action::DumpConfig,
+        };
 
         // Create all dirs
         directories.ensure_created()?;
 
-        // Save new config file
-        cfg_file.save(&config_file_name)?;
-
-        // Save the encryption key
-        write_encryption_key(
-            &settings.encryption_key,
-            &settings.config_dir.join("encryption_key"),
-        )?;
+        // The configuration file is only written when asked to
+        if cmd_line.save_config {
+            config.to_save()?.save(&config_file_name)?;
+        }
 
         Ok(App {
             settings,
             directories,
+            action,
+            config,
         })
     }
 
+    /// Create the repository of the instance and its initial commit
+    ///
+    /// With `encrypted`, a new encryption key is generated and saved in the
+    /// configuration of the instance. The chunks of the repository are
+    /// addressed by `digest` digests.
+    pub fn init_repository(
+        &self,
+        sync_dir: Option<&Path>,
+        encrypted: bool,
+        digest: Algorithm,
+    ) -> DenebResult<Manifest> {
+        let encryption_key = if encrypted {
+            Some(EncryptionKey::new())
+        } else {
+            None
+        };
+        let manifest = init_repository(
+            &self.directories.workspace,
+            encryption_key.clone(),
+            self.settings.chunk_size,
+            digest,
+            sync_dir,
+            &self.settings.import,
+        )?;
+
+        // The key is only written once the repository exists, so that the
+        // key of an existing repository is never replaced
+        let key_file = self.settings.config_dir.join("encryption_key");
+        match encryption_key {
+            Some(key) => write_encryption_key(&key, &key_file)?,
+            None if key_file.exists() => remove_file(&key_file)?,
+            None => {}
+        }
+
+        Ok(manifest)
+    }
+
+    /// Read the configuration again, to change the settings of the running instance
+    ///
+    /// Only the log level and the settings returned by `Settings::live` are
+    /// applied while running. Fails if any of the settings which can't change
+    /// while the instance is running, like the chunk size, was changed.
+    pub fn reload(&self) -> DenebResult<Settings> {
+        let settings = App::init()?.settings;
+        let changed = self.settings.fixed_changes(&settings);
+        if !changed.is_empty() {
+            return Err(err_msg(format!(
+                "These settings can't be changed without a restart: {}",
+                changed.join(", ")
+            )));
+        }
+        Ok(settings)
+    }
+
+    /// The effective settings, in the format of the configuration file, each
+    /// commented with whether it was given on the command line, in the
+    /// configuration file or is a default
+    pub fn dump_config(&self) -> String {
+        format!(
+            "# Configuration file: {:?}\n{}",
+            self.settings.config_dir.join("config.toml"),
+            self.config.dump()
+        )
+    }
+
     pub fn print_settings(&self) {
-        info!("Log level: {}", self.settings.log_level);
+        info!("Log level: {}", self.settings.log.level);
+        for (module, level) in &self.settings.log.module_levels {
+            info!("Log level of {}: {}", module, level);
+        }
+        info!("Log format: {:?}", self.settings.log.format);
+        info!(
+            "Log rotation: above {} bytes, keeping {} files",
+            self.settings.log.max_size, self.settings.log.max_files
+        );
+        if let Some(max_age) = self.settings.log.max_age {
+            info!("Log rotation interval: {:?}", max_age);
+        }
         info!("Work dir: {:?}", self.directories.workspace);
         info!("Mount point: {:?}", self.directories.mount_point);
         info!("Chunk size: {:?}", self.settings.chunk_size);
+        info!("Chunk cache size: {}", self.settings.chunk_cache_size);
         info!("Force unmount: {}", self.settings.force_unmount);
         if self.settings.auto_commit_interval > 0 {
             info!(
@@ -73,6 +229,114 @@ impl App {
         } else {
             info!("Auto commit disabled");
         }
+        if self.settings.max_dirty_size > 0 {
+            info!(
+                "Max uncommitted data size: {}",
+                self.settings.max_dirty_size
+            );
+        } else {
+            info!("Uncommitted data size unlimited");
+        }
+        if self.settings.max_memory > 0 {
+            info!("Max workspace memory: {}", self.settings.max_memory);
+        } else {
+            info!("Workspace memory unlimited");
+        }
+        info!("Engine queue size: {}", self.settings.queue_size);
+        info!("Overload policy: {:?}", self.settings.overload_policy);
+        if let Some(timeout) = self.settings.request_limits.timeout {
+            info!("Request timeout: {:?}", timeout);
+        }
+        info!(
+            "Slow request threshold: {:?}",
+            self.settings.request_limits.slow_threshold
+        );
+        info!("Cache mode: {:?}", self.settings.cache_policy.mode);
+        if self.settings.cache_policy.mode == CacheMode::Auto {
+            info!(
+                "Direct IO threshold: {}",
+                self.settings.cache_policy.direct_io_threshold
+            );
+        }
+        info!("Max write size: {}", self.settings.max_write);
+        info!("Max read-ahead size: {}", self.settings.max_readahead);
+        if !self.settings.warmup_paths.is_empty() {
+            info!("Warm-up paths: {:?}", self.settings.warmup_paths);
+        }
+        if self.settings.warmup_recent > 0 {
+            info!("Warm-up recent files: {}", self.settings.warmup_recent);
+        }
+        if let Some(max_entries) = self.settings.retention.max_entries {
+            info!("Reflog entries kept: {}", max_entries);
+        }
+        if let Some(max_age) = self.settings.retention.max_age {
+            info!("Reflog entries kept for: {} sec", max_age);
+        }
+        if let Some(ref remote_store) = self.settings.remote_store {
+            info!("Remote store: {:?}", remote_store);
+            info!("Local store size: {}", self.settings.local_store_size);
+        }
+        if let Some(ref bundle) = self.settings.bundle {
+            info!("Bundle (read-only): {:?}", bundle);
+        }
+        if let Some(ref remote_gateway) = self.settings.remote_gateway {
+            info!("Remote gateway: {}", remote_gateway);
+            info!("Local store size: {}", self.settings.local_store_size);
+        }
+        if self.settings.remote_store.is_some() || self.settings.remote_gateway.is_some() {
+            info!("Remote transfer limits: {:?}", self.settings.remote_limits);
+        }
+        if self.settings.encryption_key.is_some() {
+            info!("Encrypted repository");
+        }
+        if self.settings.verify_reads {
+            info!("Verifying chunks on read");
+        }
+        if self.settings.case_insensitive {
+            info!("Case-insensitive lookups");
+        }
+        if let Some(retention) = self.settings.trash_retention {
+            info!("Removed entries kept in the trash for: {} sec", retention);
+        }
+        if let Some(dehydrate_after) = self.settings.dehydrate_after {
+            info!("Files dehydrated after: {} sec", dehydrate_after);
+        }
+        if self.settings.checkpoint_on_stop {
+            info!("Uncommitted changes checkpointed on stop");
+        }
+        if let Some(ref script) = self.settings.hooks.pre_commit {
+            info!("Pre-commit hook: {:?}", script);
+        }
+        if let Some(ref script) = self.settings.hooks.post_commit {
+            info!("Post-commit hook: {:?}", script);
+        }
+        if self.settings.ownership.restrict_chown {
+            info!("Only root changes the owners of files");
+        }
+        if let Some(uid) = self.settings.ownership.force_uid {
+            info!("Files owned by uid: {}", uid);
+        }
+        if let Some(gid) = self.settings.ownership.force_gid {
+            info!("Files owned by gid: {}", gid);
+        }
+        if self.settings.ownership.umask != 0 {
+            info!("Umask: {:04o}", self.settings.ownership.umask);
+        }
+        if self.settings.ownership.enforce_acls {
+            info!("Enforcing the POSIX ACLs of the files");
+        }
+        if !self.settings.import.ignore_patterns.is_empty() {
+            info!(
+                "Ignore patterns: {:?}",
+                self.settings.import.ignore_patterns
+            );
+        }
+        if self.settings.import.strict {
+            info!("Strict import: unsupported files are not skipped");
+        }
+        if let Some(ref gateway_address) = self.settings.gateway_address {
+            info!("Gateway address: {}", gateway_address);
+        }
     }
 
     pub fn fs_name(&self) -> String {
@@ -84,12 +348,56 @@ pub struct Settings {
     pub instance_name: String,
     pub config_dir: PathBuf,
     pub mount_point: PathBuf,
-    pub encryption_key: EncryptionKey,
-    pub log_level: LevelFilter,
+    pub encryption_key: Option<EncryptionKey>,
+    pub log: LogOptions,
     pub chunk_size: usize,
+    pub chunk_cache_size: u64,
     pub force_unmount: bool,
     pub auto_commit_interval: usize,
+    pub max_dirty_size: u64,
+    pub max_memory: u64,
+    pub queue_size: usize,
+    pub overload_policy: OverloadPolicy,
+    pub request_limits: RequestLimits,
     pub foreground: bool,
+    pub cache_policy: CachePolicy,
+    pub max_write: u32,
+    pub max_readahead: u32,
+    pub warmup_paths: Vec<PathBuf>,
+    pub warmup_recent: usize,
+    pub retention: RetentionPolicy,
+    pub remote_store: Option<PathBuf>,
+    pub bundle: Option<PathBuf>,
+    pub local_store_size: u64,
+    pub remote_gateway: Option<String>,
+    pub remote_gateway_token: Option<String>,
+    pub gateway_address: Option<String>,
+    pub gateway_token: Option<String>,
+    pub control_token: Option<String>,
+    pub control_address: Option<String>,
+    pub control_identity: Option<PathBuf>,
+    pub control_identity_password: Option<String>,
+    pub remote_limits: RemoteLimits,
+    pub verify_reads: bool,
+    pub case_insensitive: bool,
+    pub trash_retention: Option<u64>,
+    pub dehydrate_after: Option<u64>,
+    pub checkpoint_on_stop: bool,
+    pub hooks: CommitHooks,
+    pub ownership: OwnershipPolicy,
+    pub volume: VolumeOptions,
+    pub import: ImportOptions,
+}
+
+/// How the mounted file system is shown by Finder, only used on macOS
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VolumeOptions {
+    /// Icon of the volume, an `.icns` file
+    pub icon: Option<PathBuf>,
+    /// Hidden from Finder and the desktop
+    pub nobrowse: bool,
+    /// Shown as a local disk rather than a server
+    pub local: bool,
 }
 
 impl Settings {
@@ -121,34 +429,648 @@ impl Settings {
             .log_level
             .get_or_insert(*cfg_file.log_level.get_or_insert(DEFAULT_LOG_LEVEL));
 
+        let log_format = *cmd_line
+            .log_format
+            .get_or_insert(*cfg_file.log_format.get_or_insert(DEFAULT_LOG_FORMAT));
+
+        let log_max_size = *cmd_line
+            .log_max_size
+            .get_or_insert(*cfg_file.log_max_size.get_or_insert(DEFAULT_LOG_MAX_SIZE));
+
+        let log_rotation_interval = *cmd_line.log_rotation_interval.get_or_insert(
+            *cfg_file
+                .log_rotation_interval
+                .get_or_insert(DEFAULT_LOG_ROTATION_INTERVAL),
+        );
+
+        let log_max_files = *cmd_line
+            .log_max_files
+            .get_or_insert(*cfg_file.log_max_files.get_or_insert(DEFAULT_LOG_MAX_FILES));
+
+        let log_modules = cfg_file
+            .log_modules
+            .get_or_insert_with(BTreeMap::new)
+            .clone();
+
         let chunk_size = *cmd_line
             .chunk_size
             .get_or_insert(*cfg_file.chunk_size.get_or_insert(DEFAULT_CHUNK_SIZE));
 
+        let chunk_cache_size = *cmd_line.chunk_cache_size.get_or_insert(
+            *cfg_file
+                .chunk_cache_size
+                .get_or_insert(DEFAULT_CHUNK_CACHE_SIZE),
+        );
+
         let auto_commit_interval = *cmd_line.auto_commit_interval.get_or_insert(
             *cfg_file
                 .auto_commit_interval
                 .get_or_insert(DEFAULT_AUTO_COMMIT_INTERVAL),
         );
 
+        let max_dirty_size = *cmd_line.max_dirty_size.get_or_insert(
+            *cfg_file
+                .max_dirty_size
+                .get_or_insert(DEFAULT_MAX_DIRTY_SIZE),
+        );
+
+        let max_memory = *cmd_line
+            .max_memory
+            .get_or_insert(*cfg_file.max_memory.get_or_insert(DEFAULT_MAX_MEMORY));
+
+        let queue_size = *cmd_line
+            .queue_size
+            .get_or_insert(*cfg_file.queue_size.get_or_insert(DEFAULT_QUEUE_SIZE));
+
+        let overload_policy = *cmd_line.overload_policy.get_or_insert(
+            *cfg_file
+                .overload_policy
+                .get_or_insert(DEFAULT_OVERLOAD_POLICY),
+        );
+
+        let request_timeout = *cmd_line.request_timeout.get_or_insert(
+            *cfg_file
+                .request_timeout
+                .get_or_insert(DEFAULT_REQUEST_TIMEOUT),
+        );
+        let slow_request_threshold = *cmd_line.slow_request_threshold.get_or_insert(
+            *cfg_file
+                .slow_request_threshold
+                .get_or_insert(DEFAULT_SLOW_REQUEST_THRESHOLD),
+        );
+
+        let cache_mode = *cmd_line
+            .cache_mode
+            .get_or_insert(*cfg_file.cache_mode.get_or_insert(DEFAULT_CACHE_MODE));
+
+        let direct_io_threshold = *cmd_line.direct_io_threshold.get_or_insert(
+            *cfg_file
+                .direct_io_threshold
+                .get_or_insert(DEFAULT_DIRECT_IO_THRESHOLD),
+        );
+
+        let max_write = *cmd_line
+            .max_write
+            .get_or_insert(*cfg_file.max_write.get_or_insert(DEFAULT_MAX_WRITE));
+
+        let max_readahead = *cmd_line
+            .max_readahead
+            .get_or_insert(*cfg_file.max_readahead.get_or_insert(DEFAULT_MAX_READAHEAD));
+
+        let warmup_paths = if cmd_line.warmup_paths.is_empty() {
+            cfg_file.warmup_paths.get_or_insert_with(Vec::new).clone()
+        } else {
+            cmd_line.warmup_paths.clone()
+        };
+
+        let warmup_recent = *cmd_line
+            .warmup_recent
+            .get_or_insert(*cfg_file.warmup_recent.get_or_insert(DEFAULT_WARMUP_RECENT));
+
+        // Without any limits, all the reflog entries are kept
+        let reflog_max_entries = cmd_line.reflog_max_entries.or(cfg_file.reflog_max_entries);
+        let reflog_max_age = cmd_line.reflog_max_age.or(cfg_file.reflog_max_age);
+
+        let remote_store = cmd_line
+            .remote_store
+            .clone()
+            .or_else(|| cfg_file.remote_store.clone());
+        let bundle = cmd_line.bundle.clone().or_else(|| cfg_file.bundle.clone());
+
+        let local_store_size = *cmd_line.local_store_size.get_or_insert(
+            *cfg_file
+                .local_store_size
+                .get_or_insert(DEFAULT_LOCAL_STORE_SIZE),
+        );
+
+        let remote_gateway = cmd_line
+            .remote_gateway
+            .clone()
+            .or_else(|| cfg_file.remote_gateway.clone());
+        let remote_gateway_token = cmd_line
+            .remote_gateway_token
+            .clone()
+            .or_else(|| cfg_file.remote_gateway_token.clone());
+
+        let gateway_address = cmd_line
+            .gateway_address
+            .clone()
+            .or_else(|| cfg_file.gateway_address.clone());
+        let gateway_token = cmd_line
+            .gateway_token
+            .clone()
+            .or_else(|| cfg_file.gateway_token.clone());
+        let control_token = cmd_line
+            .control_token
+            .clone()
+            .or_else(|| cfg_file.control_token.clone());
+        let control_address = cmd_line
+            .control_address
+            .clone()
+            .or_else(|| cfg_file.control_address.clone());
+        let control_identity = cmd_line
+            .control_identity
+            .clone()
+            .or_else(|| cfg_file.control_identity.clone());
+        let control_identity_password = cmd_line
+            .control_identity_password
+            .clone()
+            .or_else(|| cfg_file.control_identity_password.clone());
+
+        let default_limits = RemoteLimits::default();
+        let remote_timeout = *cmd_line.remote_timeout.get_or_insert(
+            *cfg_file
+                .remote_timeout
+                .get_or_insert(default_limits.timeout.as_secs()),
+        );
+        let remote_limits = RemoteLimits {
+            download_rate: *cmd_line.remote_download_rate.get_or_insert(
+                *cfg_file
+                    .remote_download_rate
+                    .get_or_insert(default_limits.download_rate),
+            ),
+            upload_rate: *cmd_line.remote_upload_rate.get_or_insert(
+                *cfg_file
+                    .remote_upload_rate
+                    .get_or_insert(default_limits.upload_rate),
+            ),
+            timeout: Duration::from_secs(remote_timeout),
+            max_retries: *cmd_line.remote_retries.get_or_insert(
+                *cfg_file
+                    .remote_retries
+                    .get_or_insert(default_limits.max_retries),
+            ),
+            max_transfers: *cmd_line.remote_max_transfers.get_or_insert(
+                *cfg_file
+                    .remote_max_transfers
+                    .get_or_insert(default_limits.max_transfers),
+            ),
+            ..default_limits
+        };
+
+        // Verification can be enabled either on the command line or in the config file
+        let verify_reads = cmd_line.verify_reads || *cfg_file.verify_reads.get_or_insert(false);
+
+        // Patterns given on the command line replace the ones of the config file
+        let ignore_patterns = if cmd_line.ignore_patterns.is_empty() {
+            cfg_file
+                .ignore_patterns
+                .get_or_insert_with(Vec::new)
+                .clone()
+        } else {
+            cmd_line.ignore_patterns.clone()
+        };
+        let strict_import = cmd_line.strict_import || *cfg_file.strict_import.get_or_insert(false);
+
+        let case_insensitive =
+            cmd_line.case_insensitive || *cfg_file.case_insensitive.get_or_insert(false);
+        let trash_retention = cmd_line.trash_retention.or(cfg_file.trash_retention);
+        let dehydrate_after = cmd_line.dehydrate_after.or(cfg_file.dehydrate_after);
+        let checkpoint_on_stop =
+            cmd_line.checkpoint_on_stop || *cfg_file.checkpoint_on_stop.get_or_insert(false);
+        let hooks = CommitHooks {
+            pre_commit: cmd_line
+                .pre_commit_hook
+                .clone()
+                .or_else(|| cfg_file.pre_commit_hook.clone()),
+            post_commit: cmd_line
+                .post_commit_hook
+                .clone()
+                .or_else(|| cfg_file.post_commit_hook.clone()),
+        };
+        let ownership = OwnershipPolicy {
+            restrict_chown: cmd_line.restrict_chown
+                || *cfg_file.restrict_chown.get_or_insert(false),
+            force_uid: cmd_line.force_uid.or(cfg_file.force_uid),
+            force_gid: cmd_line.force_gid.or(cfg_file.force_gid),
+            umask: cmd_line.umask.or(cfg_file.umask).unwrap_or(0),
+            enforce_acls: cmd_line.enforce_acls || *cfg_file.enforce_acls.get_or_insert(false),
+        };
+
+        let volume = VolumeOptions {
+            icon: cmd_line
+                .volume_icon
+                .clone()
+                .or_else(|| cfg_file.volume_icon.clone()),
+            nobrowse: cmd_line.nobrowse || *cfg_file.nobrowse.get_or_insert(false),
+            local: cmd_line.local_volume || *cfg_file.local_volume.get_or_insert(false),
+        };
+
         let force_unmount = cmd_line.force_unmount;
         let foreground = cmd_line.foreground;
 
-        let encryption_key = read_encryption_key(&config_dir.join("encryption_key"))
-            .unwrap_or_else(|_| EncryptionKey::new());
+        // Instances without a key hold unencrypted repositories
+        let encryption_key = read_encryption_key(&config_dir.join("encryption_key")).ok();
 
         Settings {
             instance_name,
             config_dir,
             mount_point,
             encryption_key,
-            log_level,
+            log: LogOptions {
+                level: log_level,
+                module_levels: log_modules,
+                format: log_format,
+                max_size: log_max_size,
+                max_age: match log_rotation_interval {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                },
+                max_files: log_max_files,
+            },
             chunk_size,
+            chunk_cache_size,
             force_unmount,
             auto_commit_interval,
+            max_dirty_size,
+            max_memory,
+            queue_size,
+            overload_policy,
+            request_limits: RequestLimits {
+                timeout: if request_timeout > 0 {
+                    Some(Duration::from_secs(request_timeout))
+                } else {
+                    None
+                },
+                slow_threshold: Duration::from_millis(slow_request_threshold),
+            },
             foreground,
+            cache_policy: CachePolicy::new(cache_mode, direct_io_threshold),
+            max_write,
+            max_readahead,
+            warmup_paths,
+            warmup_recent,
+            retention: RetentionPolicy::new(reflog_max_entries, reflog_max_age),
+            remote_store,
+            bundle,
+            local_store_size,
+            remote_gateway,
+            remote_gateway_token,
+            gateway_address,
+            gateway_token,
+            control_token,
+            control_address,
+            control_identity,
+            control_identity_password,
+            remote_limits,
+            verify_reads,
+            case_insensitive,
+            trash_retention,
+            dehydrate_after,
+            checkpoint_on_stop,
+            hooks,
+            ownership,
+            volume,
+            import: ImportOptions {
+                ignore_patterns,
+                strict: strict_import,
+            },
+        }
+    }
+
+    /// The settings which can be changed while the engine is running
+    pub fn live(&self) -> LiveSettings {
+        LiveSettings {
+            auto_commit_interval: self.auto_commit_interval,
+            max_dirty_size: self.max_dirty_size,
+            max_memory: self.max_memory,
+            chunk_cache_size: self.chunk_cache_size,
+            remote_limits: self.remote_limits,
+        }
+    }
+
+    /// Check the merged settings, reporting all the invalid values and the
+    /// options which can't be used together at once
+    fn validate(&self) -> DenebResult<()> {
+        let mut problems = Vec::new();
+        if self.chunk_size < MIN_CHUNK_SIZE || self.chunk_size > MAX_CHUNK_SIZE {
+            problems.push(format!(
+                "chunk_size is {}, it must be between {} and {} bytes",
+                self.chunk_size, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+            ));
+        }
+        if self.queue_size == 0 {
+            problems.push("queue_size must be at least 1".to_owned());
+        }
+        if self.max_write == 0 {
+            problems.push("max_write must be at least 1 byte".to_owned());
+        }
+        if self.ownership.umask > 0o7777 {
+            problems.push(format!(
+                "umask is {:o}, it must be an octal number up to 7777",
+                self.ownership.umask
+            ));
+        }
+        if self.remote_store.is_some() && self.remote_gateway.is_some() {
+            problems.push(
+                "remote_store and remote_gateway can't be used together, choose one remote store"
+                    .to_owned(),
+            );
+        }
+        if self.bundle.is_some() && (self.remote_store.is_some() || self.remote_gateway.is_some()) {
+            problems.push(
+                "bundle can't be used with a remote store, the bundle holds all the chunks"
+                    .to_owned(),
+            );
+        }
+        if self.remote_gateway_token.is_some() && self.remote_gateway.is_none() {
+            problems.push("remote_gateway_token is set without a remote_gateway".to_owned());
+        }
+        if self.gateway_token.is_some() && self.gateway_address.is_none() {
+            problems.push("gateway_token is set without a gateway_address".to_owned());
+        }
+        if self.control_address.is_some() {
+            if self.control_identity.is_none() {
+                problems.push("control_address needs a control_identity".to_owned());
+            }
+            if self.control_token.is_none() {
+                problems.push("control_address needs a control_token".to_owned());
+            }
+        }
+        if self.control_identity_password.is_some() && self.control_identity.is_none() {
+            problems.push("control_identity_password is set without a control_identity".to_owned());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(err_msg(format!(
+                "Invalid configuration, set on the command line or in {:?}:\n  {}",
+                self.config_dir.join("config.toml"),
+                problems.join("\n  ")
+            )))
         }
     }
+
+    // Names of the settings which differ in `new` and are only applied at startup
+    fn fixed_changes(&self, new: &Settings) -> Vec<&'static str> {
+        let encrypted = |settings: &Settings| settings.encryption_key.is_some();
+        [
+            ("mount_point", self.mount_point != new.mount_point),
+            ("encryption_key", encrypted(self) != encrypted(new)),
+            ("chunk_size", self.chunk_size != new.chunk_size),
+            ("queue_size", self.queue_size != new.queue_size),
+            (
+                "overload_policy",
+                self.overload_policy != new.overload_policy,
+            ),
+            ("remote_store", self.remote_store != new.remote_store),
+            ("bundle", self.bundle != new.bundle),
+            ("remote_gateway", self.remote_gateway != new.remote_gateway),
+            (
+                "local_store_size",
+                self.local_store_size != new.local_store_size,
+            ),
+            (
+                "gateway_address",
+                self.gateway_address != new.gateway_address,
+            ),
+            (
+                "case_insensitive",
+                self.case_insensitive != new.case_insensitive,
+            ),
+            (
+                "trash_retention",
+                self.trash_retention != new.trash_retention,
+            ),
+            (
+                "dehydrate_after",
+                self.dehydrate_after != new.dehydrate_after,
+            ),
+            (
+                "checkpoint_on_stop",
+                self.checkpoint_on_stop != new.checkpoint_on_stop,
+            ),
+            ("control_token", self.control_token != new.control_token),
+            (
+                "control_address",
+                self.control_address != new.control_address
+                    || self.control_identity != new.control_identity
+                    || self.control_identity_password != new.control_identity_password,
+            ),
+            ("commit_hooks", self.hooks != new.hooks),
+            ("ownership", self.ownership != new.ownership),
+            ("volume", self.volume != new.volume),
+        ]
+        .iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| *name)
+        .collect()
+    }
+}
+
+/// Create a new instance from the repository found in the work dir of another
+/// instance
+///
+/// The encryption key of the other instance is installed in the configuration
+/// of the new instance. After a thin clone, the new instance is configured to
+/// fetch the file contents from the other instance as they are read. With a
+/// `depth`, only the most recent `depth` commits are copied.
+pub fn clone_instance(
+    instance_name: &str,
+    remote_dir: &Path,
+    key_file: &Path,
+    thin: bool,
+    depth: Option<usize>,
+) -> DenebResult<usize> {
+    let directories = Directories::with_name(instance_name)?;
+    directories.ensure_created()?;
+
+    let config_file_name = directories.config.join("config.toml");
+    let mut cfg_file = ConfigFile::load(&config_file_name)?;
+    let chunk_size = *cfg_file.chunk_size.get_or_insert(DEFAULT_CHUNK_SIZE);
+
+    let encryption_key = read_encryption_key(key_file)?;
+    let copied = clone_repository(
+        remote_dir,
+        &directories.workspace,
+        Some(encryption_key.clone()),
+        chunk_size,
+        thin,
+        depth,
+    )?;
+
+    write_encryption_key(&encryption_key, &directories.config.join("encryption_key"))?;
+    if thin {
+        cfg_file.remote_store = Some(remote_dir.to_owned());
+    }
+    cfg_file.save(&config_file_name)?;
+
+    Ok(copied)
+}
+
+/// Copy the committed state of an instance into `dest_dir`, for backups
+///
+/// The packed chunks are copied as they are, so the encryption key of the
+/// instance is not used. Returns the number of chunks copied.
+pub fn replicate_instance(instance_name: &str, dest_dir: &Path) -> DenebResult<usize> {
+    let directories = Directories::with_name(instance_name)?;
+
+    let cfg_file = ConfigFile::load(&directories.config.join("config.toml"))?;
+    let chunk_size = cfg_file.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+
+    replicate_repository(&directories.workspace, dest_dir, chunk_size)
+}
+
+/// Write the chunks needed to go from the commit `from` of an instance to the
+/// commit `to` into the file `out`
+///
+/// Returns the number of chunks written.
+pub fn export_instance_delta(
+    instance_name: &str,
+    from: &Digest,
+    to: &Digest,
+    out: &Path,
+) -> DenebResult<usize> {
+    let directories = Directories::with_name(instance_name)?;
+
+    let cfg_file = ConfigFile::load(&directories.config.join("config.toml"))?;
+    let chunk_size = cfg_file.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+
+    let encryption_key = read_encryption_key(&directories.config.join("encryption_key")).ok();
+    export_delta(
+        &directories.workspace,
+        encryption_key,
+        chunk_size,
+        from,
+        to,
+        out,
+    )
+}
+
+/// Write the current commit of the repository of an instance into a bundle,
+/// which can be mounted read-only on another host
+///
+/// Returns the number of chunks written.
+pub fn create_instance_bundle(instance_name: &str, out: &Path) -> DenebResult<usize> {
+    let directories = Directories::with_name(instance_name)?;
+
+    let cfg_file = ConfigFile::load(&directories.config.join("config.toml"))?;
+    let chunk_size = cfg_file.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+
+    let encryption_key = read_encryption_key(&directories.config.join("encryption_key")).ok();
+    create_bundle(&directories.workspace, encryption_key, chunk_size, out)
+}
+
+/// The token which the commands sent to an instance need to present, if one
+/// is set in its configuration file
+pub fn instance_control_token(instance_name: &str) -> DenebResult<Option<String>> {
+    let directories = Directories::with_name(instance_name)?;
+    let cfg_file = ConfigFile::load(&directories.config.join("config.toml"))?;
+    Ok(cfg_file.control_token)
+}
+
+/// The policy with which `deneb-cli prune` prunes the reflog of an instance,
+/// as set in its configuration file
+pub fn instance_prune_policy(instance_name: &str) -> DenebResult<PrunePolicy> {
+    let directories = Directories::with_name(instance_name)?;
+    let cfg_file = ConfigFile::load(&directories.config.join("config.toml"))?;
+    Ok(PrunePolicy::new(
+        cfg_file.prune_keep_last,
+        cfg_file.prune_keep_daily,
+        cfg_file.prune_keep_weekly,
+    ))
+}
+
+/// Apply a delta to the repository in `dest_dir`, e.g. a backup made with
+/// `replicate_instance`, or to the repository of an instance
+///
+/// The instance must not be running. Returns the number of chunks stored.
+pub fn apply_instance_delta(
+    instance_name: &str,
+    delta: &Path,
+    dest_dir: Option<&Path>,
+) -> DenebResult<usize> {
+    let directories = Directories::with_name(instance_name)?;
+
+    let cfg_file = ConfigFile::load(&directories.config.join("config.toml"))?;
+    let chunk_size = cfg_file.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+
+    apply_delta(
+        dest_dir.unwrap_or(&directories.workspace),
+        delta,
+        chunk_size,
+    )
+}
+
+/// Upgrade the store of an instance to the current format
+///
+/// The instance must not be running. Returns the format version of the store
+/// before the upgrade and the current format version.
+pub fn upgrade_instance_store(instance_name: &str) -> DenebResult<(u32, u32)> {
+    let directories = Directories::with_name(instance_name)?;
+    upgrade_store(&directories.workspace)
+}
+
+/// Move the store of an instance to `new_dir`, e.g. on a larger disk
+///
+/// The instance must not be running. Its configuration needs no changes, the
+/// work dir keeps a link to the new location. Returns the number of chunks
+/// moved.
+pub fn relocate_instance_store(instance_name: &str, new_dir: &Path) -> DenebResult<usize> {
+    let directories = Directories::with_name(instance_name)?;
+    relocate_store(&directories.workspace, new_dir)
+}
+
+/// Returns the data areas of the store of an instance besides its main one
+pub fn instance_store_layout(instance_name: &str) -> DenebResult<StoreLayout> {
+    let directories = Directories::with_name(instance_name)?;
+    store_layout(&directories.workspace)
+}
+
+/// Change the data areas of the store of an instance
+///
+/// The instance must not be running. Returns the number of chunks moved out
+/// of the removed areas.
+pub fn set_instance_store_layout(instance_name: &str, layout: &StoreLayout) -> DenebResult<usize> {
+    let directories = Directories::with_name(instance_name)?;
+    set_store_layout(&directories.workspace, layout)
+}
+
+/// Move the chunks of the store of an instance to the data areas picked by
+/// its placement policy
+///
+/// The instance must not be running. Returns the number of chunks moved.
+pub fn rebalance_instance_store(instance_name: &str) -> DenebResult<usize> {
+    let directories = Directories::with_name(instance_name)?;
+    rebalance_store(&directories.workspace)
+}
+
+/// Compare `dir` with the committed contents of an instance, without mounting it
+///
+/// The chunk size, encryption key and ignore patterns are taken from the
+/// configuration of the instance.
+pub fn verify_import(instance_name: &str, dir: &Path) -> DenebResult<Vec<Difference>> {
+    let directories = Directories::with_name(instance_name)?;
+
+    let cfg_file = ConfigFile::load(&directories.config.join("config.toml"))?;
+    let chunk_size = cfg_file.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    let import = ImportOptions {
+        ignore_patterns: cfg_file.ignore_patterns.unwrap_or_default(),
+        strict: false,
+    };
+
+    let encryption_key = read_encryption_key(&directories.config.join("encryption_key")).ok();
+    verify_repository(
+        &directories.workspace,
+        encryption_key,
+        chunk_size,
+        dir,
+        &import,
+    )
+}
+
+/// Open the committed state of an instance for browsing, without mounting it
+///
+/// The instance may be running: nothing of its repository is written.
+pub fn open_snapshot(instance_name: &str) -> DenebResult<Snapshot> {
+    let directories = Directories::with_name(instance_name)?;
+
+    let cfg_file = ConfigFile::load(&directories.config.join("config.toml"))?;
+    let chunk_size = cfg_file.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+
+    let encryption_key = read_encryption_key(&directories.config.join("encryption_key")).ok();
+    Snapshot::open(&directories.workspace, encryption_key, chunk_size)
 }
 
 #[derive(Debug)]
@@ -1,14 +1,22 @@
 use {
-    deneb_core::errors::{DenebError, DenebResult},
+    crate::logging::LogFormat,
+    deneb_core::{
+        cas::Algorithm,
+        engine::{CacheMode, OverloadPolicy},
+        errors::{DenebError, DenebResult},
+    },
+    failure::err_msg,
     log::LevelFilter,
     serde::{Deserialize, Serialize},
     std::{
+        collections::BTreeMap,
+        fmt::Write as _,
         fs::{File, OpenOptions},
         io::{Read, Write},
         path::{Path, PathBuf},
     },
     structopt::StructOpt,
-    toml,
+    toml::{self, value::Table, Value},
 };
 
 #[derive(Debug, StructOpt)]
@@ -42,8 +50,30 @@ pub(super) struct CommandLine {
         help = "Logging level (off|error|warn|info|debug|trace)"
     )]
     pub log_level: Option<LevelFilter>,
-    #[structopt(long = "chunk_size", help = "Default chunk size for storing files")]
+    #[structopt(long = "log_format", help = "Format of the log file (text|json)")]
+    pub log_format: Option<LogFormat>,
+    #[structopt(
+        long = "log_max_size",
+        help = "Size in bytes above which the log file is rotated"
+    )]
+    pub log_max_size: Option<u64>,
+    #[structopt(
+        long = "log_rotation_interval",
+        help = "Seconds after which the log file is rotated (0 means only by size)"
+    )]
+    pub log_rotation_interval: Option<u64>,
+    #[structopt(
+        long = "log_max_files",
+        help = "Number of rotated log files which are kept"
+    )]
+    pub log_max_files: Option<u32>,
+    #[structopt(long = "chunk_size", help = "Chunk size for storing the files of new repositories")]
     pub chunk_size: Option<usize>,
+    #[structopt(
+        long = "chunk_cache_size",
+        help = "Maximum size in bytes of the unpacked chunk cache"
+    )]
+    pub chunk_cache_size: Option<u64>,
     #[structopt(
         short = "f",
         long = "force_unmount",
@@ -55,42 +85,467 @@ pub(super) struct CommandLine {
         help = "Auto commit interval in seconds (0 means disabled)"
     )]
     pub auto_commit_interval: Option<usize>,
+    #[structopt(
+        long = "max_dirty_size",
+        help = "Size in bytes of uncommitted data which triggers a commit (0 means unlimited)"
+    )]
+    pub max_dirty_size: Option<u64>,
+    #[structopt(
+        long = "max_memory",
+        help = "Memory in bytes held by the workspace which triggers a commit (0 means unlimited)"
+    )]
+    pub max_memory: Option<u64>,
+    #[structopt(
+        long = "queue_size",
+        help = "Number of requests each engine request queue can hold"
+    )]
+    pub queue_size: Option<usize>,
+    #[structopt(
+        long = "overload_policy",
+        help = "Handling of requests sent to a full engine queue (block|shed)"
+    )]
+    pub overload_policy: Option<OverloadPolicy>,
+    #[structopt(
+        long = "request_timeout",
+        help = "Seconds after which unanswered file system requests fail (0 means never)"
+    )]
+    pub request_timeout: Option<u64>,
+    #[structopt(
+        long = "slow_request_threshold",
+        help = "Duration in milliseconds above which file system operations are logged as slow"
+    )]
+    pub slow_request_threshold: Option<u64>,
     #[structopt(long = "foreground", help = "Stay in the foreground, don't fork")]
     pub foreground: bool,
+    #[structopt(
+        long = "cache_mode",
+        help = "Kernel page cache usage for files (auto|kernel|direct|uncached)"
+    )]
+    pub cache_mode: Option<CacheMode>,
+    #[structopt(
+        long = "direct_io_threshold",
+        help = "Minimum file size using direct IO, when the cache mode is \"auto\""
+    )]
+    pub direct_io_threshold: Option<u64>,
+    #[structopt(
+        long = "max_write",
+        help = "Maximum size in bytes of a single write request"
+    )]
+    pub max_write: Option<u32>,
+    #[structopt(
+        long = "max_readahead",
+        help = "Maximum size in bytes of kernel read-ahead requests"
+    )]
+    pub max_readahead: Option<u32>,
+    #[structopt(
+        long = "warmup_path",
+        parse(from_os_str),
+        raw(number_of_values = "1"),
+        help = "Path, relative to the mount point, to prefetch after mounting (can be repeated)"
+    )]
+    pub warmup_paths: Vec<PathBuf>,
+    #[structopt(
+        long = "warmup_recent",
+        help = "Number of files used before the last shutdown to prefetch after mounting"
+    )]
+    pub warmup_recent: Option<usize>,
+    #[structopt(
+        long = "reflog_max_entries",
+        help = "Number of most recent commits which are always kept in the reflog"
+    )]
+    pub reflog_max_entries: Option<usize>,
+    #[structopt(
+        long = "reflog_max_age",
+        help = "Age in seconds until which commits are kept in the reflog"
+    )]
+    pub reflog_max_age: Option<u64>,
+    #[structopt(
+        long = "remote_store",
+        parse(from_os_str),
+        help = "Work dir of another Deneb instance, from which chunks are fetched on demand"
+    )]
+    pub remote_store: Option<PathBuf>,
+    #[structopt(
+        long = "bundle",
+        parse(from_os_str),
+        help = "Bundle written by \"deneb-cli bundle create\", which is mounted read-only"
+    )]
+    pub bundle: Option<PathBuf>,
+    #[structopt(
+        long = "local_store_size",
+        help = "Maximum size in bytes of the chunks copied locally from the remote store"
+    )]
+    pub local_store_size: Option<u64>,
+    #[structopt(
+        long = "remote_gateway",
+        help = "Address of the gateway of another Deneb instance, used as remote store"
+    )]
+    pub remote_gateway: Option<String>,
+    #[structopt(
+        long = "remote_gateway_token",
+        help = "Token presented to the gateway of the remote store"
+    )]
+    pub remote_gateway_token: Option<String>,
+    #[structopt(
+        long = "gateway_address",
+        help = "Address on which the chunks of the repository are served over HTTP"
+    )]
+    pub gateway_address: Option<String>,
+    #[structopt(
+        long = "gateway_token",
+        help = "Token which clients of the gateway need to present"
+    )]
+    pub gateway_token: Option<String>,
+    #[structopt(
+        long = "control_token",
+        help = "Token which the commands sent to the command socket need to present"
+    )]
+    pub control_token: Option<String>,
+    #[structopt(
+        long = "control_address",
+        help = "Address on which commands are also accepted from other hosts, over TLS"
+    )]
+    pub control_address: Option<String>,
+    #[structopt(
+        long = "control_identity",
+        parse(from_os_str),
+        help = "PKCS #12 archive with the certificate and key of the remote command listener"
+    )]
+    pub control_identity: Option<PathBuf>,
+    #[structopt(
+        long = "control_identity_password",
+        help = "Password of the control identity archive"
+    )]
+    pub control_identity_password: Option<String>,
+    #[structopt(
+        long = "remote_download_rate",
+        help = "Maximum rate in bytes/s of the downloads from the remote store (0 means unlimited)"
+    )]
+    pub remote_download_rate: Option<u64>,
+    #[structopt(
+        long = "remote_upload_rate",
+        help = "Maximum rate in bytes/s of the uploads to the remote store (0 means unlimited)"
+    )]
+    pub remote_upload_rate: Option<u64>,
+    #[structopt(
+        long = "remote_timeout",
+        help = "Timeout in seconds of the requests to the remote gateway"
+    )]
+    pub remote_timeout: Option<u64>,
+    #[structopt(
+        long = "remote_retries",
+        help = "Number of times a failed transfer from or to the remote store is retried"
+    )]
+    pub remote_retries: Option<u32>,
+    #[structopt(
+        long = "remote_max_transfers",
+        help = "Maximum number of concurrent transfers with the remote store (0 means unlimited)"
+    )]
+    pub remote_max_transfers: Option<usize>,
+    #[structopt(
+        long = "verify_reads",
+        help = "Verify the chunks against their digests when they are read from disk"
+    )]
+    pub verify_reads: bool,
+    #[structopt(
+        long = "ignore_pattern",
+        raw(number_of_values = "1"),
+        help = "Pattern of the files left out when a directory is imported (can be repeated)"
+    )]
+    pub ignore_patterns: Vec<String>,
+    #[structopt(
+        long = "strict_import",
+        help = "Fail to import a directory holding files which can't be stored, e.g. sockets"
+    )]
+    pub strict_import: bool,
+    #[structopt(
+        long = "case_insensitive",
+        help = "Look up names case-insensitively, keeping their case (as on macOS)"
+    )]
+    pub case_insensitive: bool,
+    #[structopt(
+        long = "trash_retention",
+        help = "Move removed entries to the trash, keeping them for this many seconds (0 keeps them until the trash is emptied)"
+    )]
+    pub trash_retention: Option<u64>,
+    #[structopt(
+        long = "dehydrate_after",
+        help = "With a remote store, drop the local copies of the files untouched for this many seconds"
+    )]
+    pub dehydrate_after: Option<u64>,
+    #[structopt(
+        long = "checkpoint_on_stop",
+        help = "Save the changes which weren't committed when stopping, and restore them on the next start, instead of committing them"
+    )]
+    pub checkpoint_on_stop: bool,
+    #[structopt(
+        long = "pre_commit_hook",
+        parse(from_os_str),
+        help = "Script run before each commit, which is abandoned if the script fails"
+    )]
+    pub pre_commit_hook: Option<PathBuf>,
+    #[structopt(
+        long = "post_commit_hook",
+        parse(from_os_str),
+        help = "Script run in the background after each commit"
+    )]
+    pub post_commit_hook: Option<PathBuf>,
+    #[structopt(
+        long = "restrict_chown",
+        help = "Only let root change the owner of files, and their owners change their group and mode"
+    )]
+    pub restrict_chown: bool,
+    #[structopt(
+        long = "force_uid",
+        help = "Owner reported for all the files and given to the new ones"
+    )]
+    pub force_uid: Option<u32>,
+    #[structopt(
+        long = "force_gid",
+        help = "Group reported for all the files and given to the new ones"
+    )]
+    pub force_gid: Option<u32>,
+    #[structopt(
+        long = "umask",
+        parse(try_from_str = "parse_umask_str"),
+        help = "Permissions (in octal) removed from the mode of the new files and directories"
+    )]
+    pub umask: Option<u32>,
+    #[structopt(
+        long = "enforce_acls",
+        help = "Only let the users granted access by the POSIX ACL of a file open it"
+    )]
+    pub enforce_acls: bool,
+    #[structopt(
+        long = "volume_icon",
+        parse(from_os_str),
+        help = "Icon (.icns file) shown by Finder for the mounted file system (macOS)"
+    )]
+    pub volume_icon: Option<PathBuf>,
+    #[structopt(
+        long = "nobrowse",
+        help = "Hide the mounted file system from Finder and the desktop (macOS)"
+    )]
+    pub nobrowse: bool,
+    #[structopt(
+        long = "local_volume",
+        help = "Show the mounted file system as a local disk rather than a server (macOS)"
+    )]
+    pub local_volume: bool,
+    #[structopt(
+        long = "save_config",
+        help = "Write the settings given on the command line to the configuration file"
+    )]
+    pub save_config: bool,
+    #[structopt(subcommand)]
+    pub(super) command: Option<Subcommand>,
+}
+
+#[derive(Debug, StructOpt)]
+pub(super) enum Subcommand {
+    #[structopt(
+        name = "init",
+        about = "Create the repository of the instance, with an initial commit"
+    )]
+    Init {
+        #[structopt(
+            long = "sync_dir",
+            parse(from_os_str),
+            help = "Directory whose contents are imported into the initial commit"
+        )]
+        sync_dir: Option<PathBuf>,
+        #[structopt(
+            long = "encrypted",
+            help = "Encrypt the repository with a newly generated key"
+        )]
+        encrypted: bool,
+        #[structopt(
+            long = "digest",
+            default_value = "sha512",
+            help = "Algorithm of the digests of the chunks (sha512 or blake3)"
+        )]
+        digest: Algorithm,
+    },
+    #[structopt(
+        name = "mount",
+        about = "Mount the file system of the instance (the default)"
+    )]
+    Mount,
+    #[structopt(
+        name = "dump-config",
+        about = "Print the effective settings of the instance and where they come from"
+    )]
+    DumpConfig,
 }
 
 impl CommandLine {
     pub(super) fn read() -> CommandLine {
         CommandLine::from_args()
     }
+
+    pub(super) fn for_instance(instance_name: &str) -> CommandLine {
+        CommandLine::from_iter(&["deneb", "--instance_name", instance_name])
+    }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub(super) struct ConfigFile {
     pub(super) mount_point: Option<PathBuf>,
     pub(super) log_level: Option<LevelFilter>,
+    pub(super) log_format: Option<LogFormat>,
+    pub(super) log_max_size: Option<u64>,
+    pub(super) log_rotation_interval: Option<u64>,
+    pub(super) log_max_files: Option<u32>,
     pub(super) chunk_size: Option<usize>,
+    pub(super) chunk_cache_size: Option<u64>,
     pub(super) auto_commit_interval: Option<usize>,
+    pub(super) max_dirty_size: Option<u64>,
+    pub(super) max_memory: Option<u64>,
+    pub(super) queue_size: Option<usize>,
+    pub(super) overload_policy: Option<OverloadPolicy>,
+    pub(super) request_timeout: Option<u64>,
+    pub(super) slow_request_threshold: Option<u64>,
+    pub(super) cache_mode: Option<CacheMode>,
+    pub(super) direct_io_threshold: Option<u64>,
+    pub(super) max_write: Option<u32>,
+    pub(super) max_readahead: Option<u32>,
+    pub(super) warmup_paths: Option<Vec<PathBuf>>,
+    pub(super) warmup_recent: Option<usize>,
+    pub(super) reflog_max_entries: Option<usize>,
+    pub(super) reflog_max_age: Option<u64>,
+    pub(super) remote_store: Option<PathBuf>,
+    pub(super) bundle: Option<PathBuf>,
+    pub(super) local_store_size: Option<u64>,
+    pub(super) remote_gateway: Option<String>,
+    pub(super) remote_gateway_token: Option<String>,
+    pub(super) gateway_address: Option<String>,
+    pub(super) gateway_token: Option<String>,
+    pub(super) control_token: Option<String>,
+    pub(super) control_address: Option<String>,
+    pub(super) control_identity: Option<PathBuf>,
+    pub(super) control_identity_password: Option<String>,
+    pub(super) remote_download_rate: Option<u64>,
+    pub(super) remote_upload_rate: Option<u64>,
+    pub(super) remote_timeout: Option<u64>,
+    pub(super) remote_retries: Option<u32>,
+    pub(super) remote_max_transfers: Option<usize>,
+    pub(super) verify_reads: Option<bool>,
+    pub(super) ignore_patterns: Option<Vec<String>>,
+    pub(super) strict_import: Option<bool>,
+    pub(super) case_insensitive: Option<bool>,
+    pub(super) trash_retention: Option<u64>,
+    pub(super) prune_keep_last: Option<usize>,
+    pub(super) prune_keep_daily: Option<u64>,
+    pub(super) prune_keep_weekly: Option<u64>,
+    pub(super) dehydrate_after: Option<u64>,
+    pub(super) checkpoint_on_stop: Option<bool>,
+    pub(super) pre_commit_hook: Option<PathBuf>,
+    pub(super) post_commit_hook: Option<PathBuf>,
+    pub(super) restrict_chown: Option<bool>,
+    pub(super) force_uid: Option<u32>,
+    pub(super) force_gid: Option<u32>,
+    pub(super) umask: Option<u32>,
+    pub(super) enforce_acls: Option<bool>,
+    pub(super) volume_icon: Option<PathBuf>,
+    pub(super) nobrowse: Option<bool>,
+    pub(super) local_volume: Option<bool>,
+    // Tables are written after all the other values
+    pub(super) log_modules: Option<BTreeMap<String, LevelFilter>>,
 }
 
 impl ConfigFile {
     pub(super) fn load<P: AsRef<Path>>(file_name: P) -> DenebResult<ConfigFile> {
         let cfg = if file_name.as_ref().exists() {
-            let mut f = File::open(file_name)?;
+            let mut f = File::open(&file_name)?;
             let mut contents = String::new();
             f.read_to_string(&mut contents)?;
-            toml::from_str(&contents)?
+            toml::from_str(&contents).map_err(|e| {
+                err_msg(format!(
+                    "Invalid configuration file {:?}: {}",
+                    file_name.as_ref(),
+                    e
+                ))
+            })?
         } else {
-            ConfigFile {
-                mount_point: None,
-                log_level: None,
-                chunk_size: None,
-                auto_commit_interval: None,
-            }
+            ConfigFile::default()
         };
         Ok(cfg)
     }
 
+    /// The settings given on the command line, as they would be written in
+    /// the configuration file
+    pub(super) fn from_command_line(cmd_line: &CommandLine) -> ConfigFile {
+        let flag = |set: bool| if set { Some(true) } else { None };
+        ConfigFile {
+            mount_point: cmd_line.mount_point.clone(),
+            log_level: cmd_line.log_level,
+            log_format: cmd_line.log_format,
+            log_max_size: cmd_line.log_max_size,
+            log_rotation_interval: cmd_line.log_rotation_interval,
+            log_max_files: cmd_line.log_max_files,
+            chunk_size: cmd_line.chunk_size,
+            chunk_cache_size: cmd_line.chunk_cache_size,
+            auto_commit_interval: cmd_line.auto_commit_interval,
+            max_dirty_size: cmd_line.max_dirty_size,
+            max_memory: cmd_line.max_memory,
+            queue_size: cmd_line.queue_size,
+            overload_policy: cmd_line.overload_policy,
+            request_timeout: cmd_line.request_timeout,
+            slow_request_threshold: cmd_line.slow_request_threshold,
+            cache_mode: cmd_line.cache_mode,
+            direct_io_threshold: cmd_line.direct_io_threshold,
+            max_write: cmd_line.max_write,
+            max_readahead: cmd_line.max_readahead,
+            warmup_paths: non_empty(&cmd_line.warmup_paths),
+            warmup_recent: cmd_line.warmup_recent,
+            reflog_max_entries: cmd_line.reflog_max_entries,
+            reflog_max_age: cmd_line.reflog_max_age,
+            remote_store: cmd_line.remote_store.clone(),
+            bundle: cmd_line.bundle.clone(),
+            local_store_size: cmd_line.local_store_size,
+            remote_gateway: cmd_line.remote_gateway.clone(),
+            remote_gateway_token: cmd_line.remote_gateway_token.clone(),
+            gateway_address: cmd_line.gateway_address.clone(),
+            gateway_token: cmd_line.gateway_token.clone(),
+            control_token: cmd_line.control_token.clone(),
+            control_address: cmd_line.control_address.clone(),
+            control_identity: cmd_line.control_identity.clone(),
+            control_identity_password: cmd_line.control_identity_password.clone(),
+            remote_download_rate: cmd_line.remote_download_rate,
+            remote_upload_rate: cmd_line.remote_upload_rate,
+            remote_timeout: cmd_line.remote_timeout,
+            remote_retries: cmd_line.remote_retries,
+            remote_max_transfers: cmd_line.remote_max_transfers,
+            verify_reads: flag(cmd_line.verify_reads),
+            ignore_patterns: non_empty(&cmd_line.ignore_patterns),
+            strict_import: flag(cmd_line.strict_import),
+            case_insensitive: flag(cmd_line.case_insensitive),
+            trash_retention: cmd_line.trash_retention,
+            dehydrate_after: cmd_line.dehydrate_after,
+            checkpoint_on_stop: flag(cmd_line.checkpoint_on_stop),
+            pre_commit_hook: cmd_line.pre_commit_hook.clone(),
+            post_commit_hook: cmd_line.post_commit_hook.clone(),
+            restrict_chown: flag(cmd_line.restrict_chown),
+            force_uid: cmd_line.force_uid,
+            force_gid: cmd_line.force_gid,
+            umask: cmd_line.umask,
+            enforce_acls: flag(cmd_line.enforce_acls),
+            volume_icon: cmd_line.volume_icon.clone(),
+            nobrowse: flag(cmd_line.nobrowse),
+            local_volume: flag(cmd_line.local_volume),
+            ..ConfigFile::default()
+        }
+    }
+
+    /// The settings which are set, by their names in the configuration file
+    pub(super) fn values(&self) -> DenebResult<Table> {
+        match Value::try_from(self)? {
+            Value::Table(table) => Ok(table),
+            _ => Err(err_msg("The configuration is not a table")),
+        }
+    }
+
     pub(super) fn save<P: AsRef<Path>>(&self, file_name: P) -> DenebResult<()> {
         let new_cfg_file = toml::to_string(&self)?;
         let mut f = OpenOptions::new()
@@ -103,6 +558,103 @@ impl ConfigFile {
     }
 }
 
+/// Where the value of a setting comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+    CommandLine,
+    ConfigFile,
+    Default,
+}
+
+/// The settings of an instance, with the layers they were merged from
+pub(super) struct EffectiveConfig {
+    cmd_line: Table,
+    file: Table,
+    /// The configuration file, with the defaults filled in
+    defaults: Table,
+}
+
+impl EffectiveConfig {
+    pub(super) fn new(cmd_line: Table, file: Table, defaults: Table) -> EffectiveConfig {
+        EffectiveConfig {
+            cmd_line,
+            file,
+            defaults,
+        }
+    }
+
+    /// The value of each setting which is set, with where it comes from
+    fn settings(&self) -> Vec<(&str, &Value, Source)> {
+        let mut settings = BTreeMap::new();
+        for (name, value) in &self.defaults {
+            settings.insert(name.as_str(), (value, Source::Default));
+        }
+        for (name, value) in &self.file {
+            settings.insert(name.as_str(), (value, Source::ConfigFile));
+        }
+        for (name, value) in &self.cmd_line {
+            settings.insert(name.as_str(), (value, Source::CommandLine));
+        }
+        settings
+            .into_iter()
+            .map(|(name, (value, source))| (name, value, source))
+            .collect()
+    }
+
+    /// The configuration file with the settings of the command line written
+    /// over it
+    ///
+    /// Defaults are left out, so that changes of the defaults still apply.
+    pub(super) fn to_save(&self) -> DenebResult<ConfigFile> {
+        let mut table = self.file.clone();
+        table.extend(self.cmd_line.clone());
+        Ok(Value::Table(table).try_into()?)
+    }
+
+    /// The settings in the format of the configuration file, each commented
+    /// with where its value comes from
+    ///
+    /// Tokens and passwords are masked.
+    pub(super) fn dump(&self) -> String {
+        let mut out = String::new();
+        for (name, value, source) in self.settings() {
+            let source = match source {
+                Source::CommandLine => "command line",
+                Source::ConfigFile => "config file",
+                Source::Default => "default",
+            };
+            let entries = match value {
+                Value::Table(table) => table
+                    .iter()
+                    .map(|(key, value)| {
+                        let key = Value::from(key.as_str());
+                        (format!("{}.{}", name, key), value)
+                    })
+                    .collect(),
+                value => vec![(name.to_owned(), value)],
+            };
+            for (key, value) in entries {
+                let value = if key.ends_with("token") || key.ends_with("password") {
+                    "\"********\"".to_owned()
+                } else {
+                    value.to_string()
+                };
+                let _ = writeln!(out, "{} = {} # {}", key, value, source);
+            }
+        }
+        out
+    }
+}
+
+// Repeated command line parameters replace the list of the config file when given
+fn non_empty<T: Clone>(values: &[T]) -> Option<Vec<T>> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.to_vec())
+    }
+}
+
 fn parse_log_level_str(s: &str) -> Result<LevelFilter, DenebError> {
     match s {
         "off" => Ok(LevelFilter::Off),
@@ -116,3 +668,10 @@ fn parse_log_level_str(s: &str) -> Result<LevelFilter, DenebError> {
         )),
     }
 }
+
+fn parse_umask_str(s: &str) -> Result<u32, DenebError> {
+    match u32::from_str_radix(s, 8) {
+        Ok(umask) if umask <= 0o7777 => Ok(umask),
+        _ => Err(DenebError::CommandLineParameter("umask: ".to_string() + s)),
+    }
+}
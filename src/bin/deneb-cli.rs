@@ -1,10 +1,31 @@
 use {
     deneb::{
-        app::Directories,
-        talk::{ask, Command},
+        app::{
+            apply_instance_delta, clone_instance, create_instance_bundle, export_instance_delta,
+            instance_control_token, instance_prune_policy, instance_store_layout, open_snapshot,
+            rebalance_instance_store, relocate_instance_store, replicate_instance,
+            set_instance_store_layout, upgrade_instance_store, verify_import, Directories,
+        },
+        talk::{ask, Command, Endpoint, Reply, RepoCommand},
+        top,
     },
-    deneb_core::errors::DenebResult,
-    structopt::StructOpt,
+    deneb_core::{
+        cas::Digest,
+        errors::DenebResult,
+        inode::{FileAttributes, FileType},
+        store::{PlacementPolicy, StoreLayout},
+    },
+    failure::err_msg,
+    serde::Serialize,
+    serde_json::{json, to_string_pretty},
+    std::{
+        env::current_dir,
+        io::{stdout, Write},
+        path::{Path, PathBuf},
+        str::FromStr,
+        time::Duration,
+    },
+    structopt::{clap::Shell, StructOpt},
 };
 
 #[derive(StructOpt)]
@@ -17,6 +38,22 @@ struct Cli {
         help = "Name of the Deneb instance"
     )]
     instance_name: String,
+    #[structopt(
+        long = "control_token",
+        help = "Token presented with the commands, by default the one in the configuration file of the instance"
+    )]
+    control_token: Option<String>,
+    #[structopt(
+        long = "remote",
+        help = "Address of the remote command listener of an instance on another host"
+    )]
+    remote: Option<String>,
+    #[structopt(
+        long = "ca_cert",
+        parse(from_os_str),
+        help = "PEM certificate of the authority which signed the certificate of the remote listener"
+    )]
+    ca_cert: Option<PathBuf>,
     #[structopt(subcommand)]
     cmd: Cmd,
 }
@@ -30,31 +67,887 @@ impl Cli {
 #[derive(StructOpt)]
 enum Cmd {
     #[structopt(name = "status", about = "Display the status of the Deneb process")]
-    Status,
+    Status {
+        #[structopt(flatten)]
+        output: Output,
+    },
+    #[structopt(name = "log", about = "List the commits recorded by the Deneb process")]
+    Log {
+        #[structopt(flatten)]
+        output: Output,
+    },
     #[structopt(name = "ping", about = "Ping the Deneb process")]
     Ping,
     #[structopt(name = "commit", about = "Send a commit request")]
-    Commit,
+    Commit {
+        #[structopt(
+            short = "m",
+            long = "message",
+            help = "Message recorded with the commit (generated if missing)"
+        )]
+        message: Option<String>,
+    },
+    #[structopt(name = "diff", about = "List the changes which were not committed yet")]
+    Diff {
+        #[structopt(flatten)]
+        output: Output,
+    },
+    #[structopt(
+        name = "restore",
+        about = "Copy a file or directory from a previous commit into the repository"
+    )]
+    Restore {
+        #[structopt(
+            long = "at",
+            help = "Root hash of the commit, as listed by the log command"
+        )]
+        at: Digest,
+        #[structopt(
+            long = "overwrite",
+            help = "Replace the current file, instead of restoring next to it as <name>.restored"
+        )]
+        overwrite: bool,
+        #[structopt(parse(from_os_str), help = "Path in the repository")]
+        path: PathBuf,
+    },
+    #[structopt(
+        name = "gc",
+        about = "Commit and remove the data no longer referenced by the retained commits"
+    )]
+    Gc,
+    #[structopt(
+        name = "prune",
+        about = "Remove the commits not kept by the prune policy from the reflog, then run gc"
+    )]
+    Prune {
+        #[structopt(
+            long = "keep_last",
+            help = "Number of most recent commits which are kept (prune_keep_last)"
+        )]
+        keep_last: Option<usize>,
+        #[structopt(
+            long = "keep_daily",
+            help = "Number of days for which the last commit of each day is kept (prune_keep_daily)"
+        )]
+        keep_daily: Option<u64>,
+        #[structopt(
+            long = "keep_weekly",
+            help = "Number of weeks for which the last commit of each week is kept (prune_keep_weekly)"
+        )]
+        keep_weekly: Option<u64>,
+    },
+    #[structopt(
+        name = "operations",
+        about = "List the running maintenance operations (commit, gc, prune)"
+    )]
+    Operations {
+        #[structopt(flatten)]
+        output: Output,
+    },
+    #[structopt(name = "cancel", about = "Cancel a running maintenance operation")]
+    Cancel {
+        #[structopt(help = "Token of the operation, as listed by the operations command")]
+        token: u64,
+    },
+    #[structopt(
+        name = "pin",
+        about = "Keep the contents of a file or directory in the local store"
+    )]
+    Pin {
+        #[structopt(parse(from_os_str), help = "Path in the repository")]
+        path: PathBuf,
+    },
+    #[structopt(name = "unpin", about = "Stop keeping a pinned file or directory")]
+    Unpin {
+        #[structopt(parse(from_os_str), help = "Path in the repository")]
+        path: PathBuf,
+    },
+    #[structopt(
+        name = "dehydrate",
+        about = "Drop the local copies of a file or directory, kept by the remote store"
+    )]
+    Dehydrate {
+        #[structopt(parse(from_os_str), help = "Path in the repository")]
+        path: PathBuf,
+    },
+    #[structopt(
+        name = "clone",
+        about = "Create this instance from the work dir of another instance"
+    )]
+    Clone {
+        #[structopt(
+            long = "thin",
+            help = "Only copy the catalog, fetching file contents when they are read"
+        )]
+        thin: bool,
+        #[structopt(
+            long = "depth",
+            help = "Only copy this many of the most recent commits, starting with the current one"
+        )]
+        depth: Option<usize>,
+        #[structopt(
+            long = "encryption_key",
+            parse(from_os_str),
+            help = "Encryption key file of the other instance"
+        )]
+        encryption_key: PathBuf,
+        #[structopt(parse(from_os_str), help = "Work dir of the other instance")]
+        remote: PathBuf,
+    },
+    #[structopt(
+        name = "replicate",
+        about = "Copy the committed state of this instance into a backup directory"
+    )]
+    Replicate {
+        #[structopt(
+            parse(from_os_str),
+            help = "Backup directory, updated incrementally if it was used before"
+        )]
+        dest_dir: PathBuf,
+    },
+    #[structopt(
+        name = "export-delta",
+        about = "Write the chunks needed to go from one commit of this instance to another"
+    )]
+    ExportDelta {
+        #[structopt(help = "Root hash of the commit the delta is applied to")]
+        from: Digest,
+        #[structopt(help = "Root hash of the commit the delta leads to")]
+        to: Digest,
+        #[structopt(short = "o", long = "output", parse(from_os_str), help = "Delta file")]
+        out: PathBuf,
+    },
+    #[structopt(
+        name = "apply-delta",
+        about = "Apply a delta to this instance, while it is stopped, or to a backup directory"
+    )]
+    ApplyDelta {
+        #[structopt(parse(from_os_str), help = "Delta file")]
+        delta: PathBuf,
+        #[structopt(
+            parse(from_os_str),
+            help = "Backup directory, instead of the repository of this instance"
+        )]
+        dest_dir: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "bundle",
+        about = "Pack the repository of this instance into a single file"
+    )]
+    Bundle {
+        #[structopt(subcommand)]
+        cmd: BundleCmd,
+    },
+    #[structopt(
+        name = "relocate",
+        about = "Move the store of this instance to another directory, while it is stopped"
+    )]
+    Relocate {
+        #[structopt(
+            long = "store",
+            parse(from_os_str),
+            help = "New directory of the store, which must not exist"
+        )]
+        store: PathBuf,
+    },
+    #[structopt(
+        name = "verify-import",
+        about = "Compare a directory with the committed contents of this instance"
+    )]
+    VerifyImport {
+        #[structopt(parse(from_os_str), help = "Directory which was imported")]
+        dir: PathBuf,
+        #[structopt(flatten)]
+        output: Output,
+    },
+    #[structopt(
+        name = "ls",
+        about = "List a directory of the repository, without mounting it"
+    )]
+    Ls {
+        #[structopt(
+            parse(from_os_str),
+            default_value = "/",
+            help = "Path in the repository"
+        )]
+        path: PathBuf,
+        #[structopt(flatten)]
+        output: Output,
+    },
+    #[structopt(
+        name = "cat",
+        about = "Print a file of the repository, without mounting it"
+    )]
+    Cat {
+        #[structopt(parse(from_os_str), help = "Path in the repository")]
+        path: PathBuf,
+    },
+    #[structopt(
+        name = "stat",
+        about = "Display the attributes of files of the repository, without mounting it"
+    )]
+    Stat {
+        #[structopt(
+            parse(from_os_str),
+            raw(required = "true"),
+            help = "Paths in the repository"
+        )]
+        paths: Vec<PathBuf>,
+        #[structopt(flatten)]
+        output: Output,
+    },
+    #[structopt(
+        name = "hash",
+        about = "Display the digest of the contents of a file of the repository"
+    )]
+    Hash {
+        #[structopt(parse(from_os_str), help = "Path in the repository")]
+        path: PathBuf,
+        #[structopt(flatten)]
+        output: Output,
+    },
+    #[structopt(name = "stats", about = "Display the operation statistics once")]
+    Stats {
+        #[structopt(flatten)]
+        output: Output,
+    },
+    #[structopt(name = "top", about = "Display live operation statistics")]
+    Top {
+        #[structopt(
+            short = "i",
+            long = "interval",
+            default_value = "1",
+            help = "Refresh interval (in seconds)"
+        )]
+        interval: u64,
+    },
+    #[structopt(
+        name = "repo",
+        about = "Manage the other instances hosted by the Deneb process"
+    )]
+    Repo {
+        #[structopt(subcommand)]
+        cmd: RepoCmd,
+    },
+    #[structopt(name = "store", about = "Maintain the store of this instance")]
+    Store {
+        #[structopt(subcommand)]
+        cmd: StoreCmd,
+    },
+    #[structopt(
+        name = "trash",
+        about = "Manage the entries removed from the repository"
+    )]
+    Trash {
+        #[structopt(subcommand)]
+        cmd: TrashCmd,
+    },
+    #[structopt(
+        name = "completions",
+        about = "Write the completion script of deneb-cli for a shell to stdout"
+    )]
+    Completions {
+        #[structopt(
+            raw(possible_values = "&Shell::variants()"),
+            help = "Shell for which the script is written"
+        )]
+        shell: Shell,
+    },
+}
+
+#[derive(StructOpt)]
+struct Output {
+    #[structopt(
+        long = "output",
+        default_value = "text",
+        raw(possible_values = r#"&["text", "json"]"#),
+        help = "Format of the results, json for scripts"
+    )]
+    format: OutputFormat,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+enum RepoCmd {
+    #[structopt(
+        name = "add",
+        about = "Start an initialized instance, without mounting it"
+    )]
+    Add {
+        #[structopt(help = "Name of the instance")]
+        name: String,
+    },
+    #[structopt(name = "mount", about = "Mount an instance, starting it if needed")]
+    Mount {
+        #[structopt(help = "Name of the instance")]
+        name: String,
+    },
+    #[structopt(name = "remove", about = "Unmount and stop an instance")]
+    Remove {
+        #[structopt(help = "Name of the instance")]
+        name: String,
+    },
+    #[structopt(name = "list", about = "List the hosted instances")]
+    List,
+}
+
+#[derive(StructOpt)]
+enum TrashCmd {
+    #[structopt(name = "empty", about = "Delete all the entries of the trash")]
+    Empty,
+}
+
+#[derive(StructOpt)]
+enum BundleCmd {
+    #[structopt(
+        name = "create",
+        about = "Write the current commit into a bundle, which can be mounted read-only"
+    )]
+    Create {
+        #[structopt(parse(from_os_str), help = "Bundle file")]
+        out: PathBuf,
+    },
+}
+
+#[derive(StructOpt)]
+enum StoreCmd {
+    #[structopt(
+        name = "upgrade",
+        about = "Upgrade the store to the current format, while the instance is stopped"
+    )]
+    Upgrade,
+    #[structopt(name = "layout", about = "List the data areas of the store")]
+    Layout,
+    #[structopt(
+        name = "add-dir",
+        about = "Add a data area to the store, e.g. on another disk, while the instance is stopped"
+    )]
+    AddDir {
+        #[structopt(parse(from_os_str), help = "Directory of the data area")]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "remove-dir",
+        about = "Move the chunks out of a data area and remove it, while the instance is stopped"
+    )]
+    RemoveDir {
+        #[structopt(parse(from_os_str), help = "Directory of the data area")]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "placement",
+        about = "Choose how new chunks are spread over the data areas, while the instance is stopped"
+    )]
+    Placement {
+        #[structopt(help = "Placement policy: hash-prefix or fill-then-spill")]
+        policy: PlacementPolicy,
+        #[structopt(
+            long = "reserve",
+            default_value = "0",
+            help = "Free space kept on the disks by fill-then-spill, in bytes"
+        )]
+        reserve: u64,
+    },
+    #[structopt(
+        name = "rebalance",
+        about = "Move the chunks to the data areas picked by the placement policy, while the instance is stopped"
+    )]
+    Rebalance,
 }
 
 fn main() -> DenebResult<()> {
     let app = Cli::init();
 
+    if let Cmd::Completions { shell } = app.cmd {
+        Cli::clap().gen_completions_to("deneb-cli", shell, &mut stdout());
+        return Ok(());
+    }
+
     let dirs = Directories::with_name(&app.instance_name)?;
 
-    let socket_file = dirs.workspace.join("cmd.sock");
+    let endpoint = match app.remote {
+        Some(ref address) => Endpoint::Remote {
+            address: address.clone(),
+            ca_cert: app.ca_cert.clone(),
+        },
+        None => Endpoint::Socket(dirs.workspace.join("cmd.sock")),
+    };
+    let control_token = match app.control_token {
+        Some(ref token) => Some(token.clone()),
+        None => instance_control_token(&app.instance_name)?,
+    };
+    let token = control_token.as_ref().map(String::as_str);
 
+    // The format of the replies of the informational commands
+    let mut format = OutputFormat::Text;
     let (text, send_cmd) = match app.cmd {
-        Cmd::Status => ("status", Command::Status),
+        Cmd::Status { output } => {
+            format = output.format;
+            ("status", Command::Status)
+        }
+        Cmd::Log { output } => {
+            format = output.format;
+            ("log", Command::Log)
+        }
         Cmd::Ping => ("ping", Command::Ping),
-        Cmd::Commit => ("commit", Command::Commit),
+        Cmd::Commit { message } => ("commit", Command::Commit(message)),
+        Cmd::Diff { output } => {
+            format = output.format;
+            ("diff", Command::Diff)
+        }
+        Cmd::Restore {
+            at,
+            overwrite,
+            path,
+        } => (
+            "restore",
+            Command::Restore {
+                at,
+                path,
+                overwrite,
+            },
+        ),
+        Cmd::Gc => ("gc", Command::Gc),
+        Cmd::Prune {
+            keep_last,
+            keep_daily,
+            keep_weekly,
+        } => {
+            // The options override the policy of the configuration file
+            let policy = instance_prune_policy(&app.instance_name)?;
+            (
+                "prune",
+                Command::Prune {
+                    keep_last: keep_last.or(policy.keep_last),
+                    keep_daily: keep_daily.or(policy.keep_daily),
+                    keep_weekly: keep_weekly.or(policy.keep_weekly),
+                },
+            )
+        }
+        Cmd::Trash {
+            cmd: TrashCmd::Empty,
+        } => ("trash empty", Command::EmptyTrash),
+        Cmd::Operations { output } => {
+            format = output.format;
+            ("operations", Command::Operations)
+        }
+        Cmd::Stats { output } => {
+            format = output.format;
+            ("stats", Command::Metrics)
+        }
+        Cmd::Cancel { token } => ("cancel", Command::Cancel(token)),
+        Cmd::Pin { path } => ("pin", Command::Pin { path, pinned: true }),
+        Cmd::Unpin { path } => (
+            "unpin",
+            Command::Pin {
+                path,
+                pinned: false,
+            },
+        ),
+        Cmd::Dehydrate { path } => ("dehydrate", Command::Dehydrate(path)),
+        Cmd::Repo { cmd } => (
+            "repo",
+            Command::Repo(match cmd {
+                RepoCmd::Add { name } => RepoCommand::Add(name),
+                RepoCmd::Mount { name } => RepoCommand::Mount(name),
+                RepoCmd::Remove { name } => RepoCommand::Remove(name),
+                RepoCmd::List => RepoCommand::List,
+            }),
+        ),
+        Cmd::Clone {
+            thin,
+            depth,
+            encryption_key,
+            remote,
+        } => {
+            if depth == Some(0) {
+                return Err(err_msg("The depth of a clone must be at least 1"));
+            }
+            let copied = clone_instance(&app.instance_name, &remote, &encryption_key, thin, depth)?;
+            println!("Cloned {:?}: {} chunks copied", remote, copied);
+            return Ok(());
+        }
+        Cmd::Replicate { dest_dir } => {
+            let copied = replicate_instance(&app.instance_name, &dest_dir)?;
+            println!("Replicated into {:?}: {} chunks copied", dest_dir, copied);
+            return Ok(());
+        }
+        Cmd::ExportDelta { from, to, out } => {
+            let written = export_instance_delta(&app.instance_name, &from, &to, &out)?;
+            println!("Delta written to {:?}: {} chunks", out, written);
+            return Ok(());
+        }
+        Cmd::ApplyDelta { delta, dest_dir } => {
+            let dest_dir = dest_dir.as_ref().map(PathBuf::as_path);
+            let stored = apply_instance_delta(&app.instance_name, &delta, dest_dir)?;
+            println!("Delta {:?} applied: {} chunks stored", delta, stored);
+            return Ok(());
+        }
+        Cmd::Bundle {
+            cmd: BundleCmd::Create { out },
+        } => {
+            let written = create_instance_bundle(&app.instance_name, &out)?;
+            println!("Bundle written to {:?}: {} chunks", out, written);
+            return Ok(());
+        }
+        Cmd::Relocate { store } => {
+            let moved = relocate_instance_store(&app.instance_name, &store)?;
+            println!("Store moved to {:?}: {} chunks", store, moved);
+            return Ok(());
+        }
+        Cmd::Store { cmd } => {
+            maintain_store(&app.instance_name, cmd)?;
+            return Ok(());
+        }
+        Cmd::VerifyImport { dir, output } => {
+            let differences = verify_import(&app.instance_name, &dir)?;
+            if output.format == OutputFormat::Json {
+                print_json(&differences)?;
+            } else {
+                for difference in &differences {
+                    println!("{}", difference);
+                }
+            }
+            if !differences.is_empty() {
+                return Err(err_msg(format!("{} differences found", differences.len())));
+            }
+            if output.format == OutputFormat::Text {
+                println!("{:?} matches the repository", dir);
+            }
+            return Ok(());
+        }
+        Cmd::Ls { path, output } => {
+            return list(&app.instance_name, &endpoint, token, &path, output.format);
+        }
+        Cmd::Cat { path } => return print_file(&app.instance_name, &endpoint, token, &path),
+        Cmd::Stat { paths, output } => {
+            return print_stat(&app.instance_name, &endpoint, token, &paths, output.format);
+        }
+        Cmd::Hash { path, output } => {
+            return print_hash(&app.instance_name, &endpoint, token, &path, output.format);
+        }
+        Cmd::Completions { .. } => unreachable!(),
+        Cmd::Top { interval } => {
+            return top::run(
+                &endpoint,
+                token,
+                &app.instance_name,
+                Duration::from_secs(interval),
+            );
+        }
+    };
+
+    if format == OutputFormat::Text {
+        println!("Sending {} command", text);
+    }
+
+    match (ask(&endpoint, token, send_cmd)?, format) {
+        (Reply::Error(msg), _) => return Err(err_msg(msg)),
+        (Reply::Manifest(manifest), OutputFormat::Json) => print_json(&manifest)?,
+        (Reply::Log(manifests), OutputFormat::Json) => print_json(&manifests)?,
+        (Reply::Diff(pending), OutputFormat::Json) => print_json(&pending)?,
+        (Reply::Metrics(metrics), OutputFormat::Json) => print_json(&metrics)?,
+        (Reply::Operations(ops), OutputFormat::Json) => {
+            let ops = ops
+                .iter()
+                .map(|(token, name)| json!({ "token": token, "name": name }))
+                .collect::<Vec<_>>();
+            print_json(&ops)?;
+        }
+        (Reply::Text(reply), _) => println!("Reply: {}", reply),
+        (Reply::Manifest(manifest), _) => println!("Reply: {}", manifest),
+        (Reply::Log(manifests), _) => {
+            let log = manifests
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            println!("Reply: {}", log);
+        }
+        (Reply::Commit(summary), _) => print!("{}", summary),
+        (Reply::Diff(pending), _) => {
+            for change in &pending.changes {
+                println!("{}", change);
+            }
+            if pending.changes.is_empty() {
+                println!("No pending changes");
+            } else {
+                println!("{} bytes to be written", pending.bytes_to_write);
+            }
+        }
+        (Reply::Metrics(metrics), _) => {
+            print!("{}", top::render(&app.instance_name, &metrics, None))
+        }
+        (Reply::Operations(ops), _) => {
+            let ops = ops
+                .iter()
+                .map(|(token, name)| format!("{} {}\n", token, name))
+                .collect::<String>();
+            println!("Reply: {}", ops);
+        }
+        _ => return Err(err_msg("Unexpected reply")),
+    }
+
+    Ok(())
+}
+
+fn maintain_store(instance_name: &str, cmd: StoreCmd) -> DenebResult<()> {
+    let mut layout = instance_store_layout(instance_name)?;
+    let moved = match cmd {
+        StoreCmd::Upgrade => {
+            let (from, to) = upgrade_instance_store(instance_name)?;
+            if from == to {
+                println!("The store is already at format version {}", to);
+            } else {
+                println!("Upgraded the store from format version {} to {}", from, to);
+            }
+            return Ok(());
+        }
+        StoreCmd::Layout => {
+            print_store_layout(&layout);
+            return Ok(());
+        }
+        StoreCmd::Rebalance => {
+            let moved = rebalance_instance_store(instance_name)?;
+            println!("Store rebalanced: {} chunks moved", moved);
+            return Ok(());
+        }
+        StoreCmd::AddDir { dir } => {
+            layout.dirs.push(current_dir()?.join(dir));
+            set_instance_store_layout(instance_name, &layout)?
+        }
+        StoreCmd::RemoveDir { dir } => {
+            let dir = current_dir()?.join(dir);
+            if !layout.dirs.contains(&dir) {
+                return Err(err_msg(format!(
+                    "{:?} is not a data area of the store",
+                    dir
+                )));
+            }
+            layout.dirs.retain(|d| *d != dir);
+            set_instance_store_layout(instance_name, &layout)?
+        }
+        StoreCmd::Placement { policy, reserve } => {
+            layout.placement = policy;
+            layout.reserve = reserve;
+            set_instance_store_layout(instance_name, &layout)?
+        }
     };
+    if moved > 0 {
+        println!("{} chunks moved", moved);
+    }
+    print_store_layout(&layout);
+    Ok(())
+}
+
+fn print_store_layout(layout: &StoreLayout) {
+    println!("Placement: {}", layout.placement);
+    if layout.placement == PlacementPolicy::FillThenSpill {
+        println!("Reserve: {} bytes", layout.reserve);
+    }
+    println!("Data areas:");
+    println!("  (main, in the work dir)");
+    for dir in &layout.dirs {
+        println!("  {}", dir.display());
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) -> DenebResult<()> {
+    println!("{}", to_string_pretty(value)?);
+    Ok(())
+}
+
+/// An entry of a directory as printed in JSON
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    path: &'a Path,
+    #[serde(flatten)]
+    attributes: &'a FileAttributes,
+}
+
+// The running Deneb process, if any, also sees the changes which were not
+// committed yet. Otherwise the committed state is read from the work dir,
+// which is only possible for the instances of this host.
+fn ask_running(
+    endpoint: &Endpoint,
+    token: Option<&str>,
+    cmd: Command,
+) -> DenebResult<Option<Reply>> {
+    match (ask(endpoint, token, cmd), endpoint) {
+        (Ok(Reply::Error(msg)), _) => Err(err_msg(msg)),
+        (Ok(reply), _) => Ok(Some(reply)),
+        (Err(_), Endpoint::Socket(_)) => Ok(None),
+        (Err(e), Endpoint::Remote { .. }) => Err(e),
+    }
+}
 
-    println!("Sending {} command", text);
+fn list(
+    instance_name: &str,
+    endpoint: &Endpoint,
+    token: Option<&str>,
+    path: &Path,
+    format: OutputFormat,
+) -> DenebResult<()> {
+    let entries = match ask_running(endpoint, token, Command::List(path.to_owned()))? {
+        Some(Reply::Entries(entries)) => entries,
+        Some(_) => return Err(err_msg("Unexpected reply")),
+        None => {
+            let snapshot = open_snapshot(instance_name)?;
+            let attrs = snapshot
+                .resolve(path)?
+                .ok_or_else(|| err_msg(format!("{:?} not found", path)))?;
+            if attrs.kind == FileType::Directory {
+                snapshot.read_dir(attrs.index)?
+            } else {
+                vec![(path.to_owned(), attrs)]
+            }
+        }
+    };
+    if format == OutputFormat::Json {
+        return print_json(&json_entries(&entries));
+    }
+    for (name, attrs) in entries {
+        println!("{}", list_entry(&attrs, &name));
+    }
+    Ok(())
+}
 
-    let reply = ask(socket_file, send_cmd)?;
+fn print_file(
+    instance_name: &str,
+    endpoint: &Endpoint,
+    token: Option<&str>,
+    path: &Path,
+) -> DenebResult<()> {
+    let out = stdout();
+    let mut out = out.lock();
+    match ask_running(endpoint, token, Command::Read(path.to_owned()))? {
+        Some(Reply::Data(data)) => out.write_all(&data)?,
+        Some(_) => return Err(err_msg("Unexpected reply")),
+        None => {
+            let snapshot = open_snapshot(instance_name)?;
+            let attrs = snapshot
+                .resolve(path)?
+                .ok_or_else(|| err_msg(format!("{:?} not found", path)))?;
+            snapshot.read_file(attrs.index, &mut out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
 
-    println!("Reply: {}", reply);
+fn print_stat(
+    instance_name: &str,
+    endpoint: &Endpoint,
+    token: Option<&str>,
+    paths: &[PathBuf],
+    format: OutputFormat,
+) -> DenebResult<()> {
+    let entries = match ask_running(endpoint, token, Command::Stat(paths.to_vec()))? {
+        Some(Reply::Entries(entries)) => entries,
+        Some(_) => return Err(err_msg("Unexpected reply")),
+        None => {
+            let snapshot = open_snapshot(instance_name)?;
+            paths
+                .iter()
+                .map(|path| {
+                    snapshot
+                        .resolve(path)?
+                        .map(|attrs| (path.clone(), attrs))
+                        .ok_or_else(|| err_msg(format!("{:?} not found", path)))
+                })
+                .collect::<DenebResult<Vec<_>>>()?
+        }
+    };
+    if format == OutputFormat::Json {
+        return print_json(&json_entries(&entries));
+    }
+    for (pos, (path, attrs)) in entries.iter().enumerate() {
+        if pos > 0 {
+            println!();
+        }
+        println!("Path: {}", path.display());
+        println!("Index: {}", attrs.index);
+        println!("Type: {:?}", attrs.kind);
+        println!("Size: {}", attrs.size);
+        println!("Permissions: {:04o}", attrs.perm);
+        println!("Links: {}", attrs.nlink);
+        println!("Owner: {}:{}", attrs.uid, attrs.gid);
+        println!("Modified: {}", attrs.mtime.sec);
+        println!("Changed: {}", attrs.ctime.sec);
+    }
+    Ok(())
+}
 
+fn print_hash(
+    instance_name: &str,
+    endpoint: &Endpoint,
+    token: Option<&str>,
+    path: &Path,
+    format: OutputFormat,
+) -> DenebResult<()> {
+    let digest = match ask_running(endpoint, token, Command::Hash(path.to_owned()))? {
+        Some(Reply::Text(digest)) => digest,
+        Some(_) => return Err(err_msg("Unexpected reply")),
+        None => {
+            let snapshot = open_snapshot(instance_name)?;
+            let attrs = snapshot
+                .resolve(path)?
+                .ok_or_else(|| err_msg(format!("{:?} not found", path)))?;
+            snapshot
+                .file_digest(attrs.index)?
+                .map(|digest| digest.to_string())
+                .ok_or_else(|| err_msg(format!("No digest recorded for {:?}", path)))?
+        }
+    };
+    if format == OutputFormat::Json {
+        return print_json(&json!({ "path": path, "digest": digest }));
+    }
+    println!("{}  {}", digest, path.display());
     Ok(())
 }
+
+fn json_entries(entries: &[(PathBuf, FileAttributes)]) -> Vec<JsonEntry> {
+    entries
+        .iter()
+        .map(|(path, attributes)| JsonEntry { path, attributes })
+        .collect()
+}
+
+// Formatted like the long listing of `ls`
+fn list_entry(attrs: &FileAttributes, name: &Path) -> String {
+    let kind = match attrs.kind {
+        FileType::NamedPipe => 'p',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Directory => 'd',
+        FileType::RegularFile => '-',
+        FileType::Symlink => 'l',
+    };
+    format!(
+        "{}{:04o} {:>3} {:>6} {:>6} {:>12} {}",
+        kind,
+        attrs.perm,
+        attrs.nlink,
+        attrs.uid,
+        attrs.gid,
+        attrs.size,
+        name.display()
+    )
+}
@@ -0,0 +1,602 @@
+use {
+    crate::{
+        app::App,
+        logging::Logger,
+        talk::{
+            ask, listen, listen_remote, Command, Endpoint, Listener, RemoteControl, Reply,
+            RepoCommand,
+        },
+        top::render,
+        util::SignalAction,
+    },
+    crossbeam_channel::{bounded as channel, Receiver, RecvTimeoutError, Sender},
+    deneb_core::{
+        catalog::CatalogType,
+        engine::{
+            check_repository, start_engine, warm_up, EngineOptions, Handle, PrunePolicy, RequestId,
+            WorkspaceOptions,
+        },
+        errors::DenebResult,
+        inode::{FileAttributes, FileType},
+        store::{RemoteStore, StoreType},
+    },
+    deneb_fuse::fs::{Fs, KernelOptions},
+    failure::err_msg,
+    log::{error, info},
+    std::{
+        collections::BTreeMap,
+        fs::remove_dir_all,
+        os::unix::ffi::OsStringExt,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        thread::{spawn, JoinHandle},
+        time::Duration,
+    },
+};
+
+// Size of the reads done when a file is sent to deneb-cli
+const READ_SIZE: u32 = 1024 * 1024;
+
+// Interval between the scans for files to dehydrate
+const DEHYDRATION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The engine of an instance and the listeners for its commands
+pub struct Instance {
+    pub handle: Handle,
+    listener: Listener,
+    remote_listener: Option<Listener>,
+    // Dropped to stop the periodic dehydration
+    dehydration: Option<Sender<()>>,
+    checkpoint_on_stop: bool,
+}
+
+impl Instance {
+    /// Stop answering commands, then commit (or checkpoint) and stop the engine
+    pub fn stop(self) {
+        self.listener.stop();
+        if let Some(remote_listener) = self.remote_listener {
+            remote_listener.stop();
+        }
+        drop(self.dehydration);
+        stop_engine(&self.handle, self.checkpoint_on_stop);
+    }
+}
+
+/// Stop the engine, saving the changes which weren't committed to a
+/// checkpoint if `checkpoint` is set, or committing them otherwise
+pub fn stop_engine(handle: &Handle, checkpoint: bool) {
+    if checkpoint {
+        handle.stop_engine_with_checkpoint();
+    } else {
+        handle.stop_engine();
+    }
+}
+
+/// Start the engine of an instance and listen for the commands sent to it by deneb-cli
+///
+/// The instances hosted by a daemon are managed through its command socket,
+/// the one of the daemon's own instance, which is given `repositories`.
+pub fn start_instance(app: &App, repositories: Option<Arc<Repositories>>) -> DenebResult<Instance> {
+    // A bundle is the only source of the chunks of the repository it holds
+    let remote = match (&app.settings.remote_store, &app.settings.remote_gateway) {
+        _ if app.settings.bundle.is_some() => app.settings.bundle.clone().map(RemoteStore::Bundle),
+        (Some(remote_dir), _) => Some(RemoteStore::Dir(remote_dir.clone())),
+        (None, Some(address)) => Some(RemoteStore::Gateway {
+            address: address.clone(),
+            token: app.settings.remote_gateway_token.clone(),
+        }),
+        (None, None) => None,
+    };
+    let store_type = match remote {
+        Some(remote) => StoreType::Tiered {
+            remote,
+            capacity: app.settings.local_store_size,
+            limits: app.settings.remote_limits,
+        },
+        None => StoreType::OnDisk,
+    };
+
+    // Commands from other hosts are only accepted over TLS, with a token
+    let remote_control = match app.settings.control_address {
+        Some(ref address) => Some(RemoteControl {
+            address: address.clone(),
+            identity: app
+                .settings
+                .control_identity
+                .clone()
+                .ok_or_else(|| err_msg("A control address needs a control identity"))?,
+            identity_password: app
+                .settings
+                .control_identity_password
+                .clone()
+                .unwrap_or_default(),
+            token: app
+                .settings
+                .control_token
+                .clone()
+                .ok_or_else(|| err_msg("A control address needs a control token"))?,
+        }),
+        None => None,
+    };
+
+    // Create the file system data structure
+    let handle = start_engine(
+        CatalogType::Lmdb,
+        store_type,
+        app.directories.workspace.clone(),
+        EngineOptions {
+            workspace: WorkspaceOptions {
+                encryption_key: app.settings.encryption_key.clone(),
+                chunk_size: app.settings.chunk_size,
+                chunk_cache_size: app.settings.chunk_cache_size,
+                retention: app.settings.retention,
+                verify_reads: app.settings.verify_reads,
+                case_insensitive: app.settings.case_insensitive,
+                trash_retention: app.settings.trash_retention,
+                import: app.settings.import.clone(),
+                ..WorkspaceOptions::default()
+            },
+            cmd_queue_size: app.settings.queue_size,
+            overload_policy: app.settings.overload_policy,
+            request_limits: app.settings.request_limits,
+            auto_commit_interval: app.settings.auto_commit_interval,
+            max_dirty_size: app.settings.max_dirty_size,
+            max_memory: app.settings.max_memory,
+            cache_policy: app.settings.cache_policy,
+            hooks: app.settings.hooks.clone(),
+            ownership: app.settings.ownership,
+        },
+    )?;
+
+    // Start a listener for commands received from deneb-cli
+    let handle2 = handle.clone();
+    let action = Arc::new(move |cmd: Command| match cmd {
+        Command::Repo(cmd) => match repositories {
+            Some(ref repositories) => repositories.run(cmd),
+            None => Err(err_msg(
+                "Instances are managed by the daemon hosting this one",
+            )),
+        },
+        cmd => run_command(&handle2, cmd),
+    });
+    let listener = listen(
+        app.directories.workspace.join("cmd.sock"),
+        app.settings.control_token.clone(),
+        Arc::clone(&action),
+    )?;
+    let remote_listener = match remote_control {
+        Some(ref remote_control) => match listen_remote(remote_control, action) {
+            Ok(remote_listener) => Some(remote_listener),
+            Err(e) => {
+                listener.stop();
+                handle.stop_engine();
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+
+    // Prefetch files in the background, while the file system is being mounted
+    if !app.settings.warmup_paths.is_empty() || app.settings.warmup_recent > 0 {
+        let handle3 = handle.clone();
+        let warmup_paths = app.settings.warmup_paths.clone();
+        let warmup_recent = app.settings.warmup_recent;
+        spawn(move || warm_up(&handle3, &warmup_paths, warmup_recent));
+    }
+
+    // Only the files whose chunks can be fetched again are dehydrated
+    let has_remote = app.settings.remote_store.is_some()
+        || app.settings.remote_gateway.is_some()
+        || app.settings.bundle.is_some();
+    let dehydration = match app.settings.dehydrate_after {
+        Some(dehydrate_after) if has_remote => {
+            let (stop_tx, stop_rx) = channel(1);
+            let handle4 = handle.clone();
+            let untouched_for = Duration::from_secs(dehydrate_after);
+            spawn(move || dehydrate_periodically(&handle4, untouched_for, &stop_rx));
+            Some(stop_tx)
+        }
+        _ => None,
+    };
+
+    Ok(Instance {
+        handle,
+        listener,
+        remote_listener,
+        dehydration,
+        checkpoint_on_stop: app.settings.checkpoint_on_stop,
+    })
+}
+
+/// The FUSE options with which the file system of an instance is mounted
+pub fn mount_options(app: &App) -> Vec<String> {
+    let mut mount_options = vec![format!("fsname={}", app.fs_name())];
+    if app.settings.bundle.is_some() {
+        mount_options.push("ro".to_string());
+    }
+    if cfg!(target_os = "macos") {
+        let volume = &app.settings.volume;
+        mount_options.push("negative_vncache".to_string());
+        mount_options.push(format!("volname={}", app.settings.instance_name));
+        if let Some(ref icon) = volume.icon {
+            mount_options.push(format!("volicon={}", icon.display()));
+        }
+        if volume.nobrowse {
+            mount_options.push("nobrowse".to_string());
+        }
+        if volume.local {
+            mount_options.push("local".to_string());
+        }
+    }
+    Fs::make_options(&mount_options)
+}
+
+/// What the file system of an instance asks of the kernel when it's mounted
+pub fn kernel_options(app: &App) -> KernelOptions {
+    KernelOptions {
+        max_write: app.settings.max_write,
+        max_readahead: app.settings.max_readahead,
+        stats_dir: Some(app.directories.workspace.clone()),
+    }
+}
+
+/// The instances hosted by a daemon, besides its own
+///
+/// Each of them has its own engine, command socket and mount point, and is
+/// configured by its own config file, but they share the daemon process and
+/// its log. They are added and removed at runtime with `RepoCommand`s.
+pub struct Repositories {
+    /// Name of the daemon's own instance
+    daemon_instance: String,
+    hosted: Mutex<BTreeMap<String, Hosted>>,
+}
+
+struct Hosted {
+    app: App,
+    instance: Instance,
+    mount: Option<Mount>,
+}
+
+/// A file system mounted in the background
+struct Mount {
+    /// Dropping it tells the thread holding the FUSE session to unmount
+    unmount: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Repositories {
+    pub fn new(daemon_instance: &str) -> Repositories {
+        Repositories {
+            daemon_instance: daemon_instance.to_owned(),
+            hosted: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn run(&self, cmd: RepoCommand) -> DenebResult<Reply> {
+        let mut hosted = self.hosted.lock().unwrap();
+        let text = match cmd {
+            RepoCommand::Add(name) => {
+                self.add(&mut hosted, &name)?;
+                format!("Instance {} started", name)
+            }
+            RepoCommand::Mount(name) => {
+                if !hosted.contains_key(&name) {
+                    self.add(&mut hosted, &name)?;
+                }
+                let mount_point = mount(hosted.get_mut(&name).unwrap())?;
+                format!("Instance {} mounted at {:?}", name, mount_point)
+            }
+            RepoCommand::Remove(name) => {
+                hosted
+                    .remove(&name)
+                    .ok_or_else(|| err_msg(format!("Instance {} is not hosted", name)))?
+                    .stop();
+                format!("Instance {} stopped", name)
+            }
+            RepoCommand::List if hosted.is_empty() => "No hosted instances".to_owned(),
+            RepoCommand::List => hosted
+                .iter()
+                .map(|(name, h)| match h.mount {
+                    Some(_) => format!("{} mounted at {:?}\n", name, h.app.directories.mount_point),
+                    None => format!("{} not mounted\n", name),
+                })
+                .collect(),
+        };
+        Ok(Reply::Text(text))
+    }
+
+    /// Unmount and stop all the hosted instances
+    pub fn remove_all(&self) {
+        let hosted = std::mem::replace(&mut *self.hosted.lock().unwrap(), BTreeMap::new());
+        for (_, h) in hosted {
+            h.stop();
+        }
+    }
+
+    fn add(&self, hosted: &mut BTreeMap<String, Hosted>, name: &str) -> DenebResult<()> {
+        if name == self.daemon_instance || hosted.contains_key(name) {
+            return Err(err_msg(format!("Instance {} is already running", name)));
+        }
+        let app = App::for_instance(name)?;
+        check_repository(&app.directories.workspace)?;
+        // Two engines must never work on the same work dir
+        let socket_file = app.directories.workspace.join("cmd.sock");
+        let token = app.settings.control_token.as_ref().map(String::as_str);
+        if ask(&Endpoint::Socket(socket_file), token, Command::Ping).is_ok() {
+            return Err(err_msg(format!(
+                "Instance {} is run by another process",
+                name
+            )));
+        }
+
+        let instance = start_instance(&app, None)?;
+        info!("Hosting instance {}", name);
+        hosted.insert(
+            name.to_owned(),
+            Hosted {
+                app,
+                instance,
+                mount: None,
+            },
+        );
+        Ok(())
+    }
+}
+
+impl Hosted {
+    fn stop(self) {
+        if let Some(mount) = self.mount {
+            drop(mount.unmount);
+            let _ = mount.thread.join();
+        }
+        self.instance.stop();
+        let _ = remove_dir_all(self.app.directories.workspace.join("scratch"));
+        info!("Stopped instance {}", self.app.settings.instance_name);
+    }
+}
+
+/// Act on the signals received by the daemon, until one of them asks it to stop
+///
+/// SIGHUP reloads the configuration of the daemon's own instance, SIGUSR1
+/// commits its pending changes and SIGUSR2 logs its metrics. Returns false if
+/// the signal handler went away instead.
+pub fn serve_signals(
+    app: &App,
+    handle: &Handle,
+    logger: &Logger,
+    signals: &Receiver<SignalAction>,
+) -> bool {
+    let message = "Commit requested by signal";
+    for action in signals {
+        match action {
+            SignalAction::Stop => return true,
+            SignalAction::Reload => match reload(app, handle, logger) {
+                Ok(()) => info!("Configuration reloaded"),
+                Err(e) => error!("Could not reload the configuration: {}", e),
+            },
+            SignalAction::Commit => match handle.commit(Some(message.to_owned())) {
+                Ok(summary) => info!("{}:\n{}", message, summary),
+                Err(e) => error!("{} failed: {}", message, e),
+            },
+            SignalAction::DumpStats => match handle.metrics() {
+                Ok(metrics) => info!("\n{}", render(&app.settings.instance_name, &metrics, None)),
+                Err(e) => error!("Could not get the metrics of the engine: {}", e),
+            },
+        }
+    }
+    false
+}
+
+// Apply the settings which can change while the instance is running
+fn reload(app: &App, handle: &Handle, logger: &Logger) -> DenebResult<()> {
+    let settings = app.reload()?;
+    logger.reconfigure(&settings.log)?;
+    handle.reconfigure(settings.live())
+}
+
+// The FUSE session is held by its own thread until the instance is removed
+fn mount(hosted: &mut Hosted) -> DenebResult<PathBuf> {
+    let mount_point = hosted.app.directories.mount_point.clone();
+    if hosted.mount.is_some() {
+        return Err(err_msg(format!("Already mounted at {:?}", mount_point)));
+    }
+
+    let options = mount_options(&hosted.app);
+    let kernel = kernel_options(&hosted.app);
+    let handle = hosted.instance.handle.clone();
+    let (ready_tx, ready_rx) = channel(1);
+    let (unmount, unmount_rx) = channel::<()>(1);
+    let session_mount_point = mount_point.clone();
+    let thread = spawn(move || {
+        let session = Fs::spawn_mount(&session_mount_point, handle, &options, kernel);
+        match session {
+            Ok(_session) => {
+                let _ = ready_tx.send(Ok(()));
+                let _ = unmount_rx.recv();
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e.to_string()));
+            }
+        }
+    });
+    ready_rx.recv()?.map_err(err_msg)?;
+
+    hosted.mount = Some(Mount { unmount, thread });
+    Ok(mount_point)
+}
+
+fn run_command(handle: &Handle, cmd: Command) -> DenebResult<Reply> {
+    match cmd {
+        Command::Status => handle.manifest().map(Reply::Manifest),
+        Command::Log => handle.log().map(Reply::Log),
+        Command::Ping => handle.ping().map(Reply::Text),
+        Command::Commit(message) => handle.commit(message).map(Reply::Commit),
+        Command::Diff => handle.pending_changes().map(Reply::Diff),
+        Command::Restore {
+            at,
+            path,
+            overwrite,
+        } => handle
+            .restore(at, &path, overwrite)
+            .map(|restored| Reply::Text(format!("{:?} restored as {:?}", path, restored))),
+        Command::Metrics => handle.metrics().map(Reply::Metrics),
+        Command::Gc => handle.collect_garbage().map(Reply::Text),
+        Command::Prune {
+            keep_last,
+            keep_daily,
+            keep_weekly,
+        } => handle
+            .prune(PrunePolicy::new(keep_last, keep_daily, keep_weekly))
+            .map(Reply::Text),
+        Command::EmptyTrash => handle
+            .empty_trash()
+            .map(|deleted| Reply::Text(format!("{} entries deleted from the trash", deleted))),
+        Command::Operations => handle.running_operations().map(Reply::Operations),
+        Command::Cancel(token) => handle.cancel(token).map(|found| {
+            Reply::Text(if found {
+                format!("Operation {} cancelled", token)
+            } else {
+                format!("No running operation {}", token)
+            })
+        }),
+        Command::List(path) => list(handle, &path).map(Reply::Entries),
+        Command::Read(path) => read(handle, &path).map(Reply::Data),
+        Command::Stat(paths) => stat(handle, &paths).map(Reply::Entries),
+        Command::Hash(path) => file_digest(handle, &path).map(Reply::Text),
+        Command::Pin { path, pinned } => pin(handle, &path, pinned).map(Reply::Text),
+        Command::Dehydrate(path) => dehydrate(handle, &path).map(Reply::Text),
+        Command::Repo(_) => Err(err_msg("Unexpected command")),
+    }
+}
+
+fn resolve(handle: &Handle, path: &Path) -> DenebResult<(u64, FileAttributes)> {
+    handle
+        .resolve_path(&RequestId::default(), path)?
+        .ok_or_else(|| err_msg(format!("{:?} not found", path)))
+}
+
+// The attributes of several paths, looked up with one request per directory
+fn stat(handle: &Handle, paths: &[PathBuf]) -> DenebResult<Vec<(PathBuf, FileAttributes)>> {
+    let id = RequestId::default();
+    let mut found = vec![None; paths.len()];
+    let mut by_parent = BTreeMap::new();
+    for (pos, path) in paths.iter().enumerate() {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => by_parent
+                .entry(parent)
+                .or_insert_with(Vec::new)
+                .push((pos, name.to_owned())),
+            _ => found[pos] = Some(resolve(handle, path)?.1),
+        }
+    }
+
+    for (parent, names) in by_parent {
+        let (index, _) = resolve(handle, parent)?;
+        let (positions, names): (Vec<_>, Vec<_>) = names.into_iter().unzip();
+        for (pos, attrs) in positions
+            .into_iter()
+            .zip(handle.lookup_batch(&id, index, names)?)
+        {
+            found[pos] = attrs;
+        }
+    }
+
+    paths
+        .iter()
+        .zip(found)
+        .map(|(path, attrs)| {
+            attrs
+                .map(|attrs| (path.clone(), attrs))
+                .ok_or_else(|| err_msg(format!("{:?} not found", path)))
+        })
+        .collect()
+}
+
+fn file_digest(handle: &Handle, path: &Path) -> DenebResult<String> {
+    let (index, _) = resolve(handle, path)?;
+    handle
+        .file_digest(&RequestId::default(), index)?
+        .map(|digest| digest.to_string())
+        .ok_or_else(|| err_msg(format!("No digest recorded for {:?}", path)))
+}
+
+fn pin(handle: &Handle, path: &Path, pinned: bool) -> DenebResult<String> {
+    let (index, _) = resolve(handle, path)?;
+    let was_pinned = handle.set_pinned(index, pinned)?;
+    Ok(match (was_pinned, pinned) {
+        (false, true) => format!("{:?} pinned", path),
+        (true, true) => format!("{:?} was already pinned", path),
+        (true, false) => format!("{:?} unpinned", path),
+        (false, false) => format!("{:?} was not pinned", path),
+    })
+}
+
+fn dehydrate(handle: &Handle, path: &Path) -> DenebResult<String> {
+    let (index, _) = resolve(handle, path)?;
+    let (chunks, bytes) = handle.dehydrate(index, None)?;
+    Ok(format!(
+        "{:?} dehydrated: {} chunks, {} bytes dropped",
+        path, chunks, bytes
+    ))
+}
+
+// Drop the local copies of the files untouched for `untouched_for`, until
+// the instance is stopped
+fn dehydrate_periodically(handle: &Handle, untouched_for: Duration, stop: &Receiver<()>) {
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(DEHYDRATION_INTERVAL) {
+        match handle
+            .get_root_attr()
+            .and_then(|root| handle.dehydrate(root.index, Some(untouched_for)))
+        {
+            Ok((0, _)) => (),
+            Ok((chunks, bytes)) => info!("Dehydrated: {} chunks, {} bytes dropped", chunks, bytes),
+            Err(e) => error!("Dehydration failed: {}", e),
+        }
+    }
+}
+
+// The entries of a directory, or the file itself
+fn list(handle: &Handle, path: &Path) -> DenebResult<Vec<(PathBuf, FileAttributes)>> {
+    let id = RequestId::default();
+    let (index, attrs) = resolve(handle, path)?;
+    if attrs.kind != FileType::Directory {
+        return Ok(vec![(path.to_owned(), attrs)]);
+    }
+
+    handle.open_dir(&id, index, 0)?;
+    let entries = handle.read_dir(&id, index, 0).map(|entries| {
+        entries
+            .into_iter()
+            .filter(|(_, name, _)| name != Path::new(".") && name != Path::new(".."))
+            .map(|(_, name, attrs)| (name, attrs))
+            .collect::<Vec<_>>()
+    });
+    handle.release_dir(&id, index, 0)?;
+
+    let mut entries = entries?;
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+}
+
+// The contents of a file, or the target of a symbolic link
+fn read(handle: &Handle, path: &Path) -> DenebResult<Vec<u8>> {
+    let id = RequestId::default();
+    let (index, attrs) = resolve(handle, path)?;
+    match attrs.kind {
+        FileType::Directory => return Err(err_msg(format!("{:?} is a directory", path))),
+        FileType::Symlink => return Ok(handle.read_link(&id, index)?.into_os_string().into_vec()),
+        _ => {}
+    }
+
+    handle.open_file(&id, index, 0)?;
+    let mut data = Vec::new();
+    let result = loop {
+        match handle.read_data(&id, index, data.len() as i64, READ_SIZE) {
+            Ok(ref buf) if buf.is_empty() => break Ok(()),
+            Ok(buf) => data.extend_from_slice(&buf),
+            Err(e) => break Err(e),
+        }
+    };
+    handle.release_file(&id, index, 0, 0, false)?;
+    result.map(|_| data)
+}
@@ -4,6 +4,9 @@
 extern crate deneb_fuse;
 
 pub mod app;
+#[cfg(feature = "fuse_module")]
+pub mod daemon;
 pub mod logging;
 pub mod talk;
+pub mod top;
 pub mod util;
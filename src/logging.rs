@@ -1,5 +1,5 @@
 use {
-    deneb_core::errors::DenebResult,
+    deneb_core::errors::{DenebError, DenebResult},
     failure::err_msg,
     log::LevelFilter,
     log4rs::{
@@ -7,24 +7,97 @@ use {
             console::ConsoleAppender,
             rolling_file::{
                 policy::compound::{
-                    roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger,
-                    CompoundPolicy,
+                    roll::fixed_window::FixedWindowRoller, trigger::Trigger, CompoundPolicy,
                 },
-                RollingFileAppender,
+                LogFile, RollingFileAppender,
             },
         },
-        config::{Appender, Config, Root},
+        config::{Appender, Config, Logger as ModuleLogger, Root},
+        encode::{json::JsonEncoder, pattern::PatternEncoder, Encode},
+        Handle,
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        error::Error,
+        path::{Path, PathBuf},
+        str::FromStr,
+        sync::Mutex,
+        time::{Duration, Instant},
     },
-    std::path::Path,
 };
 
-const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024; // 10 MB
-const MAX_NUM_LOGS: u32 = 5;
+/// Format of the messages written to the log file
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line of text per message
+    Text,
+    /// One JSON object per message, for log aggregation systems
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = DenebError;
+
+    fn from_str(s: &str) -> Result<LogFormat, DenebError> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(DenebError::CommandLineParameter(
+                "log_format: ".to_string() + s,
+            )),
+        }
+    }
+}
+
+/// What is logged, and how the log file is written and rotated
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogOptions {
+    pub level: LevelFilter,
+    /// Levels of single modules, like "deneb_core::store", which replace `level`
+    pub module_levels: BTreeMap<String, LevelFilter>,
+    pub format: LogFormat,
+    /// Size in bytes above which the log file is rotated
+    pub max_size: u64,
+    /// Age after which the log file is rotated, whatever its size
+    pub max_age: Option<Duration>,
+    /// Number of rotated log files which are kept
+    pub max_files: u32,
+}
+
+/// The logger of the process, which can be reconfigured while it runs
+pub struct Logger {
+    handle: Handle,
+    foreground: bool,
+    dir: PathBuf,
+}
+
+impl Logger {
+    /// Log according to `options` from now on
+    pub fn reconfigure(&self, options: &LogOptions) -> DenebResult<()> {
+        self.handle
+            .set_config(build_config(options, self.foreground, &self.dir)?);
+        Ok(())
+    }
+}
+
+pub fn init_logger(options: &LogOptions, foreground: bool, dir: &Path) -> DenebResult<Logger> {
+    let handle = ::log4rs::init_config(build_config(options, foreground, dir)?)?;
 
-pub fn init_logger(level: LevelFilter, foreground: bool, dir: &Path) -> DenebResult<()> {
+    Ok(Logger {
+        handle,
+        foreground,
+        dir: dir.to_owned(),
+    })
+}
+
+// Messages are always written to the log file, in the requested format, and
+// to the console as text when running in the foreground
+fn build_config(options: &LogOptions, foreground: bool, dir: &Path) -> DenebResult<Config> {
     let stdout = ConsoleAppender::builder().build();
     let policy = Box::new(CompoundPolicy::new(
-        Box::new(SizeTrigger::new(MAX_LOG_SIZE)),
+        Box::new(RotationTrigger::new(options.max_size, options.max_age)),
         Box::new(
             FixedWindowRoller::builder()
                 .base(0)
@@ -32,24 +105,68 @@ pub fn init_logger(level: LevelFilter, foreground: bool, dir: &Path) -> DenebRes
                     dir.join("deneb.log.{}.gz")
                         .to_str()
                         .ok_or_else(|| err_msg("Invalid log rotation pattern."))?,
-                    MAX_NUM_LOGS,
+                    options.max_files,
                 )
                 .map_err(|_| err_msg("Could not configure log rotation."))?,
         ),
     ));
-    let log_file = RollingFileAppender::builder().build(dir.join("deneb.log"), policy)?;
+    let encoder: Box<dyn Encode> = match options.format {
+        LogFormat::Text => Box::new(PatternEncoder::default()),
+        LogFormat::Json => Box::new(JsonEncoder::new()),
+    };
+    let log_file = RollingFileAppender::builder()
+        .encoder(encoder)
+        .build(dir.join("deneb.log"), policy)?;
 
     let mut root_builder = Root::builder().appender("log_file");
     if foreground {
         root_builder = root_builder.appender("stdout");
     }
 
-    let config = Config::builder()
+    Ok(Config::builder()
         .appender(Appender::builder().build("stdout", Box::new(stdout)))
         .appender(Appender::builder().build("log_file", Box::new(log_file)))
-        .build(root_builder.build(level))?;
+        .loggers(
+            options
+                .module_levels
+                .iter()
+                .map(|(module, level)| ModuleLogger::builder().build(module.as_str(), *level)),
+        )
+        .build(root_builder.build(options.level))?)
+}
 
-    ::log4rs::init_config(config)?;
+/// Rotates the log file once it's larger than `max_size`, or once it was
+/// written to for longer than `max_age`
+#[derive(Debug)]
+struct RotationTrigger {
+    max_size: u64,
+    max_age: Option<Duration>,
+    started: Mutex<Instant>,
+}
 
-    Ok(())
+impl RotationTrigger {
+    fn new(max_size: u64, max_age: Option<Duration>) -> RotationTrigger {
+        RotationTrigger {
+            max_size,
+            max_age,
+            started: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Trigger for RotationTrigger {
+    fn trigger(&self, file: &LogFile) -> Result<bool, Box<dyn Error + Sync + Send>> {
+        let mut started = self
+            .started
+            .lock()
+            .map_err(|_| "Log rotation lock poisoned")?;
+        let expired = self.max_age.map_or(false, |max_age| {
+            file.len() > 0 && started.elapsed() >= max_age
+        });
+        if file.len() > self.max_size || expired {
+            *started = Instant::now();
+            return Ok(true);
+        }
+        Ok(false)
+    }
 }
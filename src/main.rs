@@ -1,23 +1,63 @@
 use {
     crossbeam_channel::bounded as channel,
     deneb::{
-        app::App,
+        app::{Action, App},
+        daemon::{
+            kernel_options, mount_options, serve_signals, start_instance, stop_engine, Repositories,
+        },
         logging::init_logger,
-        talk::{listen, Command},
         util::{block_signals, fork, set_signal_handler},
     },
     deneb_core::{
-        catalog::CatalogType, engine::start_engine, errors::DenebResult, store::StoreType,
+        engine::{check_repository, open_bundle},
+        errors::DenebResult,
+        gateway::start_gateway,
     },
     deneb_fuse::fs::Fs,
     failure::ResultExt,
     log::info,
     scopeguard::defer,
-    std::fs::remove_dir_all,
+    std::{fs::remove_dir_all, net::TcpListener, path::PathBuf, sync::Arc, thread::spawn},
 };
 
 fn main() -> DenebResult<()> {
-    let app = App::init()?;
+    let app = Arc::new(App::init()?);
+
+    if let Action::DumpConfig = app.action {
+        print!("{}", app.dump_config());
+        return Ok(());
+    }
+
+    if let Action::Init {
+        ref sync_dir,
+        encrypted,
+        digest,
+    } = app.action
+    {
+        deneb_core::init()?;
+        let manifest =
+            app.init_repository(sync_dir.as_ref().map(PathBuf::as_path), encrypted, digest)?;
+        println!(
+            "Initialized instance {} in {:?}\n{}",
+            app.settings.instance_name, app.directories.workspace, manifest
+        );
+        return Ok(());
+    }
+
+    // The work dir of a bundle is prepared before forking, so that errors are shown
+    if let Some(ref bundle) = app.settings.bundle {
+        open_bundle(
+            bundle,
+            &app.directories.workspace,
+            app.settings.encryption_key.clone(),
+            app.settings.chunk_size,
+        )
+        .context("Could not open the bundle")?;
+    }
+
+    // Checked before forking, so that the error is shown
+    check_repository(&app.directories.workspace)
+        .context("The instance was never initialized, run \"deneb init\" first")?;
 
     // If not instructed to stay in the foreground, do a double-fork
     // and exit in the parent and child processes. Only the grandchild
@@ -33,8 +73,8 @@ fn main() -> DenebResult<()> {
     // Initialize deneb-core
     deneb_core::init()?;
 
-    init_logger(
-        app.settings.log_level,
+    let logger = init_logger(
+        &app.settings.log,
         app.settings.foreground,
         &app.directories.log,
     )
@@ -48,44 +88,40 @@ fn main() -> DenebResult<()> {
         let _ = remove_dir_all(&app.directories.workspace.join("scratch"));
     }}
 
-    // Create the file system data structure
-    let handle = start_engine(
-        CatalogType::Lmdb,
-        StoreType::OnDisk,
-        app.directories.workspace.clone(),
-        Some(app.settings.encryption_key.clone()),
-        None,
-        app.settings.chunk_size,
-        1000,
-        app.settings.auto_commit_interval,
-    )?;
-
-    // Start a listener for commands received from deneb-cli
-    let handle2 = handle.clone();
-    listen(
-        app.directories.workspace.join("cmd.sock"),
-        move |cmd| match cmd {
-            Command::Status => Ok("".to_string()),
-            Command::Ping => handle2.ping(),
-            Command::Commit => handle2.commit(),
-        },
-    )?;
+    // Start the instance, which also manages the ones hosted by this process
+    let repositories = Arc::new(Repositories::new(&app.settings.instance_name));
+    let handle = start_instance(&app, Some(Arc::clone(&repositories)))?.handle;
 
-    let options = Fs::make_options(&[
-        "negative_vncache".to_string(),
-        format!("fsname={}", app.fs_name()),
-        format!("volname={}", app.settings.instance_name),
-    ]);
+    // Serve the chunks of the repository to other instances
+    if let Some(ref address) = app.settings.gateway_address {
+        start_gateway(
+            TcpListener::bind(address)?,
+            app.directories.workspace.clone(),
+            app.settings.gateway_token.clone(),
+            app.settings.chunk_size,
+        )?;
+    }
+
+    let options = mount_options(&app);
+    let kernel = kernel_options(&app);
+
+    // Install a signal handler for SIGINT, SIGTERM, SIGHUP, SIGUSR1 and SIGUSR2
+    let (tx, rx) = channel(1);
+    let _th = set_signal_handler(tx);
 
     if app.settings.foreground {
-        let session = Fs::spawn_mount(&app.directories.mount_point, handle.clone(), &options)?;
+        let session = Fs::spawn_mount(
+            &app.directories.mount_point,
+            handle.clone(),
+            &options,
+            kernel,
+        )?;
 
-        // Install a signal handler for SIGINT, SIGHUP and SIGTERM, and wait
-        let (tx, rx) = channel(1);
-        let _th = set_signal_handler(tx);
-        rx.recv()?;
+        // Wait for a signal asking to stop
+        serve_signals(&app, &handle, &logger, &rx);
 
-        handle.stop_engine();
+        repositories.remove_all();
+        stop_engine(&handle, app.settings.checkpoint_on_stop);
 
         // Force unmount the file system
         if app.settings.force_unmount {
@@ -93,8 +129,26 @@ fn main() -> DenebResult<()> {
             session.force_unmount()?;
         }
     } else {
-        Fs::mount(&app.directories.mount_point, handle.clone(), &options)?;
-        handle.stop_engine();
+        // The daemon only stops when its file system is unmounted
+        let signals_app = Arc::clone(&app);
+        let signals_handle = handle.clone();
+        spawn(move || {
+            while serve_signals(&signals_app, &signals_handle, &logger, &rx) {
+                info!(
+                    "Unmount {:?} to stop the daemon",
+                    signals_app.directories.mount_point
+                );
+            }
+        });
+
+        Fs::mount(
+            &app.directories.mount_point,
+            handle.clone(),
+            &options,
+            kernel,
+        )?;
+        repositories.remove_all();
+        stop_engine(&handle, app.settings.checkpoint_on_stop);
     }
 
     Ok(())
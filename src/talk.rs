@@ -1,41 +1,209 @@
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+use nix::{errno::Errno, libc::getpeereid};
 use {
-    bincode::{deserialize, serialize},
-    deneb_core::errors::DenebResult,
-    log::{error, info},
+    bincode::{config, deserialize, deserialize_from, serialize, serialize_into},
+    deneb_core::{
+        cas::Digest,
+        engine::{CommitSummary, PendingChanges},
+        errors::{DenebResult, UnixError},
+        inode::FileAttributes,
+        manifest::Manifest,
+        metrics::Metrics,
+    },
+    failure::err_msg,
+    log::{error, info, warn},
+    native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector},
+    nix::unistd::geteuid,
     serde::{Deserialize, Serialize},
+    sodiumoxide::utils::memcmp,
     std::{
-        fs::remove_file,
+        fs::{read, remove_file, set_permissions, Permissions},
         io::{Read, Write},
-        net::Shutdown,
-        os::unix::net::{UnixListener, UnixStream},
-        path::Path,
+        net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+        os::unix::{
+            fs::PermissionsExt,
+            io::AsRawFd,
+            net::{UnixListener, UnixStream},
+        },
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
         thread::spawn,
+        time::Duration,
     },
 };
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+/// Largest command accepted over TCP, before its sender is authenticated
+const MAX_REMOTE_COMMAND_SIZE: u64 = 64 * 1024;
+/// How long a remote client may take to send its command
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of remote connections answered at the same time
+const MAX_REMOTE_CONNECTIONS: usize = 16;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Command {
     Status,
+    Log,
     Ping,
-    Commit,
+    Commit(Option<String>),
+    Diff,
+    Restore {
+        at: Digest,
+        path: PathBuf,
+        overwrite: bool,
+    },
+    Metrics,
+    Gc,
+    Prune {
+        keep_last: Option<usize>,
+        keep_daily: Option<u64>,
+        keep_weekly: Option<u64>,
+    },
+    EmptyTrash,
+    Operations,
+    Cancel(u64),
+    List(PathBuf),
+    Read(PathBuf),
+    Stat(Vec<PathBuf>),
+    Hash(PathBuf),
+    Pin {
+        path: PathBuf,
+        pinned: bool,
+    },
+    Dehydrate(PathBuf),
+    Repo(RepoCommand),
+}
+
+/// Management of the instances hosted by a daemon, besides its own
+#[derive(Clone, Serialize, Deserialize)]
+pub enum RepoCommand {
+    /// Start the engine of an instance
+    Add(String),
+    /// Mount the file system of an instance, starting it if needed
+    Mount(String),
+    /// Unmount and stop an instance
+    Remove(String),
+    List,
 }
 
-pub fn listen<P, A>(socket_file: P, action: A) -> DenebResult<()>
+/// A command, with the token presented by its sender
+#[derive(Serialize, Deserialize)]
+struct Message {
+    token: Option<String>,
+    command: Command,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Reply {
+    Text(String),
+    Manifest(Manifest),
+    Log(Vec<Manifest>),
+    Operations(Vec<(u64, String)>),
+    Commit(CommitSummary),
+    Diff(PendingChanges),
+    Metrics(Metrics),
+    Entries(Vec<(PathBuf, FileAttributes)>),
+    Data(Vec<u8>),
+    Error(String),
+}
+
+/// Where the commands of deneb-cli are sent
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    /// The command socket of an instance on this host
+    Socket(PathBuf),
+    /// The remote control listener of an instance on another host, whose
+    /// certificate is checked against the system's authorities and `ca_cert`
+    Remote {
+        address: String,
+        ca_cert: Option<PathBuf>,
+    },
+}
+
+/// How an instance receives commands over TCP, from other hosts
+#[derive(Clone, Debug)]
+pub struct RemoteControl {
+    /// Address and port listened on
+    pub address: String,
+    /// Certificate and private key of the listener, in a PKCS #12 archive
+    pub identity: PathBuf,
+    /// Password of the archive
+    pub identity_password: String,
+    /// Token which the commands need to present
+    pub token: String,
+}
+
+enum Bound {
+    Socket(PathBuf),
+    Tcp(SocketAddr),
+}
+
+/// A command listener started with `listen` or `listen_remote`
+pub struct Listener {
+    bound: Bound,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Listener {
+    /// Stop accepting commands and remove the socket file, if any
+    ///
+    /// Commands which are being answered are not interrupted.
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the listener thread, which is waiting for a connection
+        match self.bound {
+            Bound::Socket(ref socket_file) => {
+                let _ = UnixStream::connect(socket_file);
+                let _ = remove_file(socket_file);
+            }
+            Bound::Tcp(address) => {
+                let _ = TcpStream::connect(address);
+            }
+        }
+    }
+}
+
+/// Answer the commands sent to `socket_file` with `action`
+///
+/// Only the user running the process and root can reach the socket, and the
+/// commands sent by other users are refused. With a `token`, the commands are
+/// also refused unless they present it, e.g. when the socket is forwarded to
+/// another host.
+pub fn listen<P, A>(socket_file: P, token: Option<String>, action: Arc<A>) -> DenebResult<Listener>
 where
     P: AsRef<Path> + Send + 'static,
-    A: Fn(Command) -> DenebResult<String> + Send + 'static,
+    A: Fn(Command) -> DenebResult<Reply> + Send + Sync + 'static,
 {
+    let handle = Listener {
+        bound: Bound::Socket(socket_file.as_ref().to_owned()),
+        stopped: Arc::new(AtomicBool::new(false)),
+    };
+    let stopped = Arc::clone(&handle.stopped);
     spawn(move || {
         let _ = remove_file(&socket_file);
-        match UnixListener::bind(socket_file) {
+        match UnixListener::bind(&socket_file) {
             Ok(listener) => {
+                set_permissions(&socket_file, Permissions::from_mode(0o600))?;
+                let token = Arc::new(token);
                 for stream in listener.incoming() {
-                    let mut socket = stream?;
-                    let mut bytes = Vec::new();
-                    socket.read_to_end(&mut bytes)?;
-                    let cmd = deserialize(&bytes)?;
-                    let reply = action(cmd)?;
-                    socket.write_all(reply.as_bytes())?;
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let socket = stream?;
+                    let action = Arc::clone(&action);
+                    let token = Arc::clone(&token);
+                    // Each command is answered on its own thread, such that a long
+                    // running command (e.g. gc) can be cancelled by another one
+                    spawn(move || {
+                        let token = token.as_ref().as_ref().map(String::as_str);
+                        if let Err(e) = answer(socket, token, &*action) {
+                            error!("Could not answer command: {}", e);
+                        }
+                    });
                 }
             }
             Err(e) => {
@@ -48,18 +216,188 @@ where
 
     info!("Started command listener");
 
+    Ok(handle)
+}
+
+/// Answer the commands sent over TCP to `remote.address` with `action`
+///
+/// The connections are encrypted with TLS, and only the commands presenting
+/// the token of `remote` are answered. At most `MAX_REMOTE_CONNECTIONS`
+/// connections are answered at once, the others are closed right away.
+pub fn listen_remote<A>(remote: &RemoteControl, action: Arc<A>) -> DenebResult<Listener>
+where
+    A: Fn(Command) -> DenebResult<Reply> + Send + Sync + 'static,
+{
+    let identity = Identity::from_pkcs12(&read(&remote.identity)?, &remote.identity_password)?;
+    let acceptor = Arc::new(TlsAcceptor::new(identity)?);
+    let listener = TcpListener::bind(&remote.address)?;
+    let address = listener.local_addr()?;
+    let handle = Listener {
+        bound: Bound::Tcp(address),
+        stopped: Arc::new(AtomicBool::new(false)),
+    };
+    let stopped = Arc::clone(&handle.stopped);
+    let token = Arc::new(remote.token.clone());
+    let connections = Arc::new(AtomicUsize::new(0));
+    spawn(move || {
+        for stream in listener.incoming() {
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Could not accept remote connection: {}", e);
+                    continue;
+                }
+            };
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_REMOTE_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                warn!("Too many remote connections, closing a new one");
+                continue;
+            }
+            let acceptor = Arc::clone(&acceptor);
+            let action = Arc::clone(&action);
+            let token = Arc::clone(&token);
+            let connections = Arc::clone(&connections);
+            spawn(move || {
+                if let Err(e) = answer_remote(&acceptor, stream, &token, &*action) {
+                    warn!("Could not answer remote command: {}", e);
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+
+    info!("Started remote command listener on {}", address);
+
+    Ok(handle)
+}
+
+fn answer<A>(mut socket: UnixStream, token: Option<&str>, action: &A) -> DenebResult<()>
+where
+    A: Fn(Command) -> DenebResult<Reply>,
+{
+    let mut bytes = Vec::new();
+    socket.read_to_end(&mut bytes)?;
+    let message: Message = deserialize(&bytes)?;
+    let owner = geteuid().as_raw();
+    let peer = peer_uid(&socket)?;
+    let reply = if peer != owner && peer != 0 {
+        warn!("Refused a command sent by user {}", peer);
+        Reply::Error(format!("Commands are only accepted from user {}", owner))
+    } else if !presents(token, message.token.as_ref().map(String::as_str)) {
+        warn!("Refused a command without a valid token");
+        Reply::Error("Invalid or missing control token".to_owned())
+    } else {
+        action(message.command).unwrap_or_else(|e| Reply::Error(e.to_string()))
+    };
+    socket.write_all(&serialize(&reply)?)?;
+    Ok(())
+}
+
+// Remote clients are not trusted until they presented the token, so the size
+// of what they send is limited
+fn answer_remote<A>(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    token: &str,
+    action: &A,
+) -> DenebResult<()>
+where
+    A: Fn(Command) -> DenebResult<Reply>,
+{
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(REMOTE_TIMEOUT))?;
+    let mut stream = acceptor
+        .accept(stream)
+        .map_err(|e| err_msg(format!("TLS handshake with {} failed: {}", peer, e)))?;
+    let message: Message = config()
+        .limit(MAX_REMOTE_COMMAND_SIZE)
+        .deserialize_from(&mut stream)?;
+    let reply = if !presents(Some(token), message.token.as_ref().map(String::as_str)) {
+        warn!("Refused a command sent from {} without a valid token", peer);
+        Reply::Error("Invalid or missing control token".to_owned())
+    } else {
+        info!("Answering a command sent from {}", peer);
+        action(message.command).unwrap_or_else(|e| Reply::Error(e.to_string()))
+    };
+    serialize_into(&mut stream, &reply)?;
+    stream.flush()?;
+    let _ = stream.shutdown();
     Ok(())
 }
 
-pub fn ask<P: AsRef<Path>>(socket_file: P, cmd: Command) -> DenebResult<String> {
-    let mut stream = UnixStream::connect(&socket_file)?;
+// The tokens are compared in constant time, so that they can't be guessed
+// from how long the replies take
+fn presents(expected: Option<&str>, given: Option<&str>) -> bool {
+    match (expected, given) {
+        (None, _) => true,
+        (Some(expected), Some(given)) => memcmp(expected.as_bytes(), given.as_bytes()),
+        (Some(_), None) => false,
+    }
+}
+
+/// The user of the process at the other end of a socket
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(socket: &UnixStream) -> Result<u32, UnixError> {
+    Ok(getsockopt(socket.as_raw_fd(), PeerCredentials)?.uid())
+}
+
+/// The user of the process at the other end of a socket
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(socket: &UnixStream) -> Result<u32, UnixError> {
+    let (mut uid, mut gid) = (0, 0);
+    Errno::result(unsafe { getpeereid(socket.as_raw_fd(), &mut uid, &mut gid) })?;
+    Ok(uid)
+}
+
+/// Send a command to the instance listening on `endpoint`, presenting `token`
+/// if it needs one
+pub fn ask(endpoint: &Endpoint, token: Option<&str>, cmd: Command) -> DenebResult<Reply> {
+    let message = Message {
+        token: token.map(str::to_owned),
+        command: cmd,
+    };
+    match endpoint {
+        Endpoint::Socket(socket_file) => ask_socket(socket_file, &message),
+        Endpoint::Remote { address, ca_cert } => ask_remote(address, ca_cert.as_ref(), &message),
+    }
+}
 
-    let msg = serialize(&cmd)?;
+// The end of the command is marked by closing the writing half of the socket
+fn ask_socket(socket_file: &Path, message: &Message) -> DenebResult<Reply> {
+    let mut stream = UnixStream::connect(socket_file)?;
+
+    let msg = serialize(message)?;
     stream.write_all(&msg)?;
     stream.shutdown(Shutdown::Write)?;
 
-    let mut reply = String::new();
-    stream.read_to_string(&mut reply)?;
+    let mut bytes = Vec::new();
+    stream.read_to_end(&mut bytes)?;
+
+    Ok(deserialize(&bytes)?)
+}
 
-    Ok(reply)
+// TLS has no half-closed connections, the messages are read one at a time
+fn ask_remote(address: &str, ca_cert: Option<&PathBuf>, message: &Message) -> DenebResult<Reply> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ca_cert) = ca_cert {
+        builder.add_root_certificate(Certificate::from_pem(&read(ca_cert)?)?);
+    }
+    let connector = builder.build()?;
+    // The certificate of the listener is checked against its host name
+    let host = address
+        .rsplitn(2, ':')
+        .last()
+        .unwrap_or(address)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let stream = TcpStream::connect(address)?;
+    let mut stream = connector
+        .connect(host, stream)
+        .map_err(|e| err_msg(format!("TLS handshake with {} failed: {}", address, e)))?;
+    serialize_into(&mut stream, message)?;
+    stream.flush()?;
+    Ok(deserialize_from(&mut stream)?)
 }
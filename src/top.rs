@@ -0,0 +1,142 @@
+use {
+    crate::talk::{ask, Command, Endpoint, Reply},
+    deneb_core::{errors::DenebResult, metrics::Metrics},
+    failure::err_msg,
+    std::{
+        io::{stdout, Write},
+        thread::sleep,
+        time::{Duration, Instant},
+    },
+};
+
+// Clear the terminal and move the cursor to the top left corner
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Periodically request the metrics of a Deneb instance and display them
+///
+/// Operation rates are computed from the difference between two successive
+/// samples. The function only returns if the instance can't be reached.
+pub fn run(
+    endpoint: &Endpoint,
+    token: Option<&str>,
+    instance_name: &str,
+    interval: Duration,
+) -> DenebResult<()> {
+    let mut previous: Option<(Instant, Metrics)> = None;
+    loop {
+        let metrics = match ask(endpoint, token, Command::Metrics)? {
+            Reply::Metrics(metrics) => metrics,
+            Reply::Error(msg) => return Err(err_msg(msg)),
+            _ => return Err(err_msg("Unexpected reply to metrics request")),
+        };
+        let now = Instant::now();
+        let elapsed = previous
+            .as_ref()
+            .map(|(then, prev)| (now.duration_since(*then), prev));
+
+        let mut out = stdout();
+        write!(out, "{}", CLEAR_SCREEN)?;
+        write!(out, "{}", render(instance_name, &metrics, elapsed))?;
+        out.flush()?;
+
+        previous = Some((now, metrics));
+        sleep(interval);
+    }
+}
+
+pub fn render(
+    instance_name: &str,
+    metrics: &Metrics,
+    previous: Option<(Duration, &Metrics)>,
+) -> String {
+    let mut text = format!("Deneb instance: {}\n\n", instance_name);
+    text += &format!(
+        "{:<12} {:>12} {:>10} {:>24}\n",
+        "operation", "total", "ops/s", "queued p50/p95/p99"
+    );
+    for (name, count) in &metrics.operations {
+        let rate = previous.map_or(0.0, |(elapsed, prev)| {
+            let before = prev.operations.get(name).cloned().unwrap_or(0);
+            per_second(count.saturating_sub(before), elapsed)
+        });
+        let queued = metrics.queue_latency.get(name).map_or_else(
+            || "-".to_string(),
+            |latency| {
+                format!(
+                    "{}/{}/{}",
+                    micros(latency.p50),
+                    micros(latency.p95),
+                    micros(latency.p99)
+                )
+            },
+        );
+        text += &format!("{:<12} {:>12} {:>10.1} {:>24}\n", name, count, rate, queued);
+    }
+    text += "\n";
+    text += &match metrics.cache_hit_rate() {
+        Some(rate) => format!("cache hit rate: {:.1}%\n", rate * 100.0),
+        None => "cache hit rate: -\n".to_string(),
+    };
+    text += &format!("dirty bytes:    {}\n", metrics.dirty_bytes);
+    text += &format!("open files:     {}\n", metrics.open_files);
+    text += &format!(
+        "memory:         {} ({} file data, {} inodes, {} dirs)\n",
+        metrics.memory.total(),
+        metrics.memory.file_data,
+        metrics.memory.inodes,
+        metrics.memory.dirs
+    );
+    text += &format!(
+        "queued:         {} interactive, {} maintenance (of {})\n",
+        metrics.interactive_queued, metrics.maintenance_queued, metrics.queue_capacity
+    );
+    if metrics.blocked_sends > 0 || metrics.shed_requests > 0 || metrics.slow_requests > 0 {
+        text += &format!(
+            "overload:       {} blocked, {} shed, {} slow\n",
+            metrics.blocked_sends, metrics.shed_requests, metrics.slow_requests
+        );
+    }
+    if metrics.pinned_entries > 0 {
+        text += &format!(
+            "pinned:         {} entries, {} chunks\n",
+            metrics.pinned_entries, metrics.pinned_chunks
+        );
+    }
+    if metrics.dedup_hits > 0 {
+        text += &format!("dedup hits:     {}\n", metrics.dedup_hits);
+    }
+    if metrics.corrupt_chunks > 0 {
+        text += &format!("corrupt chunks: {}\n", metrics.corrupt_chunks);
+    }
+    if metrics.remote_bytes_downloaded > 0 || metrics.remote_bytes_uploaded > 0 {
+        text += &format!(
+            "remote bytes:   {} down, {} up\n",
+            metrics.remote_bytes_downloaded, metrics.remote_bytes_uploaded
+        );
+        text += &format!(
+            "remote errors:  {} retried, {} failed\n",
+            metrics.remote_retries, metrics.remote_failures
+        );
+    }
+    text
+}
+
+// Latencies are shown with the unit which keeps them short
+fn micros(latency: u64) -> String {
+    if latency < 1000 {
+        format!("{}us", latency)
+    } else if latency < 1_000_000 {
+        format!("{:.1}ms", latency as f64 / 1e3)
+    } else {
+        format!("{:.1}s", latency as f64 / 1e6)
+    }
+}
+
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+    if secs > 0.0 {
+        count as f64 / secs
+    } else {
+        0.0
+    }
+}
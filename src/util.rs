@@ -1,6 +1,7 @@
 use {
     crossbeam_channel::Sender,
     deneb_core::errors::UnixError,
+    log::info,
     nix::{
         sys::signal::{pthread_sigmask, SigSet, SigmaskHow, Signal},
         unistd::{fork as nix_fork, ForkResult},
@@ -8,25 +9,55 @@ use {
     std::thread::{spawn, JoinHandle},
 };
 
-pub fn block_signals() -> Result<(), UnixError> {
+/// What the process is asked to do by a signal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignalAction {
+    /// SIGINT or SIGTERM
+    Stop,
+    /// SIGHUP
+    Reload,
+    /// SIGUSR1
+    Commit,
+    /// SIGUSR2, or SIGINFO on macOS and FreeBSD
+    DumpStats,
+}
+
+fn handled_signals() -> SigSet {
     let mut sigs = SigSet::empty();
     sigs.add(Signal::SIGINT);
     sigs.add(Signal::SIGTERM);
     sigs.add(Signal::SIGHUP);
-    pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&sigs), None)?;
+    sigs.add(Signal::SIGUSR1);
+    sigs.add(Signal::SIGUSR2);
+    // Sent by Ctrl-T, for the status of the foreground process
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    sigs.add(Signal::SIGINFO);
+    sigs
+}
+
+pub fn block_signals() -> Result<(), UnixError> {
+    pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&handled_signals()), None)?;
     Ok(())
 }
 
-pub fn set_signal_handler(tx: Sender<()>) -> JoinHandle<()> {
+/// Wait for signals in a new thread and send the matching actions to `tx`
+///
+/// The thread keeps waiting until `tx` is disconnected.
+pub fn set_signal_handler(tx: Sender<SignalAction>) -> JoinHandle<()> {
     spawn(move || {
-        let mut sigs = SigSet::empty();
-        sigs.add(Signal::SIGINT);
-        sigs.add(Signal::SIGTERM);
-        sigs.add(Signal::SIGHUP);
-        if let Ok(sig) = sigs.wait() {
-            println!("Received signal: {:?}", sig);
-            if sig == Signal::SIGINT || sig == Signal::SIGTERM || sig == Signal::SIGHUP {
-                let _ = tx.send(());
+        let sigs = handled_signals();
+        while let Ok(sig) = sigs.wait() {
+            info!("Received signal: {:?}", sig);
+            let action = match sig {
+                Signal::SIGHUP => SignalAction::Reload,
+                Signal::SIGUSR1 => SignalAction::Commit,
+                Signal::SIGUSR2 => SignalAction::DumpStats,
+                #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+                Signal::SIGINFO => SignalAction::DumpStats,
+                _ => SignalAction::Stop,
+            };
+            if tx.send(action).is_err() {
+                break;
             }
         }
     })